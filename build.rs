@@ -0,0 +1,178 @@
+//! Injects build metadata for `chronos::version_info` and builds the
+//! ramdisk image.
+//!
+//! Sets `CHRONOS_GIT_HASH` (short commit hash, `-dirty` if the tree has
+//! changes) and `CHRONOS_BUILD_TIME` (UTC, honoring `SOURCE_DATE_EPOCH`).
+//! Either falls back to "unknown" when it can't be determined, e.g. when
+//! building outside a git checkout.
+//!
+//! The ramdisk is written to `$OUT_DIR/initrd.img`: the file named by
+//! `CHRONOS_INITRD` if set, otherwise a ustar archive of the `initrd/`
+//! directory. Its FNV-1a checksum goes in `CHRONOS_INITRD_CHECKSUM`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let git_hash = git_hash().unwrap_or_else(|| "unknown".into());
+    let build_time = build_time().unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=CHRONOS_GIT_HASH={}", git_hash);
+    println!("cargo:rustc-env=CHRONOS_BUILD_TIME={}", build_time);
+
+    build_initrd();
+}
+
+fn build_initrd() {
+    println!("cargo:rerun-if-env-changed=CHRONOS_INITRD");
+    println!("cargo:rerun-if-changed=initrd");
+
+    let image = match std::env::var_os("CHRONOS_INITRD") {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", Path::new(&path).display());
+            fs::read(&path).expect("failed to read CHRONOS_INITRD")
+        }
+        None => {
+            let root = Path::new(&std::env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("initrd");
+            let mut entries = Vec::new();
+            if root.is_dir() {
+                collect(&root, &root, &mut entries);
+            }
+            entries.sort();
+            tar(&root, &entries)
+        }
+    };
+    let out = PathBuf::from(std::env::var_os("OUT_DIR").unwrap()).join("initrd.img");
+    fs::write(out, &image).expect("failed to write initrd.img");
+    println!("cargo:rustc-env=CHRONOS_INITRD_CHECKSUM={:016x}", fnv1a(&image));
+}
+
+/// FNV-1a, 64-bit; `chronos::ramdisk::checksum` computes the same.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Add the paths under `dir`, relative to `root`, to `entries`.
+fn collect(root: &Path, dir: &Path, entries: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).expect("failed to read initrd directory") {
+        let path = entry.unwrap().path();
+        println!("cargo:rerun-if-changed={}", path.display());
+        entries.push(path.strip_prefix(root).unwrap().to_path_buf());
+        if path.is_dir() {
+            collect(root, &path, entries);
+        }
+    }
+}
+
+/// Build a ustar archive of `entries` (paths relative to `root`).
+///
+/// Owners and times are zeroed so the image only depends on the contents.
+fn tar(root: &Path, entries: &[PathBuf]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    for entry in entries {
+        let path = root.join(entry);
+        let mut name = entry.to_str().expect("non-UTF-8 initrd path").replace('\\', "/");
+        let (data, mode, typeflag) = if path.is_dir() {
+            name.push('/');
+            (Vec::new(), 0o755, b'5')
+        } else {
+            (fs::read(&path).unwrap(), 0o644, b'0')
+        };
+
+        let mut header = [0u8; 512];
+        // Names over 100 bytes are split at a slash into prefix and name.
+        let (prefix, name) = match name.len() {
+            0..=100 => ("", name.as_str()),
+            _ => {
+                let split = name[..name.len().min(156)].rfind('/').expect("initrd path too long");
+                (&name[..split], &name[split + 1..])
+            }
+        };
+        assert!(name.len() <= 100 && prefix.len() <= 155, "initrd path too long");
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        octal(&mut header[100..108], mode);
+        octal(&mut header[108..116], 0);
+        octal(&mut header[116..124], 0);
+        octal(&mut header[124..136], data.len() as u64);
+        octal(&mut header[136..148], 0);
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        // The checksum is taken with its own field as spaces.
+        header[148..156].fill(b' ');
+        let checksum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+        octal(&mut header[148..155], checksum);
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(&data);
+        archive.resize(archive.len().next_multiple_of(512), 0);
+    }
+    // Two zero blocks end the archive.
+    archive.resize(archive.len() + 1024, 0);
+    archive
+}
+
+/// Write `value` as zero-padded octal followed by a NUL into `field`.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+/// Run git with `args` and return its trimmed output, if it succeeded.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
+
+fn git_hash() -> Option<String> {
+    let hash = git(&["rev-parse", "--short", "HEAD"])?;
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+        .is_some_and(|status| !status.is_empty());
+    Some(if dirty { hash + "-dirty" } else { hash })
+}
+
+/// Format the build time as `YYYY-MM-DD HH:MM UTC`.
+fn build_time() -> Option<String> {
+    let seconds = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse().ok()?,
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs(),
+    };
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let minutes = seconds % 86_400 / 60;
+    Some(format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    ))
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) date.
+///
+/// Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,553 @@
+//! ACPI table discovery: the RSDP, RSDT or XSDT, MADT and FADT.
+//!
+//! The bootloader doesn't pass the RSDP on, so [`init`] scans for it where
+//! the firmware leaves it: the first KiB of the EBDA and the BIOS ROM area
+//! (`0xE0000..0x100000`). It checks the checksums, walks the XSDT (or the
+//! RSDT on ACPI 1.0), and parses:
+//!
+//! - the MADT: the local APIC address, one entry per CPU, the I/O APICs and
+//!   the ISA interrupt source overrides;
+//! - the FADT: the PM1 control blocks and the S5 sleep type from the DSDT,
+//!   for [`power::shutdown`](crate::power::shutdown), and the CMOS century
+//!   register for [`rtc`](crate::rtc).
+//!
+//! Consumers ask through [`madt`] and [`fadt`], which return `None` before
+//! `init` or without ACPI, and keep their old constants as the fallback.
+//! Lookups never lock, so shutdown and panic paths can use them.
+
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::VirtAddr;
+
+use crate::sync::Global;
+
+/// Size of the header every system description table starts with.
+const HEADER_SIZE: usize = 36;
+
+/// Longest table read; anything longer is taken to be garbage.
+const MAX_TABLE_SIZE: usize = 1 << 20;
+
+/// PM1 control register: sleep enable, and where the sleep type goes.
+const SLP_EN: u16 = 1 << 13;
+const SLP_TYP_SHIFT: u16 = 10;
+
+/// Generic address structure space ID for I/O ports.
+const SPACE_SYSTEM_IO: u8 = 1;
+
+static ACPI: Global<Acpi> = Global::new("ACPI");
+
+/// Why the ACPI tables couldn't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No valid RSDP in the EBDA or the BIOS ROM area.
+    NoRsdp,
+    /// A table's bytes don't add up to zero.
+    BadChecksum(Signature),
+    /// A table is shorter than its header or its contents require, or
+    /// longer than any real table.
+    BadLength(Signature),
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcpiError::NoRsdp => f.write_str("no rsdp found"),
+            AcpiError::BadChecksum(signature) => write!(f, "bad checksum in {}", signature),
+            AcpiError::BadLength(signature) => write!(f, "bad length of {}", signature),
+        }
+    }
+}
+
+/// A table signature, such as `APIC` for the MADT.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature(pub [u8; 4]);
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for &byte in &self.0 {
+            let c = if byte.is_ascii_graphic() { byte as char } else { '?' };
+            fmt::Write::write_char(f, c)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+/// A table listed in the RSDT or XSDT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableInfo {
+    pub signature: Signature,
+    pub address: u64,
+    pub length: u32,
+    /// Whether its checksum was right. Tables with a bad one aren't parsed.
+    pub valid: bool,
+}
+
+/// A CPU's local APIC, from a MADT processor or x2APIC entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApic {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    /// Usable now; disabled CPUs may still be hot-plugged.
+    pub enabled: bool,
+}
+
+/// An I/O APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    /// First global system interrupt (GSI) it handles.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that reaches the I/O APICs as a different GSI, or with a
+/// non-default polarity or trigger mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3.
+    pub flags: u16,
+}
+
+/// The Multiple APIC Description Table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    /// Whether the machine also has 8259 PICs.
+    pub pcat_compat: bool,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Return the enabled CPUs.
+    pub fn enabled_cpus(&self) -> impl Iterator<Item = &LocalApic> {
+        self.local_apics.iter().filter(|cpu| cpu.enabled)
+    }
+
+    /// Return the GSI that ISA `irq` arrives as, honoring the overrides.
+    pub fn irq_to_gsi(&self, irq: u8) -> u32 {
+        self.overrides
+            .iter()
+            .find(|o| o.irq == irq)
+            .map_or(u32::from(irq), |o| o.gsi)
+    }
+}
+
+/// The parts of the Fixed ACPI Description Table the kernel uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// Port of the PM1a control block.
+    pub pm1a_control: u16,
+    /// Port of the PM1b control block, 0 if there is none.
+    pub pm1b_control: u16,
+    /// SLP_TYPa for the S5 (soft off) state, from the DSDT's `\_S5`.
+    pub s5_sleep_type: Option<u8>,
+    /// CMOS register holding the century, if the RTC has one.
+    pub century_register: Option<u8>,
+}
+
+impl Fadt {
+    /// Return what to write to the PM1a control block to power off, if the
+    /// S5 sleep type is known.
+    pub fn s5_control_value(&self) -> Option<u16> {
+        let sleep_type = u16::from(self.s5_sleep_type?);
+        Some((sleep_type << SLP_TYP_SHIFT) | SLP_EN)
+    }
+}
+
+/// Everything [`init`] found.
+#[derive(Debug)]
+pub struct Acpi {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// Physical address of the RSDT or XSDT.
+    pub root_address: u64,
+    /// Whether the root is an XSDT, with 64-bit table addresses.
+    pub extended: bool,
+    pub tables: Vec<TableInfo>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+}
+
+/// Find and parse the tables. Needs the heap, and the physical memory
+/// mapping at `phys_offset`.
+pub fn init(phys_offset: VirtAddr) -> Result<(), AcpiError> {
+    let memory = PhysMemory(phys_offset);
+    let rsdp = find_rsdp(&memory).ok_or(AcpiError::NoRsdp)?;
+    let revision = rsdp[15];
+    let oem_id = rsdp[9..15].try_into().unwrap();
+    let (root_address, extended) = if revision >= 2 {
+        (u64_at(rsdp, 24), true)
+    } else {
+        (u64::from(u32_at(rsdp, 16)), false)
+    };
+
+    let root = memory.table(root_address)?;
+    let entry_size = if extended { 8 } else { 4 };
+    let mut tables = Vec::new();
+    for entry in root[HEADER_SIZE..].chunks_exact(entry_size) {
+        let address = if extended { u64_at(entry, 0) } else { u64::from(u32_at(entry, 0)) };
+        let header = memory.read(address, HEADER_SIZE);
+        let signature = Signature(header[..4].try_into().unwrap());
+        let length = u32_at(header, 4);
+        let valid = memory.table(address).is_ok();
+        tables.push(TableInfo { signature, address, length, valid });
+    }
+    let find = |signature: &[u8; 4]| {
+        tables
+            .iter()
+            .find(|table| table.valid && table.signature.0 == *signature)
+            .map(|table| memory.read(table.address, table.length as usize))
+    };
+
+    let madt = find(b"APIC").map(parse_madt).transpose()?;
+    let fadt = match find(b"FACP") {
+        Some(fadt) => {
+            let dsdt = dsdt_address(fadt).and_then(|address| memory.table(address).ok());
+            Some(parse_fadt(fadt, dsdt)?)
+        }
+        None => None,
+    };
+    let _ = ACPI.init(Acpi { revision, oem_id, root_address, extended, tables, madt, fadt });
+    Ok(())
+}
+
+/// Return what [`init`] found, or `None` before it ran or if it failed.
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.try_get().ok()
+}
+
+/// Return the MADT, if there is one.
+pub fn madt() -> Option<&'static Madt> {
+    get()?.madt.as_ref()
+}
+
+/// Return the FADT, if there is one.
+pub fn fadt() -> Option<&'static Fadt> {
+    get()?.fadt.as_ref()
+}
+
+/// Return the GSI that ISA `irq` arrives as; the IRQ number itself without
+/// a MADT.
+pub fn irq_to_gsi(irq: u8) -> u32 {
+    madt().map_or(u32::from(irq), |madt| madt.irq_to_gsi(irq))
+}
+
+/// Print the tables found and what was parsed from them.
+pub fn dump(out: &mut dyn fmt::Write) -> fmt::Result {
+    let Some(acpi) = get() else {
+        return writeln!(out, "no acpi tables");
+    };
+    writeln!(
+        out,
+        "rsdp: revision {}, oem {}, {} at {:#x}",
+        acpi.revision,
+        core::str::from_utf8(&acpi.oem_id).unwrap_or("?").trim_end(),
+        if acpi.extended { "xsdt" } else { "rsdt" },
+        acpi.root_address
+    )?;
+    for table in &acpi.tables {
+        writeln!(
+            out,
+            "  {} at {:#x}, {} bytes{}",
+            table.signature,
+            table.address,
+            table.length,
+            if table.valid { "" } else { " (bad checksum)" }
+        )?;
+    }
+    if let Some(madt) = &acpi.madt {
+        writeln!(
+            out,
+            "madt: local apic at {:#x}, {} of {} cpus enabled{}",
+            madt.local_apic_address,
+            madt.enabled_cpus().count(),
+            madt.local_apics.len(),
+            if madt.pcat_compat { ", 8259 pics" } else { "" }
+        )?;
+        for cpu in &madt.local_apics {
+            let state = if cpu.enabled { "" } else { " (disabled)" };
+            writeln!(out, "  cpu {} apic id {}{}", cpu.processor_uid, cpu.apic_id, state)?;
+        }
+        for io_apic in &madt.io_apics {
+            writeln!(
+                out,
+                "  ioapic {} at {:#x}, gsi base {}",
+                io_apic.id, io_apic.address, io_apic.gsi_base
+            )?;
+        }
+        for o in &madt.overrides {
+            writeln!(out, "  irq {} -> gsi {}, flags {:#x}", o.irq, o.gsi, o.flags)?;
+        }
+    }
+    if let Some(fadt) = &acpi.fadt {
+        write!(out, "fadt: pm1a control {:#x}", fadt.pm1a_control)?;
+        if fadt.pm1b_control != 0 {
+            write!(out, ", pm1b control {:#x}", fadt.pm1b_control)?;
+        }
+        match fadt.s5_sleep_type {
+            Some(sleep_type) => write!(out, ", s5 sleep type {}", sleep_type)?,
+            None => write!(out, ", no s5")?,
+        }
+        match fadt.century_register {
+            Some(register) => writeln!(out, ", century register {:#x}", register)?,
+            None => writeln!(out, ", no century register")?,
+        }
+    }
+    Ok(())
+}
+
+/// Physical memory, through the bootloader's mapping of all of it.
+struct PhysMemory(VirtAddr);
+
+impl PhysMemory {
+    fn read(&self, addr: u64, len: usize) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts((self.0 + addr).as_ptr(), len) }
+    }
+
+    /// Return the table at `addr`, checking its length and checksum.
+    fn table(&self, addr: u64) -> Result<&'static [u8], AcpiError> {
+        let header = self.read(addr, HEADER_SIZE);
+        let signature = Signature(header[..4].try_into().unwrap());
+        let length = u32_at(header, 4) as usize;
+        if !(HEADER_SIZE..=MAX_TABLE_SIZE).contains(&length) {
+            return Err(AcpiError::BadLength(signature));
+        }
+        let table = self.read(addr, length);
+        if !checksum_ok(table) {
+            return Err(AcpiError::BadChecksum(signature));
+        }
+        Ok(table)
+    }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Find the RSDP: 16-byte aligned, in the first KiB of the EBDA or in the
+/// BIOS ROM area.
+fn find_rsdp(memory: &PhysMemory) -> Option<&'static [u8]> {
+    let ebda = u64::from(u16::from_le_bytes(memory.read(0x40e, 2).try_into().unwrap())) << 4;
+    let areas = [(ebda, 1024), (0xe0000, 0x20000)];
+    areas.into_iter().filter(|&(start, _)| start != 0).find_map(|(start, len)| {
+        let area = memory.read(start, len);
+        (0..len).step_by(16).find_map(|offset| {
+            let candidate = &area[offset..];
+            if !candidate.starts_with(b"RSD PTR ") || !checksum_ok(candidate.get(..20)?) {
+                return None;
+            }
+            // ACPI 2.0 and later add fields with a checksum of their own.
+            if candidate[15] >= 2 {
+                let length = u32_at(candidate, 20) as usize;
+                if length < 36 || !checksum_ok(candidate.get(..length)?) {
+                    return None;
+                }
+                return Some(memory.read(start + offset as u64, length));
+            }
+            Some(memory.read(start + offset as u64, 20))
+        })
+    })
+}
+
+/// Parse a MADT, whose checksum has been checked.
+fn parse_madt(table: &[u8]) -> Result<Madt, AcpiError> {
+    let signature = Signature(*b"APIC");
+    if table.len() < HEADER_SIZE + 8 {
+        return Err(AcpiError::BadLength(signature));
+    }
+    let mut madt = Madt {
+        local_apic_address: u64::from(u32_at(table, 36)),
+        pcat_compat: u32_at(table, 40) & 1 != 0,
+        local_apics: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+    let mut rest = &table[HEADER_SIZE + 8..];
+    while let [kind, len, ..] = *rest {
+        let len = usize::from(len);
+        let Some(entry) = rest.get(..len).filter(|_| len >= 2) else {
+            return Err(AcpiError::BadLength(signature));
+        };
+        rest = &rest[len..];
+        // Entries of unknown types, or too short for their type, are
+        // skipped.
+        match (kind, len) {
+            (0, 8..) => madt.local_apics.push(LocalApic {
+                processor_uid: u32::from(entry[2]),
+                apic_id: u32::from(entry[3]),
+                enabled: u32_at(entry, 4) & 1 != 0,
+            }),
+            (1, 12..) => madt.io_apics.push(IoApic {
+                id: entry[2],
+                address: u32_at(entry, 4),
+                gsi_base: u32_at(entry, 8),
+            }),
+            // Only bus 0, ISA, is defined.
+            (2, 10..) if entry[2] == 0 => madt.overrides.push(InterruptOverride {
+                irq: entry[3],
+                gsi: u32_at(entry, 4),
+                flags: u16::from_le_bytes([entry[8], entry[9]]),
+            }),
+            (5, 12..) => madt.local_apic_address = u64_at(entry, 4),
+            (9, 16..) => madt.local_apics.push(LocalApic {
+                processor_uid: u32_at(entry, 12),
+                apic_id: u32_at(entry, 4),
+                enabled: u32_at(entry, 8) & 1 != 0,
+            }),
+            _ => {}
+        }
+    }
+    Ok(madt)
+}
+
+/// Return the DSDT's address from a FADT: X_DSDT if the table is long
+/// enough to have it and it is set, DSDT otherwise.
+fn dsdt_address(fadt: &[u8]) -> Option<u64> {
+    let extended = fadt.get(140..148).map_or(0, |bytes| u64_at(bytes, 0));
+    let address = if extended != 0 { extended } else { u64::from(u32_at(fadt.get(..44)?, 40)) };
+    (address != 0).then_some(address)
+}
+
+/// Parse a FADT, whose checksum has been checked, and take the S5 sleep
+/// type from the DSDT if there is one.
+fn parse_fadt(table: &[u8], dsdt: Option<&[u8]>) -> Result<Fadt, AcpiError> {
+    let signature = Signature(*b"FACP");
+    // Up to and including PM1b_CNT_BLK.
+    if table.len() < 72 {
+        return Err(AcpiError::BadLength(signature));
+    }
+    let mut pm1a_control = u32_at(table, 64) as u16;
+    let pm1b_control = u32_at(table, 68) as u16;
+    // ACPI 2.0's X_PM1a_CNT_BLK takes precedence when it is a port.
+    if let Some(gas) = table.get(172..184)
+        && gas[0] == SPACE_SYSTEM_IO
+        && u64_at(gas, 4) != 0
+    {
+        pm1a_control = u64_at(gas, 4) as u16;
+    }
+    let century_register = table.get(108).copied().filter(|&register| register != 0);
+    let s5_sleep_type = dsdt.and_then(|dsdt| find_s5(&dsdt[HEADER_SIZE..]));
+    Ok(Fadt { pm1a_control, pm1b_control, s5_sleep_type, century_register })
+}
+
+/// Find SLP_TYPa in the AML definition of `\_S5`.
+///
+/// This isn't an AML interpreter: it looks for `Name(_S5_, Package() {...})`
+/// and reads the package's first element, which is how every firmware
+/// writes it.
+fn find_s5(aml: &[u8]) -> Option<u8> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+    const BYTE_PREFIX: u8 = 0x0a;
+
+    let at = aml.windows(4).position(|name| name == b"_S5_")?;
+    // The name may have a root prefix (`\`) between it and NameOp.
+    let named = at >= 1 && aml[at - 1] == NAME_OP
+        || at >= 2 && aml[at - 1] == b'\\' && aml[at - 2] == NAME_OP;
+    if !named {
+        return None;
+    }
+    let rest = aml.get(at + 4..)?;
+    if *rest.first()? != PACKAGE_OP {
+        return None;
+    }
+    // PkgLength: the top two bits of its first byte count the bytes after
+    // it. Then comes NumElements.
+    let pkg_length_bytes = 1 + usize::from(*rest.get(1)? >> 6);
+    let element = rest.get(1 + pkg_length_bytes + 1..)?;
+    match *element.first()? {
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        BYTE_PREFIX => element.get(1).copied(),
+        _ => None,
+    }
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+#[test_case]
+fn test_madt_matches_qemu() {
+    // The test runner starts QEMU with -smp 4.
+    let madt = madt().expect("no madt");
+    assert_eq!(madt.enabled_cpus().count(), 4);
+    let mut ids: Vec<u32> = madt.enabled_cpus().map(|cpu| cpu.apic_id).collect();
+    ids.dedup();
+    assert_eq!(ids.len(), 4);
+    assert_eq!(madt.local_apic_address, 0xfee0_0000);
+    assert!(!madt.io_apics.is_empty());
+    // QEMU wires the PIT to GSI 2, as every PC does.
+    assert_eq!(irq_to_gsi(0), 2);
+    assert_eq!(irq_to_gsi(1), 1);
+}
+
+#[test_case]
+fn test_fadt_matches_qemu() {
+    let fadt = fadt().expect("no fadt");
+    assert!(fadt.pm1a_control != 0);
+    assert_eq!(fadt.s5_control_value(), Some(SLP_EN));
+    assert_eq!(fadt.century_register, Some(0x32));
+
+    let mut out = alloc::string::String::new();
+    dump(&mut out).unwrap();
+    assert!(out.starts_with("rsdp: revision "), "{}", out);
+    for line in ["  APIC at ", "  FACP at ", "madt: local apic at 0xfee00000", "fadt: pm1a"] {
+        assert!(out.lines().any(|l| l.starts_with(line)), "{} missing:\n{}", line, out);
+    }
+}
+
+#[test_case]
+fn test_parse_madt_entries() {
+    let mut table = alloc::vec![0u8; HEADER_SIZE];
+    table.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+    table.extend_from_slice(&1u32.to_le_bytes());
+    table.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]); // cpu 0, apic id 0
+    table.extend_from_slice(&[0, 8, 1, 3, 0, 0, 0, 0]); // cpu 1, disabled
+    table.extend_from_slice(&[1, 12, 7, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0]); // ioapic
+    table.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]); // irq 0 -> gsi 2
+    table.extend_from_slice(&[2, 10, 0, 9, 9, 0, 0, 0, 0x0d, 0]); // irq 9, level
+    table.extend_from_slice(&[4, 6, 0xff, 0, 0, 1]); // NMI, ignored
+    let madt = parse_madt(&table).unwrap();
+    assert!(madt.pcat_compat);
+    assert_eq!(madt.local_apics.len(), 2);
+    assert_eq!(madt.enabled_cpus().count(), 1);
+    assert_eq!(madt.io_apics, [IoApic { id: 7, address: 0xfec0_0000, gsi_base: 0 }]);
+    assert_eq!((madt.irq_to_gsi(0), madt.irq_to_gsi(9), madt.irq_to_gsi(4)), (2, 9, 4));
+    assert_eq!(madt.overrides[1].flags, 0x0d);
+
+    // An entry running past the end of the table.
+    table.extend_from_slice(&[1, 12, 0]);
+    assert_eq!(parse_madt(&table), Err(AcpiError::BadLength(Signature(*b"APIC"))));
+}
+
+#[test_case]
+fn test_checksums_and_s5() {
+    assert!(checksum_ok(&[0x10, 0xf0, 0]));
+    assert!(!checksum_ok(&[0x10, 0xf1]));
+
+    // Name(\_S5_, Package(0x04) { 0x05, ... }), with a one-byte PkgLength.
+    let aml = [0x10, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0a, 0x05, 0x0a];
+    assert_eq!(find_s5(&aml), Some(5));
+    // Two-byte PkgLength, ZeroOp element.
+    let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x40, 0x01, 0x04, 0x00, 0x00];
+    assert_eq!(find_s5(&aml), Some(0));
+    // A reference to _S5_ rather than its definition.
+    assert_eq!(find_s5(b"\x70_S5_\x60"), None);
+}
@@ -1,23 +1,50 @@
 
+//! The kernel heap.
+//!
+//! The global allocator is an [`IrqHeap`]: a linked-list heap behind an
+//! [`IrqMutex`], so interrupts are off while its lock is held and a handler
+//! can't interrupt an allocation and then spin on the same lock. Small
+//! allocations made in a device interrupt handler (see
+//! [`interrupts::in_interrupt`]) come from a separate [`arena`] instead, so
+//! they don't wait on other CPUs' heap use or fragment the heap.
+//!
+//! The keyboard and serial interrupt paths still allocate nothing; the
+//! arena is there so one that does can't deadlock.
+
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+use core::ptr::{NonNull, null_mut};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
-use linked_list_allocator::LockedHeap;
+use linked_list_allocator::Heap;
+
+use crate::error::KernelError;
+use crate::interrupts;
+use crate::memory::{self, MemError};
+use crate::sync::{IrqMutex, IrqMutexGuard};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB
 
+/// Name of the global allocator, for the boot banner.
+pub const ALLOCATOR_NAME: &str = "linked-list";
+
 pub struct Dummy;
+pub mod arena;
 pub mod bump;
+pub mod debug;
 
+#[cfg(not(feature = "heap-debug"))]
+#[global_allocator]
+static ALLOCATOR: IrqHeap = IrqHeap::empty();
 
+/// With `heap-debug`, every block carries canaries; see [`debug`].
+#[cfg(feature = "heap-debug")]
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: debug::DebugHeap = debug::DebugHeap::empty();
 
 unsafe impl GlobalAlloc for Dummy {
     unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
@@ -29,10 +56,108 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
 
+/// A [`Heap`] that interrupt handlers can allocate from; see the module
+/// docs.
+pub struct IrqHeap {
+    heap: IrqMutex<Heap>,
+    arena: arena::Arena,
+}
+
+impl IrqHeap {
+    /// Create a heap with no memory; see [`Heap::init`].
+    pub const fn empty() -> Self {
+        IrqHeap { heap: IrqMutex::named("heap", Heap::empty()), arena: arena::Arena::empty() }
+    }
+
+    /// Lock the heap, with interrupts off until the guard drops.
+    pub fn lock(&self) -> IrqMutexGuard<'_, Heap> {
+        self.heap.lock()
+    }
+
+    /// Return the arena serving allocations in interrupt handlers.
+    pub fn arena(&self) -> &arena::Arena {
+        &self.arena
+    }
+}
+
+unsafe impl GlobalAlloc for IrqHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if interrupts::in_interrupt() {
+            let ptr = self.arena.alloc(layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+        }
+        self.heap
+            .lock()
+            .allocate_first_fit(layout)
+            .map_or(null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if self.arena.contains(ptr) {
+            self.arena.dealloc(ptr);
+        } else if let Some(ptr) = NonNull::new(ptr) {
+            unsafe { self.heap.lock().deallocate(ptr, layout) };
+        }
+    }
+}
+
+/// Usage of the kernel heap, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Return how much of the kernel heap is in use.
+pub fn heap_stats() -> HeapStats {
+    let heap = ALLOCATOR.lock();
+    HeapStats {
+        size: heap.size(),
+        used: heap.used(),
+        free: heap.free(),
+    }
+}
+
+/// Return how much of the interrupt arena is in use.
+pub fn arena_stats() -> arena::ArenaStats {
+    ALLOCATOR.arena().stats()
+}
+
+/// Check the canaries of every live heap block.
+///
+/// Returns `None` without the `heap-debug` feature, when blocks carry no
+/// canaries and aren't tracked.
+pub fn verify_heap() -> Option<debug::HeapCheck> {
+    #[cfg(feature = "heap-debug")]
+    return Some(ALLOCATOR.verify());
+    #[cfg(not(feature = "heap-debug"))]
+    None
+}
+
+/// Start a check of the live heap blocks that runs a few blocks at a time;
+/// see [`debug::HeapScan`].
+///
+/// Returns `None` without the `heap-debug` feature.
+pub fn scan_heap() -> Option<debug::HeapScan<'static>> {
+    #[cfg(feature = "heap-debug")]
+    return Some(ALLOCATOR.scan());
+    #[cfg(not(feature = "heap-debug"))]
+    None
+}
+
+/// Return the flags the heap is mapped with: writable, and no-execute if
+/// the CPU has it turned on.
+pub fn heap_flags() -> PageTableFlags {
+    crate::memory::data_flags(PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+}
+
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+) -> Result<(), KernelError> {
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
         let heap_end = heap_start + HEAP_SIZE - 1u64;
@@ -44,11 +169,9 @@ pub fn init_heap(
     for page in page_range {
         let frame = frame_allocator
             .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-        };
+            .ok_or(MemError::FrameAllocationFailed)?;
+        let flags = heap_flags();
+        unsafe { memory::map_page(mapper, page, frame, flags, frame_allocator)?.flush() };
     }
 
     unsafe {
@@ -57,3 +180,53 @@ pub fn init_heap(
 
     Ok(())
 }
+
+#[test_case]
+fn test_interrupt_allocation_uses_arena() {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let before = arena_stats();
+        let irq = interrupts::IrqContext::enter();
+        let small = Box::new(42u64);
+        let large: Vec<u8> = Vec::with_capacity(arena::MAX_BLOCK + 1);
+        drop(irq);
+        let outside = Box::new(7u64);
+
+        assert!(ALLOCATOR.arena().contains(&*small as *const u64 as *const u8));
+        assert!(!ALLOCATOR.arena().contains(large.as_ptr()));
+        assert!(!ALLOCATOR.arena().contains(&*outside as *const u64 as *const u8));
+        assert_eq!(arena_stats().live, before.live + 1);
+        drop(small);
+        assert_eq!(arena_stats().live, before.live);
+    });
+}
+
+#[test_case]
+fn test_interrupt_allocation_with_heap_locked() {
+    // What an interrupt would find if another CPU held the heap lock: the
+    // arena serves it without touching the heap.
+    let heap = ALLOCATOR.lock();
+    let irq = interrupts::IrqContext::enter();
+    let value = alloc::boxed::Box::new([1u8; 32]);
+    assert_eq!(value[31], 1);
+    drop(value);
+    drop(irq);
+    drop(heap);
+}
+
+#[test_case]
+fn test_channel_send_does_not_allocate() {
+    let (sender, receiver) = crate::task::channel(4);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let heap = heap_stats().used;
+        let arena = arena_stats();
+        let irq = interrupts::IrqContext::enter();
+        sender.try_send(1u8).unwrap();
+        drop(irq);
+        assert_eq!(heap_stats().used, heap);
+        assert_eq!(arena_stats(), arena);
+    });
+    assert_eq!(receiver.try_recv(), Some(1));
+}
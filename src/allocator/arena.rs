@@ -0,0 +1,131 @@
+//! A small bump arena for allocations made in interrupt handlers.
+//!
+//! Handlers should stay short, so anything they allocate is expected to be
+//! small and freed soon. The arena hands out [`ARENA_SIZE`] bytes in order
+//! and starts over once every block in it has been freed, so allocating
+//! never searches a free list. Blocks over [`MAX_BLOCK`] bytes, and any
+//! that don't fit, are left to the heap.
+
+use alloc::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::ptr::null_mut;
+
+use crate::sync::IrqMutex;
+
+/// Bytes in the arena.
+pub const ARENA_SIZE: usize = 4096;
+
+/// Largest block the arena serves.
+pub const MAX_BLOCK: usize = 128;
+
+/// Largest alignment the arena serves.
+const MAX_ALIGN: usize = 16;
+
+#[repr(align(16))]
+struct Storage(UnsafeCell<[u8; ARENA_SIZE]>);
+
+struct Bump {
+    /// Offset of the next free byte.
+    next: usize,
+    /// Blocks handed out and not yet freed.
+    live: usize,
+}
+
+/// Usage of the arena.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArenaStats {
+    /// Bytes handed out since the arena last emptied.
+    pub used: usize,
+    /// Blocks not yet freed.
+    pub live: usize,
+}
+
+pub struct Arena {
+    storage: Storage,
+    bump: IrqMutex<Bump>,
+}
+
+// SAFETY: `storage` is only handed out in disjoint blocks, under `bump`.
+unsafe impl Sync for Arena {}
+
+impl Arena {
+    /// Create an arena with nothing handed out.
+    pub const fn empty() -> Self {
+        Arena {
+            storage: Storage(UnsafeCell::new([0; ARENA_SIZE])),
+            bump: IrqMutex::named("heap arena", Bump { next: 0, live: 0 }),
+        }
+    }
+
+    fn start(&self) -> usize {
+        self.storage.0.get() as usize
+    }
+
+    /// Return whether `ptr` points into the arena.
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        (self.start()..self.start() + ARENA_SIZE).contains(&(ptr as usize))
+    }
+
+    /// Allocate a block for `layout`, or return null if it is too large or
+    /// doesn't fit.
+    pub fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() > MAX_BLOCK || layout.align() > MAX_ALIGN {
+            return null_mut();
+        }
+        let mut bump = self.bump.lock();
+        let offset = bump.next.next_multiple_of(layout.align());
+        if offset + layout.size() > ARENA_SIZE {
+            return null_mut();
+        }
+        bump.next = offset + layout.size();
+        bump.live += 1;
+        self.storage.0.get().cast::<u8>().wrapping_add(offset)
+    }
+
+    /// Free a block [`alloc`](Self::alloc) returned. Its bytes are only
+    /// reused once every block is freed.
+    pub fn dealloc(&self, ptr: *mut u8) {
+        debug_assert!(self.contains(ptr));
+        let mut bump = self.bump.lock();
+        bump.live -= 1;
+        if bump.live == 0 {
+            bump.next = 0;
+        }
+    }
+
+    pub fn stats(&self) -> ArenaStats {
+        let bump = self.bump.lock();
+        ArenaStats { used: bump.next, live: bump.live }
+    }
+}
+
+#[test_case]
+fn test_arena_bumps_and_resets() {
+    let arena = Arena::empty();
+    let small = Layout::from_size_align(3, 1).unwrap();
+    let aligned = Layout::from_size_align(8, 8).unwrap();
+    let a = arena.alloc(small);
+    let b = arena.alloc(aligned);
+    assert!(arena.contains(a) && arena.contains(b));
+    assert_eq!(b as usize - a as usize, 8);
+    assert_eq!(arena.stats(), ArenaStats { used: 16, live: 2 });
+
+    arena.dealloc(a);
+    assert_eq!(arena.stats().used, 16);
+    arena.dealloc(b);
+    assert_eq!(arena.stats(), ArenaStats { used: 0, live: 0 });
+    assert_eq!(arena.alloc(small), a);
+}
+
+#[test_case]
+fn test_arena_refuses_large_and_full() {
+    let arena = Arena::empty();
+    assert!(arena.alloc(Layout::from_size_align(MAX_BLOCK + 1, 1).unwrap()).is_null());
+    assert!(arena.alloc(Layout::from_size_align(8, 64).unwrap()).is_null());
+    let block = Layout::from_size_align(MAX_BLOCK, 1).unwrap();
+    for _ in 0..ARENA_SIZE / MAX_BLOCK {
+        assert!(!arena.alloc(block).is_null());
+    }
+    assert!(arena.alloc(block).is_null());
+    assert!(!arena.contains(&0u8));
+}
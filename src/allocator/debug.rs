@@ -0,0 +1,450 @@
+//! Heap corruption detection, used as the global allocator with the
+//! `heap-debug` feature.
+//!
+//! [`DebugHeap`] pads every allocation with a header before it and a
+//! trailer after it:
+//!
+//! ```text
+//! | padding | prev next size checksum HEADER_MAGIC | data ... | TRAILER_MAGIC |
+//! ```
+//!
+//! The checksum covers the block's address and requested size, so a stray
+//! write over the size is caught too. The magic values sit right against
+//! the data, where an overflow or underflow lands first. `dealloc` checks
+//! the block and panics with a [`Corruption`] if anything changed; the
+//! headers also link every live block into a list that [`DebugHeap::verify`]
+//! walks on demand. A [`HeapScan`] walks it a few blocks at a time instead,
+//! so allocation isn't held up for the whole walk.
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::mem::size_of;
+use core::ops::Deref;
+use core::ptr::{self, null_mut};
+use super::IrqHeap;
+use crate::sync::IrqMutex;
+
+const HEADER_MAGIC: u64 = 0xc0ff_ee00_c0ff_ee00;
+const TRAILER_MAGIC: u64 = 0xdead_beef_dead_beef;
+
+/// Bytes added after each block.
+const TRAILER_SIZE: usize = size_of::<u64>();
+
+/// Bookkeeping in front of each block's data. `magic` comes last so it is
+/// the first thing an underflow hits.
+#[repr(C)]
+struct Header {
+    prev: *mut Header,
+    next: *mut Header,
+    size: usize,
+    checksum: u64,
+    magic: u64,
+}
+
+/// The live blocks, newest first.
+struct Live {
+    head: *mut Header,
+    /// The next block a [`HeapScan`] checks; moved on if that block is
+    /// freed in between.
+    cursor: *mut Header,
+}
+
+// SAFETY: the headers are only reached through the `live` lock.
+unsafe impl Send for Live {}
+
+/// Which part of a block's bookkeeping was damaged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Canary {
+    /// The magic value just before the data.
+    Header,
+    /// The size or checksum in the header.
+    Checksum,
+    /// The header's size disagrees with the layout passed to `dealloc`.
+    Size,
+    /// The magic value just after the data.
+    Trailer,
+}
+
+/// A damaged block: what was expected in its bookkeeping and what was
+/// found instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corruption {
+    /// Address of the block's data, as the allocation returned it.
+    pub block: usize,
+    /// Requested size, as far as it can be trusted.
+    pub size: usize,
+    pub canary: Canary,
+    pub expected: [u8; 8],
+    pub found: [u8; 8],
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let canary = match self.canary {
+            Canary::Header => "header canary",
+            Canary::Checksum => "header checksum",
+            Canary::Size => "size",
+            Canary::Trailer => "trailer canary",
+        };
+        write!(
+            f,
+            "heap corruption in block {:#x} ({} bytes): {} is {}, expected {}",
+            self.block,
+            self.size,
+            canary,
+            Bytes(&self.found),
+            Bytes(&self.expected)
+        )
+    }
+}
+
+/// Bytes as space-separated hex.
+struct Bytes<'a>(&'a [u8]);
+
+impl fmt::Display for Bytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            write!(f, "{}{:02x}", sep, byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Result of [`DebugHeap::verify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapCheck {
+    /// Live blocks checked.
+    pub blocks: usize,
+    /// Bytes requested by those blocks.
+    pub bytes: usize,
+    /// Damaged blocks.
+    pub corrupted: usize,
+    /// The first damaged block, newest first.
+    pub first: Option<Corruption>,
+}
+
+impl fmt::Display for HeapCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} live blocks, {} bytes, {} corrupted",
+            self.blocks, self.bytes, self.corrupted
+        )?;
+        if let Some(corruption) = &self.first {
+            write!(f, "\n{}", corruption)?;
+        }
+        Ok(())
+    }
+}
+
+/// An [`IrqHeap`] that guards every block with canaries.
+pub struct DebugHeap {
+    heap: IrqHeap,
+    live: IrqMutex<Live>,
+}
+
+impl DebugHeap {
+    /// Create an empty heap; see [`IrqHeap::empty`].
+    pub const fn empty() -> Self {
+        DebugHeap {
+            heap: IrqHeap::empty(),
+            live: IrqMutex::named("heap-debug live", Live { head: null_mut(), cursor: null_mut() }),
+        }
+    }
+
+    /// Check every live block.
+    pub fn verify(&self) -> HeapCheck {
+        let live = self.live.lock();
+        let mut check = HeapCheck { blocks: 0, bytes: 0, corrupted: 0, first: None };
+        let mut header = live.head;
+        while !header.is_null() {
+            check.blocks += 1;
+            // SAFETY: the list only holds headers of live blocks, and the
+            // lock keeps them from being freed.
+            unsafe {
+                check.bytes += (*header).size;
+                if let Err(corruption) = check_block(header, None) {
+                    check.corrupted += 1;
+                    check.first.get_or_insert(corruption);
+                }
+                header = (*header).next;
+            }
+        }
+        check
+    }
+
+    /// Start checking the live blocks a few at a time; see [`HeapScan`].
+    pub fn scan(&self) -> HeapScan<'_> {
+        self.live.lock().cursor = null_mut();
+        HeapScan {
+            heap: self,
+            started: false,
+            check: HeapCheck { blocks: 0, bytes: 0, corrupted: 0, first: None },
+        }
+    }
+}
+
+/// A check of the live blocks, done in steps that each hold the heap's
+/// bookkeeping lock only briefly. Blocks allocated after the scan started
+/// may be missed; blocks freed before it reaches them are skipped.
+///
+/// Only one scan should run at a time: they share the position.
+pub struct HeapScan<'a> {
+    heap: &'a DebugHeap,
+    started: bool,
+    check: HeapCheck,
+}
+
+impl HeapScan<'_> {
+    /// Check up to `blocks` more blocks. Returns `true` once every block
+    /// has been checked.
+    pub fn step(&mut self, blocks: usize) -> bool {
+        let mut live = self.heap.live.lock();
+        let mut header = if self.started { live.cursor } else { live.head };
+        self.started = true;
+        for _ in 0..blocks {
+            if header.is_null() {
+                break;
+            }
+            self.check.blocks += 1;
+            // SAFETY: as in `verify`.
+            unsafe {
+                self.check.bytes += (*header).size;
+                if let Err(corruption) = check_block(header, None) {
+                    self.check.corrupted += 1;
+                    self.check.first.get_or_insert(corruption);
+                }
+                header = (*header).next;
+            }
+        }
+        live.cursor = header;
+        header.is_null()
+    }
+
+    /// Return what the scan has found so far.
+    pub fn check(&self) -> &HeapCheck {
+        &self.check
+    }
+}
+
+impl Deref for DebugHeap {
+    type Target = IrqHeap;
+
+    fn deref(&self) -> &IrqHeap {
+        &self.heap
+    }
+}
+
+unsafe impl GlobalAlloc for DebugHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some((outer, offset)) = padded(layout) else {
+            return null_mut();
+        };
+        let base = unsafe { self.heap.alloc(outer) };
+        if base.is_null() {
+            return base;
+        }
+        unsafe {
+            let data = base.add(offset);
+            let header = header_of(data);
+            header.write(Header {
+                prev: null_mut(),
+                next: null_mut(),
+                size: layout.size(),
+                checksum: checksum(data as usize, layout.size()),
+                magic: HEADER_MAGIC,
+            });
+            data.add(layout.size()).cast::<u64>().write_unaligned(TRAILER_MAGIC);
+
+            let mut live = self.live.lock();
+            (*header).next = live.head;
+            if !live.head.is_null() {
+                (*live.head).prev = header;
+            }
+            live.head = header;
+            data
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (outer, offset) = padded(layout).expect("dealloc of a layout alloc refused");
+        let header = header_of(ptr);
+        {
+            let mut live = self.live.lock();
+            // SAFETY: `ptr` came from `alloc` with this layout, so a header
+            // precedes it.
+            if let Err(corruption) = unsafe { check_block(header, Some(layout.size())) } {
+                // Unlocked, so the panic handler can still allocate.
+                drop(live);
+                panic!("{}", corruption);
+            }
+            unsafe {
+                let Header { prev, next, .. } = header.read();
+                if live.cursor == header {
+                    live.cursor = next;
+                }
+                if prev.is_null() {
+                    live.head = next;
+                } else {
+                    (*prev).next = next;
+                }
+                if !next.is_null() {
+                    (*next).prev = prev;
+                }
+            }
+        }
+        unsafe { self.heap.dealloc(ptr.sub(offset), outer) };
+    }
+}
+
+/// Return the layout actually allocated for `layout`, and where the data
+/// starts in it.
+fn padded(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(align_of::<Header>());
+    let offset = size_of::<Header>().next_multiple_of(align);
+    let size = offset.checked_add(layout.size())?.checked_add(TRAILER_SIZE)?;
+    Some((Layout::from_size_align(size, align).ok()?, offset))
+}
+
+fn header_of(data: *mut u8) -> *mut Header {
+    data.wrapping_sub(size_of::<Header>()).cast()
+}
+
+fn checksum(block: usize, size: usize) -> u64 {
+    ((block as u64) ^ (size as u64).rotate_left(32) ^ HEADER_MAGIC)
+        .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+}
+
+/// Check the canaries of the block whose header is at `header`, and its
+/// size against `size` if given.
+///
+/// # Safety
+///
+/// `header` must be the header of a live block.
+unsafe fn check_block(header: *mut Header, size: Option<usize>) -> Result<(), Corruption> {
+    let data = header.wrapping_add(1).cast::<u8>();
+    let block = data as usize;
+    let Header { size: stored, checksum: stored_checksum, magic, .. } = unsafe { header.read() };
+    let damaged = |size, canary, expected: u64, found: u64| Corruption {
+        block,
+        size,
+        canary,
+        expected: expected.to_le_bytes(),
+        found: found.to_le_bytes(),
+    };
+    // Without a trustworthy size in the header, report the caller's.
+    let reported = size.unwrap_or(stored);
+    if magic != HEADER_MAGIC {
+        return Err(damaged(reported, Canary::Header, HEADER_MAGIC, magic));
+    }
+    if stored_checksum != checksum(block, stored) {
+        return Err(damaged(reported, Canary::Checksum, checksum(block, stored), stored_checksum));
+    }
+    if let Some(size) = size
+        && size != stored
+    {
+        return Err(damaged(size, Canary::Size, size as u64, stored as u64));
+    }
+    let trailer = unsafe { ptr::read_unaligned(data.add(stored).cast::<u64>()) };
+    if trailer != TRAILER_MAGIC {
+        return Err(damaged(stored, Canary::Trailer, TRAILER_MAGIC, trailer));
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_padded_keeps_alignment() {
+    for align in [1, 8, 16, 64, 4096] {
+        let layout = Layout::from_size_align(3, align).unwrap();
+        let (outer, offset) = padded(layout).unwrap();
+        assert!(offset >= size_of::<Header>());
+        assert_eq!(offset % align, 0);
+        assert!(outer.align() >= align);
+        assert_eq!(outer.size(), offset + 3 + TRAILER_SIZE);
+    }
+}
+
+#[test_case]
+fn test_corruption_display() {
+    let corruption = Corruption {
+        block: 0x4444_4444_0040,
+        size: 16,
+        canary: Canary::Trailer,
+        expected: TRAILER_MAGIC.to_le_bytes(),
+        found: (TRAILER_MAGIC & !0xff).to_le_bytes(),
+    };
+    assert_eq!(
+        alloc::format!("{}", corruption),
+        "heap corruption in block 0x444444440040 (16 bytes): trailer canary is \
+         00 be ad de ef be ad de, expected ef be ad de ef be ad de"
+    );
+}
+
+#[cfg(feature = "heap-debug")]
+#[test_case]
+fn test_overflow_is_detected() {
+    use super::verify_heap;
+
+    assert_eq!(verify_heap().unwrap().corrupted, 0);
+    let block: alloc::boxed::Box<[u8]> = alloc::vec![0u8; 13].into_boxed_slice();
+    let data = block.as_ptr() as *mut u8;
+    // One byte past the end, then the byte before the start.
+    let past_end = unsafe { data.add(13) };
+    let saved = unsafe { past_end.read() };
+    unsafe { past_end.write(0x41) };
+    let check = verify_heap().unwrap();
+    assert_eq!(check.corrupted, 1, "{}", check);
+    let mut found = TRAILER_MAGIC.to_le_bytes();
+    found[0] = 0x41;
+    assert_eq!(
+        check.first,
+        Some(Corruption {
+            block: data as usize,
+            size: 13,
+            canary: Canary::Trailer,
+            expected: TRAILER_MAGIC.to_le_bytes(),
+            found,
+        })
+    );
+    unsafe { past_end.write(saved) };
+
+    let before = unsafe { data.sub(1) };
+    unsafe { before.write(0) };
+    let check = verify_heap().unwrap();
+    let corruption = check.first.expect("underflow not detected");
+    assert_eq!(corruption.canary, Canary::Header);
+    assert_eq!(corruption.found[7], 0);
+    unsafe { before.write((HEADER_MAGIC >> 56) as u8) };
+
+    // Repaired, so dropping it passes.
+    assert_eq!(verify_heap().unwrap().corrupted, 0);
+    drop(block);
+}
+
+#[cfg(feature = "heap-debug")]
+#[test_case]
+fn test_clean_heap_verifies() {
+    let blocks: alloc::vec::Vec<alloc::boxed::Box<[u8]>> =
+        (1..40).map(|n| alloc::vec![n as u8; n].into_boxed_slice()).collect();
+    let check = super::verify_heap().unwrap();
+    assert!(check.blocks >= blocks.len(), "{}", check);
+    assert_eq!(check.corrupted, 0, "{}", check);
+    assert_eq!(check.first, None);
+}
+
+#[cfg(feature = "heap-debug")]
+#[test_case]
+fn test_scan_survives_frees_between_steps() {
+    let mut blocks: alloc::vec::Vec<alloc::boxed::Box<[u8]>> =
+        (1..20).map(|n| alloc::vec![n as u8; n].into_boxed_slice()).collect();
+    let total = super::verify_heap().unwrap().blocks;
+    let mut scan = super::scan_heap().unwrap();
+    assert!(!scan.step(4));
+    // The newest blocks come first, so the scan is sitting on these.
+    blocks.truncate(10);
+    while !scan.step(4) {}
+    let check = scan.check();
+    assert!(check.blocks < total && check.blocks >= total - 9, "{} of {}", check.blocks, total);
+    assert_eq!(check.corrupted, 0);
+}
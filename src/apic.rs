@@ -0,0 +1,233 @@
+//! The local APIC, in x2APIC or xAPIC mode.
+//!
+//! Interrupts still come through the 8259 PICs (see [`crate::interrupts`]);
+//! the local APIC is only used to identify CPUs and to send the
+//! inter-processor interrupts that start them (see [`crate::smp`]).
+//!
+//! [`map`] picks the [`Mode`]. With x2APIC support, and unless the command
+//! line says `x2apic=off`, the registers are MSRs from `0x800` and the
+//! interrupt command register is written in one go. Otherwise the register
+//! page is mapped, uncached, at [`LAPIC_VIRT`], where every CPU sees its
+//! own local APIC. The functions here work the same in either mode.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::arch::msr::{ApicBase, Msr};
+use crate::memory::{self, MemError};
+
+/// Where the register page is mapped.
+pub const LAPIC_VIRT: u64 = 0x_6666_6666_0000;
+
+/// Register offsets.
+const REG_ID: usize = 0x20;
+const REG_TPR: usize = 0x80;
+const REG_SVR: usize = 0xf0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+
+/// Spurious-interrupt vector register: software enable bit.
+const SVR_ENABLE: u32 = 1 << 8;
+/// Vector for spurious interrupts. Its low four bits must be set on older
+/// APICs.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Interrupt command register bits.
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+/// Polls of the delivery status before giving up on an IPI.
+const POLL_LIMIT: u32 = 1_000_000;
+
+/// First of the x2APIC register MSRs; register offset `n` is MSR
+/// `0x800 + n / 16`.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Set once [`map`] has made the registers reachable.
+static READY: AtomicBool = AtomicBool::new(false);
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
+/// How the local APIC's registers are reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Memory-mapped, at [`LAPIC_VIRT`].
+    XApic,
+    /// Through MSRs.
+    X2Apic,
+}
+
+/// Return the mode [`map`] chose; [`Mode::XApic`] before it runs.
+pub fn mode() -> Mode {
+    if X2APIC.load(Ordering::Acquire) { Mode::X2Apic } else { Mode::XApic }
+}
+
+/// Why an IPI wasn't delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpiTimeout;
+
+/// Return the local APIC's physical base address: the MADT's if there is
+/// one, the APIC base MSR's otherwise.
+pub fn base_address() -> PhysAddr {
+    match crate::acpi::madt() {
+        Some(madt) => PhysAddr::new(madt.local_apic_address),
+        None => ApicBase::read().base_addr(),
+    }
+}
+
+/// Make the registers reachable and the APIC enabled: switch to x2APIC
+/// mode if possible, or map the register page at [`LAPIC_VIRT`]. Only the
+/// first call does anything.
+pub fn map(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MemError> {
+    if READY.load(Ordering::Acquire) {
+        return Ok(());
+    }
+    if crate::cpu::features().x2apic && crate::cmdline::get_bool("x2apic") != Some(false) {
+        ApicBase::enable_x2apic();
+        X2APIC.store(true, Ordering::Release);
+        READY.store(true, Ordering::Release);
+        return Ok(());
+    }
+    ApicBase::enable();
+    let page = Page::containing_address(VirtAddr::new(LAPIC_VIRT));
+    let frame = PhysFrame::containing_address(base_address());
+    let flags = memory::data_flags(
+        PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH,
+    );
+    unsafe { memory::map_page(mapper, page, frame, flags, frame_allocator)?.flush() };
+    READY.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Return whether [`map`] has run.
+pub fn is_mapped() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// Return the x2APIC MSR of the register at `offset`.
+fn msr(offset: usize) -> Msr {
+    Msr(X2APIC_MSR_BASE + (offset >> 4) as u32)
+}
+
+fn read(offset: usize) -> u32 {
+    debug_assert!(is_mapped());
+    match mode() {
+        // SAFETY: the CPU is in x2APIC mode, where these MSRs exist.
+        Mode::X2Apic => unsafe { msr(offset).read() as u32 },
+        Mode::XApic => unsafe {
+            core::ptr::read_volatile((LAPIC_VIRT as usize + offset) as *const u32)
+        },
+    }
+}
+
+fn write(offset: usize, value: u32) {
+    debug_assert!(is_mapped());
+    match mode() {
+        Mode::X2Apic => unsafe { msr(offset).write(u64::from(value)) },
+        Mode::XApic => unsafe {
+            core::ptr::write_volatile((LAPIC_VIRT as usize + offset) as *mut u32, value)
+        },
+    }
+}
+
+/// Return the APIC ID of the calling CPU.
+///
+/// x2APIC IDs are 32 bits, but [`crate::smp`] only handles those that fit
+/// in 8.
+pub fn id() -> u8 {
+    match mode() {
+        Mode::X2Apic => read(REG_ID) as u8,
+        Mode::XApic => (read(REG_ID) >> 24) as u8,
+    }
+}
+
+/// Software-enable the calling CPU's local APIC and let it accept every
+/// interrupt priority.
+///
+/// The firmware's local vector table is left alone, so PIC interrupts keep
+/// arriving through LINT0. In x2APIC mode, this is also where each AP
+/// switches its own APIC over.
+pub fn enable() {
+    if mode() == Mode::X2Apic {
+        ApicBase::enable_x2apic();
+    }
+    let svr = read(REG_SVR);
+    if svr & SVR_ENABLE == 0 {
+        write(REG_SVR, (svr & !0xff) | SVR_ENABLE | u32::from(SPURIOUS_VECTOR));
+    }
+    write(REG_TPR, 0);
+}
+
+/// Wait for the previous IPI to be accepted.
+fn wait_for_delivery() -> Result<(), IpiTimeout> {
+    for _ in 0..POLL_LIMIT {
+        if read(REG_ICR_LOW) & ICR_DELIVERY_PENDING == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(IpiTimeout)
+}
+
+fn send_ipi(apic_id: u8, command: u32) -> Result<(), IpiTimeout> {
+    if mode() == Mode::X2Apic {
+        // One write with the destination in the high half, and no delivery
+        // status to wait on.
+        let icr = u64::from(apic_id) << 32 | u64::from(command);
+        unsafe { msr(REG_ICR_LOW).write(icr) };
+        return Ok(());
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        wait_for_delivery()?;
+        write(REG_ICR_HIGH, u32::from(apic_id) << 24);
+        // Writing the low half sends the IPI.
+        write(REG_ICR_LOW, command);
+        wait_for_delivery()
+    })
+}
+
+/// Send an INIT IPI, which resets the target into wait-for-SIPI.
+pub fn send_init(apic_id: u8) -> Result<(), IpiTimeout> {
+    send_ipi(apic_id, ICR_INIT | ICR_ASSERT)
+}
+
+/// Send a startup IPI: the target starts in real mode at `page * 4096`.
+pub fn send_startup(apic_id: u8, page: u8) -> Result<(), IpiTimeout> {
+    send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | u32::from(page))
+}
+
+#[test_case]
+fn test_x2apic_msr_numbers() {
+    assert_eq!(msr(REG_ID), Msr(0x802));
+    assert_eq!(msr(REG_TPR), Msr(0x808));
+    assert_eq!(msr(REG_SVR), Msr(0x80f));
+    assert_eq!(msr(REG_ICR_LOW), Msr(0x830));
+}
+
+#[test_case]
+fn test_mode_matches_msr() {
+    if !is_mapped() {
+        return;
+    }
+    let x2apic = crate::cpu::features().x2apic
+        && crate::cmdline::get_bool("x2apic") != Some(false);
+    assert_eq!(mode(), if x2apic { Mode::X2Apic } else { Mode::XApic });
+    assert_eq!(ApicBase::read().is_x2apic(), x2apic);
+    if let Some(cpu) = crate::smp::current() {
+        assert_eq!(cpu.apic_id.load(Ordering::Relaxed), id());
+    }
+
+    let start = crate::interrupts::ticks();
+    crate::time::sleep_ms(20);
+    assert!(crate::interrupts::ticks() > start, "timer stopped in {:?} mode", mode());
+}
@@ -0,0 +1,17 @@
+//! Access to x86_64 model-specific, control and debug registers, and port
+//! I/O.
+//!
+//! Reading or writing these registers is always `unsafe` at the instruction
+//! level, and a wrong write can take the machine down in ways that are hard
+//! to trace. All such accesses go through [`msr`], [`cr`] and [`dr`], so
+//! there is one place to audit them. Where a register has a known safe use,
+//! the wrappers expose it as a safe function that keeps the invariants the
+//! kernel relies on (long mode stays enabled, read-only bits are never
+//! written back).
+//!
+//! Device drivers declare their I/O ports through [`port`].
+
+pub mod cr;
+pub mod dr;
+pub mod msr;
+pub mod port;
@@ -0,0 +1,138 @@
+//! Control registers.
+//!
+//! The flag types are the `x86_64` crate's; only the accesses live here.
+
+use core::arch::asm;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub use x86_64::registers::control::{Cr0Flags, Cr3Flags, Cr4Flags};
+
+/// Paging and protection enables.
+pub struct Cr0;
+
+impl Cr0 {
+    /// Read the flags. Unknown bits are dropped.
+    pub fn read() -> Cr0Flags {
+        Cr0Flags::from_bits_truncate(Self::read_raw())
+    }
+
+    /// Read the whole register.
+    pub fn read_raw() -> u64 {
+        let value: u64;
+        // SAFETY: reading CR0 has no effects.
+        unsafe { asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }
+
+    /// Change the flags through `f` and write them back.
+    ///
+    /// # Safety
+    ///
+    /// Clearing protection or paging, or changing caching, breaks the
+    /// running kernel. `f` should only touch bits like write protect.
+    pub unsafe fn update(f: impl FnOnce(&mut Cr0Flags)) {
+        let raw = Self::read_raw();
+        let mut flags = Cr0Flags::from_bits_truncate(raw);
+        f(&mut flags);
+        let value = (raw & !Cr0Flags::all().bits()) | flags.bits();
+        unsafe { asm!("mov cr0, {}", in(reg) value, options(nostack, preserves_flags)) };
+    }
+}
+
+/// The faulting address of the last page fault.
+pub struct Cr2;
+
+impl Cr2 {
+    /// Read the address. Only meaningful in a page fault handler.
+    pub fn read() -> u64 {
+        let value: u64;
+        // SAFETY: reading CR2 has no effects.
+        unsafe { asm!("mov {}, cr2", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }
+
+    /// Read the address, if it is canonical.
+    pub fn read_addr() -> Option<VirtAddr> {
+        VirtAddr::try_new(Self::read()).ok()
+    }
+}
+
+/// The active level 4 page table.
+pub struct Cr3;
+
+impl Cr3 {
+    /// Read the level 4 table frame and the flags.
+    pub fn read() -> (PhysFrame, Cr3Flags) {
+        let value = Self::read_raw();
+        let frame = PhysFrame::containing_address(PhysAddr::new(value & 0x000f_ffff_ffff_f000));
+        (frame, Cr3Flags::from_bits_truncate(value))
+    }
+
+    /// Read the whole register.
+    pub fn read_raw() -> u64 {
+        let value: u64;
+        // SAFETY: reading CR3 has no effects.
+        unsafe { asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }
+
+    /// Switch to the page tables at `frame`, which flushes the TLB's
+    /// non-global entries.
+    ///
+    /// # Safety
+    ///
+    /// The tables must map the running code, its stack and everything the
+    /// kernel touches until it switches back.
+    pub unsafe fn write(frame: PhysFrame, flags: Cr3Flags) {
+        let value = frame.start_address().as_u64() | flags.bits();
+        unsafe { asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags)) };
+    }
+}
+
+/// Architectural extensions.
+pub struct Cr4;
+
+impl Cr4 {
+    /// Read the flags. Unknown bits are dropped.
+    pub fn read() -> Cr4Flags {
+        Cr4Flags::from_bits_truncate(Self::read_raw())
+    }
+
+    /// Read the whole register.
+    pub fn read_raw() -> u64 {
+        let value: u64;
+        // SAFETY: reading CR4 has no effects.
+        unsafe { asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }
+
+    /// Change the flags through `f` and write them back.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `f` clears physical address extension, which long mode
+    /// requires.
+    ///
+    /// # Safety
+    ///
+    /// Only set features the CPU supports (see [`crate::cpu::features`]);
+    /// setting an unsupported bit raises a general protection fault.
+    pub unsafe fn update(f: impl FnOnce(&mut Cr4Flags)) {
+        let raw = Self::read_raw();
+        let mut flags = Cr4Flags::from_bits_truncate(raw);
+        f(&mut flags);
+        assert!(flags.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION), "CR4.PAE cleared");
+        let value = (raw & !Cr4Flags::all().bits()) | flags.bits();
+        unsafe { asm!("mov cr4, {}", in(reg) value, options(nostack, preserves_flags)) };
+    }
+}
+
+#[test_case]
+fn test_long_mode_bits() {
+    let cr0 = Cr0::read();
+    assert!(cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE));
+    assert!(cr0.contains(Cr0Flags::PAGING));
+    assert!(Cr4::read().contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION));
+    assert_eq!(Cr3::read().0, x86_64::registers::control::Cr3::read().0);
+}
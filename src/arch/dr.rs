@@ -0,0 +1,127 @@
+//! Debug registers.
+//!
+//! DR0–DR3 hold breakpoint addresses, DR7 enables them and says what each
+//! one watches, and DR6 tells the `#DB` handler which one fired. They are
+//! per CPU. [`crate::debug`] builds watchpoints on them.
+
+use core::arch::asm;
+
+/// Number of address registers, DR0–DR3.
+pub const ADDRESS_REGISTERS: usize = 4;
+
+/// Read address register `index` (DR0–DR3).
+///
+/// # Panics
+///
+/// Panics if `index` is not below [`ADDRESS_REGISTERS`].
+pub fn read_address(index: usize) -> u64 {
+    let value: u64;
+    // SAFETY: reading a debug register has no effects.
+    unsafe {
+        match index {
+            0 => asm!("mov {}, dr0", out(reg) value, options(nomem, nostack, preserves_flags)),
+            1 => asm!("mov {}, dr1", out(reg) value, options(nomem, nostack, preserves_flags)),
+            2 => asm!("mov {}, dr2", out(reg) value, options(nomem, nostack, preserves_flags)),
+            3 => asm!("mov {}, dr3", out(reg) value, options(nomem, nostack, preserves_flags)),
+            _ => panic!("no debug address register {}", index),
+        }
+    }
+    value
+}
+
+/// Set address register `index` (DR0–DR3) to `addr`.
+///
+/// Writing an address does nothing until [`Dr7`] enables the register.
+///
+/// # Panics
+///
+/// Panics if `index` is not below [`ADDRESS_REGISTERS`].
+pub fn write_address(index: usize, addr: u64) {
+    // SAFETY: an address alone arms nothing.
+    unsafe {
+        match index {
+            0 => asm!("mov dr0, {}", in(reg) addr, options(nomem, nostack, preserves_flags)),
+            1 => asm!("mov dr1, {}", in(reg) addr, options(nomem, nostack, preserves_flags)),
+            2 => asm!("mov dr2, {}", in(reg) addr, options(nomem, nostack, preserves_flags)),
+            3 => asm!("mov dr3, {}", in(reg) addr, options(nomem, nostack, preserves_flags)),
+            _ => panic!("no debug address register {}", index),
+        }
+    }
+}
+
+/// Debug status: which condition raised the last `#DB`.
+pub struct Dr6;
+
+impl Dr6 {
+    /// Value the register holds after reset, with no conditions set.
+    pub const CLEAR: u64 = 0xFFFF_0FF0;
+
+    /// Read the whole register.
+    pub fn read() -> u64 {
+        let value: u64;
+        // SAFETY: reading DR6 has no effects.
+        unsafe { asm!("mov {}, dr6", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }
+
+    /// Return the address registers whose condition was met, as a bit per
+    /// register (B0–B3).
+    pub fn hits() -> u8 {
+        (Self::read() & 0xF) as u8
+    }
+
+    /// Reset the status. The CPU never clears it itself.
+    pub fn clear() {
+        // SAFETY: DR6 only reports; clearing it changes nothing else.
+        unsafe { asm!("mov dr6, {}", in(reg) Self::CLEAR, options(nomem, nostack, preserves_flags)) };
+    }
+}
+
+/// Debug control: which address registers are enabled, and on what.
+pub struct Dr7;
+
+impl Dr7 {
+    /// Bit 10 always reads as set.
+    const RESERVED: u64 = 1 << 10;
+
+    /// Read the whole register.
+    pub fn read() -> u64 {
+        let value: u64;
+        // SAFETY: reading DR7 has no effects.
+        unsafe { asm!("mov {}, dr7", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }
+
+    /// Write the whole register.
+    ///
+    /// # Safety
+    ///
+    /// Every enabled address register must hold an address whose hits the
+    /// `#DB` handler can deal with, or the kernel traps on its own
+    /// accesses. General detect (bit 13) must stay clear.
+    pub unsafe fn write(value: u64) {
+        let value = value | Self::RESERVED;
+        unsafe { asm!("mov dr7, {}", in(reg) value, options(nomem, nostack, preserves_flags)) };
+    }
+
+    /// Return the DR7 bits that enable address register `index` locally
+    /// with the 2-bit condition `rw` and 2-bit length code `len`.
+    pub const fn slot_bits(index: usize, rw: u64, len: u64) -> u64 {
+        let enable = 1 << (index * 2);
+        let control = ((len << 2) | rw) << (16 + index * 4);
+        enable | control
+    }
+
+    /// Return the DR7 bits that belong to address register `index`.
+    pub const fn slot_mask(index: usize) -> u64 {
+        Self::slot_bits(index, 0b11, 0b11)
+    }
+}
+
+#[test_case]
+fn test_slot_bits() {
+    // Write-only, four bytes, in DR1: L1, R/W1 = 01, LEN1 = 11.
+    assert_eq!(Dr7::slot_bits(1, 0b01, 0b11), (1 << 2) | (0b1101 << 20));
+    assert_eq!(Dr7::slot_mask(3), (1 << 6) | (0b1111 << 28));
+    assert_eq!(Dr7::read() & Dr7::RESERVED, Dr7::RESERVED);
+}
@@ -0,0 +1,214 @@
+//! Model-specific registers.
+//!
+//! [`Msr`] is the raw `rdmsr`/`wrmsr` pair. [`Efer`], [`ApicBase`] and
+//! [`GsBase`] wrap the registers the kernel uses with safe accessors.
+
+use core::arch::asm;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub use x86_64::registers::model_specific::EferFlags;
+
+/// A model-specific register, by number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msr(pub u32);
+
+/// Local APIC base address and enable bit.
+pub const IA32_APIC_BASE: Msr = Msr(0x1b);
+/// Extended feature enables: long mode, NX, `syscall`.
+pub const IA32_EFER: Msr = Msr(0xc000_0080);
+/// `syscall`/`sysret` segment selectors.
+pub const IA32_STAR: Msr = Msr(0xc000_0081);
+/// `syscall` entry point in 64-bit mode.
+pub const IA32_LSTAR: Msr = Msr(0xc000_0082);
+/// RFLAGS bits cleared on `syscall`.
+pub const IA32_FMASK: Msr = Msr(0xc000_0084);
+pub const IA32_FS_BASE: Msr = Msr(0xc000_0100);
+pub const IA32_GS_BASE: Msr = Msr(0xc000_0101);
+/// GS base swapped in by `swapgs`.
+pub const IA32_KERNEL_GS_BASE: Msr = Msr(0xc000_0102);
+/// Machine-check capabilities, including the number of banks.
+pub const IA32_MCG_CAP: Msr = Msr(0x179);
+/// Machine-check global status.
+pub const IA32_MCG_STATUS: Msr = Msr(0x17a);
+
+impl Msr {
+    /// Read the register.
+    ///
+    /// # Safety
+    ///
+    /// The register must exist on this CPU; reading one that doesn't raises
+    /// a general protection fault. Some reads have side effects.
+    pub unsafe fn read(self) -> u64 {
+        let (low, high): (u32, u32);
+        unsafe {
+            asm!("rdmsr", in("ecx") self.0, out("eax") low, out("edx") high,
+                 options(nomem, nostack, preserves_flags));
+        }
+        (u64::from(high) << 32) | u64::from(low)
+    }
+
+    /// Write the register.
+    ///
+    /// # Safety
+    ///
+    /// The register must exist and `value` must be valid for it. Writes can
+    /// change paging, segmentation or interrupt delivery under running code.
+    pub unsafe fn write(self, value: u64) {
+        let low = value as u32;
+        let high = (value >> 32) as u32;
+        unsafe {
+            asm!("wrmsr", in("ecx") self.0, in("eax") low, in("edx") high,
+                 options(nostack, preserves_flags));
+        }
+    }
+}
+
+/// The extended feature enable register.
+pub struct Efer;
+
+impl Efer {
+    /// Read the flags. Unknown bits are dropped.
+    pub fn read() -> EferFlags {
+        EferFlags::from_bits_truncate(Self::read_raw())
+    }
+
+    /// Read the whole register.
+    pub fn read_raw() -> u64 {
+        // SAFETY: every x86_64 CPU has EFER, and reading it has no effects.
+        unsafe { IA32_EFER.read() }
+    }
+
+    /// Change the flags through `f` and write them back.
+    ///
+    /// Long mode active is read-only and never written. Unknown bits keep
+    /// their current value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `f` clears long mode enable, or clears no-execute enable
+    /// while it is set: either would break the page tables in use.
+    pub fn update(f: impl FnOnce(&mut EferFlags)) {
+        let raw = Self::read_raw();
+        let old = EferFlags::from_bits_truncate(raw);
+        let mut flags = old;
+        f(&mut flags);
+        assert!(flags.contains(EferFlags::LONG_MODE_ENABLE), "EFER.LME cleared");
+        assert!(
+            !old.contains(EferFlags::NO_EXECUTE_ENABLE)
+                || flags.contains(EferFlags::NO_EXECUTE_ENABLE),
+            "EFER.NXE cleared"
+        );
+        flags.remove(EferFlags::LONG_MODE_ACTIVE);
+        let unknown = raw & !EferFlags::all().bits();
+        // SAFETY: the bits that keep the current mode and mappings valid are
+        // checked above.
+        unsafe { IA32_EFER.write(unknown | flags.bits()) };
+    }
+}
+
+/// A value of the `IA32_APIC_BASE` register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicBase(pub u64);
+
+impl ApicBase {
+    const BSP: u64 = 1 << 8;
+    const EXTD: u64 = 1 << 10;
+    const ENABLE: u64 = 1 << 11;
+    const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    /// Read the register.
+    ///
+    /// Only call this on CPUs with a local APIC (see
+    /// [`crate::cpu::features`]).
+    pub fn read() -> ApicBase {
+        // SAFETY: the register exists whenever there is a local APIC, which
+        // every x86_64 CPU has.
+        ApicBase(unsafe { IA32_APIC_BASE.read() })
+    }
+
+    /// Physical address of the register page.
+    pub fn base_addr(self) -> PhysAddr {
+        PhysAddr::new(self.0 & Self::ADDR_MASK)
+    }
+
+    /// Whether the APIC is globally enabled.
+    pub fn is_enabled(self) -> bool {
+        self.0 & Self::ENABLE != 0
+    }
+
+    /// Whether this is the bootstrap processor.
+    pub fn is_bsp(self) -> bool {
+        self.0 & Self::BSP != 0
+    }
+
+    /// Whether the APIC is in x2APIC mode.
+    pub fn is_x2apic(self) -> bool {
+        self.0 & Self::EXTD != 0
+    }
+
+    /// Globally enable the APIC, keeping its base address.
+    ///
+    /// Disabling isn't offered: on most CPUs the APIC can't be enabled
+    /// again without a reset.
+    pub fn enable() {
+        let base = Self::read();
+        if !base.is_enabled() {
+            // SAFETY: only the enable bit changes.
+            unsafe { IA32_APIC_BASE.write(base.0 | Self::ENABLE) };
+        }
+    }
+
+    /// Enable the APIC and switch it to x2APIC mode.
+    ///
+    /// Only call this on CPUs that support x2APIC (see
+    /// [`crate::cpu::features`]). Like enabling, it can't be undone without
+    /// a reset.
+    pub fn enable_x2apic() {
+        // A disabled APIC must go through xAPIC mode first.
+        Self::enable();
+        let base = Self::read();
+        if !base.is_x2apic() {
+            // SAFETY: the APIC is enabled and the caller checked for x2APIC
+            // support.
+            unsafe { IA32_APIC_BASE.write(base.0 | Self::EXTD) };
+        }
+    }
+}
+
+/// The GS segment base, which holds the per-CPU block (see
+/// [`crate::smp::current`]).
+pub struct GsBase;
+
+impl GsBase {
+    /// Read the base address.
+    pub fn read() -> VirtAddr {
+        // SAFETY: the register exists in long mode.
+        VirtAddr::new(unsafe { IA32_GS_BASE.read() })
+    }
+
+    /// Set the base address. The kernel never dereferences GS directly, so
+    /// this can't invalidate a live access.
+    pub fn write(addr: VirtAddr) {
+        // SAFETY: `VirtAddr` is canonical, so the write can't fault.
+        unsafe { IA32_GS_BASE.write(addr.as_u64()) };
+    }
+}
+
+#[test_case]
+fn test_efer_long_mode() {
+    let efer = Efer::read();
+    assert!(efer.contains(EferFlags::LONG_MODE_ENABLE));
+    assert!(efer.contains(EferFlags::LONG_MODE_ACTIVE));
+    // A no-op update writes back the same register.
+    let before = Efer::read_raw();
+    Efer::update(|_| {});
+    assert_eq!(Efer::read_raw(), before);
+}
+
+#[test_case]
+fn test_apic_base() {
+    let base = ApicBase::read();
+    assert!(base.is_bsp());
+    assert!(base.base_addr().as_u64() != 0);
+    assert!(base.base_addr().is_aligned(4096u64));
+}
@@ -0,0 +1,273 @@
+//! Port I/O.
+//!
+//! A driver declares its ports once, as a [`PortGroup`] struct built from a
+//! base port, instead of spelling port numbers at each access. Every
+//! [`Port`] carries the name of its device, so accesses can be traced: with
+//! tracing on (see [`set_tracing`], or the `port-trace` feature to trace
+//! from boot) each read and write is kept in a small ring that the shell's
+//! `ports` command prints. Like the kernel log, recording never waits: an
+//! access made while the ring is busy is counted but not kept.
+//!
+//! Ports made [`with_delay`](Port::with_delay) follow each write with a
+//! write to port `0x80`, which takes about a microsecond, for old devices
+//! (the PICs) that need time between writes. [`set_io_delay`] turns the
+//! delay off everywhere.
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+use crate::collections::FixedRing;
+
+/// Unused port (the POST diagnostic port) written to for the I/O delay.
+const DELAY_PORT: u16 = 0x80;
+
+/// Accesses the trace ring keeps.
+pub const TRACE_SIZE: usize = 64;
+
+static IO_DELAY: AtomicBool = AtomicBool::new(true);
+static DELAYS: AtomicU64 = AtomicU64::new(0);
+static TRACING: AtomicBool = AtomicBool::new(cfg!(feature = "port-trace"));
+/// The most recent accesses.
+static TRACE: Mutex<FixedRing<Access, TRACE_SIZE>> = Mutex::new(FixedRing::new());
+static TRACE_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// The ports of one device, built from its base port.
+pub trait PortGroup: Sized {
+    /// Device name the accesses are traced under.
+    const DEVICE: &'static str;
+    /// Base port of the standard PC device.
+    const BASE: u16;
+
+    /// Build the group for a device at `base`.
+    fn at(base: u16) -> Self;
+
+    /// Build the group for the standard PC device.
+    fn standard() -> Self {
+        Self::at(Self::BASE)
+    }
+}
+
+/// An I/O port of a device, read and written as `T` (`u8`, `u16` or
+/// `u32`).
+pub struct Port<T> {
+    number: u16,
+    device: &'static str,
+    delay: bool,
+    _value: PhantomData<fn(T) -> T>,
+}
+
+impl<T> Port<T> {
+    /// Port `number`, belonging to `device`.
+    pub const fn new(device: &'static str, number: u16) -> Self {
+        Port { number, device, delay: false, _value: PhantomData }
+    }
+
+    /// Follow each write with the I/O delay.
+    pub const fn with_delay(mut self) -> Self {
+        self.delay = true;
+        self
+    }
+
+    /// Return the port number.
+    pub const fn number(&self) -> u16 {
+        self.number
+    }
+
+    /// Return the name of the port's device.
+    pub const fn device(&self) -> &'static str {
+        self.device
+    }
+}
+
+impl<T: PortRead + Into<u32> + Copy> Port<T> {
+    /// Read the port.
+    ///
+    /// # Safety
+    ///
+    /// Reading a device register can have side effects, such as taking a
+    /// byte out of a FIFO.
+    pub unsafe fn read(&self) -> T {
+        let value = unsafe { T::read_from_port(self.number) };
+        trace(self.device, self.number, value.into(), false);
+        value
+    }
+}
+
+impl<T: PortWrite + Into<u32> + Copy> Port<T> {
+    /// Write the port, then wait if the port asks for the I/O delay.
+    ///
+    /// # Safety
+    ///
+    /// The write must be valid for the device behind the port.
+    pub unsafe fn write(&self, value: T) {
+        unsafe { T::write_to_port(self.number, value) };
+        trace(self.device, self.number, value.into(), true);
+        if self.delay {
+            io_delay();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Port<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Port({}, {:#x})", self.device, self.number)
+    }
+}
+
+/// Turn the I/O delay after writes on or off.
+pub fn set_io_delay(enabled: bool) {
+    IO_DELAY.store(enabled, Ordering::Relaxed);
+}
+
+/// Return how many I/O delays have been inserted.
+pub fn delay_count() -> u64 {
+    DELAYS.load(Ordering::Relaxed)
+}
+
+/// Wait about a microsecond, unless the delay is turned off.
+fn io_delay() {
+    if IO_DELAY.load(Ordering::Relaxed) {
+        unsafe { u8::write_to_port(DELAY_PORT, 0) };
+        DELAYS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// One traced port access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub device: &'static str,
+    pub port: u16,
+    pub value: u32,
+    pub write: bool,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.write {
+            write!(f, "{}: out {:#06x} <- {:#x}", self.device, self.port, self.value)
+        } else {
+            write!(f, "{}: in  {:#06x} -> {:#x}", self.device, self.port, self.value)
+        }
+    }
+}
+
+/// Turn the trace on or off. Turning it on doesn't clear old entries.
+pub fn set_tracing(enabled: bool) {
+    TRACING.store(enabled, Ordering::Relaxed);
+}
+
+/// Return whether accesses are being traced.
+pub fn tracing() -> bool {
+    TRACING.load(Ordering::Relaxed)
+}
+
+/// Forget the traced accesses.
+pub fn clear_trace() {
+    TRACE.lock().clear();
+    TRACE_DROPPED.store(0, Ordering::Relaxed);
+}
+
+/// Call `f` on each traced access, oldest first.
+pub fn for_each_traced(f: impl FnMut(&Access)) {
+    TRACE.lock().iter().for_each(f);
+}
+
+/// Print the traced accesses, oldest first.
+pub fn dump_trace(out: &mut dyn fmt::Write) -> fmt::Result {
+    let mut trace = TRACE.lock();
+    for access in trace.iter() {
+        writeln!(out, "{}", access)?;
+    }
+    let dropped = TRACE_DROPPED.load(Ordering::Relaxed);
+    if dropped > 0 {
+        writeln!(out, "({} accesses not recorded)", dropped)?;
+    }
+    Ok(())
+}
+
+fn trace(device: &'static str, port: u16, value: u32, write: bool) {
+    if !tracing() {
+        return;
+    }
+    match TRACE.try_lock() {
+        Some(mut trace) => {
+            trace.push_overwrite(Access { device, port, value, write });
+        }
+        None => {
+            TRACE_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[test_case]
+fn test_trace_attributes_accesses() {
+    use crate::rtc::CmosPorts;
+
+    let was_tracing = tracing();
+    clear_trace();
+    set_tracing(true);
+    let cmos = CmosPorts::standard();
+    let seconds = unsafe {
+        cmos.index.write(0x00);
+        cmos.data.read()
+    };
+    set_tracing(was_tracing);
+
+    let mut cmos_accesses = alloc::vec::Vec::new();
+    for_each_traced(|access| {
+        if access.device == CmosPorts::DEVICE {
+            cmos_accesses.push(*access);
+        }
+    });
+    assert_eq!(cmos_accesses, [
+        Access { device: "cmos", port: 0x70, value: 0, write: true },
+        Access { device: "cmos", port: 0x71, value: seconds.into(), write: false },
+    ]);
+    let mut out = alloc::string::String::new();
+    dump_trace(&mut out).unwrap();
+    assert!(out.contains("cmos: out 0x0070 <- 0x0"), "{}", out);
+    assert!(out.contains("cmos: in  0x0071 -> "), "{}", out);
+
+    // With tracing off, nothing more is recorded.
+    if !was_tracing {
+        clear_trace();
+        let _ = unsafe { cmos.data.read() };
+        let mut count = 0;
+        for_each_traced(|_| count += 1);
+        assert_eq!(count, 0);
+    }
+}
+
+#[test_case]
+fn test_trace_ring_wraps() {
+    let mut trace = FixedRing::<Access, TRACE_SIZE>::new();
+    for port in 0..TRACE_SIZE as u16 + 3 {
+        trace.push_overwrite(Access { device: "test", port, value: 0, write: false });
+    }
+    let ports: alloc::vec::Vec<u16> = trace.iter().map(|access| access.port).collect();
+    assert_eq!(ports.len(), TRACE_SIZE);
+    assert_eq!(ports[0], 3);
+    assert_eq!(ports[TRACE_SIZE - 1], TRACE_SIZE as u16 + 2);
+}
+
+#[test_case]
+fn test_io_delay_toggle() {
+    // Writing the unused POST port back is harmless; only the delay count
+    // matters.
+    let port = Port::<u8>::new("test", DELAY_PORT).with_delay();
+    let plain = Port::<u8>::new("test", DELAY_PORT);
+
+    let before = delay_count();
+    unsafe { port.write(0) };
+    assert_eq!(delay_count(), before + 1);
+    unsafe { plain.write(0) };
+    assert_eq!(delay_count(), before + 1);
+
+    set_io_delay(false);
+    unsafe { port.write(0) };
+    set_io_delay(true);
+    assert_eq!(delay_count(), before + 1);
+}
@@ -0,0 +1,386 @@
+//! ATA disk reads with PIO on the primary channel.
+//!
+//! [`init`] sends IDENTIFY to the master and slave drive on the primary
+//! channel (ports `0x1f0`-`0x1f7`, control at `0x3f6`) and remembers the
+//! ATA drives that answer. Reads use 28-bit LBA addressing and poll the
+//! status register, so the drive's interrupt (IRQ14) is turned off;
+//! interrupt-driven completion can replace the polling once IRQ handlers can
+//! be registered at runtime. Writes aren't supported yet.
+//!
+//! Every wait on the drive is bounded, so a missing or hung drive produces
+//! [`AtaError::Timeout`] instead of hanging the kernel.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::println;
+use crate::time;
+
+/// Bytes per sector.
+pub const SECTOR_SIZE: usize = 512;
+
+/// How long to wait for the drive before giving up.
+const TIMEOUT_US: u32 = 500_000;
+
+/// Pause between polls of the status register, timed with
+/// [`time::pit_oneshot_us`].
+const POLL_INTERVAL_US: u32 = 10;
+
+/// Status register bits.
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+/// Commands.
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_IDENTIFY: u8 = 0xec;
+const CMD_FLUSH_CACHE: u8 = 0xe7;
+
+/// Device control bit that masks the drive's interrupt.
+const CONTROL_NIEN: u8 = 1 << 1;
+
+/// Which drive on the channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+/// Why a disk operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    /// No ATA drive answered at that position.
+    NoDrive,
+    /// The drive stayed busy or never became ready for data.
+    Timeout,
+    /// The drive reported an error; holds its error register.
+    DeviceError(u8),
+    /// The buffer is smaller than `count` sectors.
+    BufferTooSmall,
+    /// The request runs past the end of the drive.
+    OutOfRange,
+}
+
+impl fmt::Display for AtaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AtaError::NoDrive => f.write_str("no drive"),
+            AtaError::Timeout => f.write_str("drive timed out"),
+            AtaError::DeviceError(error) => write!(f, "drive error {:#04x}", error),
+            AtaError::BufferTooSmall => f.write_str("buffer too small"),
+            AtaError::OutOfRange => f.write_str("sector out of range"),
+        }
+    }
+}
+
+/// A drive found by [`init`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriveInfo {
+    pub drive: Drive,
+    /// Sectors addressable with 28-bit LBA.
+    pub sectors: u32,
+    /// Model name reported by IDENTIFY, trimmed.
+    pub model: String,
+}
+
+impl fmt::Display for DriveInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let position = match self.drive {
+            Drive::Master => "master",
+            Drive::Slave => "slave",
+        };
+        write!(
+            f,
+            "primary {}: {}, {} KiB",
+            position,
+            self.model,
+            self.sectors as u64 * SECTOR_SIZE as u64 / 1024
+        )
+    }
+}
+
+/// The registers of one ATA channel.
+struct Channel {
+    data: Port<u16>,
+    error: Port<u8>,
+    sector_count: Port<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive_head: Port<u8>,
+    /// Status on read, command on write.
+    command: Port<u8>,
+    /// Alternate status on read, device control on write.
+    control: Port<u8>,
+}
+
+/// Wait between status polls. If channel 2 can't time it, the poll just
+/// comes sooner; the poll count still bounds the wait.
+fn pause() {
+    let _ = time::pit_oneshot_us(POLL_INTERVAL_US);
+}
+
+impl Channel {
+    const fn new(base: u16, control: u16) -> Self {
+        Channel {
+            data: Port::new(base),
+            error: Port::new(base + 1),
+            sector_count: Port::new(base + 2),
+            lba_low: Port::new(base + 3),
+            lba_mid: Port::new(base + 4),
+            lba_high: Port::new(base + 5),
+            drive_head: Port::new(base + 6),
+            command: Port::new(base + 7),
+            control: Port::new(control),
+        }
+    }
+
+    /// Read the alternate status, which doesn't acknowledge interrupts.
+    fn status(&mut self) -> u8 {
+        unsafe { self.control.read() }
+    }
+
+    /// Give the drive the 400ns it needs to post a valid status.
+    fn delay(&mut self) {
+        for _ in 0..4 {
+            self.status();
+        }
+    }
+
+    /// Select `drive` and load the top four LBA bits.
+    fn select(&mut self, drive: Drive, lba_top: u8) {
+        let slave = match drive {
+            Drive::Master => 0,
+            Drive::Slave => 1 << 4,
+        };
+        unsafe { self.drive_head.write(0xe0 | slave | (lba_top & 0x0f)) };
+        self.delay();
+    }
+
+    /// Wait until the drive isn't busy.
+    fn wait_not_busy(&mut self) -> Result<u8, AtaError> {
+        for _ in 0..TIMEOUT_US / POLL_INTERVAL_US {
+            let status = self.status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            pause();
+        }
+        Err(AtaError::Timeout)
+    }
+
+    /// Wait until the drive has data ready, or report its error.
+    fn wait_data(&mut self) -> Result<(), AtaError> {
+        for _ in 0..TIMEOUT_US / POLL_INTERVAL_US {
+            let status = self.status();
+            if status & STATUS_BSY == 0 {
+                if status & (STATUS_ERR | STATUS_DF) != 0 {
+                    return Err(AtaError::DeviceError(unsafe { self.error.read() }));
+                }
+                if status & STATUS_DRQ != 0 {
+                    return Ok(());
+                }
+            }
+            pause();
+        }
+        Err(AtaError::Timeout)
+    }
+
+    /// Read one sector's worth of data into `buf`.
+    fn read_sector(&mut self, buf: &mut [u8]) {
+        for pair in buf[..SECTOR_SIZE].chunks_exact_mut(2) {
+            let word = unsafe { self.data.read() };
+            pair.copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    /// Send IDENTIFY to `drive` and decode the answer. `None` if there is no
+    /// ATA drive there (nothing at all, or an ATAPI device).
+    fn identify(&mut self, drive: Drive) -> Option<DriveInfo> {
+        // A floating bus reads as all ones: no controller.
+        if self.status() == 0xff {
+            return None;
+        }
+        self.select(drive, 0);
+        unsafe {
+            self.sector_count.write(0);
+            self.lba_low.write(0);
+            self.lba_mid.write(0);
+            self.lba_high.write(0);
+            self.command.write(CMD_IDENTIFY);
+        }
+        self.delay();
+        if self.status() == 0 {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+        // ATAPI and SATA devices put a signature here and abort IDENTIFY.
+        let signature = unsafe { (self.lba_mid.read(), self.lba_high.read()) };
+        if signature != (0, 0) {
+            return None;
+        }
+        self.wait_data().ok()?;
+
+        let mut data = [0; SECTOR_SIZE];
+        self.read_sector(&mut data);
+        let word = |n: usize| u16::from_le_bytes([data[2 * n], data[2 * n + 1]]);
+        // The model is in words 27-46, with the bytes of each word swapped.
+        let model: String = (27..47)
+            .flat_map(|n| word(n).to_be_bytes())
+            .map(char::from)
+            .collect();
+        Some(DriveInfo {
+            drive,
+            sectors: u32::from(word(60)) | u32::from(word(61)) << 16,
+            model: model.trim().into(),
+        })
+    }
+}
+
+static PRIMARY: Mutex<Channel> = Mutex::new(Channel::new(0x1f0, 0x3f6));
+
+static DRIVES: OnceCell<Vec<DriveInfo>> = OnceCell::uninit();
+
+/// Detect the drives on the primary channel and print what was found.
+///
+/// Needs the heap. Only the first call probes, and registers [`quiesce`]
+/// to run at shutdown if it found a drive.
+pub fn init() {
+    let mut fresh = false;
+    let drives = DRIVES.get_or_init(|| {
+        fresh = true;
+        let mut channel = PRIMARY.lock();
+        unsafe { channel.control.write(CONTROL_NIEN) };
+        [Drive::Master, Drive::Slave]
+            .into_iter()
+            .filter_map(|drive| channel.identify(drive))
+            .collect()
+    });
+    if fresh && !drives.is_empty() {
+        // Each drive may wait out a command and then a cache flush.
+        let timeout = core::time::Duration::from_micros(u64::from(TIMEOUT_US) * 4);
+        crate::power::on_shutdown("ata", quiesce, timeout);
+    }
+    if fresh && crate::klog::level() > 0 {
+        for drive in drives {
+            println!("ata {}", drive);
+        }
+    }
+}
+
+/// Wait for the command in progress, then have each drive write back its
+/// cache, so powering off leaves nothing half done. Every wait is bounded.
+pub fn quiesce() {
+    let Some(drives) = DRIVES.get() else {
+        return;
+    };
+    let mut channel = PRIMARY.lock();
+    for info in drives {
+        // A drive can't be selected while the channel is busy.
+        if channel.wait_not_busy().is_err() {
+            continue;
+        }
+        channel.select(info.drive, 0);
+        unsafe { channel.command.write(CMD_FLUSH_CACHE) };
+        channel.delay();
+        let _ = channel.wait_not_busy();
+    }
+}
+
+/// Iterate over the drives found by [`init`].
+pub fn drives() -> impl Iterator<Item = &'static DriveInfo> {
+    DRIVES.get().into_iter().flatten()
+}
+
+/// Return the drive at `drive`, if [`init`] found one there.
+pub fn drive(drive: Drive) -> Option<&'static DriveInfo> {
+    drives().find(|info| info.drive == drive)
+}
+
+/// Read `count` sectors starting at `lba` from the primary master into
+/// `buf`.
+pub fn read_sectors(lba: u32, count: u8, buf: &mut [u8]) -> Result<(), AtaError> {
+    Drive::Master.read_sectors(lba, count, buf)
+}
+
+impl Drive {
+    /// Read `count` sectors starting at `lba` from this drive into `buf`.
+    ///
+    /// A count of 0 reads nothing.
+    pub fn read_sectors(self, lba: u32, count: u8, buf: &mut [u8]) -> Result<(), AtaError> {
+        let info = drive(self).ok_or(AtaError::NoDrive)?;
+        let len = count as usize * SECTOR_SIZE;
+        if buf.len() < len {
+            return Err(AtaError::BufferTooSmall);
+        }
+        if lba as u64 + count as u64 > info.sectors as u64 {
+            return Err(AtaError::OutOfRange);
+        }
+        if count == 0 {
+            return Ok(());
+        }
+
+        let mut channel = PRIMARY.lock();
+        channel.select(self, (lba >> 24) as u8);
+        channel.wait_not_busy()?;
+        unsafe {
+            channel.sector_count.write(count);
+            channel.lba_low.write(lba as u8);
+            channel.lba_mid.write((lba >> 8) as u8);
+            channel.lba_high.write((lba >> 16) as u8);
+            channel.command.write(CMD_READ_SECTORS);
+        }
+        for sector in buf[..len].chunks_exact_mut(SECTOR_SIZE) {
+            channel.delay();
+            channel.wait_data()?;
+            channel.read_sector(sector);
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_drives_detected() {
+    // The boot image is the master; the runner attaches
+    // tests/data/ata-test.img (8 sectors) as the slave.
+    let master = drive(Drive::Master).expect("no master drive");
+    assert!(master.sectors > 0);
+    assert!(!master.model.is_empty());
+    let slave = drive(Drive::Slave).expect("no slave drive");
+    assert_eq!(slave.sectors, 8);
+}
+
+#[test_case]
+fn test_read_known_bytes() {
+    // Byte i of sector n of the test image is (i + 3n) % 251, except for a
+    // 16-byte magic at the start.
+    let mut buf = [0; 3 * SECTOR_SIZE];
+    Drive::Slave.read_sectors(0, 1, &mut buf).unwrap();
+    assert_eq!(&buf[..16], b"CHRONOS ATA TEST");
+    assert_eq!(buf[16], 16);
+
+    Drive::Slave.read_sectors(5, 3, &mut buf).unwrap();
+    for (sector, data) in buf.chunks(SECTOR_SIZE).enumerate() {
+        let n = 5 + sector;
+        for i in [0, 1, 250, 251, 511] {
+            assert_eq!(data[i] as usize, (i + 3 * n) % 251, "lba {} byte {}", n, i);
+        }
+    }
+
+    // The boot sector of the boot image ends with the MBR signature.
+    read_sectors(0, 1, &mut buf).unwrap();
+    assert_eq!(&buf[510..512], &[0x55, 0xaa]);
+}
+
+#[test_case]
+fn test_read_errors() {
+    let mut buf = [0; SECTOR_SIZE];
+    assert_eq!(Drive::Slave.read_sectors(0, 2, &mut buf), Err(AtaError::BufferTooSmall));
+    assert_eq!(Drive::Slave.read_sectors(8, 1, &mut buf), Err(AtaError::OutOfRange));
+    assert_eq!(Drive::Slave.read_sectors(7, 0, &mut buf), Ok(()));
+}
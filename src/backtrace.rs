@@ -0,0 +1,111 @@
+//! Stack backtraces by walking frame pointers.
+//!
+//! The target spec forces frame pointers, so every function saves the
+//! caller's RBP at `[rbp]` with its return address at `[rbp + 8]`. [`print`]
+//! follows that chain and prints the return addresses, each with the
+//! function it falls in when the [symbol table](crate::symbols) has it.
+//! `addr2line -e <kernel binary>` turns them into source lines offline.
+//!
+//! The chain may be corrupt (a panic is often caused by corruption), so every
+//! frame pointer is checked before it is dereferenced: it has to be aligned
+//! and lie inside a known stack, which is the boot stack, the double-fault
+//! stack or the running thread's stack. Within one stack, frames must move
+//! towards the top. Walks are capped at [`MAX_FRAMES`].
+
+use core::arch::asm;
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Most frames printed by one backtrace.
+pub const MAX_FRAMES: usize = 32;
+
+/// Size of the stack the bootloader runs the kernel on (its default of 512
+/// pages).
+const BOOT_STACK_SIZE: u64 = 512 * 4096;
+
+/// Top of the boot stack, or zero before [`init`].
+static BOOT_STACK_TOP: AtomicU64 = AtomicU64::new(0);
+
+/// Record where the boot stack is.
+///
+/// Must be called early on the boot stack, before it is more than a page
+/// deep: the top is taken to be the next page boundary above the stack
+/// pointer.
+pub fn init() {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+    BOOT_STACK_TOP.store((rsp + 4095) & !4095, Ordering::Relaxed);
+}
+
+/// Return the known stack that holds the 16-byte frame record at `fp`.
+pub(crate) fn stack_containing(fp: u64) -> Option<Range<u64>> {
+    let boot_top = BOOT_STACK_TOP.load(Ordering::Relaxed);
+    let boot = (boot_top != 0).then(|| boot_top - BOOT_STACK_SIZE..boot_top);
+    [boot, crate::gdt::double_fault_stack(), crate::thread::current_stack()]
+        .into_iter()
+        .flatten()
+        .find(|stack| fp >= stack.start && fp + 16 <= stack.end)
+}
+
+/// Fill `out` with return addresses from the frame chain and return how
+/// many were found.
+///
+/// The first address points into the function that called this one, the
+/// next into its caller, and so on.
+#[inline(never)]
+pub fn return_addresses(out: &mut [u64]) -> usize {
+    let mut fp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack)) };
+
+    let mut count = 0;
+    let mut previous: Option<(u64, Range<u64>)> = None;
+    while count < out.len() && fp.is_multiple_of(8) {
+        let Some(stack) = stack_containing(fp) else {
+            break;
+        };
+        // Callers' frames are further up the same stack; a pointer that
+        // doesn't move up means the chain loops or is corrupt.
+        if let Some((previous_fp, previous_stack)) = &previous
+            && *previous_stack == stack
+            && fp <= *previous_fp
+        {
+            break;
+        }
+        // SAFETY: `fp..fp + 16` lies inside a mapped stack.
+        let (next_fp, return_address) =
+            unsafe { (*(fp as *const u64), *((fp + 8) as *const u64)) };
+        if return_address == 0 {
+            break;
+        }
+        out[count] = return_address;
+        count += 1;
+        previous = Some((fp, stack));
+        fp = next_fp;
+    }
+    count
+}
+
+/// Print the return addresses of the calling function's callers to `out`.
+///
+/// Safe to call from panic and fault handlers: nothing is allocated, and a
+/// broken frame chain just ends the backtrace early.
+#[inline(never)]
+pub fn print(out: &mut impl fmt::Write) -> fmt::Result {
+    let mut addresses = [0; MAX_FRAMES];
+    let count = return_addresses(&mut addresses);
+    writeln!(out, "backtrace:")?;
+    // Skip our own frame, which `return_addresses` sees as its caller.
+    for (i, &address) in addresses[..count].iter().skip(1).enumerate() {
+        match crate::symbols::resolve(address) {
+            Some((name, offset)) => {
+                writeln!(out, "  #{:<2} {:#018x} {}+{:#x}", i, address, name, offset)?
+            }
+            None => writeln!(out, "  #{:<2} {:#018x}", i, address)?,
+        }
+    }
+    if count <= 1 {
+        writeln!(out, "  <frame pointer outside known stacks>")?;
+    }
+    Ok(())
+}
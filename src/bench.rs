@@ -0,0 +1,159 @@
+//! Throughput benchmarks with a soft regression gate, built with the
+//! `bench` feature.
+//!
+//! [`measure`] times a workload with the TSC, and [`gate`] prints the
+//! result as one line of `key=value` fields for scripts to pick up:
+//!
+//! ```text
+//! bench name=vga-direct iterations=10000 cycles=... cycles_per_iter=... baseline=... limit=...
+//! ```
+//!
+//! Cycle counts under QEMU swing with the host, so the gate is loose: a
+//! result only fails once it is [`TOLERANCE`] times its baseline. A
+//! benchmark that can't be gated yet, for a reason its caller states,
+//! uses [`report`] instead, which prints its result with no pass or fail.
+
+use core::fmt;
+
+use crate::serial_println;
+use crate::time::Stopwatch;
+
+/// How many times its baseline a result may take before it fails.
+pub const TOLERANCE: u64 = 3;
+
+/// Expected cost of one iteration of a benchmark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Baseline {
+    pub cycles_per_iter: u64,
+    /// Source file holding the value, named in update instructions.
+    pub defined_in: &'static str,
+}
+
+impl Baseline {
+    /// Slowest result that still passes.
+    pub fn limit(&self) -> u64 {
+        self.cycles_per_iter.saturating_mul(TOLERANCE)
+    }
+}
+
+/// A timed run of a benchmark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Measurement {
+    pub name: &'static str,
+    pub iterations: u64,
+    pub cycles: u64,
+}
+
+impl Measurement {
+    pub fn cycles_per_iter(&self) -> u64 {
+        self.cycles / self.iterations.max(1)
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bench name={} iterations={} cycles={} cycles_per_iter={}",
+            self.name,
+            self.iterations,
+            self.cycles,
+            self.cycles_per_iter()
+        )
+    }
+}
+
+/// How a measurement compares with its baseline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    /// Under a [`TOLERANCE`]th of the baseline, which could be tightened.
+    Faster,
+    /// Over the baseline's [`limit`](Baseline::limit).
+    Regressed,
+}
+
+/// Compare `measurement` with `baseline`.
+pub fn verdict(measurement: &Measurement, baseline: &Baseline) -> Verdict {
+    let per_iter = measurement.cycles_per_iter();
+    if per_iter > baseline.limit() {
+        Verdict::Regressed
+    } else if per_iter.saturating_mul(TOLERANCE) < baseline.cycles_per_iter {
+        Verdict::Faster
+    } else {
+        Verdict::Pass
+    }
+}
+
+/// Time `iterations` calls of `f`, passing each its iteration number.
+pub fn measure(name: &'static str, iterations: u64, mut f: impl FnMut(u64)) -> Measurement {
+    let stopwatch = Stopwatch::start();
+    for i in 0..iterations {
+        f(i);
+    }
+    Measurement { name, iterations, cycles: stopwatch.elapsed_cycles() }
+}
+
+/// Print `measurement` and panic if it regressed past `baseline`.
+pub fn gate(measurement: &Measurement, baseline: &Baseline) {
+    serial_println!(
+        "{} baseline={} limit={}",
+        measurement,
+        baseline.cycles_per_iter,
+        baseline.limit()
+    );
+    match verdict(measurement, baseline) {
+        Verdict::Pass => {}
+        Verdict::Faster => {
+            serial_println!(
+                "bench note: {} is well under its baseline; consider lowering it in {} to {}",
+                measurement.name,
+                baseline.defined_in,
+                measurement.cycles_per_iter()
+            );
+        }
+        Verdict::Regressed => panic!(
+            "{} took {} cycles per iteration, over {}x its baseline of {}; if the slowdown \
+             is expected, set its baseline in {} to {}",
+            measurement.name,
+            measurement.cycles_per_iter(),
+            TOLERANCE,
+            baseline.cycles_per_iter,
+            baseline.defined_in,
+            measurement.cycles_per_iter()
+        ),
+    }
+}
+
+/// Print `measurement`, which has no baseline yet, and how to give it one
+/// in `defined_in`.
+pub fn report(measurement: &Measurement, defined_in: &str) {
+    serial_println!("{} baseline=none", measurement);
+    serial_println!(
+        "bench note: {} has no baseline; set it in {} to a measured value such as {}",
+        measurement.name,
+        defined_in,
+        measurement.cycles_per_iter()
+    );
+}
+
+#[test_case]
+fn test_verdict() {
+    let baseline = Baseline { cycles_per_iter: 300, defined_in: file!() };
+    let run = |cycles| Measurement { name: "test", iterations: 10, cycles };
+    assert_eq!(verdict(&run(3000), &baseline), Verdict::Pass);
+    assert_eq!(verdict(&run(9000), &baseline), Verdict::Pass);
+    assert_eq!(verdict(&run(9010), &baseline), Verdict::Regressed);
+    assert_eq!(verdict(&run(1000), &baseline), Verdict::Pass);
+    assert_eq!(verdict(&run(990), &baseline), Verdict::Faster);
+}
+
+#[test_case]
+fn test_measurement_format() {
+    let run = Measurement { name: "vga-direct", iterations: 4, cycles: 1000 };
+    assert_eq!(
+        alloc::format!("{}", run),
+        "bench name=vga-direct iterations=4 cycles=1000 cycles_per_iter=250"
+    );
+    assert_eq!(measure("count", 5, |_| {}).iterations, 5);
+}
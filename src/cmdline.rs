@@ -0,0 +1,369 @@
+//! Kernel command line.
+//!
+//! bootloader 0.9 doesn't pass a command line, so [`init_from_firmware`]
+//! reads one from QEMU's fw_cfg device instead, from a file named
+//! [`FW_CFG_FILE`]:
+//!
+//! ```text
+//! qemu-system-x86_64 ... -fw_cfg name=opt/chronos/cmdline,string="loglevel=0 test=heap"
+//! ```
+//!
+//! Options are `key=value` pairs or bare flags separated by spaces. Values
+//! may be double-quoted to include spaces. When a key repeats, the last
+//! value wins. The table has a fixed capacity so parsing never allocates;
+//! options past [`MAX_OPTIONS`] are dropped and reported.
+//!
+//! Options understood so far:
+//! - `loglevel=<n>`: initial [`klog`](crate::klog) level; `0` silences
+//!   informational boot output.
+//! - `console=vga|serial|both`: where `println!` output goes; see
+//!   [`console`](crate::console).
+//! - `test=<substring>`: only run tests whose name contains it.
+//! - `apic=off`: don't use the local APIC, so only the boot CPU runs.
+//! - `x2apic=off`: drive the local APIC through xAPIC MMIO even if the CPU
+//!   supports x2APIC.
+//! - `panicbeep`: play a tone on the PC speaker when the kernel panics.
+//! - `scrub=<seconds>`: run the [integrity checks](crate::scrub) that
+//!   often.
+//! - `debugexit=<port>`, `debugexit_size=1|2|4`: where QEMU's
+//!   `isa-debug-exit` device is and its `iosize`; see
+//!   [`debug_exit`](crate::debug_exit).
+//! - `selftest`: run the [self-test](crate::selftest) checks at boot and
+//!   print the report.
+//! - `replay=record|<file>`: record keyboard input from boot, or replay a
+//!   recorded log; see [`replay`](crate::task::keyboard::replay).
+//! - `hostlog[=<KiB>]`: also log to a memory ring the host reads; see
+//!   [`hostlog`](crate::hostlog).
+//! - `keymap=<file>`: load a keymap over the US layout; see
+//!   [`keymap`](crate::task::keyboard::keymap).
+
+use conquer_once::spin::OnceCell;
+use x86_64::instructions::port::Port;
+
+use crate::collections::FixedVec;
+use crate::println;
+
+/// Longest command line kept; the rest is cut off.
+pub const MAX_LEN: usize = 512;
+
+/// Most distinct options kept.
+pub const MAX_OPTIONS: usize = 16;
+
+/// The fw_cfg file the command line is read from.
+pub const FW_CFG_FILE: &str = "opt/chronos/cmdline";
+
+/// Keys some part of the kernel reads. Others are reported at boot.
+pub const KNOWN_KEYS: [&str; 14] = [
+    "loglevel",
+    "console",
+    "test",
+    "apic",
+    "x2apic",
+    "panicbeep",
+    "randseed",
+    "scrub",
+    "debugexit",
+    "debugexit_size",
+    "selftest",
+    "replay",
+    "hostlog",
+    "keymap",
+];
+
+/// A `key` or `key=value` option, as byte ranges into the command line.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    key: (usize, usize),
+    value: Option<(usize, usize)>,
+}
+
+/// A parsed command line.
+pub struct Cmdline {
+    text: [u8; MAX_LEN],
+    entries: FixedVec<Entry, MAX_OPTIONS>,
+    dropped: usize,
+}
+
+impl Cmdline {
+    /// Parse `line`, keeping its first [`MAX_LEN`] bytes.
+    pub fn parse(line: &str) -> Self {
+        let mut len = line.len().min(MAX_LEN);
+        while !line.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut cmdline = Cmdline {
+            text: [0; MAX_LEN],
+            entries: FixedVec::new(),
+            dropped: 0,
+        };
+        cmdline.text[..len].copy_from_slice(&line.as_bytes()[..len]);
+
+        let bytes = &line.as_bytes()[..len];
+        let mut i = 0;
+        loop {
+            while i < len && bytes[i] == b' ' {
+                i += 1;
+            }
+            if i == len {
+                break;
+            }
+            let key_start = i;
+            while i < len && bytes[i] != b' ' && bytes[i] != b'=' {
+                i += 1;
+            }
+            let key = (key_start, i);
+            let mut value = None;
+            if i < len && bytes[i] == b'=' {
+                i += 1;
+                // A quoted value runs to the closing quote, or to the end of
+                // the line if there is none.
+                let (start, terminator) = match bytes.get(i) {
+                    Some(b'"') => (i + 1, b'"'),
+                    _ => (i, b' '),
+                };
+                i = start;
+                while i < len && bytes[i] != terminator {
+                    i += 1;
+                }
+                value = Some((start, i));
+                if terminator == b'"' && i < len {
+                    i += 1;
+                }
+            }
+            cmdline.insert(Entry { key, value });
+        }
+        cmdline
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        let key = self.slice(entry.key);
+        let existing = self.entries.iter().position(|e| self.slice(e.key) == key);
+        match existing {
+            Some(index) => self.entries[index] = entry,
+            None => {
+                if self.entries.push(entry).is_err() {
+                    self.dropped += 1;
+                }
+            }
+        }
+    }
+
+    fn slice(&self, (start, end): (usize, usize)) -> &str {
+        // Spans always start and end next to ASCII delimiters.
+        core::str::from_utf8(&self.text[start..end]).unwrap_or("")
+    }
+
+    fn entries(&self) -> impl Iterator<Item = Entry> + '_ {
+        self.entries.iter().copied()
+    }
+
+    fn find(&self, key: &str) -> Option<Entry> {
+        self.entries().find(|entry| self.slice(entry.key) == key)
+    }
+
+    /// Return whether `key` was given at all.
+    pub fn contains(&self, key: &str) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// Return the value of `key`; a bare flag has the empty string.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        let entry = self.find(key)?;
+        Some(entry.value.map_or("", |value| self.slice(value)))
+    }
+
+    /// Return `key` as a boolean.
+    ///
+    /// A bare flag is `true`, as are `1`, `on`, `yes` and `true`; `0`,
+    /// `off`, `no` and `false` are `false`. Anything else is `None`.
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        let entry = self.find(key)?;
+        let Some(value) = entry.value else {
+            return Some(true);
+        };
+        match self.slice(value) {
+            "1" | "on" | "yes" | "true" => Some(true),
+            "0" | "off" | "no" | "false" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Return `key` as a decimal or `0x`-prefixed hexadecimal number.
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        let value = self.get_str(key)?;
+        match value.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        }
+    }
+
+    /// Return the keys not in [`KNOWN_KEYS`].
+    pub fn unknown_keys(&self) -> impl Iterator<Item = &str> {
+        self.entries()
+            .map(|entry| self.slice(entry.key))
+            .filter(|key| !KNOWN_KEYS.contains(key))
+    }
+
+    /// Return how many options didn't fit in the table.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+static CMDLINE: OnceCell<Cmdline> = OnceCell::uninit();
+
+/// Parse `line` as the kernel command line and report options that will be
+/// ignored.
+///
+/// Only the first call has an effect.
+pub fn init(line: &str) {
+    let mut fresh = false;
+    let cmdline = CMDLINE.get_or_init(|| {
+        fresh = true;
+        Cmdline::parse(line)
+    });
+    if !fresh {
+        return;
+    }
+    for key in cmdline.unknown_keys() {
+        println!("cmdline: unknown option {}", key);
+    }
+    if cmdline.dropped() > 0 {
+        println!("cmdline: {} options dropped (table full)", cmdline.dropped());
+    }
+}
+
+/// Read the command line from fw_cfg and [`init`] with it, or with an empty
+/// command line if there is none.
+pub fn init_from_firmware() {
+    let mut buf = [0; MAX_LEN];
+    let len = read_fw_cfg_file(FW_CFG_FILE, &mut buf).unwrap_or(0);
+    let line = core::str::from_utf8(&buf[..len]).unwrap_or("");
+    init(line.trim_end_matches(['\0', '\n']));
+}
+
+/// Return the kernel command line, if [`init`] has run.
+pub fn get() -> Option<&'static Cmdline> {
+    CMDLINE.get()
+}
+
+/// [`Cmdline::get_str`] on the kernel command line.
+pub fn get_str(key: &str) -> Option<&'static str> {
+    get()?.get_str(key)
+}
+
+/// [`Cmdline::get_bool`] on the kernel command line.
+pub fn get_bool(key: &str) -> Option<bool> {
+    get()?.get_bool(key)
+}
+
+/// [`Cmdline::get_u64`] on the kernel command line.
+pub fn get_u64(key: &str) -> Option<u64> {
+    get()?.get_u64(key)
+}
+
+/// fw_cfg selector (write) and data (read) ports.
+const FW_CFG_SELECTOR: u16 = 0x510;
+const FW_CFG_DATA: u16 = 0x511;
+/// Items holding the "QEMU" signature and the file directory.
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+
+/// Select fw_cfg item `key` and read its first bytes into `buf`.
+fn fw_cfg_read(key: u16, buf: &mut [u8]) {
+    let mut data = Port::<u8>::new(FW_CFG_DATA);
+    unsafe {
+        Port::<u16>::new(FW_CFG_SELECTOR).write(key);
+        for byte in buf {
+            *byte = data.read();
+        }
+    }
+}
+
+/// Copy the fw_cfg file `name` into `buf` and return its length (capped at
+/// the buffer's), or `None` if there is no fw_cfg device or no such file.
+fn read_fw_cfg_file(name: &str, buf: &mut [u8]) -> Option<usize> {
+    let mut signature = [0; 4];
+    fw_cfg_read(FW_CFG_SIGNATURE, &mut signature);
+    if &signature != b"QEMU" {
+        return None;
+    }
+
+    // The directory is a big-endian count followed by 64-byte entries of
+    // size, selector, reserved and a NUL-padded name. Reading continues from
+    // where the previous read stopped.
+    let mut count = [0; 4];
+    fw_cfg_read(FW_CFG_FILE_DIR, &mut count);
+    let mut data = Port::<u8>::new(FW_CFG_DATA);
+    for _ in 0..u32::from_be_bytes(count) {
+        let mut entry = [0; 64];
+        for byte in &mut entry {
+            *byte = unsafe { data.read() };
+        }
+        let entry_name = &entry[8..];
+        let name_len = entry_name.iter().position(|&b| b == 0).unwrap_or(entry_name.len());
+        if &entry_name[..name_len] == name.as_bytes() {
+            let size = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]) as usize;
+            let selector = u16::from_be_bytes([entry[4], entry[5]]);
+            let len = size.min(buf.len());
+            fw_cfg_read(selector, &mut buf[..len]);
+            return Some(len);
+        }
+    }
+    None
+}
+
+#[test_case]
+fn test_parse_options() {
+    let cmdline = Cmdline::parse("  loglevel=3 quiet console=\"vga serial\" apic=off  addr=0x1f");
+    assert_eq!(cmdline.get_u64("loglevel"), Some(3));
+    assert_eq!(cmdline.get_bool("quiet"), Some(true));
+    assert_eq!(cmdline.get_str("quiet"), Some(""));
+    assert_eq!(cmdline.get_str("console"), Some("vga serial"));
+    assert_eq!(cmdline.get_bool("apic"), Some(false));
+    assert_eq!(cmdline.get_u64("addr"), Some(0x1f));
+    assert_eq!(cmdline.get_u64("console"), None);
+    assert_eq!(cmdline.get_bool("console"), None);
+    assert!(!cmdline.contains("missing"));
+
+    let mut unknown = cmdline.unknown_keys();
+    assert_eq!(unknown.next(), Some("quiet"));
+    assert_eq!(unknown.next(), Some("addr"));
+    assert_eq!(unknown.next(), None);
+}
+
+#[test_case]
+fn test_parse_edge_cases() {
+    // Last value wins, and an unterminated quote runs to the end.
+    let cmdline = Cmdline::parse("test=a test=b empty= note=\"open quote");
+    assert_eq!(cmdline.get_str("test"), Some("b"));
+    assert_eq!(cmdline.get_str("empty"), Some(""));
+    assert_eq!(cmdline.get_str("note"), Some("open quote"));
+    assert_eq!(cmdline.entries().count(), 3);
+
+    let cmdline = Cmdline::parse("");
+    assert_eq!(cmdline.entries().count(), 0);
+}
+
+#[test_case]
+fn test_parse_overflow() {
+    use alloc::format;
+    use alloc::string::String;
+
+    let mut line = String::new();
+    for i in 0..MAX_OPTIONS + 4 {
+        line += &format!("k{}={} ", i, i);
+    }
+    // Repeating a key that already has a slot still works when full.
+    line += "k0=last";
+    let cmdline = Cmdline::parse(&line);
+    assert_eq!(cmdline.dropped(), 4);
+    assert_eq!(cmdline.get_str("k0"), Some("last"));
+    assert_eq!(cmdline.get_u64("k15"), Some(15));
+    assert!(!cmdline.contains("k16"));
+
+    // Input past MAX_LEN is cut off.
+    let long = "x".repeat(MAX_LEN + 100);
+    let cmdline = Cmdline::parse(&long);
+    assert_eq!(cmdline.get_str(&long[..MAX_LEN]), Some(""));
+}
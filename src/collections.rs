@@ -0,0 +1,475 @@
+//! Fixed-capacity collections that work before the heap exists and inside
+//! interrupt handlers.
+//!
+//! Every type here is `const`-constructible, so it can sit in a `static`,
+//! and none of them allocate. Capacity is a const parameter; a capacity of
+//! zero is allowed and gives a collection that is always full.
+//!
+//! - [`FixedVec`] is a vector of at most `N` elements.
+//! - [`FixedString`] is a string of at most `N` bytes. Writing past the end
+//!   truncates and is remembered.
+//! - [`FixedRing`] is a single-producer single-consumer queue whose
+//!   [`push`](FixedRing::push) and [`pop`](FixedRing::pop) take `&self` and
+//!   never block, so one side can run in an interrupt handler. Under a lock
+//!   it also works as a ring of the most recent values, with
+//!   [`push_overwrite`](FixedRing::push_overwrite).
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// A vector of at most `N` elements, stored inline.
+pub struct FixedVec<T, const N: usize> {
+    items: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+impl<T, const N: usize> FixedVec<T, N> {
+    /// Create an empty vector.
+    pub const fn new() -> Self {
+        FixedVec { items: [const { MaybeUninit::uninit() }; N], len: 0 }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Append `value`, or hand it back if the vector is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+        self.items[self.len].write(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove and return the last element.
+    pub fn pop(&mut self) -> Option<T> {
+        self.len = self.len.checked_sub(1)?;
+        // SAFETY: the element at the old `len - 1` was initialized, and
+        // lowering `len` hands ownership of it to us.
+        Some(unsafe { self.items[self.len].assume_init_read() })
+    }
+
+    /// Remove every element.
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl<T, const N: usize> Default for FixedVec<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Deref for FixedVec<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // SAFETY: the first `len` elements are initialized, and
+        // `MaybeUninit<T>` has the layout of `T`.
+        unsafe { slice::from_raw_parts(self.items.as_ptr().cast(), self.len) }
+    }
+}
+
+impl<T, const N: usize> DerefMut for FixedVec<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        // SAFETY: as for `deref`.
+        unsafe { slice::from_raw_parts_mut(self.items.as_mut_ptr().cast(), self.len) }
+    }
+}
+
+impl<T, const N: usize> Drop for FixedVec<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T: fmt::Debug, const N: usize> fmt::Debug for FixedVec<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// A string of at most `N` bytes, stored inline.
+///
+/// Writes that don't fit keep as many whole characters as fit and set
+/// [`is_truncated`](Self::is_truncated). They still return `Ok`, so one
+/// long argument doesn't stop the rest of a `write!` from being tried.
+pub struct FixedString<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+    truncated: bool,
+}
+
+impl<const N: usize> FixedString<N> {
+    /// Create an empty string.
+    pub const fn new() -> Self {
+        FixedString { bytes: [0; N], len: 0, truncated: false }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are copied in.
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return whether anything written was cut off.
+    pub const fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Empty the string and forget any truncation.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.truncated = false;
+    }
+}
+
+impl<const N: usize> Default for FixedString<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for FixedString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut take = s.len().min(N - self.len);
+        if take < s.len() {
+            self.truncated = true;
+            while !s.is_char_boundary(take) {
+                take -= 1;
+            }
+        }
+        self.bytes[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        Ok(())
+    }
+}
+
+impl<const N: usize> fmt::Display for FixedString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for FixedString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// A queue of at most `N` elements for one producer and one consumer.
+///
+/// [`push`](Self::push) and [`pop`](Self::pop) are lock-free and never
+/// wait. If a second producer (or consumer) runs while another is mid-call,
+/// for example an interrupt handler pushing into a ring that the code it
+/// interrupted was also pushing into, its call fails as if the ring were
+/// full (or empty) instead of spinning.
+pub struct FixedRing<T, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    /// Values ever popped. Only the consumer advances it.
+    head: AtomicUsize,
+    /// Values ever pushed. Only the producer advances it.
+    tail: AtomicUsize,
+    producing: AtomicBool,
+    consuming: AtomicBool,
+}
+
+// SAFETY: each slot is written only by the producer while it is outside
+// `head..tail`, and read only by the consumer while it is inside, with the
+// `producing` and `consuming` flags keeping each side to one caller.
+unsafe impl<T: Send, const N: usize> Sync for FixedRing<T, N> {}
+
+/// Holds one side of a ring and lets it go on drop.
+struct Claim<'a>(&'a AtomicBool);
+
+impl<'a> Claim<'a> {
+    fn take(flag: &'a AtomicBool) -> Option<Self> {
+        (!flag.swap(true, Ordering::Acquire)).then_some(Claim(flag))
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl<T, const N: usize> FixedRing<T, N> {
+    /// Create an empty ring.
+    pub const fn new() -> Self {
+        FixedRing {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            producing: AtomicBool::new(false),
+            consuming: AtomicBool::new(false),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Number of values queued. With the other side running concurrently
+    /// this may already be out of date.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        self.tail.load(Ordering::Acquire).wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    /// Queue `value`, or hand it back if the ring is full or another
+    /// producer is mid-push.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let Some(_claim) = Claim::take(&self.producing) else {
+            return Err(value);
+        };
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+        // SAFETY: the ring isn't full, so the slot is outside `head..tail`
+        // and the consumer won't touch it until `tail` is advanced.
+        unsafe { (*self.slots[tail % N].get()).write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Take the oldest value, or `None` if the ring is empty or another
+    /// consumer is mid-pop.
+    pub fn pop(&self) -> Option<T> {
+        let _claim = Claim::take(&self.consuming)?;
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        // SAFETY: the slot is inside `head..tail`, so it was initialized,
+        // and the producer won't reuse it until `head` is advanced.
+        let value = unsafe { (*self.slots[head % N].get()).assume_init_read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Queue `value`, dropping the oldest value to make room if the ring is
+    /// full. Returns `value` back only if the capacity is zero.
+    pub fn push_overwrite(&mut self, value: T) -> Option<T> {
+        if N == 0 {
+            return Some(value);
+        }
+        if self.is_full() {
+            drop(self.pop());
+        }
+        // Nothing else can hold a claim while we have `&mut self`.
+        self.push(value).err()
+    }
+
+    /// The queued values, oldest first, as two slices: up to the end of the
+    /// storage and then from its start.
+    pub fn as_slices(&mut self) -> (&[T], &[T]) {
+        let len = self.len();
+        if len == 0 {
+            return (&[], &[]);
+        }
+        let start = *self.head.get_mut() % N;
+        let first = len.min(N - start);
+        let base = self.slots.as_ptr().cast::<T>();
+        // SAFETY: the slots in `head..tail` are initialized, and
+        // `UnsafeCell<MaybeUninit<T>>` has the layout of `T`. `&mut self`
+        // keeps both sides away while the slices are borrowed.
+        unsafe {
+            (
+                slice::from_raw_parts(base.add(start), first),
+                slice::from_raw_parts(base, len - first),
+            )
+        }
+    }
+
+    /// Iterate over the queued values, oldest first.
+    pub fn iter(&mut self) -> impl Iterator<Item = &T> {
+        let (older, newer) = self.as_slices();
+        older.iter().chain(newer)
+    }
+
+    /// Drop every queued value.
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl<T, const N: usize> Default for FixedRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for FixedRing<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(test)]
+struct Counted(#[allow(dead_code)] u32);
+
+#[cfg(test)]
+impl Drop for Counted {
+    fn drop(&mut self) {
+        DROPS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_vec_full_and_empty() {
+    let mut vec = FixedVec::<u32, 3>::new();
+    assert!(vec.is_empty());
+    assert_eq!(vec.pop(), None);
+    for i in 0..3 {
+        assert_eq!(vec.push(i), Ok(()));
+    }
+    assert!(vec.is_full());
+    assert_eq!(vec.push(9), Err(9));
+    assert_eq!(&vec[..], &[0, 1, 2]);
+    vec[1] = 7;
+    assert_eq!(vec.pop(), Some(2));
+    assert_eq!(&vec[..], &[0, 7]);
+    vec.clear();
+    assert!(vec.is_empty());
+}
+
+#[test_case]
+fn test_vec_drops_elements() {
+    DROPS.store(0, Ordering::Relaxed);
+    let mut vec = FixedVec::<Counted, 4>::new();
+    for i in 0..3 {
+        assert!(vec.push(Counted(i)).is_ok());
+    }
+    drop(vec.pop());
+    assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    drop(vec);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 3);
+}
+
+#[test_case]
+fn test_string_truncates_at_char_boundary() {
+    use core::fmt::Write;
+
+    let mut text = FixedString::<8>::new();
+    write!(text, "ab{}", 12).unwrap();
+    assert_eq!(text.as_str(), "ab12");
+    assert!(!text.is_truncated());
+    // "é" is two bytes and only one is left after "xyz".
+    text.write_str("xyzé!").unwrap();
+    assert_eq!(text.as_str(), "ab12xyz");
+    assert!(text.is_truncated());
+    text.write_str("!").unwrap();
+    assert_eq!(text.as_str(), "ab12xyz!");
+    assert_eq!(text.len(), 8);
+    text.clear();
+    assert!(text.is_empty() && !text.is_truncated());
+}
+
+#[test_case]
+fn test_ring_full_empty_and_wraparound() {
+    static RING: FixedRing<u32, 4> = FixedRing::new();
+    assert_eq!(RING.pop(), None);
+    // Several laps, so the slot index wraps while values are queued.
+    for lap in 0..5 {
+        for i in 0..4 {
+            assert_eq!(RING.push(lap * 10 + i), Ok(()));
+        }
+        assert!(RING.is_full());
+        assert_eq!(RING.push(99), Err(99));
+        for i in 0..3 {
+            assert_eq!(RING.pop(), Some(lap * 10 + i));
+        }
+        assert_eq!(RING.pop(), Some(lap * 10 + 3));
+        assert!(RING.is_empty());
+        assert_eq!(RING.push(lap), Ok(()));
+        assert_eq!(RING.pop(), Some(lap));
+    }
+}
+
+#[test_case]
+fn test_ring_overwrite_keeps_newest() {
+    let mut ring = FixedRing::<u32, 3>::new();
+    for i in 0..5 {
+        assert_eq!(ring.push_overwrite(i), None);
+    }
+    assert_eq!(ring.as_slices(), (&[2, 3][..], &[4][..]));
+    assert!(ring.iter().copied().eq([2, 3, 4]));
+    ring.clear();
+    assert_eq!(ring.as_slices(), (&[][..], &[][..]));
+}
+
+#[test_case]
+fn test_ring_busy_side_fails_instead_of_waiting() {
+    let ring = FixedRing::<u32, 2>::new();
+    ring.push(1).unwrap();
+    // As if an interrupt arrived in the middle of another push or pop.
+    ring.producing.store(true, Ordering::Relaxed);
+    assert_eq!(ring.push(2), Err(2));
+    ring.producing.store(false, Ordering::Relaxed);
+    ring.consuming.store(true, Ordering::Relaxed);
+    assert_eq!(ring.pop(), None);
+    ring.consuming.store(false, Ordering::Relaxed);
+    assert_eq!(ring.pop(), Some(1));
+}
+
+#[test_case]
+fn test_ring_drops_queued_values() {
+    DROPS.store(0, Ordering::Relaxed);
+    let mut ring = FixedRing::<Counted, 2>::new();
+    assert!(ring.push(Counted(0)).is_ok());
+    assert!(ring.push(Counted(1)).is_ok());
+    assert!(ring.push_overwrite(Counted(2)).is_none());
+    assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+    drop(ring);
+    assert_eq!(DROPS.load(Ordering::Relaxed), 3);
+}
+
+#[test_case]
+fn test_zero_capacity_is_always_full() {
+    let mut vec = FixedVec::<u32, 0>::new();
+    assert!(vec.is_full() && vec.is_empty());
+    assert_eq!(vec.push(1), Err(1));
+    assert_eq!(vec.pop(), None);
+
+    let mut text = FixedString::<0>::new();
+    fmt::Write::write_str(&mut text, "x").unwrap();
+    assert!(text.is_empty() && text.is_truncated());
+
+    let mut ring = FixedRing::<u32, 0>::new();
+    assert!(ring.is_full() && ring.is_empty());
+    assert_eq!(ring.push(1), Err(1));
+    assert_eq!(ring.pop(), None);
+    assert_eq!(ring.push_overwrite(2), Some(2));
+    assert_eq!(ring.as_slices(), (&[][..], &[][..]));
+}
@@ -0,0 +1,323 @@
+//! Where `print!` output and the shell's input go.
+//!
+//! The console is the VGA screen with the keyboard, COM1, or both. [`init`]
+//! picks it from the `console=vga|serial|both` command-line option, and
+//! falls back to COM1 when [`vga_buffer::init`] found no adapter, so a
+//! headless machine still shows its output on the host. The shell's
+//! `console` command changes it at run time with [`select`].
+//!
+//! The selection is a single atomic, read once per
+//! [`print!`](crate::print): output in progress when it changes finishes on
+//! the old sink.
+//!
+//! A task can be given a console of its own with
+//! [`Task::with_console`](crate::task::Task::with_console), and tasks it
+//! creates inherit it; their `print!` output goes there whatever is
+//! selected. Interrupt handlers and code outside tasks print to the selected
+//! console. [`print_to`] picks the console explicitly. Everything printed
+//! is recorded in the [kernel log](crate::klog) as well.
+//!
+//! Colored output keeps its colors on COM1 too, as ANSI SGR escape
+//! sequences around each line of the text; the debug console and the log
+//! get it plain.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::task::executor;
+use crate::vga_buffer::Color;
+use crate::{cmdline, serial, vga_buffer};
+
+/// A console selection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Console {
+    /// The VGA screen and the keyboard. The default.
+    Vga = 0,
+    /// COM1 only.
+    Serial = 1,
+    /// The screen, the keyboard and COM1.
+    Both = 2,
+}
+
+impl Console {
+    /// Parse a `console=` value or `console` command argument.
+    pub fn parse(name: &str) -> Option<Console> {
+        match name {
+            "vga" => Some(Console::Vga),
+            "serial" => Some(Console::Serial),
+            "both" => Some(Console::Both),
+            _ => None,
+        }
+    }
+
+    /// Return the name [`parse`](Self::parse) accepts.
+    pub fn name(self) -> &'static str {
+        match self {
+            Console::Vga => "vga",
+            Console::Serial => "serial",
+            Console::Both => "both",
+        }
+    }
+
+    /// Return the console stored as `value` by `as u8`.
+    pub(crate) fn from_u8(value: u8) -> Option<Console> {
+        match value {
+            0 => Some(Console::Vga),
+            1 => Some(Console::Serial),
+            2 => Some(Console::Both),
+            _ => None,
+        }
+    }
+
+    /// Return whether output goes to the screen and the keyboard is read.
+    pub fn has_vga(self) -> bool {
+        self != Console::Serial
+    }
+
+    /// Return whether output goes to COM1.
+    pub fn has_serial(self) -> bool {
+        self != Console::Vga
+    }
+}
+
+impl fmt::Display for Console {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Why [`select`] refused a console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleError {
+    /// There is no VGA adapter to put output on.
+    NoVga,
+    /// COM1 wasn't set up.
+    NoSerial,
+}
+
+impl fmt::Display for ConsoleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsoleError::NoVga => f.write_str("no vga adapter"),
+            ConsoleError::NoSerial => f.write_str("no serial port"),
+        }
+    }
+}
+
+static SELECTED: AtomicU8 = AtomicU8::new(Console::Vga as u8);
+
+/// Return the current console.
+pub fn selected() -> Console {
+    Console::from_u8(SELECTED.load(Ordering::Relaxed)).unwrap_or(Console::Vga)
+}
+
+/// Return the console the caller's `print!` output goes to: the polled
+/// task's own, if it has one, and otherwise the selected one. Always the
+/// selected one in interrupt context.
+pub fn current() -> Console {
+    if crate::interrupts::in_interrupt() {
+        return selected();
+    }
+    executor::task_console().unwrap_or_else(selected)
+}
+
+/// Print `args` to `console`, to the [debug console](crate::debugcon) if
+/// there is one, and into the kernel log.
+///
+/// Output for a device that isn't there is dropped, so a task bound to the
+/// screen on a headless machine is only heard in the log.
+pub fn print_to(console: Console, args: fmt::Arguments) {
+    print_with(console, args, args, |writer| writer.write_fmt(args));
+}
+
+/// The SGR escape sequence that has a terminal draw in `.0`, on `.1` if
+/// given.
+struct Sgr(Color, Option<Color>);
+
+impl fmt::Display for Sgr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = |color: Color, base: u8| match color.ansi() {
+            dim @ 0..=7 => base + dim,
+            bright => base + 60 + bright - 8,
+        };
+        write!(f, "\x1b[{}", code(self.0, 30))?;
+        if let Some(background) = self.1 {
+            write!(f, ";{}", code(background, 40))?;
+        }
+        f.write_str("m")
+    }
+}
+
+/// Puts a terminal back in its own colors after an [`Sgr`].
+const SGR_RESET: &str = "\x1b[0m";
+
+/// `.1` in `.0`'s colors, put back before each line break, so that the
+/// next line and its [timestamp](serial) start in the terminal's own.
+struct Colored<T>(Sgr, T);
+
+impl<T: fmt::Display> fmt::Display for Colored<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Lines<'a, 'b> {
+            f: &'a mut fmt::Formatter<'b>,
+            sgr: &'a Sgr,
+            colored: bool,
+        }
+
+        impl Write for Lines<'_, '_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                for line in s.split_inclusive('\n') {
+                    let text = line.strip_suffix('\n').unwrap_or(line);
+                    if !text.is_empty() && !self.colored {
+                        write!(self.f, "{}", self.sgr)?;
+                        self.colored = true;
+                    }
+                    self.f.write_str(text)?;
+                    if text.len() < line.len() {
+                        if self.colored {
+                            self.f.write_str(SGR_RESET)?;
+                            self.colored = false;
+                        }
+                        self.f.write_str("\n")?;
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        let mut lines = Lines { f, sgr: &self.0, colored: false };
+        write!(lines, "{}", self.1)?;
+        if lines.colored {
+            lines.f.write_str(SGR_RESET)?;
+        }
+        Ok(())
+    }
+}
+
+/// Print `args` as [`print_to`] does, in `foreground` on `background` on
+/// the screen and on the serial side.
+///
+/// The colors are set and put back under the writer's lock, so output
+/// from an interrupt handler never picks them up.
+pub fn print_colored_to(
+    console: Console,
+    foreground: Color,
+    background: Color,
+    args: fmt::Arguments,
+) {
+    let colored = Colored(Sgr(foreground, Some(background)), args);
+    print_with(console, args, format_args!("{}", colored), |writer| {
+        writer.with_color(foreground, background, |writer| writer.write_fmt(args))
+    });
+}
+
+/// Print `tag`, a space and `args` as one line, as [`print_to`] does, with
+/// the tag in `color` on the screen and on the serial side. The screen line
+/// is written under one hold of the writer's lock, so nothing a handler
+/// prints lands inside it.
+pub fn print_tagged_to(console: Console, tag: &str, color: Color, args: fmt::Arguments) {
+    let colored = Colored(Sgr(color, None), tag);
+    print_with(
+        console,
+        format_args!("{} {}\n", tag, args),
+        format_args!("{} {}\n", colored, args),
+        |writer| {
+            let background = writer.color_code().background();
+            writer.with_color(color, background, |writer| writer.write_string(tag));
+            writeln!(writer, " {}", args)
+        },
+    );
+}
+
+/// Print `args` to the debug console and the kernel log, `serial`, which
+/// is `args` perhaps with escape sequences, to `console`'s serial side, and
+/// have `vga` draw it on the screen if `console` has one.
+fn print_with(
+    console: Console,
+    args: fmt::Arguments,
+    serial: fmt::Arguments,
+    vga: impl FnOnce(&mut vga_buffer::Writer) -> fmt::Result,
+) {
+    if crate::debugcon::is_present() {
+        let _ = crate::debugcon::Debugcon.write_fmt(args);
+    }
+    if console.has_vga()
+        && let Some(writer) = vga_buffer::vt::current_terminal()
+    {
+        let _ = vga(&mut writer.lock());
+    }
+    if console.has_serial() {
+        serial::_print(serial);
+    }
+    crate::klog::record(args);
+}
+
+/// Switch the console to `console`, if the devices it needs are there.
+pub fn select(console: Console) -> Result<(), ConsoleError> {
+    if console.has_vga() && !vga_buffer::is_present() {
+        return Err(ConsoleError::NoVga);
+    }
+    if console.has_serial() && !serial::SERIAL1.is_initialized() {
+        return Err(ConsoleError::NoSerial);
+    }
+    SELECTED.store(console as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Pick the console from the command line, or COM1 if there is no VGA
+/// adapter. Call after [`cmdline::init_from_firmware`] and the console
+/// [stage](crate::init::Stage).
+pub(crate) fn init() {
+    let wanted = cmdline::get_str("console").and_then(Console::parse).unwrap_or(Console::Vga);
+    let console = if vga_buffer::is_present() { wanted } else { Console::Serial };
+    if select(console).is_err() {
+        return;
+    }
+    if console != wanted {
+        crate::println!("console: no vga adapter, using serial");
+    }
+}
+
+#[test_case]
+fn test_parse_round_trips() {
+    for console in [Console::Vga, Console::Serial, Console::Both] {
+        assert_eq!(Console::parse(console.name()), Some(console));
+    }
+    assert_eq!(Console::parse("tty"), None);
+    assert!(Console::Both.has_vga() && Console::Both.has_serial());
+    assert!(!Console::Serial.has_vga());
+}
+
+#[test_case]
+fn test_sgr_matches_the_screen_colors() {
+    use alloc::format;
+
+    assert_eq!(format!("{}", Sgr(Color::Red, Some(Color::Blue))), "\x1b[31;44m");
+    assert_eq!(format!("{}", Sgr(Color::LightRed, None)), "\x1b[91m");
+    assert_eq!(format!("{}", Sgr(Color::Yellow, Some(Color::DarkGray))), "\x1b[93;100m");
+    // The screen reads the sequences back as the same colors.
+    let mut writer = crate::vga_buffer::WRITER.get().lock();
+    for value in 0..16 {
+        let color = Color::from_u8(value);
+        write!(writer, "{}", Sgr(color, Some(color))).unwrap();
+        assert_eq!(writer.color_code(), crate::vga_buffer::ColorCode::new(color, color));
+    }
+    writer.write_string(SGR_RESET);
+    drop(writer);
+
+    let colored = Colored(Sgr(Color::Red, None), "one\n\ntwo");
+    assert_eq!(format!("{}", colored), "\x1b[31mone\x1b[0m\n\n\x1b[31mtwo\x1b[0m");
+}
+
+#[test_case]
+fn test_colored_print_reaches_serial_in_color() {
+    use crate::test_framework::{record_serial, serial_sent};
+
+    record_serial(true);
+    let colored = format_args!("colored on serial\n");
+    print_colored_to(Console::Serial, Color::LightGreen, Color::Blue, colored);
+    print_tagged_to(Console::Serial, "[NOTE]", Color::LightRed, format_args!("tagged on serial"));
+    record_serial(false);
+    assert!(serial_sent("\x1b[92;44mcolored on serial\x1b[0m\n"));
+    assert!(serial_sent("\x1b[91m[NOTE]\x1b[0m tagged on serial\n"));
+}
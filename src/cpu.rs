@@ -0,0 +1,198 @@
+//! CPU identification and feature detection through CPUID.
+//!
+//! CPUID is slow (it traps to the hypervisor under virtualization), so the
+//! results are gathered once and cached. Code that depends on an optional
+//! CPU feature should check [`features`] rather than assume it.
+
+use conquer_once::spin::OnceCell;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::fmt;
+use core::str;
+
+/// Optional CPU features the kernel cares about.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuFeatures {
+    pub sse2: bool,
+    pub sse4_2: bool,
+    pub avx: bool,
+    /// Local APIC present.
+    pub apic: bool,
+    pub x2apic: bool,
+    /// The local APIC timer supports TSC-deadline mode.
+    pub tsc_deadline: bool,
+    /// The TSC runs at a constant rate in every power state.
+    pub invariant_tsc: bool,
+    /// `rdfsbase` and friends are available (still needs CR4.FSGSBASE).
+    pub fsgsbase: bool,
+    /// No-execute page protection.
+    pub nx: bool,
+    pub one_gb_pages: bool,
+    pub rdrand: bool,
+}
+
+/// Lists the supported features, separated by spaces.
+impl fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = [
+            (self.sse2, "sse2"),
+            (self.sse4_2, "sse4.2"),
+            (self.avx, "avx"),
+            (self.apic, "apic"),
+            (self.x2apic, "x2apic"),
+            (self.tsc_deadline, "tsc-deadline"),
+            (self.invariant_tsc, "invariant-tsc"),
+            (self.fsgsbase, "fsgsbase"),
+            (self.nx, "nx"),
+            (self.one_gb_pages, "1g-pages"),
+            (self.rdrand, "rdrand"),
+        ];
+        let mut first = true;
+        for (_, name) in flags.iter().filter(|(present, _)| *present) {
+            if !first {
+                f.write_str(" ")?;
+            }
+            f.write_str(name)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// Everything read from CPUID at first use.
+struct CpuInfo {
+    vendor: [u8; 12],
+    /// `None` if the brand string leaves aren't implemented.
+    brand: Option<[u8; 48]>,
+    features: CpuFeatures,
+}
+
+static INFO: OnceCell<CpuInfo> = OnceCell::uninit();
+
+fn info() -> &'static CpuInfo {
+    INFO.get_or_init(query)
+}
+
+/// Return the CPU's optional features.
+pub fn features() -> CpuFeatures {
+    info().features
+}
+
+/// Return the CPU vendor ID, e.g. `GenuineIntel` or `AuthenticAMD`.
+pub fn vendor_string() -> &'static str {
+    str::from_utf8(&info().vendor).unwrap_or("unknown")
+}
+
+/// Return the processor brand string, or `None` if the CPU doesn't report
+/// one.
+pub fn brand_string() -> Option<&'static str> {
+    let brand = info().brand.as_ref()?;
+    let brand = str::from_utf8(brand).ok()?;
+    Some(brand.trim_matches(|c: char| c == '\0' || c == ' '))
+}
+
+/// Print a one-line summary of the CPU.
+pub fn log_summary() {
+    crate::println!(
+        "cpu: {} ({}): {}",
+        vendor_string(),
+        brand_string().unwrap_or("no brand string"),
+        features()
+    );
+}
+
+/// Return whether bit `n` of `value` is set.
+fn bit(value: u32, n: u32) -> bool {
+    value & (1 << n) != 0
+}
+
+/// Run every CPUID leaf we need. Uncached.
+fn query() -> CpuInfo {
+    // Leaves above the reported maximum return garbage, so every leaf is
+    // checked against it first.
+    let leaf0 = __cpuid(0);
+    let max_leaf = leaf0.eax;
+    let mut vendor = [0; 12];
+    vendor[..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+    vendor[8..].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+    let mut features = CpuFeatures::default();
+    if max_leaf >= 1 {
+        let leaf1 = __cpuid(1);
+        features.sse2 = bit(leaf1.edx, 26);
+        features.apic = bit(leaf1.edx, 9);
+        features.sse4_2 = bit(leaf1.ecx, 20);
+        features.x2apic = bit(leaf1.ecx, 21);
+        features.tsc_deadline = bit(leaf1.ecx, 24);
+        features.avx = bit(leaf1.ecx, 28);
+        features.rdrand = bit(leaf1.ecx, 30);
+    }
+    if max_leaf >= 7 {
+        let leaf7 = __cpuid_count(7, 0);
+        features.fsgsbase = bit(leaf7.ebx, 0);
+    }
+
+    let max_extended = __cpuid(0x8000_0000).eax;
+    // Without extended leaves, this reads back as a basic leaf number.
+    let has_extended = |leaf: u32| max_extended & 0x8000_0000 != 0 && max_extended >= leaf;
+    if has_extended(0x8000_0001) {
+        let leaf = __cpuid(0x8000_0001);
+        features.nx = bit(leaf.edx, 20);
+        features.one_gb_pages = bit(leaf.edx, 26);
+    }
+    if has_extended(0x8000_0007) {
+        features.invariant_tsc = bit(__cpuid(0x8000_0007).edx, 8);
+    }
+    let brand = has_extended(0x8000_0004).then(|| {
+        let mut brand = [0; 48];
+        for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+            let result = __cpuid(leaf);
+            for (j, reg) in [result.eax, result.ebx, result.ecx, result.edx].iter().enumerate() {
+                let start = i * 16 + j * 4;
+                brand[start..start + 4].copy_from_slice(&reg.to_le_bytes());
+            }
+        }
+        brand
+    });
+
+    CpuInfo {
+        vendor,
+        brand,
+        features,
+    }
+}
+
+#[test_case]
+fn test_vendor_is_known() {
+    // QEMU's TCG models report Intel or AMD; KVM passes the host's through.
+    let known = ["GenuineIntel", "AuthenticAMD", "HygonGenuine", "TCGTCGTCGTCG"];
+    assert!(known.contains(&vendor_string()), "unexpected vendor {}", vendor_string());
+}
+
+#[test_case]
+fn test_baseline_features_present() {
+    // SSE2 is part of the x86_64 baseline, and QEMU always models an APIC.
+    let features = features();
+    assert!(features.sse2);
+    assert!(features.apic);
+    if let Some(brand) = brand_string() {
+        assert!(!brand.starts_with(' ') && !brand.ends_with('\0'));
+    }
+}
+
+#[test_case]
+fn test_features_are_cached() {
+    use crate::time::Stopwatch;
+
+    features();
+    let uncached = Stopwatch::start();
+    let fresh = query();
+    let uncached = uncached.elapsed_cycles();
+
+    let cached = Stopwatch::start();
+    let features = features();
+    let cached = cached.elapsed_cycles();
+
+    assert_eq!(features, fresh.features);
+    assert!(cached < uncached, "cached {} vs uncached {} cycles", cached, uncached);
+}
@@ -0,0 +1,484 @@
+//! A crash record that survives a warm reboot.
+//!
+//! [`init`] sets aside [`REGION_SIZE`] bytes at the top of the highest
+//! usable memory region, where the bootloader, which allocates from the
+//! bottom, doesn't reach. The panic and double-fault handlers [`record`]
+//! what they know there: the message, the uptime, the backtrace and the
+//! last [`MAX_KMSG`] bytes of [kernel log](crate::klog). A warm reboot
+//! (`reboot`, a triple fault, QEMU's `system_reset`) leaves RAM alone, so
+//! the next boot finds the record, prints it, keeps a copy for the shell's
+//! `lastcrash` and clears it. A cold boot finds garbage, which the magic and
+//! checksum reject.
+//!
+//! Recording takes no locks and allocates nothing. The kernel log is left
+//! out if it was locked when the kernel crashed. Backtrace addresses are
+//! resolved against the running kernel's symbols, which are only right if
+//! the kernel didn't change across the reboot.
+//!
+//! # Format
+//!
+//! Fields are little-endian:
+//!
+//! | offset | size | field |
+//! |---|---|---|
+//! | 0 | 8 | [`MAGIC`] |
+//! | 8 | 4 | [`VERSION`] |
+//! | 12 | 4 | FNV-1a of bytes 16 to [`RECORD_SIZE`] |
+//! | 16 | 8 | uptime in nanoseconds |
+//! | 24 | 2 | message length, at most [`MAX_MESSAGE`] |
+//! | 26 | 2 | backtrace length, at most [`MAX_FRAMES`] |
+//! | 28 | 4 | kernel log length, at most [`MAX_KMSG`] |
+//! | 32 | 8 × [`MAX_FRAMES`] | backtrace return addresses |
+//! | [`MESSAGE_OFFSET`] | [`MAX_MESSAGE`] | message, UTF-8 |
+//! | [`KMSG_OFFSET`] | [`MAX_KMSG`] | end of the kernel log |
+//!
+//! The magic is cleared first and written last, so a crash while recording
+//! leaves no record rather than a torn one.
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::backtrace::{self, MAX_FRAMES};
+use crate::symbols::Symbol;
+use crate::{klog, memory, println};
+
+/// First eight bytes of a record.
+pub const MAGIC: u64 = u64::from_le_bytes(*b"CHRNCRSH");
+
+/// Format version in the header.
+pub const VERSION: u32 = 1;
+
+/// Bytes set aside for the record.
+pub const REGION_SIZE: u64 = 2 * 4096;
+
+/// Offset of [`MAGIC`].
+pub const MAGIC_OFFSET: usize = 0;
+/// Offset of [`VERSION`].
+pub const VERSION_OFFSET: usize = 8;
+/// Offset of the checksum.
+pub const CHECKSUM_OFFSET: usize = 12;
+/// Offset of the uptime.
+pub const UPTIME_OFFSET: usize = 16;
+/// Offset of the message length.
+pub const MESSAGE_LEN_OFFSET: usize = 24;
+/// Offset of the backtrace length.
+pub const FRAMES_OFFSET: usize = 26;
+/// Offset of the kernel log length.
+pub const KMSG_LEN_OFFSET: usize = 28;
+/// Offset of the backtrace.
+pub const BACKTRACE_OFFSET: usize = 32;
+/// Offset of the message.
+pub const MESSAGE_OFFSET: usize = BACKTRACE_OFFSET + 8 * MAX_FRAMES;
+/// Offset of the kernel log.
+pub const KMSG_OFFSET: usize = MESSAGE_OFFSET + MAX_MESSAGE;
+
+/// Longest message a record holds; longer ones are cut.
+pub const MAX_MESSAGE: usize = 768;
+
+/// Bytes of kernel log a record holds.
+pub const MAX_KMSG: usize = 4096;
+
+/// Bytes a record takes.
+pub const RECORD_SIZE: usize = KMSG_OFFSET + MAX_KMSG;
+
+const _: () = assert!(RECORD_SIZE as u64 <= REGION_SIZE);
+
+/// FNV-1a, 32-bit.
+fn checksum(bytes: impl IntoIterator<Item = u8>) -> u32 {
+    bytes.into_iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// The memory a record is written to, accessed with volatile loads and
+/// stores only.
+struct Region {
+    base: VirtAddr,
+}
+
+impl Region {
+    /// Write `value` at `offset`, which is inside the record and aligned
+    /// for `T`.
+    fn put<T: Copy>(&self, offset: usize, value: T) {
+        debug_assert!(offset + size_of::<T>() <= RECORD_SIZE);
+        // SAFETY: the region is mapped and ours, per `init` or the test.
+        unsafe { (self.base + offset as u64).as_mut_ptr::<T>().write_volatile(value) };
+    }
+
+    /// Read the value at `offset`, as for [`put`](Self::put).
+    fn get<T: Copy>(&self, offset: usize) -> T {
+        // SAFETY: as in `put`.
+        unsafe { (self.base + offset as u64).as_ptr::<T>().read_volatile() }
+    }
+
+    /// Write a whole record.
+    fn write(
+        &self,
+        uptime_ns: u64,
+        message: fmt::Arguments,
+        backtrace: &[u64],
+        kmsg: (&[u8], &[u8]),
+    ) {
+        use core::fmt::Write;
+
+        self.put(MAGIC_OFFSET, 0u64);
+        self.put(VERSION_OFFSET, VERSION);
+        self.put(UPTIME_OFFSET, uptime_ns);
+        let mut writer = MessageWriter { region: self, len: 0 };
+        let _ = writer.write_fmt(message);
+        self.put(MESSAGE_LEN_OFFSET, writer.len as u16);
+        let frames = backtrace.len().min(MAX_FRAMES);
+        for (i, &address) in backtrace[..frames].iter().enumerate() {
+            self.put(BACKTRACE_OFFSET + 8 * i, address);
+        }
+        self.put(FRAMES_OFFSET, frames as u16);
+        let (older, newer) = kmsg;
+        let skip = (older.len() + newer.len()).saturating_sub(MAX_KMSG);
+        let mut kmsg_len = 0;
+        for &byte in older.iter().chain(newer).skip(skip) {
+            self.put(KMSG_OFFSET + kmsg_len, byte);
+            kmsg_len += 1;
+        }
+        self.put(KMSG_LEN_OFFSET, kmsg_len as u32);
+        let sum = checksum((UPTIME_OFFSET..RECORD_SIZE).map(|offset| self.get::<u8>(offset)));
+        self.put(CHECKSUM_OFFSET, sum);
+        self.put(MAGIC_OFFSET, MAGIC);
+    }
+
+    /// Look for a record: copy it to `saved` and clear its magic. Returns
+    /// whether a good one was found, or why the one found is bad.
+    fn check(&self, saved: &mut Saved) -> Result<bool, ParseError> {
+        saved.valid = false;
+        if self.get::<u64>(MAGIC_OFFSET) != MAGIC {
+            return Ok(false);
+        }
+        for (offset, byte) in saved.bytes.iter_mut().enumerate() {
+            *byte = self.get(offset);
+        }
+        self.put(MAGIC_OFFSET, 0u64);
+        parse(&saved.bytes)?;
+        saved.valid = true;
+        Ok(true)
+    }
+}
+
+/// Formats a message into the region, cutting it at [`MAX_MESSAGE`] bytes.
+struct MessageWriter<'a> {
+    region: &'a Region,
+    len: usize,
+}
+
+impl fmt::Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == MAX_MESSAGE {
+                return Err(fmt::Error);
+            }
+            self.region.put(MESSAGE_OFFSET + self.len, byte);
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Address of the region, or zero before [`init`] or if it found no room.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Set while a record is being written, so a crash inside [`record`]
+/// doesn't start another.
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+fn region() -> Option<Region> {
+    match BASE.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(Region { base: VirtAddr::new(base) }),
+    }
+}
+
+/// Record a crash with `message`, the caller's backtrace and the end of
+/// the kernel log. Does nothing before [`init`].
+///
+/// For the panic and double-fault handlers: takes no locks and never
+/// allocates. A later call replaces the record.
+#[inline(never)]
+pub fn record(message: fmt::Arguments) {
+    let Some(region) = region() else {
+        return;
+    };
+    if RECORDING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    let mut addresses = [0; MAX_FRAMES];
+    let count = backtrace::return_addresses(&mut addresses);
+    // Skip our own frame, as `backtrace::print` does.
+    let backtrace = &addresses[count.min(1)..count];
+    let uptime_ns = crate::time::uptime().as_nanos() as u64;
+    let written = klog::with_contents(|older, newer| {
+        region.write(uptime_ns, message, backtrace, (older, newer));
+    });
+    if written.is_none() {
+        region.write(uptime_ns, message, backtrace, (&[], &[]));
+    }
+    RECORDING.store(false, Ordering::Release);
+}
+
+/// A copy of the record the previous boot left.
+struct Saved {
+    bytes: [u8; RECORD_SIZE],
+    valid: bool,
+}
+
+impl Saved {
+    const fn new() -> Self {
+        Saved { bytes: [0; RECORD_SIZE], valid: false }
+    }
+
+    fn record(&self) -> Option<CrashRecord<'_>> {
+        if !self.valid {
+            return None;
+        }
+        parse(&self.bytes).ok()
+    }
+}
+
+static PREVIOUS: Mutex<Saved> = Mutex::new(Saved::new());
+
+/// Set aside the region, then print and clear any record the previous boot
+/// left there.
+///
+/// Must run before the first frame is allocated, like
+/// [`memory::reserve`]. Without a region, [`record`] does nothing.
+pub(crate) fn init(memory_map: &MemoryMap, phys_offset: VirtAddr) {
+    let highest = memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+        .filter(|region| region.range.end_addr() - region.range.start_addr() >= REGION_SIZE)
+        .max_by_key(|region| region.range.end_addr());
+    let Some(highest) = highest else {
+        return;
+    };
+    let end = PhysAddr::new(highest.range.end_addr());
+    let start = end - REGION_SIZE;
+    if let Err(err) = memory::reserve(start, end, memory::ReservedKind::CrashLog) {
+        println!("crashlog: {}", err);
+        return;
+    }
+    let base = phys_offset + start.as_u64();
+    let region = Region { base };
+    let mut saved = PREVIOUS.lock();
+    match region.check(&mut saved) {
+        Ok(true) => {
+            if let Some(record) = saved.record() {
+                println!("previous crash detected\n{}", record);
+            }
+        }
+        Ok(false) => {}
+        Err(err) => println!("crashlog: previous record unreadable: {}", err),
+    }
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+}
+
+/// Write the record the previous boot left to `out`, and return whether
+/// there was one.
+pub fn show_previous(out: &mut dyn fmt::Write) -> Result<bool, fmt::Error> {
+    let saved = PREVIOUS.lock();
+    match saved.record() {
+        Some(record) => write!(out, "{}", record).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+/// A record read back by [`parse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashRecord<'a> {
+    pub uptime: Duration,
+    /// The message, which may have been cut mid-character.
+    pub message: &'a [u8],
+    backtrace: [u64; MAX_FRAMES],
+    frames: usize,
+    /// The end of the kernel log, which usually starts mid-line.
+    pub kmsg: &'a [u8],
+}
+
+impl CrashRecord<'_> {
+    /// Return the return addresses, innermost first.
+    pub fn backtrace(&self) -> &[u64] {
+        &self.backtrace[..self.frames]
+    }
+}
+
+/// Write `bytes` as text, replacing what isn't UTF-8.
+fn write_lossy(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for chunk in bytes.utf8_chunks() {
+        f.write_str(chunk.valid())?;
+        if !chunk.invalid().is_empty() {
+            f.write_str("\u{fffd}")?;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for CrashRecord<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "at {}.{:03}s: ",
+            self.uptime.as_secs(),
+            self.uptime.subsec_millis()
+        )?;
+        write_lossy(f, self.message)?;
+        writeln!(f)?;
+        writeln!(f, "backtrace:")?;
+        for (i, &address) in self.backtrace().iter().enumerate() {
+            writeln!(f, "  #{:<2} {:#018x} {}", i, address, Symbol(address))?;
+        }
+        if !self.kmsg.is_empty() {
+            writeln!(f, "kernel log:")?;
+            write_lossy(f, self.kmsg)?;
+            if !self.kmsg.ends_with(b"\n") {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why [`parse`] couldn't read a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// Shorter than [`RECORD_SIZE`].
+    TooShort,
+    /// Doesn't start with [`MAGIC`].
+    BadMagic,
+    /// A format version this kernel doesn't know.
+    Version(u32),
+    /// The contents don't match the checksum.
+    BadChecksum,
+    /// A length field is larger than its area.
+    BadLength,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TooShort => f.write_str("record cut short"),
+            ParseError::BadMagic => f.write_str("no crash record magic"),
+            ParseError::Version(version) => write!(f, "unknown version {}", version),
+            ParseError::BadChecksum => f.write_str("checksum mismatch"),
+            ParseError::BadLength => f.write_str("bad length"),
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Decode a copy of a record.
+pub fn parse(bytes: &[u8]) -> Result<CrashRecord<'_>, ParseError> {
+    let bytes = bytes.get(..RECORD_SIZE).ok_or(ParseError::TooShort)?;
+    if read_u64(bytes, MAGIC_OFFSET) != MAGIC {
+        return Err(ParseError::BadMagic);
+    }
+    let version = read_u32(bytes, VERSION_OFFSET);
+    if version != VERSION {
+        return Err(ParseError::Version(version));
+    }
+    if read_u32(bytes, CHECKSUM_OFFSET) != checksum(bytes[UPTIME_OFFSET..].iter().copied()) {
+        return Err(ParseError::BadChecksum);
+    }
+    let message_len = usize::from(read_u16(bytes, MESSAGE_LEN_OFFSET));
+    let frames = usize::from(read_u16(bytes, FRAMES_OFFSET));
+    let kmsg_len = read_u32(bytes, KMSG_LEN_OFFSET) as usize;
+    if message_len > MAX_MESSAGE || frames > MAX_FRAMES || kmsg_len > MAX_KMSG {
+        return Err(ParseError::BadLength);
+    }
+    let mut backtrace = [0; MAX_FRAMES];
+    for (i, address) in backtrace[..frames].iter_mut().enumerate() {
+        *address = read_u64(bytes, BACKTRACE_OFFSET + 8 * i);
+    }
+    Ok(CrashRecord {
+        uptime: Duration::from_nanos(read_u64(bytes, UPTIME_OFFSET)),
+        message: &bytes[MESSAGE_OFFSET..MESSAGE_OFFSET + message_len],
+        backtrace,
+        frames,
+        kmsg: &bytes[KMSG_OFFSET..KMSG_OFFSET + kmsg_len],
+    })
+}
+
+/// Region memory for tests.
+#[cfg(test)]
+#[repr(C, align(8))]
+struct TestBuffer([u8; RECORD_SIZE]);
+
+#[cfg(test)]
+impl TestBuffer {
+    fn region(&mut self) -> Region {
+        Region { base: VirtAddr::from_ptr(self.0.as_mut_ptr()) }
+    }
+}
+
+#[test_case]
+fn test_record_survives_to_next_check() {
+    let mut buffer = TestBuffer([0xAA; RECORD_SIZE]);
+    let region = buffer.region();
+    region.write(
+        1_500_000_000,
+        format_args!("kernel panic: {}", "oops"),
+        &[0x1000, 0x2000],
+        (b"older ", b"newer\n"),
+    );
+
+    // What the next boot does.
+    let mut saved = Saved::new();
+    assert_eq!(region.check(&mut saved), Ok(true));
+    let record = saved.record().unwrap();
+    assert_eq!(record.uptime, Duration::from_millis(1500));
+    assert_eq!(record.message, b"kernel panic: oops");
+    assert_eq!(record.backtrace(), &[0x1000, 0x2000]);
+    assert_eq!(record.kmsg, b"older newer\n");
+    let mut text = alloc::string::String::new();
+    fmt::write(&mut text, format_args!("{}", record)).unwrap();
+    let expected = "at 1.500s: kernel panic: oops\nbacktrace:\n  #0  0x0000000000001000";
+    assert!(text.starts_with(expected), "{}", text);
+    assert!(text.ends_with("kernel log:\nolder newer\n"), "{}", text);
+
+    // The magic is gone, so the boot after that finds nothing.
+    assert_eq!(&buffer.0[..8], &[0; 8]);
+    let region = buffer.region();
+    assert_eq!(region.check(&mut saved), Ok(false));
+    assert!(saved.record().is_none());
+}
+
+#[test_case]
+fn test_record_limits_and_corruption() {
+    let mut buffer = TestBuffer([0; RECORD_SIZE]);
+    let region = buffer.region();
+    let kmsg = [b'x'; MAX_KMSG + 100];
+    let backtrace = [7; MAX_FRAMES + 4];
+    region.write(0, format_args!("{:1000}", "long"), &backtrace, (&kmsg[..50], &kmsg[50..]));
+    let record = parse(&buffer.0).unwrap();
+    assert_eq!(record.message.len(), MAX_MESSAGE);
+    assert_eq!(record.backtrace().len(), MAX_FRAMES);
+    assert_eq!(record.kmsg.len(), MAX_KMSG);
+
+    buffer.0[KMSG_OFFSET] ^= 1;
+    assert_eq!(parse(&buffer.0), Err(ParseError::BadChecksum));
+    let mut saved = Saved::new();
+    assert_eq!(buffer.region().check(&mut saved), Err(ParseError::BadChecksum));
+    assert!(saved.record().is_none());
+    assert_eq!(parse(&buffer.0), Err(ParseError::BadMagic));
+    assert_eq!(parse(&buffer.0[..100]), Err(ParseError::TooShort));
+}
@@ -0,0 +1,325 @@
+//! Hardware watchpoints.
+//!
+//! [`watch`] arms one of the four debug address registers (see
+//! [`crate::arch::dr`]) on a 1, 2, 4 or 8 byte location, so that a write,
+//! or any access, raises a `#DB` trap right after the instruction that made
+//! it. The trap handler calls [`handle_trap`], which prints the instruction
+//! that hit, the old and new values and a short backtrace, then carries on
+//! or panics, as the watchpoint's [`OnHit`] says. The trap can land while
+//! the interrupted code holds an output lock, so the report goes out with
+//! [`emergency_println!`], which takes none: to the debug console and COM1
+//! only, not the screen.
+//!
+//! The old value is the one seen when the watchpoint was set or last hit;
+//! the new one is read in the handler. Accesses that don't change the value
+//! show the same value twice.
+//!
+//! Debug registers are per CPU: a watchpoint only catches accesses made on
+//! the CPU that called [`watch`].
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::VirtAddr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::arch::dr::{self, Dr6, Dr7, ADDRESS_REGISTERS};
+use crate::collections::FixedVec;
+use crate::emergency_println;
+use crate::symbols::Symbol;
+use crate::sync::IrqMutex;
+
+/// Most frames [`handle_trap`] prints from the code that hit.
+pub const BACKTRACE_FRAMES: usize = 6;
+
+/// Which accesses a watchpoint catches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    /// Reads and writes.
+    ReadWrite,
+}
+
+impl WatchKind {
+    /// The DR7 R/W code.
+    fn rw_bits(self) -> u64 {
+        match self {
+            WatchKind::Write => 0b01,
+            WatchKind::ReadWrite => 0b11,
+        }
+    }
+}
+
+/// What [`handle_trap`] does after reporting a hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnHit {
+    /// Let the code that hit carry on. The default.
+    Continue,
+    /// Panic, with the hit in the message.
+    Panic,
+}
+
+/// Why a watchpoint couldn't be set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchError {
+    /// All four debug address registers are in use.
+    NoFreeSlot,
+    /// The length isn't 1, 2, 4 or 8.
+    InvalidLength(usize),
+    /// The address isn't a multiple of the length.
+    Misaligned,
+    /// Nothing is mapped at the address.
+    NotMapped,
+}
+
+impl fmt::Display for WatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchError::NoFreeSlot => f.write_str("no free debug register"),
+            WatchError::InvalidLength(len) => write!(f, "length {} is not 1, 2, 4 or 8", len),
+            WatchError::Misaligned => f.write_str("address not aligned to the length"),
+            WatchError::NotMapped => f.write_str("address not mapped"),
+        }
+    }
+}
+
+/// A debug address register in use, returned by [`watch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchSlot(usize);
+
+impl WatchSlot {
+    /// Return the register number, 0 to 3.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// An armed watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: VirtAddr,
+    pub len: usize,
+    pub kind: WatchKind,
+    pub on_hit: OnHit,
+    /// The value when it was set or last hit.
+    pub last: u64,
+}
+
+/// One reported hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub slot: WatchSlot,
+    pub addr: VirtAddr,
+    /// The instruction after the one that made the access.
+    pub rip: u64,
+    pub old: u64,
+    pub new: u64,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "watchpoint {}: {:#x}: {:#x} -> {:#x} at {}",
+            self.slot.0,
+            self.addr.as_u64(),
+            self.old,
+            self.new,
+            Symbol(self.rip)
+        )
+    }
+}
+
+static SLOTS: IrqMutex<[Option<Watchpoint>; ADDRESS_REGISTERS]> =
+    IrqMutex::named("watchpoints", [None; ADDRESS_REGISTERS]);
+
+/// Hits reported since boot.
+static HITS: AtomicU64 = AtomicU64::new(0);
+
+/// The last hit reported.
+static LAST_HIT: IrqMutex<Option<WatchHit>> = IrqMutex::named("last watch hit", None);
+
+/// The DR7 length code for `len` bytes.
+fn len_bits(len: usize) -> Option<u64> {
+    match len {
+        1 => Some(0b00),
+        2 => Some(0b01),
+        4 => Some(0b11),
+        8 => Some(0b10),
+        _ => None,
+    }
+}
+
+/// Read the `len`-byte value at `addr`.
+///
+/// # Safety
+///
+/// `addr` must be mapped, readable and aligned to `len`, which is 1, 2, 4
+/// or 8.
+unsafe fn read_value(addr: VirtAddr, len: usize) -> u64 {
+    unsafe {
+        match len {
+            1 => u64::from(addr.as_ptr::<u8>().read_volatile()),
+            2 => u64::from(addr.as_ptr::<u16>().read_volatile()),
+            4 => u64::from(addr.as_ptr::<u32>().read_volatile()),
+            _ => addr.as_ptr::<u64>().read_volatile(),
+        }
+    }
+}
+
+/// Trap on `kind` accesses to the `len` bytes at `addr`, on this CPU.
+///
+/// Hits are reported and then carry on; see [`set_on_hit`].
+pub fn watch(addr: VirtAddr, len: usize, kind: WatchKind) -> Result<WatchSlot, WatchError> {
+    let len_code = len_bits(len).ok_or(WatchError::InvalidLength(len))?;
+    if !addr.as_u64().is_multiple_of(len as u64) {
+        return Err(WatchError::Misaligned);
+    }
+    if crate::memory::page_flags(addr).is_none() {
+        return Err(WatchError::NotMapped);
+    }
+    let mut slots = SLOTS.lock();
+    let index = slots.iter().position(Option::is_none).ok_or(WatchError::NoFreeSlot)?;
+    // SAFETY: mapped and aligned, checked above.
+    let last = unsafe { read_value(addr, len) };
+    slots[index] = Some(Watchpoint { addr, len, kind, on_hit: OnHit::Continue, last });
+    dr::write_address(index, addr.as_u64());
+    let bits = Dr7::slot_bits(index, kind.rw_bits(), len_code);
+    let dr7 = (Dr7::read() & !Dr7::slot_mask(index)) | bits;
+    // SAFETY: the slot is recorded, so the handler knows the hit.
+    unsafe { Dr7::write(dr7) };
+    Ok(WatchSlot(index))
+}
+
+/// Disarm `slot` and free its register.
+pub fn unwatch(slot: WatchSlot) {
+    let mut slots = SLOTS.lock();
+    // SAFETY: disabling a register can't cause a trap.
+    unsafe { Dr7::write(Dr7::read() & !Dr7::slot_mask(slot.0)) };
+    dr::write_address(slot.0, 0);
+    slots[slot.0] = None;
+}
+
+/// Choose what happens when `slot` is hit.
+pub fn set_on_hit(slot: WatchSlot, on_hit: OnHit) {
+    if let Some(watch) = SLOTS.lock()[slot.0].as_mut() {
+        watch.on_hit = on_hit;
+    }
+}
+
+/// Return the armed watchpoints.
+pub fn watchpoints() -> FixedVec<(WatchSlot, Watchpoint), ADDRESS_REGISTERS> {
+    let mut armed = FixedVec::new();
+    for (index, watch) in SLOTS.lock().iter().enumerate() {
+        if let Some(watch) = watch {
+            let _ = armed.push((WatchSlot(index), *watch));
+        }
+    }
+    armed
+}
+
+/// Return how many hits have been reported since boot.
+pub fn hits() -> u64 {
+    HITS.load(Ordering::Relaxed)
+}
+
+/// Return the last hit reported.
+pub fn last_hit() -> Option<WatchHit> {
+    *LAST_HIT.lock()
+}
+
+/// Report the watchpoints behind a `#DB` trap. Called by the trap handler;
+/// traps with no watchpoint hit (single steps, `int1`) are ignored.
+pub(crate) fn handle_trap(stack_frame: &InterruptStackFrame) {
+    let fired = Dr6::hits();
+    Dr6::clear();
+    // Held only briefly, by `watch` and its kin; a hit meanwhile is dropped.
+    let Some(mut slots) = SLOTS.try_lock() else {
+        return;
+    };
+    let mut panic_on = None;
+    for index in (0..ADDRESS_REGISTERS).filter(|index| fired & (1 << index) != 0) {
+        let Some(watch) = slots[index].as_mut() else {
+            continue;
+        };
+        // SAFETY: checked when the watchpoint was set, and the access just
+        // succeeded.
+        let new = unsafe { read_value(watch.addr, watch.len) };
+        let hit = WatchHit {
+            slot: WatchSlot(index),
+            addr: watch.addr,
+            rip: stack_frame.instruction_pointer.as_u64(),
+            old: watch.last,
+            new,
+        };
+        watch.last = new;
+        HITS.fetch_add(1, Ordering::Relaxed);
+        // Only `last_hit` takes it, briefly; a hit inside it isn't kept.
+        if let Some(mut last) = LAST_HIT.try_lock() {
+            *last = Some(hit);
+        }
+        emergency_println!("{}", hit);
+        if watch.on_hit == OnHit::Panic {
+            panic_on = Some(hit);
+        }
+    }
+    drop(slots);
+    if fired != 0 {
+        print_backtrace(stack_frame.instruction_pointer.as_u64());
+    }
+    if let Some(hit) = panic_on {
+        panic!("{}", hit);
+    }
+}
+
+/// Print the frames from the code at `rip` outwards, leaving out the
+/// handler's own.
+#[inline(never)]
+fn print_backtrace(rip: u64) {
+    let mut addresses = [0; crate::backtrace::MAX_FRAMES];
+    let count = crate::backtrace::return_addresses(&mut addresses);
+    let addresses = &addresses[..count];
+    // The trap handler's frame record holds the interrupted RIP.
+    let start = addresses.iter().position(|&address| address == rip).unwrap_or(0);
+    for (i, &address) in addresses[start..].iter().take(BACKTRACE_FRAMES).enumerate() {
+        emergency_println!("  #{:<2} {:#018x} {}", i, address, Symbol(address));
+    }
+}
+
+#[test_case]
+fn test_watchpoint_reports_writer_and_values() {
+    static WATCHED: AtomicU64 = AtomicU64::new(5);
+
+    #[inline(never)]
+    fn poke(value: u64) {
+        WATCHED.store(value, Ordering::Relaxed);
+    }
+
+    let slot = watch(VirtAddr::from_ptr(&WATCHED), 8, WatchKind::Write).unwrap();
+    let before = hits();
+    poke(7);
+    unwatch(slot);
+    poke(9);
+
+    assert_eq!(hits(), before + 1);
+    let hit = last_hit().unwrap();
+    assert_eq!((hit.slot, hit.old, hit.new), (slot, 5, 7));
+    let (_, offset) = crate::symbols::resolve(hit.rip).expect("rip in a known function");
+    assert_eq!(hit.rip - offset as u64, poke as fn(u64) as usize as u64);
+}
+
+#[test_case]
+fn test_watch_rejects_bad_requests() {
+    static WORD: AtomicU64 = AtomicU64::new(0);
+
+    let addr = VirtAddr::from_ptr(&WORD);
+    assert_eq!(watch(addr, 3, WatchKind::Write), Err(WatchError::InvalidLength(3)));
+    assert_eq!(watch(addr + 1u64, 4, WatchKind::Write), Err(WatchError::Misaligned));
+    let mut slots: FixedVec<WatchSlot, ADDRESS_REGISTERS> = FixedVec::new();
+    while let Ok(slot) = watch(addr, 8, WatchKind::Write) {
+        slots.push(slot).unwrap();
+    }
+    assert_eq!(watch(addr, 8, WatchKind::Write), Err(WatchError::NoFreeSlot));
+    for &slot in slots.iter() {
+        unwatch(slot);
+    }
+}
@@ -0,0 +1,154 @@
+//! QEMU and Bochs debug console on port `0xE9`.
+//!
+//! Every byte written to the port shows up on the host (`-debugcon stdio`
+//! or `-debugcon file:<path>`). The device needs no setup, so it works
+//! before the serial port or the VGA writer exist. Reading the port returns
+//! `0xE9` when the device is there; [`is_present`] probes once and keeps
+//! the answer. On real hardware nothing answers and output here is dropped.
+//!
+//! [`print!`](crate::print) output goes here first whenever the device is
+//! present, so lines printed before [`SERIAL1`](crate::serial::SERIAL1) is
+//! set up aren't lost. [`early_print!`](crate::early_print) writes here
+//! alone, without locks, allocation or anything that needs init, for code
+//! that runs before anything else is set up. Writes skip the
+//! [port trace](crate::arch::port), which they would otherwise flood.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::instructions::port::PortWrite;
+
+use crate::arch::port::{Port, PortGroup};
+
+/// What a present device returns when its port is read.
+const ANSWER: u8 = 0xE9;
+
+const UNPROBED: u8 = 0;
+const ABSENT: u8 = 1;
+const PRESENT: u8 = 2;
+
+static STATE: AtomicU8 = AtomicU8::new(UNPROBED);
+
+/// The debug console's single port.
+pub struct DebugconPorts {
+    pub data: Port<u8>,
+}
+
+impl PortGroup for DebugconPorts {
+    const DEVICE: &'static str = "debugcon";
+    const BASE: u16 = 0xE9;
+
+    fn at(base: u16) -> Self {
+        DebugconPorts { data: Port::new(Self::DEVICE, base) }
+    }
+}
+
+/// Return whether a debug console answers at `ports`.
+pub fn probe(ports: &DebugconPorts) -> bool {
+    // Reading has no side effects; the port holds no state.
+    unsafe { ports.data.read() == ANSWER }
+}
+
+/// Return whether the standard debug console is present. Probes on the
+/// first call.
+pub fn is_present() -> bool {
+    match STATE.load(Ordering::Relaxed) {
+        PRESENT => true,
+        ABSENT => false,
+        _ => {
+            // Racing probes get the same answer, so either store is fine.
+            let present = probe(&DebugconPorts::standard());
+            STATE.store(if present { PRESENT } else { ABSENT }, Ordering::Relaxed);
+            present
+        }
+    }
+}
+
+/// Writes to the debug console; does nothing if it isn't present.
+pub struct Debugcon;
+
+impl fmt::Write for Debugcon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !is_present() {
+            return Ok(());
+        }
+        for byte in s.bytes() {
+            unsafe { u8::write_to_port(DebugconPorts::BASE, byte) };
+        }
+        #[cfg(test)]
+        capture(s);
+        Ok(())
+    }
+}
+
+/// Internal print function used by the `early_print!` macro.
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let _ = Debugcon.write_fmt(args);
+}
+
+/// Prints formatted text to the debug console only, without locks or
+/// allocation; usable from the very first instruction of the kernel.
+#[macro_export]
+macro_rules! early_print {
+    ($($arg:tt)*) => ($crate::debugcon::_print(format_args!($($arg)*)));
+}
+
+/// Like [`early_print!`], appending a newline.
+#[macro_export]
+macro_rules! early_println {
+    () => ($crate::early_print!("\n"));
+    ($($arg:tt)*) => ($crate::early_print!("{}\n", format_args!($($arg)*)));
+}
+
+/// What tests wrote to the debug console, which they can't read back from
+/// the port.
+#[cfg(test)]
+static CAPTURE: spin::Mutex<Option<alloc::string::String>> = spin::Mutex::new(None);
+
+#[cfg(test)]
+fn capture(s: &str) {
+    if let Some(mut capture) = CAPTURE.try_lock()
+        && let Some(text) = capture.as_mut()
+    {
+        text.push_str(s);
+    }
+}
+
+/// Run `f` and return what it wrote to the debug console.
+#[cfg(test)]
+pub(crate) fn captured(f: impl FnOnce()) -> alloc::string::String {
+    *CAPTURE.lock() = Some(alloc::string::String::new());
+    f();
+    CAPTURE.lock().take().unwrap_or_default()
+}
+
+#[test_case]
+fn test_present_under_qemu() {
+    // The test runner starts QEMU with `-debugcon`.
+    assert!(is_present());
+    assert!(probe(&DebugconPorts::standard()));
+}
+
+#[test_case]
+fn test_probe_without_device() {
+    // Nothing decodes the port next to it, so reads float high.
+    assert!(!probe(&DebugconPorts::at(DebugconPorts::BASE + 1)));
+}
+
+#[test_case]
+fn test_output_follows_print_order() {
+    use crate::console::{self, Console};
+
+    let previous = console::selected();
+    console::select(Console::Serial).unwrap();
+    let out = captured(|| {
+        crate::early_println!("early {}", 1);
+        crate::println!("both {}", 2);
+        crate::serial_println!("serial only");
+        crate::println!("both {}", 3);
+    });
+    console::select(previous).unwrap();
+    assert_eq!(out, "early 1\nboth 2\nboth 3\n");
+}
@@ -0,0 +1,204 @@
+//! Output for the panic and double-fault paths.
+//!
+//! A panic that strikes while [`WRITER`](crate::vga_buffer::WRITER) or
+//! [`SERIAL1`](crate::serial::SERIAL1) is locked would deadlock the panic
+//! handler on its first `println!`, and the host would see nothing at all.
+//! So the handlers call [`take_over`] first: it disables interrupts and
+//! force-unlocks every output lock [`register`]ed here. Then they write
+//! their first line with [`emergency_println!`](crate::emergency_println),
+//! which takes no lock at all. It writes straight to the
+//! [debug console](crate::debugcon) and to COM1's data port, polling the
+//! line status register itself. After that, the usual locked output can
+//! follow.
+//!
+//! Output printed by the code that held a lock may be cut off mid-line.
+//! If another CPU was printing, its output and the panic's may interleave.
+//! Both are better than silence.
+
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+use crate::collections::FixedVec;
+use crate::debugcon;
+use crate::sync::{Global, IrqMutex};
+
+/// Most output locks that can be registered.
+pub const MAX_SINKS: usize = 8;
+
+/// COM1's data and line status ports.
+const UART_DATA: u16 = 0x3F8;
+const UART_LINE_STATUS: u16 = 0x3FD;
+/// Line status bit set when the transmit register can take a byte.
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+/// Line status polls per byte before writing it anyway.
+const TRANSMIT_POLLS: u32 = 100_000;
+
+/// A registered lock: the [`Global`] holding it, and a function that
+/// force-unlocks it and returns its name if it was locked.
+struct Sink {
+    global: AtomicPtr<()>,
+    release: AtomicPtr<()>,
+    ready: AtomicBool,
+}
+
+impl Sink {
+    const fn new() -> Self {
+        Sink {
+            global: AtomicPtr::new(ptr::null_mut()),
+            release: AtomicPtr::new(ptr::null_mut()),
+            ready: AtomicBool::new(false),
+        }
+    }
+}
+
+static SINKS: [Sink; MAX_SINKS] = [const { Sink::new() }; MAX_SINKS];
+static SINK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Force-unlock the lock in `global`, if it is initialized and locked, and
+/// return its name.
+///
+/// # Safety
+///
+/// `global` must point to a `Global<IrqMutex<T>>`, and no guard of the lock
+/// may be used afterwards.
+unsafe fn release<T>(global: *const ()) -> Option<&'static str> {
+    let global = unsafe { &*(global as *const Global<IrqMutex<T>>) };
+    let lock = global.try_get().ok()?;
+    if !lock.is_locked() {
+        return None;
+    }
+    unsafe { lock.force_unlock() };
+    Some(lock.name().unwrap_or("<unnamed>"))
+}
+
+/// Have [`take_over`] force-unlock the lock in `global`.
+///
+/// The global need not be initialized yet. Registering the same global
+/// twice has no effect. Returns `false` if [`MAX_SINKS`] locks are already
+/// registered.
+pub fn register<T>(global: &'static Global<IrqMutex<T>>) -> bool {
+    let address = global as *const _ as *mut ();
+    let registered = SINK_COUNT.load(Ordering::Acquire).min(MAX_SINKS);
+    if SINKS[..registered].iter().any(|sink| sink.global.load(Ordering::Relaxed) == address) {
+        return true;
+    }
+    let index = SINK_COUNT.fetch_add(1, Ordering::AcqRel);
+    let Some(sink) = SINKS.get(index) else {
+        return false;
+    };
+    sink.global.store(address, Ordering::Relaxed);
+    sink.release.store(release::<T> as *mut (), Ordering::Relaxed);
+    sink.ready.store(true, Ordering::Release);
+    true
+}
+
+/// Names of the locks [`take_over`] found held.
+pub type Released = FixedVec<&'static str, MAX_SINKS>;
+
+/// Disable interrupts and force-unlock every registered output lock.
+///
+/// Returns the names of the locks that were held.
+///
+/// # Safety
+///
+/// Whoever held those locks must never use their guards again: call this
+/// only on a path that doesn't return to them, such as a panic handler or
+/// a task recovery that abandons the panicking task's stack.
+pub unsafe fn take_over() -> Released {
+    interrupts::disable();
+    let mut released = Released::new();
+    let registered = SINK_COUNT.load(Ordering::Acquire).min(MAX_SINKS);
+    for sink in &SINKS[..registered] {
+        if !sink.ready.load(Ordering::Acquire) {
+            continue;
+        }
+        let release = sink.release.load(Ordering::Relaxed);
+        // SAFETY: `register` stored `release::<T>` for the `T` of this
+        // global. The holders are abandoned, as the caller promises.
+        let release: unsafe fn(*const ()) -> Option<&'static str> =
+            unsafe { core::mem::transmute(release) };
+        if let Some(name) = unsafe { release(sink.global.load(Ordering::Relaxed)) } {
+            let _ = released.push(name);
+        }
+    }
+    #[cfg(feature = "lock-order")]
+    crate::sync::lock_order::forget_held();
+    released
+}
+
+/// Write one byte to COM1, waiting a bounded time for the transmitter.
+fn uart_write(byte: u8) {
+    for _ in 0..TRANSMIT_POLLS {
+        if unsafe { u8::read_from_port(UART_LINE_STATUS) } & TRANSMIT_EMPTY != 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { u8::write_to_port(UART_DATA, byte) };
+    crate::serial::tap(&[byte]);
+}
+
+/// Writes to the debug console and COM1 without taking any lock.
+pub struct Emergency;
+
+impl fmt::Write for Emergency {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                uart_write(b'\r');
+            }
+            uart_write(byte);
+        }
+        fmt::Write::write_str(&mut debugcon::Debugcon, s)
+    }
+}
+
+/// Internal print function used by the `emergency_println!` macro.
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let _ = Emergency.write_fmt(args);
+}
+
+/// Prints a line to the debug console and COM1 without locks, for panic
+/// and double-fault paths; see [`emergency`](crate::emergency).
+#[macro_export]
+macro_rules! emergency_println {
+    () => ($crate::emergency::_print(format_args!("\n")));
+    ($($arg:tt)*) => ($crate::emergency::_print(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+#[test_case]
+fn test_take_over_releases_held_locks() {
+    static SINK: Global<IrqMutex<u32>> = Global::new("SINK");
+    static UNUSED: Global<IrqMutex<u32>> = Global::new("UNUSED");
+
+    assert!(register(&SINK));
+    assert!(register(&UNUSED));
+    let count = SINK_COUNT.load(Ordering::Relaxed);
+    assert!(register(&SINK));
+    assert_eq!(SINK_COUNT.load(Ordering::Relaxed), count);
+    let sink = SINK.init(IrqMutex::named("test sink", 0)).unwrap();
+
+    interrupts::without_interrupts(|| {
+        core::mem::forget(sink.lock());
+        let released = unsafe { take_over() };
+        assert_eq!(&released[..], ["test sink"]);
+        assert!(!interrupts::are_enabled());
+        assert!(sink.try_lock().is_some());
+        // Nothing is held the second time.
+        assert!(unsafe { take_over() }.is_empty());
+    });
+}
+
+#[test_case]
+fn test_emergency_output_reaches_debugcon() {
+    let out = debugcon::captured(|| {
+        crate::emergency_println!("emergency {}", 1);
+    });
+    assert_eq!(out, "emergency 1\n");
+}
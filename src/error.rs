@@ -0,0 +1,303 @@
+//! The kernel-wide error type.
+//!
+//! Each module keeps its own error enum, which says precisely what went
+//! wrong. [`KernelError`] wraps any of them, so code that calls into several
+//! modules can use `?` throughout and leave its caller to decide whether to
+//! degrade or panic.
+//!
+//! A `KernelError` displays on one line as `module: message`, for the boot
+//! log. [`KernelError::code`] packs the module and variant into a `u32` for
+//! callers that can only pass numbers, such as a future syscall layer.
+//!
+//! [`bail!`](crate::bail) and [`ensure!`](crate::ensure) return early with
+//! any error that converts into a `KernelError`.
+
+use core::fmt;
+
+use crate::acpi::AcpiError;
+use crate::ata::AtaError;
+use crate::fs::TarError;
+use crate::init::InitError;
+use crate::loader::elf::LoadError;
+use crate::memory::{MemError, ReserveError};
+use crate::net::NetError;
+use crate::process::ProcessError;
+use crate::ramdisk::RamdiskError;
+use crate::rtc::RtcError;
+use crate::serial::SerialError;
+use crate::smp::SmpError;
+use crate::sync::GlobalError;
+use crate::task::keyboard::Ps2Error;
+
+/// An error from any kernel module.
+#[derive(Debug)]
+pub enum KernelError {
+    Init(InitError),
+    Mem(MemError),
+    Ata(AtaError),
+    Serial(SerialError),
+    Ps2(Ps2Error),
+    Ramdisk(RamdiskError),
+    Fs(TarError),
+    Rtc(RtcError),
+    Smp(SmpError),
+    Global(GlobalError),
+    Loader(LoadError),
+    Process(ProcessError),
+    Acpi(AcpiError),
+    Net(NetError),
+}
+
+impl KernelError {
+    /// Name of the module the error came from.
+    pub fn module(&self) -> &'static str {
+        match self {
+            KernelError::Init(_) => "init",
+            KernelError::Mem(_) => "mem",
+            KernelError::Ata(_) => "ata",
+            KernelError::Serial(_) => "serial",
+            KernelError::Ps2(_) => "ps2",
+            KernelError::Ramdisk(_) => "ramdisk",
+            KernelError::Fs(_) => "fs",
+            KernelError::Rtc(_) => "rtc",
+            KernelError::Smp(_) => "smp",
+            KernelError::Global(_) => "sync",
+            KernelError::Loader(_) => "loader",
+            KernelError::Process(_) => "process",
+            KernelError::Acpi(_) => "acpi",
+            KernelError::Net(_) => "net",
+        }
+    }
+
+    /// The error as a number: the module in the high 16 bits and the
+    /// variant, counting from 1, in the low 16. Payloads are dropped.
+    pub fn code(&self) -> u32 {
+        let (module, variant) = match self {
+            KernelError::Init(err) => (1, match err {
+                InitError::NoUsableMemory => 1,
+                InitError::HeapUnusable => 2,
+                InitError::DependencyFailed(_) => 3,
+                InitError::Injected => 4,
+            }),
+            KernelError::Mem(err) => (2, match err {
+                MemError::FrameAllocationFailed => 1,
+                MemError::ParentEntryHugePage => 2,
+                MemError::PageAlreadyMapped(_) => 3,
+                MemError::Reserve(ReserveError::TooLate) => 4,
+                MemError::Reserve(ReserveError::Full) => 5,
+                MemError::UserRangeInUse => 6,
+                MemError::NotMapped(_) => 7,
+            }),
+            KernelError::Ata(err) => (3, match err {
+                AtaError::NoDrive => 1,
+                AtaError::Timeout => 2,
+                AtaError::DeviceError(_) => 3,
+                AtaError::BufferTooSmall => 4,
+                AtaError::OutOfRange => 5,
+            }),
+            KernelError::Serial(err) => (4, match err {
+                SerialError::NotPresent => 1,
+                SerialError::Loopback(_) => 2,
+            }),
+            KernelError::Ps2(err) => (5, match err {
+                Ps2Error::NoController => 1,
+                Ps2Error::Timeout => 2,
+                Ps2Error::Resend => 3,
+                Ps2Error::SelfTestFailed => 4,
+            }),
+            KernelError::Ramdisk(err) => (6, match err {
+                RamdiskError::NotMapped(_) => 1,
+                RamdiskError::Reserve(_) => 2,
+                RamdiskError::Map(_) => 3,
+            }),
+            KernelError::Fs(err) => (7, match err {
+                TarError::Truncated { .. } => 1,
+                TarError::BadChecksum { .. } => 2,
+                TarError::BadHeader { .. } => 3,
+            }),
+            KernelError::Rtc(err) => (8, match err {
+                RtcError::Busy => 1,
+                RtcError::Invalid => 2,
+            }),
+            KernelError::Smp(err) => (9, match err {
+                SmpError::NoApic => 1,
+                SmpError::TrampolineUnavailable => 2,
+                SmpError::Map(_) => 3,
+                SmpError::ApTimeout(_) => 4,
+            }),
+            KernelError::Global(err) => (10, match err {
+                GlobalError::UsedBeforeInit(_) => 1,
+                GlobalError::AlreadyInitialized(_) => 2,
+            }),
+            KernelError::Loader(err) => (11, match err {
+                LoadError::Truncated => 1,
+                LoadError::BadMagic => 2,
+                LoadError::WrongClass(_) => 3,
+                LoadError::WrongEndian(_) => 4,
+                LoadError::WrongMachine(_) => 5,
+                LoadError::Relocatable => 6,
+                LoadError::Dynamic => 7,
+                LoadError::BadType(_) => 8,
+                LoadError::BadProgramHeaderSize(_) => 9,
+                LoadError::NoSegments => 10,
+                LoadError::BadSegment(_) => 11,
+                LoadError::OutsideUserRange(_) => 12,
+                LoadError::WriteExecute(_) => 13,
+                LoadError::Overlap(_) => 14,
+                LoadError::BadEntry(_) => 15,
+                LoadError::Map(_) => 16,
+            }),
+            KernelError::Process(err) => (12, match err {
+                ProcessError::Busy => 1,
+            }),
+            KernelError::Acpi(err) => (13, match err {
+                AcpiError::NoRsdp => 1,
+                AcpiError::BadChecksum(_) => 2,
+                AcpiError::BadLength(_) => 3,
+            }),
+            KernelError::Net(err) => (14, match err {
+                NetError::NoDevice => 1,
+                NetError::NoRegisters => 2,
+                NetError::NoDmaMemory => 3,
+                NetError::Map(_) => 4,
+                NetError::Timeout => 5,
+                NetError::NoIrq => 6,
+                NetError::Irq(_) => 7,
+                NetError::TooShort(_) => 8,
+                NetError::TooLong(_) => 9,
+                NetError::TxFull => 10,
+                NetError::Busy => 11,
+            }),
+        };
+        (module << 16) | variant
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.module())?;
+        match self {
+            KernelError::Init(err) => write!(f, "{}", err),
+            KernelError::Mem(err) => write!(f, "{}", err),
+            KernelError::Ata(err) => write!(f, "{}", err),
+            KernelError::Serial(err) => write!(f, "{}", err),
+            KernelError::Ps2(err) => write!(f, "{}", err),
+            KernelError::Ramdisk(err) => write!(f, "{}", err),
+            KernelError::Fs(err) => write!(f, "{}", err),
+            KernelError::Rtc(err) => write!(f, "{}", err),
+            KernelError::Smp(err) => write!(f, "{}", err),
+            KernelError::Global(err) => write!(f, "{}", err),
+            KernelError::Loader(err) => write!(f, "{}", err),
+            KernelError::Process(err) => write!(f, "{}", err),
+            KernelError::Acpi(err) => write!(f, "{}", err),
+            KernelError::Net(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+macro_rules! impl_from {
+    ($($variant:ident($error:ty)),* $(,)?) => {
+        $(
+            impl From<$error> for KernelError {
+                fn from(err: $error) -> Self {
+                    KernelError::$variant(err)
+                }
+            }
+        )*
+    };
+}
+
+impl_from! {
+    Init(InitError),
+    Mem(MemError),
+    Ata(AtaError),
+    Serial(SerialError),
+    Ps2(Ps2Error),
+    Ramdisk(RamdiskError),
+    Fs(TarError),
+    Rtc(RtcError),
+    Smp(SmpError),
+    Global(GlobalError),
+    Loader(LoadError),
+    Process(ProcessError),
+    Acpi(AcpiError),
+    Net(NetError),
+}
+
+impl From<ReserveError> for KernelError {
+    fn from(err: ReserveError) -> Self {
+        KernelError::Mem(MemError::Reserve(err))
+    }
+}
+
+/// Return early with `Err(KernelError::from(err))`.
+#[macro_export]
+macro_rules! bail {
+    ($err:expr) => {
+        return Err($crate::error::KernelError::from($err))
+    };
+}
+
+/// [`bail!`] with `err` unless `cond` holds.
+#[macro_export]
+macro_rules! ensure {
+    ($cond:expr, $err:expr) => {
+        if !$cond {
+            $crate::bail!($err);
+        }
+    };
+}
+
+#[test_case]
+fn test_conversions_keep_context() {
+    use x86_64::structures::paging::mapper::MapToError;
+
+    let err = KernelError::from(MemError::from(MapToError::FrameAllocationFailed));
+    assert_eq!(err.module(), "mem");
+    assert_eq!(err.code(), 0x0002_0001);
+    assert_eq!(alloc::format!("{}", err), "mem: frame allocation failed");
+
+    let err = KernelError::from(AtaError::DeviceError(0x04));
+    assert_eq!(err.code(), 0x0003_0003);
+    assert_eq!(alloc::format!("{}", err), "ata: drive error 0x04");
+
+    let err = KernelError::from(ReserveError::Full);
+    assert_eq!(err.code() >> 16, 2);
+
+    let err = KernelError::from(AcpiError::BadChecksum(crate::acpi::Signature(*b"APIC")));
+    assert_eq!(err.code(), 0x000d_0002);
+    assert_eq!(alloc::format!("{}", err), "acpi: bad checksum in APIC");
+
+    let check = |ok: bool| -> Result<u32, KernelError> {
+        ensure!(ok, RtcError::Busy);
+        Ok(1)
+    };
+    assert_eq!(check(true).ok(), Some(1));
+    assert_eq!(check(false).map_err(|err| err.code()), Err(0x0008_0001));
+}
+
+#[test_case]
+fn test_display_is_single_line() {
+    use crate::init::Stage;
+
+    let errors = [
+        KernelError::from(InitError::DependencyFailed(Stage::Memory)),
+        KernelError::from(MemError::Reserve(ReserveError::TooLate)),
+        KernelError::from(SerialError::NotPresent),
+        KernelError::from(Ps2Error::NoController),
+        KernelError::from(TarError::BadChecksum { offset: 0x200 }),
+        KernelError::from(SmpError::ApTimeout(3)),
+        KernelError::from(GlobalError::UsedBeforeInit("WRITER")),
+        KernelError::from(AcpiError::BadChecksum(crate::acpi::Signature(*b"FACP"))),
+    ];
+    let mut codes = alloc::vec::Vec::new();
+    for err in &errors {
+        let line = alloc::format!("{}", err);
+        assert!(!line.contains('\n'), "{:?}", line);
+        assert!(line.starts_with(err.module()), "{}", line);
+        codes.push(err.code());
+    }
+    codes.sort_unstable();
+    codes.dedup();
+    assert_eq!(codes.len(), errors.len());
+}
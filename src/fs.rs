@@ -0,0 +1,38 @@
+//! Filesystems.
+//!
+//! Only the boot ramdisk is mounted so far: [`init`] opens it as a
+//! [`TarFs`] and [`root`] returns it. Files received at run time live in
+//! the flat in-memory table of [`mem`].
+
+use conquer_once::spin::OnceCell;
+
+use crate::println;
+
+pub mod mem;
+pub mod tar;
+
+pub use tar::{EntryKind, File, TarError, TarFs};
+
+static ROOT: OnceCell<TarFs> = OnceCell::uninit();
+
+/// Open the ramdisk as the root filesystem.
+///
+/// Needs the ramdisk mapped (see [`crate::ramdisk::init`]). Only the first
+/// successful call mounts.
+pub fn init() -> Result<(), TarError> {
+    let fs = TarFs::new(crate::ramdisk::data())?;
+    let mut fresh = false;
+    ROOT.init_once(|| {
+        fresh = true;
+        fs
+    });
+    if fresh && crate::klog::level() > 0 {
+        println!("fs: ramdisk mounted, {} entries", fs.entries().count());
+    }
+    Ok(())
+}
+
+/// Return the root filesystem, if [`init`] mounted one.
+pub fn root() -> Option<&'static TarFs> {
+    ROOT.get()
+}
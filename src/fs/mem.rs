@@ -0,0 +1,106 @@
+//! Files held in memory, such as those the shell's `recv` receives.
+//!
+//! A small flat table of named byte buffers. Names are compared without a
+//! leading `/`, so `/blob` and `blob` are the same file. Readers get a
+//! shared handle, so a file replaced or removed while being read stays
+//! intact for them.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Most files the table holds.
+pub const MAX_FILES: usize = 8;
+
+static FILES: Mutex<Vec<(String, Arc<[u8]>)>> = Mutex::new(Vec::new());
+
+/// The table already holds [`MAX_FILES`] files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableFull;
+
+impl fmt::Display for TableFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "all {} in-memory files are in use", MAX_FILES)
+    }
+}
+
+fn key(name: &str) -> &str {
+    name.trim_start_matches('/')
+}
+
+/// Store `data` as `name`, replacing any file of that name.
+pub fn insert(name: &str, data: Vec<u8>) -> Result<(), TableFull> {
+    let name = key(name);
+    let data = Arc::from(data);
+    interrupts::without_interrupts(|| {
+        let mut files = FILES.lock();
+        if let Some((_, existing)) = files.iter_mut().find(|(n, _)| n == name) {
+            *existing = data;
+        } else if files.len() == MAX_FILES {
+            return Err(TableFull);
+        } else {
+            files.push((String::from(name), data));
+        }
+        Ok(())
+    })
+}
+
+/// Return the file called `name`.
+pub fn get(name: &str) -> Option<Arc<[u8]>> {
+    let name = key(name);
+    interrupts::without_interrupts(|| {
+        FILES.lock().iter().find(|(n, _)| n == name).map(|(_, data)| data.clone())
+    })
+}
+
+/// Remove the file called `name`, returning whether there was one.
+pub fn remove(name: &str) -> bool {
+    let name = key(name);
+    interrupts::without_interrupts(|| {
+        let mut files = FILES.lock();
+        let before = files.len();
+        files.retain(|(n, _)| n != name);
+        files.len() != before
+    })
+}
+
+/// Return the names and sizes of all files, oldest first.
+pub fn list() -> Vec<(String, usize)> {
+    interrupts::without_interrupts(|| {
+        FILES.lock().iter().map(|(name, data)| (name.clone(), data.len())).collect()
+    })
+}
+
+#[test_case]
+fn test_insert_replace_remove() {
+    insert("/test-mem", alloc::vec![1, 2, 3]).unwrap();
+    assert_eq!(get("test-mem").as_deref(), Some(&[1, 2, 3][..]));
+    let held = get("test-mem").unwrap();
+    insert("test-mem", alloc::vec![4]).unwrap();
+    assert_eq!(*held, [1, 2, 3]);
+    assert_eq!(get("/test-mem").as_deref(), Some(&[4][..]));
+    assert!(list().contains(&(String::from("test-mem"), 1)));
+    assert!(remove("test-mem"));
+    assert!(!remove("test-mem"));
+    assert!(get("test-mem").is_none());
+}
+
+#[test_case]
+fn test_table_full() {
+    let names: Vec<String> = (0..MAX_FILES).map(|i| alloc::format!("full-{}", i)).collect();
+    let free = MAX_FILES - list().len();
+    for name in &names[..free] {
+        insert(name, Vec::new()).unwrap();
+    }
+    assert_eq!(insert("one-more", Vec::new()), Err(TableFull));
+    // Replacing needs no new slot.
+    if let Some(name) = names[..free].last() {
+        assert_eq!(insert(name, alloc::vec![0]), Ok(()));
+    }
+    for name in &names[..free] {
+        remove(name);
+    }
+}
@@ -0,0 +1,429 @@
+//! Read-only ustar archives.
+//!
+//! [`TarFs::new`] walks every header once, checking its checksum and that
+//! the entry's data fits in the archive, so later lookups can't run into a
+//! malformed archive. Nothing is copied: files are slices of the archive.
+//!
+//! Paths are normalized: the ustar `prefix` field is joined to the name, and
+//! leading `./` or `/` and trailing `/` are dropped, so `./bin/` is `bin`
+//! and the root directory is the empty path. Symbolic links are listed with
+//! their target but not followed.
+
+use core::fmt;
+
+/// Size of a header and the unit file data is padded to.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Longest path a header can hold: a 155-byte prefix, a slash and a
+/// 100-byte name.
+const MAX_PATH: usize = 256;
+
+/// Why an archive was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarError {
+    /// The archive ends inside the header or data at this offset.
+    Truncated { offset: usize },
+    /// The header at this offset doesn't match its checksum.
+    BadChecksum { offset: usize },
+    /// The header at this offset has an unreadable field.
+    BadHeader { offset: usize },
+}
+
+impl fmt::Display for TarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TarError::Truncated { offset } => write!(f, "archive truncated at {:#x}", offset),
+            TarError::BadChecksum { offset } => write!(f, "bad header checksum at {:#x}", offset),
+            TarError::BadHeader { offset } => write!(f, "malformed header at {:#x}", offset),
+        }
+    }
+}
+
+/// What an entry is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// A symbolic link to the given target, which isn't resolved.
+    Symlink(&'static str),
+    /// Any other type (hard links, devices, FIFOs), by type flag.
+    Other(u8),
+}
+
+/// One archive member.
+#[derive(Clone, Copy)]
+pub struct Entry {
+    path: [u8; MAX_PATH],
+    path_len: usize,
+    kind: EntryKind,
+    data: &'static [u8],
+}
+
+impl Entry {
+    /// The normalized path.
+    pub fn path(&self) -> &str {
+        // Checked to be UTF-8 when the header was parsed.
+        core::str::from_utf8(&self.path[..self.path_len]).unwrap_or_default()
+    }
+
+    /// The last component of the path.
+    pub fn name(&self) -> &str {
+        let path = self.path();
+        path.rsplit_once('/').map_or(path, |(_, name)| name)
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    /// Size of the entry's data in bytes.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Entry")
+            .field("path", &self.path())
+            .field("kind", &self.kind)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// A regular file opened with [`TarFs::open`].
+#[derive(Debug, Clone, Copy)]
+pub struct File {
+    data: &'static [u8],
+}
+
+impl File {
+    /// Size of the file in bytes.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Copy bytes starting at `offset` into `buf`. Returns how many were
+    /// copied, which is 0 at or past the end of the file.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let rest = self.data.get(offset..).unwrap_or_default();
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        len
+    }
+
+    /// The whole file.
+    pub fn as_slice(&self) -> &'static [u8] {
+        self.data
+    }
+}
+
+/// A validated ustar archive.
+#[derive(Debug, Clone, Copy)]
+pub struct TarFs {
+    data: &'static [u8],
+}
+
+impl TarFs {
+    /// Check every header in `data` and open it as a filesystem.
+    ///
+    /// An empty slice is an empty archive, as is one that ends right after a
+    /// member without the two zero blocks.
+    pub fn new(data: &'static [u8]) -> Result<Self, TarError> {
+        for entry in (Headers { data, offset: 0, done: false }) {
+            entry?;
+        }
+        Ok(TarFs { data })
+    }
+
+    /// Iterate over every entry, in archive order.
+    pub fn entries(&self) -> impl Iterator<Item = Entry> + use<> {
+        Headers {
+            data: self.data,
+            offset: 0,
+            done: false,
+        }
+        .filter_map(Result::ok)
+    }
+
+    /// Find the entry at `path`. When a path appears more than once the last
+    /// one wins, as when extracting.
+    pub fn lookup(&self, path: &str) -> Option<Entry> {
+        let path = normalize(path);
+        self.entries().filter(|entry| entry.path() == path).last()
+    }
+
+    /// Open the regular file at `path`.
+    pub fn open(&self, path: &str) -> Option<File> {
+        self.lookup(path)
+            .filter(|entry| entry.kind == EntryKind::File)
+            .map(|entry| File { data: entry.data })
+    }
+
+    /// Iterate over the entries directly inside the directory at `path`, or
+    /// return `None` if there is no such directory. The root always exists.
+    ///
+    /// Only directories with their own entry can be listed, and only
+    /// children with their own entry are listed.
+    pub fn read_dir(&self, path: &str) -> Option<impl Iterator<Item = Entry> + use<>> {
+        let dir = normalize(path);
+        let exists =
+            dir.is_empty() || self.lookup(dir).is_some_and(|entry| entry.kind == EntryKind::Directory);
+        if !exists {
+            return None;
+        }
+        let mut parent = [0; MAX_PATH];
+        parent[..dir.len()].copy_from_slice(dir.as_bytes());
+        let parent_len = dir.len();
+        Some(self.entries().filter(move |entry| {
+            let path = entry.path();
+            let entry_parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            !path.is_empty() && entry_parent.as_bytes() == &parent[..parent_len]
+        }))
+    }
+}
+
+/// Drop leading `./` and `/` and trailing `/` from `path`.
+fn normalize(path: &str) -> &str {
+    let mut path = path;
+    while let Some(rest) = path.strip_prefix("./").or_else(|| path.strip_prefix('/')) {
+        path = rest;
+    }
+    if path == "." {
+        return "";
+    }
+    path.trim_end_matches('/')
+}
+
+/// Iterates over the headers of an archive, stopping after the first error.
+struct Headers {
+    data: &'static [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl Iterator for Headers {
+    type Item = Result<Entry, TarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.offset >= self.data.len() {
+            return None;
+        }
+        let result = self.parse();
+        match &result {
+            Ok(Some(_)) => {}
+            Ok(None) | Err(_) => self.done = true,
+        }
+        result.transpose()
+    }
+}
+
+impl Headers {
+    /// Parse the header at `offset` and move past its data. `None` at the
+    /// zero block that ends the archive.
+    fn parse(&mut self) -> Result<Option<Entry>, TarError> {
+        let offset = self.offset;
+        let header = self
+            .data
+            .get(offset..offset + BLOCK_SIZE)
+            .ok_or(TarError::Truncated { offset })?;
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+
+        let bad_header = TarError::BadHeader { offset };
+        let stored = number(&header[148..156]).ok_or(bad_header)?;
+        // The sum is taken with the checksum field as spaces. Some old tars
+        // summed signed bytes, so accept that too.
+        let field = 148..156;
+        let (unsigned, signed) = header.iter().enumerate().fold((0u64, 0i64), |(u, s), (i, &byte)| {
+            let byte = if field.contains(&i) { b' ' } else { byte };
+            (u + u64::from(byte), s + i64::from(byte as i8))
+        });
+        if stored != unsigned && stored as i64 != signed {
+            return Err(TarError::BadChecksum { offset });
+        }
+
+        let size = number(&header[124..136]).ok_or(bad_header)? as usize;
+        let start = offset + BLOCK_SIZE;
+        let data = start
+            .checked_add(size)
+            .and_then(|end| self.data.get(start..end))
+            .ok_or(TarError::Truncated { offset })?;
+
+        let name = text(&header[0..100]).ok_or(bad_header)?;
+        let prefix = match &header[257..262] {
+            b"ustar" => text(&header[345..500]).ok_or(bad_header)?,
+            _ => "",
+        };
+        let mut path = [0; MAX_PATH];
+        let mut path_len = 0;
+        for part in [prefix, if prefix.is_empty() { "" } else { "/" }, name] {
+            path[path_len..path_len + part.len()].copy_from_slice(part.as_bytes());
+            path_len += part.len();
+        }
+        // Normalizing only trims, so the result is a subslice of `path`.
+        let full = core::str::from_utf8(&path[..path_len]).map_err(|_| bad_header)?;
+        let normalized = normalize(full);
+        let skip = normalized.as_ptr() as usize - full.as_ptr() as usize;
+        let path_len = normalized.len();
+        path.copy_within(skip..skip + path_len, 0);
+
+        let kind = match header[156] {
+            b'0' | 0 if name.ends_with('/') => EntryKind::Directory,
+            b'0' | 0 | b'7' => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            b'2' => EntryKind::Symlink(text(&header[157..257]).ok_or(bad_header)?),
+            other => EntryKind::Other(other),
+        };
+
+        self.offset = start + size.next_multiple_of(BLOCK_SIZE);
+        Ok(Some(Entry {
+            path,
+            path_len,
+            kind,
+            data,
+        }))
+    }
+}
+
+/// Read a NUL-terminated (or full-width) text field.
+fn text(field: &'static [u8]) -> Option<&'static str> {
+    let len = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).ok()
+}
+
+/// Read a numeric field: octal digits padded with spaces or NULs, or the
+/// GNU base-256 form (high bit set) used for large sizes.
+fn number(field: &[u8]) -> Option<u64> {
+    if field.first().is_some_and(|&byte| byte & 0x80 != 0) {
+        return field[1..].iter().try_fold(u64::from(field[0] & 0x7f), |value, &byte| {
+            value.checked_mul(256).map(|value| value | u64::from(byte))
+        });
+    }
+    let mut digits = field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != 0 && byte != b' ');
+    digits.try_fold(0u64, |value, &byte| match byte {
+        b'0'..=b'7' => value.checked_mul(8).map(|value| value + u64::from(byte - b'0')),
+        _ => None,
+    })
+}
+
+/// A small archive built with Python's tarfile in ustar format; see the
+/// tests for its contents.
+#[cfg(test)]
+static TEST_ARCHIVE: &[u8] = include_bytes!("../../tests/data/fs-test.tar");
+
+/// The deep directory in [`TEST_ARCHIVE`], long enough to need the prefix
+/// field.
+#[cfg(test)]
+const TEST_DEEP_DIR: &str = "deep/directory00/directory01/directory02/directory03/directory04/\
+                             directory05/directory06/directory07/directory08/directory09";
+
+#[test_case]
+fn test_listing() {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    let fs = TarFs::new(TEST_ARCHIVE).unwrap();
+    let paths: Vec<(String, EntryKind)> =
+        fs.entries().map(|entry| (entry.path().into(), entry.kind())).collect();
+    let expected: [(String, EntryKind); 6] = [
+        ("readme.txt".into(), EntryKind::File),
+        ("bin".into(), EntryKind::Directory),
+        ("bin/pattern.bin".into(), EntryKind::File),
+        ("bin/link".into(), EntryKind::Symlink("pattern.bin")),
+        (TEST_DEEP_DIR.into(), EntryKind::Directory),
+        (alloc::format!("{}/file.txt", TEST_DEEP_DIR), EntryKind::File),
+    ];
+    assert_eq!(paths, expected);
+
+    let root: Vec<_> = fs.read_dir("/").unwrap().map(|entry| entry.path().len()).collect();
+    assert_eq!(root, ["readme.txt".len(), "bin".len()]);
+    let bin: Vec<_> = fs.read_dir("./bin/").unwrap().collect();
+    assert_eq!(bin.iter().map(Entry::name).collect::<Vec<_>>(), ["pattern.bin", "link"]);
+    assert!(fs.read_dir("readme.txt").is_none());
+    assert!(fs.read_dir("missing").is_none());
+
+    let deep = fs.read_dir(TEST_DEEP_DIR).unwrap().next().unwrap();
+    assert_eq!(deep.name(), "file.txt");
+    let file = fs.open(deep.path()).unwrap();
+    assert_eq!(file.as_slice(), b"long path\n");
+}
+
+#[test_case]
+fn test_read_at_odd_offsets() {
+    let fs = TarFs::new(TEST_ARCHIVE).unwrap();
+    // Byte i of pattern.bin is i % 251.
+    let file = fs.open("/bin/pattern.bin").unwrap();
+    assert_eq!(file.len(), 1000);
+    let mut buf = [0; 7];
+    for offset in [0, 1, 250, 511, 513, 990] {
+        assert_eq!(file.read_at(offset, &mut buf), 7);
+        for (i, &byte) in buf.iter().enumerate() {
+            assert_eq!(byte as usize, (offset + i) % 251);
+        }
+    }
+    assert_eq!(file.read_at(997, &mut buf), 3);
+    assert_eq!(file.read_at(1000, &mut buf), 0);
+    assert_eq!(file.read_at(5000, &mut buf), 0);
+
+    assert_eq!(fs.open("readme.txt").unwrap().as_slice(), b"chronos tar test archive\n");
+    assert!(fs.open("bin").is_none());
+    assert!(fs.open("bin/link").is_none());
+    assert!(fs.open("missing").is_none());
+}
+
+#[test_case]
+fn test_rejects_bad_archives() {
+    use alloc::boxed::Box;
+
+    // The second header (bin/) starts at block 2.
+    let mut corrupt = Box::<[u8]>::from(TEST_ARCHIVE);
+    corrupt[2 * BLOCK_SIZE] ^= 0x01;
+    let corrupt: &'static [u8] = Box::leak(corrupt);
+    assert_eq!(
+        TarFs::new(corrupt).unwrap_err(),
+        TarError::BadChecksum { offset: 2 * BLOCK_SIZE }
+    );
+
+    // Cut inside pattern.bin's data, then inside a header.
+    let file_header = 3 * BLOCK_SIZE;
+    assert_eq!(
+        TarFs::new(&TEST_ARCHIVE[..file_header + BLOCK_SIZE + 100]).unwrap_err(),
+        TarError::Truncated { offset: file_header }
+    );
+    assert_eq!(
+        TarFs::new(&TEST_ARCHIVE[..2 * BLOCK_SIZE + 100]).unwrap_err(),
+        TarError::Truncated { offset: 2 * BLOCK_SIZE }
+    );
+
+    // Missing end blocks and an empty archive are fine.
+    assert!(TarFs::new(&TEST_ARCHIVE[..2 * BLOCK_SIZE]).is_ok());
+    assert_eq!(TarFs::new(&[]).unwrap().entries().count(), 0);
+}
+
+#[test_case]
+fn test_numbers_and_paths() {
+    assert_eq!(number(b"0000644\0"), Some(0o644));
+    assert_eq!(number(b"   17 \0"), Some(0o17));
+    assert_eq!(number(b"\0\0\0\0"), Some(0));
+    assert_eq!(number(b"0009\0"), None);
+    assert_eq!(number(&[0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00]), Some(0x1_0000));
+
+    assert_eq!(normalize("./a/b/"), "a/b");
+    assert_eq!(normalize("/./a"), "a");
+    assert_eq!(normalize("."), "");
+    assert_eq!(normalize("./"), "");
+}
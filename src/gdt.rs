@@ -2,21 +2,44 @@
 //!
 //! On x86_64, we still use a GDT for a few key things in long mode:
 //! - setting the kernel code segment selector
-//! - loading a TSS, which provides Interrupt Stack Table (IST) entries
+//! - loading a TSS, which provides Interrupt Stack Table (IST) entries and
+//!   the stack used when entering the kernel from user mode
+//! - user code and data segments for ring 3 (see [`crate::process`])
 //!
 //! The IST is especially useful for handling faults like a double fault on a
 //! known-good stack (e.g., if the normal kernel stack is corrupted/overflowed).
 
-use lazy_static::lazy_static;
+use conquer_once::spin::OnceCell;
+use core::cell::UnsafeCell;
+use core::ops::Range;
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 
+use crate::sync::{Global, GlobalError};
+
 /// IST slot used for the double fault handler.
 ///
 /// This index must match what the IDT double-fault entry is configured to use.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// Size of the double-fault handler's stack.
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+/// Value painted over the lowest [`CANARY_WORDS`] words of every IST
+/// stack. A handler that runs the stack that deep overwrites it.
+pub const STACK_CANARY: u64 = 0x57ac_c0de_57ac_c0de;
+/// Words of [`STACK_CANARY`] at the bottom of each IST stack.
+pub const CANARY_WORDS: usize = 4;
+
+/// Paint the canary at the bottom of the stack starting at `bottom`.
+fn paint_canary(bottom: VirtAddr) {
+    let words: *mut u64 = bottom.as_mut_ptr();
+    for word in 0..CANARY_WORDS {
+        unsafe { words.add(word).write_volatile(STACK_CANARY) };
+    }
+}
+
 /// Segment selectors we need after loading the GDT.
 ///
 /// In long mode the segmentation model is mostly “flat”, but the CPU still uses
@@ -26,70 +49,160 @@ struct Selectors {
     code_selector: SegmentSelector,
     /// TSS segment selector (points at the TSS descriptor in the GDT).
     tss_selector: SegmentSelector,
+    /// User code and data segment selectors, with RPL 3.
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
 }
 
-lazy_static! {
-    /// Task State Segment for this CPU.
-    ///
-    /// We primarily use the TSS to provide an Interrupt Stack Table entry for
-    /// double faults so that they run on a dedicated stack.
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-
-        // Provide a separate stack for double faults. If a double fault occurs
-        // because the normal stack is broken, switching stacks here can be the
-        // difference between a useful panic and an immediate reset.
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            // Use the end of the stack as the initial stack pointer (stacks grow down).
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-
-        tss
-    };
+impl Selectors {
+    /// Add the descriptors to `gdt` and return their selectors. Every CPU's
+    /// GDT has the same layout.
+    fn add_to(gdt: &mut GlobalDescriptorTable, tss: &'static TaskStateSegment) -> Selectors {
+        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        Selectors { code_selector, tss_selector, user_code_selector, user_data_selector }
+    }
 }
 
-lazy_static! {
-    /// The GDT plus the selectors for the entries we care about.
-    ///
-    /// We install:
-    /// - a kernel code segment descriptor
-    /// - a TSS descriptor pointing to [`TSS`]
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
+/// The TSS, wrapped so [`set_kernel_stack`] can update it after it's loaded.
+///
+/// The CPU only reads the TSS when it switches stacks, so writing to it while
+/// interrupts can't observe a half-written entry is fine.
+struct TssCell(UnsafeCell<TaskStateSegment>);
 
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector  = gdt.add_entry(Descriptor::tss_segment(&TSS));
-
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
-}
+unsafe impl Sync for TssCell {}
+
+/// Task State Segment for the boot CPU, built by [`init`].
+///
+/// We primarily use the TSS to provide an Interrupt Stack Table entry for
+/// double faults so that they run on a dedicated stack. Its RSP0 entry is
+/// updated on every thread switch (see [`set_kernel_stack`]).
+static TSS: Global<TssCell> = Global::new("TSS");
+
+/// The GDT plus the selectors for the entries we care about, built by
+/// [`init`].
+///
+/// We install:
+/// - a kernel code segment descriptor
+/// - a TSS descriptor pointing to [`TSS`]
+/// - user data and code segment descriptors
+static GDT: Global<(GlobalDescriptorTable, Selectors)> = Global::new("GDT");
 
-/// Load the GDT and activate the TSS.
+/// Build the GDT and TSS, load the GDT and activate the TSS.
 ///
 /// This should be called early during boot, before installing IDT entries that
-/// rely on IST stacks (like the double-fault handler).
-pub fn init() {
+/// rely on IST stacks (like the double-fault handler). Fails if called twice.
+pub fn init() -> Result<(), GlobalError> {
     use x86_64::instructions::segmentation::{CS, Segment};
     use x86_64::instructions::tables::load_tss;
 
+    let mut tss = TaskStateSegment::new();
+
+    // Provide a separate stack for double faults. If a double fault occurs
+    // because the normal stack is broken, switching stacks here can be the
+    // difference between a useful panic and an immediate reset.
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+        // Use the end of the stack as the initial stack pointer (stacks grow down).
+        let stack_start = VirtAddr::from_ptr(&raw const STACK);
+        paint_canary(stack_start);
+        stack_start + DOUBLE_FAULT_STACK_SIZE
+    };
+    let tss = TSS.init(TssCell(UnsafeCell::new(tss)))?;
+
+    let mut gdt = GlobalDescriptorTable::new();
+    let selectors = Selectors::add_to(&mut gdt, unsafe { &*tss.0.get() });
+    let gdt = GDT.init((gdt, selectors))?;
+
     // Load the GDT itself.
-    GDT.0.load();
+    gdt.0.load();
 
     // Update CS and load the Task Register (TR) with the TSS selector.
     // These operations are privileged and must be done in an unsafe block.
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.code_selector);
+        load_tss(gdt.1.tss_selector);
+    }
+    Ok(())
+}
+
+/// Size of each application processor's double-fault stack.
+const AP_DOUBLE_FAULT_STACK_SIZE: usize = 4096;
+
+/// Double-fault stacks of the application processors, by CPU index.
+static mut AP_DOUBLE_FAULT_STACKS: [[u8; AP_DOUBLE_FAULT_STACK_SIZE]; crate::smp::MAX_CPUS] =
+    [[0; AP_DOUBLE_FAULT_STACK_SIZE]; crate::smp::MAX_CPUS];
+
+/// TSSs of the application processors, by CPU index.
+static AP_TSS: [TssCell; crate::smp::MAX_CPUS] =
+    [const { TssCell(UnsafeCell::new(TaskStateSegment::new())) }; crate::smp::MAX_CPUS];
+
+/// GDTs of the application processors, by CPU index.
+static AP_GDT: [OnceCell<(GlobalDescriptorTable, Selectors)>; crate::smp::MAX_CPUS] =
+    [const { OnceCell::uninit() }; crate::smp::MAX_CPUS];
+
+/// Load a GDT and TSS of its own on application processor `cpu`.
+///
+/// Each CPU needs its own TSS, since the CPU marks a TSS busy when loading
+/// it and keeps its stack pointers there.
+pub fn init_ap(cpu: usize) {
+    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::tables::load_tss;
+
+    assert!(cpu > 0, "CPU 0 uses the boot GDT");
+    let tss = AP_TSS[cpu].0.get();
+    let gdt = AP_GDT[cpu].get_or_init(|| {
+        let stack_start = VirtAddr::from_ptr(unsafe { &raw const AP_DOUBLE_FAULT_STACKS[cpu] });
+        paint_canary(stack_start);
+        unsafe {
+            (*tss).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+                stack_start + AP_DOUBLE_FAULT_STACK_SIZE;
+        }
+        let mut gdt = GlobalDescriptorTable::new();
+        let selectors = Selectors::add_to(&mut gdt, unsafe { &*tss });
+        (gdt, selectors)
+    });
+
+    gdt.0.load();
+    unsafe {
+        CS::set_reg(gdt.1.code_selector);
+        load_tss(gdt.1.tss_selector);
+    }
+}
+
+/// Set the stack the CPU switches to when entering ring 0 (TSS RSP0).
+///
+/// Called by the scheduler on every thread switch so each thread enters the
+/// kernel on its own stack. Must be called with interrupts disabled.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe {
+        (*TSS.get().0.get()).privilege_stack_table[0] = stack_top;
     }
 }
+
+/// Return the user code and data segment selectors, for entering ring 3.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    let selectors = &GDT.get().1;
+    (selectors.user_code_selector, selectors.user_data_selector)
+}
+
+/// Return the address range of the double-fault handler's stack, or `None`
+/// before [`init`].
+pub fn double_fault_stack() -> Option<Range<u64>> {
+    let tss = TSS.try_get().ok()?;
+    let top = unsafe { (*tss.0.get()).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] };
+    Some(top.as_u64() - DOUBLE_FAULT_STACK_SIZE as u64..top.as_u64())
+}
+
+/// Return the IST stacks in use, by CPU index, for checking their canaries.
+pub fn ist_stacks() -> impl Iterator<Item = (usize, Range<u64>)> {
+    let boot = double_fault_stack().map(|stack| (0, stack));
+    let aps = (1..crate::smp::MAX_CPUS).filter(|&cpu| AP_GDT[cpu].is_initialized()).map(|cpu| {
+        let start = unsafe { &raw const AP_DOUBLE_FAULT_STACKS[cpu] } as u64;
+        (cpu, start..start + AP_DOUBLE_FAULT_STACK_SIZE as u64)
+    });
+    boot.into_iter().chain(aps)
+}
@@ -0,0 +1,564 @@
+//! Kernel log in a memory ring the host reads, for tracing without serial.
+//!
+//! With `hostlog` (or `hostlog=<KiB>`) on the command line, [`init`] takes
+//! physically contiguous frames for a ring and prints its physical address
+//! once on COM1. From then on everything [`klog`](crate::klog) records is
+//! also written to the ring as a binary record, and [`log`] writes records
+//! that go nowhere else, so verbose tracing costs a memory copy instead of a
+//! port write a byte. The host reads the ring through the QEMU monitor, for
+//! example `pmemsave <addr> <size> hostlog.bin`, and decodes it as [`parse`]
+//! does.
+//!
+//! # Format
+//!
+//! Fields are little-endian. The ring starts with a [`HEADER_SIZE`]-byte
+//! header:
+//!
+//! | offset | size | field |
+//! |---|---|---|
+//! | 0 | 8 | [`MAGIC`] |
+//! | 8 | 4 | [`VERSION`] |
+//! | 12 | 4 | size of the record area after the header |
+//! | 16 | 4 | write index: where in the record area the next record goes |
+//! | 20 | 4 | records dropped because another writer held the ring |
+//! | 24 | 8 | wraps: how many times the writer went back to the start |
+//!
+//! Records start on [`RECORD_ALIGN`] boundaries of the record area and take
+//! [`record_size`] of their message length:
+//!
+//! | offset | size | field |
+//! |---|---|---|
+//! | 0 | 4 | sequence number |
+//! | 4 | 1 | level |
+//! | 5 | 1 | flags: [`FLAG_TRUNCATED`] |
+//! | 6 | 2 | message length, at most [`MAX_MESSAGE`] |
+//! | 8 | 8 | [monotonic](crate::time::monotonic) time in nanoseconds |
+//! | 16 | length | message, UTF-8 |
+//! | size − 4 | 4 | the sequence number again |
+//!
+//! Sequence numbers count up by one a record and skip [`WRAP_MARK`]. The
+//! first copy is written first and the second last, so a record whose
+//! copies differ was torn: the host read it while it was being written, or
+//! it was partly overwritten.
+//!
+//! When a record doesn't fit before the end of the area, the writer puts
+//! [`WRAP_MARK`] where its sequence number would go, if there is room,
+//! starts again at offset 0 and counts a wrap. After a wrap, the records
+//! from the write index up to the mark are the older lap and those before
+//! the write index the newer one. The first record of the older lap has
+//! usually lost its start to the newer lap; readers step forward
+//! [`RECORD_ALIGN`] bytes at a time to the first whole one.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use x86_64::VirtAddr;
+
+use crate::collections::FixedString;
+use crate::sync::Global;
+use crate::{cmdline, memory, println, serial_println};
+
+/// First eight bytes of a ring.
+pub const MAGIC: u64 = u64::from_le_bytes(*b"CHRNHLOG");
+
+/// Format version in the header.
+pub const VERSION: u32 = 1;
+
+/// Bytes of header before the record area.
+pub const HEADER_SIZE: usize = 32;
+
+/// Header offset of [`MAGIC`].
+pub const MAGIC_OFFSET: usize = 0;
+/// Header offset of [`VERSION`].
+pub const VERSION_OFFSET: usize = 8;
+/// Header offset of the record area's size.
+pub const DATA_SIZE_OFFSET: usize = 12;
+/// Header offset of the write index.
+pub const WRITE_INDEX_OFFSET: usize = 16;
+/// Header offset of the dropped record count.
+pub const DROPPED_OFFSET: usize = 20;
+/// Header offset of the wrap count.
+pub const WRAPS_OFFSET: usize = 24;
+
+/// Bytes of a record before its message.
+pub const RECORD_HEADER: usize = 16;
+
+/// Records start on multiples of this.
+pub const RECORD_ALIGN: usize = 8;
+
+/// Longest message a record holds; longer ones are cut.
+pub const MAX_MESSAGE: usize = 240;
+
+/// Sequence number that marks the end of a lap.
+pub const WRAP_MARK: u32 = u32::MAX;
+
+/// Record flag: the message was cut to [`MAX_MESSAGE`] bytes.
+pub const FLAG_TRUNCATED: u8 = 1;
+
+/// Level of the records copied from [`klog`](crate::klog).
+pub const LEVEL_CONSOLE: u8 = 1;
+
+/// Ring size for a bare `hostlog`.
+pub const DEFAULT_SIZE: usize = 64 * 1024;
+
+/// Smallest ring: the header and two of the longest records.
+pub const MIN_SIZE: usize = HEADER_SIZE + 2 * record_size(MAX_MESSAGE);
+
+/// Largest ring `hostlog=<KiB>` gets.
+pub const MAX_SIZE: usize = 4 * 1024 * 1024;
+
+/// Return the bytes a record with a `len`-byte message takes.
+pub const fn record_size(len: usize) -> usize {
+    (RECORD_HEADER + len + 4).next_multiple_of(RECORD_ALIGN)
+}
+
+/// Return the sequence number after `seq`.
+fn next_seq(seq: u32) -> u32 {
+    match seq.wrapping_add(1) {
+        WRAP_MARK => 0,
+        next => next,
+    }
+}
+
+/// A ring in memory, written by the kernel and read by the host.
+///
+/// Writing is lock-free and never waits. A writer that finds another one
+/// mid-record, an interrupt handler or another CPU, drops its record and
+/// counts it in the header.
+pub struct HostRing {
+    base: VirtAddr,
+    size: usize,
+    writing: AtomicBool,
+    /// Sequence number of the next record.
+    seq: AtomicU32,
+    dropped: AtomicU32,
+}
+
+impl HostRing {
+    /// Set up a ring in the `size` bytes at `base` and write its header.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be mapped, writable, 8-byte aligned and used by
+    /// nothing else for as long as the ring is.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is not a multiple of [`RECORD_ALIGN`] between
+    /// [`MIN_SIZE`] and [`MAX_SIZE`].
+    pub unsafe fn new(base: VirtAddr, size: usize) -> Self {
+        assert!(
+            (MIN_SIZE..=MAX_SIZE).contains(&size) && size.is_multiple_of(RECORD_ALIGN),
+            "bad hostlog size {}",
+            size
+        );
+        let ring = HostRing {
+            base,
+            size,
+            writing: AtomicBool::new(false),
+            seq: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+        };
+        ring.put(VERSION_OFFSET, VERSION);
+        ring.put(DATA_SIZE_OFFSET, (size - HEADER_SIZE) as u32);
+        ring.put(WRITE_INDEX_OFFSET, 0u32);
+        ring.put(DROPPED_OFFSET, 0u32);
+        ring.put(WRAPS_OFFSET, 0u64);
+        ring.put(MAGIC_OFFSET, MAGIC);
+        ring
+    }
+
+    /// Return the ring's size in bytes, header included.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Write `value` at `offset` into the ring. `offset` is in the ring and
+    /// aligned for `T`.
+    fn put<T: Copy>(&self, offset: usize, value: T) {
+        debug_assert!(offset + size_of::<T>() <= self.size);
+        // SAFETY: the ring's memory is ours, per `new`.
+        unsafe { (self.base + offset as u64).as_mut_ptr::<T>().write_volatile(value) };
+    }
+
+    /// Read the value at `offset` in the ring, as for [`put`](Self::put).
+    fn get<T: Copy>(&self, offset: usize) -> T {
+        // SAFETY: as in `put`.
+        unsafe { (self.base + offset as u64).as_ptr::<T>().read_volatile() }
+    }
+
+    /// Append a record, and return whether it was written.
+    ///
+    /// Messages longer than [`MAX_MESSAGE`] are cut and flagged, as they
+    /// are when `truncated` is set.
+    pub fn write(&self, level: u8, timestamp_ns: u64, message: &str, truncated: bool) -> bool {
+        let claimed = self.writing.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed);
+        if claimed.is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let mut len = message.len().min(MAX_MESSAGE);
+        while !message.is_char_boundary(len) {
+            len -= 1;
+        }
+        let flags = if truncated || len < message.len() { FLAG_TRUNCATED } else { 0 };
+        let size = record_size(len);
+        let data_size = self.size - HEADER_SIZE;
+        let mut head = self.get::<u32>(WRITE_INDEX_OFFSET) as usize;
+        if head + size > data_size {
+            if head < data_size {
+                self.put(HEADER_SIZE + head, WRAP_MARK);
+            }
+            head = 0;
+            self.put(WRAPS_OFFSET, self.get::<u64>(WRAPS_OFFSET) + 1);
+        }
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(next_seq(seq), Ordering::Relaxed);
+        // Volatile stores stay in program order and x86 doesn't reorder
+        // stores, so the host sees them in this order.
+        let record = HEADER_SIZE + head;
+        self.put(record, seq);
+        self.put(record + 4, level);
+        self.put(record + 5, flags);
+        self.put(record + 6, len as u16);
+        self.put(record + 8, timestamp_ns);
+        for (i, &byte) in message.as_bytes()[..len].iter().enumerate() {
+            self.put(record + RECORD_HEADER + i, byte);
+        }
+        self.put(record + size - 4, seq);
+        self.put(DROPPED_OFFSET, self.dropped.load(Ordering::Relaxed));
+        self.put(WRITE_INDEX_OFFSET, (head + size) as u32);
+        self.writing.store(false, Ordering::Release);
+        true
+    }
+
+    /// Format `args` into a record stamped with the current time.
+    pub fn write_fmt(&self, level: u8, args: fmt::Arguments) -> bool {
+        use core::fmt::Write;
+
+        let mut message = FixedString::<MAX_MESSAGE>::new();
+        let _ = message.write_fmt(args);
+        let now = crate::time::monotonic().as_nanos() as u64;
+        self.write(level, now, message.as_str(), message.is_truncated())
+    }
+}
+
+static RING: Global<HostRing> = Global::new("HOSTLOG");
+
+/// Return the ring, if `hostlog` set one up.
+pub fn ring() -> Option<&'static HostRing> {
+    RING.try_get().ok()
+}
+
+/// Write a record of `level` to the ring only, if there is one.
+pub fn log(level: u8, args: fmt::Arguments) {
+    if let Some(ring) = ring() {
+        ring.write_fmt(level, args);
+    }
+}
+
+/// Copy logged output to the ring. Called by [`klog`](crate::klog).
+pub(crate) fn record(args: fmt::Arguments) {
+    log(LEVEL_CONSOLE, args);
+}
+
+/// Why [`init`] couldn't set up the ring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostlogError {
+    /// Memory isn't set up yet.
+    NoMemory,
+    /// No run of free frames was long enough.
+    NoContiguousFrames(usize),
+    /// The ring is already set up.
+    AlreadyStarted,
+}
+
+impl fmt::Display for HostlogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostlogError::NoMemory => f.write_str("memory not set up"),
+            HostlogError::NoContiguousFrames(count) => {
+                write!(f, "no {} contiguous free frames", count)
+            }
+            HostlogError::AlreadyStarted => f.write_str("already started"),
+        }
+    }
+}
+
+/// Set up the ring if the command line asks for one, and print where it
+/// is on COM1. Call after [`crate::init_memory`].
+pub(crate) fn init() {
+    let size = match cmdline::get_u64("hostlog") {
+        Some(0) => return,
+        Some(kib) => (kib as usize).saturating_mul(1024).clamp(MIN_SIZE, MAX_SIZE),
+        None if cmdline::get_bool("hostlog") == Some(true) => DEFAULT_SIZE,
+        None => return,
+    };
+    if let Err(err) = start(size) {
+        println!("hostlog: {}", err);
+    }
+}
+
+/// Allocate a ring of at least `size` bytes and make it the log's.
+fn start(size: usize) -> Result<(), HostlogError> {
+    let frames = size.div_ceil(4096);
+    let phys_offset = *memory::PHYS_OFFSET.try_get().map_err(|_| HostlogError::NoMemory)?;
+    let allocator = memory::FRAME_ALLOCATOR.try_get().map_err(|_| HostlogError::NoMemory)?;
+    if RING.is_initialized() {
+        return Err(HostlogError::AlreadyStarted);
+    }
+    let frame = allocator
+        .lock()
+        .allocate_contiguous(frames)
+        .ok_or(HostlogError::NoContiguousFrames(frames))?;
+    let size = (frames * 4096).min(MAX_SIZE);
+    let base = phys_offset + frame.start_address().as_u64();
+    // SAFETY: the frames were just allocated and the physical memory
+    // mapping covers them.
+    let ring = unsafe {
+        core::ptr::write_bytes(base.as_mut_ptr::<u8>(), 0, size);
+        HostRing::new(base, size)
+    };
+    RING.init(ring).map_err(|_| HostlogError::AlreadyStarted)?;
+    serial_println!(
+        "hostlog: {} KiB ring at physical {:#x}",
+        size / 1024,
+        frame.start_address().as_u64()
+    );
+    Ok(())
+}
+
+/// A record read back by [`parse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub seq: u32,
+    pub level: u8,
+    pub truncated: bool,
+    pub timestamp_ns: u64,
+    pub message: String,
+}
+
+/// The contents of a ring, as [`parse`] found them.
+#[derive(Debug, Default)]
+pub struct Parsed {
+    /// Whole records, oldest first.
+    pub records: Vec<Record>,
+    pub wraps: u64,
+    pub dropped: u32,
+    /// Torn records, which are left out.
+    pub torn: usize,
+}
+
+/// Why [`parse`] couldn't read a ring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// Shorter than its header says.
+    TooShort,
+    /// Doesn't start with [`MAGIC`].
+    BadMagic,
+    /// A format version this kernel doesn't know.
+    Version(u32),
+    /// The write index is outside the record area or misaligned.
+    BadWriteIndex(u32),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TooShort => f.write_str("ring cut short"),
+            ParseError::BadMagic => f.write_str("no hostlog magic"),
+            ParseError::Version(version) => write!(f, "unknown version {}", version),
+            ParseError::BadWriteIndex(index) => write!(f, "bad write index {}", index),
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// Read the whole record at `offset` in the record area and return it with
+/// its size, or `None` if there is none there.
+fn read_record(data: &[u8], offset: usize) -> Option<(Record, usize)> {
+    let seq = read_u32(data, offset)?;
+    let header = data.get(offset..offset + RECORD_HEADER)?;
+    let len = usize::from(u16::from_le_bytes([header[6], header[7]]));
+    if seq == WRAP_MARK || len > MAX_MESSAGE {
+        return None;
+    }
+    let size = record_size(len);
+    if read_u32(data, offset + size - 4)? != seq {
+        return None;
+    }
+    let message = &data[offset + RECORD_HEADER..offset + RECORD_HEADER + len];
+    let record = Record {
+        seq,
+        level: header[4],
+        truncated: header[5] & FLAG_TRUNCATED != 0,
+        timestamp_ns: read_u64(data, offset + 8)?,
+        message: String::from_utf8_lossy(message).into_owned(),
+    };
+    Some((record, size))
+}
+
+/// Decode a copy of a ring, oldest record first.
+///
+/// Older-lap records are kept only if their sequence numbers lead into the
+/// newer lap's, which leaves out records from earlier laps that a longer
+/// newer lap uncovered.
+pub fn parse(ring: &[u8]) -> Result<Parsed, ParseError> {
+    let magic = read_u64(ring, MAGIC_OFFSET).ok_or(ParseError::TooShort)?;
+    if magic != MAGIC {
+        return Err(ParseError::BadMagic);
+    }
+    let version = read_u32(ring, VERSION_OFFSET).ok_or(ParseError::TooShort)?;
+    if version != VERSION {
+        return Err(ParseError::Version(version));
+    }
+    let header = |offset| read_u32(ring, offset).ok_or(ParseError::TooShort);
+    let data_size = header(DATA_SIZE_OFFSET)? as usize;
+    let head = header(WRITE_INDEX_OFFSET)?;
+    let mut parsed = Parsed {
+        dropped: header(DROPPED_OFFSET)?,
+        wraps: read_u64(ring, WRAPS_OFFSET).ok_or(ParseError::TooShort)?,
+        ..Parsed::default()
+    };
+    let data = ring.get(HEADER_SIZE..HEADER_SIZE + data_size).ok_or(ParseError::TooShort)?;
+    if head as usize > data_size || !(head as usize).is_multiple_of(RECORD_ALIGN) {
+        return Err(ParseError::BadWriteIndex(head));
+    }
+    let head = head as usize;
+
+    let mut older = Vec::new();
+    if parsed.wraps > 0
+        && let Some(mut offset) = (head..data_size)
+            .step_by(RECORD_ALIGN)
+            .find(|&offset| read_record(data, offset).is_some())
+    {
+        while let Some((record, size)) = read_record(data, offset) {
+            older.push(record);
+            offset += size;
+        }
+        if read_u32(data, offset).is_some_and(|seq| seq != WRAP_MARK) {
+            parsed.torn += 1;
+        }
+    }
+
+    let mut offset = 0;
+    while offset < head {
+        match read_record(data, offset) {
+            Some((record, size)) if offset + size <= head => {
+                parsed.records.push(record);
+                offset += size;
+            }
+            _ => {
+                parsed.torn += 1;
+                break;
+            }
+        }
+    }
+
+    let leads_in = match (older.last(), parsed.records.first()) {
+        (Some(last), Some(first)) => next_seq(last.seq) == first.seq,
+        _ => true,
+    };
+    if leads_in {
+        older.append(&mut parsed.records);
+        parsed.records = older;
+    }
+    Ok(parsed)
+}
+
+/// Ring memory for tests.
+#[cfg(test)]
+#[repr(C, align(8))]
+struct TestBuffer([u8; 1024]);
+
+#[cfg(test)]
+impl TestBuffer {
+    fn ring(&mut self) -> HostRing {
+        // SAFETY: the buffer outlives the ring in each test.
+        unsafe { HostRing::new(VirtAddr::from_ptr(self.0.as_mut_ptr()), self.0.len()) }
+    }
+}
+
+#[test_case]
+fn test_format_constants() {
+    let mut buffer = TestBuffer([0xAA; 1024]);
+    let ring = buffer.ring();
+    ring.write(3, 42, "hi", false);
+    let bytes = &buffer.0;
+    assert_eq!(&bytes[..8], b"CHRNHLOG");
+    assert_eq!(read_u32(bytes, VERSION_OFFSET), Some(VERSION));
+    assert_eq!(read_u32(bytes, DATA_SIZE_OFFSET), Some(1024 - HEADER_SIZE as u32));
+    assert_eq!(read_u32(bytes, WRITE_INDEX_OFFSET), Some(24));
+    assert_eq!(read_u64(bytes, WRAPS_OFFSET), Some(0));
+    // seq 0, level 3, no flags, length 2, time 42, "hi", padding, seq 0.
+    let record = &bytes[HEADER_SIZE..HEADER_SIZE + 24];
+    assert_eq!(&record[..8], &[0, 0, 0, 0, 3, 0, 2, 0]);
+    assert_eq!(&record[8..18], &[42, 0, 0, 0, 0, 0, 0, 0, b'h', b'i']);
+    assert_eq!(&record[20..], &[0, 0, 0, 0]);
+    assert_eq!((record_size(0), record_size(4), record_size(5)), (24, 24, 32));
+}
+
+#[test_case]
+fn test_records_survive_wrap() {
+    use core::fmt::Write;
+
+    let mut buffer = TestBuffer([0; 1024]);
+    let ring = buffer.ring();
+    for i in 0..100u64 {
+        let mut message = FixedString::<32>::new();
+        write!(message, "record {}", i).unwrap();
+        assert!(ring.write(2, i, message.as_str(), false));
+    }
+    let mut long = FixedString::<{ MAX_MESSAGE + 10 }>::new();
+    for _ in 0..MAX_MESSAGE + 10 {
+        long.write_char('x').unwrap();
+    }
+    assert!(ring.write(1, 100, long.as_str(), false));
+    drop(ring);
+
+    let parsed = parse(&buffer.0).unwrap();
+    assert!(parsed.wraps > 0);
+    assert_eq!(parsed.torn, 0);
+    let (last, rest) = parsed.records.split_last().unwrap();
+    assert_eq!((last.seq, last.message.len()), (100, MAX_MESSAGE));
+    assert!(last.truncated);
+    assert!(rest.len() >= 3, "only {} records kept", rest.len());
+    for (pair, record) in rest.windows(2).zip(rest) {
+        assert_eq!(next_seq(pair[0].seq), pair[1].seq);
+        assert_eq!(record.message, alloc::format!("record {}", record.seq));
+        assert_eq!((record.timestamp_ns, record.level), (u64::from(record.seq), 2));
+    }
+    assert_eq!(next_seq(rest.last().unwrap().seq), last.seq);
+}
+
+#[test_case]
+fn test_torn_and_dropped_records() {
+    let mut buffer = TestBuffer([0; 1024]);
+    let ring = buffer.ring();
+    for message in ["one", "two", "three"] {
+        ring.write(1, 0, message, false);
+    }
+    ring.writing.store(true, Ordering::Relaxed);
+    assert!(!ring.write(1, 0, "nested", false));
+    ring.writing.store(false, Ordering::Relaxed);
+    ring.write(1, 0, "four", false);
+    drop(ring);
+    // Tear the last record, as if the host read it mid-write.
+    let head = read_u32(&buffer.0, WRITE_INDEX_OFFSET).unwrap() as usize;
+    buffer.0[HEADER_SIZE + head - 4] ^= 0xFF;
+
+    let parsed = parse(&buffer.0).unwrap();
+    assert_eq!((parsed.torn, parsed.dropped), (1, 1));
+    let messages: Vec<&str> = parsed.records.iter().map(|r| r.message.as_str()).collect();
+    assert_eq!(messages, ["one", "two", "three"]);
+    buffer.0[0] = 0;
+    assert_eq!(parse(&buffer.0).unwrap_err(), ParseError::BadMagic);
+}
@@ -0,0 +1,355 @@
+//! Boot initialization in named stages.
+//!
+//! [`crate::init`] and [`crate::init_memory`] run the kernel's setup as a
+//! sequence of [`Stage`]s. Each stage returns a `Result`. The outcome and
+//! how long it took are logged as `[ ok ]` or `[fail]` and recorded in the
+//! [`InitReport`].
+//!
+//! A failed critical stage halts the machine with a message, since nothing
+//! after it can work. Failures in other stages, such as a missing PS/2
+//! controller, are recorded and boot continues in a degraded mode.
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
+use x86_64::VirtAddr;
+
+use crate::error::KernelError;
+use crate::ensure;
+use crate::sync::IrqMutex;
+use crate::time::Stopwatch;
+use crate::{println, BootInfo};
+
+/// A step of kernel initialization, in boot order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Set up the VGA writer and the serial port, so output is visible.
+    Console,
+    /// Load the GDT and TSS.
+    Gdt,
+    /// Load the IDT.
+    Idt,
+    /// Set up the PICs and PIT and enable interrupts.
+    Pic,
+    /// Set up paging from the bootloader's memory map.
+    Memory,
+    /// Reserve and map the boot ramdisk and mount it as the root filesystem.
+    Ramdisk,
+    /// Map and initialize the kernel heap.
+    Heap,
+    /// Find and parse the ACPI tables.
+    Acpi,
+    /// Start the wall clock, scan the PCI buses, detect disks and probe
+    /// optional devices.
+    Devices,
+    /// Start the other CPUs.
+    Smp,
+    /// Bring up the network card, if there is one.
+    Network,
+    /// Check that the heap serves allocations, as the executor needs.
+    Executor,
+}
+
+impl Stage {
+    /// Every stage, in boot order.
+    pub const ALL: [Stage; 12] = [
+        Stage::Console,
+        Stage::Gdt,
+        Stage::Idt,
+        Stage::Pic,
+        Stage::Memory,
+        Stage::Ramdisk,
+        Stage::Heap,
+        Stage::Acpi,
+        Stage::Devices,
+        Stage::Smp,
+        Stage::Network,
+        Stage::Executor,
+    ];
+
+    /// Lower-case name used in the boot log.
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Console => "console",
+            Stage::Gdt => "gdt",
+            Stage::Idt => "idt",
+            Stage::Pic => "pic",
+            Stage::Memory => "memory",
+            Stage::Ramdisk => "ramdisk",
+            Stage::Heap => "heap",
+            Stage::Acpi => "acpi",
+            Stage::Devices => "devices",
+            Stage::Smp => "smp",
+            Stage::Network => "network",
+            Stage::Executor => "executor",
+        }
+    }
+
+    /// Whether boot halts when this stage fails.
+    pub fn is_critical(self) -> bool {
+        !matches!(
+            self,
+            Stage::Console
+                | Stage::Ramdisk
+                | Stage::Acpi
+                | Stage::Devices
+                | Stage::Smp
+                | Stage::Network
+        )
+    }
+}
+
+/// Why a stage failed, for failures that belong to the staged init itself.
+/// Stages also fail with the errors of the modules they set up; see
+/// [`KernelError`].
+#[derive(Debug)]
+pub enum InitError {
+    /// The bootloader's memory map has no usable memory.
+    NoUsableMemory,
+    /// The heap didn't serve a test allocation.
+    HeapUnusable,
+    /// A stage this one needs didn't succeed.
+    DependencyFailed(Stage),
+    /// Failure requested through [`inject_failure`].
+    Injected,
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitError::NoUsableMemory => f.write_str("memory map has no usable regions"),
+            InitError::HeapUnusable => f.write_str("heap allocation failed"),
+            InitError::DependencyFailed(stage) => write!(f, "needs {}", stage.name()),
+            InitError::Injected => f.write_str("injected failure"),
+        }
+    }
+}
+
+/// The outcome of one stage.
+#[derive(Debug)]
+pub struct StageRecord {
+    pub stage: Stage,
+    pub result: Result<(), KernelError>,
+    /// TSC cycles the stage took.
+    pub cycles: u64,
+}
+
+/// Outcomes of the stages that have run so far.
+pub struct InitReport {
+    stages: [OnceCell<StageRecord>; Stage::ALL.len()],
+}
+
+impl InitReport {
+    /// Return the record of `stage`, if it has run.
+    pub fn get(&self, stage: Stage) -> Option<&StageRecord> {
+        self.stages[stage as usize].get()
+    }
+
+    /// Iterate over the stages that have run, in boot order.
+    pub fn iter(&self) -> impl Iterator<Item = &StageRecord> {
+        self.stages.iter().filter_map(OnceCell::get)
+    }
+
+    /// Iterate over the stages that failed.
+    pub fn failures(&self) -> impl Iterator<Item = &StageRecord> {
+        self.iter().filter(|record| record.result.is_err())
+    }
+}
+
+/// One line per stage that has run, as in the boot log.
+impl fmt::Display for InitReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for record in self.iter() {
+            match &record.result {
+                Ok(()) => writeln!(f, "[ ok ] {} ({} cycles)", record.stage.name(), record.cycles)?,
+                Err(err) => writeln!(
+                    f,
+                    "[fail] {} ({} cycles): {}",
+                    record.stage.name(),
+                    record.cycles,
+                    err
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+static REPORT: InitReport = InitReport {
+    stages: [const { OnceCell::uninit() }; Stage::ALL.len()],
+};
+
+/// Return what initialization has done so far.
+pub fn report() -> &'static InitReport {
+    &REPORT
+}
+
+/// Stages that [`inject_failure`] has marked, one bit per stage.
+static INJECTED: AtomicU16 = AtomicU16::new(0);
+
+/// Make `stage` fail with [`InitError::Injected`] when it runs.
+///
+/// For tests of degraded boots; only non-critical stages can be failed.
+#[doc(hidden)]
+pub fn inject_failure(stage: Stage) {
+    assert!(!stage.is_critical(), "can't inject a failure into critical stage {}", stage.name());
+    INJECTED.fetch_or(1 << stage as u16, Ordering::Relaxed);
+}
+
+/// Run `stage`, log and record its outcome, and halt if a critical stage
+/// failed. Returns whether it succeeded.
+fn run(stage: Stage, body: impl FnOnce() -> Result<(), KernelError>) -> bool {
+    let stopwatch = Stopwatch::start();
+    let result = if INJECTED.load(Ordering::Relaxed) & (1 << stage as u16) != 0 {
+        Err(InitError::Injected.into())
+    } else {
+        body()
+    };
+    let record = StageRecord {
+        stage,
+        result,
+        cycles: stopwatch.elapsed_cycles(),
+    };
+
+    let quiet = crate::klog::level() == 0;
+    match &record.result {
+        Ok(()) if quiet => {}
+        Ok(()) => println!("[ ok ] {} ({} cycles)", stage.name(), record.cycles),
+        Err(err) => println!("[fail] {}: {}", stage.name(), err),
+    }
+    let ok = record.result.is_ok();
+    // Stages run once per boot; a second run keeps the first record.
+    let _ = REPORT.stages[stage as usize].try_init_once(|| record);
+
+    if !ok && stage.is_critical() {
+        println!("init: critical stage {} failed, halting", stage.name());
+        x86_64::instructions::interrupts::disable();
+        crate::hlt_loop();
+    }
+    ok
+}
+
+/// Set up the console, so the stages after it can log.
+pub(crate) fn run_console() {
+    run(Stage::Console, || {
+        crate::vga_buffer::init()?;
+        crate::vga_buffer::register();
+        crate::serial::init()?;
+        crate::serial::register();
+        Ok(())
+    });
+    crate::power::on_shutdown("kmsg", crate::klog::sync_to_serial, crate::power::DEFAULT_TIMEOUT);
+}
+
+/// Run the stages that don't need the memory map.
+pub(crate) fn run_early() {
+    run(Stage::Gdt, || Ok(crate::gdt::init()?));
+    run(Stage::Idt, || Ok(crate::interrupts::init_idt()?));
+    run(Stage::Pic, || {
+        unsafe { crate::interrupts::PICS.lock().initialize() };
+        crate::interrupts::unmask_irqs();
+        crate::power::on_shutdown(
+            "irqs",
+            crate::interrupts::mask_all_irqs,
+            crate::power::DEFAULT_TIMEOUT,
+        );
+        crate::time::init_pit();
+        x86_64::instructions::interrupts::enable();
+        Ok(())
+    });
+}
+
+/// Run the stages that set up memory and the devices and executor that
+/// depend on it.
+pub(crate) fn run_memory(boot_info: &'static BootInfo) {
+    use crate::memory::{self, BootInfoFrameAllocator};
+    use bootloader::bootinfo::MemoryRegionType;
+
+    let mut paging = None;
+    run(Stage::Memory, || {
+        let usable = boot_info
+            .memory_map
+            .iter()
+            .any(|region| region.region_type == MemoryRegionType::Usable);
+        ensure!(usable, InitError::NoUsableMemory);
+        let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+        memory::PHYS_OFFSET.init(phys_mem_offset)?;
+        let mut mapper = unsafe { memory::init(phys_mem_offset) };
+        memory::protect_phys_window(&mut mapper, phys_mem_offset, &boot_info.memory_map);
+        crate::smp::reserve_trampoline(&boot_info.memory_map);
+        crate::crashlog::init(&boot_info.memory_map, phys_mem_offset);
+        let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+        paging = Some((mapper, frame_allocator));
+        Ok(())
+    });
+    // Before the heap, which is the first to allocate frames.
+    run(Stage::Ramdisk, || {
+        let (mapper, frame_allocator) = paging
+            .as_mut()
+            .ok_or(InitError::DependencyFailed(Stage::Memory))?;
+        crate::ramdisk::init(mapper, frame_allocator)?;
+        Ok(crate::fs::init()?)
+    });
+    run(Stage::Heap, || {
+        let (mapper, frame_allocator) = paging
+            .as_mut()
+            .ok_or(InitError::DependencyFailed(Stage::Memory))?;
+        crate::allocator::init_heap(mapper, frame_allocator)
+    });
+    // Before the devices, whose drivers read the tables.
+    run(Stage::Acpi, || {
+        let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+        Ok(crate::acpi::init(phys_mem_offset)?)
+    });
+    run(Stage::Devices, || {
+        crate::time::wallclock::init()?;
+        crate::pci::init();
+        crate::ata::init();
+        crate::task::keyboard::keymap::init();
+        crate::task::keyboard::init()
+    });
+    run(Stage::Smp, || {
+        if crate::cmdline::get_bool("apic") == Some(false) {
+            return Ok(());
+        }
+        let (mapper, frame_allocator) = paging
+            .as_mut()
+            .ok_or(InitError::DependencyFailed(Stage::Memory))?;
+        Ok(crate::smp::init(mapper, frame_allocator)?)
+    });
+    // Before the handoff below, while runs of frames are still free.
+    run(Stage::Network, || {
+        let (mapper, frame_allocator) = paging
+            .as_mut()
+            .ok_or(InitError::DependencyFailed(Stage::Memory))?;
+        Ok(crate::net::init(mapper, frame_allocator)?)
+    });
+    // Later users of physical memory (user address spaces) allocate from
+    // the same frames.
+    if let Some((_, frame_allocator)) = paging {
+        let _ = memory::FRAME_ALLOCATOR.init(IrqMutex::named("FRAME_ALLOCATOR", frame_allocator));
+    }
+    run(Stage::Executor, || {
+        let layout = Layout::new::<u64>();
+        let probe = unsafe { alloc(layout) };
+        ensure!(!probe.is_null(), InitError::HeapUnusable);
+        unsafe { dealloc(probe, layout) };
+        Ok(())
+    });
+}
+
+#[test_case]
+fn test_boot_stages_recorded() {
+    // The test kernel booted through init and init_memory.
+    let report = report();
+    for stage in Stage::ALL {
+        let record = report.get(stage).expect("stage did not run");
+        assert_eq!(record.stage, stage);
+        assert!(record.result.is_ok(), "{} failed", stage.name());
+    }
+    assert_eq!(report.failures().count(), 0);
+    let rendered = alloc::format!("{}", report);
+    assert_eq!(rendered.lines().count(), Stage::ALL.len());
+    assert!(rendered.starts_with("[ ok ] console"));
+}
@@ -2,17 +2,38 @@
 //!
 //! This module builds and loads the CPU’s IDT (Interrupt Descriptor Table),
 //! sets up handlers for a few exceptions, and wires up PIC-based hardware IRQs
-//! (timer + keyboard). It also provides a small enum for mapping IRQ lines to
+//! (timer, keyboard and COM1). It also provides a small enum for mapping IRQ lines to
 //! IDT vector indices.
+//!
+//! Before any of that, [`init_early`] loads a minimal IDT so a fatal
+//! exception in early boot prints a line instead of resetting silently.
+//!
+//! Drivers found at run time, such as PCI devices, attach to the few lines
+//! without a fixed handler with [`register_irq`].
 
-use lazy_static::lazy_static;
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::{PrivilegeLevel, VirtAddr};
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::PageTableFlags;
 use pic8259::ChainedPics;
-use spin;
+use crate::arch::port::{Port, PortGroup};
+use crate::sync::{Global, GlobalError, IrqMutex};
+
+mod report;
+pub mod trace;
+
+pub use report::{
+    FaultReport, Registers, exception_name, fault_report, last_fault, mark_handled,
+    pending_fault, saved_registers,
+};
+use report::capture_shim;
 
+use crate::collections::FixedString;
 use crate::gdt;
 use crate::println;
-use crate::print;
+use crate::symbols::Symbol;
 use crate::hlt_loop;
 
 /// Offset where PIC1 vectors start in the IDT.
@@ -28,45 +49,109 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
 /// Global handle to the legacy 8259 PICs.
 ///
-/// This is protected by a spinlock because handlers can run at interrupt time.
-/// Access is `unsafe` internally because the PICs are a global piece of hardware
-/// with side effects.
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
-
-lazy_static! {
-    /// The system Interrupt Descriptor Table.
-    ///
-    /// Built once at runtime and then loaded with [`init_idt`]. We install:
-    /// - breakpoint exception handler
-    /// - double-fault handler on a dedicated IST stack
-    /// - PIC timer and keyboard IRQ handlers
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-
-        // CPU exceptions
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-
-        // Page faults
-        idt.page_fault.set_handler_fn(page_fault_handler);
-
-        // Double fault: use a known-good stack (IST) so stack overflows don't
-        // immediately cascade into triple faults / resets.
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+/// This is an [`IrqMutex`] because handlers take it at interrupt time; they
+/// use [`IrqMutex::lock_irq_already_disabled`]. Access is `unsafe` internally
+/// because the PICs are a global piece of hardware with side effects.
+pub static PICS: IrqMutex<ChainedPics> =
+    IrqMutex::named("PICS", unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Interrupt controller the kernel drives, for the boot banner.
+pub const MODE: &str = "pic";
 
-        // Hardware IRQs from the remapped PICs
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
+/// Number of timer interrupts handled since boot.
+///
+/// Only the timer handler writes this, so relaxed ordering is enough.
+static TICKS: AtomicU64 = AtomicU64::new(0);
 
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
+/// Breakpoint exceptions handled.
+static BREAKPOINTS: AtomicU64 = AtomicU64::new(0);
 
-        idt
-    };
+/// Interrupts handled per PIC IRQ line.
+static IRQ_COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+/// TSC cycles spent in each PIC IRQ line's handler, with the `cpu-time`
+/// feature. A line's handler doesn't nest and is the only writer of its
+/// slots, so plain loads and stores do.
+#[cfg(feature = "cpu-time")]
+static IRQ_CYCLES: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+/// Longest single run of each PIC IRQ line's handler, in TSC cycles.
+#[cfg(feature = "cpu-time")]
+static IRQ_MAX_CYCLES: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+/// Extra cycles to spin at the start of each line's handler, so tests can
+/// make one slow.
+#[cfg(all(test, feature = "cpu-time"))]
+static INJECTED_DELAY: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+/// Handlers attached with [`register_irq`], as `fn()` addresses by IRQ
+/// line; 0 for none.
+static IRQ_HANDLERS: [AtomicUsize; 16] = [const { AtomicUsize::new(0) }; 16];
+
+/// The line the secondary PIC is chained to on the primary.
+const CASCADE_LINE: usize = 2;
+
+/// The system Interrupt Descriptor Table, built and loaded by [`init_idt`].
+static IDT: Global<InterruptDescriptorTable> = Global::new("IDT");
+
+/// Build the IDT. We install:
+/// - breakpoint exception handler
+/// - debug trap handler, for [watchpoints](crate::debug)
+/// - page fault and general protection fault handlers, which kill the user
+///   process when it faults (see [`crate::process`])
+/// - register-saving shims in front of those three, for their
+///   [`FaultReport`]s
+/// - double-fault handler on a dedicated IST stack
+/// - PIC timer, keyboard and COM1 IRQ handlers
+/// - entry points for the lines in [`DYNAMIC_LINES`]
+/// - a handler for spurious local APIC interrupts
+/// - the system call gate, open to user mode
+fn build_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+
+    // CPU exceptions, behind shims that save the registers for the report
+    unsafe {
+        idt.breakpoint.set_handler_addr(report::shim_addr(breakpoint_entry));
+        idt.page_fault.set_handler_addr(report::shim_addr(page_fault_entry));
+        idt.general_protection_fault
+            .set_handler_addr(report::shim_addr(general_protection_fault_entry));
+    }
+
+    idt.debug.set_handler_fn(debug_handler);
+
+    // Double fault: use a known-good stack (IST) so stack overflows don't
+    // immediately cascade into triple faults / resets.
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+
+    // Hardware IRQs from the remapped PICs
+    idt[InterruptIndex::Timer.as_usize()]
+        .set_handler_fn(timer_interrupt_handler);
+
+    idt[InterruptIndex::Keyboard.as_usize()]
+        .set_handler_fn(keyboard_interrupt_handler);
+
+    idt[InterruptIndex::Com1.as_usize()]
+        .set_handler_fn(com1_interrupt_handler);
+
+    for &(line, entry) in DYNAMIC_LINES {
+        idt[usize::from(PIC_1_OFFSET + line)].set_handler_fn(entry);
+    }
+
+    idt[usize::from(crate::apic::SPURIOUS_VECTOR)]
+        .set_handler_fn(spurious_interrupt_handler);
+
+    // System calls, the only vector user mode may raise with `int`.
+    unsafe {
+        idt[usize::from(crate::process::SYSCALL_VECTOR)]
+            .set_handler_addr(crate::process::syscall_entry_addr())
+            .set_privilege_level(PrivilegeLevel::Ring3);
+    }
+
+    idt
 }
 
 /// IDT vector numbers for PIC-delivered hardware interrupts.
@@ -80,6 +165,8 @@ pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     /// IRQ1: PS/2 keyboard interrupt.
     Keyboard,
+    /// IRQ4: COM1 serial port interrupt.
+    Com1 = PIC_1_OFFSET + 4,
 }
 
 impl InterruptIndex {
@@ -92,73 +179,585 @@ impl InterruptIndex {
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    /// Return the PIC IRQ line this interrupt arrives on.
+    fn irq(self) -> usize {
+        usize::from(self.as_u8() - PIC_1_OFFSET)
+    }
+
+    /// Count one occurrence in [`irq_counts`], and time the handler for
+    /// [`irq_times`] until the returned guard is dropped.
+    fn enter(self) -> HandlerTimer {
+        enter_line(self.irq())
+    }
+}
+
+/// [`InterruptIndex::enter`] by IRQ line.
+fn enter_line(irq: usize) -> HandlerTimer {
+    IRQ_COUNTS[irq].fetch_add(1, Ordering::Relaxed);
+    HandlerTimer::start(irq)
+}
+
+/// Return how many interrupts each PIC IRQ line has delivered since boot.
+pub fn irq_counts() -> [u64; 16] {
+    core::array::from_fn(|irq| IRQ_COUNTS[irq].load(Ordering::Relaxed))
+}
+
+/// Time spent in one PIC IRQ line's handler, in TSC cycles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IrqTime {
+    pub total_cycles: u64,
+    /// The longest single run.
+    pub max_cycles: u64,
+}
+
+/// Return the time spent in each PIC IRQ line's handler since boot, or
+/// `None` without the `cpu-time` feature.
+#[cfg(feature = "cpu-time")]
+pub fn irq_times() -> Option<[IrqTime; 16]> {
+    Some(core::array::from_fn(|irq| IrqTime {
+        total_cycles: IRQ_CYCLES[irq].load(Ordering::Relaxed),
+        max_cycles: IRQ_MAX_CYCLES[irq].load(Ordering::Relaxed),
+    }))
+}
+
+/// Return the time spent in each PIC IRQ line's handler since boot, or
+/// `None` without the `cpu-time` feature.
+#[cfg(not(feature = "cpu-time"))]
+pub fn irq_times() -> Option<[IrqTime; 16]> {
+    None
+}
+
+/// Adds the time until it is dropped to its IRQ line's [`irq_times`]. Two
+/// TSC reads a handler run; nothing without the `cpu-time` feature.
+#[must_use]
+struct HandlerTimer {
+    #[cfg(feature = "cpu-time")]
+    irq: usize,
+    #[cfg(feature = "cpu-time")]
+    start: u64,
+}
+
+impl HandlerTimer {
+    #[cfg_attr(not(feature = "cpu-time"), allow(unused_variables))]
+    fn start(irq: usize) -> Self {
+        #[cfg(feature = "cpu-time")]
+        {
+            let start = crate::time::rdtsc();
+            #[cfg(test)]
+            {
+                let delay = INJECTED_DELAY[irq].load(Ordering::Relaxed);
+                while crate::time::rdtsc().wrapping_sub(start) < delay {
+                    core::hint::spin_loop();
+                }
+            }
+            HandlerTimer { irq, start }
+        }
+        #[cfg(not(feature = "cpu-time"))]
+        HandlerTimer {}
+    }
+}
+
+#[cfg(feature = "cpu-time")]
+impl Drop for HandlerTimer {
+    fn drop(&mut self) {
+        let cycles = crate::time::rdtsc().wrapping_sub(self.start);
+        let total = &IRQ_CYCLES[self.irq];
+        total.store(total.load(Ordering::Relaxed) + cycles, Ordering::Relaxed);
+        let max = &IRQ_MAX_CYCLES[self.irq];
+        if cycles > max.load(Ordering::Relaxed) {
+            max.store(cycles, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Return the name of the device on PIC IRQ line `irq`, if it has a
+/// handler.
+pub fn irq_name(irq: usize) -> Option<&'static str> {
+    [InterruptIndex::Timer, InterruptIndex::Keyboard, InterruptIndex::Com1]
+        .into_iter()
+        .find(|index| index.irq() == irq)
+        .map(|index| match index {
+            InterruptIndex::Timer => "timer",
+            InterruptIndex::Keyboard => "keyboard",
+            InterruptIndex::Com1 => "com1",
+        })
 }
 
-/// Load the IDT into the CPU.
+/// The mask registers of the two 8259 PICs.
 ///
-/// Call this during early boot after the GDT/TSS is set up.
-pub fn init_idt() {
-    IDT.load();
+/// The PICs are otherwise driven through [`PICS`]; take that lock around any
+/// use of these ports so the two don't interleave.
+pub struct PicMaskPorts {
+    pub primary: Port<u8>,
+    pub secondary: Port<u8>,
+}
+
+impl PortGroup for PicMaskPorts {
+    const DEVICE: &'static str = "pic";
+    /// The primary PIC's command port.
+    const BASE: u16 = 0x20;
+
+    fn at(base: u16) -> Self {
+        // Each PIC's data port, which holds the mask, follows its command
+        // port; the secondary sits 0x80 above the primary.
+        PicMaskPorts {
+            primary: Port::new(Self::DEVICE, base + 1).with_delay(),
+            secondary: Port::new(Self::DEVICE, base + 0x81).with_delay(),
+        }
+    }
+}
+
+/// Unmask the IRQ lines whose handlers aren't enabled by default.
+///
+/// Call right after initializing the PICs; the firmware's masks are kept for
+/// everything else.
+pub fn unmask_irqs() {
+    let _pics = PICS.lock();
+    let masks = PicMaskPorts::standard();
+    let com1 = InterruptIndex::Com1.as_u8() - PIC_1_OFFSET;
+    unsafe {
+        let primary = masks.primary.read();
+        masks.primary.write(primary & !(1 << com1));
+    }
+}
+
+/// Mask or unmask the IRQ line `index` arrives on.
+pub fn set_masked(index: InterruptIndex, masked: bool) {
+    set_line_masked(index.irq(), masked);
+}
+
+/// Mask or unmask PIC line `irq`.
+fn set_line_masked(irq: usize, masked: bool) {
+    let _pics = PICS.lock();
+    let masks = PicMaskPorts::standard();
+    let (port, bit) = if irq < 8 { (&masks.primary, irq) } else { (&masks.secondary, irq - 8) };
+    unsafe {
+        let mask = port.read();
+        port.write(if masked { mask | 1 << bit } else { mask & !(1 << bit) });
+    }
+}
+
+/// Return whether the IRQ line `index` arrives on is masked.
+pub fn is_masked(index: InterruptIndex) -> bool {
+    is_line_masked(index.irq())
+}
+
+/// Return whether PIC line `irq` is masked.
+fn is_line_masked(irq: usize) -> bool {
+    let _pics = PICS.lock();
+    let masks = PicMaskPorts::standard();
+    let mask = unsafe {
+        if irq < 8 { masks.primary.read() } else { masks.secondary.read() }
+    };
+    mask & 1 << (irq % 8) != 0
+}
+
+/// Set once [`mask_all_irqs`] has run.
+static IRQS_MASKED: AtomicBool = AtomicBool::new(false);
+
+/// Mask every IRQ line at both PICs, for shutdown.
+///
+/// The timer stops with the rest, so nothing is preempted afterwards.
+pub fn mask_all_irqs() {
+    let _pics = PICS.lock();
+    let masks = PicMaskPorts::standard();
+    unsafe {
+        masks.primary.write(0xff);
+        masks.secondary.write(0xff);
+    }
+    IRQS_MASKED.store(true, Ordering::Relaxed);
+}
+
+/// Return whether [`mask_all_irqs`] has run.
+pub fn irqs_masked() -> bool {
+    IRQS_MASKED.load(Ordering::Relaxed)
+}
+
+/// Build the IDT and load it into the CPU.
+///
+/// Call this during early boot after the GDT/TSS is set up. Fails if called
+/// twice.
+pub fn init_idt() -> Result<(), GlobalError> {
+    IDT.init(build_idt())?.load();
+    Ok(())
+}
+
+/// Load the IDT that [`init_idt`] built, on another CPU.
+///
+/// # Panics
+///
+/// Panics if [`init_idt`] hasn't run.
+pub fn load_idt() {
+    IDT.get().load();
+}
+
+/// Table [`init_early`] loads until [`init_idt`] replaces it.
+struct EarlyIdt(UnsafeCell<InterruptDescriptorTable>);
+
+// SAFETY: only `init_early` writes the table, once, before it is loaded.
+unsafe impl Sync for EarlyIdt {}
+
+static EARLY_IDT: EarlyIdt = EarlyIdt(UnsafeCell::new(InterruptDescriptorTable::new()));
+static EARLY_LOADED: AtomicBool = AtomicBool::new(false);
+
+/// Hook [`set_early_fault_hook`] installed, as a `fn` address, or 0.
+static EARLY_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Load a minimal IDT that reports fatal exceptions before [`init_idt`] runs.
+///
+/// Call it first thing in the kernel entry point. Until then a page fault,
+/// general protection fault or double fault triple-faults with no output.
+/// Its handlers write one line with the vector and RIP straight to the
+/// debug console and COM1, without locks or any other setup, then halt.
+/// Loading the full IDT replaces it. The GDT isn't loaded yet, so the
+/// double fault handler has no IST stack and a stack overflow still resets.
+/// Calls after the first do nothing.
+pub fn init_early() {
+    if EARLY_LOADED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    // SAFETY: the flag above lets only the first call write the table, and
+    // nothing reads it until it is loaded below.
+    let idt = unsafe { &mut *EARLY_IDT.0.get() };
+    idt.page_fault.set_handler_fn(early_page_fault_handler);
+    idt.general_protection_fault.set_handler_fn(early_general_protection_fault_handler);
+    idt.double_fault.set_handler_fn(early_double_fault_handler);
+    // SAFETY: the table is no longer written.
+    unsafe { &*EARLY_IDT.0.get() }.load();
+}
+
+/// An exception caught by the [early IDT](init_early).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EarlyFault {
+    pub vector: u8,
+    pub name: &'static str,
+    pub rip: u64,
+}
+
+impl EarlyFault {
+    /// The line the handlers print.
+    fn message(&self) -> FixedString<96> {
+        let mut message = FixedString::new();
+        let _ = writeln!(
+            message,
+            "EARLY EXCEPTION: {} (vector {}) at rip {:#x}",
+            self.name, self.vector, self.rip
+        );
+        message
+    }
+}
+
+/// Call `hook` with the fault and the line printed for it once an early
+/// handler has printed. The handler halts if the hook returns.
+///
+/// For tests that fault before [`init_idt`].
+#[doc(hidden)]
+pub fn set_early_fault_hook(hook: fn(&EarlyFault, &str)) {
+    EARLY_HOOK.store(hook as usize, Ordering::Release);
+}
+
+/// Write `text` to the debug console and COM1 with plain port writes.
+fn early_write(text: &str) {
+    use x86_64::instructions::port::{PortRead, PortWrite};
+
+    const DEBUGCON: u16 = 0xE9;
+    const COM1_DATA: u16 = 0x3F8;
+    const COM1_LINE_STATUS: u16 = 0x3FD;
+    const TRANSMIT_EMPTY: u8 = 0x20;
+
+    // SAFETY: the debug console and COM1 data ports take bytes without side
+    // effects beyond output; reading line status and the debug console has
+    // none.
+    unsafe {
+        let debugcon = u8::read_from_port(DEBUGCON) == DEBUGCON as u8;
+        for byte in text.bytes() {
+            if debugcon {
+                u8::write_to_port(DEBUGCON, byte);
+            }
+            // Bounded, in case the UART is missing.
+            for _ in 0..10_000 {
+                if u8::read_from_port(COM1_LINE_STATUS) & TRANSMIT_EMPTY != 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            u8::write_to_port(COM1_DATA, byte);
+        }
+    }
+}
+
+fn early_fault(fault: EarlyFault) -> ! {
+    let message = fault.message();
+    early_write(message.as_str());
+    let hook = EARLY_HOOK.load(Ordering::Acquire);
+    if hook != 0 {
+        // SAFETY: only `set_early_fault_hook` stores a non-zero value, and
+        // it stores a `fn(&EarlyFault, &str)`.
+        let hook: fn(&EarlyFault, &str) = unsafe { core::mem::transmute(hook) };
+        hook(&fault, message.as_str());
+    }
+    hlt_loop();
+}
+
+extern "x86-interrupt" fn early_page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    let rip = stack_frame.instruction_pointer.as_u64();
+    early_fault(EarlyFault { vector: 14, name: "page fault", rip });
+}
+
+extern "x86-interrupt" fn early_general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) {
+    let rip = stack_frame.instruction_pointer.as_u64();
+    early_fault(EarlyFault { vector: 13, name: "general protection fault", rip });
+}
+
+extern "x86-interrupt" fn early_double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    let rip = stack_frame.instruction_pointer.as_u64();
+    early_fault(EarlyFault { vector: 8, name: "double fault", rip });
+}
+
+/// Marks a device interrupt handler as running on this CPU while alive;
+/// see [`in_interrupt`].
+///
+/// The timer handler drops it before preempting, since the next thread
+/// isn't in interrupt context.
+pub struct IrqContext(());
+
+impl IrqContext {
+    pub fn enter() -> Self {
+        crate::smp::this_cpu().irq_depth.fetch_add(1, Ordering::Relaxed);
+        IrqContext(())
+    }
+}
+
+impl Drop for IrqContext {
+    fn drop(&mut self) {
+        crate::smp::this_cpu().irq_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Return whether a device interrupt handler is running on this CPU.
+/// Another CPU's handlers don't count, so task code isn't taken for
+/// interrupt code while they run.
+///
+/// The global allocator serves small allocations made here from a separate
+/// arena (see [`crate::allocator`]).
+pub fn in_interrupt() -> bool {
+    crate::smp::this_cpu().irq_depth.load(Ordering::Relaxed) != 0
+}
+
+/// Return the number of timer ticks observed since interrupts were enabled.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Return how many breakpoint exceptions have been handled.
+pub fn breakpoints() -> u64 {
+    BREAKPOINTS.load(Ordering::Relaxed)
 }
 
 /// Timer IRQ handler (PIT, IRQ0).
 ///
-/// Prints a dot so you can visually confirm interrupts are firing, then sends
-/// an EOI (end-of-interrupt) to the PIC so it can deliver further IRQs.
+/// Bumps the tick counter, stirs its timing into the entropy pool (see
+/// [`crate::rand`]), wakes any async sleeps that expired, then sends an
+/// EOI (end-of-interrupt) to the PIC so it can deliver further IRQs. Finally gives
+/// the scheduler a chance to preempt the running thread (see
+/// [`crate::thread`]).
 extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+    stack_frame: InterruptStackFrame)
 {
-    print!(".");
+    trace::record(InterruptIndex::Timer.as_u8(), &stack_frame);
+    let irq = IrqContext::enter();
+    let timer = InterruptIndex::Timer.enter();
+    crate::profile::sample(&stack_frame);
+    crate::rand::add_interrupt_timing();
+    crate::time::record_tick();
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    crate::task::timer::wake_expired(now);
 
     unsafe {
-        PICS.lock()
+        PICS.lock_irq_already_disabled()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    drop(timer);
+    drop(irq);
+
+    // Must come after the EOI: the next thread may not return here for a
+    // while, and the PIC holds back further ticks until it sees one.
+    crate::thread::preempt();
 }
 
 /// Keyboard IRQ handler (PS/2, IRQ1).
 ///
-/// Reads a scancode from port `0x60`, feeds it into the `pc_keyboard` decoder,
-/// and prints either the decoded Unicode character or the raw key value.
-/// Finally, sends an EOI to the PIC.
+/// Reads a scancode from the PS/2 data port and queues it for the keyboard task (see
+/// [`crate::task::keyboard`]), which does the decoding outside interrupt
+/// context. Finally, sends an EOI to the PIC.
 extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+    stack_frame: InterruptStackFrame)
 {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
-
-    lazy_static! {
-        /// Keyboard state machine for scancode decoding.
-        ///
-        /// Stored behind a spinlock because the handler can be invoked at any time.
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(
-                ScancodeSet1::new(),
-                layouts::Us104Key,
-                HandleControl::Ignore,
-            ));
-    }
-
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
+    use crate::arch::port::PortGroup;
+    use crate::task::keyboard::{self, ControllerStatus, Ps2Ports};
+
+    trace::record(InterruptIndex::Keyboard.as_u8(), &stack_frame);
+    let _irq = IrqContext::enter();
+    let _timer = InterruptIndex::Keyboard.enter();
+    crate::rand::add_interrupt_timing();
+    // An interrupt latched while the byte was read by polling finds the
+    // buffer empty; the data port would only repeat the old byte.
+    if keyboard::controller_status().has(ControllerStatus::OUTPUT_FULL) {
+        let scancode = unsafe { Ps2Ports::standard().data.read() };
+        keyboard::add_scancode(scancode);
     }
 
     unsafe {
-        PICS.lock()
+        PICS.lock_irq_already_disabled()
             .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
 }
 
+/// COM1 serial IRQ handler (IRQ4).
+///
+/// Moves received bytes into the serial input stream (see
+/// [`crate::serial::stream`]), then sends an EOI to the PIC.
+extern "x86-interrupt" fn com1_interrupt_handler(
+    stack_frame: InterruptStackFrame)
+{
+    trace::record(InterruptIndex::Com1.as_u8(), &stack_frame);
+    let _irq = IrqContext::enter();
+    let _timer = InterruptIndex::Com1.enter();
+    crate::serial::receive_interrupt();
+
+    unsafe {
+        PICS.lock_irq_already_disabled()
+            .notify_end_of_interrupt(InterruptIndex::Com1.as_u8());
+    }
+}
+
+/// Why [`register_irq`] refused a handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The line has a fixed handler, or isn't one of [`DYNAMIC_LINES`].
+    Unsupported(u8),
+    /// Another handler is registered on the line.
+    InUse(u8),
+    /// The handler isn't the one registered on the line.
+    NotRegistered(u8),
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IrqError::Unsupported(line) => write!(f, "irq {} can't take a handler", line),
+            IrqError::InUse(line) => write!(f, "irq {} already has a handler", line),
+            IrqError::NotRegistered(line) => write!(f, "irq {} has another handler", line),
+        }
+    }
+}
+
+/// Call `handler` in interrupt context whenever PIC line `line` fires, and
+/// unmask the line.
+///
+/// `handler` must quiet its device before returning, since PCI interrupts
+/// stay asserted until then; the end of interrupt is sent after it. Only
+/// one handler per line, and only for [`DYNAMIC_LINES`].
+pub fn register_irq(line: u8, handler: fn()) -> Result<(), IrqError> {
+    if !DYNAMIC_LINES.iter().any(|&(dynamic, _)| dynamic == line) {
+        return Err(IrqError::Unsupported(line));
+    }
+    IRQ_HANDLERS[usize::from(line)]
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map_err(|_| IrqError::InUse(line))?;
+    let line = usize::from(line);
+    set_line_masked(line, false);
+    if line >= 8 {
+        set_line_masked(CASCADE_LINE, false);
+    }
+    Ok(())
+}
+
+/// Mask PIC line `line` again and remove `handler`, which
+/// [`register_irq`] put there.
+///
+/// The cascade line stays unmasked, as other lines behind it may be in use.
+pub fn unregister_irq(line: u8, handler: fn()) -> Result<(), IrqError> {
+    let slot = IRQ_HANDLERS.get(usize::from(line)).ok_or(IrqError::Unsupported(line))?;
+    if slot.load(Ordering::Acquire) != handler as usize {
+        return Err(IrqError::NotRegistered(line));
+    }
+    // Masked first, so the handler isn't called once it is gone.
+    set_line_masked(usize::from(line), true);
+    slot.store(0, Ordering::Release);
+    Ok(())
+}
+
+/// Handle PIC line `line` for its entry point: call the handler
+/// registered with [`register_irq`], if any, then send the EOI.
+fn dynamic_irq_handler(line: u8, stack_frame: &InterruptStackFrame) {
+    let vector = PIC_1_OFFSET + line;
+    trace::record(vector, stack_frame);
+    let _irq = IrqContext::enter();
+    let timer = enter_line(usize::from(line));
+    let handler = IRQ_HANDLERS[usize::from(line)].load(Ordering::Acquire);
+    if handler != 0 {
+        // SAFETY: only `register_irq` stores a non-zero value, and it stores
+        // a `fn()`.
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+    drop(timer);
+
+    unsafe {
+        PICS.lock_irq_already_disabled().notify_end_of_interrupt(vector);
+    }
+}
+
+/// Define an entry point per line that hands it to [`dynamic_irq_handler`],
+/// and [`DYNAMIC_LINES`] listing them.
+macro_rules! dynamic_entries {
+    ($($line:literal => $entry:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $entry(stack_frame: InterruptStackFrame) {
+                dynamic_irq_handler($line, &stack_frame);
+            }
+        )*
+
+        /// PIC lines [`register_irq`] takes handlers for, with their entry
+        /// points: the ones the firmware routes PCI interrupts to.
+        pub const DYNAMIC_LINES: &[(u8, extern "x86-interrupt" fn(InterruptStackFrame))] =
+            &[$(($line, $entry)),*];
+    };
+}
+
+dynamic_entries! {
+    3 => irq3_entry,
+    5 => irq5_entry,
+    9 => irq9_entry,
+    10 => irq10_entry,
+    11 => irq11_entry,
+}
+
+/// Spurious local APIC interrupt handler.
+///
+/// The APIC raises these when an interrupt goes away before it is
+/// delivered. They must not be acknowledged with an EOI.
+extern "x86-interrupt" fn spurious_interrupt_handler(
+    stack_frame: InterruptStackFrame)
+{
+    trace::record(crate::apic::SPURIOUS_VECTOR, &stack_frame);
+}
+
+capture_shim!(breakpoint_entry => breakpoint_handler, error_code = false);
+capture_shim!(page_fault_entry => page_fault_handler, error_code = true);
+capture_shim!(general_protection_fault_entry => general_protection_fault_handler, error_code = true);
+
 /// Breakpoint exception handler (INT3).
 ///
 /// Useful for testing that the IDT is loaded correctly and exceptions are
@@ -166,32 +765,108 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
 {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    BREAKPOINTS.fetch_add(1, Ordering::Relaxed);
+    println!("{}", fault_report(3, &stack_frame, None, &saved_registers()));
+    mark_handled();
+}
+
+/// Debug exception handler (`#DB`).
+///
+/// Reports watchpoint hits; see [`crate::debug`].
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    crate::debug::handle_trap(&stack_frame);
 }
 
+/// Return whether the CPU was in user mode when the interrupt or exception
+/// hit.
+pub(crate) fn from_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == PrivilegeLevel::Ring3 as u64
+}
+
+/// Page fault handler.
+///
+/// A fault in user mode kills the process; one in the kernel halts.
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    use x86_64::registers::control::Cr2;
+    use crate::arch::cr::Cr2;
+    use crate::process::{self, Fault};
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    if from_user_mode(&stack_frame) {
+        process::kill(Fault::PageFault(Cr2::read()));
+    }
+    let report = fault_report(14, &stack_frame, Some(error_code.bits()), &saved_registers());
+    let addr = Cr2::read();
+    if is_nx_violation(error_code, addr) {
+        // The panic screen prints the report.
+        panic!("attempted execution of non-executable memory at {:#x}", addr);
+    }
+    println!("{}", report);
     println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
     hlt_loop();
 }
 
+/// Return whether a page fault at `addr` was an instruction fetch from a
+/// present page marked no-execute.
+fn is_nx_violation(error_code: PageFaultErrorCode, addr: u64) -> bool {
+    let fetch = PageFaultErrorCode::INSTRUCTION_FETCH | PageFaultErrorCode::PROTECTION_VIOLATION;
+    error_code.contains(fetch)
+        && VirtAddr::try_new(addr)
+            .ok()
+            .and_then(crate::memory::page_flags)
+            .is_some_and(|flags| flags.contains(PageTableFlags::NO_EXECUTE))
+}
+
+/// General protection fault handler.
+///
+/// A fault in user mode kills the process; one in the kernel is a bug.
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    use crate::process::{self, Fault};
+
+    if from_user_mode(&stack_frame) {
+        process::kill(Fault::GeneralProtection(error_code));
+    }
+    // The panic screen prints the report.
+    fault_report(13, &stack_frame, Some(error_code), &saved_registers());
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT ({:#x}) at {}",
+        error_code,
+        Symbol(stack_frame.instruction_pointer.as_u64())
+    );
+}
+
 /// Double fault handler.
 ///
 /// A double fault usually indicates a serious kernel bug (e.g., stack overflow,
 /// invalid IDT/GDT/TSS setup, or an exception while handling another exception).
-/// We panic here so you get a message instead of silently resetting.
+/// We print a backtrace over serial (the frame chain continues from this
+/// stack into the faulting one) and panic so you get a message instead of
+/// silently resetting.
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    // SAFETY: a double fault never returns to the interrupted code.
+    unsafe { crate::emergency::take_over() };
+    crate::emergency_println!(
+        "EXCEPTION: DOUBLE FAULT at {}",
+        Symbol(stack_frame.instruction_pointer.as_u64())
+    );
+    crate::crashlog::record(format_args!(
+        "EXCEPTION: DOUBLE FAULT at {:#x}",
+        stack_frame.instruction_pointer.as_u64()
+    ));
+    // Another CPU may hold SERIAL1, so the backtrace goes out unlocked.
+    let _ = crate::backtrace::print(&mut crate::emergency::Emergency);
+    panic!(
+        "EXCEPTION: DOUBLE FAULT at {}\n{:#?}",
+        Symbol(stack_frame.instruction_pointer.as_u64()),
+        stack_frame
+    );
 }
 
 /// Smoke test: trigger a breakpoint exception.
@@ -202,3 +877,56 @@ extern "x86-interrupt" fn double_fault_handler(
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
+
+#[test_case]
+fn test_nx_violation_decoding() {
+    let heap = crate::allocator::HEAP_START as u64;
+    let code = test_nx_violation_decoding as fn() as usize as u64;
+    let fetch = PageFaultErrorCode::INSTRUCTION_FETCH | PageFaultErrorCode::PROTECTION_VIOLATION;
+    assert!(is_nx_violation(fetch, heap));
+    assert!(!is_nx_violation(fetch, code));
+    assert!(!is_nx_violation(PageFaultErrorCode::PROTECTION_VIOLATION, heap));
+    assert!(!is_nx_violation(PageFaultErrorCode::INSTRUCTION_FETCH, 0xdead_beef_0000));
+}
+
+#[test_case]
+fn test_early_fault_message() {
+    let fault = EarlyFault { vector: 13, name: "general protection fault", rip: u64::MAX };
+    assert_eq!(
+        fault.message().as_str(),
+        "EARLY EXCEPTION: general protection fault (vector 13) at rip 0xffffffffffffffff\n"
+    );
+}
+
+#[cfg(feature = "cpu-time")]
+#[test_case]
+fn test_slow_irq_handler_shows_in_max() {
+    let irq = InterruptIndex::Timer.irq();
+    let delay = crate::time::cycles_per_tick().expect("TSC rate not measured") / 4;
+    let before = irq_times().unwrap()[irq];
+    INJECTED_DELAY[irq].store(delay, Ordering::Relaxed);
+    let start = ticks();
+    while ticks() < start + 2 {
+        core::hint::spin_loop();
+    }
+    INJECTED_DELAY[irq].store(0, Ordering::Relaxed);
+    let after = irq_times().unwrap()[irq];
+    assert!(after.max_cycles >= delay);
+    assert!(after.total_cycles - before.total_cycles >= 2 * delay);
+    assert!(irq_times().unwrap()[InterruptIndex::Keyboard.irq()].max_cycles < delay);
+}
+
+#[test_case]
+fn test_register_irq() {
+    fn handler() {}
+
+    assert_eq!(register_irq(1, handler), Err(IrqError::Unsupported(1)));
+    assert_eq!(register_irq(3, handler), Ok(()));
+    assert_eq!(register_irq(3, handler), Err(IrqError::InUse(3)));
+    assert!(!is_line_masked(3));
+
+    assert_eq!(unregister_irq(5, handler), Err(IrqError::NotRegistered(5)));
+    assert_eq!(unregister_irq(3, handler), Ok(()));
+    assert!(is_line_masked(3));
+    assert_eq!(unregister_irq(3, handler), Err(IrqError::NotRegistered(3)));
+}
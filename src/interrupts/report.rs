@@ -0,0 +1,432 @@
+//! Compact exception reports.
+//!
+//! The `{:#?}` print of an [`InterruptStackFrame`] takes a screen of its
+//! own and leaves out the general-purpose registers. A [`FaultReport`]
+//! renders the same state, plus the registers, CR2/CR3 and the top of the
+//! stack, in under 20 lines of at most [`WIDTH`] columns.
+//!
+//! The `x86-interrupt` ABI saves registers where Rust can't see them, so
+//! the exceptions that report go through a shim made by [`capture_shim!`]:
+//! it pushes every general-purpose register, hands them to [`capture`],
+//! which copies them into this CPU's snapshot, pops them again and jumps
+//! to the real handler with the CPU's frame untouched. The handler then
+//! reads the snapshot with [`saved_registers`].
+//!
+//! The last report built on each CPU is kept. Until its handler calls
+//! [`mark_handled`], it is [pending](pending_fault), and the panic screen
+//! prints it.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::VirtAddr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::collections::FixedString;
+use crate::smp::MAX_CPUS;
+use crate::symbols::Symbol;
+use crate::sync::IrqMutex;
+
+/// Widest line of a rendered report, in columns: the VGA text width.
+pub const WIDTH: usize = 80;
+
+/// Stack qwords shown, from RSP up.
+pub const STACK_WORDS: usize = 8;
+
+/// General-purpose registers as they were when the exception hit, in the
+/// order the shim leaves them on the stack.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+/// Registers in [`Registers`].
+const REGISTER_COUNT: usize = 15;
+
+impl Registers {
+    fn to_array(self) -> [u64; REGISTER_COUNT] {
+        [
+            self.rax, self.rbx, self.rcx, self.rdx, self.rsi, self.rdi, self.rbp, self.r8,
+            self.r9, self.r10, self.r11, self.r12, self.r13, self.r14, self.r15,
+        ]
+    }
+
+    fn from_array(values: [u64; REGISTER_COUNT]) -> Self {
+        let [rax, rbx, rcx, rdx, rsi, rdi, rbp, r8, r9, r10, r11, r12, r13, r14, r15] = values;
+        Registers { rax, rbx, rcx, rdx, rsi, rdi, rbp, r8, r9, r10, r11, r12, r13, r14, r15 }
+    }
+}
+
+/// Register names, in [`Registers`] order.
+const REGISTER_NAMES: [&str; REGISTER_COUNT] = [
+    "RAX", "RBX", "RCX", "RDX", "RSI", "RDI", "RBP", "R8", "R9", "R10", "R11", "R12", "R13",
+    "R14", "R15",
+];
+
+/// Each CPU's last register snapshot, written by [`capture`].
+static SNAPSHOTS: [[AtomicU64; REGISTER_COUNT]; MAX_CPUS] =
+    [const { [const { AtomicU64::new(0) }; REGISTER_COUNT] }; MAX_CPUS];
+
+/// Each CPU's last report, and whether its handler has finished with it.
+static LAST: [IrqMutex<Option<(FaultReport, bool)>>; MAX_CPUS] =
+    [const { IrqMutex::named("fault report", None) }; MAX_CPUS];
+
+fn cpu_index() -> usize {
+    crate::smp::current().map_or(0, |cpu| cpu.index)
+}
+
+/// Copy the registers a [`capture_shim!`] shim pushed into this CPU's
+/// snapshot.
+pub(super) extern "C" fn capture(registers: &Registers) {
+    let snapshot = &SNAPSHOTS[cpu_index()];
+    for (slot, value) in snapshot.iter().zip(registers.to_array()) {
+        slot.store(value, Ordering::Relaxed);
+    }
+}
+
+/// Return the registers the shim saved for the exception this CPU is
+/// handling.
+///
+/// A nested exception on the same CPU overwrites them.
+pub fn saved_registers() -> Registers {
+    let snapshot = &SNAPSHOTS[cpu_index()];
+    Registers::from_array(core::array::from_fn(|index| snapshot[index].load(Ordering::Relaxed)))
+}
+
+/// Define `$shim`, an IDT entry that saves the general-purpose registers
+/// for [`saved_registers`] and then jumps to the `x86-interrupt` handler
+/// `$handler`.
+///
+/// `error_code` says whether the CPU pushes an error code for the vector;
+/// the shim then realigns the stack for the call.
+macro_rules! capture_shim {
+    ($shim:ident => $handler:path, error_code = false) => {
+        capture_shim!(@define $shim, $handler, "0");
+    };
+    ($shim:ident => $handler:path, error_code = true) => {
+        capture_shim!(@define $shim, $handler, "8");
+    };
+    (@define $shim:ident, $handler:path, $pad:literal) => {
+        #[unsafe(naked)]
+        unsafe extern "C" fn $shim() {
+            core::arch::naked_asm!(
+                "push r15",
+                "push r14",
+                "push r13",
+                "push r12",
+                "push r11",
+                "push r10",
+                "push r9",
+                "push r8",
+                "push rbp",
+                "push rdi",
+                "push rsi",
+                "push rdx",
+                "push rcx",
+                "push rbx",
+                "push rax",
+                "mov rdi, rsp",
+                concat!("sub rsp, ", $pad),
+                "cld",
+                "call {capture}",
+                concat!("add rsp, ", $pad),
+                "pop rax",
+                "pop rbx",
+                "pop rcx",
+                "pop rdx",
+                "pop rsi",
+                "pop rdi",
+                "pop rbp",
+                "pop r8",
+                "pop r9",
+                "pop r10",
+                "pop r11",
+                "pop r12",
+                "pop r13",
+                "pop r14",
+                "pop r15",
+                "jmp {handler}",
+                capture = sym $crate::interrupts::report::capture,
+                handler = sym $handler,
+            )
+        }
+    };
+}
+
+pub(super) use capture_shim;
+
+/// Return the address of a [`capture_shim!`] shim, for the IDT.
+pub(super) fn shim_addr(shim: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(shim as usize as u64)
+}
+
+/// Everything a [`FaultReport`] shows, copied out of the handler.
+#[derive(Debug, Clone, Copy)]
+pub struct FaultReport {
+    pub vector: u8,
+    pub error_code: Option<u64>,
+    pub registers: Registers,
+    pub cs: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub ss: u64,
+    pub rsp: u64,
+    /// The faulting address, for page faults.
+    pub cr2: Option<u64>,
+    pub cr3: u64,
+    /// The top of the stack, or `None` if RSP doesn't point at mapped
+    /// memory.
+    pub stack: Option<[u64; STACK_WORDS]>,
+}
+
+/// Build the report for exception `vector` from the handler's frame, its
+/// error code and the saved registers, and keep it as this CPU's
+/// [pending fault](pending_fault).
+pub fn fault_report(
+    vector: u8,
+    stack_frame: &InterruptStackFrame,
+    error_code: Option<u64>,
+    registers: &Registers,
+) -> FaultReport {
+    use crate::arch::cr::{Cr2, Cr3};
+
+    let rsp = stack_frame.stack_pointer.as_u64();
+    let report = FaultReport {
+        vector,
+        error_code,
+        registers: *registers,
+        cs: stack_frame.code_segment,
+        rip: stack_frame.instruction_pointer.as_u64(),
+        rflags: stack_frame.cpu_flags,
+        ss: stack_frame.stack_segment,
+        rsp,
+        cr2: (vector == 14).then(Cr2::read),
+        cr3: Cr3::read_raw(),
+        stack: read_stack(rsp),
+    };
+    if let Some(mut last) = LAST[cpu_index()].try_lock() {
+        *last = Some((report, false));
+    }
+    report
+}
+
+/// Read [`STACK_WORDS`] qwords at `rsp`, if all of them are mapped.
+fn read_stack(rsp: u64) -> Option<[u64; STACK_WORDS]> {
+    let end = rsp.checked_add((STACK_WORDS * 8) as u64 - 1)?;
+    for addr in [rsp, end] {
+        crate::memory::page_flags(VirtAddr::try_new(addr).ok()?)?;
+    }
+    let words = rsp as *const u64;
+    // SAFETY: both ends of the range are mapped, and it spans at most two
+    // pages. Stack pointers are normally aligned, but don't rely on it.
+    Some(core::array::from_fn(|index| unsafe { words.add(index).read_unaligned() }))
+}
+
+/// Note that this CPU's handler is done with its report, so a later panic
+/// doesn't print it.
+pub fn mark_handled() {
+    if let Some(mut last) = LAST[cpu_index()].try_lock()
+        && let Some((_, handled)) = last.as_mut()
+    {
+        *handled = true;
+    }
+}
+
+/// Return the last report built on this CPU.
+pub fn last_fault() -> Option<FaultReport> {
+    LAST[cpu_index()].try_lock()?.map(|(report, _)| report)
+}
+
+/// Return the last report built on this CPU if its handler never
+/// finished, as when it panicked.
+pub fn pending_fault() -> Option<FaultReport> {
+    LAST[cpu_index()].try_lock()?.filter(|&(_, handled)| !handled).map(|(report, _)| report)
+}
+
+/// Return the mnemonic and name of exception `vector`.
+pub fn exception_name(vector: u8) -> (&'static str, &'static str) {
+    match vector {
+        0 => ("#DE", "divide error"),
+        1 => ("#DB", "debug"),
+        2 => ("NMI", "non-maskable interrupt"),
+        3 => ("#BP", "breakpoint"),
+        4 => ("#OF", "overflow"),
+        5 => ("#BR", "bound range exceeded"),
+        6 => ("#UD", "invalid opcode"),
+        7 => ("#NM", "device not available"),
+        8 => ("#DF", "double fault"),
+        10 => ("#TS", "invalid TSS"),
+        11 => ("#NP", "segment not present"),
+        12 => ("#SS", "stack-segment fault"),
+        13 => ("#GP", "general protection fault"),
+        14 => ("#PF", "page fault"),
+        16 => ("#MF", "x87 floating-point error"),
+        17 => ("#AC", "alignment check"),
+        18 => ("#MC", "machine check"),
+        19 => ("#XM", "SIMD floating-point error"),
+        20 => ("#VE", "virtualization exception"),
+        21 => ("#CP", "control protection"),
+        _ => ("#??", "exception"),
+    }
+}
+
+/// RFLAGS bits worth naming, lowest first. IOPL is shown separately.
+const RFLAGS_BITS: [(u32, &str); 16] = [
+    (0, "CF"),
+    (2, "PF"),
+    (4, "AF"),
+    (6, "ZF"),
+    (7, "SF"),
+    (8, "TF"),
+    (9, "IF"),
+    (10, "DF"),
+    (11, "OF"),
+    (14, "NT"),
+    (16, "RF"),
+    (17, "VM"),
+    (18, "AC"),
+    (19, "VIF"),
+    (20, "VIP"),
+    (21, "ID"),
+];
+
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (mnemonic, name) = exception_name(self.vector);
+        write!(f, "EXCEPTION {} {} (vector {})", mnemonic, name, self.vector)?;
+        if let Some(code) = self.error_code {
+            write!(f, " error {:#x}", code)?;
+        }
+        writeln!(f)?;
+
+        let values = self.registers.to_array();
+        for pair in 0..REGISTER_COUNT.div_ceil(2) {
+            for column in 0..2 {
+                let index = pair * 2 + column;
+                let Some(value) = values.get(index) else { continue };
+                if column == 1 {
+                    f.write_str("  ")?;
+                }
+                write!(f, "{:<3} {:016x}", REGISTER_NAMES[index], value)?;
+            }
+            writeln!(f)?;
+        }
+
+        // The symbol gets whatever the line has left.
+        let mut symbol = FixedString::<{ WIDTH - 29 }>::new();
+        let _ = write!(symbol, "{}", Symbol(self.rip));
+        writeln!(f, "CS:RIP {:04x}:{:016x} {}", self.cs, self.rip, symbol)?;
+
+        write!(f, "RFLAGS {:016x} [", self.rflags)?;
+        for (bit, flag) in RFLAGS_BITS {
+            if self.rflags & (1 << bit) != 0 {
+                write!(f, " {}", flag)?;
+            }
+        }
+        let iopl = (self.rflags >> 12) & 3;
+        if iopl != 0 {
+            write!(f, " IOPL={}", iopl)?;
+        }
+        writeln!(f, " ]")?;
+
+        writeln!(f, "SS:RSP {:04x}:{:016x}", self.ss, self.rsp)?;
+        match self.cr2 {
+            Some(cr2) => writeln!(f, "CR2 {:016x}  CR3 {:016x}", cr2, self.cr3)?,
+            None => writeln!(f, "CR3 {:016x}", self.cr3)?,
+        }
+
+        match self.stack {
+            Some(stack) => {
+                for (row, words) in stack.chunks(4).enumerate() {
+                    write!(f, "[rsp+{:02x}]", row * 32)?;
+                    for word in words {
+                        write!(f, " {:016x}", word)?;
+                    }
+                    writeln!(f)?;
+                }
+            }
+            None => writeln!(f, "[rsp] not mapped")?,
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_breakpoint_report_shows_planted_registers() {
+    use alloc::format;
+    use alloc::string::String;
+
+    const R12: u64 = 0x1212_3434_5656_7878;
+    const R13: u64 = 0x1313_abab_cdcd_efef;
+    const R14: u64 = 0x1414_0000_ffff_0001;
+    const R15: u64 = 0x1515_dead_beef_1515;
+
+    // SAFETY: the breakpoint handler returns, leaving every register as
+    // it found it.
+    unsafe {
+        core::arch::asm!(
+            "int3",
+            in("r12") R12,
+            in("r13") R13,
+            in("r14") R14,
+            in("r15") R15,
+        );
+    }
+
+    let report = last_fault().expect("breakpoint left no report");
+    assert_eq!(report.vector, 3);
+    assert_eq!(report.error_code, None);
+    assert_eq!(
+        (report.registers.r12, report.registers.r13, report.registers.r14, report.registers.r15),
+        (R12, R13, R14, R15)
+    );
+    assert!(pending_fault().is_none(), "breakpoint handler didn't mark its report handled");
+
+    let rendered: String = format!("{}", report);
+    let lines: alloc::vec::Vec<&str> = rendered.lines().collect();
+    assert!(lines.len() < 20, "report takes {} lines", lines.len());
+    for line in &lines {
+        assert!(line.len() <= WIDTH, "line wider than {}: {:?}", WIDTH, line);
+    }
+    assert!(lines[0].starts_with("EXCEPTION #BP breakpoint (vector 3)"));
+    assert!(rendered.contains(&format!("R12 {:016x}  R13 {:016x}", R12, R13)));
+    assert!(rendered.contains(&format!("R14 {:016x}  R15 {:016x}", R14, R15)));
+    assert!(rendered.contains("\nRFLAGS "));
+    assert!(rendered.contains("[rsp+00] "));
+}
+
+#[test_case]
+fn test_page_fault_report_shows_cr2_and_unmapped_stack() {
+    let report = FaultReport {
+        vector: 14,
+        error_code: Some(2),
+        registers: Registers::default(),
+        cs: 8,
+        rip: 0,
+        rflags: 0x3046,
+        ss: 0x10,
+        rsp: 0,
+        cr2: Some(0xdead_b000),
+        cr3: 0x1000,
+        stack: None,
+    };
+    let rendered = alloc::format!("{}", report);
+    assert!(rendered.starts_with("EXCEPTION #PF page fault (vector 14) error 0x2\n"));
+    assert!(rendered.contains("RFLAGS 0000000000003046 [ PF ZF IOPL=3 ]\n"));
+    assert!(rendered.contains("CR2 00000000deadb000  CR3 0000000000001000\n"));
+    assert!(rendered.ends_with("[rsp] not mapped\n"));
+}
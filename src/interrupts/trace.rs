@@ -0,0 +1,225 @@
+//! Per-vector interrupt trace, for debugging interrupt storms.
+//!
+//! [`irq_counts`](super::irq_counts) shows how often an interrupt fires but
+//! not when, or what it kept interrupting. While tracing is [enabled](enable)
+//! for a vector, each occurrence appends a [`Record`] of the vector, the TSC
+//! and the interrupted RIP to a ring of the last [`RING_SIZE`] records.
+//!
+//! Handlers write the ring without a lock or allocation: a writer claims a
+//! slot by bumping a shared counter and stamps the slot with the count once
+//! it is filled in, so a reader can tell a slot still being written, or
+//! already reused, from a finished one. [`dump`] copies the newest records
+//! with interrupts off and prints them with the time since the record
+//! before and the function each RIP falls in.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering, fence};
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::symbols::Symbol;
+use crate::time::{self, TIMER_HZ};
+
+/// Records the ring keeps.
+pub const RING_SIZE: usize = 256;
+
+/// One traced interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub vector: u8,
+    /// TSC when the handler started.
+    pub tsc: u64,
+    /// Where the interrupt came in.
+    pub rip: u64,
+}
+
+struct Slot {
+    /// One more than the record's position in the trace once it is
+    /// complete; 0 while it is being written.
+    stamp: AtomicU64,
+    vector: AtomicU64,
+    tsc: AtomicU64,
+    rip: AtomicU64,
+}
+
+/// Traced vectors, one bit each.
+static ENABLED: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+/// Records ever written.
+static NEXT: AtomicU64 = AtomicU64::new(0);
+static RING: [Slot; RING_SIZE] = [const {
+    Slot {
+        stamp: AtomicU64::new(0),
+        vector: AtomicU64::new(0),
+        tsc: AtomicU64::new(0),
+        rip: AtomicU64::new(0),
+    }
+}; RING_SIZE];
+
+fn bit(vector: u8) -> (&'static AtomicU64, u64) {
+    (&ENABLED[usize::from(vector / 64)], 1 << (vector % 64))
+}
+
+/// Start tracing `vector`.
+pub fn enable(vector: u8) {
+    let (word, mask) = bit(vector);
+    word.fetch_or(mask, Ordering::Relaxed);
+}
+
+/// Stop tracing `vector`. Its records stay in the ring.
+pub fn disable(vector: u8) {
+    let (word, mask) = bit(vector);
+    word.fetch_and(!mask, Ordering::Relaxed);
+}
+
+/// Return whether `vector` is traced.
+pub fn is_enabled(vector: u8) -> bool {
+    let (word, mask) = bit(vector);
+    word.load(Ordering::Relaxed) & mask != 0
+}
+
+/// Return the traced vectors, lowest first.
+pub fn enabled_vectors() -> impl Iterator<Item = u8> {
+    (0..=u8::MAX).filter(|&vector| is_enabled(vector))
+}
+
+/// Record an occurrence of `vector` if it is traced. Called first thing by
+/// the interrupt handlers; never blocks or allocates.
+pub fn record(vector: u8, stack_frame: &InterruptStackFrame) {
+    if !is_enabled(vector) {
+        return;
+    }
+    let tsc = time::rdtsc();
+    let position = NEXT.fetch_add(1, Ordering::Relaxed);
+    let slot = &RING[position as usize % RING_SIZE];
+    slot.stamp.store(0, Ordering::Relaxed);
+    fence(Ordering::Release);
+    slot.vector.store(u64::from(vector), Ordering::Relaxed);
+    slot.tsc.store(tsc, Ordering::Relaxed);
+    slot.rip.store(stack_frame.instruction_pointer.as_u64(), Ordering::Relaxed);
+    slot.stamp.store(position + 1, Ordering::Release);
+}
+
+/// Return the record at `position`, unless it is being written or has been
+/// overwritten.
+fn read(position: u64) -> Option<Record> {
+    let slot = &RING[position as usize % RING_SIZE];
+    let stamp = slot.stamp.load(Ordering::Acquire);
+    let record = Record {
+        vector: slot.vector.load(Ordering::Relaxed) as u8,
+        tsc: slot.tsc.load(Ordering::Relaxed),
+        rip: slot.rip.load(Ordering::Relaxed),
+    };
+    fence(Ordering::Acquire);
+    (stamp == position + 1 && slot.stamp.load(Ordering::Relaxed) == stamp).then_some(record)
+}
+
+/// A copy of the newest records, oldest first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub records: Vec<Record>,
+    /// Records written since boot (or the last [`clear`]).
+    pub total: u64,
+}
+
+/// Copy the newest `last_n` records (at most [`RING_SIZE`]).
+pub fn snapshot(last_n: usize) -> Snapshot {
+    // Allocated up front: nothing may allocate with interrupts off.
+    let mut records = Vec::with_capacity(last_n.min(RING_SIZE));
+    let total = interrupts::without_interrupts(|| {
+        let total = NEXT.load(Ordering::Acquire);
+        let start = total.saturating_sub(records.capacity() as u64);
+        records.extend((start..total).filter_map(read));
+        total
+    });
+    Snapshot { records, total }
+}
+
+/// Forget every record. Which vectors are traced doesn't change.
+pub fn clear() {
+    interrupts::without_interrupts(|| {
+        for slot in &RING {
+            slot.stamp.store(0, Ordering::Relaxed);
+        }
+        NEXT.store(0, Ordering::Relaxed);
+    });
+}
+
+/// Microseconds in `cycles`, going by the TSC rate the timer measured.
+fn cycles_to_us(cycles: u64) -> Option<u64> {
+    let per_second = time::cycles_per_tick()? * u64::from(TIMER_HZ);
+    Some((u128::from(cycles) * 1_000_000 / u128::from(per_second)) as u64)
+}
+
+/// Print the newest `last_n` records, oldest first, each with the cycles
+/// (and microseconds) since the record before it.
+pub fn dump(last_n: usize, out: &mut dyn fmt::Write) -> fmt::Result {
+    let snapshot = snapshot(last_n);
+    write!(out, "traced vectors:")?;
+    let mut any = false;
+    for vector in enabled_vectors() {
+        write!(out, " {}", vector)?;
+        any = true;
+    }
+    writeln!(out, "{}", if any { "" } else { " none" })?;
+    writeln!(out, "{} records, showing {}", snapshot.total, snapshot.records.len())?;
+    let mut previous = None;
+    for record in &snapshot.records {
+        write!(out, "{:>3} tsc {:>16}", record.vector, record.tsc)?;
+        match previous.map(|tsc| record.tsc.wrapping_sub(tsc)) {
+            Some(delta) => {
+                write!(out, " {:>+12}", delta)?;
+                match cycles_to_us(delta) {
+                    Some(us) => write!(out, " {:>8} us", us)?,
+                    None => write!(out, " {:>8}   ", "-")?,
+                }
+            }
+            None => write!(out, " {:>12} {:>8}   ", "-", "-")?,
+        }
+        writeln!(out, "  {}", Symbol(record.rip))?;
+        previous = Some(record.tsc);
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_enable_is_per_vector() {
+    assert!(!is_enabled(200));
+    enable(200);
+    enable(3);
+    assert!(is_enabled(200) && is_enabled(3) && !is_enabled(201));
+    assert!(enabled_vectors().eq([3, 200]));
+    disable(200);
+    disable(3);
+    assert_eq!(enabled_vectors().count(), 0);
+}
+
+#[test_case]
+fn test_timer_trace_follows_the_pit() {
+    use super::InterruptIndex;
+
+    let timer = InterruptIndex::Timer.as_u8();
+    clear();
+    enable(timer);
+    time::sleep_ms(100);
+    disable(timer);
+
+    let traced = snapshot(RING_SIZE);
+    let records = &traced.records;
+    // 100 ms is 10 ticks; allow for a slow start or a slow host.
+    assert!(records.len() >= 5, "only {} timer records", records.len());
+    assert!(records.iter().all(|record| record.vector == timer));
+    let period = time::cycles_per_tick().expect("timer running");
+    for pair in records.windows(2) {
+        let delta = pair[1].tsc.wrapping_sub(pair[0].tsc);
+        assert!(pair[1].tsc > pair[0].tsc);
+        assert!(delta > period / 4 && delta < period * 8, "delta {} vs period {}", delta, period);
+    }
+
+    let mut out = alloc::string::String::new();
+    dump(3, &mut out).unwrap();
+    assert!(out.starts_with("traced vectors: none\n"));
+    assert_eq!(out.lines().count(), 2 + 3);
+    clear();
+    assert!(snapshot(RING_SIZE).records.is_empty());
+}
@@ -0,0 +1,152 @@
+//! Kernel log: recent console output and the log level.
+//!
+//! Everything printed with `print!`/`println!` is also appended to a
+//! fixed-size ring, so the shell's `dmesg` can show boot messages after they
+//! have scrolled off the screen. Recording never allocates and never waits:
+//! output that arrives while the ring is locked (say, from an interrupt
+//! handler or a panic) is left out of the ring, though it is still printed.
+//! With `hostlog` on the command line, output is also copied to the
+//! [`hostlog`](crate::hostlog) ring for the host to read.
+//!
+//! The log level decides how chatty boot is: 0 prints only failures, 1 (the
+//! default) adds progress messages. It starts from the `loglevel`
+//! command-line option and the shell can change it.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::collections::FixedRing;
+
+/// Bytes of output the ring keeps.
+pub const RING_SIZE: usize = 8 * 1024;
+
+/// Log level used when the command line doesn't set one.
+pub const DEFAULT_LEVEL: u8 = 1;
+
+static LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LEVEL);
+
+/// Return the current log level.
+pub fn level() -> u8 {
+    LEVEL.load(Ordering::Relaxed)
+}
+
+/// Change the log level.
+pub fn set_level(level: u8) {
+    LEVEL.store(level, Ordering::Relaxed);
+}
+
+/// A byte ring that overwrites its oldest contents.
+struct Ring(FixedRing<u8, RING_SIZE>);
+
+impl Ring {
+    const fn new() -> Self {
+        Ring(FixedRing::new())
+    }
+
+    /// Copy the contents, oldest first, into `out` and return how many bytes
+    /// were copied.
+    fn copy_to(&mut self, out: &mut [u8; RING_SIZE]) -> usize {
+        let (older, newer) = self.0.as_slices();
+        out[..older.len()].copy_from_slice(older);
+        out[older.len()..older.len() + newer.len()].copy_from_slice(newer);
+        older.len() + newer.len()
+    }
+}
+
+impl fmt::Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.0.push_overwrite(byte);
+        }
+        Ok(())
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring::new());
+
+/// Append formatted output to the ring, unless it is busy.
+pub(crate) fn record(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    interrupts::without_interrupts(|| {
+        if let Some(mut ring) = RING.try_lock() {
+            let _ = ring.write_fmt(args);
+        }
+    });
+    crate::hostlog::record(args);
+}
+
+/// Call `f` with the ring's contents, oldest first in two parts, unless the
+/// ring is busy. Never waits, so the panic path can use it.
+pub(crate) fn with_contents<R>(f: impl FnOnce(&[u8], &[u8]) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut ring = RING.try_lock()?;
+        let (older, newer) = ring.0.as_slices();
+        Some(f(older, newer))
+    })
+}
+
+/// Write the ring's contents to `out`, oldest first.
+///
+/// When the ring has wrapped, the first line is usually cut off; it is
+/// skipped.
+pub fn dump(out: &mut dyn fmt::Write) -> fmt::Result {
+    let mut copy = [0; RING_SIZE];
+    let (len, wrapped) = interrupts::without_interrupts(|| {
+        let mut ring = RING.lock();
+        (ring.copy_to(&mut copy), ring.0.is_full())
+    });
+    let mut text = &copy[..len];
+    if wrapped && let Some(newline) = text.iter().position(|&b| b == b'\n') {
+        text = &text[newline + 1..];
+    }
+    // A multi-byte character may have been cut at the start.
+    for chunk in text.utf8_chunks() {
+        out.write_str(chunk.valid())?;
+    }
+    Ok(())
+}
+
+/// Write the ring to COM1 unless console output already goes there, so the
+/// host's log ends with what the screen showed. Registered with
+/// [`power::on_shutdown`](crate::power::on_shutdown).
+pub fn sync_to_serial() {
+    if crate::console::selected().has_serial() {
+        return;
+    }
+    if let Ok(serial) = crate::serial::SERIAL1.try_get() {
+        let _ = dump(&mut *serial.lock());
+    }
+}
+
+#[test_case]
+fn test_ring_wraps_oldest_first() {
+    use core::fmt::Write;
+
+    static TEST_RING: Mutex<Ring> = Mutex::new(Ring::new());
+    let mut ring = TEST_RING.lock();
+    write!(ring, "hello ").unwrap();
+    let mut copy = [0; RING_SIZE];
+    let len = ring.copy_to(&mut copy);
+    assert_eq!(&copy[..len], b"hello ");
+
+    for i in 0..RING_SIZE {
+        write!(ring, "{}", i % 10).unwrap();
+    }
+    write!(ring, "end").unwrap();
+    let len = ring.copy_to(&mut copy);
+    assert_eq!(len, RING_SIZE);
+    assert!(copy.ends_with(b"789end"));
+}
+
+#[test_case]
+fn test_println_is_recorded() {
+    use alloc::string::String;
+
+    crate::println!("klog marker 4127");
+    let mut log = String::new();
+    dump(&mut log).unwrap();
+    assert!(log.contains("klog marker 4127\n"));
+}
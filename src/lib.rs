@@ -12,21 +12,89 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-#[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+pub use bootloader::{entry_point, BootInfo};
 
 extern crate alloc;
 use core::panic::PanicInfo;
 
+pub mod acpi;
+pub mod apic;
+pub mod arch;
+pub mod ata;
+pub mod backtrace;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod cmdline;
+pub mod collections;
+pub mod console;
+pub mod cpu;
+pub mod crashlog;
+pub mod debug;
+pub mod debugcon;
+pub mod emergency;
+pub mod error;
+pub mod fs;
 pub mod gdt;
+pub mod hostlog;
+pub mod init;
 pub mod interrupts;
+pub mod klog;
+pub mod loader;
 pub mod serial;
+pub mod shell;
+pub mod speaker;
+pub mod statusbar;
+pub mod symbols;
+pub mod sync;
 pub mod vga_buffer;
 pub mod memory;
+pub mod net;
+pub mod output;
+pub mod pci;
+pub mod power;
+pub mod process;
+pub mod profile;
+pub mod ramdisk;
+pub mod rand;
+pub mod rtc;
+pub mod scrub;
+pub mod selftest;
+pub mod severity;
+pub mod smp;
 pub mod allocator;
+pub mod test_framework;
+pub mod task;
+pub mod time;
+pub mod thread;
+pub mod version;
+
+pub use version::{version_info, VersionInfo};
+
+/// Define the `_start` entry point of a test binary.
+///
+/// The generated entry function runs [`init`] and [`init_memory`], then the
+/// binary's `test_main` (from `reexport_test_harness_main`), so every test
+/// crate boots the same way as the library's own unit tests. Test runs always
+/// have QEMU's `isa-debug-exit` device, so [`power::shutdown`] may use it. Binaries with
+/// `harness = false` use [`entry_point!`] directly instead.
+#[macro_export]
+macro_rules! test_entry_point {
+    () => {
+        $crate::entry_point!(test_kernel_main);
+
+        fn test_kernel_main(boot_info: &'static $crate::BootInfo) -> ! {
+            $crate::interrupts::init_early();
+            $crate::init();
+            $crate::power::set_debug_exit_configured(true);
+            $crate::init_memory(boot_info);
+            test_main();
+            $crate::hlt_loop();
+        }
+    };
+}
 
 #[cfg(test)]
-entry_point!(test_kernel_main);
+test_entry_point!();
 
 /// Trait implemented by things that can be run as tests.
 ///
@@ -34,6 +102,9 @@ entry_point!(test_kernel_main);
 /// success. The test harness passes us a slice of `&dyn Testable`.
 pub trait Testable {
     fn run(&self) -> ();
+
+    /// Name matched against the `test` command-line filter.
+    fn name(&self) -> &'static str;
 }
 
 /// Blanket impl so plain `fn()` tests can be used directly.
@@ -44,10 +115,14 @@ where
     T: Fn(),
 {
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
+        serial_print!("{}...\t", self.name());
         self();
         serial_println!("[ok]");
     }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
 }
 
 /// Exit codes understood by QEMU when using the `isa-debug-exit` device.
@@ -63,70 +138,232 @@ pub enum QemuExitCode {
     Failed = 0x11,
 }
 
-/// Exit QEMU with a specific status code.
+/// Width of a write to the `isa-debug-exit` port.
 ///
-/// This relies on QEMU being launched with the debug exit device enabled
-/// (commonly `-device isa-debug-exit,iobase=0xf4,iosize=0x04`).
-pub fn exit_qemu(exit_code: QemuExitCode) {
+/// QEMU only acts on writes that match the device's `iosize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoSize {
+    U8,
+    U16,
+    U32,
+}
+
+impl IoSize {
+    /// Return the size for an `iosize` of `bytes`, if it is 1, 2 or 4.
+    pub fn from_bytes(bytes: u64) -> Option<IoSize> {
+        match bytes {
+            1 => Some(IoSize::U8),
+            2 => Some(IoSize::U16),
+            4 => Some(IoSize::U32),
+            _ => None,
+        }
+    }
+}
+
+/// Where the `isa-debug-exit` device is and how wide its port is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugExit {
+    pub port: u16,
+    pub size: IoSize,
+}
+
+/// The device `cargo test` starts QEMU with; see `test-args` in
+/// `Cargo.toml`.
+pub const DEFAULT_DEBUG_EXIT: DebugExit = DebugExit { port: 0xf4, size: IoSize::U32 };
+
+/// TSC cycles to wait after a write to the debug-exit port before deciding
+/// it had no effect.
+const DEBUG_EXIT_WAIT_CYCLES: u64 = 5_000;
+
+/// Return the debug-exit device to use: [`DEFAULT_DEBUG_EXIT`], with the
+/// port and size overridden by the `debugexit=` and `debugexit_size=`
+/// command-line options.
+pub fn debug_exit() -> DebugExit {
+    let port = cmdline::get_u64("debugexit").and_then(|port| u16::try_from(port).ok());
+    let size = cmdline::get_u64("debugexit_size").and_then(IoSize::from_bytes);
+    DebugExit {
+        port: port.unwrap_or(DEFAULT_DEBUG_EXIT.port),
+        size: size.unwrap_or(DEFAULT_DEBUG_EXIT.size),
+    }
+}
+
+/// Write `exit_code` to the debug-exit port as a `size`-wide value.
+///
+/// Returns if QEMU didn't exit, which it doesn't when `size` doesn't match
+/// the device or there is no device.
+pub fn exit_qemu_sized(exit_code: QemuExitCode, size: IoSize) {
     use x86_64::instructions::port::Port;
 
+    let port = debug_exit().port;
+    let code = exit_code as u32;
     unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
+        match size {
+            IoSize::U8 => Port::<u8>::new(port).write(code as u8),
+            IoSize::U16 => Port::<u16>::new(port).write(code as u16),
+            IoSize::U32 => Port::<u32>::new(port).write(code),
+        }
     }
 }
 
+/// Exit QEMU with a specific status code.
+///
+/// This relies on QEMU being launched with the debug exit device enabled
+/// (commonly `-device isa-debug-exit,iobase=0xf4,iosize=0x04`; see
+/// [`debug_exit`] for other ports and sizes). The configured size is tried
+/// first, then 32-, 16- and 8-bit writes. If the kernel is still running
+/// after all of them, it prints "isa-debug-exit not responding, halting" and
+/// powers off through ACPI (see [`power::power_off`]), so a run with a
+/// misconfigured device ends instead of waiting for the test timeout. The
+/// host then sees a clean power-off rather than `exit_code`, so the serial
+/// log is what tells a failure apart.
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    let configured = debug_exit().size;
+    let fallbacks = [IoSize::U32, IoSize::U16, IoSize::U8];
+    let sizes = core::iter::once(configured)
+        .chain(fallbacks.into_iter().filter(|&size| size != configured));
+    for size in sizes {
+        exit_qemu_sized(exit_code, size);
+        let deadline = time::rdtsc() + DEBUG_EXIT_WAIT_CYCLES;
+        while time::rdtsc() < deadline {
+            core::hint::spin_loop();
+        }
+    }
+    serial_println!("isa-debug-exit not responding, halting");
+    power::power_off();
+}
+
 /// Initialize core CPU/kernel state needed for interrupts and basic runtime.
 ///
 /// Order matters here:
-/// - Load GDT/TSS (needed for IST stacks like double fault)
-/// - Load IDT
-/// - Initialize the PICs (enable delivery of IRQs)
-/// - Enable CPU interrupts
+/// - Record the boot stack for backtraces (while it is still shallow)
+/// - Set up the VGA writer and serial port (the console [`init::Stage`])
+/// - Read the command line, pick the console and set the log level
+/// - Run the [`init::Stage`]s that don't need memory: load the GDT/TSS
+///   (needed for IST stacks like double fault), load the IDT, then set up
+///   the PICs and PIT and enable interrupts
+/// - Turn on no-execute pages, so every later mapping can use them
+/// - Log what the CPU supports, unless the log level is 0
+///
+/// Halts if a critical stage fails.
 pub fn init() {
-    gdt::init();
-    interrupts::init_idt();
-    unsafe { interrupts::PICS.lock().initialize() };
-    x86_64::instructions::interrupts::enable();
+    backtrace::init();
+    init::run_console();
+    cmdline::init_from_firmware();
+    console::init();
+    if let Some(level) = cmdline::get_u64("loglevel") {
+        klog::set_level(level.min(u8::MAX.into()) as u8);
+    }
+    init::run_early();
+    memory::enable_nx();
+    if klog::level() > 0 {
+        cpu::log_summary();
+    }
+}
+
+/// Set up paging and the kernel heap from the bootloader's memory map, then
+/// probe devices, check the executor can allocate and start the
+/// [`hostlog`] ring if the command line asks for it.
+///
+/// Call once, after [`init`]. Halts if a critical stage fails; see
+/// [`init::report`] for the outcome of each stage.
+pub fn init_memory(boot_info: &'static BootInfo) {
+    init::run_memory(boot_info);
+    hostlog::init();
 }
 
 
 /// Custom test runner used by the `custom_test_frameworks` feature.
 ///
-/// Prints test count, executes tests, then exits QEMU with a success code.
+/// Prints test count, executes tests, [quiesces](power::quiesce) the
+/// subsystems, then exits QEMU with a success code. With the
+/// `test-poweroff` feature, the run ends with [`power::shutdown`] instead,
+/// which quiesces too and which QEMU reports as a clean exit.
+///
+/// A `test=<substring>` command-line option runs only the tests whose name
+/// contains the substring. With the `heap-debug` feature, the heap's
+/// canaries are checked after every test.
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
+    let filter = cmdline::get_str("test");
+    let selected = |test: &&&dyn Testable| filter.is_none_or(|f| test.name().contains(f));
+    let count = tests.iter().filter(selected).count();
+    serial_println!("Running {} tests", count);
+    if count < tests.len() {
+        serial_println!("({} filtered out by test={})", tests.len() - count, filter.unwrap_or(""));
+    }
+    for test in tests.iter().filter(selected) {
         test.run();
+        #[cfg(feature = "heap-debug")]
+        if let Some(check) = allocator::verify_heap()
+            && check.corrupted > 0
+        {
+            panic!("{} left the heap corrupted: {}", test.name(), check);
+        }
+    }
+    #[cfg(feature = "test-poweroff")]
+    power::shutdown();
+    #[cfg(not(feature = "test-poweroff"))]
+    {
+        power::quiesce();
+        exit_qemu(QemuExitCode::Success);
     }
-    exit_qemu(QemuExitCode::Success);
+}
+
+/// Report a kernel panic everywhere it can be seen.
+///
+/// Takes over the output, prints the message through the
+/// [emergency](emergency) path, records it in the [crash log](crashlog),
+/// draws the [panic screen](vga_buffer::panic_screen), then prints the
+/// message, any pending fault and the version through the usual output,
+/// and a backtrace through the emergency path. The kernel's panic handler
+/// halts after this.
+///
+/// # Safety
+///
+/// As for [`emergency::take_over`]: whoever held the output locks must
+/// never resume.
+pub unsafe fn report_panic(info: &PanicInfo) {
+    let released = unsafe { emergency::take_over() };
+    emergency_println!("kernel panic: {}", info);
+    crashlog::record(format_args!("kernel panic: {}", info));
+    unsafe { vga_buffer::panic_screen(info) };
+    if !released.is_empty() {
+        emergency_println!("released output locks: {:?}", &released[..]);
+    }
+    println!("{}", info);
+    if let Some(report) = interrupts::pending_fault() {
+        emergency_println!("{}", report);
+        println!("{}", report);
+    }
+    println!("{}", version_info().short());
+    let _ = backtrace::print(&mut emergency::Emergency);
 }
 
 /// Panic handler used during `cargo test`.
 ///
-/// Prints the panic information over serial, exits QEMU with a failure code,
-/// and then halts the CPU.
+/// Prints the panic information and a backtrace over serial and the
+/// [debug console](debugcon), exits QEMU with a failure code, and then halts
+/// the CPU.
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
+    // SAFETY: a test panic never returns to whoever held the locks.
+    unsafe { emergency::take_over() };
+    emergency_println!("[failed]\n\nError: {}\n", info);
+    if let Some(report) = interrupts::pending_fault() {
+        emergency_println!("{}", report);
+    }
+    let _ = backtrace::print(&mut emergency::Emergency);
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
 
-#[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
-    // like before
-    init();
-    test_main();
-    hlt_loop();
-}
 
 /// Panic handler for test builds.
 ///
-/// Delegates to [`test_panic_handler`].
+/// Gives [`task::recovery`] a chance to contain panics in restartable tasks,
+/// otherwise delegates to [`test_panic_handler`].
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    task::recovery::recover(info);
     test_panic_handler(info)
 }
 
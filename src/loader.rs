@@ -0,0 +1,6 @@
+//! Loading user programs.
+//!
+//! [`elf`] maps a statically linked ELF64 executable, typically a file from
+//! the ramdisk, into a fresh [`AddressSpace`](crate::memory::AddressSpace).
+
+pub mod elf;
@@ -0,0 +1,466 @@
+//! ELF64 executables.
+//!
+//! [`load`] checks the whole file before mapping anything, so a rejected
+//! image leaves the address space as it was. Only statically linked
+//! x86_64 executables are accepted: relocatable objects, position
+//! independent executables and anything asking for an interpreter are
+//! refused until there is a dynamic loader.
+//!
+//! Each `PT_LOAD` segment gets its own pages, writable or executable but
+//! never both, and segments may not share a page, since a page has only one
+//! set of flags. Pages start out zeroed, so the part of a segment past its
+//! file contents (the BSS) reads as zero. The stack sits at the top of the
+//! user range with an unmapped guard page below it.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::memory::address_space::{is_user_range, USER_END};
+use crate::memory::{self, AddressSpace, MemError};
+
+/// Pages in the initial user stack.
+pub const STACK_PAGES: u64 = 16;
+/// Initial user stack pointer.
+pub const STACK_TOP: u64 = USER_END;
+
+const PAGE_SIZE: u64 = 4096;
+/// The stack and the guard page below it.
+const STACK_RANGE: Range<u64> = STACK_TOP - (STACK_PAGES + 1) * PAGE_SIZE..STACK_TOP;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const MACHINE_X86_64: u16 = 0x3e;
+
+/// `e_type` values.
+const TYPE_REL: u16 = 1;
+const TYPE_EXEC: u16 = 2;
+const TYPE_DYN: u16 = 3;
+
+/// `p_type` values.
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+/// `p_flags` bits.
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// Where a loaded program starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadedImage {
+    pub entry: VirtAddr,
+    pub stack_top: VirtAddr,
+}
+
+/// Why an image wasn't loaded. Segments are named by their virtual address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The file ends inside a header or a segment.
+    Truncated,
+    /// Not an ELF file.
+    BadMagic,
+    /// Not a 64-bit ELF file; the `EI_CLASS` byte.
+    WrongClass(u8),
+    /// Not little-endian; the `EI_DATA` byte.
+    WrongEndian(u8),
+    /// Not for x86_64; the `e_machine` value.
+    WrongMachine(u16),
+    /// A relocatable object, not an executable.
+    Relocatable,
+    /// Needs dynamic linking (a PIE, or a `PT_DYNAMIC` or `PT_INTERP`
+    /// segment).
+    Dynamic,
+    /// Some other `e_type`.
+    BadType(u16),
+    /// Program headers of an unexpected size.
+    BadProgramHeaderSize(u16),
+    /// Nothing to load.
+    NoSegments,
+    /// A segment's sizes or file range don't make sense.
+    BadSegment(u64),
+    /// A segment lies outside the user range.
+    OutsideUserRange(u64),
+    /// A segment is both writable and executable.
+    WriteExecute(u64),
+    /// A segment shares a page with another segment or the stack.
+    Overlap(u64),
+    /// The entry point isn't in an executable segment.
+    BadEntry(u64),
+    /// Mapping a page failed.
+    Map(MemError),
+}
+
+impl From<MemError> for LoadError {
+    fn from(err: MemError) -> Self {
+        LoadError::Map(err)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Truncated => f.write_str("file truncated"),
+            LoadError::BadMagic => f.write_str("not an ELF file"),
+            LoadError::WrongClass(class) => write!(f, "not a 64-bit ELF file (class {})", class),
+            LoadError::WrongEndian(data) => write!(f, "not little-endian (data {})", data),
+            LoadError::WrongMachine(machine) => {
+                write!(f, "not an x86_64 program (machine {:#x})", machine)
+            }
+            LoadError::Relocatable => f.write_str("relocatable objects aren't supported"),
+            LoadError::Dynamic => f.write_str("dynamically linked programs aren't supported"),
+            LoadError::BadType(kind) => write!(f, "unsupported ELF type {}", kind),
+            LoadError::BadProgramHeaderSize(size) => {
+                write!(f, "bad program header size {}", size)
+            }
+            LoadError::NoSegments => f.write_str("no loadable segments"),
+            LoadError::BadSegment(vaddr) => write!(f, "bad segment at {:#x}", vaddr),
+            LoadError::OutsideUserRange(vaddr) => {
+                write!(f, "segment at {:#x} outside user range", vaddr)
+            }
+            LoadError::WriteExecute(vaddr) => {
+                write!(f, "segment at {:#x} writable and executable", vaddr)
+            }
+            LoadError::Overlap(vaddr) => write!(f, "segment at {:#x} overlaps", vaddr),
+            LoadError::BadEntry(entry) => write!(f, "entry point {:#x} not in code", entry),
+            LoadError::Map(err) => write!(f, "map failed: {}", err),
+        }
+    }
+}
+
+/// A checked `PT_LOAD` segment.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    vaddr: u64,
+    memsz: u64,
+    offset: usize,
+    filesz: usize,
+    flags: u32,
+}
+
+impl Segment {
+    /// Page-aligned addresses the segment covers.
+    fn pages(&self) -> Range<u64> {
+        self.vaddr & !(PAGE_SIZE - 1)..(self.vaddr + self.memsz).next_multiple_of(PAGE_SIZE)
+    }
+
+    fn page_flags(&self) -> Result<PageTableFlags, MemError> {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X != 0 {
+            memory::code_flags(flags)
+        } else {
+            Ok(memory::data_flags(flags))
+        }
+    }
+}
+
+fn bytes<const N: usize>(image: &[u8], offset: usize) -> Result<[u8; N], LoadError> {
+    offset
+        .checked_add(N)
+        .and_then(|end| image.get(offset..end))
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(LoadError::Truncated)
+}
+
+fn u16_at(image: &[u8], offset: usize) -> Result<u16, LoadError> {
+    bytes(image, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(image: &[u8], offset: usize) -> Result<u32, LoadError> {
+    bytes(image, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(image: &[u8], offset: usize) -> Result<u64, LoadError> {
+    bytes(image, offset).map(u64::from_le_bytes)
+}
+
+/// Check the headers and return the entry point and the segments, sorted
+/// by address.
+fn parse(image: &[u8]) -> Result<(u64, Vec<Segment>), LoadError> {
+    if image.len() < HEADER_SIZE {
+        return Err(LoadError::Truncated);
+    }
+    let ident: [u8; 16] = bytes(image, 0)?;
+    if ident[..4] != *b"\x7fELF" {
+        return Err(LoadError::BadMagic);
+    }
+    if ident[4] != CLASS_64 {
+        return Err(LoadError::WrongClass(ident[4]));
+    }
+    if ident[5] != LITTLE_ENDIAN {
+        return Err(LoadError::WrongEndian(ident[5]));
+    }
+    match u16_at(image, 16)? {
+        TYPE_EXEC => {}
+        TYPE_REL => return Err(LoadError::Relocatable),
+        TYPE_DYN => return Err(LoadError::Dynamic),
+        other => return Err(LoadError::BadType(other)),
+    }
+    let machine = u16_at(image, 18)?;
+    if machine != MACHINE_X86_64 {
+        return Err(LoadError::WrongMachine(machine));
+    }
+    let entry = u64_at(image, 24)?;
+    let phoff = usize::try_from(u64_at(image, 32)?).map_err(|_| LoadError::Truncated)?;
+    let phentsize = u16_at(image, 54)?;
+    let phnum = usize::from(u16_at(image, 56)?);
+    if usize::from(phentsize) != PROGRAM_HEADER_SIZE {
+        return Err(LoadError::BadProgramHeaderSize(phentsize));
+    }
+
+    let mut segments = Vec::new();
+    for index in 0..phnum {
+        let header = phoff
+            .checked_add(index * PROGRAM_HEADER_SIZE)
+            .ok_or(LoadError::Truncated)?;
+        let kind = u32_at(image, header)?;
+        match kind {
+            PT_LOAD => {}
+            PT_DYNAMIC | PT_INTERP => return Err(LoadError::Dynamic),
+            _ => continue,
+        }
+        let flags = u32_at(image, header + 4)?;
+        let offset = u64_at(image, header + 8)?;
+        let vaddr = u64_at(image, header + 16)?;
+        let filesz = u64_at(image, header + 32)?;
+        let memsz = u64_at(image, header + 40)?;
+
+        let end = vaddr.checked_add(memsz).ok_or(LoadError::BadSegment(vaddr))?;
+        let file_end = offset.checked_add(filesz).ok_or(LoadError::BadSegment(vaddr))?;
+        if filesz > memsz || memsz == 0 {
+            return Err(LoadError::BadSegment(vaddr));
+        }
+        if file_end > image.len() as u64 {
+            return Err(LoadError::Truncated);
+        }
+        if !is_user_range(vaddr, end) {
+            return Err(LoadError::OutsideUserRange(vaddr));
+        }
+        if flags & PF_W != 0 && flags & PF_X != 0 {
+            return Err(LoadError::WriteExecute(vaddr));
+        }
+        segments.push(Segment {
+            vaddr,
+            memsz,
+            offset: offset as usize,
+            filesz: filesz as usize,
+            flags,
+        });
+    }
+    if segments.is_empty() {
+        return Err(LoadError::NoSegments);
+    }
+
+    segments.sort_unstable_by_key(|segment| segment.vaddr);
+    for pair in segments.windows(2) {
+        if pair[0].pages().end > pair[1].pages().start {
+            return Err(LoadError::Overlap(pair[1].vaddr));
+        }
+    }
+    if let Some(last) = segments.last()
+        && last.pages().end > STACK_RANGE.start
+    {
+        return Err(LoadError::Overlap(last.vaddr));
+    }
+    let in_code = |segment: &Segment| {
+        segment.flags & PF_X != 0 && (segment.vaddr..segment.vaddr + segment.memsz).contains(&entry)
+    };
+    if !segments.iter().any(in_code) {
+        return Err(LoadError::BadEntry(entry));
+    }
+    Ok((entry, segments))
+}
+
+/// Map the executable `image` and a stack into `space`.
+///
+/// Nothing is mapped if the image is rejected. If mapping itself fails
+/// part way, the pages mapped so far stay in `space`.
+pub fn load(
+    image: &[u8],
+    space: &mut AddressSpace,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<LoadedImage, LoadError> {
+    let (entry, segments) = parse(image)?;
+
+    for segment in &segments {
+        let flags = segment.page_flags()?;
+        for addr in segment.pages().step_by(PAGE_SIZE as usize) {
+            let page = Page::containing_address(VirtAddr::new(addr));
+            space.map_user(page, flags, frame_allocator)?;
+        }
+        let contents = &image[segment.offset..segment.offset + segment.filesz];
+        space.write(VirtAddr::new(segment.vaddr), contents)?;
+    }
+
+    let stack_flags = memory::data_flags(PageTableFlags::WRITABLE);
+    // The first page of the range is the guard page.
+    for addr in (STACK_RANGE.start + PAGE_SIZE..STACK_RANGE.end).step_by(PAGE_SIZE as usize) {
+        let page = Page::containing_address(VirtAddr::new(addr));
+        space.map_user(page, stack_flags, frame_allocator)?;
+    }
+
+    Ok(LoadedImage {
+        entry: VirtAddr::new(entry),
+        stack_top: VirtAddr::new(STACK_TOP),
+    })
+}
+
+/// Build an ELF64 image with one program header per `(type, flags, vaddr,
+/// contents, memsz)`.
+#[cfg(test)]
+fn build_image(kind: u16, entry: u64, segments: &[(u32, u32, u64, &[u8], u64)]) -> Vec<u8> {
+    let data_start = HEADER_SIZE + segments.len() * PROGRAM_HEADER_SIZE;
+    let mut image = alloc::vec![0; data_start];
+    image[..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', CLASS_64, LITTLE_ENDIAN, 1, 0]);
+    image[16..18].copy_from_slice(&kind.to_le_bytes());
+    image[18..20].copy_from_slice(&MACHINE_X86_64.to_le_bytes());
+    image[20..24].copy_from_slice(&1u32.to_le_bytes());
+    image[24..32].copy_from_slice(&entry.to_le_bytes());
+    image[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    image[52..54].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    image[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    image[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+    for (index, &(kind, flags, vaddr, contents, memsz)) in segments.iter().enumerate() {
+        let offset = image.len() as u64;
+        image.extend_from_slice(contents);
+        let header = &mut image[HEADER_SIZE + index * PROGRAM_HEADER_SIZE..][..PROGRAM_HEADER_SIZE];
+        header[0..4].copy_from_slice(&kind.to_le_bytes());
+        header[4..8].copy_from_slice(&flags.to_le_bytes());
+        header[8..16].copy_from_slice(&offset.to_le_bytes());
+        header[16..24].copy_from_slice(&vaddr.to_le_bytes());
+        header[24..32].copy_from_slice(&vaddr.to_le_bytes());
+        header[32..40].copy_from_slice(&(contents.len() as u64).to_le_bytes());
+        header[40..48].copy_from_slice(&memsz.to_le_bytes());
+        header[48..56].copy_from_slice(&PAGE_SIZE.to_le_bytes());
+    }
+    image
+}
+
+/// Where [`test_executable`] puts its code.
+#[cfg(test)]
+pub(crate) const TEST_TEXT: u64 = crate::memory::address_space::USER_START + PAGE_SIZE;
+
+/// Build an executable whose only segment is `code`, read-only at
+/// [`TEST_TEXT`], starting with its first byte.
+#[cfg(test)]
+pub(crate) fn test_executable(code: &[u8]) -> Vec<u8> {
+    build_image(TYPE_EXEC, TEST_TEXT, &[(PT_LOAD, 4 | PF_X, TEST_TEXT, code, code.len() as u64)])
+}
+
+/// `mov edi, 42; mov eax, 60; syscall; jmp $`
+#[cfg(test)]
+const TEST_CODE: [u8; 14] = [
+    0xbf, 0x2a, 0x00, 0x00, 0x00, 0xb8, 0x3c, 0x00, 0x00, 0x00, 0x0f, 0x05, 0xeb, 0xfe,
+];
+
+#[test_case]
+fn test_load_static_image() {
+    use crate::memory::address_space::USER_START;
+    use crate::memory::FRAME_ALLOCATOR;
+
+    let text = USER_START + 0x1000;
+    let data = USER_START + 0x3000;
+    let image = build_image(TYPE_EXEC, text, &[
+        (PT_LOAD, 4 | PF_X, text, &TEST_CODE, TEST_CODE.len() as u64),
+        (PT_LOAD, 4 | PF_W, data, b"data", 0x1800),
+    ]);
+    let mut frames = FRAME_ALLOCATOR.get().lock();
+    let mut space = AddressSpace::new(&mut *frames).unwrap();
+    let loaded = load(&image, &mut space, &mut *frames).unwrap();
+    drop(frames);
+    assert_eq!(loaded.entry, VirtAddr::new(text));
+    assert_eq!(loaded.stack_top, VirtAddr::new(STACK_TOP));
+
+    let data_flags = memory::data_flags(PageTableFlags::WRITABLE);
+    let flags = |addr: u64| space.translate(VirtAddr::new(addr)).map(|(_, flags)| flags);
+    let code = flags(text).unwrap();
+    assert!(code.contains(PageTableFlags::USER_ACCESSIBLE));
+    assert!(!code.contains(PageTableFlags::WRITABLE));
+    assert!(!code.contains(PageTableFlags::NO_EXECUTE));
+    let mut buf = [0; TEST_CODE.len()];
+    space.read(VirtAddr::new(text), &mut buf).unwrap();
+    assert_eq!(buf, TEST_CODE);
+
+    // The data segment is writable, never executable, and zeroed past its
+    // file contents, on both of its pages.
+    for page in [data, data + PAGE_SIZE] {
+        let flags = flags(page).unwrap();
+        assert!(flags.contains(data_flags));
+    }
+    let mut buf = [0xff; 8];
+    space.read(VirtAddr::new(data), &mut buf).unwrap();
+    assert_eq!(&buf, b"data\0\0\0\0");
+    space.read(VirtAddr::new(data + 0x17f8), &mut buf).unwrap();
+    assert_eq!(buf, [0; 8]);
+    assert!(flags(USER_START).is_none());
+    assert!(flags(text + PAGE_SIZE).is_none());
+
+    // The stack is mapped up to its top, the guard page below it isn't.
+    assert!(flags(STACK_TOP - 8).unwrap().contains(data_flags));
+    assert!(flags(STACK_TOP - STACK_PAGES * PAGE_SIZE).is_some());
+    assert!(flags(STACK_TOP - (STACK_PAGES + 1) * PAGE_SIZE).is_none());
+}
+
+#[test_case]
+fn test_rejects_bad_images() {
+    use crate::memory::address_space::USER_START;
+    use crate::memory::FRAME_ALLOCATOR;
+
+    let text = USER_START + 0x1000;
+    let code = (PT_LOAD, 4 | PF_X, text, &TEST_CODE[..], 0x100);
+    let reject = |image: &[u8]| parse(image).map(|_| ()).unwrap_err();
+
+    let mut image = build_image(TYPE_EXEC, text, &[code]);
+    assert!(parse(&image).is_ok());
+    assert_eq!(reject(&image[..HEADER_SIZE + 8]), LoadError::Truncated);
+    assert_eq!(reject(&image[..image.len() - 1]), LoadError::Truncated);
+    image[0] = 0;
+    assert_eq!(reject(&image), LoadError::BadMagic);
+    let mut image = build_image(TYPE_EXEC, text, &[code]);
+    image[4] = 1;
+    assert_eq!(reject(&image), LoadError::WrongClass(1));
+
+    assert_eq!(reject(&build_image(TYPE_REL, text, &[code])), LoadError::Relocatable);
+    assert_eq!(reject(&build_image(TYPE_DYN, text, &[code])), LoadError::Dynamic);
+    let interp = (PT_INTERP, 4, 0, &b"/lib/ld.so\0"[..], 11);
+    assert_eq!(reject(&build_image(TYPE_EXEC, text, &[interp, code])), LoadError::Dynamic);
+    assert_eq!(reject(&build_image(TYPE_EXEC, text, &[])), LoadError::NoSegments);
+
+    let bad_segment = |segment| reject(&build_image(TYPE_EXEC, text, &[code, segment]));
+    let data = text + 0x800;
+    assert_eq!(bad_segment((PT_LOAD, 4 | PF_W, data, b"data", 4)), LoadError::Overlap(data));
+    let data = text + PAGE_SIZE;
+    let writable_code = (PT_LOAD, 4 | PF_W | PF_X, data, &b""[..], 4);
+    assert_eq!(bad_segment(writable_code), LoadError::WriteExecute(data));
+    assert_eq!(bad_segment((PT_LOAD, 4 | PF_W, data, b"data", 2)), LoadError::BadSegment(data));
+    let wrapping = u64::MAX - 1;
+    assert_eq!(bad_segment((PT_LOAD, 4, wrapping, b"", 4)), LoadError::BadSegment(wrapping));
+    let low = 0x40_0000;
+    assert_eq!(bad_segment((PT_LOAD, 4, low, b"", 4)), LoadError::OutsideUserRange(low));
+    let guard = STACK_TOP - (STACK_PAGES + 1) * PAGE_SIZE;
+    assert_eq!(bad_segment((PT_LOAD, 4 | PF_W, guard, b"", 4)), LoadError::Overlap(guard));
+
+    let data_entry = build_image(TYPE_EXEC, text + PAGE_SIZE, &[
+        code,
+        (PT_LOAD, 4 | PF_W, text + PAGE_SIZE, b"data", 4),
+    ]);
+    assert_eq!(reject(&data_entry), LoadError::BadEntry(text + PAGE_SIZE));
+
+    // A rejected image maps nothing.
+    let overlapping = build_image(TYPE_EXEC, text, &[code, (PT_LOAD, 4, text, b"", 4)]);
+    let mut frames = FRAME_ALLOCATOR.get().lock();
+    let mut space = AddressSpace::new(&mut *frames).unwrap();
+    let result = load(&overlapping, &mut space, &mut *frames);
+    drop(frames);
+    assert_eq!(result, Err(LoadError::Overlap(text)));
+    assert!(space.translate(VirtAddr::new(text)).is_none());
+    assert!(space.translate(VirtAddr::new(STACK_TOP - 8)).is_none());
+}
@@ -7,29 +7,21 @@
 extern crate alloc;
 use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
 
-use bootloader::{BootInfo, entry_point};
-use chronos::println;
-use x86_64::VirtAddr;
+use chronos::{entry_point, BootInfo};
+use chronos::{print, println};
+use chronos::task::{executor::Executor, Priority, Task};
 use core::panic::PanicInfo;
 
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-
-    println!("Hello World{}", "!");
+    chronos::interrupts::init_early();
+    println!("{}", chronos::version_info());
     chronos::init();
 
-    use chronos::allocator;
-    use chronos::memory::{self, BootInfoFrameAllocator};
-
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
-
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("heap initialization failed");
+    chronos::init_memory(boot_info);
+    println!("physical memory offset: {:#x}", boot_info.physical_memory_offset);
+    println!("memory map: {} regions", boot_info.memory_map.len());
 
     // allocate a number on the heap
     let heap_value = Box::new(41);
@@ -48,19 +40,54 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("current reference count is {}", Rc::strong_count(&cloned_reference));
     core::mem::drop(reference_counted);
     println!("reference count is {} now", Rc::strong_count(&cloned_reference));
+    if chronos::cmdline::get_bool("selftest") == Some(true) {
+        print!("{}", chronos::selftest::run());
+    }
+
     // For testing
     #[cfg(test)]
     test_main();
 
-    println!("It didnt crash yay");
-    chronos::hlt_loop();
+    let mut executor = Executor::new();
+    executor.spawn(Task::named("example", example_task()));
+    executor.spawn(Task::named("wallclock", chronos::time::wallclock::resync_task()));
+    executor.spawn(Task::named("statusbar", chronos::statusbar::run()));
+    executor.spawn(Task::named("ps2", chronos::task::keyboard::reinit_task()));
+    executor.spawn(Task::named("replay", chronos::task::keyboard::replay::run()));
+    if let Some(seconds) = chronos::cmdline::get_u64("scrub").filter(|&seconds| seconds > 0) {
+        let period = core::time::Duration::from_secs(seconds);
+        executor.spawn(
+            Task::named("scrub", chronos::scrub::run(period)).with_priority(Priority::Low),
+        );
+    }
+    executor.spawn(
+        Task::named("shell", chronos::shell::run()).with_priority(Priority::High),
+    );
+    executor.run();
+}
+
+async fn async_number() -> u32 {
+    42
+}
+
+async fn example_task() {
+    let number = async_number().await;
+    println!("async number: {}", number);
 }
 
 /// This function is called on panic.
+///
+/// Panics in tasks marked restartable are contained and never get past
+/// `recover`.
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    chronos::task::recovery::recover(info);
+    // SAFETY: the kernel halts below; whoever held the locks never resumes.
+    unsafe { chronos::report_panic(info) };
+    if chronos::cmdline::get_bool("panicbeep") == Some(true) {
+        chronos::speaker::sad_beep();
+    }
     chronos::hlt_loop();
 }
 
@@ -1,12 +1,217 @@
 use x86_64::{
     structures::paging::{Page, PhysFrame, Mapper, Size4KiB, FrameAllocator, PageTable, OffsetPageTable},
+    structures::paging::{page_table::PageTableEntry, PageTableFlags},
     VirtAddr,
     PhysAddr,
 };
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::mapper::{MapToError, MapperFlush};
+
+use crate::collections::FixedVec;
+use crate::error::KernelError;
+use crate::sync::{Global, IrqMutex};
+
+pub mod address_space;
+
+pub use address_space::AddressSpace;
 
 pub struct EmptyFrameAllocator;
 
+/// The frame allocator, handed over by the boot code once the stages that
+/// map memory (see [`crate::init`]) have run.
+pub static FRAME_ALLOCATOR: Global<IrqMutex<BootInfoFrameAllocator>> =
+    Global::new("FRAME_ALLOCATOR");
+
+/// Where the bootloader mapped all of physical memory.
+pub static PHYS_OFFSET: Global<VirtAddr> = Global::new("PHYS_OFFSET");
+
+/// Usable frames in the memory map, counted by [`BootInfoFrameAllocator::init`].
+static USABLE_FRAMES: AtomicU64 = AtomicU64::new(0);
+/// Frames handed out by any [`BootInfoFrameAllocator`].
+static ALLOCATED_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+/// Physical frame usage, in 4 KiB frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub usable: u64,
+    pub allocated: u64,
+}
+
+/// Return how many physical frames exist and how many have been allocated.
+pub fn frame_stats() -> FrameStats {
+    FrameStats {
+        usable: USABLE_FRAMES.load(Ordering::Relaxed),
+        allocated: ALLOCATED_FRAMES.load(Ordering::Relaxed),
+    }
+}
+
+/// What a range set aside with [`reserve`] holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservedKind {
+    /// The boot ramdisk (see [`crate::ramdisk`]).
+    Ramdisk,
+    /// The AP startup trampoline (see [`crate::smp`]).
+    Trampoline,
+    /// The crash record kept across reboots (see [`crate::crashlog`]).
+    CrashLog,
+}
+
+impl ReservedKind {
+    /// Lower-case name used in the memory map listing.
+    pub fn name(self) -> &'static str {
+        match self {
+            ReservedKind::Ramdisk => "ramdisk",
+            ReservedKind::Trampoline => "trampoline",
+            ReservedKind::CrashLog => "crashlog",
+        }
+    }
+}
+
+/// A physical range the frame allocator must not hand out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReservedRange {
+    pub start: PhysAddr,
+    /// One past the last byte.
+    pub end: PhysAddr,
+    pub kind: ReservedKind,
+}
+
+impl ReservedRange {
+    /// Whether any part of `frame` is in this range.
+    pub fn contains(&self, frame: PhysFrame) -> bool {
+        let start = frame.start_address();
+        start < self.end && start + frame.size() > self.start
+    }
+}
+
+/// Why [`reserve`] refused a range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReserveError {
+    /// Frames have already been allocated, possibly from the range.
+    TooLate,
+    /// All [`MAX_RESERVED`] slots are taken.
+    Full,
+}
+
+impl fmt::Display for ReserveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReserveError::TooLate => f.write_str("frames already allocated"),
+            ReserveError::Full => f.write_str("no free reservation slot"),
+        }
+    }
+}
+
+/// Why a memory operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemError {
+    /// The frame allocator ran out of frames.
+    FrameAllocationFailed,
+    /// A page table on the way to the page is a huge page.
+    ParentEntryHugePage,
+    /// The page is already mapped to this frame.
+    PageAlreadyMapped(PhysFrame),
+    /// A physical range couldn't be reserved.
+    Reserve(ReserveError),
+    /// The kernel has mappings where user address spaces go.
+    UserRangeInUse,
+    /// The address isn't mapped.
+    NotMapped(VirtAddr),
+    /// A mapping asked to be both writable and executable.
+    WritableExecutable,
+}
+
+impl From<MapToError<Size4KiB>> for MemError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => MemError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MemError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(frame) => MemError::PageAlreadyMapped(frame),
+        }
+    }
+}
+
+impl From<ReserveError> for MemError {
+    fn from(err: ReserveError) -> Self {
+        MemError::Reserve(err)
+    }
+}
+
+impl fmt::Display for MemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemError::FrameAllocationFailed => f.write_str("frame allocation failed"),
+            MemError::ParentEntryHugePage => f.write_str("parent entry is a huge page"),
+            MemError::PageAlreadyMapped(frame) => {
+                write!(f, "page already mapped to {:#x}", frame.start_address().as_u64())
+            }
+            MemError::Reserve(err) => write!(f, "reserve failed: {}", err),
+            MemError::UserRangeInUse => f.write_str("user address range in use by the kernel"),
+            MemError::NotMapped(addr) => write!(f, "{:#x} not mapped", addr.as_u64()),
+            MemError::WritableExecutable => f.write_str("writable and executable mapping refused"),
+        }
+    }
+}
+
+/// Most ranges [`reserve`] can hold.
+pub const MAX_RESERVED: usize = 8;
+
+static RESERVED: Mutex<[Option<ReservedRange>; MAX_RESERVED]> = Mutex::new([None; MAX_RESERVED]);
+
+/// Keep the frames in `start..end` away from the frame allocator.
+///
+/// Must happen before the first frame is allocated, since an allocator
+/// might already have handed out frames from the range.
+pub fn reserve(start: PhysAddr, end: PhysAddr, kind: ReservedKind) -> Result<(), ReserveError> {
+    if ALLOCATED_FRAMES.load(Ordering::Relaxed) > 0 {
+        return Err(ReserveError::TooLate);
+    }
+    let mut reserved = RESERVED.lock();
+    let slot = reserved.iter_mut().find(|slot| slot.is_none()).ok_or(ReserveError::Full)?;
+    *slot = Some(ReservedRange { start, end, kind });
+    Ok(())
+}
+
+/// Iterate over the ranges set aside with [`reserve`].
+pub fn reserved() -> impl Iterator<Item = ReservedRange> {
+    let reserved = *RESERVED.lock();
+    reserved.into_iter().flatten()
+}
+
+/// The bootloader's memory map, kept by [`BootInfoFrameAllocator::init`].
+static MEMORY_MAP: OnceCell<&'static MemoryMap> = OnceCell::uninit();
+
+/// Print the bootloader's memory map, one region per line, followed by the
+/// reserved ranges carved out of it.
+pub fn dump_memory_map(out: &mut dyn fmt::Write) -> fmt::Result {
+    let Some(map) = MEMORY_MAP.get() else {
+        return writeln!(out, "memory map not available");
+    };
+    for region in map.iter() {
+        writeln!(
+            out,
+            "{:#012x}-{:#012x} {:?}",
+            region.range.start_addr(),
+            region.range.end_addr(),
+            region.region_type
+        )?;
+    }
+    for range in reserved() {
+        writeln!(
+            out,
+            "{:#012x}-{:#012x} reserved ({})",
+            range.start.as_u64(),
+            range.end.as_u64(),
+            range.kind.name()
+        )?;
+    }
+    Ok(())
+}
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
@@ -26,16 +231,21 @@ impl BootInfoFrameAllocator {
     /// memory map is valid. The main requirement is that all frames that are marked
     /// as `USABLE` in it are really unused.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let allocator = BootInfoFrameAllocator {
             memory_map,
             next: 0,
-        }
+        };
+        let _ = MEMORY_MAP.try_init_once(|| memory_map);
+        USABLE_FRAMES.store(allocator.usable_frames().count() as u64, Ordering::Relaxed);
+        allocator
     }
 }
 
 impl BootInfoFrameAllocator {
-    /// Returns an iterator over the usable frames specified in the memory map.
+    /// Returns an iterator over the usable frames specified in the memory map,
+    /// leaving out the reserved ones.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let reserved = *RESERVED.lock();
         // get usable regions from memory map
         let regions = self.memory_map.iter();
         let usable_regions = regions
@@ -46,7 +256,34 @@ impl BootInfoFrameAllocator {
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+        frame_addresses
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+            .filter(move |&frame| !reserved.iter().flatten().any(|range| range.contains(frame)))
+    }
+
+    /// Allocate `count` physically consecutive frames and return the first.
+    ///
+    /// Frames are handed out in order, so the frames passed over to find a
+    /// long enough run are never handed out; they count as allocated.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let mut start = None;
+        let mut run = 0;
+        let mut previous: Option<PhysFrame> = None;
+        for (taken, frame) in self.usable_frames().skip(self.next).enumerate() {
+            if previous.is_some_and(|previous| previous + 1 == frame) {
+                run += 1;
+            } else {
+                start = Some(frame);
+                run = 1;
+            }
+            previous = Some(frame);
+            if run >= count {
+                self.next += taken + 1;
+                ALLOCATED_FRAMES.fetch_add(taken as u64 + 1, Ordering::Relaxed);
+                return start;
+            }
+        }
+        None
     }
 }
 
@@ -54,6 +291,9 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
+        if frame.is_some() {
+            ALLOCATED_FRAMES.fetch_add(1, Ordering::Relaxed);
+        }
         frame
     }
 }
@@ -80,7 +320,7 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     -> &'static mut PageTable
 {
-    use x86_64::registers::control::Cr3;
+    use crate::arch::cr::Cr3;
 
     let (level_4_table_frame, _) = Cr3::read();
 
@@ -91,22 +331,143 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     unsafe { &mut *page_table_ptr }
 }
 
+/// Turn on no-execute page protection if the CPU has it. Called once CPUID
+/// has been read, before anything is mapped; the APs copy the setting.
+pub fn enable_nx() -> bool {
+    use crate::arch::msr::{Efer, EferFlags};
+
+    if crate::cpu::features().nx {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    }
+    nx_enabled()
+}
+
+/// Return whether the CPU honours [`PageTableFlags::NO_EXECUTE`]. Until it
+/// does, the bit is reserved and setting it faults.
+pub fn nx_enabled() -> bool {
+    use crate::arch::msr::{Efer, EferFlags};
+
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+/// Return `flags` for a kernel data mapping: no-execute, if the CPU
+/// supports it. Every kernel mapping that holds no code uses this.
+pub fn data_flags(flags: PageTableFlags) -> PageTableFlags {
+    if nx_enabled() {
+        flags | PageTableFlags::NO_EXECUTE
+    } else {
+        flags - PageTableFlags::NO_EXECUTE
+    }
+}
+
+/// Return `flags` for a kernel code mapping, which must not be writable.
+pub fn code_flags(flags: PageTableFlags) -> Result<PageTableFlags, MemError> {
+    if flags.contains(PageTableFlags::WRITABLE) {
+        return Err(MemError::WritableExecutable);
+    }
+    Ok(flags - PageTableFlags::NO_EXECUTE)
+}
+
+/// Return `flags` for a mapping that is writable and executable at once,
+/// bypassing the checks in [`code_flags`] and [`map_page`]. Only for the
+/// few that can't be split, and each caller says why.
+pub fn allow_wx(flags: PageTableFlags) -> PageTableFlags {
+    (flags - PageTableFlags::NO_EXECUTE) | WX_ALLOWED
+}
+
+/// Marks flags that came from [`allow_wx`]. The CPU ignores the bit.
+const WX_ALLOWED: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Map `page` to `frame` with `flags`. Every mapping the kernel makes goes
+/// through here, which refuses one that is writable and executable unless
+/// its flags came from [`allow_wx`]. Without NX nothing can be no-execute,
+/// so nothing is refused.
+///
+/// # Safety
+///
+/// As for [`Mapper::map_to`].
+pub unsafe fn map_page(
+    mapper: &mut impl Mapper<Size4KiB>,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<MapperFlush<Size4KiB>, MemError> {
+    let executable = !flags.intersects(PageTableFlags::NO_EXECUTE | WX_ALLOWED);
+    if flags.contains(PageTableFlags::WRITABLE) && executable && nx_enabled() {
+        return Err(MemError::WritableExecutable);
+    }
+    Ok(unsafe { mapper.map_to(page, frame, flags, frame_allocator)? })
+}
+
+/// Make the bootloader's mapping of all physical memory, at `phys_offset`,
+/// no-execute, by setting the bit in the level 4 entries that cover it.
+/// Nothing runs from there, and all of it is writable. An entry that also
+/// maps kernel code is left alone. Does nothing without NX.
+pub fn protect_phys_window(
+    mapper: &mut OffsetPageTable,
+    phys_offset: VirtAddr,
+    memory_map: &MemoryMap,
+) {
+    if !nx_enabled() {
+        return;
+    }
+    let Some(end) = memory_map.iter().map(|region| region.range.end_addr()).max() else {
+        return;
+    };
+    let code = VirtAddr::new(protect_phys_window as usize as u64).p4_index();
+    let first = usize::from(phys_offset.p4_index());
+    let last = usize::from((phys_offset + end.max(1) - 1u64).p4_index());
+    let table = mapper.level_4_table();
+    for index in first..=last {
+        let entry = &mut table[index];
+        if index != usize::from(code) && entry.flags().contains(PageTableFlags::PRESENT) {
+            entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+        }
+    }
+    x86_64::instructions::tlb::flush_all();
+}
+
+/// Map the `size` bytes of device registers at `phys` to `virt`, uncached.
+///
+/// Both addresses are rounded down to their pages, and the end up.
+pub fn map_mmio(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+) -> Result<(), MemError> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE | Flags::WRITE_THROUGH;
+    let flags = data_flags(flags);
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+    let page = Page::<Size4KiB>::containing_address(virt);
+    for (i, frame) in PhysFrame::range_inclusive(first, last).enumerate() {
+        unsafe { map_page(mapper, page + i as u64, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(())
+}
+
 /// Creates an example mapping for the given page to frame `0xb8000`.
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
+) -> Result<(), KernelError> {
     use x86_64::structures::paging::PageTableFlags as Flags;
 
     let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = Flags::PRESENT | Flags::WRITABLE;
+    let flags = data_flags(Flags::PRESENT | Flags::WRITABLE);
 
     let map_to_result = unsafe {
         // FIXME: this is not safe, we do it only for testing
-        mapper.map_to(page, frame, flags, frame_allocator)
+        map_page(mapper, page, frame, flags, frame_allocator)
     };
-    map_to_result.expect("map_to failed").flush();
+    map_to_result?.flush();
+    Ok(())
 }
 
 /// Translates the given virtual address to the mapped physical address, or
@@ -130,7 +491,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
     -> Option<PhysAddr>
 {
     use x86_64::structures::paging::page_table::FrameError;
-    use x86_64::registers::control::Cr3;
+    use crate::arch::cr::Cr3;
 
     // read the active level 4 frame from the CR3 register
     let (level_4_table_frame, _) = Cr3::read();
@@ -158,4 +519,158 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
 
     // calculate the physical address by adding the page offset
     Some(frame.start_address() + u64::from(addr.page_offset()))
-}
\ No newline at end of file
+}
+/// Return the entries mapping `addr`, from the level 4 table down to the
+/// one that maps the page (a level 3 or 2 entry for a huge page), or
+/// `None` if `addr` isn't mapped or [`PHYS_OFFSET`] isn't set yet.
+fn page_entries(addr: VirtAddr) -> Option<FixedVec<*mut PageTableEntry, 4>> {
+    use crate::arch::cr::Cr3;
+
+    let phys_offset = *PHYS_OFFSET.try_get().ok()?;
+    let (mut frame, _) = Cr3::read();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut entries = FixedVec::new();
+    for (level, index) in indexes.into_iter().enumerate() {
+        let table: *mut PageTable = (phys_offset + frame.start_address().as_u64()).as_mut_ptr();
+        let entry: *mut PageTableEntry = unsafe { &mut (&mut *table)[index] };
+        let flags = unsafe { (*entry).flags() };
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        let _ = entries.push(entry);
+        if level == 3 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        frame = PhysFrame::containing_address(unsafe { (*entry).addr() });
+    }
+    Some(entries)
+}
+
+/// Return the flags the CPU applies to `addr`: those of the entry mapping
+/// its page, except that it is only writable or user accessible if every
+/// level allows it, and it is no-execute if any level says so. `None` if
+/// `addr` isn't mapped.
+pub fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    const ALL_LEVELS: PageTableFlags =
+        PageTableFlags::WRITABLE.union(PageTableFlags::USER_ACCESSIBLE);
+
+    let entries = page_entries(addr)?;
+    let mut flags = unsafe { (**entries.last()?).flags() };
+    for &entry in entries.iter() {
+        let level = unsafe { (*entry).flags() };
+        flags &= level | !ALL_LEVELS;
+        flags |= level & PageTableFlags::NO_EXECUTE;
+    }
+    Some(flags)
+}
+
+/// Replace the flags of the entry mapping `addr` and return the old ones,
+/// for tests that need to damage a mapping; `None` if `addr` isn't mapped.
+///
+/// # Safety
+///
+/// The new flags must not break anything that uses the page until they
+/// are put back.
+#[doc(hidden)]
+pub unsafe fn replace_page_flags(addr: VirtAddr, flags: PageTableFlags) -> Option<PageTableFlags> {
+    let entries = page_entries(addr)?;
+    let entry = unsafe { &mut **entries.last()? };
+    let old = entry.flags();
+    entry.set_flags(flags);
+    x86_64::instructions::tlb::flush(addr);
+    Some(old)
+}
+
+#[test_case]
+fn test_page_flags_follow_the_mapping() {
+    let heap = VirtAddr::new(crate::allocator::HEAP_START as u64);
+    let flags = page_flags(heap).expect("heap mapped");
+    assert!(flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::USER_ACCESSIBLE));
+    assert_eq!(page_flags(VirtAddr::new(address_space::USER_START)), None);
+
+    let code = VirtAddr::new(page_flags as fn(VirtAddr) -> Option<PageTableFlags> as usize as u64);
+    let old = unsafe { replace_page_flags(code, page_flags(code).unwrap() | PageTableFlags::WRITABLE) };
+    assert!(page_flags(code).unwrap().contains(PageTableFlags::WRITABLE));
+    unsafe { replace_page_flags(code, old.unwrap()) };
+    assert!(!page_flags(code).unwrap().contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn test_data_mappings_are_no_execute() {
+    use alloc::vec;
+
+    assert!(nx_enabled(), "the test CPU has NX");
+    let heap = VirtAddr::new(crate::allocator::HEAP_START as u64);
+    assert!(page_flags(heap).unwrap().contains(PageTableFlags::NO_EXECUTE));
+    let buffer = vec![0u8; 4096];
+    let flags = page_flags(VirtAddr::from_ptr(buffer.as_ptr())).unwrap();
+    assert!(flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    let code = VirtAddr::new(page_flags as fn(VirtAddr) -> Option<PageTableFlags> as usize as u64);
+    assert!(!page_flags(code).unwrap().contains(PageTableFlags::NO_EXECUTE));
+
+    let rw = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    assert_eq!(data_flags(rw), rw | PageTableFlags::NO_EXECUTE);
+    assert_eq!(code_flags(rw), Err(MemError::WritableExecutable));
+    assert_eq!(code_flags(PageTableFlags::PRESENT), Ok(PageTableFlags::PRESENT));
+    assert_eq!(allow_wx(rw | PageTableFlags::NO_EXECUTE), rw | WX_ALLOWED);
+
+    // The physical-memory window is no-execute from the level 4 entry down.
+    let window = *PHYS_OFFSET.get() + 0xb8000u64;
+    assert!(page_flags(window).unwrap().contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn test_map_page_refuses_writable_executable() {
+    let mut frames = FRAME_ALLOCATOR.get().lock();
+    let mut space = AddressSpace::new(&mut *frames).unwrap();
+    let page = Page::containing_address(VirtAddr::new(address_space::USER_START));
+    let refused = space.map_user(page, PageTableFlags::WRITABLE, &mut *frames);
+    assert_eq!(refused, Err(MemError::WritableExecutable));
+    assert!(space.translate(page.start_address()).is_none());
+    let allowed = space.map_user(page, allow_wx(PageTableFlags::WRITABLE), &mut *frames);
+    assert!(allowed.is_ok());
+}
+
+#[test_case]
+fn test_reserved_frames_not_usable() {
+    let map = MEMORY_MAP.get().expect("memory not initialized");
+    // Only iterates; allocating from a second allocator would hand out
+    // frames the first one already gave away.
+    let allocator = BootInfoFrameAllocator { memory_map: map, next: 0 };
+    let ranges: alloc::vec::Vec<_> = reserved().collect();
+    assert!(!ranges.is_empty(), "the ramdisk was not reserved");
+    assert!(allocator
+        .usable_frames()
+        .all(|frame| !ranges.iter().any(|range| range.contains(frame))));
+
+    let range = ReservedRange {
+        start: PhysAddr::new(0x1800),
+        end: PhysAddr::new(0x3000),
+        kind: ReservedKind::Ramdisk,
+    };
+    let frame = |addr| PhysFrame::containing_address(PhysAddr::new(addr));
+    assert!(!range.contains(frame(0x0000)));
+    assert!(range.contains(frame(0x1000)));
+    assert!(range.contains(frame(0x2000)));
+    assert!(!range.contains(frame(0x3000)));
+}
+
+#[test_case]
+fn test_reserve_after_allocation_rejected() {
+    // The heap is mapped by now, so frames have been handed out.
+    let start = PhysAddr::new(0x10_0000);
+    let result = reserve(start, start + 4096u64, ReservedKind::Ramdisk);
+    assert_eq!(result, Err(ReserveError::TooLate));
+}
+
+#[test_case]
+fn test_allocate_contiguous() {
+    let before = frame_stats().allocated;
+    let first = FRAME_ALLOCATOR.get().lock().allocate_contiguous(4).expect("no run of 4 frames");
+    let allocator = BootInfoFrameAllocator { memory_map: MEMORY_MAP.get().unwrap(), next: 0 };
+    for i in 0..4 {
+        assert!(allocator.usable_frames().any(|frame| frame == first + i));
+    }
+    assert!(frame_stats().allocated >= before + 4);
+}
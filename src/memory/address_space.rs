@@ -0,0 +1,183 @@
+//! Address spaces for user programs.
+//!
+//! An [`AddressSpace`] has its own level 4 table. The kernel's entries are
+//! copied in, so the kernel stays mapped (but not user accessible) while the
+//! address space is active, and the user range [`USER_START`]..[`USER_END`],
+//! which the kernel never maps, belongs to the program. Pages are mapped and
+//! filled through the physical memory mapping, so an address space doesn't
+//! have to be active to be set up.
+//!
+//! Frames aren't freed when an address space is dropped: the frame
+//! allocator can't take frames back yet.
+
+use core::ops::Range;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+    Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{map_page, MemError, PHYS_OFFSET};
+use crate::arch::cr::Cr3;
+
+/// Start of the user range: level 4 entry 64, well above the entries the
+/// bootloader hands out and below the kernel heap.
+pub const USER_START: u64 = 0x0000_2000_0000_0000;
+/// One past the end of the user range (level 4 entries 64 to 95).
+pub const USER_END: u64 = 0x0000_3000_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Level 4 entries covering the user range.
+const USER_ENTRIES: Range<usize> = (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+/// Return whether `start..end` lies in the user range.
+pub fn is_user_range(start: u64, end: u64) -> bool {
+    USER_START <= start && start <= end && end <= USER_END
+}
+
+/// A set of page tables sharing the kernel's mappings, with a private user
+/// range.
+pub struct AddressSpace {
+    level_4: PhysFrame,
+    phys_offset: VirtAddr,
+}
+
+impl AddressSpace {
+    /// Create an address space with an empty user range.
+    ///
+    /// Call with the kernel's own page tables active; their entries are the
+    /// ones copied. Fails if the kernel has mapped anything in the user
+    /// range.
+    pub fn new(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<Self, MemError> {
+        let phys_offset = *PHYS_OFFSET.get();
+        let (active, _) = Cr3::read();
+        let active = unsafe { &*table_ptr(phys_offset, active) };
+        if active.iter().enumerate().any(|(index, entry)| {
+            USER_ENTRIES.contains(&index) && !entry.is_unused()
+        }) {
+            return Err(MemError::UserRangeInUse);
+        }
+
+        let level_4 = frame_allocator.allocate_frame().ok_or(MemError::FrameAllocationFailed)?;
+        let table = unsafe { &mut *table_ptr(phys_offset, level_4) };
+        table.zero();
+        for (index, entry) in active.iter().enumerate() {
+            if !USER_ENTRIES.contains(&index) {
+                table[index] = entry.clone();
+            }
+        }
+        Ok(AddressSpace { level_4, phys_offset })
+    }
+
+    /// Return the frame of the level 4 table, for loading into CR3.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4
+    }
+
+    /// Map `page` to a new zeroed frame, user accessible with `flags`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `page` isn't in the user range.
+    pub fn map_user(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<PhysFrame, MemError> {
+        let start = page.start_address().as_u64();
+        assert!(is_user_range(start, start + PAGE_SIZE), "{:#x} not in user range", start);
+        let frame = frame_allocator.allocate_frame().ok_or(MemError::FrameAllocationFailed)?;
+        let bytes = (self.phys_offset + frame.start_address().as_u64()).as_mut_ptr::<u8>();
+        unsafe { bytes.write_bytes(0, PAGE_SIZE as usize) };
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        // The tables aren't active, so there's no TLB entry to flush.
+        unsafe { map_page(&mut self.mapper(), page, frame, flags, frame_allocator)?.ignore() };
+        Ok(frame)
+    }
+
+    /// Return the physical address `addr` maps to, and the page's flags.
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match self.mapper().translate(addr) {
+            TranslateResult::Mapped { frame, offset, flags } => {
+                Some((frame.start_address() + offset, flags))
+            }
+            _ => None,
+        }
+    }
+
+    /// Copy `bytes` to `addr`, which must be mapped. Page protections
+    /// don't apply.
+    pub fn write(&mut self, addr: VirtAddr, bytes: &[u8]) -> Result<(), MemError> {
+        self.for_each_chunk(addr, bytes.len(), |target, range| unsafe {
+            target.copy_from_nonoverlapping(bytes[range.clone()].as_ptr(), range.len());
+        })
+    }
+
+    /// Copy from `addr`, which must be mapped, into `buf`.
+    pub fn read(&self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), MemError> {
+        self.for_each_chunk(addr, buf.len(), |source, range| unsafe {
+            buf[range.clone()].as_mut_ptr().copy_from_nonoverlapping(source, range.len());
+        })
+    }
+
+    /// Call `f` with a kernel pointer to each piece of `addr..addr + len`
+    /// that doesn't cross a page, and the matching range of offsets.
+    fn for_each_chunk(
+        &self,
+        addr: VirtAddr,
+        len: usize,
+        mut f: impl FnMut(*mut u8, Range<usize>),
+    ) -> Result<(), MemError> {
+        let mut done = 0;
+        while done < len {
+            let at = addr + done as u64;
+            let (phys, _) = self.translate(at).ok_or(MemError::NotMapped(at))?;
+            let chunk = (PAGE_SIZE - at.as_u64() % PAGE_SIZE).min((len - done) as u64) as usize;
+            f((self.phys_offset + phys.as_u64()).as_mut_ptr(), done..done + chunk);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'_> {
+        // SAFETY: the table is only reached through this address space, and
+        // all of physical memory is mapped at `phys_offset`.
+        unsafe {
+            OffsetPageTable::new(&mut *table_ptr(self.phys_offset, self.level_4), self.phys_offset)
+        }
+    }
+}
+
+fn table_ptr(phys_offset: VirtAddr, frame: PhysFrame) -> *mut PageTable {
+    (phys_offset + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+#[test_case]
+fn test_address_space_isolated() {
+    use super::FRAME_ALLOCATOR;
+
+    let mut frames = FRAME_ALLOCATOR.get().lock();
+    let mut space = AddressSpace::new(&mut *frames).unwrap();
+    let mut other = AddressSpace::new(&mut *frames).unwrap();
+    let page = Page::containing_address(VirtAddr::new(USER_START));
+    space.map_user(page, PageTableFlags::WRITABLE, &mut *frames).unwrap();
+    drop(frames);
+
+    let addr = VirtAddr::new(USER_START + 0x10);
+    space.write(addr, b"user").unwrap();
+    let mut buf = [0; 6];
+    space.read(addr - 1u64, &mut buf).unwrap();
+    assert_eq!(&buf, b"\0user\0");
+    let (_, flags) = space.translate(addr).unwrap();
+    assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+
+    // The other space doesn't see the page, but both see the kernel.
+    assert!(other.translate(addr).is_none());
+    assert_eq!(other.write(addr, b"x"), Err(MemError::NotMapped(addr)));
+    let kernel = VirtAddr::new(is_user_range as fn(u64, u64) -> bool as usize as u64);
+    assert!(space.translate(kernel).is_some());
+    assert_eq!(space.translate(kernel), other.translate(kernel));
+}
@@ -0,0 +1,433 @@
+//! Raw Ethernet frames over the network card.
+//!
+//! [`init`] looks among the PCI devices for an e1000, the card QEMU gives
+//! a `pc` machine by default. It maps the card's registers at
+//! [`NIC_VIRT`], sets up its descriptor rings and brings it up (see
+//! [`e1000`]), then attaches its interrupt with
+//! [`register_irq`](crate::interrupts::register_irq).
+//!
+//! There is no protocol stack yet. [`send`] transmits a frame as given,
+//! source address and all, and [`frames`] yields the frames that arrive.
+//! The interrupt handler copies them into a bounded
+//! [channel](crate::task::channel), so it never allocates. Frames are
+//! dropped when the channel is full, or when the card has no free receive
+//! descriptor. Both kinds of drop are counted in [`stats`].
+
+use core::fmt;
+use core::ops::Deref;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+use conquer_once::spin::OnceCell;
+use futures_util::stream::Stream;
+use x86_64::structures::paging::{Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::interrupts::{self, IrqError};
+use crate::memory::{self, BootInfoFrameAllocator, MemError};
+use crate::pci::{self, Bar};
+use crate::println;
+use crate::sync::{Global, IrqMutex};
+use crate::task::channel::{self, Receiver, Sender};
+use crate::task::futures::StreamExt;
+
+pub mod e1000;
+
+use e1000::E1000;
+
+/// Where the card's registers are mapped.
+pub const NIC_VIRT: u64 = 0x_7777_7777_0000;
+
+/// Longest frame sent or received: a 14-byte header and 1500 bytes of
+/// payload. The card adds and strips the checksum.
+pub const MAX_FRAME: usize = 1514;
+
+/// Shortest frame [`send`] takes: the header. The card pads frames to the
+/// Ethernet minimum.
+pub const HEADER_LEN: usize = 14;
+
+/// Frames buffered for [`frames`] before new ones are dropped.
+const QUEUE_CAPACITY: usize = 32;
+
+/// A 48-bit Ethernet address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+/// A received Ethernet frame, without its checksum.
+#[derive(Clone)]
+pub struct Frame {
+    len: usize,
+    data: [u8; MAX_FRAME],
+}
+
+impl Frame {
+    /// Copy `bytes`, which must be at most [`MAX_FRAME`] long.
+    fn new(bytes: &[u8]) -> Frame {
+        let mut frame = Frame { len: bytes.len(), data: [0; MAX_FRAME] };
+        frame.data[..bytes.len()].copy_from_slice(bytes);
+        frame
+    }
+
+    /// Return the EtherType, or `None` for a runt.
+    pub fn ethertype(&self) -> Option<u16> {
+        Some(u16::from_be_bytes([*self.get(12)?, *self.get(13)?]))
+    }
+}
+
+impl Deref for Frame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Frame")
+            .field("len", &self.len)
+            .field("ethertype", &self.ethertype())
+            .finish()
+    }
+}
+
+/// Why the card couldn't be set up, or a frame sent.
+#[derive(Debug)]
+pub enum NetError {
+    /// There is no supported network card, or it wasn't set up.
+    NoDevice,
+    /// The card has no memory BAR for its registers.
+    NoRegisters,
+    /// No run of frames was free for the descriptor rings and buffers.
+    NoDmaMemory,
+    /// Mapping the card's registers failed.
+    Map(MemError),
+    /// The card didn't come out of reset.
+    Timeout,
+    /// The firmware routed the card to no interrupt line.
+    NoIrq,
+    /// Attaching the interrupt handler failed.
+    Irq(IrqError),
+    /// The frame was shorter than its header.
+    TooShort(usize),
+    /// The frame was longer than [`MAX_FRAME`].
+    TooLong(usize),
+    /// Every transmit descriptor is waiting to be sent.
+    TxFull,
+    /// A [`Frames`] stream is open already.
+    Busy,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::NoDevice => f.write_str("no network card"),
+            NetError::NoRegisters => f.write_str("network card has no register window"),
+            NetError::NoDmaMemory => f.write_str("no contiguous memory for descriptor rings"),
+            NetError::Map(err) => write!(f, "mapping the registers failed: {}", err),
+            NetError::Timeout => f.write_str("network card stuck in reset"),
+            NetError::NoIrq => f.write_str("network card has no interrupt line"),
+            NetError::Irq(err) => write!(f, "{}", err),
+            NetError::TooShort(len) => write!(f, "frame of {} bytes is too short", len),
+            NetError::TooLong(len) => write!(f, "frame of {} bytes is too long", len),
+            NetError::TxFull => f.write_str("transmit ring full"),
+            NetError::Busy => f.write_str("receive stream already open"),
+        }
+    }
+}
+
+/// Frame counts since [`init`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetStats {
+    pub rx_frames: u64,
+    /// Received, but dropped because the [`frames`] queue was full.
+    pub rx_dropped: u64,
+    /// Not received because the card had no free descriptor.
+    pub rx_missed: u64,
+    /// Received with errors, or too long.
+    pub rx_errors: u64,
+    /// Times the card ran out of receive descriptors.
+    pub rx_overruns: u64,
+    pub tx_frames: u64,
+    /// Frames [`send`] refused because the transmit ring was full.
+    pub tx_full: u64,
+    pub link_changes: u64,
+}
+
+struct Counters {
+    rx_frames: AtomicU64,
+    rx_missed: AtomicU64,
+    rx_errors: AtomicU64,
+    rx_overruns: AtomicU64,
+    tx_frames: AtomicU64,
+    tx_full: AtomicU64,
+    link_changes: AtomicU64,
+}
+
+static COUNTERS: Counters = Counters {
+    rx_frames: AtomicU64::new(0),
+    rx_missed: AtomicU64::new(0),
+    rx_errors: AtomicU64::new(0),
+    rx_overruns: AtomicU64::new(0),
+    tx_frames: AtomicU64::new(0),
+    tx_full: AtomicU64::new(0),
+    link_changes: AtomicU64::new(0),
+};
+
+fn bump(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Return the frame counts.
+pub fn stats() -> NetStats {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    NetStats {
+        rx_frames: load(&COUNTERS.rx_frames),
+        rx_dropped: SENDER.try_get().map_or(0, Sender::dropped),
+        rx_missed: load(&COUNTERS.rx_missed),
+        rx_errors: load(&COUNTERS.rx_errors),
+        rx_overruns: load(&COUNTERS.rx_overruns),
+        tx_frames: load(&COUNTERS.tx_frames),
+        tx_full: load(&COUNTERS.tx_full),
+        link_changes: load(&COUNTERS.link_changes),
+    }
+}
+
+static NIC: Global<E1000> = Global::new("NIC");
+
+/// Where the interrupt handler queues received frames.
+static SENDER: OnceCell<Sender<Frame>> = OnceCell::uninit();
+
+/// The receiving end, while no [`Frames`] stream has it.
+static RECEIVER: IrqMutex<Option<Receiver<Frame>>> = IrqMutex::named("NET_RECEIVER", None);
+
+/// Find the network card and bring it up. No card is not an error.
+///
+/// Needs the heap and PCI scan, and a frame allocator that can still hand
+/// out contiguous frames.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), NetError> {
+    let Some(device) = pci::devices()
+        .find(|device| e1000::DEVICE_IDS.contains(&(device.vendor_id, device.device_id)))
+    else {
+        println!("net: no supported network card");
+        return Ok(());
+    };
+    let Bar::Memory { address, size, .. } = device.bars[0] else {
+        return Err(NetError::NoRegisters);
+    };
+    let line = device.irq_line.ok_or(NetError::NoIrq)?;
+    let regs = VirtAddr::new(NIC_VIRT);
+    memory::map_mmio(mapper, frame_allocator, regs, PhysAddr::new(address), size)
+        .map_err(NetError::Map)?;
+    let dma = frame_allocator
+        .allocate_contiguous(e1000::DMA_FRAMES)
+        .ok_or(NetError::NoDmaMemory)?
+        .start_address();
+    let dma_virt = *memory::PHYS_OFFSET.get() + dma.as_u64();
+    unsafe { core::ptr::write_bytes(dma_virt.as_mut_ptr::<u8>(), 0, e1000::DMA_FRAMES * 4096) };
+    device.enable_memory_space();
+    device.enable_bus_mastering();
+
+    let nic = unsafe { E1000::new(regs, dma, dma_virt)? };
+    let (sender, receiver) = channel::channel(QUEUE_CAPACITY);
+    let _ = SENDER.try_init_once(|| sender);
+    *RECEIVER.lock() = Some(receiver);
+    let nic = NIC.init(nic).map_err(|_| NetError::Busy)?;
+    interrupts::register_irq(line, interrupt).map_err(NetError::Irq)?;
+    nic.enable_interrupts();
+    println!(
+        "net: e1000 {} at {:02x}:{:02x}.{}, irq {}, link {}",
+        nic.mac(),
+        device.bus,
+        device.dev,
+        device.func,
+        line,
+        if nic.link_up() { "up" } else { "down" }
+    );
+    Ok(())
+}
+
+/// Handle the card's interrupt: count what it reports and queue what it
+/// received.
+fn interrupt() {
+    let Ok(nic) = NIC.try_get() else {
+        return;
+    };
+    let causes = nic.take_causes();
+    if causes == 0 {
+        // Another device on a shared line.
+        return;
+    }
+    if causes & e1000::ICR_LSC != 0 {
+        bump(&COUNTERS.link_changes);
+    }
+    if causes & e1000::ICR_RXO != 0 {
+        bump(&COUNTERS.rx_overruns);
+    }
+    let missed = nic.take_missed();
+    COUNTERS.rx_missed.fetch_add(u64::from(missed), Ordering::Relaxed);
+    nic.receive(|frame| match frame {
+        Some(bytes) => {
+            bump(&COUNTERS.rx_frames);
+            if let Ok(sender) = SENDER.try_get() {
+                // A full queue counts the frame as dropped.
+                let _ = sender.try_send(Frame::new(bytes));
+            }
+        }
+        None => bump(&COUNTERS.rx_errors),
+    });
+}
+
+/// Send `frame`, a whole Ethernet frame without the checksum.
+pub fn send(frame: &[u8]) -> Result<(), NetError> {
+    if frame.len() < HEADER_LEN {
+        return Err(NetError::TooShort(frame.len()));
+    }
+    if frame.len() > MAX_FRAME {
+        return Err(NetError::TooLong(frame.len()));
+    }
+    let nic = NIC.try_get().map_err(|_| NetError::NoDevice)?;
+    match nic.transmit(frame) {
+        Ok(()) => {
+            bump(&COUNTERS.tx_frames);
+            Ok(())
+        }
+        Err(err) => {
+            bump(&COUNTERS.tx_full);
+            Err(err)
+        }
+    }
+}
+
+/// Return the card's MAC address, or `None` without a card.
+pub fn mac() -> Option<MacAddress> {
+    NIC.try_get().ok().map(E1000::mac)
+}
+
+/// Return whether the link is up, or `None` without a card.
+pub fn link_up() -> Option<bool> {
+    NIC.try_get().ok().map(E1000::link_up)
+}
+
+/// Open the stream of received frames. Only one can be open at a time;
+/// dropping it lets the next one open, and frames that arrive in between
+/// wait in the queue.
+pub fn frames() -> Result<Frames, NetError> {
+    NIC.try_get().map_err(|_| NetError::NoDevice)?;
+    let receiver = RECEIVER.lock().take().ok_or(NetError::Busy)?;
+    Ok(Frames { receiver: Some(receiver) })
+}
+
+/// The frames the card receives, oldest first. See [`frames`].
+pub struct Frames {
+    /// Always `Some` until dropped.
+    receiver: Option<Receiver<Frame>>,
+}
+
+impl Frames {
+    /// Wait for the next frame.
+    pub async fn recv(&mut self) -> Frame {
+        match self.next().await {
+            Some(frame) => frame,
+            // The sender is never dropped, so the stream never ends.
+            None => core::future::pending().await,
+        }
+    }
+}
+
+impl Stream for Frames {
+    type Item = Frame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Frame>> {
+        match self.receiver.as_mut() {
+            Some(receiver) => Pin::new(receiver).poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl Drop for Frames {
+    fn drop(&mut self) {
+        *RECEIVER.lock() = self.receiver.take();
+    }
+}
+
+/// The EtherType of ARP.
+#[cfg(test)]
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Build an ARP request from `mac` at QEMU's guest address, 10.0.2.15, for
+/// its gateway, 10.0.2.2.
+#[cfg(test)]
+fn arp_request(mac: MacAddress) -> [u8; 42] {
+    let mut frame = [0; 42];
+    frame[0..6].copy_from_slice(&[0xff; 6]);
+    frame[6..12].copy_from_slice(&mac.0);
+    frame[12..14].copy_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+    // Ethernet and IPv4, 6- and 4-byte addresses, request.
+    frame[14..22].copy_from_slice(&[0, 1, 8, 0, 6, 4, 0, 1]);
+    frame[22..28].copy_from_slice(&mac.0);
+    frame[28..32].copy_from_slice(&[10, 0, 2, 15]);
+    frame[38..42].copy_from_slice(&[10, 0, 2, 2]);
+    frame
+}
+
+#[test_case]
+fn test_send_checks_length() {
+    assert!(matches!(send(&[0; 10]), Err(NetError::TooShort(10))));
+    assert!(matches!(send(&[0; MAX_FRAME + 1]), Err(NetError::TooLong(1515))));
+}
+
+#[test_case]
+fn test_transmit_ring_wraps() {
+    // A local experimental EtherType, which QEMU's user network ignores.
+    let mut frame = [0; 60];
+    frame[0..6].copy_from_slice(&[0xff; 6]);
+    frame[6..12].copy_from_slice(&mac().expect("no network card").0);
+    frame[12..14].copy_from_slice(&0x88b5u16.to_be_bytes());
+    let before = stats().tx_frames;
+    for _ in 0..2 * e1000::RING_SIZE + 3 {
+        send(&frame).unwrap();
+    }
+    assert_eq!(stats().tx_frames - before, 2 * e1000::RING_SIZE as u64 + 3);
+}
+
+#[test_case]
+fn test_arp_reply_arrives() {
+    use crate::task::block_on_with_timeout;
+
+    let mac = mac().expect("no network card");
+    let mut stream = frames().unwrap();
+    assert!(matches!(frames(), Err(NetError::Busy)));
+    send(&arp_request(mac)).unwrap();
+    let reply = block_on_with_timeout(
+        async {
+            loop {
+                let frame = stream.recv().await;
+                // Opcode 2, a reply.
+                let reply = frame.get(20..22) == Some(&[0, 2][..]);
+                if frame.ethertype() == Some(ETHERTYPE_ARP) && reply {
+                    return frame;
+                }
+            }
+        },
+        2 * u64::from(crate::time::TIMER_HZ),
+    )
+    .expect("no ARP reply");
+    assert_eq!(&reply[0..6], &mac.0);
+    assert_eq!(&reply[32..38], &mac.0);
+    assert_eq!(&reply[28..32], &[10, 0, 2, 2]);
+    drop(stream);
+    assert!(frames().is_ok());
+}
@@ -0,0 +1,402 @@
+//! Driver for the Intel 8254x (e1000) gigabit controllers, as QEMU
+//! emulates them.
+//!
+//! Register offsets and bits are from Intel's 8254x software developer's
+//! manual. Both directions use legacy descriptors in rings of
+//! [`RING_SIZE`], with one [`BUFFER_SIZE`] buffer per descriptor. The
+//! rings and buffers share one physically contiguous block of
+//! [`DMA_FRAMES`] frames:
+//!
+//! | Frames | Contents |
+//! |--------|----------|
+//! | 0 | receive descriptors, then transmit descriptors from byte 2048 |
+//! | 1–16 | receive buffers |
+//! | 17–32 | transmit buffers |
+//!
+//! The receive tail trails the head by one descriptor, so the card never
+//! fills the last free one; software hands each descriptor back by moving
+//! the tail onto it once its frame has been copied out.
+
+use core::ptr;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{MacAddress, NetError, MAX_FRAME};
+use crate::sync::IrqMutex;
+
+/// Descriptors per ring. The ring's size in bytes must be a multiple of 128.
+pub const RING_SIZE: usize = 32;
+
+/// Bytes per receive or transmit buffer: the card's default receive buffer
+/// size.
+pub const BUFFER_SIZE: usize = 2048;
+
+/// Frames in the DMA block; see the module docs.
+pub const DMA_FRAMES: usize = 1 + 2 * RING_SIZE * BUFFER_SIZE / 4096;
+
+/// Offset of the transmit descriptors in the DMA block.
+const TX_RING_OFFSET: u64 = 2048;
+/// Offset of the receive buffers in the DMA block.
+const RX_BUFFERS_OFFSET: u64 = 4096;
+/// Offset of the transmit buffers in the DMA block.
+const TX_BUFFERS_OFFSET: u64 = RX_BUFFERS_OFFSET + (RING_SIZE * BUFFER_SIZE) as u64;
+
+/// PCI vendor and device IDs the driver takes: the 82540EM QEMU emulates
+/// by default, and the 82545EM it offers as `e1000-82545em`.
+pub const DEVICE_IDS: [(u16, u16); 2] = [(0x8086, 0x100e), (0x8086, 0x100f)];
+
+/// Register offsets.
+const CTRL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const EERD: usize = 0x0014;
+const ICR: usize = 0x00c0;
+const IMS: usize = 0x00d0;
+const IMC: usize = 0x00d8;
+const RCTL: usize = 0x0100;
+const TCTL: usize = 0x0400;
+const TIPG: usize = 0x0410;
+const MPC: usize = 0x4010;
+const RDBAL: usize = 0x2800;
+const RDBAH: usize = 0x2804;
+const RDLEN: usize = 0x2808;
+const RDH: usize = 0x2810;
+const RDT: usize = 0x2818;
+const TDBAL: usize = 0x3800;
+const TDBAH: usize = 0x3804;
+const TDLEN: usize = 0x3808;
+const TDH: usize = 0x3810;
+const TDT: usize = 0x3818;
+/// Multicast table: 128 dwords.
+const MTA: usize = 0x5200;
+const RAL: usize = 0x5400;
+const RAH: usize = 0x5404;
+
+/// Device control bits.
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+/// Device status: link up.
+const STATUS_LU: u32 = 1 << 1;
+
+/// EEPROM read register bits.
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+/// Interrupt cause bits, in ICR, IMS and IMC.
+pub const ICR_LSC: u32 = 1 << 2;
+pub const ICR_RXDMT0: u32 = 1 << 4;
+pub const ICR_RXO: u32 = 1 << 6;
+pub const ICR_RXT0: u32 = 1 << 7;
+
+/// Receive control: enable, accept broadcast, strip the CRC. A buffer size
+/// field of 0 means [`BUFFER_SIZE`].
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+
+/// Transmit control: enable, pad short frames, and the collision threshold
+/// and distance the manual recommends for full duplex.
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x0f << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+
+/// Inter-packet gap the manual recommends for the 82540EM.
+const TIPG_DEFAULT: u32 = 10 | 10 << 10 | 10 << 20;
+
+/// Descriptor status: the card is done with it.
+const STATUS_DD: u8 = 1 << 0;
+/// Receive descriptor status: last descriptor of a frame.
+const STATUS_EOP: u8 = 1 << 1;
+
+/// Transmit descriptor command: end of packet, insert the CRC, report
+/// status (set DD when sent).
+const CMD_EOP: u8 = 1 << 0;
+const CMD_IFCS: u8 = 1 << 1;
+const CMD_RS: u8 = 1 << 3;
+
+/// Reads of a register while waiting for the card.
+const POLL_LIMIT: u32 = 100_000;
+
+/// A legacy receive descriptor.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct RxDescriptor {
+    addr: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+/// A legacy transmit descriptor.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct TxDescriptor {
+    addr: u64,
+    length: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+/// The memory-mapped registers.
+struct Regs(VirtAddr);
+
+impl Regs {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.0 + reg as u64).as_ptr::<u32>()) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.0 + reg as u64).as_mut_ptr::<u32>(), value) }
+    }
+}
+
+/// One descriptor ring and its buffers.
+struct Ring {
+    descriptors: VirtAddr,
+    buffers: VirtAddr,
+    buffers_phys: PhysAddr,
+    /// The next descriptor to look at: the oldest one the card may have
+    /// filled, or the next one to transmit from.
+    next: usize,
+}
+
+impl Ring {
+    fn buffer(&self, index: usize) -> *mut u8 {
+        (self.buffers + (index * BUFFER_SIZE) as u64).as_mut_ptr()
+    }
+
+    fn buffer_phys(&self, index: usize) -> u64 {
+        self.buffers_phys.as_u64() + (index * BUFFER_SIZE) as u64
+    }
+
+    fn rx_descriptor(&self, index: usize) -> *mut RxDescriptor {
+        self.descriptors.as_mut_ptr::<RxDescriptor>().wrapping_add(index)
+    }
+
+    fn tx_descriptor(&self, index: usize) -> *mut TxDescriptor {
+        self.descriptors.as_mut_ptr::<TxDescriptor>().wrapping_add(index)
+    }
+}
+
+/// An e1000 that has been reset and is receiving and transmitting.
+pub struct E1000 {
+    regs: Regs,
+    mac: MacAddress,
+    rx: IrqMutex<Ring>,
+    tx: IrqMutex<Ring>,
+}
+
+impl E1000 {
+    /// Reset the card with its registers mapped at `regs`, set up the rings
+    /// in the [`DMA_FRAMES`] frames at `dma` (mapped at `dma_virt`), and
+    /// turn on receiving and transmitting. Interrupts stay off until
+    /// [`enable_interrupts`](Self::enable_interrupts).
+    ///
+    /// # Safety
+    ///
+    /// `regs` must map the card's register BAR, and the DMA block must be
+    /// unused, mapped and physically contiguous.
+    pub unsafe fn new(
+        regs: VirtAddr,
+        dma: PhysAddr,
+        dma_virt: VirtAddr,
+    ) -> Result<E1000, NetError> {
+        let regs = Regs(regs);
+        regs.write(IMC, u32::MAX);
+        regs.write(CTRL, regs.read(CTRL) | CTRL_RST);
+        wait(|| regs.read(CTRL) & CTRL_RST == 0).ok_or(NetError::Timeout)?;
+        // Reset doesn't clear the mask on all models.
+        regs.write(IMC, u32::MAX);
+        let _ = regs.read(ICR);
+        regs.write(CTRL, regs.read(CTRL) | CTRL_SLU | CTRL_ASDE);
+
+        let mac = read_mac(&regs);
+        let [a, b, c, d, e, f] = mac.0;
+        regs.write(RAL, u32::from_le_bytes([a, b, c, d]));
+        // Address valid.
+        regs.write(RAH, u32::from_le_bytes([e, f, 0, 0]) | 1 << 31);
+        for i in 0..128 {
+            regs.write(MTA + 4 * i, 0);
+        }
+
+        let rx = Ring {
+            descriptors: dma_virt,
+            buffers: dma_virt + RX_BUFFERS_OFFSET,
+            buffers_phys: dma + RX_BUFFERS_OFFSET,
+            next: 0,
+        };
+        let tx = Ring {
+            descriptors: dma_virt + TX_RING_OFFSET,
+            buffers: dma_virt + TX_BUFFERS_OFFSET,
+            buffers_phys: dma + TX_BUFFERS_OFFSET,
+            next: 0,
+        };
+        for i in 0..RING_SIZE {
+            let descriptor = RxDescriptor { addr: rx.buffer_phys(i), ..RxDescriptor::default() };
+            unsafe { ptr::write_volatile(rx.rx_descriptor(i), descriptor) };
+            // Free until used: transmitting checks DD.
+            let descriptor = TxDescriptor {
+                addr: tx.buffer_phys(i),
+                status: STATUS_DD,
+                ..TxDescriptor::default()
+            };
+            unsafe { ptr::write_volatile(tx.tx_descriptor(i), descriptor) };
+        }
+        let ring_bytes = (RING_SIZE * size_of::<RxDescriptor>()) as u32;
+
+        let rx_ring = dma.as_u64();
+        regs.write(RDBAL, rx_ring as u32);
+        regs.write(RDBAH, (rx_ring >> 32) as u32);
+        regs.write(RDLEN, ring_bytes);
+        regs.write(RDH, 0);
+        regs.write(RDT, (RING_SIZE - 1) as u32);
+        regs.write(RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        let tx_ring = dma.as_u64() + TX_RING_OFFSET;
+        regs.write(TDBAL, tx_ring as u32);
+        regs.write(TDBAH, (tx_ring >> 32) as u32);
+        regs.write(TDLEN, ring_bytes);
+        regs.write(TDH, 0);
+        regs.write(TDT, 0);
+        regs.write(TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+        regs.write(TIPG, TIPG_DEFAULT);
+
+        Ok(E1000 {
+            regs,
+            mac,
+            rx: IrqMutex::named("E1000_RX", rx),
+            tx: IrqMutex::named("E1000_TX", tx),
+        })
+    }
+
+    /// Interrupt on received frames, receive overruns, running low on
+    /// receive descriptors and link changes.
+    pub fn enable_interrupts(&self) {
+        self.regs.write(IMS, ICR_RXT0 | ICR_RXO | ICR_RXDMT0 | ICR_LSC);
+    }
+
+    /// Return the card's MAC address.
+    pub fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    /// Return whether the link is up.
+    pub fn link_up(&self) -> bool {
+        self.regs.read(STATUS) & STATUS_LU != 0
+    }
+
+    /// Read and clear the interrupt causes, a set of `ICR_` bits. 0 means
+    /// the interrupt on a shared line wasn't this card's.
+    pub fn take_causes(&self) -> u32 {
+        self.regs.read(ICR)
+    }
+
+    /// Read and clear the count of frames missed for lack of a free
+    /// receive descriptor.
+    pub fn take_missed(&self) -> u32 {
+        self.regs.read(MPC)
+    }
+
+    /// Queue `frame` for sending.
+    ///
+    /// Fails with [`NetError::TxFull`] if the card hasn't sent the frame
+    /// queued [`RING_SIZE`] frames ago yet.
+    pub fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        debug_assert!(frame.len() <= BUFFER_SIZE);
+        let mut tx = self.tx.lock();
+        let index = tx.next;
+        let descriptor = tx.tx_descriptor(index);
+        if unsafe { ptr::read_volatile(descriptor) }.status & STATUS_DD == 0 {
+            return Err(NetError::TxFull);
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(frame.as_ptr(), tx.buffer(index), frame.len());
+            ptr::write_volatile(descriptor, TxDescriptor {
+                addr: tx.buffer_phys(index),
+                length: frame.len() as u16,
+                cmd: CMD_EOP | CMD_IFCS | CMD_RS,
+                ..TxDescriptor::default()
+            });
+        }
+        tx.next = (index + 1) % RING_SIZE;
+        self.regs.write(TDT, tx.next as u32);
+        Ok(())
+    }
+
+    /// Pass each frame the card has received to `deliver`, oldest first, and
+    /// give its descriptor back. Frames with errors, or too long to be
+    /// [`MAX_FRAME`], are passed as `None`.
+    ///
+    /// Doesn't allocate, for the interrupt handler.
+    pub fn receive(&self, mut deliver: impl FnMut(Option<&[u8]>)) {
+        let mut rx = self.rx.lock();
+        loop {
+            let index = rx.next;
+            let descriptor = unsafe { ptr::read_volatile(rx.rx_descriptor(index)) };
+            if descriptor.status & STATUS_DD == 0 {
+                break;
+            }
+            let len = usize::from(descriptor.length);
+            let whole = descriptor.status & STATUS_EOP != 0 && descriptor.errors == 0;
+            if whole && len <= MAX_FRAME {
+                deliver(Some(unsafe { core::slice::from_raw_parts(rx.buffer(index), len) }));
+            } else {
+                deliver(None);
+            }
+            let fresh = RxDescriptor { addr: rx.buffer_phys(index), ..RxDescriptor::default() };
+            unsafe { ptr::write_volatile(rx.rx_descriptor(index), fresh) };
+            self.regs.write(RDT, index as u32);
+            rx.next = (index + 1) % RING_SIZE;
+        }
+    }
+}
+
+/// Poll `done` up to [`POLL_LIMIT`] times; `None` if it never held.
+fn wait(mut done: impl FnMut() -> bool) -> Option<()> {
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return Some(());
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+/// Read the MAC address from the EEPROM, or from the receive address
+/// registers the card loaded from it if the EEPROM doesn't answer.
+fn read_mac(regs: &Regs) -> MacAddress {
+    let mut words = [0u16; 3];
+    for (address, word) in words.iter_mut().enumerate() {
+        regs.write(EERD, EERD_START | (address as u32) << 8);
+        let mut value = 0;
+        if wait(|| {
+            value = regs.read(EERD);
+            value & EERD_DONE != 0
+        })
+        .is_none()
+        {
+            let [a, b, c, d] = regs.read(RAL).to_le_bytes();
+            let [e, f, _, _] = regs.read(RAH).to_le_bytes();
+            return MacAddress([a, b, c, d, e, f]);
+        }
+        *word = (value >> 16) as u16;
+    }
+    let [[a, b], [c, d], [e, f]] = words.map(u16::to_le_bytes);
+    MacAddress([a, b, c, d, e, f])
+}
+
+#[test_case]
+fn test_layout() {
+    assert_eq!(size_of::<RxDescriptor>(), 16);
+    assert_eq!(size_of::<TxDescriptor>(), 16);
+    assert_eq!((RING_SIZE * size_of::<RxDescriptor>()) % 128, 0);
+    assert!(RING_SIZE * size_of::<RxDescriptor>() <= TX_RING_OFFSET as usize);
+    assert_eq!(TX_BUFFERS_OFFSET as usize + RING_SIZE * BUFFER_SIZE, DMA_FRAMES * 4096);
+}
@@ -0,0 +1,119 @@
+//! Kernel messages fanned out to every output device at once.
+//!
+//! [`kprintln!`](crate::kprintln) sends one line to each [`register`]ed
+//! output: the VGA screen and COM1 register themselves at boot with
+//! [`vga_buffer::register`](crate::vga_buffer::register) and
+//! [`serial::register`](crate::serial::register). That is the whole point
+//! of it; use it instead of a `println!` and a `serial_println!` side by
+//! side. Unlike `print!` it ignores the [console](crate::console)
+//! selection. The message is recorded in the [kernel log](crate::klog) too.
+//!
+//! The outputs are a fixed array of function pointers, so registering
+//! needs no heap. Interrupts stay disabled across the whole fan-out, so a
+//! timer handler's message can't land between one output and the next.
+
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+/// Most outputs that can be registered.
+pub const MAX_OUTPUTS: usize = 8;
+
+/// Something kernel messages can be written to.
+pub trait KernelOutput {
+    /// Write `args`, dropping whatever can't be written.
+    fn write_output(&mut self, args: fmt::Arguments);
+}
+
+/// Registered output functions, as pointers; null until set.
+static OUTPUTS: [AtomicPtr<()>; MAX_OUTPUTS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_OUTPUTS];
+static OUTPUT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Have [`kprint!`](crate::kprint) call `output` with every message.
+///
+/// Registering the same function twice has no effect. Returns `false` if
+/// [`MAX_OUTPUTS`] are already registered.
+pub fn register(output: fn(fmt::Arguments)) -> bool {
+    let address = output as *mut ();
+    let registered = OUTPUT_COUNT.load(Ordering::Acquire).min(MAX_OUTPUTS);
+    if OUTPUTS[..registered].iter().any(|slot| slot.load(Ordering::Relaxed) == address) {
+        return true;
+    }
+    let index = OUTPUT_COUNT.fetch_add(1, Ordering::AcqRel);
+    let Some(slot) = OUTPUTS.get(index) else {
+        return false;
+    };
+    slot.store(address, Ordering::Release);
+    true
+}
+
+/// Return how many outputs are registered.
+pub fn count() -> usize {
+    OUTPUT_COUNT.load(Ordering::Acquire).min(MAX_OUTPUTS)
+}
+
+/// Write `args` to every registered output, with interrupts disabled
+/// throughout, and to the kernel log.
+///
+/// This function is not meant to be called directly. It is used by the
+/// [`kprint!`](crate::kprint) and [`kprintln!`](crate::kprintln) macros.
+#[doc(hidden)]
+pub fn _kprint(args: fmt::Arguments) {
+    interrupts::without_interrupts(|| {
+        for slot in &OUTPUTS[..count()] {
+            let address = slot.load(Ordering::Acquire);
+            if address.is_null() {
+                continue;
+            }
+            // SAFETY: `register` only stores `fn(fmt::Arguments)` pointers.
+            let output: fn(fmt::Arguments) = unsafe { core::mem::transmute(address) };
+            output(args);
+        }
+        crate::klog::record(args);
+    });
+}
+
+/// Prints to every registered output.
+#[macro_export]
+macro_rules! kprint {
+    ($($arg:tt)*) => ($crate::output::_kprint(format_args!($($arg)*)));
+}
+
+/// Prints to every registered output, with a newline.
+#[macro_export]
+macro_rules! kprintln {
+    () => ($crate::kprint!("\n"));
+    ($($arg:tt)*) => ($crate::kprint!("{}\n", format_args!($($arg)*)));
+}
+
+#[cfg(test)]
+static CAPTURED: spin::Mutex<crate::collections::FixedString<64>> =
+    spin::Mutex::new(crate::collections::FixedString::new());
+
+#[cfg(test)]
+fn capture(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let mut captured = CAPTURED.lock();
+    if interrupts::are_enabled() {
+        let _ = captured.write_str("interrupts on! ");
+    }
+    let _ = captured.write_fmt(args);
+}
+
+#[test_case]
+fn test_kprintln_fans_out() {
+    assert!(register(capture));
+    let registered = count();
+    assert!(register(capture));
+    assert_eq!(count(), registered);
+
+    CAPTURED.lock().clear();
+    crate::kprintln!("fan out {}", 7);
+    assert_eq!(CAPTURED.lock().as_str(), "fan out 7\n");
+    if crate::vga_buffer::is_present() {
+        assert!(crate::vga_buffer::screen_contains("fan out 7"));
+    }
+}
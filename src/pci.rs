@@ -0,0 +1,328 @@
+//! PCI device enumeration through the legacy configuration mechanism.
+//!
+//! Configuration space is reached through two I/O ports: a dword written to
+//! `0xcf8` selects bus, device, function and register, and `0xcfc` then
+//! reads or writes that register. [`init`] scans every bus once at boot and
+//! keeps the devices it finds; [`devices`] and [`find`] look them up
+//! afterwards.
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::println;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// Offsets of the configuration registers used here.
+const REG_VENDOR_DEVICE: u8 = 0x00;
+const REG_COMMAND: u8 = 0x04;
+const REG_CLASS: u8 = 0x08;
+const REG_HEADER_TYPE: u8 = 0x0c;
+const REG_BAR0: u8 = 0x10;
+const REG_INTERRUPT: u8 = 0x3c;
+
+/// Command register bits.
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// Header type bit marking a device with more than one function.
+const MULTI_FUNCTION: u8 = 0x80;
+
+/// Read the configuration register at `offset` (rounded down to a dword).
+pub fn read_config(bus: u8, dev: u8, func: u8, offset: u8) -> u32 {
+    interrupts::without_interrupts(|| unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(config_address(bus, dev, func, offset));
+        Port::<u32>::new(CONFIG_DATA).read()
+    })
+}
+
+/// Write the configuration register at `offset` (rounded down to a dword).
+pub fn write_config(bus: u8, dev: u8, func: u8, offset: u8, value: u32) {
+    interrupts::without_interrupts(|| unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(config_address(bus, dev, func, offset));
+        Port::<u32>::new(CONFIG_DATA).write(value);
+    })
+}
+
+fn config_address(bus: u8, dev: u8, func: u8, offset: u8) -> u32 {
+    1 << 31
+        | u32::from(bus) << 16
+        | u32::from(dev & 0x1f) << 11
+        | u32::from(func & 0x07) << 8
+        | u32::from(offset & 0xfc)
+}
+
+/// A decoded base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// Not implemented, or the upper half of the 64-bit BAR before it.
+    None,
+    /// A memory window.
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+    /// A range of I/O ports.
+    Io { port: u32, size: u32 },
+}
+
+/// A PCI function found by the boot scan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub bars: [Bar; 6],
+    /// Legacy interrupt line the firmware routed the device to, if any.
+    pub irq_line: Option<u8>,
+}
+
+impl PciDevice {
+    /// Read one of this function's configuration registers.
+    pub fn read(&self, offset: u8) -> u32 {
+        read_config(self.bus, self.dev, self.func, offset)
+    }
+
+    /// Write one of this function's configuration registers.
+    pub fn write(&self, offset: u8, value: u32) {
+        write_config(self.bus, self.dev, self.func, offset, value)
+    }
+
+    /// Set bits in the command register, leaving the status register alone.
+    fn set_command_bits(&self, bits: u16) {
+        // Status bits are write-one-to-clear, so write zeros to them.
+        let command = self.read(REG_COMMAND) as u16;
+        self.write(REG_COMMAND, u32::from(command | bits));
+    }
+
+    /// Let the device master the bus (start DMA).
+    pub fn enable_bus_mastering(&self) {
+        self.set_command_bits(COMMAND_BUS_MASTER);
+    }
+
+    /// Let the device decode accesses to its memory BARs.
+    pub fn enable_memory_space(&self) {
+        self.set_command_bits(COMMAND_MEMORY_SPACE);
+    }
+
+    /// Let the device decode accesses to its I/O BARs.
+    pub fn enable_io_space(&self) {
+        self.set_command_bits(COMMAND_IO_SPACE);
+    }
+}
+
+/// One line: location, IDs, class and IRQ.
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} {:02x}.{:02x}.{:02x} {}",
+            self.bus,
+            self.dev,
+            self.func,
+            self.vendor_id,
+            self.device_id,
+            self.class,
+            self.subclass,
+            self.prog_if,
+            class_name(self.class, self.subclass)
+        )?;
+        if let Some(irq) = self.irq_line {
+            write!(f, " irq {}", irq)?;
+        }
+        Ok(())
+    }
+}
+
+/// Return a short name for a class/subclass pair.
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "ide controller",
+        (0x01, 0x06) => "sata controller",
+        (0x01, 0x08) => "nvme controller",
+        (0x01, _) => "storage controller",
+        (0x02, 0x00) => "ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia device",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "isa bridge",
+        (0x06, 0x04) => "pci bridge",
+        (0x06, _) => "bridge",
+        (0x0c, 0x03) => "usb controller",
+        (0x0c, _) => "serial bus controller",
+        _ => "device",
+    }
+}
+
+/// Decode the BARs of a function whose header has `count` of them.
+///
+/// Each BAR is sized by writing all ones and reading back which bits stick.
+/// Decoding is turned off meanwhile, so the device doesn't briefly claim the
+/// addresses the probe writes.
+fn read_bars(bus: u8, dev: u8, func: u8, count: usize) -> [Bar; 6] {
+    let mut bars = [Bar::None; 6];
+    interrupts::without_interrupts(|| {
+        let command = read_config(bus, dev, func, REG_COMMAND) & 0xffff;
+        write_config(
+            bus,
+            dev,
+            func,
+            REG_COMMAND,
+            command & !u32::from(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+
+        let mut index = 0;
+        while index < count {
+            let offset = REG_BAR0 + 4 * index as u8;
+            let probe = |offset: u8| {
+                let original = read_config(bus, dev, func, offset);
+                write_config(bus, dev, func, offset, u32::MAX);
+                let mask = read_config(bus, dev, func, offset);
+                write_config(bus, dev, func, offset, original);
+                (original, mask)
+            };
+            let (low, low_mask) = probe(offset);
+            if low & 1 == 1 {
+                let mask = low_mask & !0x3;
+                if mask != 0 {
+                    bars[index] = Bar::Io {
+                        port: low & !0x3,
+                        size: (!mask).wrapping_add(1) & 0xffff,
+                    };
+                }
+                index += 1;
+                continue;
+            }
+            let is_64bit = (low >> 1) & 0x3 == 0x2 && index + 1 < count;
+            let (high, high_mask) = if is_64bit { probe(offset + 4) } else { (0, u32::MAX) };
+            let address = u64::from(high) << 32 | u64::from(low & !0xf);
+            let mask = u64::from(high_mask) << 32 | u64::from(low_mask & !0xf);
+            if low_mask & !0xf != 0 {
+                bars[index] = Bar::Memory {
+                    address,
+                    size: (!mask).wrapping_add(1),
+                    prefetchable: low & 0x8 != 0,
+                    is_64bit,
+                };
+            }
+            index += if is_64bit { 2 } else { 1 };
+        }
+
+        write_config(bus, dev, func, REG_COMMAND, command);
+    });
+    bars
+}
+
+/// Read the function at `bus:dev.func`, or `None` if nothing answers.
+fn probe_function(bus: u8, dev: u8, func: u8) -> Option<PciDevice> {
+    let ids = read_config(bus, dev, func, REG_VENDOR_DEVICE);
+    let vendor_id = ids as u16;
+    if vendor_id == 0xffff {
+        return None;
+    }
+    let class = read_config(bus, dev, func, REG_CLASS);
+    let header_type = (read_config(bus, dev, func, REG_HEADER_TYPE) >> 16) as u8;
+    // General devices have six BARs, PCI-to-PCI bridges two.
+    let bar_count = match header_type & !MULTI_FUNCTION {
+        0x00 => 6,
+        0x01 => 2,
+        _ => 0,
+    };
+    let irq_line = read_config(bus, dev, func, REG_INTERRUPT) as u8;
+    Some(PciDevice {
+        bus,
+        dev,
+        func,
+        vendor_id,
+        device_id: (ids >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        bars: read_bars(bus, dev, func, bar_count),
+        irq_line: (irq_line != 0xff && irq_line != 0).then_some(irq_line),
+    })
+}
+
+/// Scan every bus, device and function.
+fn scan() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for dev in 0..32 {
+            let Some(first) = probe_function(bus, dev, 0) else {
+                continue;
+            };
+            devices.push(first);
+            let header_type = (first.read(REG_HEADER_TYPE) >> 16) as u8;
+            if header_type & MULTI_FUNCTION != 0 {
+                devices.extend((1..8).filter_map(|func| probe_function(bus, dev, func)));
+            }
+        }
+    }
+    devices
+}
+
+static DEVICES: OnceCell<Vec<PciDevice>> = OnceCell::uninit();
+
+/// Scan the PCI buses and print a line per device found.
+///
+/// Needs the heap. Only the first call scans.
+pub fn init() {
+    let mut fresh = false;
+    let devices = DEVICES.get_or_init(|| {
+        fresh = true;
+        scan()
+    });
+    if fresh && crate::klog::level() > 0 {
+        for device in devices {
+            println!("pci {}", device);
+        }
+    }
+}
+
+/// Iterate over the devices found by [`init`].
+pub fn devices() -> impl Iterator<Item = &'static PciDevice> {
+    DEVICES.get().into_iter().flatten()
+}
+
+/// Return the first device with the given class and subclass.
+pub fn find(class: u8, subclass: u8) -> Option<&'static PciDevice> {
+    devices().find(|device| device.class == class && device.subclass == subclass)
+}
+
+#[test_case]
+fn test_config_address() {
+    assert_eq!(config_address(0, 0, 0, 0), 0x8000_0000);
+    assert_eq!(config_address(1, 2, 3, 0x3e), 0x8001_133c);
+}
+
+#[test_case]
+fn test_finds_qemu_devices() {
+    // QEMU's default pc machine: i440FX host bridge, PIIX3 IDE, e1000 NIC.
+    let host = find(0x06, 0x00).expect("no host bridge");
+    assert_eq!((host.bus, host.dev, host.func), (0, 0, 0));
+    assert_eq!(host.vendor_id, 0x8086);
+
+    let ide = find(0x01, 0x01).expect("no ide controller");
+    assert_eq!(ide.vendor_id, 0x8086);
+    // The IDE controller's bus-master registers are in I/O space.
+    assert!(matches!(ide.bars[4], Bar::Io { size: 16, .. }));
+
+    let nic = find(0x02, 0x00).expect("no nic");
+    assert_ne!(nic.vendor_id, 0xffff);
+    assert!(matches!(nic.bars[0], Bar::Memory { size, .. } if size > 0));
+
+    // Multi-function devices show up as several functions.
+    assert!(devices().any(|device| device.func > 0));
+}
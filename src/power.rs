@@ -0,0 +1,200 @@
+//! Powering the machine off and resetting it.
+//!
+//! [`shutdown`] first writes the S5 sleep type to the PM1a control port the
+//! FADT names (see [`acpi`](crate::acpi)). Without ACPI tables, or if that
+//! has no effect, it tries a fixed list of [`ShutdownMethod`]s known to work
+//! under QEMU and Bochs, in order, until one of them takes effect.
+//!
+//! [`reboot`] pulses the reset line through the 8042 keyboard controller and
+//! falls back to a triple fault, which resets any x86 machine.
+//!
+//! Both first [`quiesce`] the subsystems that registered with
+//! [`on_shutdown`], so buffered output reaches the host and the disk isn't
+//! left mid-command.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+use x86_64::instructions::{hlt, interrupts, port::Port};
+use x86_64::VirtAddr;
+
+use crate::arch::port::PortGroup;
+use crate::task::keyboard::Ps2Ports;
+use crate::{serial_println, println, QemuExitCode};
+
+pub mod quiesce;
+
+pub use quiesce::{on_shutdown, quiesce, DEFAULT_TIMEOUT};
+
+/// One way of turning the machine off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMethod {
+    /// Write `value` (S5 sleep type plus SLP_EN) to an ACPI PM1a control
+    /// port.
+    Pm1aControl { port: u16, value: u16 },
+    /// Exit through QEMU's `isa-debug-exit` device with a success code.
+    ///
+    /// Skipped unless [`set_debug_exit_configured`] says the device exists,
+    /// since port `0xf4` may belong to something else on real hardware.
+    IsaDebugExit,
+}
+
+/// The fixed ACPI ports [`SHUTDOWN_METHODS`] starts with.
+pub const ACPI_METHODS: [ShutdownMethod; 2] = [
+    // QEMU's q35 and pc machines.
+    ShutdownMethod::Pm1aControl { port: 0x604, value: 0x2000 },
+    // Bochs and older QEMU versions.
+    ShutdownMethod::Pm1aControl { port: 0xb004, value: 0x2000 },
+];
+
+/// The methods [`shutdown`] tries, in order.
+pub const SHUTDOWN_METHODS: [ShutdownMethod; 3] =
+    [ACPI_METHODS[0], ACPI_METHODS[1], ShutdownMethod::IsaDebugExit];
+
+/// Whether QEMU was started with the `isa-debug-exit` device.
+static DEBUG_EXIT_CONFIGURED: AtomicBool = AtomicBool::new(false);
+
+/// Record whether the `isa-debug-exit` device is present.
+///
+/// The kernel can't probe for it, so whoever knows how QEMU was launched
+/// (the test entry point) has to say so.
+pub fn set_debug_exit_configured(configured: bool) {
+    DEBUG_EXIT_CONFIGURED.store(configured, Ordering::Relaxed);
+}
+
+impl ShutdownMethod {
+    /// Short name used in log messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShutdownMethod::Pm1aControl { .. } => "acpi-pm1a",
+            ShutdownMethod::IsaDebugExit => "isa-debug-exit",
+        }
+    }
+
+    /// Try to power off this way.
+    ///
+    /// Returns (with the machine still running) if the method doesn't work
+    /// here or was skipped.
+    pub fn attempt(&self) {
+        match *self {
+            ShutdownMethod::Pm1aControl { port, value } => unsafe {
+                Port::<u16>::new(port).write(value);
+            },
+            ShutdownMethod::IsaDebugExit => {
+                if DEBUG_EXIT_CONFIGURED.load(Ordering::Relaxed) {
+                    crate::exit_qemu_sized(QemuExitCode::Success, crate::debug_exit().size);
+                }
+            }
+        }
+    }
+}
+
+/// Return the shutdown method the FADT describes, if the tables were found
+/// and the DSDT defines `\_S5`.
+pub fn acpi_method() -> Option<ShutdownMethod> {
+    let fadt = crate::acpi::fadt()?;
+    let value = fadt.s5_control_value()?;
+    Some(ShutdownMethod::Pm1aControl { port: fadt.pm1a_control, value })
+}
+
+/// Power the machine off: [`quiesce`], then the FADT's way if there is
+/// one, then each of [`SHUTDOWN_METHODS`] in turn.
+pub fn shutdown() -> ! {
+    quiesce();
+    attempt_acpi_method();
+    shutdown_with(&SHUTDOWN_METHODS)
+}
+
+/// Power the machine off like [`shutdown`], but never through
+/// `isa-debug-exit`.
+///
+/// [`exit_qemu`](crate::exit_qemu) falls back to this when that device
+/// doesn't respond.
+pub fn power_off() -> ! {
+    attempt_acpi_method();
+    shutdown_with(&ACPI_METHODS)
+}
+
+/// Try the FADT's shutdown method, if there is one.
+fn attempt_acpi_method() {
+    if let Some(method) = acpi_method() {
+        interrupts::disable();
+        method.attempt();
+        serial_println!("power: {} from the fadt had no effect", method.name());
+    }
+}
+
+/// Power the machine off, trying each of `methods` in turn.
+///
+/// If none of them works, prints "shutdown not supported" and halts with
+/// interrupts disabled.
+pub fn shutdown_with(methods: &[ShutdownMethod]) -> ! {
+    interrupts::disable();
+    for method in methods {
+        method.attempt();
+        serial_println!("power: {} had no effect", method.name());
+    }
+    serial_println!("shutdown not supported");
+    println!("shutdown not supported");
+    loop {
+        hlt();
+    }
+}
+
+/// Status bit set while the controller's input buffer is full.
+const KBC_INPUT_FULL: u8 = 1 << 1;
+/// Command that pulses the CPU reset line.
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+/// Reset the machine.
+///
+/// The subsystems are [quiesced](quiesce), then interrupts are disabled
+/// and serial output is flushed, so the last log lines reach the host.
+/// Then the keyboard controller is asked to pulse the reset line; if the
+/// machine is still running after a short wait, it is reset with a triple
+/// fault.
+pub fn reboot() -> ! {
+    quiesce();
+    interrupts::disable();
+    crate::serial::flush();
+    reset_via_keyboard_controller();
+    // Give the controller time to act before falling back.
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+    triple_fault();
+}
+
+/// Ask the 8042 to pulse the reset line.
+///
+/// Waits a bounded time for the controller's input buffer to empty, and
+/// sends the command anyway if it never does.
+fn reset_via_keyboard_controller() {
+    let port = Ps2Ports::standard().status_cmd;
+    unsafe {
+        for _ in 0..100_000 {
+            if port.read() & KBC_INPUT_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        port.write(KBC_PULSE_RESET);
+    }
+}
+
+/// Reset the machine with a triple fault.
+///
+/// Loads an empty IDT and raises a breakpoint: delivering it faults, so
+/// does delivering that fault as a double fault, and the third fault resets
+/// the CPU.
+pub fn triple_fault() -> ! {
+    interrupts::disable();
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        lidt(&empty);
+        asm!("int3", options(noreturn));
+    }
+}
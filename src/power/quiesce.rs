@@ -0,0 +1,214 @@
+//! Quiescing subsystems before the power goes.
+//!
+//! Subsystems register a callback with [`on_shutdown`] when they start,
+//! such as flushing COM1 or letting the disk finish its command.
+//! [`shutdown`](super::shutdown) and [`reboot`](super::reboot) call
+//! [`quiesce`], which runs the callbacks newest first, so a subsystem is
+//! quiesced before the ones it was built on. Each step is traced on the
+//! console.
+//!
+//! A callback gets its timeout only while the timer can preempt: it then
+//! runs on a kernel thread of its own (see [`crate::thread`]), and if it is
+//! still running when the timeout runs out it is left behind and the next
+//! one starts. Once interrupts are off or the IRQs are masked, callbacks
+//! run inline and one that overruns is only reported.
+//!
+//! The registry is a [`FixedVec`], so registering works before the heap.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::collections::FixedVec;
+use crate::thread::{self, Thread};
+use crate::time;
+use crate::{println, serial_println};
+
+/// Most callbacks that can be registered.
+pub const MAX_HOOKS: usize = 16;
+
+/// Timeout for callbacks that don't need longer.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Stack of the thread a callback runs on.
+const HOOK_STACK_SIZE: usize = 4096 * 4;
+
+/// A registered shutdown callback.
+#[derive(Debug, Clone, Copy)]
+pub struct Hook {
+    /// Name shown in the trace.
+    pub name: &'static str,
+    pub callback: fn(),
+    pub timeout: Duration,
+}
+
+/// How a callback ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// It returned after this long.
+    Done(Duration),
+    /// It was still running when its timeout ran out, and was left behind.
+    TimedOut,
+    /// It ran inline and returned after this long, past its timeout.
+    Overran(Duration),
+}
+
+/// Callbacks in registration order.
+pub struct Hooks {
+    hooks: FixedVec<Hook, MAX_HOOKS>,
+}
+
+impl Hooks {
+    /// Create an empty set.
+    pub const fn new() -> Self {
+        Hooks { hooks: FixedVec::new() }
+    }
+
+    /// Add `hook`. Returns `false` if [`MAX_HOOKS`] are already registered.
+    pub fn register(&mut self, hook: Hook) -> bool {
+        self.hooks.push(hook).is_ok()
+    }
+
+    /// Run the callbacks newest first and pass each one's outcome to
+    /// `report`.
+    pub fn run(&self, mut report: impl FnMut(&Hook, Outcome)) {
+        for hook in self.hooks.iter().rev() {
+            report(hook, run_hook(hook));
+        }
+    }
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Done(elapsed) => write!(f, "done in {} ms", elapsed.as_millis()),
+            Outcome::TimedOut => f.write_str("timed out, skipped"),
+            Outcome::Overran(elapsed) => {
+                write!(f, "done in {} ms, over its timeout", elapsed.as_millis())
+            }
+        }
+    }
+}
+
+static HOOKS: Mutex<Hooks> = Mutex::new(Hooks::new());
+
+/// Set once [`quiesce`] has started.
+static QUIESCED: AtomicBool = AtomicBool::new(false);
+
+/// Have [`quiesce`] call `callback`, giving it `timeout` to return.
+///
+/// Returns `false` if [`MAX_HOOKS`] are already registered.
+pub fn on_shutdown(name: &'static str, callback: fn(), timeout: Duration) -> bool {
+    interrupts::without_interrupts(|| HOOKS.lock().register(Hook { name, callback, timeout }))
+}
+
+/// Run the registered callbacks, newest first, tracing each step.
+///
+/// Only the first call runs them; later calls return at once, so a
+/// shutdown that falls back to another path doesn't quiesce twice.
+pub fn quiesce() {
+    if QUIESCED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    // Copied out, so a callback that registers another doesn't deadlock.
+    let mut hooks = Hooks::new();
+    interrupts::without_interrupts(|| {
+        for &hook in HOOKS.lock().hooks.iter() {
+            hooks.register(hook);
+        }
+    });
+    hooks.run(|hook, outcome| trace(format_args!("power: {}: {}", hook.name, outcome)));
+}
+
+/// Print a trace line on the console, and on COM1 if the console doesn't
+/// already go there.
+fn trace(args: fmt::Arguments) {
+    println!("{}", args);
+    if !crate::console::selected().has_serial() {
+        serial_println!("{}", args);
+    }
+}
+
+/// Return whether a callback that never returns would still be preempted.
+fn can_preempt() -> bool {
+    interrupts::are_enabled()
+        && time::pit_configured()
+        && !crate::interrupts::irqs_masked()
+        && crate::allocator::heap_stats().size > 0
+}
+
+/// Run `hook`, on a thread of its own if it can be timed out.
+fn run_hook(hook: &Hook) -> Outcome {
+    let start = time::monotonic();
+    if !can_preempt() {
+        (hook.callback)();
+        let elapsed = time::monotonic() - start;
+        return if elapsed > hook.timeout {
+            Outcome::Overran(elapsed)
+        } else {
+            Outcome::Done(elapsed)
+        };
+    }
+    let deadline = crate::interrupts::ticks() + time::duration_to_ticks(hook.timeout);
+    let id = Thread::spawn(hook.callback, HOOK_STACK_SIZE);
+    while thread::is_alive(id) {
+        if crate::interrupts::ticks() >= deadline {
+            return Outcome::TimedOut;
+        }
+        thread::yield_now();
+    }
+    Outcome::Done(time::monotonic() - start)
+}
+
+#[test_case]
+fn test_hooks_run_newest_first_and_hung_ones_are_skipped() {
+    static ORDER: Mutex<FixedVec<&str, 4>> = Mutex::new(FixedVec::new());
+    static RELEASE: AtomicBool = AtomicBool::new(false);
+    static HANGING: AtomicBool = AtomicBool::new(false);
+
+    fn first() {
+        let _ = ORDER.lock().push("first");
+    }
+    fn second() {
+        let _ = ORDER.lock().push("second");
+    }
+    fn hang() {
+        HANGING.store(true, Ordering::Release);
+        let _ = ORDER.lock().push("hang");
+        while !RELEASE.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        HANGING.store(false, Ordering::Release);
+    }
+
+    let mut hooks = Hooks::new();
+    assert!(hooks.register(Hook { name: "first", callback: first, timeout: DEFAULT_TIMEOUT }));
+    let short = Duration::from_millis(50);
+    assert!(hooks.register(Hook { name: "hang", callback: hang, timeout: short }));
+    assert!(hooks.register(Hook { name: "second", callback: second, timeout: DEFAULT_TIMEOUT }));
+
+    let mut outcomes: FixedVec<(&str, Outcome), 4> = FixedVec::new();
+    hooks.run(|hook, outcome| {
+        let _ = outcomes.push((hook.name, outcome));
+    });
+
+    assert_eq!(&ORDER.lock()[..], ["second", "hang", "first"]);
+    assert!(outcomes.iter().map(|&(name, _)| name).eq(["second", "hang", "first"]));
+    assert_eq!(outcomes[1].1, Outcome::TimedOut);
+    assert!(matches!(outcomes[0].1, Outcome::Done(_)));
+    assert!(matches!(outcomes[2].1, Outcome::Done(_)));
+
+    // Let the abandoned thread finish, so it doesn't outlive the test.
+    RELEASE.store(true, Ordering::Release);
+    while HANGING.load(Ordering::Acquire) {
+        thread::yield_now();
+    }
+}
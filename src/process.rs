@@ -0,0 +1,381 @@
+//! User processes.
+//!
+//! [`run`] runs a program that [`loader::elf`](crate::loader::elf) loaded
+//! into an [`AddressSpace`] in ring 3, and returns once it exits or is
+//! killed. The calling thread waits in the kernel meanwhile: its registers
+//! stay saved on its own stack, and the exit path switches straight back to
+//! them (see [`enter_user`] and [`resume_kernel`]). The process enters the
+//! kernel on a stack of its own, which the scheduler keeps in the TSS while
+//! the thread is switched out (see [`thread::set_entry_stack`]). Threads
+//! that run while the process is preempted run on its page tables, whose
+//! kernel half is the kernel's own.
+//!
+//! Only one process runs at a time.
+//!
+//! # System calls
+//!
+//! Programs make system calls with `int 0x80`, with Linux's x86_64 numbers
+//! and registers: the number in `rax`, arguments in `rdi`, `rsi` and `rdx`,
+//! and the result, or a negated errno, back in `rax`. Other registers are
+//! preserved. There are two calls:
+//!
+//! - `write(fd, buf, len)` (1) prints `buf` on the console for fd 1 or 2
+//!   and returns `len`
+//! - `exit(status)` (60) ends the process
+//!
+//! Page faults and general protection faults in user mode [`kill`] the
+//! process instead of stopping the kernel.
+
+use alloc::vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use crate::arch::cr::{Cr3, Cr3Flags};
+use crate::gdt;
+use crate::loader::elf::LoadedImage;
+use crate::memory::address_space::is_user_range;
+use crate::memory::AddressSpace;
+use crate::sync::IrqMutex;
+use crate::thread;
+use crate::{print, println};
+
+/// Interrupt vector for system calls.
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// Size of the stack a process enters the kernel on.
+const KERNEL_STACK_SIZE: usize = 4096 * 4;
+
+/// System call numbers.
+const SYS_WRITE: u64 = 1;
+const SYS_EXIT: u64 = 60;
+
+/// Errors system calls return, negated.
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const ENOSYS: i64 = 38;
+
+/// How a process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The program called `exit` with this status.
+    Exited(i32),
+    /// The program was killed for a fault.
+    Killed(Fault),
+}
+
+/// A fault that killed a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// A page fault at the address.
+    PageFault(u64),
+    /// A general protection fault, with its error code.
+    GeneralProtection(u64),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::PageFault(addr) => write!(f, "page fault at {:#x}", addr),
+            Fault::GeneralProtection(code) => {
+                write!(f, "general protection fault (error code {:#x})", code)
+            }
+        }
+    }
+}
+
+/// Why a process couldn't run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    /// Another process is running.
+    Busy,
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::Busy => f.write_str("another process is running"),
+        }
+    }
+}
+
+/// The running process.
+struct Process {
+    space: AddressSpace,
+    status: Option<ExitStatus>,
+}
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Only locked with interrupts disabled and never while the process runs,
+/// so system calls and fault handlers always find it free.
+static CURRENT: IrqMutex<Option<Process>> = IrqMutex::named("process", None);
+
+/// Stack pointer [`enter_user`] left the kernel at.
+static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+
+/// Run `image`, loaded into `space`, until it exits or is killed.
+///
+/// Fails without running it if another process is running.
+pub fn run(image: LoadedImage, space: AddressSpace) -> Result<ExitStatus, ProcessError> {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return Err(ProcessError::Busy);
+    }
+    let kernel_stack = vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice();
+    let entry_stack = VirtAddr::from_ptr(kernel_stack.as_ptr()) + KERNEL_STACK_SIZE as u64;
+    let level_4 = space.level_4_frame();
+    *CURRENT.lock() = Some(Process { space, status: None });
+    let (code, data) = gdt::user_selectors();
+
+    let were_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    thread::set_entry_stack(Some(entry_stack.align_down(16u64)));
+    let (kernel_tables, flags) = Cr3::read();
+    // SAFETY: the address space shares the kernel's mappings, and the user
+    // stack and entry point were mapped by the loader.
+    unsafe {
+        Cr3::write(level_4, Cr3Flags::empty());
+        enter_user(
+            image.entry.as_u64(),
+            image.stack_top.as_u64(),
+            code.0.into(),
+            data.0.into(),
+        );
+        Cr3::write(kernel_tables, flags);
+    }
+    thread::set_entry_stack(None);
+    let process = CURRENT.lock().take().expect("process missing after exit");
+    if were_enabled {
+        interrupts::enable();
+    }
+    RUNNING.store(false, Ordering::Release);
+    Ok(process.status.expect("process ended without a status"))
+}
+
+/// Kill the running process for `fault`, which it raised in user mode.
+///
+/// Called by the exception handlers.
+pub(crate) fn kill(fault: Fault) -> ! {
+    println!("user process fault: {}", fault);
+    finish(ExitStatus::Killed(fault))
+}
+
+/// End the running process with `status` and go back to [`run`].
+fn finish(status: ExitStatus) -> ! {
+    with_process(|process| process.status = Some(status));
+    // SAFETY: a process is running, so `run` is waiting in `enter_user`.
+    unsafe { resume_kernel() }
+}
+
+fn with_process<R>(f: impl FnOnce(&mut Process) -> R) -> R {
+    let mut current = CURRENT.try_lock().expect("process table locked in user mode");
+    f(current.as_mut().expect("no process running"))
+}
+
+/// Save the callee-saved registers and the stack pointer in
+/// [`KERNEL_RSP`], then `iretq` to `rip` in ring 3 on `rsp`, with
+/// interrupts enabled and every other register cleared.
+///
+/// Returns when [`resume_kernel`] switches back.
+#[unsafe(naked)]
+unsafe extern "C" fn enter_user(rip: u64, rsp: u64, code_selector: u64, data_selector: u64) {
+    core::arch::naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rip + {saved}], rsp",
+        // The interrupt return frame: SS, RSP, RFLAGS (just IF), CS, RIP.
+        "push rcx",
+        "push rsi",
+        "push 0x202",
+        "push rdx",
+        "push rdi",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+        saved = sym KERNEL_RSP,
+    )
+}
+
+/// Return from [`enter_user`] into [`run`], abandoning the current stack.
+#[unsafe(naked)]
+unsafe extern "C" fn resume_kernel() -> ! {
+    core::arch::naked_asm!(
+        "mov rsp, [rip + {saved}]",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        saved = sym KERNEL_RSP,
+    )
+}
+
+/// Address of the system call entry, for the IDT.
+pub(crate) fn syscall_entry_addr() -> VirtAddr {
+    VirtAddr::new(syscall_entry as unsafe extern "C" fn() as usize as u64)
+}
+
+/// The system call registers, as [`syscall_entry`] saves them.
+#[repr(C)]
+struct SyscallFrame {
+    rax: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+}
+
+/// Entry for `int 0x80`: save the registers the ABI lets
+/// [`syscall_dispatch`] clobber, call it, and return to the program.
+///
+/// The CPU's 5-word frame and the 9 saved registers keep the stack 16-byte
+/// aligned at the call.
+#[unsafe(naked)]
+unsafe extern "C" fn syscall_entry() {
+    core::arch::naked_asm!(
+        "push rcx",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rax",
+        "mov rdi, rsp",
+        "cld",
+        "call {dispatch}",
+        "pop rax",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rcx",
+        "iretq",
+        dispatch = sym syscall_dispatch,
+    )
+}
+
+/// Run the system call in `frame` and put its result in `rax`.
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let result = match frame.rax {
+        SYS_WRITE => write(frame.rdi, frame.rsi, frame.rdx),
+        SYS_EXIT => finish(ExitStatus::Exited(frame.rdi as i32)),
+        _ => -ENOSYS,
+    };
+    frame.rax = result as u64;
+}
+
+/// `write(fd, buf, len)`: print `len` bytes at `buf` on the console.
+///
+/// Bytes that aren't UTF-8 print as U+FFFD. Fails with `EFAULT`, having
+/// printed what came before, if part of the buffer isn't mapped.
+fn write(fd: u64, buf: u64, len: u64) -> i64 {
+    if fd != 1 && fd != 2 {
+        return -EBADF;
+    }
+    let Some(end) = buf.checked_add(len).filter(|&end| is_user_range(buf, end)) else {
+        return -EFAULT;
+    };
+    with_process(|process| {
+        let mut chunk = [0u8; 128];
+        let mut addr = buf;
+        while addr < end {
+            let chunk = &mut chunk[..(end - addr).min(128) as usize];
+            if process.space.read(VirtAddr::new(addr), chunk).is_err() {
+                return -EFAULT;
+            }
+            for piece in chunk.utf8_chunks() {
+                print!("{}", piece.valid());
+                if !piece.invalid().is_empty() {
+                    print!("\u{fffd}");
+                }
+            }
+            addr += chunk.len() as u64;
+        }
+        len as i64
+    })
+}
+
+/// Load `code` as a program into a new address space.
+#[cfg(test)]
+fn load_code(code: &[u8]) -> (LoadedImage, AddressSpace) {
+    use crate::loader::elf;
+    use crate::memory::FRAME_ALLOCATOR;
+
+    let mut frames = FRAME_ALLOCATOR.get().lock();
+    let mut space = AddressSpace::new(&mut *frames).unwrap();
+    let image = elf::load(&elf::test_executable(code), &mut space, &mut *frames).unwrap();
+    (image, space)
+}
+
+#[cfg(test)]
+fn run_code(code: &[u8]) -> ExitStatus {
+    let (image, space) = load_code(code);
+    run(image, space).unwrap()
+}
+
+/// `mov edi, eax; mov eax, 60; int 0x80`: exit with the last result.
+#[cfg(test)]
+const EXIT_WITH_RAX: [u8; 9] = [0x89, 0xc7, 0xb8, 0x3c, 0, 0, 0, 0xcd, 0x80];
+
+#[test_case]
+fn test_user_faults_kill_the_process() {
+    use crate::loader::elf::TEST_TEXT;
+
+    // `lea rax, [rip]; mov [rax], al`: the text is read-only.
+    let store = [0x48, 0x8d, 0x05, 0, 0, 0, 0, 0x88, 0x00];
+    assert_eq!(run_code(&store), ExitStatus::Killed(Fault::PageFault(TEST_TEXT + 7)));
+    // `hlt` is privileged.
+    assert_eq!(run_code(&[0xf4]), ExitStatus::Killed(Fault::GeneralProtection(0)));
+    // The kernel carries on, and can run another process.
+    assert_eq!(run_code(&EXIT_WITH_RAX), ExitStatus::Exited(0));
+}
+
+#[test_case]
+fn test_syscalls_check_arguments() {
+    static KERNEL_DATA: [u8; 4] = *b"data";
+
+    // write(1, KERNEL_DATA, 4)
+    let mut code = alloc::vec![0xb8, 1, 0, 0, 0, 0xbf, 1, 0, 0, 0, 0x48, 0xbe];
+    code.extend_from_slice(&(&raw const KERNEL_DATA as u64).to_le_bytes());
+    code.extend_from_slice(&[0xba, 4, 0, 0, 0, 0xcd, 0x80]);
+    code.extend_from_slice(&EXIT_WITH_RAX);
+    assert_eq!(run_code(&code), ExitStatus::Exited(-EFAULT as i32));
+
+    // Call 1000, which doesn't exist.
+    let mut code = alloc::vec![0xb8, 0xe8, 0x03, 0, 0, 0xcd, 0x80];
+    code.extend_from_slice(&EXIT_WITH_RAX);
+    assert_eq!(run_code(&code), ExitStatus::Exited(-ENOSYS as i32));
+}
+
+#[test_case]
+fn test_only_one_process_runs() {
+    let (image, space) = load_code(&EXIT_WITH_RAX);
+    RUNNING.store(true, Ordering::Relaxed);
+    let result = run(image, space);
+    RUNNING.store(false, Ordering::Relaxed);
+    assert_eq!(result, Err(ProcessError::Busy));
+}
@@ -0,0 +1,282 @@
+//! Sampling profiler driven by the timer interrupt.
+//!
+//! While the profiler runs, every timer tick records the interrupted
+//! instruction pointer in a fixed hash table of 16-byte address buckets.
+//! [`report`] prints the hottest buckets with the functions they fall in
+//! (see [`symbols`](crate::symbols)), which at [`TIMER_HZ`] ticks shows
+//! where boot time or a slow command goes. Samples taken while the CPU sat
+//! in `hlt` are counted as idle instead of being bucketed.
+//!
+//! Only the bootstrap CPU takes the timer interrupt, so the table has a
+//! single writer and recording is a few plain loads and stores, with no
+//! lock. Readers copy the table with interrupts off, which keeps the
+//! writer out. A sample that finds no free slot within a few probes is
+//! counted as dropped.
+//!
+//! [`TIMER_HZ`]: crate::time::TIMER_HZ
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::symbols::Symbol;
+
+/// Granularity of the address buckets, in bytes.
+pub const BUCKET_SIZE: u64 = 16;
+
+/// Number of buckets the table holds; a power of two.
+pub const TABLE_SIZE: usize = 4096;
+
+/// Slots tried before a sample is dropped.
+const MAX_PROBES: usize = 16;
+
+/// Opcode of `hlt`.
+const HLT: u8 = 0xf4;
+
+/// One bucket: its start address shifted down (0 while free) and its count.
+struct Slot {
+    key: AtomicU64,
+    count: AtomicU64,
+}
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static SLOTS: [Slot; TABLE_SIZE] =
+    [const { Slot { key: AtomicU64::new(0), count: AtomicU64::new(0) } }; TABLE_SIZE];
+static SAMPLES: AtomicU64 = AtomicU64::new(0);
+static IDLE: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Clear the table and start sampling. Does nothing if already running.
+pub fn start() {
+    if running() {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        for slot in &SLOTS {
+            slot.key.store(0, Ordering::Relaxed);
+            slot.count.store(0, Ordering::Relaxed);
+        }
+        SAMPLES.store(0, Ordering::Relaxed);
+        IDLE.store(0, Ordering::Relaxed);
+        DROPPED.store(0, Ordering::Relaxed);
+        RUNNING.store(true, Ordering::Relaxed);
+    });
+}
+
+/// Stop sampling, keeping what was recorded for [`report`].
+pub fn stop() {
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+/// Return whether the profiler is sampling.
+pub fn running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Profiles until dropped; see [`scope`].
+#[must_use = "profiling stops when the scope is dropped"]
+pub struct Scope {
+    started: bool,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        if self.started {
+            stop();
+        }
+    }
+}
+
+/// Start the profiler for as long as the returned guard lives, to profile
+/// one phase. If the profiler is already running it is left alone, and
+/// keeps running after the guard is dropped.
+pub fn scope() -> Scope {
+    let started = !running();
+    start();
+    Scope { started }
+}
+
+/// Record a timer tick that interrupted `frame`. Called by the timer
+/// interrupt handler only.
+pub(crate) fn sample(frame: &InterruptStackFrame) {
+    if !running() {
+        return;
+    }
+    // Single writer: plain loads and stores are enough.
+    add(&SAMPLES, 1);
+    let rip = frame.instruction_pointer.as_u64();
+    if interrupted_hlt(frame) {
+        add(&IDLE, 1);
+        return;
+    }
+    let key = rip / BUCKET_SIZE;
+    // Bucket 0 would look like a free slot.
+    if key == 0 {
+        add(&DROPPED, 1);
+        return;
+    }
+    let mut index = hash(key);
+    for _ in 0..MAX_PROBES {
+        let slot = &SLOTS[index];
+        match slot.key.load(Ordering::Relaxed) {
+            0 => {
+                slot.key.store(key, Ordering::Relaxed);
+                slot.count.store(1, Ordering::Relaxed);
+                return;
+            }
+            k if k == key => {
+                add(&slot.count, 1);
+                return;
+            }
+            _ => index = (index + 1) % TABLE_SIZE,
+        }
+    }
+    add(&DROPPED, 1);
+}
+
+fn add(counter: &AtomicU64, n: u64) {
+    counter.store(counter.load(Ordering::Relaxed) + n, Ordering::Relaxed);
+}
+
+fn hash(key: u64) -> usize {
+    (key.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - TABLE_SIZE.trailing_zeros())) as usize
+}
+
+/// Return whether the tick woke the CPU from `hlt`: the saved instruction
+/// pointer is then just past the `hlt`.
+fn interrupted_hlt(frame: &InterruptStackFrame) -> bool {
+    let rip = frame.instruction_pointer.as_u64();
+    // User code can't halt, and the byte before the first one of a page may
+    // not be mapped.
+    if crate::interrupts::from_user_mode(frame) || rip.is_multiple_of(4096) {
+        return false;
+    }
+    // SAFETY: the code at `rip` runs in this address space, and the byte
+    // before it is on the same page.
+    unsafe { ((rip - 1) as *const u8).read_volatile() == HLT }
+}
+
+/// The recorded samples, as copied by [`snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    /// Ticks sampled, idle ones included.
+    pub samples: u64,
+    /// Ticks that found the CPU halted.
+    pub idle: u64,
+    /// Samples lost to a full table.
+    pub dropped: u64,
+    /// Bucket start addresses and their counts, hottest first.
+    pub buckets: Vec<(u64, u64)>,
+}
+
+/// Copy the recorded samples. The profiler may keep running.
+pub fn snapshot() -> Profile {
+    let mut buckets = Vec::with_capacity(TABLE_SIZE);
+    let (samples, idle, dropped) = interrupts::without_interrupts(|| {
+        for slot in &SLOTS {
+            let key = slot.key.load(Ordering::Relaxed);
+            if key != 0 {
+                buckets.push((key * BUCKET_SIZE, slot.count.load(Ordering::Relaxed)));
+            }
+        }
+        (
+            SAMPLES.load(Ordering::Relaxed),
+            IDLE.load(Ordering::Relaxed),
+            DROPPED.load(Ordering::Relaxed),
+        )
+    });
+    buckets.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    Profile { samples, idle, dropped, buckets }
+}
+
+/// Print the `top_n` hottest buckets and the sample totals.
+pub fn report(top_n: usize, out: &mut dyn fmt::Write) -> fmt::Result {
+    let profile = snapshot();
+    writeln!(
+        out,
+        "{} samples, {} idle, {} dropped{}",
+        profile.samples,
+        profile.idle,
+        profile.dropped,
+        if running() { " (running)" } else { "" }
+    )?;
+    let busy = profile.samples - profile.idle;
+    for &(addr, count) in profile.buckets.iter().take(top_n) {
+        let percent = count * 1000 / busy.max(1);
+        writeln!(
+            out,
+            "{:>7} {:>3}.{}% {:#018x} {}",
+            count,
+            percent / 10,
+            percent % 10,
+            addr,
+            Symbol(addr)
+        )?;
+    }
+    Ok(())
+}
+
+/// Spin for `ticks` timer ticks, almost all of the time in an `asm!` loop
+/// so samples land in this function.
+#[cfg(test)]
+#[inline(never)]
+fn busy_loop(ticks: u64) {
+    let deadline = crate::interrupts::ticks() + ticks;
+    while crate::interrupts::ticks() < deadline {
+        unsafe {
+            core::arch::asm!(
+                "2:",
+                "dec {n}",
+                "jnz 2b",
+                n = inout(reg) 100_000u64 => _,
+                options(nomem, nostack),
+            );
+        }
+    }
+}
+
+#[test_case]
+fn test_busy_function_dominates() {
+    let busy = busy_loop as fn(u64) as usize as u64;
+    let (name, _) = crate::symbols::resolve(busy).expect("busy_loop not in the symbol table");
+    {
+        let _scope = scope();
+        assert!(running());
+        busy_loop(20);
+    }
+    assert!(!running());
+
+    let profile = snapshot();
+    assert!(profile.samples >= 15, "{:?}", profile);
+    let in_busy: u64 = profile
+        .buckets
+        .iter()
+        .filter(|&&(addr, _)| crate::symbols::resolve(addr).is_some_and(|(n, _)| n == name))
+        .map(|&(_, count)| count)
+        .sum();
+    let not_idle = profile.samples - profile.idle;
+    assert!(in_busy * 2 > not_idle, "{} of {}: {:?}", in_busy, not_idle, profile);
+
+    let mut out = alloc::string::String::new();
+    report(3, &mut out).unwrap();
+    let mut lines = out.lines();
+    let totals = alloc::format!("{} samples, {} idle", profile.samples, profile.idle);
+    assert!(lines.next().is_some_and(|line| line.starts_with(&totals)), "{}", out);
+    assert!(lines.next().is_some_and(|line| line.contains(name)), "{}", out);
+}
+
+#[test_case]
+fn test_idle_ticks_are_not_bucketed() {
+    start();
+    let deadline = crate::interrupts::ticks() + 5;
+    while crate::interrupts::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+    stop();
+    let profile = snapshot();
+    assert!(profile.idle >= 3, "{:?}", profile);
+    let bucketed: u64 = profile.buckets.iter().map(|&(_, count)| count).sum();
+    assert_eq!(bucketed + profile.idle + profile.dropped, profile.samples);
+}
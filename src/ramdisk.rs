@@ -0,0 +1,134 @@
+//! The boot ramdisk: an archive of files available without a disk driver.
+//!
+//! bootloader 0.9 can't load extra modules, so `build.rs` packs the
+//! `initrd/` directory (or the file named by `CHRONOS_INITRD`) into an image
+//! that is linked into the kernel, page-aligned. [`init`] then treats it like
+//! a loaded ramdisk: it finds the physical frames holding the image, reserves
+//! them so the frame allocator never hands them out, and maps them read-only
+//! at [`RAMDISK_START`]. [`data`] returns that mapping.
+
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{self, MemError, ReserveError, ReservedKind};
+
+/// Where the ramdisk is mapped.
+pub const RAMDISK_START: u64 = 0x_5555_5555_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[repr(C, align(4096))]
+struct PageAligned<B: ?Sized> {
+    bytes: B,
+}
+
+static IMAGE: &PageAligned<[u8]> = &PageAligned {
+    bytes: *include_bytes!(concat!(env!("OUT_DIR"), "/initrd.img")),
+};
+
+/// Checksum of the image, as computed by `build.rs`, in hex.
+pub const BUILD_CHECKSUM: &str = env!("CHRONOS_INITRD_CHECKSUM");
+
+/// Why [`init`] failed.
+#[derive(Debug)]
+pub enum RamdiskError {
+    /// A page of the image isn't mapped in the kernel's address space.
+    NotMapped(VirtAddr),
+    /// The image's frames couldn't be reserved.
+    Reserve(ReserveError),
+    /// Mapping the image at [`RAMDISK_START`] failed.
+    Map(MemError),
+}
+
+impl fmt::Display for RamdiskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RamdiskError::NotMapped(addr) => write!(f, "image page {:#x} not mapped", addr.as_u64()),
+            RamdiskError::Reserve(err) => write!(f, "reserving the image failed: {:?}", err),
+            RamdiskError::Map(err) => write!(f, "mapping the image failed: {}", err),
+        }
+    }
+}
+
+static DATA: OnceCell<&'static [u8]> = OnceCell::uninit();
+
+/// Reserve the image's frames and map them read-only at [`RAMDISK_START`].
+///
+/// Must run before any frame is allocated; see [`memory::reserve`].
+pub fn init(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), RamdiskError> {
+    let image = &IMAGE.bytes;
+    let pages = (image.len() as u64).div_ceil(PAGE_SIZE);
+
+    // Reserve each physically contiguous run of frames.
+    let mut run: Option<(PhysAddr, PhysAddr)> = None;
+    for page in 0..pages {
+        let start = image_frame(mapper, page)?;
+        run = match run {
+            Some((run_start, run_end)) if run_end == start => Some((run_start, start + PAGE_SIZE)),
+            Some((run_start, run_end)) => {
+                memory::reserve(run_start, run_end, ReservedKind::Ramdisk)
+                    .map_err(RamdiskError::Reserve)?;
+                Some((start, start + PAGE_SIZE))
+            }
+            None => Some((start, start + PAGE_SIZE)),
+        };
+    }
+    if let Some((run_start, run_end)) = run {
+        memory::reserve(run_start, run_end, ReservedKind::Ramdisk).map_err(RamdiskError::Reserve)?;
+    }
+
+    let flags = memory::data_flags(PageTableFlags::PRESENT);
+    for page in 0..pages {
+        let frame = PhysFrame::containing_address(image_frame(mapper, page)?);
+        let target = Page::containing_address(VirtAddr::new(RAMDISK_START + page * PAGE_SIZE));
+        unsafe {
+            memory::map_page(mapper, target, frame, flags, frame_allocator)
+                .map_err(RamdiskError::Map)?
+                .flush();
+        }
+    }
+
+    let data = unsafe { core::slice::from_raw_parts(RAMDISK_START as *const u8, image.len()) };
+    let _ = DATA.try_init_once(|| data);
+    Ok(())
+}
+
+/// Return the physical address of page `page` of the image.
+fn image_frame(mapper: &impl Translate, page: u64) -> Result<PhysAddr, RamdiskError> {
+    let addr = VirtAddr::from_ptr(IMAGE.bytes.as_ptr()) + page * PAGE_SIZE;
+    mapper.translate_addr(addr).ok_or(RamdiskError::NotMapped(addr))
+}
+
+/// Return the ramdisk's contents, or an empty slice before [`init`].
+pub fn data() -> &'static [u8] {
+    DATA.get().copied().unwrap_or(&[])
+}
+
+/// FNV-1a, 64-bit; `build.rs` computes the same over the image.
+pub fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[test_case]
+fn test_data_matches_build() {
+    let data = data();
+    assert!(!data.is_empty());
+    assert_eq!(data.as_ptr() as u64, RAMDISK_START);
+    let expected = u64::from_str_radix(BUILD_CHECKSUM, 16).unwrap();
+    assert_eq!(checksum(data), expected);
+}
+
+#[test_case]
+fn test_checksum() {
+    assert_eq!(checksum(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(checksum(b"a"), 0xaf63_dc4c_8601_ec8c);
+}
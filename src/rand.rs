@@ -0,0 +1,287 @@
+//! Kernel random numbers.
+//!
+//! When CPUID reports RDRAND, [`u64`] and [`fill`] read the CPU's generator,
+//! retrying as Intel requires since RDRAND can briefly run dry. Otherwise,
+//! or when RDRAND keeps failing, they fall back to a xoshiro256** generator.
+//! That generator is seeded at first use from TSC jitter, the CMOS clock and
+//! an entropy pool. Interrupt timings and [`seed_entropy`] keep stirring the
+//! pool into it.
+//!
+//! The fallback generator is fast but not cryptographically strong.
+//!
+//! Booting with `randseed=<n>` (or calling [`set_deterministic`]) makes every
+//! value come from the fallback generator seeded with `n` alone, so runs can
+//! be replayed.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::arch::port::PortGroup;
+use crate::rtc::CmosPorts;
+use crate::time::rdtsc;
+
+/// RDRAND attempts before giving up, as recommended by Intel.
+pub const RDRAND_RETRIES: u32 = 10;
+
+/// Times RDRAND failed [`RDRAND_RETRIES`] times in a row.
+static RDRAND_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// RDRAND attempts still to be failed on purpose; see
+/// [`inject_rdrand_failures`].
+static INJECTED_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// Entropy stirred in by interrupts and drivers.
+static POOL: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+/// Contributions to [`POOL`] so far; also picks the word the next one goes
+/// into.
+static POOL_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+static GENERATOR: Mutex<Option<Generator>> = Mutex::new(None);
+
+/// xoshiro256**, plus what it has taken from the pool.
+struct Generator {
+    state: [u64; 4],
+    /// [`POOL_EVENTS`] when the pool was last mixed in.
+    pool_events: usize,
+    /// Seeded from a fixed value; RDRAND and the pool are ignored.
+    deterministic: bool,
+}
+
+impl Generator {
+    /// Expand `seed` into a full state with splitmix64, which never yields
+    /// the all-zero state xoshiro can't leave.
+    fn from_seed(seed: u64, deterministic: bool) -> Self {
+        let mut seed = seed;
+        let mut splitmix = || {
+            seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        Generator {
+            state: [splitmix(), splitmix(), splitmix(), splitmix()],
+            pool_events: 0,
+            deterministic,
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    /// Fold in the pool if anything was added since last time.
+    fn mix_pool(&mut self) {
+        let events = POOL_EVENTS.load(Ordering::Relaxed);
+        if self.deterministic || events == self.pool_events {
+            return;
+        }
+        self.pool_events = events;
+        for (state, word) in self.state.iter_mut().zip(&POOL) {
+            *state ^= word.load(Ordering::Relaxed);
+        }
+        // A pool that cancels the state out would leave xoshiro stuck.
+        if self.state == [0; 4] {
+            *self = Generator::from_seed(rdtsc(), false);
+        }
+    }
+}
+
+/// Gather a seed from whatever varies between boots.
+fn boot_seed() -> u64 {
+    let mut seed = rdtsc();
+    // How long port reads take varies by a few cycles each time.
+    let mut port = Port::<u8>::new(0x80);
+    for _ in 0..64 {
+        let start = rdtsc();
+        let _ = unsafe { port.read() };
+        seed = seed.rotate_left(5) ^ rdtsc().wrapping_sub(start);
+    }
+    for byte in cmos_time() {
+        seed = seed.rotate_left(8) ^ u64::from(byte);
+    }
+    seed ^= crate::interrupts::ticks().rotate_left(32);
+    for word in &POOL {
+        seed ^= word.load(Ordering::Relaxed);
+    }
+    seed
+}
+
+/// Read the raw CMOS clock registers (seconds through year).
+fn cmos_time() -> [u8; 6] {
+    let cmos = CmosPorts::standard();
+    [0x00, 0x02, 0x04, 0x07, 0x08, 0x09].map(|register| cmos.read(register))
+}
+
+/// Run `f` on the generator, seeding it first if needed.
+fn with_generator<R>(f: impl FnOnce(&mut Generator) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let mut generator = GENERATOR.lock();
+        let generator = generator.get_or_insert_with(|| match crate::cmdline::get_u64("randseed") {
+            Some(seed) => Generator::from_seed(seed, true),
+            None => Generator::from_seed(boot_seed(), false),
+        });
+        f(generator)
+    })
+}
+
+/// Execute RDRAND once. `None` if it had no value ready.
+fn rdrand_step() -> Option<u64> {
+    let injected = INJECTED_FAILURES.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+        left.checked_sub(1)
+    });
+    if injected.is_ok() {
+        return None;
+    }
+    let value: u64;
+    let ok: u8;
+    unsafe {
+        asm!(
+            "rdrand {value}",
+            "setc {ok}",
+            value = out(reg) value,
+            ok = out(reg_byte) ok,
+            options(nomem, nostack),
+        );
+    }
+    (ok != 0).then_some(value)
+}
+
+/// Call `step` until it yields a value, at most [`RDRAND_RETRIES`] times.
+fn retry(mut step: impl FnMut() -> Option<u64>) -> Option<u64> {
+    (0..RDRAND_RETRIES).find_map(|_| step())
+}
+
+/// Return a random `u64`.
+pub fn u64() -> u64 {
+    with_generator(|generator| {
+        if !generator.deterministic && crate::cpu::features().rdrand {
+            match retry(rdrand_step) {
+                Some(value) => return value,
+                None => {
+                    RDRAND_FAILURES.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        generator.mix_pool();
+        generator.next()
+    })
+}
+
+/// Fill `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let bytes = u64().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+/// Stir `bytes` into the entropy pool. For drivers with unpredictable data,
+/// such as device timings or hardware serial numbers; the bytes needn't be
+/// secret or uniformly random.
+pub fn seed_entropy(bytes: &[u8]) {
+    for chunk in bytes.chunks(8) {
+        let mut word = [0; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        add_to_pool(u64::from_le_bytes(word));
+    }
+}
+
+/// Stir the current TSC into the pool. Called from interrupt handlers, so it
+/// takes no locks.
+pub(crate) fn add_interrupt_timing() {
+    add_to_pool(rdtsc());
+}
+
+fn add_to_pool(value: u64) {
+    let events = POOL_EVENTS.fetch_add(1, Ordering::Relaxed);
+    let rotation = (events / POOL.len() * 13 % 64) as u32;
+    POOL[events % POOL.len()].fetch_xor(value.rotate_left(rotation), Ordering::Relaxed);
+}
+
+/// Return how many times RDRAND failed every retry and the fallback
+/// generator was used instead.
+pub fn rdrand_failures() -> u64 {
+    RDRAND_FAILURES.load(Ordering::Relaxed)
+}
+
+/// Make the next `attempts` RDRAND executions report failure, to exercise
+/// the retry and fallback paths.
+#[doc(hidden)]
+pub fn inject_rdrand_failures(attempts: u32) {
+    INJECTED_FAILURES.store(attempts, Ordering::Relaxed);
+}
+
+/// Reseed the generator: from `seed` alone, deterministically, or from boot
+/// entropy with RDRAND enabled again if `None`.
+pub fn set_deterministic(seed: Option<u64>) {
+    interrupts::without_interrupts(|| {
+        *GENERATOR.lock() = Some(match seed {
+            Some(seed) => Generator::from_seed(seed, true),
+            None => Generator::from_seed(boot_seed(), false),
+        });
+    });
+}
+
+#[test_case]
+fn test_output_sanity() {
+    let mut buf = [0u8; 61];
+    fill(&mut buf);
+    assert!(buf.iter().any(|&byte| byte != 0));
+
+    let values: [u64; 8] = core::array::from_fn(|_| u64());
+    for (i, a) in values.iter().enumerate() {
+        assert_ne!(*a, 0);
+        assert!(values[i + 1..].iter().all(|b| b != a), "repeated value {:#x}", a);
+    }
+}
+
+#[test_case]
+fn test_rdrand_retry() {
+    let mut failures = 3;
+    let flaky = || {
+        failures -= 1;
+        (failures < 0).then_some(7)
+    };
+    assert_eq!(retry(flaky), Some(7));
+    assert_eq!(retry(|| None), None);
+
+    // Exhausting the retries falls back to the software generator.
+    let before = rdrand_failures();
+    inject_rdrand_failures(RDRAND_RETRIES);
+    let a = u64();
+    let b = u64();
+    inject_rdrand_failures(0);
+    assert_ne!(a, b);
+    let expected = if crate::cpu::features().rdrand { before + 1 } else { before };
+    assert_eq!(rdrand_failures(), expected);
+}
+
+#[test_case]
+fn test_deterministic_seed() {
+    let draw = |generator: &mut Generator| -> [u64; 4] {
+        core::array::from_fn(|_| {
+            generator.mix_pool();
+            generator.next()
+        })
+    };
+    let mut generator = Generator::from_seed(0x5eed, true);
+    let first = draw(&mut generator);
+    // The pool is ignored while deterministic.
+    seed_entropy(b"ignored while deterministic");
+    let second = draw(&mut Generator::from_seed(0x5eed, true));
+    assert_eq!(first, second);
+    assert_ne!(Generator::from_seed(0x5eee, true).next(), first[0]);
+}
@@ -0,0 +1,326 @@
+//! The CMOS real-time clock.
+//!
+//! [`now`] reads the date and time straight from the RTC registers. That
+//! takes a dozen port round trips and only has whole-second resolution, so
+//! most code should ask [`time::wallclock`](crate::time::wallclock) instead,
+//! which reads the RTC once and counts from there.
+//!
+//! The RTC is assumed to run in UTC. Its year register has two digits; the
+//! century comes from the CMOS register the FADT names (see
+//! [`acpi`](crate::acpi)), and is assumed to be the 2000s without one.
+
+use core::fmt;
+use x86_64::instructions::interrupts;
+
+use crate::arch::port::{Port, PortGroup};
+
+/// The CMOS index and data ports.
+pub struct CmosPorts {
+    /// Selects the register `data` accesses.
+    pub index: Port<u8>,
+    pub data: Port<u8>,
+}
+
+impl PortGroup for CmosPorts {
+    const DEVICE: &'static str = "cmos";
+    const BASE: u16 = 0x70;
+
+    fn at(base: u16) -> Self {
+        CmosPorts {
+            index: Port::new(Self::DEVICE, base),
+            data: Port::new(Self::DEVICE, base + 1),
+        }
+    }
+}
+
+impl CmosPorts {
+    /// Read register `register`.
+    pub fn read(&self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+}
+
+/// Clock registers.
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+/// Status A bit set while the RTC is updating its registers.
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B bits: 24-hour mode and binary (rather than BCD) values.
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+/// Hours bit marking PM in 12-hour mode.
+const HOUR_PM: u8 = 1 << 7;
+
+/// Polls of status A before giving up on an update finishing.
+const POLL_LIMIT: u32 = 100_000;
+
+/// Reads of all registers tried before giving up on two agreeing.
+const READ_ATTEMPTS: u32 = 5;
+
+/// Why the RTC couldn't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// The RTC stayed mid-update, or kept changing between reads.
+    Busy,
+    /// The registers don't hold a valid date.
+    Invalid,
+}
+
+impl fmt::Display for RtcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RtcError::Busy => f.write_str("rtc never settled"),
+            RtcError::Invalid => f.write_str("rtc holds an invalid date"),
+        }
+    }
+}
+
+/// A calendar date and time of day, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1-12.
+    pub month: u8,
+    /// 1-31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Whether every field is in range, including the day for its month.
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Seconds since 1970-01-01 00:00:00 UTC; negative before then.
+    pub fn to_unix_seconds(&self) -> i64 {
+        let days = days_from_civil(i64::from(self.year), self.month, self.day);
+        days * 86_400
+            + i64::from(self.hour) * 3600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second)
+    }
+
+    /// The date and time `seconds` after the Unix epoch.
+    pub fn from_unix_seconds(seconds: i64) -> Self {
+        let days = seconds.div_euclid(86_400);
+        let time = seconds.rem_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+/// ISO 8601, e.g. `2024-02-29 13:05:09`.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Gregorian leap years: every fourth year, except centuries not divisible
+/// by 400.
+pub fn is_leap_year(year: u16) -> bool {
+    year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+/// Days in `month` (1-12) of `year`.
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days from 1970-01-01 to the given date, after Howard Hinnant's
+/// `days_from_civil`. Years count from March so the leap day comes last.
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = i64::from(month);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5
+        + i64::from(day)
+        - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The inverse of [`days_from_civil`].
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Read CMOS register `register`.
+fn read_register(register: u8) -> u8 {
+    CmosPorts::standard().read(register)
+}
+
+/// Wait for the RTC to finish any update in progress.
+fn wait_for_update() -> Result<(), RtcError> {
+    for _ in 0..POLL_LIMIT {
+        if read_register(REG_STATUS_A) & UPDATE_IN_PROGRESS == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(RtcError::Busy)
+}
+
+/// Read the clock registers in their raw encoding.
+fn read_raw() -> Result<[u8; 6], RtcError> {
+    wait_for_update()?;
+    Ok([REG_SECONDS, REG_MINUTES, REG_HOURS, REG_DAY, REG_MONTH, REG_YEAR].map(read_register))
+}
+
+/// Decode raw register values given status register B and, if the RTC has
+/// one, the century register.
+fn decode(raw: [u8; 6], status_b: u8, century: Option<u8>) -> Result<DateTime, RtcError> {
+    let value = |byte: u8| {
+        if status_b & BINARY != 0 {
+            byte
+        } else {
+            (byte >> 4) * 10 + (byte & 0x0f)
+        }
+    };
+    let [second, minute, hours, day, month, year] = raw;
+    let mut hour = value(hours & !HOUR_PM);
+    if status_b & HOURS_24 == 0 {
+        // 12-hour mode runs 12, 1, ..., 11, with PM in the top bit.
+        hour %= 12;
+        if hours & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    let date = DateTime {
+        year: u16::from(century.map_or(20, value)) * 100 + u16::from(value(year)),
+        month: value(month),
+        day: value(day),
+        hour,
+        minute: value(minute),
+        second: value(second),
+    };
+    if date.is_valid() {
+        Ok(date)
+    } else {
+        Err(RtcError::Invalid)
+    }
+}
+
+/// Read the current date and time from the RTC.
+///
+/// Reads every register until two reads in a row agree, so an update
+/// landing mid-read can't produce a torn value.
+pub fn now() -> Result<DateTime, RtcError> {
+    interrupts::without_interrupts(|| {
+        let mut previous = read_raw()?;
+        for _ in 0..READ_ATTEMPTS {
+            let current = read_raw()?;
+            if current == previous {
+                let century = crate::acpi::fadt().and_then(|fadt| fadt.century_register);
+                // Read right after the other registers, so the century
+                // can't have rolled over in between.
+                let century = century.map(read_register);
+                return decode(current, read_register(REG_STATUS_B), century);
+            }
+            previous = current;
+        }
+        Err(RtcError::Busy)
+    })
+}
+
+#[test_case]
+fn test_unix_seconds_fixtures() {
+    let date = |year, month, day, hour, minute, second| DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    };
+    let fixtures = [
+        (date(1970, 1, 1, 0, 0, 0), 0),
+        (date(2000, 1, 1, 0, 0, 0), 946_684_800),
+        (date(2000, 2, 29, 12, 0, 0), 951_825_600),
+        (date(2000, 3, 1, 0, 0, 0), 951_868_800),
+        (date(2024, 2, 29, 23, 59, 59), 1_709_251_199),
+        (date(2024, 3, 1, 0, 0, 0), 1_709_251_200),
+        (date(2038, 1, 19, 3, 14, 8), 2_147_483_648),
+        (date(2100, 3, 1, 0, 0, 0), 4_107_542_400),
+    ];
+    for (date, seconds) in fixtures {
+        assert_eq!(date.to_unix_seconds(), seconds, "{}", date);
+        assert_eq!(DateTime::from_unix_seconds(seconds), date);
+    }
+}
+
+#[test_case]
+fn test_leap_years() {
+    assert!(is_leap_year(2024));
+    assert!(is_leap_year(2000));
+    assert!(!is_leap_year(1900));
+    assert!(!is_leap_year(2100));
+    assert_eq!(days_in_month(2024, 2), 29);
+    assert_eq!(days_in_month(2023, 2), 28);
+    let feb_29 = |year| DateTime { year, month: 2, day: 29, hour: 0, minute: 0, second: 0 };
+    assert!(feb_29(2024).is_valid());
+    assert!(!feb_29(2100).is_valid());
+}
+
+#[test_case]
+fn test_decode_bcd_12_hour() {
+    // 2024-02-29 11:30:45 PM in BCD, 12-hour mode.
+    let raw = [0x45, 0x30, 0x11 | HOUR_PM, 0x29, 0x02, 0x24];
+    let date = decode(raw, 0, None).unwrap();
+    assert_eq!(alloc::format!("{}", date), "2024-02-29 23:30:45");
+    // 12 AM is midnight.
+    assert_eq!(decode([0, 0, 0x12, 1, 1, 0], 0, None).unwrap().hour, 0);
+    // Binary, 24-hour mode.
+    assert_eq!(decode([59, 59, 23, 31, 12, 99], BINARY | HOURS_24, None).unwrap().year, 2099);
+    assert_eq!(decode([0, 0, 0, 0x30, 0x02, 0x24], 0, None), Err(RtcError::Invalid));
+    // The century register, in the same encoding as the others.
+    assert_eq!(decode([0, 0, 0, 1, 1, 0x99], 0, Some(0x19)).unwrap().year, 1999);
+    assert_eq!(decode([0, 0, 0, 1, 1, 5], BINARY, Some(21)).unwrap().year, 2105);
+    // The machine's clock reads as a sane date.
+    assert!(now().unwrap().year >= 2024);
+}
@@ -0,0 +1,415 @@
+//! Background integrity checks.
+//!
+//! Corruption found long after it happened is hard to trace back, so the
+//! scrubber looks for it while the damage is fresh. With `scrub=<seconds>`
+//! on the command line, a low-priority task runs a [`Pass`] that often:
+//!
+//! - the live heap blocks' canaries (only with the `heap-debug` feature);
+//! - the page-table flags of known kernel regions: code mapped read-only
+//!   and executable, the heap writable and no-execute, and the guard pages
+//!   around the heap not mapped at all;
+//! - the canaries at the bottom of every IST stack (see [`crate::gdt`]).
+//!
+//! A pass works in small steps and the task yields between them. The heap
+//! is walked with a [`HeapScan`](crate::allocator::debug::HeapScan), which
+//! holds the heap's bookkeeping lock for one step at a time; page tables
+//! are only read. Problems are printed (and so logged); after
+//! [`ESCALATE_AFTER`] failing passes in a row, the scrubber panics. The
+//! shell's `scrub` command shows the last report or runs a pass on demand.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::allocator::debug::{HeapCheck, HeapScan};
+use crate::allocator::{self, HEAP_SIZE, HEAP_START};
+use crate::collections::FixedVec;
+use crate::gdt::{self, CANARY_WORDS, STACK_CANARY};
+use crate::interrupts::ticks;
+use crate::memory;
+use crate::println;
+use crate::task::sync::AsyncMutex;
+
+/// Failing passes in a row after which the scrubber panics.
+pub const ESCALATE_AFTER: u32 = 3;
+/// Problems a report lists; the rest are only counted.
+pub const MAX_FINDINGS: usize = 16;
+
+const PAGE_SIZE: u64 = 4096;
+/// Pages checked per step.
+const PAGES_PER_STEP: u64 = 64;
+/// Heap blocks checked per step.
+const BLOCKS_PER_STEP: usize = 32;
+
+/// How a region should be mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expect {
+    /// Present, read-only and executable.
+    Code,
+    /// Present, writable, and no-execute if the heap is.
+    Data,
+    /// Not present.
+    Guard,
+}
+
+#[derive(Debug, Clone)]
+struct Region {
+    name: &'static str,
+    pages: Range<u64>,
+    expect: Expect,
+}
+
+/// Return the address range of the kernel's code: what the symbol table
+/// spans, or the page of this function if the table is empty.
+fn text_range() -> Range<u64> {
+    crate::symbols::text_range().unwrap_or_else(|| {
+        let here = text_range as fn() -> Range<u64> as usize as u64;
+        here..here + 1
+    })
+}
+
+/// Round `range` out to whole pages.
+fn pages(range: Range<u64>) -> Range<u64> {
+    range.start & !(PAGE_SIZE - 1)..range.end.next_multiple_of(PAGE_SIZE)
+}
+
+fn regions() -> FixedVec<Region, 4> {
+    let heap = HEAP_START as u64..(HEAP_START + HEAP_SIZE) as u64;
+    let mut regions = FixedVec::new();
+    for region in [
+        Region { name: "kernel text", pages: pages(text_range()), expect: Expect::Code },
+        Region { name: "heap", pages: heap.clone(), expect: Expect::Data },
+        Region {
+            name: "heap guard",
+            pages: heap.start - PAGE_SIZE..heap.start,
+            expect: Expect::Guard,
+        },
+        Region { name: "heap guard", pages: heap.end..heap.end + PAGE_SIZE, expect: Expect::Guard },
+    ] {
+        let _ = regions.push(region);
+    }
+    regions
+}
+
+/// Something a pass found wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Finding {
+    /// Heap blocks with damaged canaries; the check names the first.
+    Heap(HeapCheck),
+    /// A page of a known region mapped with the wrong flags.
+    Page {
+        region: &'static str,
+        addr: u64,
+        /// The flags it has, or `None` if it isn't mapped.
+        flags: Option<PageTableFlags>,
+        problem: &'static str,
+    },
+    /// An IST stack canary was overwritten.
+    Canary { cpu: usize, addr: u64, found: u64 },
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::Heap(check) => write!(f, "heap: {}", check),
+            Finding::Page { region, addr, flags, problem } => {
+                write!(f, "{} page {:#x} is {}", region, addr, problem)?;
+                match flags {
+                    Some(flags) => write!(f, " ({:?})", flags),
+                    None => Ok(()),
+                }
+            }
+            Finding::Canary { cpu, addr, found } => write!(
+                f,
+                "cpu {} ist stack canary at {:#x} is {:#018x}, expected {:#018x}",
+                cpu, addr, found, STACK_CANARY
+            ),
+        }
+    }
+}
+
+/// The outcome of one pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Passes finished before this one.
+    pub pass: u64,
+    /// Tick the pass started at.
+    pub tick: u64,
+    /// The heap check, or `None` without the `heap-debug` feature.
+    pub heap: Option<HeapCheck>,
+    pub pages: u64,
+    pub stacks: usize,
+    /// The first [`MAX_FINDINGS`] problems.
+    pub findings: Vec<Finding>,
+    /// Problems found past those.
+    pub missed: usize,
+}
+
+impl Report {
+    /// Return whether nothing was found wrong.
+    pub fn is_ok(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    fn add(&mut self, finding: Finding) {
+        if self.findings.len() < MAX_FINDINGS {
+            self.findings.push(finding);
+        } else {
+            self.missed += 1;
+        }
+    }
+
+    fn problems(&self) -> usize {
+        self.findings.len() + self.missed
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pass {} at tick {}: ", self.pass, self.tick)?;
+        match self.problems() {
+            0 => writeln!(f, "ok")?,
+            1 => writeln!(f, "1 problem")?,
+            n => writeln!(f, "{} problems", n)?,
+        }
+        match &self.heap {
+            Some(check) => writeln!(f, "heap: {} live blocks, {} bytes", check.blocks, check.bytes)?,
+            None => writeln!(f, "heap: not checked (needs heap-debug)")?,
+        }
+        writeln!(f, "pages: {} checked", self.pages)?;
+        writeln!(f, "ist stacks: {} checked", self.stacks)?;
+        for finding in &self.findings {
+            writeln!(f, "  {}", finding)?;
+        }
+        if self.missed > 0 {
+            writeln!(f, "  ... and {} more", self.missed)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Stage {
+    Heap,
+    Pages { region: usize, next: u64 },
+    Stacks,
+    Done,
+}
+
+/// One run over every check, a step at a time.
+pub struct Pass {
+    stage: Stage,
+    heap: Option<HeapScan<'static>>,
+    regions: FixedVec<Region, 4>,
+    /// Whether heap pages should be no-execute.
+    heap_nx: bool,
+    report: Report,
+}
+
+impl Pass {
+    fn new() -> Self {
+        Pass {
+            stage: Stage::Heap,
+            heap: allocator::scan_heap(),
+            regions: regions(),
+            heap_nx: allocator::heap_flags().contains(PageTableFlags::NO_EXECUTE),
+            report: Report {
+                pass: PASSES.load(Ordering::Relaxed),
+                tick: ticks(),
+                heap: None,
+                pages: 0,
+                stacks: 0,
+                findings: Vec::new(),
+                missed: 0,
+            },
+        }
+    }
+
+    /// Do a bounded amount of checking. Returns `true` once the pass is
+    /// done.
+    pub fn step(&mut self) -> bool {
+        match self.stage {
+            Stage::Heap => {
+                let Some(scan) = &mut self.heap else {
+                    self.stage = Stage::Pages { region: 0, next: 0 };
+                    return false;
+                };
+                if scan.step(BLOCKS_PER_STEP) {
+                    let check = *scan.check();
+                    if check.corrupted > 0 {
+                        self.report.add(Finding::Heap(check));
+                    }
+                    self.report.heap = Some(check);
+                    self.stage = Stage::Pages { region: 0, next: 0 };
+                }
+            }
+            Stage::Pages { region, next } => {
+                let Some(current) = self.regions.get(region) else {
+                    self.stage = Stage::Stacks;
+                    return false;
+                };
+                let (name, expect) = (current.name, current.expect);
+                let start = next.max(current.pages.start);
+                let end = (start + PAGES_PER_STEP * PAGE_SIZE).min(current.pages.end);
+                for addr in (start..end).step_by(PAGE_SIZE as usize) {
+                    self.report.pages += 1;
+                    let flags = memory::page_flags(VirtAddr::new(addr));
+                    if let Some(problem) = self.check_page(flags, expect) {
+                        self.report.add(Finding::Page { region: name, addr, flags, problem });
+                    }
+                }
+                self.stage = if end >= current.pages.end {
+                    Stage::Pages { region: region + 1, next: 0 }
+                } else {
+                    Stage::Pages { region, next: end }
+                };
+            }
+            Stage::Stacks => {
+                for (cpu, stack) in gdt::ist_stacks() {
+                    self.report.stacks += 1;
+                    let words = stack.start as *const u64;
+                    for word in 0..CANARY_WORDS {
+                        let found = unsafe { words.add(word).read_volatile() };
+                        if found != STACK_CANARY {
+                            let addr = stack.start + (word * 8) as u64;
+                            self.report.add(Finding::Canary { cpu, addr, found });
+                        }
+                    }
+                }
+                self.stage = Stage::Done;
+            }
+            Stage::Done => {}
+        }
+        matches!(self.stage, Stage::Done)
+    }
+
+    /// Return what is wrong with a page mapped with `flags`, if anything.
+    fn check_page(&self, flags: Option<PageTableFlags>, expect: Expect) -> Option<&'static str> {
+        use PageTableFlags as F;
+
+        let Some(flags) = flags else {
+            return (expect != Expect::Guard).then_some("not mapped");
+        };
+        match expect {
+            Expect::Guard => Some("mapped"),
+            _ if flags.contains(F::USER_ACCESSIBLE) => Some("user accessible"),
+            Expect::Code if flags.contains(F::WRITABLE) => Some("writable"),
+            Expect::Code if flags.contains(F::NO_EXECUTE) => Some("not executable"),
+            Expect::Data if !flags.contains(F::WRITABLE) => Some("read-only"),
+            Expect::Data if self.heap_nx && !flags.contains(F::NO_EXECUTE) => Some("executable"),
+            _ => None,
+        }
+    }
+}
+
+/// Passes finished.
+static PASSES: AtomicU64 = AtomicU64::new(0);
+/// Failing passes since the last clean one.
+static FAILING: AtomicU32 = AtomicU32::new(0);
+/// Held for the whole of a pass, across its yields; heap scans can't
+/// overlap.
+static PASS: AsyncMutex<()> = AsyncMutex::new(());
+static LAST: Mutex<Option<Report>> = Mutex::new(None);
+
+/// Keep the report of a finished pass, print what it found, and panic if
+/// passes have failed [`ESCALATE_AFTER`] times in a row.
+fn finish(pass: Pass) -> Report {
+    let report = pass.report;
+    PASSES.fetch_add(1, Ordering::Relaxed);
+    *LAST.lock() = Some(report.clone());
+    if report.is_ok() {
+        FAILING.store(0, Ordering::Relaxed);
+        return report;
+    }
+    for finding in &report.findings {
+        println!("scrub: {}", finding);
+    }
+    let failing = FAILING.fetch_add(1, Ordering::Relaxed) + 1;
+    if failing >= ESCALATE_AFTER {
+        panic!("scrub: {} passes in a row found problems; last:\n{}", failing, report);
+    }
+    report
+}
+
+/// Run a whole pass now, without yielding. Returns `None` if a pass is
+/// already under way.
+pub fn run_now() -> Option<Report> {
+    let _pass = PASS.try_lock()?;
+    let mut pass = Pass::new();
+    while !pass.step() {}
+    Some(finish(pass))
+}
+
+/// Check only the page tables and IST stack canaries, now.
+///
+/// Unlike [`run_now`], the report isn't kept and doesn't count towards
+/// escalation, and the heap is left alone, so this can run alongside a
+/// pass.
+pub fn check_layout() -> Report {
+    let mut pass = Pass::new();
+    pass.heap = None;
+    pass.stage = Stage::Pages { region: 0, next: 0 };
+    while !pass.step() {}
+    pass.report
+}
+
+/// Return the report of the last finished pass.
+pub fn last_report() -> Option<Report> {
+    LAST.lock().clone()
+}
+
+/// Run a pass every `period`, yielding between steps. A pass started by
+/// [`run_now`] meanwhile is waited for.
+pub async fn run(period: Duration) {
+    loop {
+        crate::task::sleep(period).await;
+        let _pass = PASS.lock().await;
+        let mut pass = Pass::new();
+        while !pass.step() {
+            crate::task::yield_now().await;
+        }
+        finish(pass);
+    }
+}
+
+#[test_case]
+fn test_clean_pass() {
+    let report = run_now().expect("no pass running");
+    assert!(report.is_ok(), "{}", report);
+    assert!(report.pages > (HEAP_SIZE as u64 / PAGE_SIZE));
+    assert!(report.stacks >= 1);
+    assert_eq!(report.heap.is_some(), cfg!(feature = "heap-debug"));
+    assert_eq!(last_report(), Some(report));
+}
+
+#[test_case]
+fn test_corrupted_canary_and_page_reported_in_one_pass() {
+    let (cpu, stack) = gdt::ist_stacks().next().expect("boot cpu ist stack");
+    let canary = (stack.start + 8) as *mut u64;
+    let code = VirtAddr::new(Pass::step as fn(&mut Pass) -> bool as usize as u64);
+    let code_page = code.as_u64() & !(PAGE_SIZE - 1);
+
+    unsafe { canary.write_volatile(0x41) };
+    let flags = memory::page_flags(code).unwrap();
+    unsafe { memory::replace_page_flags(code, flags | PageTableFlags::WRITABLE) };
+    let report = run_now().expect("no pass running");
+    unsafe { memory::replace_page_flags(code, flags) };
+    unsafe { canary.write_volatile(STACK_CANARY) };
+
+    assert!(report.findings.contains(&Finding::Canary { cpu, addr: stack.start + 8, found: 0x41 }));
+    assert!(report.findings.iter().any(|finding| matches!(
+        finding,
+        Finding::Page { region: "kernel text", addr, problem: "writable", .. } if *addr == code_page
+    )), "{}", report);
+    assert_eq!(report.problems(), 2, "{}", report);
+    assert_eq!(FAILING.load(Ordering::Relaxed), 1);
+
+    // A clean pass resets the count towards escalation.
+    assert!(run_now().unwrap().is_ok());
+    assert_eq!(FAILING.load(Ordering::Relaxed), 0);
+}
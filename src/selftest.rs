@@ -0,0 +1,462 @@
+//! Boot-time self-test.
+//!
+//! With `selftest` on the command line, or the shell's `selftest` command,
+//! the kernel runs a set of [`SelfTest`] checks on its core subsystems and
+//! prints one line for each:
+//!
+//! ```text
+//! selftest: PASS breakpoint: int3 handled
+//! selftest: SKIP ps2: no controller at 0x60
+//! selftest: FAIL timer: 61 ticks per rtc second, expected 100
+//! selftest: 6 checks, 4 passed, 1 skipped, 1 failed
+//! selftest: *** 1 FAILED ***
+//! ```
+//!
+//! A check skips when the hardware or subsystem it needs isn't there.
+//! Checks can't be interrupted, so each bounds its own loops; one that runs
+//! past its [`budget`](SelfTest::budget) anyway is reported as failed.
+//! Drivers add their own checks with [`register`].
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+use spin::Mutex;
+
+use crate::allocator;
+use crate::arch::port::PortGroup;
+use crate::collections::FixedVec;
+use crate::interrupts;
+use crate::rtc;
+use crate::scrub;
+use crate::serial;
+use crate::task::keyboard::{ControllerStatus, Ps2Ports};
+use crate::time::{self, TIMER_HZ};
+
+/// Most checks [`register`] takes.
+pub const MAX_CHECKS: usize = 16;
+
+/// How a check turned out, with a one-line reason.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass(String),
+    Fail(String),
+    /// What the check needs isn't there.
+    Skip(String),
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Outcome::Pass(_) => "PASS",
+            Outcome::Fail(_) => "FAIL",
+            Outcome::Skip(_) => "SKIP",
+        }
+    }
+
+    fn reason(&self) -> &str {
+        match self {
+            Outcome::Pass(reason) | Outcome::Fail(reason) | Outcome::Skip(reason) => reason,
+        }
+    }
+}
+
+/// One self-test check.
+pub trait SelfTest: Sync {
+    /// Short name, printed on the check's line and matched by the shell's
+    /// filter.
+    fn name(&self) -> &'static str;
+
+    /// Longest the check should take.
+    fn budget(&self) -> Duration;
+
+    /// Run the check. Runs with interrupts as the caller left them.
+    fn run(&self) -> Outcome;
+}
+
+/// A check's name, outcome, and how long it took.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub elapsed: Duration,
+}
+
+/// The outcome of every check run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub entries: Vec<Entry>,
+}
+
+impl Report {
+    fn count(&self, label: &str) -> usize {
+        self.entries.iter().filter(|entry| entry.outcome.label() == label).count()
+    }
+
+    pub fn passed(&self) -> usize {
+        self.count("PASS")
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count("FAIL")
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.count("SKIP")
+    }
+
+    /// Return the outcome of the check named `name`, if it ran.
+    pub fn outcome(&self, name: &str) -> Option<&Outcome> {
+        self.entries.iter().find(|entry| entry.name == name).map(|entry| &entry.outcome)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            writeln!(
+                f,
+                "selftest: {} {}: {}",
+                entry.outcome.label(),
+                entry.name,
+                entry.outcome.reason()
+            )?;
+        }
+        writeln!(
+            f,
+            "selftest: {} checks, {} passed, {} skipped, {} failed",
+            self.entries.len(),
+            self.passed(),
+            self.skipped(),
+            self.failed()
+        )?;
+        match self.failed() {
+            0 => Ok(()),
+            failed => writeln!(f, "selftest: *** {} FAILED ***", failed),
+        }
+    }
+}
+
+/// int3 reaches the breakpoint handler through the IDT.
+struct Breakpoint;
+
+impl SelfTest for Breakpoint {
+    fn name(&self) -> &'static str {
+        "breakpoint"
+    }
+
+    fn budget(&self) -> Duration {
+        Duration::from_millis(100)
+    }
+
+    fn run(&self) -> Outcome {
+        let before = interrupts::breakpoints();
+        x86_64::instructions::interrupts::int3();
+        match interrupts::breakpoints() - before {
+            1 => Outcome::Pass("int3 handled".into()),
+            n => Outcome::Fail(format!("int3 handled {} times", n)),
+        }
+    }
+}
+
+/// Polls of the RTC while waiting for its seconds to change, each about a
+/// millisecond apart.
+const RTC_POLLS: u32 = 1100;
+/// How far the tick rate may stray from [`TIMER_HZ`], in percent.
+const TIMER_TOLERANCE: u64 = 20;
+
+/// Wait for the RTC's seconds to move on from `second`, and return the new
+/// value. `None` if it didn't within [`RTC_POLLS`] polls.
+fn next_rtc_second(second: u8) -> Option<u8> {
+    for _ in 0..RTC_POLLS {
+        let now = rtc::now().ok()?.second;
+        if now != second {
+            return Some(now);
+        }
+        time::pit_oneshot_us(1000).ok()?;
+    }
+    None
+}
+
+/// The timer ticks at [`TIMER_HZ`], measured over one RTC second.
+struct TimerRate;
+
+impl SelfTest for TimerRate {
+    fn name(&self) -> &'static str {
+        "timer"
+    }
+
+    fn budget(&self) -> Duration {
+        Duration::from_secs(3)
+    }
+
+    fn run(&self) -> Outcome {
+        if !time::pit_configured() {
+            return Outcome::Skip("pit not programmed".into());
+        }
+        if !x86_64::instructions::interrupts::are_enabled() {
+            return Outcome::Skip("interrupts off".into());
+        }
+        let start = match rtc::now() {
+            Ok(date) => date.second,
+            Err(err) => return Outcome::Skip(format!("rtc: {}", err)),
+        };
+        let Some(edge) = next_rtc_second(start) else {
+            return Outcome::Fail("rtc seconds not advancing".into());
+        };
+        let first = interrupts::ticks();
+        if next_rtc_second(edge).is_none() {
+            return Outcome::Fail("rtc seconds not advancing".into());
+        }
+        let ticks = interrupts::ticks() - first;
+        let expected = TIMER_HZ as u64;
+        if ticks.abs_diff(expected) * 100 > expected * TIMER_TOLERANCE {
+            Outcome::Fail(format!("{} ticks per rtc second, expected {}", ticks, expected))
+        } else {
+            Outcome::Pass(format!("{} ticks per rtc second", ticks))
+        }
+    }
+}
+
+/// Allocations [`HeapBalance`] makes.
+const HEAP_BATCH: usize = 32;
+
+/// A batch of allocations, once freed, leaves the heap as it was.
+struct HeapBalance;
+
+impl SelfTest for HeapBalance {
+    fn name(&self) -> &'static str {
+        "heap"
+    }
+
+    fn budget(&self) -> Duration {
+        Duration::from_millis(100)
+    }
+
+    fn run(&self) -> Outcome {
+        let before = allocator::heap_stats();
+        if before.size == 0 {
+            return Outcome::Skip("heap not set up".into());
+        }
+        let batch: Vec<Box<[u8]>> =
+            (0..HEAP_BATCH).map(|i| alloc::vec![i as u8; 16 << (i % 6)].into_boxed_slice()).collect();
+        let peak = allocator::heap_stats().used;
+        if let Some((i, block)) =
+            batch.iter().enumerate().find(|(i, block)| block.iter().any(|&byte| byte != *i as u8))
+        {
+            return Outcome::Fail(format!("block {} of {} bytes corrupted", i, block.len()));
+        }
+        drop(batch);
+        let after = allocator::heap_stats().used;
+        if after != before.used {
+            return Outcome::Fail(format!("{} bytes used after freeing, {} before", after, before.used));
+        }
+        Outcome::Pass(format!("{} blocks, {} bytes, freed", HEAP_BATCH, peak - before.used))
+    }
+}
+
+/// COM1 hands back a byte sent in loopback mode.
+struct UartLoopback;
+
+impl SelfTest for UartLoopback {
+    fn name(&self) -> &'static str {
+        "uart"
+    }
+
+    fn budget(&self) -> Duration {
+        Duration::from_millis(100)
+    }
+
+    fn run(&self) -> Outcome {
+        if !serial::SERIAL1.is_initialized() {
+            return Outcome::Skip("com1 not set up".into());
+        }
+        match serial::loopback_test() {
+            Ok(()) => Outcome::Pass("com1 loopback".into()),
+            Err(err) => Outcome::Fail(format!("{}", err)),
+        }
+    }
+}
+
+/// A PS/2 controller answers at `base` without reporting errors.
+struct Ps2Controller {
+    base: u16,
+}
+
+impl SelfTest for Ps2Controller {
+    fn name(&self) -> &'static str {
+        "ps2"
+    }
+
+    fn budget(&self) -> Duration {
+        Duration::from_millis(10)
+    }
+
+    fn run(&self) -> Outcome {
+        // Reading the status register has no side effects.
+        let status = ControllerStatus(unsafe { Ps2Ports::at(self.base).status_cmd.read() });
+        if status.0 == 0xFF {
+            return Outcome::Skip(format!("no controller at {:#x}", self.base));
+        }
+        if status.has(ControllerStatus::TIMEOUT) || status.has(ControllerStatus::PARITY) {
+            return Outcome::Fail(format!("controller status {:#04x}", status.0));
+        }
+        Outcome::Pass(format!("controller at {:#x}", self.base))
+    }
+}
+
+/// The kernel's page-table layout and IST stacks are as [`scrub`] expects.
+struct PageLayout;
+
+impl SelfTest for PageLayout {
+    fn name(&self) -> &'static str {
+        "pages"
+    }
+
+    fn budget(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn run(&self) -> Outcome {
+        if allocator::heap_stats().size == 0 {
+            return Outcome::Skip("memory not set up".into());
+        }
+        let report = scrub::check_layout();
+        match report.findings.first() {
+            None => Outcome::Pass(format!(
+                "{} pages, {} ist stacks",
+                report.pages, report.stacks
+            )),
+            Some(finding) => Outcome::Fail(format!(
+                "{} ({} problems)",
+                finding,
+                report.findings.len() + report.missed
+            )),
+        }
+    }
+}
+
+static BUILTIN: [&dyn SelfTest; 6] = [
+    &Breakpoint,
+    &TimerRate,
+    &HeapBalance,
+    &UartLoopback,
+    &Ps2Controller { base: Ps2Ports::BASE },
+    &PageLayout,
+];
+
+static REGISTERED: Mutex<FixedVec<&'static dyn SelfTest, MAX_CHECKS>> =
+    Mutex::new(FixedVec::new());
+
+/// Add `check` to the ones [`run`] runs, after the built-in checks.
+///
+/// Returns `false` if [`MAX_CHECKS`] are already registered.
+pub fn register(check: &'static dyn SelfTest) -> bool {
+    REGISTERED.lock().push(check).is_ok()
+}
+
+/// Run `checks` in order, timing each against its budget.
+fn run_checks<'a>(checks: impl IntoIterator<Item = &'a dyn SelfTest>) -> Report {
+    let mut report = Report::default();
+    for check in checks {
+        let start = time::monotonic();
+        let mut outcome = check.run();
+        let elapsed = time::monotonic() - start;
+        if elapsed > check.budget() && !matches!(outcome, Outcome::Fail(_)) {
+            outcome = Outcome::Fail(format!(
+                "took {} ms, budget {} ms",
+                elapsed.as_millis(),
+                check.budget().as_millis()
+            ));
+        }
+        report.entries.push(Entry { name: check.name(), outcome, elapsed });
+    }
+    report
+}
+
+/// Run the checks whose name contains `filter`.
+pub fn run_matching(filter: &str) -> Report {
+    // Copied out, so a check may register another without deadlocking.
+    let registered: Vec<&'static dyn SelfTest> = REGISTERED.lock().iter().copied().collect();
+    run_checks(
+        BUILTIN
+            .iter()
+            .copied()
+            .chain(registered)
+            .filter(|check| check.name().contains(filter)),
+    )
+}
+
+/// Run every check.
+pub fn run() -> Report {
+    run_matching("")
+}
+
+#[test_case]
+fn test_full_suite_passes() {
+    let report = run();
+    assert_eq!(report.failed(), 0, "{}", report);
+    for name in ["breakpoint", "timer", "heap", "uart", "ps2", "pages"] {
+        assert!(
+            matches!(report.outcome(name), Some(Outcome::Pass(_))),
+            "{} didn't pass:\n{}",
+            name,
+            report
+        );
+    }
+    assert!(!format!("{}", report).contains("FAILED"));
+}
+
+#[test_case]
+fn test_absent_controller_skips() {
+    // Nothing decodes these ports on a PC; they read as all ones.
+    let absent = Ps2Controller { base: 0x3a0 };
+    let report = run_checks([&absent as &dyn SelfTest]);
+    assert_eq!(report.outcome("ps2"), Some(&Outcome::Skip("no controller at 0x3a0".into())));
+    assert_eq!(report.failed(), 0);
+}
+
+#[test_case]
+fn test_failures_surfaced() {
+    struct Broken;
+
+    impl SelfTest for Broken {
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+
+        fn budget(&self) -> Duration {
+            Duration::from_secs(1)
+        }
+
+        fn run(&self) -> Outcome {
+            Outcome::Fail("always".into())
+        }
+    }
+
+    struct Slow;
+
+    impl SelfTest for Slow {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        fn budget(&self) -> Duration {
+            Duration::ZERO
+        }
+
+        fn run(&self) -> Outcome {
+            crate::time::sleep_ms(20);
+            Outcome::Pass("done".into())
+        }
+    }
+
+    let report = run_checks([&Broken as &dyn SelfTest, &Slow]);
+    assert_eq!(report.failed(), 2);
+    assert!(matches!(report.outcome("slow"), Some(Outcome::Fail(reason)) if reason.starts_with("took ")));
+    let text = format!("{}", report);
+    assert!(text.starts_with("selftest: FAIL broken: always\n"), "{}", text);
+    assert!(text.ends_with("2 checks, 0 passed, 0 skipped, 2 failed\nselftest: *** 2 FAILED ***\n"), "{}", text);
+}
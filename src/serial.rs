@@ -1,24 +1,100 @@
-//! Serial output support.
+//! Serial output and input support.
 //!
 //! This module provides a simple, synchronized interface for printing text to
 //! the first serial port (COM1). It is primarily intended for early boot
 //! debugging and kernel logging, where VGA or more complex output facilities
 //! may not yet be available.
+//!
+//! Input mirrors the keyboard: the COM1 interrupt handler moves received
+//! bytes into a bounded [`channel`](crate::task::channel()) through
+//! [`receive_interrupt`], and tasks read them with [`stream`] or [`lines`].
+//! With flow control on (the default), a full channel doesn't drop bytes:
+//! the handler turns off the receive interrupt and drops RTS instead, leaving
+//! further bytes in the UART until the stream has drained the channel.
 
-use lazy_static::lazy_static;
-use spin::Mutex;
+use alloc::string::String;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
 use uart_16550::SerialPort;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
 
-lazy_static! {
-    /// Global handle to the first serial port (COM1, I/O port 0x3F8).
-    ///
-    /// Wrapped in a spinlock to allow safe shared access from different contexts,
-    /// including interrupt handlers. The port is initialized once at startup.
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+use crate::error::KernelError;
+use crate::output::KernelOutput;
+use crate::sync::{Global, GlobalError, IrqMutex};
+use crate::{bail, ensure};
+use crate::task::channel::{self, Receiver, Sender};
+
+pub mod xfer;
+
+/// I/O base port of COM1.
+const COM1: u16 = 0x3F8;
+
+/// Number of received bytes buffered for the stream.
+const RX_QUEUE_CAPACITY: usize = 256;
+
+/// Longest line [`lines`] yields before truncating.
+pub const MAX_LINE_LEN: usize = 256;
+
+/// Appended to lines that were cut off at [`MAX_LINE_LEN`].
+pub const TRUNCATION_MARKER: &str = "[truncated]";
+
+/// Global handle to the first serial port (COM1, I/O port 0x3F8), set up by
+/// [`init`].
+///
+/// Wrapped in an [`IrqMutex`] to allow safe shared access from different
+/// contexts, including interrupt handlers.
+pub static SERIAL1: Global<IrqMutex<SerialPort>> = Global::new("SERIAL1");
+
+/// Why the serial port couldn't be set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// No UART answered at COM1.
+    NotPresent,
+    /// In loopback mode, the UART didn't hand back the byte sent; it
+    /// returned this one, or nothing.
+    Loopback(Option<u8>),
+}
+
+impl fmt::Display for SerialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialError::NotPresent => f.write_str("no uart at com1"),
+            SerialError::Loopback(Some(byte)) => {
+                write!(f, "loopback returned {:#04x}, sent {:#04x}", byte, LOOPBACK_BYTE)
+            }
+            SerialError::Loopback(None) => f.write_str("loopback returned nothing"),
+        }
+    }
+}
+
+/// Check for a UART at COM1: its scratch register keeps what is written to
+/// it, while an empty port reads back as all ones.
+fn uart_present() -> bool {
+    let mut scratch = Port::<u8>::new(COM1 + 7);
+    [0x5a, 0xa5].into_iter().all(|value| unsafe {
+        scratch.write(value);
+        scratch.read() == value
+    })
+}
+
+/// Initialize the UART and set up [`SERIAL1`]. Output printed before this is
+/// dropped.
+pub fn init() -> Result<(), KernelError> {
+    if SERIAL1.is_initialized() {
+        bail!(GlobalError::AlreadyInitialized("SERIAL1"));
+    }
+    ensure!(uart_present(), SerialError::NotPresent);
+    let mut serial_port = unsafe { SerialPort::new(COM1) };
+    serial_port.init();
+    SERIAL1.init(IrqMutex::named("SERIAL1", serial_port))?;
+    crate::emergency::register(&SERIAL1);
+    crate::power::on_shutdown("serial", flush, crate::power::DEFAULT_TIMEOUT);
+    Ok(())
 }
 
 /// Low-level serial printing routine.
@@ -26,20 +102,172 @@ lazy_static! {
 /// This function is not meant to be called directly. It is used by the
 /// [`serial_print!`] and [`serial_println!`] macros.
 ///
-/// Interrupts are temporarily disabled while holding the serial lock to avoid
-/// deadlock if an interrupt handler attempts to write to the serial port while
-/// it is already in use.
+/// Once the wall clock is set, each line starts with the UTC time of day
+/// (see [`crate::time::wallclock`]).
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
+    if let Ok(serial) = SERIAL1.try_get() {
+        serial.lock().write_output(args);
+    }
+}
+
+impl KernelOutput for SerialPort {
+    fn write_output(&mut self, args: fmt::Arguments) {
+        use core::fmt::Write;
+
+        let written = if TX_BUFFERING.load(Ordering::Relaxed) {
+            let mut burst = Burst { bytes: [0; TX_FIFO_DEPTH], len: 0 };
+            let written = Timestamped { port: &mut Tapped { port: &mut burst } }.write_fmt(args);
+            burst.send();
+            written
+        } else {
+            Timestamped { port: &mut Tapped { port: self } }.write_fmt(args)
+        };
+        written.expect("Printing to serial failed");
+    }
+}
+
+/// Send [`kprintln!`](crate::kprintln) output to COM1, once [`init`] has
+/// set it up. Returns `false` if there was no room for another
+/// [output](crate::output).
+pub fn register() -> bool {
+    crate::output::register(_print)
+}
+
+/// Bytes the UART's transmit FIFO holds.
+const TX_FIFO_DEPTH: usize = 16;
+
+/// Whether [`_print`] sends in [`Burst`]s; see [`set_tx_buffering`].
+static TX_BUFFERING: AtomicBool = AtomicBool::new(false);
+
+/// Send output in bursts that fill the UART's transmit FIFO, waiting on
+/// the line status register once per burst instead of once per byte.
+///
+/// Off by default. Unlike the unbuffered path, backspace and delete go out
+/// as they are rather than being turned into erase sequences.
+pub fn set_tx_buffering(enabled: bool) {
+    TX_BUFFERING.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns whether output is sent in FIFO-sized bursts.
+pub fn tx_buffering() -> bool {
+    TX_BUFFERING.load(Ordering::Relaxed)
+}
+
+/// Collects output for COM1 and sends it a FIFO's worth at a time. Only
+/// used with the [`SERIAL1`] lock held.
+struct Burst {
+    bytes: [u8; TX_FIFO_DEPTH],
+    len: usize,
+}
+
+impl Burst {
+    /// Send what has been collected.
+    fn send(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        let mut line_status = Port::<u8>::new(COM1 + 5);
+        let mut data = Port::<u8>::new(COM1);
+        // Bit 5: the transmit FIFO is empty, so it takes a full burst.
+        while unsafe { line_status.read() } & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        for &byte in &self.bytes[..self.len] {
+            unsafe { data.write(byte) };
+        }
+        self.len = 0;
+    }
+}
+
+impl fmt::Write for Burst {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == TX_FIFO_DEPTH {
+                self.send();
+            }
+            self.bytes[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Function [`set_tap`] installed, or null.
+static TAP: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Hand every byte sent to COM1 to `tap` as well, or stop with `None`.
+///
+/// For [`record_serial`](crate::test_framework::record_serial). The tap
+/// sees [`_print`] output, timestamps included, and the
+/// [emergency](crate::emergency) path's, and may run with interrupts off
+/// and output locks held, so it must take no lock itself.
+pub(crate) fn set_tap(tap: Option<fn(&[u8])>) {
+    let tap = tap.map_or(core::ptr::null_mut(), |tap| tap as *mut ());
+    TAP.store(tap, Ordering::Release);
+}
+
+/// Pass `bytes`, just sent to COM1, to the [tap](set_tap), if there is one.
+pub(crate) fn tap(bytes: &[u8]) {
+    let tap = TAP.load(Ordering::Acquire);
+    if !tap.is_null() {
+        // SAFETY: only `set_tap` stores to `TAP`, and only `fn(&[u8])`s.
+        let tap: fn(&[u8]) = unsafe { core::mem::transmute(tap) };
+        tap(bytes);
+    }
+}
+
+/// Hands what it writes to the [tap](set_tap) on its way to `port`.
+struct Tapped<'a> {
+    port: &'a mut dyn fmt::Write,
+}
 
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+impl fmt::Write for Tapped<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        tap(s.as_bytes());
+        self.port.write_str(s)
+    }
+}
+
+/// Whether the next byte through [`_print`] starts a line.
+static AT_LINE_START: AtomicBool = AtomicBool::new(true);
+
+/// Bytes [`_print`] has sent, timestamps included.
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+
+/// Return how many bytes [`serial_print!`](crate::serial_print) and
+/// console output have sent to COM1.
+pub fn bytes_sent() -> u64 {
+    BYTES_SENT.load(Ordering::Relaxed)
+}
+
+/// Writes to COM1, prefixing each line with the time of day.
+struct Timestamped<'a> {
+    port: &'a mut dyn fmt::Write,
+}
+
+impl core::fmt::Write for Timestamped<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for line in s.split_inclusive('\n') {
+            if AT_LINE_START.load(Ordering::Relaxed)
+                && let Some(now) = crate::time::wallclock::try_now()
+            {
+                let time = now.date_time();
+                write!(
+                    self.port,
+                    "[{:02}:{:02}:{:02}.{:03}] ",
+                    time.hour,
+                    time.minute,
+                    time.second,
+                    now.since_epoch().subsec_millis()
+                )?;
+            }
+            self.port.write_str(line)?;
+            BYTES_SENT.fetch_add(line.len() as u64, Ordering::Relaxed);
+            AT_LINE_START.store(line.ends_with('\n'), Ordering::Relaxed);
+        }
+        Ok(())
+    }
 }
 
 /// Prints formatted text to the host through the serial interface.
@@ -65,3 +293,446 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+/// Wait until COM1 has shifted out everything written to it.
+///
+/// Gives up after a bounded number of polls, so a missing or wedged UART
+/// can't hang the caller. Used before resetting the machine.
+pub fn flush() {
+    // Line status register; bit 6 is set once both the holding register
+    // and the shift register are empty.
+    let mut line_status = Port::<u8>::new(COM1 + 5);
+    for _ in 0..100_000 {
+        if unsafe { line_status.read() } & 0x40 != 0 {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Byte [`loopback_test`] sends.
+const LOOPBACK_BYTE: u8 = 0xA5;
+/// Modem control bit that wires the transmitter to the receiver.
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+/// Send a byte through COM1 with the UART in loopback mode and check it
+/// comes back.
+///
+/// Holds the [`SERIAL1`] lock, so interrupts are off and the receive
+/// interrupt can't take the byte. Bytes already waiting are passed to the
+/// serial stream first. Nothing goes out on the line meanwhile.
+pub fn loopback_test() -> Result<(), KernelError> {
+    let serial = SERIAL1.try_get()?;
+    let _port = serial.lock();
+    RX.receive();
+    flush();
+    let mut modem_control = Port::<u8>::new(COM1 + 4);
+    let mut line_status = Port::<u8>::new(COM1 + 5);
+    let mut data = Port::<u8>::new(COM1);
+    let saved = unsafe { modem_control.read() };
+    let mut received = None;
+    unsafe {
+        modem_control.write(saved | MCR_LOOPBACK);
+        data.write(LOOPBACK_BYTE);
+        for _ in 0..100_000 {
+            // bit 0: data ready
+            if line_status.read() & 1 != 0 {
+                received = Some(data.read());
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        modem_control.write(saved);
+    }
+    ensure!(received == Some(LOOPBACK_BYTE), SerialError::Loopback(received));
+    Ok(())
+}
+
+/// Receive side of a UART, as seen by the receive interrupt handler.
+trait RxPort: Sync {
+    /// Read a received byte, if one is waiting.
+    fn read(&self) -> Option<u8>;
+
+    /// Turn the receive interrupt and RTS on or off together.
+    fn set_rx_enabled(&self, enabled: bool);
+}
+
+/// The receive side of COM1, accessed directly through its registers.
+///
+/// Doesn't take the [`SERIAL1`] lock, so the interrupt handler can't
+/// deadlock against a task that is printing.
+struct Com1Rx;
+
+impl RxPort for Com1Rx {
+    fn read(&self) -> Option<u8> {
+        let mut line_status: Port<u8> = Port::new(COM1 + 5);
+        let mut data: Port<u8> = Port::new(COM1);
+        // bit 0: data ready
+        unsafe { (line_status.read() & 1 != 0).then(|| data.read()) }
+    }
+
+    fn set_rx_enabled(&self, enabled: bool) {
+        let mut interrupt_enable: Port<u8> = Port::new(COM1 + 1);
+        let mut modem_control: Port<u8> = Port::new(COM1 + 4);
+        // Modem control as set by `SerialPort::init` (DTR, RTS, OUT2), minus
+        // RTS while paused.
+        let (ier, mcr) = if enabled { (0x01, 0x0B) } else { (0x00, 0x09) };
+        interrupts::without_interrupts(|| unsafe {
+            interrupt_enable.write(ier);
+            modem_control.write(mcr);
+        });
+    }
+}
+
+/// Serial input received in interrupt context.
+static RX: SerialInput = SerialInput::new(&Com1Rx);
+
+/// A UART's receive side with the sending half of its channel and the
+/// flow-control state.
+struct SerialInput {
+    port: &'static dyn RxPort,
+    sender: OnceCell<Sender<u8>>,
+    flow_control: AtomicBool,
+    /// Set while the receive interrupt is off because the channel was full.
+    paused: AtomicBool,
+}
+
+impl SerialInput {
+    const fn new(port: &'static dyn RxPort) -> Self {
+        SerialInput {
+            port,
+            sender: OnceCell::uninit(),
+            flow_control: AtomicBool::new(true),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    /// Create the channel and return its receiving end.
+    ///
+    /// Panics if called more than once.
+    fn open(&self) -> Receiver<u8> {
+        let (sender, receiver) = channel::channel(RX_QUEUE_CAPACITY);
+        self.sender
+            .try_init_once(|| sender)
+            .expect("serial::stream should only be called once");
+        receiver
+    }
+
+    /// Move waiting bytes from the UART into the channel.
+    ///
+    /// With flow control on, stops at a full channel and pauses reception.
+    /// Otherwise bytes that don't fit are dropped and counted. Bytes are
+    /// discarded while no stream is open.
+    fn receive(&self) {
+        let port = self.port;
+        let Ok(sender) = self.sender.try_get() else {
+            while port.read().is_some() {}
+            return;
+        };
+        loop {
+            if sender.is_full() && self.flow_control.load(Ordering::Relaxed) {
+                self.paused.store(true, Ordering::Relaxed);
+                port.set_rx_enabled(false);
+                return;
+            }
+            let Some(byte) = port.read() else {
+                return;
+            };
+            let _ = sender.try_send(byte);
+        }
+    }
+
+    /// Turn reception back on if it was paused.
+    ///
+    /// Any bytes held back in the UART raise an interrupt right away.
+    fn resume(&self) {
+        if self.paused.swap(false, Ordering::Relaxed) {
+            self.port.set_rx_enabled(true);
+        }
+    }
+}
+
+/// Drain COM1's receive buffer into the serial stream.
+///
+/// Called by the COM1 interrupt handler; never blocks or allocates.
+pub(crate) fn receive_interrupt() {
+    RX.receive();
+}
+
+/// Choose whether a full receive queue pauses reception (the default) or
+/// drops the bytes that don't fit.
+pub fn set_flow_control(enabled: bool) {
+    RX.flow_control.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        RX.resume();
+    }
+}
+
+/// Return the number of received bytes dropped because the stream fell
+/// behind with flow control off.
+pub fn dropped_bytes() -> u64 {
+    RX.sender.try_get().map_or(0, Sender::dropped)
+}
+
+/// Return the stream of bytes received on COM1.
+///
+/// There is only one receive channel, so this panics if called more than
+/// once.
+pub fn stream() -> SerialStream {
+    // The UART only raises receive interrupts once it is initialized.
+    SERIAL1.get();
+    SerialStream::with_input(&RX)
+}
+
+/// Stream of bytes received on a serial port. Returned by [`stream`].
+pub struct SerialStream {
+    receiver: Receiver<u8>,
+    input: &'static SerialInput,
+}
+
+impl SerialStream {
+    fn with_input(input: &'static SerialInput) -> Self {
+        SerialStream {
+            receiver: input.open(),
+            input,
+        }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let this = self.get_mut();
+        let item = Pin::new(&mut this.receiver).poll_next(cx);
+        if item.is_pending() {
+            // Drained, and the waker is registered for what resuming delivers.
+            this.input.resume();
+        }
+        item
+    }
+}
+
+/// Splits a byte stream into lines.
+///
+/// A line ends at CR, LF or CRLF. Lines longer than [`MAX_LINE_LEN`] are cut
+/// off there and end with [`TRUNCATION_MARKER`]; the rest of the line is
+/// skipped.
+#[derive(Default)]
+struct LineAssembler {
+    line: String,
+    truncated: bool,
+    after_cr: bool,
+}
+
+impl LineAssembler {
+    /// Add `byte`, returning the line it completes, if any.
+    fn push(&mut self, byte: u8) -> Option<String> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match byte {
+            b'\n' if after_cr => None,
+            b'\r' | b'\n' => {
+                let mut line = core::mem::take(&mut self.line);
+                if core::mem::take(&mut self.truncated) {
+                    line.push_str(TRUNCATION_MARKER);
+                }
+                Some(line)
+            }
+            _ if self.line.len() >= MAX_LINE_LEN => {
+                self.truncated = true;
+                None
+            }
+            _ => {
+                self.line.push(char::from(byte));
+                None
+            }
+        }
+    }
+}
+
+/// Return the lines received on COM1.
+///
+/// Opens the byte stream, so like [`stream`] it panics if called more than
+/// once.
+pub fn lines() -> SerialLines<SerialStream> {
+    SerialLines::new(stream())
+}
+
+/// Stream of lines split from a byte stream. Returned by [`lines`].
+pub struct SerialLines<S> {
+    bytes: S,
+    assembler: LineAssembler,
+}
+
+impl<S: Stream<Item = u8> + Unpin> SerialLines<S> {
+    fn new(bytes: S) -> Self {
+        SerialLines {
+            bytes,
+            assembler: LineAssembler::default(),
+        }
+    }
+
+    /// Return the byte stream underneath, to read raw bytes between lines,
+    /// as [`xfer::receive`] does.
+    pub fn bytes(&mut self) -> &mut S {
+        &mut self.bytes
+    }
+}
+
+impl<S: Stream<Item = u8> + Unpin> Stream for SerialLines<S> {
+    type Item = String;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<String>> {
+        let this = self.get_mut();
+        loop {
+            match Pin::new(&mut this.bytes).poll_next(cx) {
+                Poll::Ready(Some(byte)) => {
+                    if let Some(line) = this.assembler.push(byte) {
+                        return Poll::Ready(Some(line));
+                    }
+                }
+                // A line without a terminator is dropped when input ends.
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// A UART whose receive buffer is filled by the test.
+#[cfg(test)]
+struct FakeUart {
+    fifo: spin::Mutex<alloc::collections::VecDeque<u8>>,
+    rx_enabled: AtomicBool,
+}
+
+#[cfg(test)]
+impl FakeUart {
+    const fn new() -> Self {
+        FakeUart {
+            fifo: spin::Mutex::new(alloc::collections::VecDeque::new()),
+            rx_enabled: AtomicBool::new(true),
+        }
+    }
+
+    fn inject(&self, bytes: &[u8]) {
+        self.fifo.lock().extend(bytes);
+    }
+}
+
+#[cfg(test)]
+impl RxPort for FakeUart {
+    fn read(&self) -> Option<u8> {
+        self.fifo.lock().pop_front()
+    }
+
+    fn set_rx_enabled(&self, enabled: bool) {
+        self.rx_enabled.store(enabled, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn test_stream_yields_received_bytes() {
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+
+    static UART: FakeUart = FakeUart::new();
+    static INPUT: SerialInput = SerialInput::new(&UART);
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut stream = SerialStream::with_input(&INPUT);
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+
+    UART.inject(b"hi");
+    INPUT.receive();
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    for expected in *b"hi" {
+        assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Ready(Some(expected)));
+    }
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+}
+
+#[test_case]
+fn test_full_queue_pauses_reception() {
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+
+    static UART: FakeUart = FakeUart::new();
+    static INPUT: SerialInput = SerialInput::new(&UART);
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut stream = SerialStream::with_input(&INPUT);
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+
+    let burst: alloc::vec::Vec<u8> = (0..RX_QUEUE_CAPACITY + 10).map(|i| i as u8).collect();
+    UART.inject(&burst);
+    INPUT.receive();
+    assert!(!UART.rx_enabled.load(Ordering::SeqCst));
+    assert_eq!(UART.fifo.lock().len(), 10);
+
+    // Reception stays off until the stream has drained the queue.
+    for &expected in &burst[..RX_QUEUE_CAPACITY] {
+        assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Ready(Some(expected)));
+        assert!(!UART.rx_enabled.load(Ordering::SeqCst));
+    }
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+    assert!(UART.rx_enabled.load(Ordering::SeqCst));
+
+    // The bytes held back in the UART arrive once it interrupts again.
+    INPUT.receive();
+    for &expected in &burst[RX_QUEUE_CAPACITY..] {
+        assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Ready(Some(expected)));
+    }
+    assert_eq!(stream.receiver.dropped(), 0);
+}
+
+#[test_case]
+fn test_full_queue_drops_without_flow_control() {
+    static UART: FakeUart = FakeUart::new();
+    static INPUT: SerialInput = SerialInput::new(&UART);
+
+    INPUT.flow_control.store(false, Ordering::Relaxed);
+    let stream = SerialStream::with_input(&INPUT);
+    let burst = [b'x'; RX_QUEUE_CAPACITY + 10];
+    UART.inject(&burst);
+    INPUT.receive();
+
+    assert!(UART.rx_enabled.load(Ordering::SeqCst));
+    assert!(UART.fifo.lock().is_empty());
+    assert_eq!(stream.receiver.dropped(), 10);
+}
+
+#[test_case]
+fn test_lines_split_on_any_line_ending() {
+    use crate::task::channel::channel;
+    use crate::task::test_util::counting_waker;
+    use alloc::format;
+    use core::sync::atomic::AtomicUsize;
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let (bytes_tx, bytes_rx) = channel::<u8>(1024);
+    let mut lines = SerialLines::new(bytes_rx);
+    let long = [b'a'; MAX_LINE_LEN + 5];
+    for &byte in b"unix\nmac\rdos\r\n\n"
+        .iter()
+        .chain(&long)
+        .chain(b"\r\nafter\n")
+    {
+        bytes_tx.try_send(byte).unwrap();
+    }
+
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    let truncated = format!("{}{}", "a".repeat(MAX_LINE_LEN), TRUNCATION_MARKER);
+    for expected in ["unix", "mac", "dos", "", &truncated, "after"] {
+        assert_eq!(
+            Pin::new(&mut lines).poll_next(&mut cx),
+            Poll::Ready(Some(String::from(expected)))
+        );
+    }
+    assert_eq!(Pin::new(&mut lines).poll_next(&mut cx), Poll::Pending);
+}
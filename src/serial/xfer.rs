@@ -0,0 +1,399 @@
+//! Receiving files over the serial port.
+//!
+//! [`receive`] speaks the receiving side of YMODEM, so a host can push a
+//! file into the running kernel with `sb` from lrzsz (`sb file < pty > pty`
+//! against QEMU's `-serial pty`):
+//!
+//! 1. The receiver sends `C`, asking for CRC-16 blocks.
+//! 2. The sender answers with block 0: the file name and its size in
+//!    decimal. A size over the receive buffer is refused with `CAN CAN`.
+//! 3. After `ACK` and another `C`, data blocks follow: `SOH` (128 bytes) or
+//!    `STX` (1024 bytes), the block number and its complement, the data and
+//!    its CRC. Each is answered with `ACK`, or `NAK` to have it sent again.
+//! 4. `EOT` ends the file, and an empty block 0 ends the batch.
+//!
+//! A sender that skips block 0 (plain XMODEM-CRC) works too; the file then
+//! keeps the padding of its last block. Every block has to arrive within
+//! [`BLOCK_TIMEOUT`]; after [`MAX_ERRORS`] timeouts or bad blocks in a row
+//! the transfer is cancelled.
+//!
+//! Kernel log output to COM1 during a transfer reaches the sender, which
+//! ignores it between blocks.
+
+use alloc::boxed::Box;
+use core::fmt;
+use core::time::Duration;
+use futures_util::stream::Stream;
+
+use super::{SerialStream, SERIAL1};
+use crate::task::futures::StreamExt;
+use crate::task::timer;
+
+/// Start of a 128-byte block.
+const SOH: u8 = 0x01;
+/// Start of a 1024-byte block.
+const STX: u8 = 0x02;
+/// End of the file.
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+/// Cancel; sent twice.
+const CAN: u8 = 0x18;
+/// Asks the sender to start, with CRC-16 blocks.
+const START: u8 = b'C';
+
+/// Longest block.
+const MAX_BLOCK: usize = 1024;
+
+/// How long to wait for each block before asking for it again.
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Timeouts or bad blocks in a row before the transfer is cancelled.
+pub const MAX_ERRORS: u32 = 10;
+
+/// Why a transfer failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XferError {
+    /// Nothing arrived in time, [`MAX_ERRORS`] times in a row.
+    Timeout,
+    /// Blocks kept failing their checks.
+    Corrupted,
+    /// The file doesn't fit the receive buffer.
+    TooLarge { size: usize, limit: usize },
+    /// A block arrived out of order.
+    OutOfSequence { expected: u8, got: u8 },
+    /// The sender cancelled.
+    Cancelled,
+    /// The input stream ended.
+    Disconnected,
+}
+
+impl fmt::Display for XferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XferError::Timeout => f.write_str("timed out"),
+            XferError::Corrupted => f.write_str("too many bad blocks"),
+            XferError::TooLarge { size, limit } => {
+                write!(f, "{} bytes is over the {} byte limit", size, limit)
+            }
+            XferError::OutOfSequence { expected, got } => {
+                write!(f, "got block {} instead of {}", got, expected)
+            }
+            XferError::Cancelled => f.write_str("cancelled by sender"),
+            XferError::Disconnected => f.write_str("input closed"),
+        }
+    }
+}
+
+/// Receive a file from COM1 into `buf`, returning its length.
+///
+/// Takes the serial stream, so it runs in place of whatever reads lines from
+/// it (see [`SerialLines::bytes`](super::SerialLines::bytes)).
+pub async fn receive(stream: &mut SerialStream, buf: &mut [u8]) -> Result<usize, XferError> {
+    let mut send = |byte: u8| SERIAL1.get().lock().send_raw(byte);
+    receive_from(stream, &mut send, buf, BLOCK_TIMEOUT).await
+}
+
+/// What a read of one frame found.
+enum Frame {
+    Block { number: u8, data: Box<[u8; MAX_BLOCK]>, len: usize },
+    /// A block with a bad CRC or block number.
+    Bad,
+    Eot,
+    Cancel,
+}
+
+/// Both directions of the line, with the block timeout.
+struct Link<'a, S> {
+    rx: &'a mut S,
+    tx: &'a mut dyn FnMut(u8),
+    timeout: Duration,
+}
+
+impl<S: Stream<Item = u8> + Unpin> Link<'_, S> {
+    async fn byte(&mut self) -> Result<u8, XferError> {
+        match timer::timeout(self.timeout, self.rx.next()).await {
+            Ok(Some(byte)) => Ok(byte),
+            Ok(None) => Err(XferError::Disconnected),
+            Err(timer::Elapsed) => Err(XferError::Timeout),
+        }
+    }
+
+    fn send(&mut self, byte: u8) {
+        (self.tx)(byte);
+    }
+
+    fn cancel(&mut self) {
+        self.send(CAN);
+        self.send(CAN);
+    }
+
+    /// Read the next frame, skipping noise before its first byte.
+    async fn frame(&mut self) -> Result<Frame, XferError> {
+        let len = loop {
+            match self.byte().await? {
+                SOH => break 128,
+                STX => break MAX_BLOCK,
+                EOT => return Ok(Frame::Eot),
+                CAN => return Ok(Frame::Cancel),
+                _ => {}
+            }
+        };
+        let number = self.byte().await?;
+        let complement = self.byte().await?;
+        let mut data = Box::new([0; MAX_BLOCK]);
+        for byte in &mut data[..len] {
+            *byte = self.byte().await?;
+        }
+        let crc = u16::from_be_bytes([self.byte().await?, self.byte().await?]);
+        if number != !complement || crc != crc16(&data[..len]) {
+            return Ok(Frame::Bad);
+        }
+        Ok(Frame::Block { number, data, len })
+    }
+}
+
+/// Receive a file from `rx` into `buf`, sending replies through `tx`.
+pub(crate) async fn receive_from<S: Stream<Item = u8> + Unpin>(
+    rx: &mut S,
+    tx: &mut dyn FnMut(u8),
+    buf: &mut [u8],
+    block_timeout: Duration,
+) -> Result<usize, XferError> {
+    let mut link = Link { rx, tx, timeout: block_timeout };
+    let limit = buf.len();
+    // From block 0, if the sender sent one.
+    let mut size = None;
+    let mut len = 0;
+    let mut started = false;
+    let mut expected = 1u8;
+    let mut errors = 0;
+    link.send(START);
+    loop {
+        let error = match link.frame().await {
+            Ok(Frame::Block { number, data, len: block_len }) => {
+                errors = 0;
+                if number == 0 && expected == 1 {
+                    // The header, or a resend of it after a lost ACK.
+                    if !started {
+                        started = true;
+                        size = parse_header(&data[..block_len]);
+                        if data[0] == 0 {
+                            // An empty batch.
+                            link.send(ACK);
+                            return Ok(0);
+                        }
+                    }
+                    if let Some(size) = size
+                        && size > limit
+                    {
+                        link.cancel();
+                        return Err(XferError::TooLarge { size, limit });
+                    }
+                    link.send(ACK);
+                    link.send(START);
+                    continue;
+                }
+                if started && number == expected.wrapping_sub(1) {
+                    // Our ACK got lost; the sender repeated the block.
+                    link.send(ACK);
+                    continue;
+                }
+                if number != expected {
+                    link.cancel();
+                    return Err(XferError::OutOfSequence { expected, got: number });
+                }
+                let wanted = match size {
+                    Some(size) => block_len.min(size - len),
+                    None if len + block_len > limit => {
+                        link.cancel();
+                        return Err(XferError::TooLarge { size: len + block_len, limit });
+                    }
+                    None => block_len,
+                };
+                buf[len..len + wanted].copy_from_slice(&data[..wanted]);
+                len += wanted;
+                started = true;
+                expected = expected.wrapping_add(1);
+                link.send(ACK);
+                continue;
+            }
+            Ok(Frame::Eot) if started => break,
+            Ok(Frame::Cancel) => return Err(XferError::Cancelled),
+            // A bad block, or an EOT with nothing to end.
+            Ok(Frame::Bad | Frame::Eot) => XferError::Corrupted,
+            Err(XferError::Timeout) => XferError::Timeout,
+            Err(err) => return Err(err),
+        };
+        errors += 1;
+        if errors >= MAX_ERRORS {
+            link.cancel();
+            return Err(error);
+        }
+        link.send(if started { NAK } else { START });
+    }
+    link.send(ACK);
+    // YMODEM ends the batch with an empty block 0; XMODEM has no batch.
+    if size.is_some() {
+        link.send(START);
+        if let Ok(Frame::Block { number: 0, .. }) = link.frame().await {
+            link.send(ACK);
+        }
+    }
+    Ok(len)
+}
+
+/// Return the size from a block 0: the file name, a NUL, then the size in
+/// decimal, optionally followed by more fields.
+fn parse_header(block: &[u8]) -> Option<usize> {
+    let name_end = block.iter().position(|&byte| byte == 0)?;
+    let fields = &block[name_end + 1..];
+    let digits = fields.iter().take_while(|byte| byte.is_ascii_digit()).count();
+    core::str::from_utf8(&fields[..digits]).ok()?.parse().ok()
+}
+
+/// CRC-16/XMODEM: polynomial 0x1021, starting at 0.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        let mut crc = crc ^ (u16::from(byte) << 8);
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+/// Build a 128-byte block, padding `data` with SUB as XMODEM does.
+#[cfg(test)]
+fn block(number: u8, data: &[u8]) -> alloc::vec::Vec<u8> {
+    let mut padded = [0x1a; 128];
+    padded[..data.len()].copy_from_slice(data);
+    let mut frame = alloc::vec![SOH, number, !number];
+    frame.extend_from_slice(&padded);
+    frame.extend_from_slice(&crc16(&padded).to_be_bytes());
+    frame
+}
+
+/// Build a block 0 for `name` of `size` bytes; an empty name ends a batch.
+#[cfg(test)]
+fn header(name: &str, size: usize) -> alloc::vec::Vec<u8> {
+    let mut fields = alloc::vec::Vec::new();
+    if !name.is_empty() {
+        fields.extend_from_slice(alloc::format!("{}\0{} 0", name, size).as_bytes());
+    }
+    let mut frame = block(0, &[]);
+    frame[3..131].fill(0);
+    frame[3..3 + fields.len()].copy_from_slice(&fields);
+    let crc = crc16(&frame[3..131]);
+    frame[131..].copy_from_slice(&crc.to_be_bytes());
+    frame
+}
+
+/// Outcome of [`transfer`]: the result, what was received, and the replies.
+#[cfg(test)]
+type Transfer = (Result<usize, XferError>, alloc::vec::Vec<u8>, alloc::vec::Vec<u8>);
+
+/// Feed `script` through a channel to a receiver with a `limit` byte buffer.
+/// With `hang_up`, the input ends after the script; otherwise it stays open.
+#[cfg(test)]
+fn transfer(script: &[u8], limit: usize, hang_up: bool) -> Transfer {
+    use crate::task::simple_executor::SimpleExecutor;
+    use crate::task::Task;
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    let (sender, mut receiver) = crate::task::channel(script.len().max(1));
+    for &byte in script {
+        sender.try_send(byte).unwrap();
+    }
+    let sender = (!hang_up).then_some(sender);
+    let outcome = Rc::new(RefCell::new(None));
+    let result = outcome.clone();
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async move {
+        let mut buf = alloc::vec![0; limit];
+        let mut replies = alloc::vec::Vec::new();
+        let mut tx = |byte: u8| replies.push(byte);
+        let timeout = Duration::from_millis(30);
+        let received = receive_from(&mut receiver, &mut tx, &mut buf, timeout).await;
+        *result.borrow_mut() = Some((received, buf, replies));
+    }));
+    executor.run();
+    drop(sender);
+    // The finished task dropped its handle.
+    Rc::into_inner(outcome).unwrap().into_inner().unwrap()
+}
+
+#[test_case]
+fn test_crc16() {
+    assert_eq!(crc16(b"123456789"), 0x31c3);
+    assert_eq!(crc16(&[]), 0);
+    assert_eq!(parse_header(b"blob\x00200 0\x00"), Some(200));
+    assert_eq!(parse_header(b"blob\x00\x00"), None);
+}
+
+#[test_case]
+fn test_good_transfer() {
+    let data: alloc::vec::Vec<u8> = (0..200).map(|i| i as u8).collect();
+    let mut script = header("blob", 200);
+    script.extend(block(1, &data[..128]));
+    script.extend(block(2, &data[128..]));
+    script.push(EOT);
+    script.extend(header("", 0));
+    let (result, buf, replies) = transfer(&script, 256, false);
+    assert_eq!(result, Ok(200));
+    assert_eq!(buf[..200], data[..]);
+    assert_eq!(replies, [START, ACK, START, ACK, ACK, ACK, START, ACK]);
+}
+
+#[test_case]
+fn test_corrupted_block_is_resent() {
+    let data = [0x5a; 128];
+    let mut bad = block(1, &data);
+    bad[10] ^= 0xff;
+    let mut script = header("blob", 130);
+    script.extend(bad);
+    script.extend(block(1, &data));
+    // The sender missed our ACK and repeats the block.
+    script.extend(block(1, &data));
+    script.extend(block(2, b"ab"));
+    script.push(EOT);
+    script.extend(header("", 0));
+    let (result, buf, replies) = transfer(&script, 256, false);
+    assert_eq!(result, Ok(130));
+    assert_eq!(buf[..128], data);
+    assert_eq!(buf[128..130], *b"ab");
+    assert_eq!(replies, [START, ACK, START, NAK, ACK, ACK, ACK, ACK, START, ACK]);
+}
+
+#[test_case]
+fn test_truncated_transfer_times_out() {
+    let mut script = header("blob", 200);
+    script.extend(&block(1, &[1; 128])[..60]);
+    let (result, _, replies) = transfer(&script, 256, false);
+    assert_eq!(result, Err(XferError::Timeout));
+    let naks = replies.iter().filter(|&&byte| byte == NAK).count();
+    assert_eq!(naks, MAX_ERRORS as usize - 1);
+    assert!(replies.ends_with(&[CAN, CAN]), "{:?}", replies);
+
+    let (result, _, _) = transfer(&script, 256, true);
+    assert_eq!(result, Err(XferError::Disconnected));
+}
+
+#[test_case]
+fn test_size_limit_and_sequence() {
+    let (result, _, replies) = transfer(&header("big", 5000), 256, false);
+    assert_eq!(result, Err(XferError::TooLarge { size: 5000, limit: 256 }));
+    assert_eq!(replies, [START, CAN, CAN]);
+
+    // Plain XMODEM: no header, so the limit applies as blocks arrive.
+    let mut script = block(1, b"x");
+    script.extend(block(2, b"y"));
+    let (result, _, _) = transfer(&script, 200, false);
+    assert_eq!(result, Err(XferError::TooLarge { size: 256, limit: 200 }));
+
+    let mut script = header("blob", 300);
+    script.extend(block(2, b"skipped one"));
+    let (result, _, _) = transfer(&script, 512, false);
+    assert_eq!(result, Err(XferError::OutOfSequence { expected: 1, got: 2 }));
+}
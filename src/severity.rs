@@ -0,0 +1,134 @@
+//! Severity-tagged messages: [`kerror!`](crate::kerror),
+//! [`kwarn!`](crate::kwarn) and [`kinfo!`](crate::kinfo).
+//!
+//! Each prints one line starting with its tag, `[ERROR]`, `[WARN]` or
+//! `[INFO]`, on the [console](crate::console) `print!` would use. On the
+//! screen the tag is in the level's [`Color`] and the message in the
+//! writer's current colors; COM1, the debug console and the
+//! [kernel log](crate::klog) get the same line in plain text. The whole
+//! screen line is written under one hold of the writer's lock, which keeps
+//! interrupts off, so nothing a handler prints can land inside it.
+//!
+//! The `log-level-warn` feature leaves out [`kinfo!`](crate::kinfo), and
+//! `log-level-error` leaves out [`kwarn!`](crate::kwarn) as well. The check
+//! is on a constant, so the left-out calls compile to nothing.
+
+use core::fmt;
+
+use crate::console;
+use crate::vga_buffer::Color;
+
+/// How severe a message is. Later levels are more verbose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+}
+
+/// The most verbose level that is printed, set by the `log-level-*`
+/// features.
+pub const MAX_LEVEL: Level = if cfg!(feature = "log-level-error") {
+    Level::Error
+} else if cfg!(feature = "log-level-warn") {
+    Level::Warn
+} else {
+    Level::Info
+};
+
+impl Level {
+    /// Return the tag messages at this level start with.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Level::Error => "[ERROR]",
+            Level::Warn => "[WARN]",
+            Level::Info => "[INFO]",
+        }
+    }
+
+    /// Return the color of the tag on the screen.
+    pub fn color(self) -> Color {
+        match self {
+            Level::Error => Color::LightRed,
+            Level::Warn => Color::Yellow,
+            Level::Info => Color::LightGreen,
+        }
+    }
+
+    /// Return whether messages at this level are printed.
+    pub const fn enabled(self) -> bool {
+        self as u8 <= MAX_LEVEL as u8
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.tag())
+    }
+}
+
+/// Print `args` as a line at `level`.
+///
+/// This function is not meant to be called directly. It is used by the
+/// [`kerror!`](crate::kerror), [`kwarn!`](crate::kwarn) and
+/// [`kinfo!`](crate::kinfo) macros.
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) {
+    console::print_tagged_to(console::current(), level.tag(), level.color(), args);
+}
+
+/// Prints a line tagged `[ERROR]`.
+#[macro_export]
+macro_rules! kerror {
+    ($($arg:tt)*) => {
+        if $crate::severity::Level::Error.enabled() {
+            $crate::severity::_log($crate::severity::Level::Error, format_args!($($arg)*));
+        }
+    };
+}
+
+/// Prints a line tagged `[WARN]`, unless the `log-level-error` feature is
+/// on.
+#[macro_export]
+macro_rules! kwarn {
+    ($($arg:tt)*) => {
+        if $crate::severity::Level::Warn.enabled() {
+            $crate::severity::_log($crate::severity::Level::Warn, format_args!($($arg)*));
+        }
+    };
+}
+
+/// Prints a line tagged `[INFO]`, unless a `log-level-*` feature is on.
+#[macro_export]
+macro_rules! kinfo {
+    ($($arg:tt)*) => {
+        if $crate::severity::Level::Info.enabled() {
+            $crate::severity::_log($crate::severity::Level::Info, format_args!($($arg)*));
+        }
+    };
+}
+
+#[test_case]
+fn test_levels() {
+    assert!(Level::Error < Level::Warn && Level::Warn < Level::Info);
+    assert!(Level::Error.enabled());
+    assert_eq!(Level::Info.enabled(), MAX_LEVEL == Level::Info);
+}
+
+#[test_case]
+fn test_tag_is_colored() {
+    let before = {
+        let mut writer = crate::vga_buffer::WRITER.get().lock();
+        writer.write_string("\n");
+        writer.color_code()
+    };
+    crate::kerror!("disk {} failed", 3);
+    let writer = crate::vga_buffer::WRITER.get().lock();
+    let row = writer.height() - 2;
+    assert!(writer.row_text(row).unwrap().starts_with(b"[ERROR] disk 3 failed"));
+    let (_, foreground, background) = writer.char_at(row, 1).unwrap();
+    assert_eq!((foreground, background), (Color::LightRed, before.background()));
+    let (_, foreground, _) = writer.char_at(row, 8).unwrap();
+    assert_eq!(foreground, before.foreground());
+    assert_eq!(writer.color_code(), before);
+}
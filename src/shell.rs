@@ -0,0 +1,1080 @@
+//! Interactive shell.
+//!
+//! [`run`] reads lines from the keyboard and from COM1 and runs each one as
+//! a command, answering on the same side it came from. The keyboard is only
+//! read while the [console](crate::console) includes the screen. A line is
+//! split into words at spaces (double quotes group words); the first word
+//! names the command and the rest become its [`Args`].
+//!
+//! Commands are plain functions writing to a `dyn fmt::Write`, so the same
+//! command serves the screen, the serial port and tests. The built-ins are
+//! registered on first use; other modules can add their own with
+//! [`register`].
+//!
+//! On the keyboard, Tab completes command names, and paths after `cat` and
+//! `ls` (see [`complete`]).
+//!
+//! `recv` is the exception: it reads raw bytes from COM1 rather than
+//! writing output (see [`serial::xfer`]), so [`run`] handles it itself on
+//! the serial side.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::arch::port;
+use crate::task::futures::{race, Either, StreamExt};
+use crate::task::keyboard;
+use crate::vga_buffer::WRITER;
+use crate::console::{self, Console};
+use crate::{
+    acpi, allocator, crashlog, debug, fs, klog, memory, power, profile, serial, statusbar, time,
+};
+
+/// Printed before each command line.
+pub const PROMPT: &str = "> ";
+
+/// Largest file `recv` accepts, in bytes.
+pub const RECV_LIMIT: usize = 16 * 1024;
+
+/// Why a command failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShellError {
+    /// The arguments don't fit the command; holds the expected usage.
+    Usage(&'static str),
+    /// An argument has the right place but a bad value.
+    InvalidArgument(String),
+    /// A path names nothing.
+    NotFound(String),
+    /// Writing the output failed.
+    Output,
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShellError::Usage(usage) => write!(f, "usage: {}", usage),
+            ShellError::InvalidArgument(arg) => write!(f, "invalid argument: {}", arg),
+            ShellError::NotFound(path) => write!(f, "no such file or directory: {}", path),
+            ShellError::Output => f.write_str("output error"),
+        }
+    }
+}
+
+impl From<fmt::Error> for ShellError {
+    fn from(_: fmt::Error) -> Self {
+        ShellError::Output
+    }
+}
+
+/// The arguments after the command name.
+pub struct Args<'a> {
+    words: Vec<&'a str>,
+}
+
+impl<'a> Args<'a> {
+    /// Return the number of arguments.
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    /// Return whether there are no arguments.
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Return argument `index`, counting from 0.
+    pub fn get(&self, index: usize) -> Option<&'a str> {
+        self.words.get(index).copied()
+    }
+
+    /// Iterate over the arguments.
+    pub fn iter(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.words.iter().copied()
+    }
+}
+
+/// Signature of a command.
+pub type CommandFn = fn(&Args, &mut dyn fmt::Write) -> Result<(), ShellError>;
+
+/// A registered command.
+#[derive(Clone, Copy)]
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: CommandFn,
+}
+
+static COMMANDS: OnceCell<Mutex<Vec<Command>>> = OnceCell::uninit();
+
+/// Run `f` on the command table, registering the built-ins first if needed.
+fn with_commands<R>(f: impl FnOnce(&mut Vec<Command>) -> R) -> R {
+    let commands = COMMANDS.get_or_init(|| Mutex::new(builtins()));
+    interrupts::without_interrupts(|| f(&mut commands.lock()))
+}
+
+/// Add a command, replacing any existing one with the same name.
+pub fn register(name: &'static str, help: &'static str, run: CommandFn) {
+    let command = Command { name, help, run };
+    with_commands(|commands| match commands.iter_mut().find(|c| c.name == name) {
+        Some(existing) => *existing = command,
+        None => commands.push(command),
+    });
+}
+
+/// Split `line` into words at spaces. Double quotes group words, and an
+/// unterminated quote runs to the end of the line.
+fn split(line: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let end = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                words.push(&quoted[..end]);
+                (end + 2).min(rest.len())
+            }
+            None => {
+                let end = rest.find(' ').unwrap_or(rest.len());
+                words.push(&rest[..end]);
+                end
+            }
+        };
+        rest = rest[end..].trim_start();
+    }
+    words
+}
+
+/// Run the command on `line`, writing its output and any error to `out`.
+///
+/// Blank lines do nothing. Unknown commands get a list of registered
+/// commands sharing a prefix with the name.
+pub fn execute(line: &str, out: &mut dyn fmt::Write) -> fmt::Result {
+    let mut words = split(line);
+    if words.is_empty() {
+        return Ok(());
+    }
+    let name = words.remove(0);
+    let args = Args { words };
+    // Copy the command out so it runs without the table locked, free to
+    // register commands itself.
+    let command = with_commands(|commands| commands.iter().find(|c| c.name == name).copied());
+    let Some(command) = command else {
+        writeln!(out, "unknown command: {}", name)?;
+        let suggestions = suggestions(name);
+        if !suggestions.is_empty() {
+            writeln!(out, "did you mean: {}?", suggestions.join(", "))?;
+        }
+        return Ok(());
+    };
+    match (command.run)(&args, out) {
+        Ok(()) => Ok(()),
+        Err(ShellError::Output) => Err(fmt::Error),
+        Err(err) => writeln!(out, "{}: {}", name, err),
+    }
+}
+
+/// Return the commands whose names start with `name`, or failing that,
+/// share at least its first two characters.
+fn suggestions(name: &str) -> Vec<&'static str> {
+    with_commands(|commands| {
+        let names = || commands.iter().map(|c| c.name);
+        let extending: Vec<_> = names().filter(|c| c.starts_with(name)).collect();
+        if !extending.is_empty() {
+            return extending;
+        }
+        names()
+            .filter(|c| {
+                let common = c.bytes().zip(name.bytes()).take_while(|(a, b)| a == b).count();
+                common >= 2
+            })
+            .collect()
+    })
+}
+
+/// Return the ways to finish the word of `line` starting at `start`: a
+/// command name for the first word, or a ramdisk or in-memory path for the
+/// first argument of `cat` or `ls`. Directories end in `/`.
+pub fn complete(line: &str, start: usize) -> Vec<String> {
+    let word = &line[start..];
+    let mut choices: Vec<String> = match split(&line[..start])[..] {
+        [] => with_commands(|commands| {
+            commands
+                .iter()
+                .filter(|c| c.name.starts_with(word))
+                .map(|c| String::from(c.name))
+                .collect()
+        }),
+        ["cat" | "ls"] => complete_path(word),
+        _ => Vec::new(),
+    };
+    choices.sort_unstable();
+    choices.dedup();
+    choices
+}
+
+/// Return the paths that `word` is the start of.
+fn complete_path(word: &str) -> Vec<String> {
+    let (dir, prefix) = word.split_at(word.rfind('/').map_or(0, |i| i + 1));
+    let mut choices = Vec::new();
+    if let Some(entries) = fs::root().and_then(|root| root.read_dir(dir)) {
+        for entry in entries.filter(|entry| entry.name().starts_with(prefix)) {
+            let slash = if entry.kind() == fs::EntryKind::Directory { "/" } else { "" };
+            choices.push(alloc::format!("{}{}{}", dir, entry.name(), slash));
+        }
+    }
+    // In-memory files have no directories.
+    if !dir.contains('/') || dir == "/" {
+        for (name, _) in fs::mem::list() {
+            if name.starts_with(prefix) {
+                choices.push(alloc::format!("{}{}", dir, name));
+            }
+        }
+    }
+    choices
+}
+
+/// Writes shell output to the VGA screen, if there is one.
+struct VgaOut;
+
+impl fmt::Write for VgaOut {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Ok(writer) = WRITER.try_get() {
+            writer.lock().write_string(s);
+        }
+        Ok(())
+    }
+}
+
+/// Writes shell output to COM1 for a terminal: newlines become CRLF and a
+/// form feed becomes the ANSI clear-screen sequence.
+struct SerialOut;
+
+impl fmt::Write for SerialOut {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut port = serial::SERIAL1.get().lock();
+        for c in s.chars() {
+            match c {
+                '\n' => port.write_str("\r\n")?,
+                '\x0c' => port.write_str("\x1b[2J\x1b[H")?,
+                c => port.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the shell on the keyboard and COM1 until both inputs end.
+///
+/// The keyboard is left unread while the console is serial only, so typing
+/// there does nothing until `console vga` or `console both`. Without a
+/// keyboard the shell says so once and reads COM1 alone.
+///
+/// Opens the keyboard and serial line streams, so it can only run once.
+pub async fn run() {
+    let mut keyboard_lines = keyboard::lines().with_completer(complete);
+    let mut serial_lines = serial::lines();
+    if !keyboard::is_present() {
+        let _ = writeln!(VgaOut, "{}", NO_KEYBOARD);
+        let _ = writeln!(SerialOut, "{}", NO_KEYBOARD);
+    }
+    let _ = VgaOut.write_str(PROMPT);
+    let _ = SerialOut.write_str(PROMPT);
+    loop {
+        let next = if reads_keyboard() {
+            race(keyboard_lines.next(), serial_lines.next()).await
+        } else {
+            Either::Right(serial_lines.next().await)
+        };
+        match next {
+            Either::Left(Some(line)) => {
+                let _ = execute(&line, &mut VgaOut);
+                let _ = VgaOut.write_str(PROMPT);
+            }
+            Either::Right(Some(line)) => {
+                match split(&line)[..] {
+                    ["recv", name] => receive_file(name, serial_lines.bytes()).await,
+                    _ => {
+                        let _ = execute(&line, &mut SerialOut);
+                    }
+                }
+                let _ = SerialOut.write_str(PROMPT);
+            }
+            Either::Left(None) | Either::Right(None) => break,
+        }
+    }
+}
+
+/// Printed when the shell starts without a keyboard.
+const NO_KEYBOARD: &str = "shell: no keyboard found; type commands on COM1";
+
+/// Return whether input is read from the keyboard as well as COM1: only
+/// with the screen as console, and a keyboard to read.
+fn reads_keyboard() -> bool {
+    console::selected().has_vga() && keyboard::is_present()
+}
+
+/// Receive a file over COM1 into the in-memory file table as `name`.
+async fn receive_file(name: &str, stream: &mut serial::SerialStream) {
+    let _ = writeln!(SerialOut, "recv: send with YMODEM, up to {} bytes", RECV_LIMIT);
+    let mut buf = alloc::vec![0; RECV_LIMIT];
+    let _ = match serial::xfer::receive(stream, &mut buf).await {
+        Ok(len) => {
+            buf.truncate(len);
+            match fs::mem::insert(name, buf) {
+                Ok(()) => writeln!(SerialOut, "recv: {} bytes into {}", len, name),
+                Err(err) => writeln!(SerialOut, "recv: {}", err),
+            }
+        }
+        Err(err) => writeln!(SerialOut, "recv: {}", err),
+    };
+}
+
+fn builtins() -> Vec<Command> {
+    let commands: [(&'static str, &'static str, CommandFn); 33] = [
+        ("help", "list commands", help),
+        ("clear", "clear the screen", clear),
+        ("date", "show the date and time (UTC)", date),
+        ("uptime", "time since boot", uptime),
+        ("boot", "show init stage timings", boot),
+        ("version", "show the kernel version and build", version),
+        ("statusbar", "show or toggle the status bar: statusbar [on|off]", statusbar),
+        ("console", "show or switch the console: console [vga|serial|both]", console_cmd),
+        ("mem", "heap, interrupt arena and frame usage", mem),
+        ("scrub", "integrity checks: scrub [now]", scrub),
+        ("selftest", "run subsystem self-tests: selftest [name]", selftest),
+        ("heapcheck", "check heap canaries (heap-debug feature)", heapcheck),
+        ("memmap", "physical memory map and reserved ranges", memmap),
+        ("irqstats", "interrupts and handler time per IRQ line, dropped input", irqstats),
+        ("irqtrace", "trace interrupt vectors: irqtrace [on|off vector | clear | n]", irqtrace),
+        ("watch", "hardware watchpoints: watch [hex-addr len | off slot]", watch),
+        ("acpi", "ACPI tables, CPUs and interrupt overrides", acpi_tables),
+        ("tasks", "list executor tasks", tasks),
+        ("estat", "executor counters (task-stats feature)", estat),
+        ("dmesg", "show recent kernel output", dmesg),
+        ("lastcrash", "show the crash the previous boot recorded", lastcrash),
+        ("loglevel", "show or set the log level: loglevel [n]", loglevel),
+        ("ports", "show or control the port trace: ports [on|off|clear]", ports),
+        ("profile", "sampling profiler: profile [start|stop|report [n]]", profile),
+        ("echo", "print the arguments", echo),
+        ("ls", "list a ramdisk directory: ls [dir]", ls),
+        ("cat", "print files: cat file...", cat),
+        ("hexdump", "print a file in hex: hexdump file", hexdump),
+        ("recv", "receive a file over serial with YMODEM: recv name", recv),
+        ("replay", "keyboard record/replay: replay [record|stop|dump|play file]", replay),
+        ("keymap", "show or load the keymap: keymap [file|builtin]", keymap),
+        ("reboot", "reset the machine", reboot),
+        ("shutdown", "power the machine off", shutdown),
+    ];
+    commands
+        .into_iter()
+        .map(|(name, help, run)| Command { name, help, run })
+        .collect()
+}
+
+fn help(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    let commands = with_commands(|commands| commands.clone());
+    for command in commands {
+        writeln!(out, "{:<10} {}", command.name, command.help)?;
+    }
+    Ok(())
+}
+
+fn clear(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    out.write_char('\x0c')?;
+    Ok(())
+}
+
+fn date(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    match time::wallclock::now() {
+        Some(now) => writeln!(out, "{} UTC", now)?,
+        None => writeln!(out, "clock not set")?,
+    }
+    Ok(())
+}
+
+fn uptime(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    let uptime = time::uptime();
+    writeln!(out, "up {}.{:02}s", uptime.as_secs(), uptime.subsec_millis() / 10)?;
+    Ok(())
+}
+
+fn statusbar(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    match (args.get(0), args.len()) {
+        (None, _) => {
+            let state = if statusbar::is_enabled() { "on" } else { "off" };
+            writeln!(out, "status bar {}", state)?;
+        }
+        (Some("on"), 1) => statusbar::enable(),
+        (Some("off"), 1) => statusbar::disable(),
+        (Some(arg), 1) => return Err(ShellError::InvalidArgument(arg.into())),
+        _ => return Err(ShellError::Usage("statusbar [on|off]")),
+    }
+    Ok(())
+}
+
+fn console_cmd(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    match (args.get(0), args.len()) {
+        (None, _) => writeln!(out, "console {}", console::selected())?,
+        (Some(name), 1) => {
+            let wanted = Console::parse(name)
+                .ok_or_else(|| ShellError::InvalidArgument(name.into()))?;
+            if let Err(err) = console::select(wanted) {
+                writeln!(out, "console: {}", err)?;
+            }
+        }
+        _ => return Err(ShellError::Usage("console [vga|serial|both]")),
+    }
+    Ok(())
+}
+
+fn mem(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    let heap = allocator::heap_stats();
+    let frames = memory::frame_stats();
+    writeln!(out, "heap:   {} / {} bytes used, {} free", heap.used, heap.size, heap.free)?;
+    writeln!(
+        out,
+        "frames: {} / {} allocated ({} KiB usable)",
+        frames.allocated,
+        frames.usable,
+        frames.usable * 4
+    )?;
+    let arena = allocator::arena_stats();
+    writeln!(
+        out,
+        "arena:  {} / {} bytes used, {} live (interrupt allocations)",
+        arena.used,
+        allocator::arena::ARENA_SIZE,
+        arena.live
+    )?;
+    Ok(())
+}
+
+fn heapcheck(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    match allocator::verify_heap() {
+        Some(check) => writeln!(out, "{}", check)?,
+        None => writeln!(out, "blocks aren't tracked without the heap-debug feature")?,
+    }
+    Ok(())
+}
+
+fn memmap(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    memory::dump_memory_map(out)?;
+    Ok(())
+}
+
+fn watch(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    const USAGE: &str = "watch [hex-addr len | off slot]";
+    match (args.get(0), args.get(1), args.len()) {
+        (None, _, _) => {
+            for (slot, watch) in debug::watchpoints().iter() {
+                writeln!(
+                    out,
+                    "{}: {:#x} {} bytes {:?}, last {:#x}",
+                    slot.index(),
+                    watch.addr.as_u64(),
+                    watch.len,
+                    watch.kind,
+                    watch.last
+                )?;
+            }
+            writeln!(out, "{} hits", debug::hits())?;
+        }
+        (Some("off"), Some(slot), 2) => {
+            let index: usize =
+                slot.parse().map_err(|_| ShellError::InvalidArgument(slot.into()))?;
+            let armed = debug::watchpoints();
+            let (slot, _) = armed
+                .iter()
+                .find(|(armed, _)| armed.index() == index)
+                .ok_or_else(|| ShellError::InvalidArgument(slot.into()))?;
+            debug::unwatch(*slot);
+        }
+        (Some(addr), Some(len), 2) => {
+            let digits = addr.strip_prefix("0x").unwrap_or(addr);
+            let addr = u64::from_str_radix(digits, 16)
+                .ok()
+                .and_then(|addr| x86_64::VirtAddr::try_new(addr).ok())
+                .ok_or_else(|| ShellError::InvalidArgument(addr.into()))?;
+            let len = len.parse().map_err(|_| ShellError::InvalidArgument(len.into()))?;
+            match debug::watch(addr, len, debug::WatchKind::Write) {
+                Ok(slot) => writeln!(out, "watchpoint {} set", slot.index())?,
+                Err(err) => writeln!(out, "watch: {}", err)?,
+            }
+        }
+        _ => return Err(ShellError::Usage(USAGE)),
+    }
+    Ok(())
+}
+
+fn acpi_tables(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    acpi::dump(out)?;
+    Ok(())
+}
+
+fn irqstats(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    use crate::interrupts::{irq_counts, irq_name, irq_times};
+    use crate::time::cycles_to_micros;
+
+    let times = irq_times();
+    for (irq, count) in irq_counts().into_iter().enumerate() {
+        let name = match irq_name(irq) {
+            Some(name) => name,
+            None if count > 0 => "-",
+            None => continue,
+        };
+        write!(out, "irq {:<2} {:<9} {:>8}", irq, name, count)?;
+        if let Some(time) = times.map(|times| times[irq])
+            && let (Some(total), Some(max)) =
+                (cycles_to_micros(time.total_cycles), cycles_to_micros(time.max_cycles))
+        {
+            write!(out, "  total {}us max {}us", total, max)?;
+        }
+        writeln!(out)?;
+    }
+    writeln!(out, "dropped scancodes: {}", keyboard::dropped_scancodes())?;
+    let ps2 = keyboard::stats();
+    writeln!(
+        out,
+        "keyboard: hot-plugs {} self-test failures {} overruns {} reinits {}/{} failed {}",
+        ps2.hot_plugs,
+        ps2.self_test_failures,
+        ps2.overruns,
+        ps2.reinits,
+        ps2.reinits_scheduled,
+        ps2.reinit_failures
+    )?;
+    writeln!(out, "ps/2 status: {}", keyboard::controller_status())?;
+    writeln!(out, "ps/2 keyboard: {}", keyboard::info())?;
+    writeln!(out, "dropped serial bytes: {}", serial::dropped_bytes())?;
+    Ok(())
+}
+
+fn scrub(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    use crate::scrub;
+
+    let report = match (args.get(0), args.len()) {
+        (None, _) => scrub::last_report(),
+        (Some("now"), 1) => match scrub::run_now() {
+            Some(report) => Some(report),
+            None => {
+                writeln!(out, "a pass is already running")?;
+                return Ok(());
+            }
+        },
+        _ => return Err(ShellError::Usage("scrub [now]")),
+    };
+    match report {
+        Some(report) => write!(out, "{}", report)?,
+        None => writeln!(out, "no pass yet; try scrub now")?,
+    }
+    Ok(())
+}
+
+fn selftest(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    if args.len() > 1 {
+        return Err(ShellError::Usage("selftest [name]"));
+    }
+    write!(out, "{}", crate::selftest::run_matching(args.get(0).unwrap_or("")))?;
+    Ok(())
+}
+
+fn irqtrace(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    use crate::interrupts::trace;
+
+    const USAGE: &str = "irqtrace [on|off vector | clear | n]";
+    let vector = || {
+        let arg = args.get(1).unwrap_or_default();
+        arg.parse::<u8>().map_err(|_| ShellError::InvalidArgument(arg.into()))
+    };
+    match (args.get(0), args.len()) {
+        (None, _) => trace::dump(20, out)?,
+        (Some("on"), 2) => trace::enable(vector()?),
+        (Some("off"), 2) => trace::disable(vector()?),
+        (Some("clear"), 1) => trace::clear(),
+        (Some(n), 1) => {
+            let n = n.parse().map_err(|_| ShellError::InvalidArgument(n.into()))?;
+            trace::dump(n, out)?;
+        }
+        _ => return Err(ShellError::Usage(USAGE)),
+    }
+    Ok(())
+}
+
+fn version(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    writeln!(out, "{}", crate::version_info())?;
+    Ok(())
+}
+
+fn boot(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    write!(out, "{}", crate::init::report())?;
+    Ok(())
+}
+
+fn tasks(_args: &Args, mut out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    crate::task::executor::dump_tasks(&mut out)?;
+    Ok(())
+}
+
+fn estat(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    #[cfg(feature = "task-stats")]
+    {
+        match crate::task::executor::stats() {
+            Some(stats) => writeln!(out, "{}", stats)?,
+            None => writeln!(out, "executor not running")?,
+        }
+    }
+    #[cfg(not(feature = "task-stats"))]
+    writeln!(out, "executor counters need the task-stats feature")?;
+    Ok(())
+}
+
+fn dmesg(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    klog::dump(out)?;
+    Ok(())
+}
+
+fn lastcrash(_args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    if !crashlog::show_previous(out)? {
+        writeln!(out, "no crash recorded by the previous boot")?;
+    }
+    Ok(())
+}
+
+fn loglevel(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    match (args.get(0), args.len()) {
+        (None, _) => writeln!(out, "log level {}", klog::level())?,
+        (Some(level), 1) => {
+            let level = level
+                .parse()
+                .map_err(|_| ShellError::InvalidArgument(level.into()))?;
+            klog::set_level(level);
+        }
+        _ => return Err(ShellError::Usage("loglevel [n]")),
+    }
+    Ok(())
+}
+
+fn ports(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    match (args.get(0), args.len()) {
+        (None, _) => {
+            if !port::tracing() {
+                writeln!(out, "port trace off")?;
+            }
+            port::dump_trace(out)?;
+        }
+        (Some("on"), 1) => port::set_tracing(true),
+        (Some("off"), 1) => port::set_tracing(false),
+        (Some("clear"), 1) => port::clear_trace(),
+        (Some(arg), 1) => return Err(ShellError::InvalidArgument(arg.into())),
+        _ => return Err(ShellError::Usage("ports [on|off|clear]")),
+    }
+    Ok(())
+}
+
+fn profile(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    match (args.get(0), args.len()) {
+        (None, _) | (Some("report"), 1) => profile::report(10, out)?,
+        (Some("report"), 2) => {
+            let n = args.get(1).unwrap_or_default();
+            let n = n.parse().map_err(|_| ShellError::InvalidArgument(n.into()))?;
+            profile::report(n, out)?;
+        }
+        (Some("start"), 1) => profile::start(),
+        (Some("stop"), 1) => profile::stop(),
+        (Some(arg), 1) => return Err(ShellError::InvalidArgument(arg.into())),
+        _ => return Err(ShellError::Usage("profile [start|stop|report [n]]")),
+    }
+    Ok(())
+}
+
+fn echo(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    let words: Vec<&str> = args.iter().collect();
+    writeln!(out, "{}", words.join(" "))?;
+    Ok(())
+}
+
+/// Return the root filesystem, or an error naming `path` if none is mounted.
+fn root_fs(path: &str) -> Result<&'static fs::TarFs, ShellError> {
+    fs::root().ok_or_else(|| ShellError::NotFound(path.into()))
+}
+
+fn ls(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    if args.len() > 1 {
+        return Err(ShellError::Usage("ls [dir]"));
+    }
+    let path = args.get(0).unwrap_or("/");
+    let root = root_fs(path)?;
+    let entries = root.read_dir(path).ok_or_else(|| ShellError::NotFound(path.into()))?;
+    for entry in entries {
+        match entry.kind() {
+            fs::EntryKind::File => writeln!(out, "{:>8} {}", entry.len(), entry.name())?,
+            fs::EntryKind::Directory => writeln!(out, "{:>8} {}/", "-", entry.name())?,
+            fs::EntryKind::Symlink(target) => {
+                writeln!(out, "{:>8} {} -> {}", "-", entry.name(), target)?
+            }
+            fs::EntryKind::Other(_) => writeln!(out, "{:>8} {}?", "-", entry.name())?,
+        }
+    }
+    Ok(())
+}
+
+/// Run `f` on the contents of `path`: a ramdisk file, or failing that an
+/// in-memory one (see [`fs::mem`]).
+fn with_file<R>(path: &str, f: impl FnOnce(&[u8]) -> R) -> Result<R, ShellError> {
+    if let Some(file) = fs::root().and_then(|root| root.open(path)) {
+        return Ok(f(file.as_slice()));
+    }
+    let data = fs::mem::get(path).ok_or_else(|| ShellError::NotFound(path.into()))?;
+    Ok(f(&data))
+}
+
+fn cat(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    if args.is_empty() {
+        return Err(ShellError::Usage("cat file..."));
+    }
+    for path in args.iter() {
+        with_file(path, |data| -> fmt::Result {
+            // Invalid UTF-8 shows up as '?'.
+            for chunk in data.utf8_chunks() {
+                out.write_str(chunk.valid())?;
+                if !chunk.invalid().is_empty() {
+                    out.write_char('?')?;
+                }
+            }
+            Ok(())
+        })??;
+    }
+    Ok(())
+}
+
+fn hexdump(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    let (Some(path), 1) = (args.get(0), args.len()) else {
+        return Err(ShellError::Usage("hexdump file"));
+    };
+    with_file(path, |data| -> fmt::Result {
+        for (line, bytes) in data.chunks(16).enumerate() {
+            write!(out, "{:08x} ", line * 16)?;
+            for i in 0..16 {
+                match bytes.get(i) {
+                    Some(byte) => write!(out, " {:02x}", byte)?,
+                    None => out.write_str("   ")?,
+                }
+            }
+            out.write_str("  |")?;
+            for &byte in bytes {
+                let c = if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' };
+                out.write_char(c)?;
+            }
+            writeln!(out, "|")?;
+        }
+        writeln!(out, "{:08x}", data.len())
+    })??;
+    Ok(())
+}
+
+fn recv(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    if args.len() != 1 {
+        return Err(ShellError::Usage("recv name"));
+    }
+    // The serial side never gets here; see `run`.
+    writeln!(out, "recv: only works on the serial console")?;
+    Ok(())
+}
+
+fn replay(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    use crate::task::keyboard::replay;
+
+    match (args.get(0), args.get(1), args.len()) {
+        (None, _, _) => {
+            let state = if replay::is_replaying() {
+                "replaying"
+            } else if replay::is_recording() {
+                "recording"
+            } else {
+                "idle"
+            };
+            let recorded = replay::recorded().entries.len();
+            writeln!(out, "replay: {}, {} inputs recorded", state, recorded)?;
+        }
+        (Some("record"), None, 1) => replay::record(),
+        (Some("stop"), None, 1) => replay::stop(),
+        (Some("dump"), None, 1) => write!(out, "{}", replay::recorded())?,
+        (Some("play"), Some(path), 2) => match replay::load(path) {
+            Ok(log) => replay::queue(log),
+            Err(err) => writeln!(out, "replay: {}", err)?,
+        },
+        (Some(arg), None, 1) => return Err(ShellError::InvalidArgument(arg.into())),
+        _ => return Err(ShellError::Usage("replay [record|stop|dump|play file]")),
+    }
+    Ok(())
+}
+
+fn keymap(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    use crate::task::keyboard::{self, keymap};
+
+    match (args.get(0), args.len()) {
+        (None, _) => match keymap::loaded_keys() {
+            Some(keys) => writeln!(out, "keymap: {} keys over us", keys)?,
+            None => writeln!(out, "keymap: builtin (us)")?,
+        },
+        (Some("builtin"), 1) => keyboard::reset_keymap(),
+        (Some(path), 1) => {
+            if let Err(err) = with_file(path, keyboard::load_keymap)? {
+                writeln!(out, "keymap: {}: {}", path, err)?;
+            }
+        }
+        _ => return Err(ShellError::Usage("keymap [file|builtin]")),
+    }
+    Ok(())
+}
+
+fn reboot(_args: &Args, _out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    power::reboot();
+}
+
+fn shutdown(_args: &Args, _out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+    power::shutdown();
+}
+
+/// Run each line of `script` and return everything written.
+#[cfg(test)]
+fn run_script(script: &str) -> String {
+    let mut out = String::new();
+    for line in script.lines() {
+        execute(line, &mut out).unwrap();
+    }
+    out
+}
+
+#[test_case]
+fn test_split() {
+    assert_eq!(split("  echo a  b "), ["echo", "a", "b"]);
+    assert_eq!(split("echo \"a  b\" c"), ["echo", "a  b", "c"]);
+    assert_eq!(split("echo \"\" \"open"), ["echo", "", "open"]);
+    assert!(split("   ").is_empty());
+}
+
+#[test_case]
+fn test_boot_shows_init_stages() {
+    let out = run_script("boot");
+    assert!(out.lines().any(|line| line.starts_with("[ ok ] memory (")), "{}", out);
+    assert!(out.contains("[ ok ] heap ("), "{}", out);
+}
+
+#[test_case]
+fn test_version_shows_build() {
+    let info = crate::version_info();
+    let out = run_script("version");
+    assert_eq!(out, alloc::format!("{}\n", info));
+    assert!(out.starts_with(&alloc::format!("{} {} (", info.name, info.version)));
+    assert_eq!(out.lines().count(), 2);
+}
+
+#[test_case]
+fn test_estat_says_when_counters_are_off() {
+    let out = run_script("estat");
+    if cfg!(feature = "task-stats") {
+        assert_eq!(out, "executor not running\n");
+    } else {
+        assert_eq!(out, "executor counters need the task-stats feature\n");
+    }
+}
+
+#[test_case]
+fn test_statusbar_toggle() {
+    let out = run_script("statusbar on\nstatusbar\nstatusbar off\nstatusbar\nstatusbar dim");
+    assert_eq!(out, "status bar on\nstatus bar off\nstatusbar: invalid argument: dim\n");
+    assert_eq!(WRITER.get().lock().reserved_rows(), 0);
+}
+
+#[test_case]
+fn test_console_switch() {
+    let previous = console::selected();
+    let out = run_script("console serial\nconsole\nconsole both\nconsole\nconsole tty");
+    console::select(previous).unwrap();
+    assert_eq!(out, "console serial\nconsole both\nconsole: invalid argument: tty\n");
+}
+
+#[test_case]
+fn test_serial_input_without_keyboard() {
+    let previous = console::selected();
+    let was_present = keyboard::is_present();
+    console::select(Console::Both).unwrap();
+    keyboard::set_present(false);
+    let without = reads_keyboard();
+    keyboard::set_present(true);
+    let with = reads_keyboard();
+    keyboard::set_present(was_present);
+    console::select(previous).unwrap();
+    assert!(!without);
+    assert!(with);
+}
+
+#[test_case]
+fn test_keymap_command() {
+    let out = run_script(
+        "keymap keymaps/de.kmap\nkeymap\nkeymap hello.txt\nkeymap\nkeymap builtin\nkeymap",
+    );
+    assert_eq!(
+        out,
+        "keymap: 12 keys over us\nkeymap: hello.txt: not a keymap (want \"chronos keymap 1\")\n\
+         keymap: 12 keys over us\nkeymap: builtin (us)\n"
+    );
+    assert_eq!(run_script("keymap nope"), "keymap: no such file or directory: nope\n");
+}
+
+#[test_case]
+fn test_watch_arguments() {
+    let out = run_script("watch 1000 3\nwatch zz 8\nwatch off 9\nwatch 1000");
+    assert_eq!(
+        out,
+        "watch: length 3 is not 1, 2, 4 or 8\nwatch: invalid argument: zz\n\
+         watch: invalid argument: 9\nwatch: usage: watch [hex-addr len | off slot]\n"
+    );
+}
+
+#[test_case]
+fn test_builtins() {
+    let out = run_script("echo hello   \"big world\"\n\nuptime\nmem");
+    let mut lines = out.lines();
+    assert_eq!(lines.next(), Some("hello big world"));
+    assert!(lines.next().is_some_and(|line| line.starts_with("up ")));
+    assert!(lines.next().is_some_and(|line| line.starts_with("heap:")));
+    assert!(lines.next().is_some_and(|line| line.starts_with("frames:")));
+    assert!(lines.next().is_some_and(|line| line.starts_with("arena:")));
+
+    let help = run_script("help");
+    for name in ["help", "echo", "dmesg", "lastcrash", "reboot", "shutdown"] {
+        assert!(help.lines().any(|line| line.starts_with(name)), "{} missing", name);
+    }
+    assert!(run_script("irqstats").contains("timer"));
+    assert!(run_script("acpi").contains("\nmadt: "));
+    let heapcheck = run_script("heapcheck");
+    let expected = if cfg!(feature = "heap-debug") { " 0 corrupted\n" } else { "feature\n" };
+    assert!(heapcheck.ends_with(expected), "{}", heapcheck);
+    let date = run_script("date");
+    assert!(date.starts_with("20") && date.ends_with(" UTC\n"), "{}", date);
+}
+
+#[test_case]
+fn test_loglevel() {
+    let previous = klog::level();
+    assert_eq!(run_script("loglevel 3\nloglevel"), "log level 3\n");
+    assert_eq!(run_script("loglevel x"), "loglevel: invalid argument: x\n");
+    assert_eq!(run_script("loglevel 1 2"), "loglevel: usage: loglevel [n]\n");
+    klog::set_level(previous);
+}
+
+#[test_case]
+fn test_lastcrash() {
+    // Whether there is a record depends on how the previous boot ended.
+    let out = run_script("lastcrash");
+    assert!(out == "no crash recorded by the previous boot\n" || out.starts_with("at "), "{}", out);
+}
+
+#[test_case]
+fn test_unknown_command_suggestions() {
+    assert_eq!(run_script("rebo"), "unknown command: rebo\ndid you mean: reboot?\n");
+    assert_eq!(run_script("shutdwn"), "unknown command: shutdwn\ndid you mean: shutdown?\n");
+    assert_eq!(run_script("xyzzy"), "unknown command: xyzzy\n");
+}
+
+#[test_case]
+fn test_register() {
+    fn count(args: &Args, out: &mut dyn fmt::Write) -> Result<(), ShellError> {
+        writeln!(out, "{} args", args.len())?;
+        Ok(())
+    }
+
+    register("count", "count the arguments", count);
+    assert_eq!(run_script("count a \"b c\""), "2 args\n");
+    assert!(run_script("help").contains("count the arguments"));
+}
+
+#[test_case]
+fn test_profile() {
+    assert_eq!(run_script("profile start\nprofile stop\nprofile start\nprofile stop"), "");
+    assert!(run_script("profile").contains(" samples, "));
+    assert_eq!(run_script("profile report 0").lines().count(), 1);
+    assert_eq!(run_script("profile report x"), "profile: invalid argument: x\n");
+    assert_eq!(run_script("profile go"), "profile: invalid argument: go\n");
+    assert_eq!(run_script("profile report 1 2"), "profile: usage: profile [start|stop|report [n]]\n");
+}
+
+#[test_case]
+fn test_ls_and_cat() {
+    // The ramdisk is built from the initrd/ directory.
+    let root = run_script("ls");
+    assert!(root.lines().any(|line| line.ends_with(" hello.txt")));
+    assert!(root.lines().any(|line| line.ends_with(" etc/")));
+    assert_eq!(run_script("ls ./etc"), "      56 motd\n");
+    assert_eq!(run_script("cat /hello.txt"), "Hello from the chronos ramdisk!\n");
+    assert_eq!(run_script("cat nope"), "cat: no such file or directory: nope\n");
+    assert_eq!(run_script("ls nope"), "ls: no such file or directory: nope\n");
+    assert_eq!(run_script("cat"), "cat: usage: cat file...\n");
+}
+
+#[test_case]
+fn test_hexdump_and_mem_files() {
+    fs::mem::insert("shell-test", b"chronos\x00\x01 0123456789".to_vec()).unwrap();
+    assert_eq!(run_script("cat /shell-test"), "chronos\0\x01 0123456789");
+    assert_eq!(
+        run_script("hexdump shell-test"),
+        "00000000  63 68 72 6f 6e 6f 73 00 01 20 30 31 32 33 34 35  |chronos.. 012345|\n\
+         00000010  36 37 38 39                                      |6789|\n\
+         00000014\n"
+    );
+    fs::mem::remove("shell-test");
+    assert_eq!(run_script("hexdump shell-test"), "hexdump: no such file or directory: shell-test\n");
+    assert_eq!(run_script("recv"), "recv: usage: recv name\n");
+}
+
+#[test_case]
+fn test_complete() {
+    assert_eq!(complete("he", 0), ["heapcheck", "help", "hexdump"]);
+    assert_eq!(complete("statusb", 0), ["statusbar"]);
+    assert!(complete("zz", 0).is_empty());
+    // Only the first word is a command.
+    assert!(complete("echo he", 5).is_empty());
+
+    fs::mem::insert("complete-test.txt", b"x".to_vec()).unwrap();
+    assert!(complete("cat complete-t", 4).contains(&String::from("complete-test.txt")));
+    assert!(complete("cat /complete-t", 4).contains(&String::from("/complete-test.txt")));
+    // Only the first argument is a path.
+    assert!(complete("cat a complete-t", 6).is_empty());
+    assert!(fs::mem::remove("complete-test.txt"));
+}
+
+#[test_case]
+fn test_irqtrace() {
+    use crate::interrupts::trace;
+
+    assert_eq!(run_script("irqtrace on 250"), "");
+    assert!(trace::is_enabled(250));
+    assert!(run_script("irqtrace").starts_with("traced vectors: 250\n"));
+    assert_eq!(run_script("irqtrace off 250"), "");
+    assert!(!trace::is_enabled(250));
+    assert_eq!(run_script("irqtrace on 256"), "irqtrace: invalid argument: 256\n");
+    assert!(run_script("irqtrace on").starts_with("irqtrace: usage:"));
+}
+
+#[test_case]
+fn test_scrub() {
+    let out = run_script("scrub now");
+    assert!(out.contains(": ok\n"), "{}", out);
+    assert!(out.contains("\npages: "));
+    assert!(run_script("scrub").starts_with("pass "));
+    assert!(run_script("scrub later").starts_with("scrub: usage:"));
+}
+
+#[test_case]
+fn test_selftest() {
+    let out = run_script("selftest heap");
+    assert!(out.starts_with("selftest: PASS heap: "), "{}", out);
+    assert!(out.ends_with("1 checks, 1 passed, 0 skipped, 0 failed\n"), "{}", out);
+    assert!(run_script("selftest heap uart").starts_with("selftest: usage:"));
+}
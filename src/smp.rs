@@ -0,0 +1,530 @@
+//! Starting the application processors (APs).
+//!
+//! The boot CPU finds the others in ACPI's MADT, or in the MP configuration
+//! table the firmware leaves in low memory if there is no MADT. For each AP,
+//! [`init`]:
+//!
+//! 1. copies a trampoline to [`TRAMPOLINE_ADDR`];
+//! 2. sends INIT, waits 10 ms, then sends up to two startup IPIs 200 µs
+//!    apart, as the MP specification requires.
+//!
+//! The AP then runs the trampoline. It switches from real mode through
+//! protected mode to long mode, using the kernel's own page tables plus an
+//! identity mapping of the trampoline page, and calls [`ap_entry`] on a
+//! stack of its own.
+//!
+//! There, the AP:
+//! - loads its own GDT and TSS and the shared IDT;
+//! - enables its local APIC;
+//! - points GS at its [`PerCpu`] block;
+//! - marks itself online.
+//!
+//! It then halts with interrupts off. APs are started one at a time, so the
+//! single trampoline page is never shared.
+
+use core::arch::global_asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::arch::cr::{Cr0, Cr3};
+use crate::arch::msr::{Efer, EferFlags, GsBase};
+use crate::{apic, gdt, memory, println};
+
+/// Most CPUs brought up, the boot CPU included.
+pub const MAX_CPUS: usize = 8;
+
+/// Physical address the trampoline is copied to. Startup IPIs take the
+/// start address as a page number below 1 MiB.
+pub const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// Size of each AP's kernel stack.
+const AP_STACK_SIZE: usize = 4096 * 4;
+
+/// How long an AP gets to come online after its startup IPIs.
+const AP_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Why [`init`] couldn't start every CPU.
+#[derive(Debug)]
+pub enum SmpError {
+    /// CPUID reports no local APIC.
+    NoApic,
+    /// The trampoline page was in use when memory was set up.
+    TrampolineUnavailable,
+    /// Mapping the APIC registers or the trampoline failed.
+    Map(memory::MemError),
+    /// The CPU with this APIC ID didn't accept an IPI or never came online.
+    ApTimeout(u8),
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmpError::NoApic => f.write_str("no local apic"),
+            SmpError::TrampolineUnavailable => {
+                write!(f, "trampoline page {:#x} in use", TRAMPOLINE_ADDR)
+            }
+            SmpError::Map(err) => write!(f, "mapping failed: {}", err),
+            SmpError::ApTimeout(apic_id) => write!(f, "cpu with apic id {} did not start", apic_id),
+        }
+    }
+}
+
+/// Per-CPU data, reached through the GS base of each CPU.
+#[derive(Debug)]
+#[repr(C)]
+pub struct PerCpu {
+    /// Index into the CPU tables; 0 is the boot CPU.
+    pub index: usize,
+    /// The CPU's local APIC ID.
+    pub apic_id: AtomicU8,
+    /// Device interrupt handlers running on the CPU, counting nested ones;
+    /// see [`in_interrupt`](crate::interrupts::in_interrupt).
+    pub irq_depth: AtomicUsize,
+    /// [`IrqMutex`](crate::sync::IrqMutex) guards the CPU has taken and not
+    /// dropped; see [`locks_held`](crate::sync::locks_held).
+    pub locks_held: AtomicUsize,
+}
+
+static PER_CPU: [PerCpu; MAX_CPUS] = {
+    let mut cpus = [const {
+        PerCpu {
+            index: 0,
+            apic_id: AtomicU8::new(0),
+            irq_depth: AtomicUsize::new(0),
+            locks_held: AtomicUsize::new(0),
+        }
+    }; MAX_CPUS];
+    let mut index = 0;
+    while index < MAX_CPUS {
+        cpus[index].index = index;
+        index += 1;
+    }
+    cpus
+};
+
+/// CPUs found, the boot CPU included; 1 until [`init`] looks.
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Which CPUs have come online, by index. The boot CPU always has.
+static ONLINE: [AtomicBool; MAX_CPUS] = {
+    let mut online = [const { AtomicBool::new(false) }; MAX_CPUS];
+    online[0] = AtomicBool::new(true);
+    online
+};
+
+/// Set by [`reserve_trampoline`] if the trampoline page is free for use.
+static TRAMPOLINE_RESERVED: AtomicBool = AtomicBool::new(false);
+
+#[repr(C, align(16))]
+struct ApStack([u8; AP_STACK_SIZE]);
+
+static mut AP_STACKS: [ApStack; MAX_CPUS] = [const { ApStack([0; AP_STACK_SIZE]) }; MAX_CPUS];
+
+/// Return how many CPUs were found, the boot CPU included.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// Return whether CPU `index` has come online.
+pub fn is_online(index: usize) -> bool {
+    ONLINE.get(index).is_some_and(|online| online.load(Ordering::Acquire))
+}
+
+/// Return how many CPUs are online.
+pub fn online_count() -> usize {
+    (0..MAX_CPUS).filter(|&index| is_online(index)).count()
+}
+
+/// Return the calling CPU's per-CPU block, or `None` before its GS base
+/// is set.
+pub fn current() -> Option<&'static PerCpu> {
+    let base = GsBase::read().as_u64();
+    PER_CPU.iter().find(|&cpu| core::ptr::from_ref(cpu) as u64 == base)
+}
+
+/// Return the calling CPU's per-CPU block, taking the boot CPU's before
+/// its GS base is set, when no other CPU runs yet.
+pub fn this_cpu() -> &'static PerCpu {
+    current().unwrap_or(&PER_CPU[0])
+}
+
+/// Point the calling CPU's GS base at per-CPU block `index`.
+fn set_per_cpu(index: usize, apic_id: u8) {
+    let cpu = &PER_CPU[index];
+    cpu.apic_id.store(apic_id, Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(cpu));
+}
+
+/// Keep the frame allocator away from the trampoline page.
+///
+/// Must run before the first frame is allocated. The page is also usable if
+/// the bootloader's own code was there, which is dead once the kernel runs.
+pub(crate) fn reserve_trampoline(memory_map: &MemoryMap) {
+    let start = PhysAddr::new(TRAMPOLINE_ADDR);
+    let end = start + 4096u64;
+    let region = memory_map.iter().find(|region| {
+        region.range.start_addr() <= start.as_u64() && end.as_u64() <= region.range.end_addr()
+    });
+    let available = match region.map(|region| region.region_type) {
+        Some(MemoryRegionType::Usable) => {
+            memory::reserve(start, end, memory::ReservedKind::Trampoline).is_ok()
+        }
+        Some(MemoryRegionType::Bootloader) => true,
+        _ => false,
+    };
+    TRAMPOLINE_RESERVED.store(available, Ordering::Relaxed);
+}
+
+// The AP starts here in real mode, at TRAMPOLINE_ADDR, with interrupts off.
+// Addresses are computed relative to that, since this copy of the code
+// isn't where it runs.
+global_asm!(
+    r#"
+    .section .text.ap_trampoline, "ax"
+    .global ap_trampoline_start
+    .global ap_trampoline_data
+    .global ap_trampoline_end
+    .set BASE, {base}
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    xor %ax, %ax
+    mov %ax, %ds
+    lgdtl (ap_gdt_pointer - ap_trampoline_start + BASE)
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+    ljmpl $0x08, $(ap_protected - ap_trampoline_start + BASE)
+
+    .code32
+ap_protected:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    # Enable PAE, load the kernel's page tables and turn on long mode.
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+    mov (ap_trampoline_data - ap_trampoline_start + BASE), %eax
+    mov %eax, %cr3
+    mov $0xc0000080, %ecx
+    mov (ap_trampoline_data + 8 - ap_trampoline_start + BASE), %eax
+    mov (ap_trampoline_data + 12 - ap_trampoline_start + BASE), %edx
+    wrmsr
+    mov (ap_trampoline_data + 16 - ap_trampoline_start + BASE), %eax
+    mov %eax, %cr0
+    ljmpl $0x18, $(ap_long - ap_trampoline_start + BASE)
+
+    .code64
+ap_long:
+    xor %eax, %eax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov (ap_trampoline_data + 24 - ap_trampoline_start + BASE), %rsp
+    mov (ap_trampoline_data + 40 - ap_trampoline_start + BASE), %rdi
+    mov (ap_trampoline_data + 32 - ap_trampoline_start + BASE), %rax
+    # End the frame-pointer chain for backtraces.
+    xor %ebp, %ebp
+    call *%rax
+    ud2
+
+    .balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00af9a000000ffff
+ap_gdt_pointer:
+    .word ap_gdt_pointer - ap_gdt - 1
+    .long ap_gdt - ap_trampoline_start + BASE
+
+    .balign 8
+ap_trampoline_data:
+    .fill 6, 8, 0
+ap_trampoline_end:
+    .code64
+    .text
+    "#,
+    base = const TRAMPOLINE_ADDR,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// What the trampoline reads at `ap_trampoline_data`.
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    efer: u64,
+    cr0: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu_index: u64,
+}
+
+/// Copy the trampoline to [`TRAMPOLINE_ADDR`] and return its data block.
+fn install_trampoline(phys_offset: VirtAddr) -> &'static mut TrampolineData {
+    let start = &raw const ap_trampoline_start;
+    let len = &raw const ap_trampoline_end as usize - start as usize;
+    let data_offset = &raw const ap_trampoline_data as usize - start as usize;
+    assert!(len <= 4096, "trampoline larger than a page");
+    let target = (phys_offset + TRAMPOLINE_ADDR).as_mut_ptr::<u8>();
+    unsafe {
+        core::ptr::copy_nonoverlapping(start, target, len);
+        &mut *(target.add(data_offset) as *mut TrampolineData)
+    }
+}
+
+/// Where an AP lands in Rust, on its own stack, with interrupts off.
+extern "C" fn ap_entry(index: usize) -> ! {
+    gdt::init_ap(index);
+    crate::interrupts::load_idt();
+    apic::enable();
+    set_per_cpu(index, apic::id());
+    ONLINE[index].store(true, Ordering::Release);
+    crate::hlt_loop();
+}
+
+/// Busy-wait for at least `duration`, timed by PIT channel 2 so it doesn't
+/// depend on the TSC. Falls back to [`time::monotonic`](crate::time::monotonic)
+/// if the channel doesn't count.
+fn spin_for(duration: Duration) {
+    let us = duration.as_micros() as u32;
+    if crate::time::pit_oneshot_us(us).is_ok() {
+        return;
+    }
+    let deadline = crate::time::monotonic() + duration;
+    while crate::time::monotonic() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Start AP `index` with APIC ID `apic_id` and wait for it to come online.
+fn start_ap(index: usize, apic_id: u8, data: &mut TrampolineData) -> Result<(), SmpError> {
+    let stack = unsafe { &raw const AP_STACKS[index] };
+    data.stack_top = stack as u64 + AP_STACK_SIZE as u64;
+    data.cpu_index = index as u64;
+    data.entry = ap_entry as extern "C" fn(usize) -> ! as usize as u64;
+    // The data reaches the AP through memory it reads after the IPI.
+    core::sync::atomic::fence(Ordering::SeqCst);
+
+    let page = (TRAMPOLINE_ADDR / 4096) as u8;
+    let timeout = SmpError::ApTimeout(apic_id);
+    apic::send_init(apic_id).map_err(|_| SmpError::ApTimeout(apic_id))?;
+    spin_for(Duration::from_millis(10));
+    for _ in 0..2 {
+        apic::send_startup(apic_id, page).map_err(|_| SmpError::ApTimeout(apic_id))?;
+        spin_for(Duration::from_micros(200));
+        if is_online(index) {
+            return Ok(());
+        }
+    }
+    let deadline = crate::time::monotonic() + AP_TIMEOUT;
+    while crate::time::monotonic() < deadline {
+        if is_online(index) {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(timeout)
+}
+
+/// Find the other CPUs and start them.
+///
+/// Needs interrupts on for the delays, and [`reserve_trampoline`] to have
+/// run before frames were allocated.
+pub(crate) fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), SmpError> {
+    if !crate::cpu::features().apic {
+        return Err(SmpError::NoApic);
+    }
+    apic::map(mapper, frame_allocator).map_err(SmpError::Map)?;
+    apic::enable();
+    let bsp_id = apic::id();
+    set_per_cpu(0, bsp_id);
+
+    let phys_offset = mapper.phys_offset();
+    let mut ap_ids = [0u8; MAX_CPUS];
+    let mut aps = 0;
+    let mut add = |apic_id: u8| {
+        if apic_id != bsp_id && aps + 1 < MAX_CPUS {
+            ap_ids[aps] = apic_id;
+            aps += 1;
+        }
+    };
+    match crate::acpi::madt() {
+        // The xAPIC addresses IPIs with 8-bit IDs; CPUs with larger x2APIC
+        // IDs are out of reach.
+        Some(madt) => madt
+            .enabled_cpus()
+            .filter_map(|cpu| u8::try_from(cpu.apic_id).ok())
+            .for_each(&mut add),
+        None => mp::processors(phys_offset)
+            .filter(|p| p.enabled)
+            .for_each(|p| add(p.apic_id)),
+    }
+    CPU_COUNT.store(1 + aps, Ordering::Release);
+    if aps == 0 {
+        return Ok(());
+    }
+    if !TRAMPOLINE_RESERVED.load(Ordering::Relaxed) {
+        return Err(SmpError::TrampolineUnavailable);
+    }
+
+    // The AP turns paging on while running at the trampoline's physical
+    // address, so that page must be identity-mapped meanwhile.
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TRAMPOLINE_ADDR));
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_ADDR));
+    let identity = match mapper.translate_addr(page.start_address()) {
+        Some(addr) if addr == frame.start_address() => false,
+        Some(_) => return Err(SmpError::Map(memory::MemError::PageAlreadyMapped(frame))),
+        None => {
+            // The AP runs from this page, and loading a descriptor from its
+            // GDT writes the accessed bit.
+            let flags = memory::allow_wx(PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            unsafe {
+                memory::map_page(mapper, page, frame, flags, frame_allocator)
+                    .map_err(SmpError::Map)?
+                    .flush()
+            };
+            true
+        }
+    };
+
+    let data = install_trampoline(phys_offset);
+    data.cr3 = Cr3::read().0.start_address().as_u64();
+    // Long mode active is read-only; the AP's CR0 write sets it.
+    data.efer = Efer::read_raw() & !EferFlags::LONG_MODE_ACTIVE.bits();
+    data.cr0 = Cr0::read_raw();
+
+    let mut result = Ok(());
+    for (offset, &apic_id) in ap_ids[..aps].iter().enumerate() {
+        if let Err(err) = start_ap(offset + 1, apic_id, data) {
+            result = result.and(Err(err));
+        }
+    }
+
+    if identity && let Ok((_, flush)) = mapper.unmap(page) {
+        flush.flush();
+    }
+    if crate::klog::level() > 0 {
+        println!("smp: {} of {} cpus online", online_count(), cpu_count());
+    }
+    result
+}
+
+/// The Intel MultiProcessor Specification tables.
+mod mp {
+    use x86_64::VirtAddr;
+
+    /// A processor entry of the configuration table.
+    #[derive(Debug, Clone, Copy)]
+    pub struct Processor {
+        pub apic_id: u8,
+        pub enabled: bool,
+    }
+
+    /// Return the bytes at physical `addr`, through the physical memory
+    /// mapping.
+    fn phys(phys_offset: VirtAddr, addr: u64, len: usize) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts((phys_offset + addr).as_ptr(), len) }
+    }
+
+    fn checksum_ok(bytes: &[u8]) -> bool {
+        bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+    }
+
+    /// Find the floating pointer structure: in the first KiB of the EBDA,
+    /// the last KiB of base memory, or the BIOS ROM.
+    fn floating_pointer(phys_offset: VirtAddr) -> Option<&'static [u8]> {
+        let read_u16 = |addr| {
+            let bytes = phys(phys_offset, addr, 2);
+            u64::from(u16::from_le_bytes([bytes[0], bytes[1]]))
+        };
+        let ebda = read_u16(0x40e) << 4;
+        let base_end = read_u16(0x413) * 1024;
+        let areas = [(ebda, 1024), (base_end.saturating_sub(1024), 1024), (0xf0000, 0x10000)];
+        areas.into_iter().filter(|&(start, _)| start != 0).find_map(|(start, len)| {
+            let area = phys(phys_offset, start, len);
+            area.chunks_exact(16).find(|candidate| {
+                candidate.starts_with(b"_MP_") && candidate[8] == 1 && checksum_ok(candidate)
+            })
+        })
+    }
+
+    /// Iterate over the processor entries of the configuration table.
+    /// Empty if there is no valid table.
+    pub fn processors(phys_offset: VirtAddr) -> impl Iterator<Item = Processor> {
+        let table = floating_pointer(phys_offset).and_then(|pointer| {
+            let config = u64::from(u32::from_le_bytes(pointer[4..8].try_into().unwrap()));
+            // A zero address means one of the default configurations, which
+            // list no processors.
+            if config == 0 {
+                return None;
+            }
+            let header = phys(phys_offset, config, 44);
+            let length = usize::from(u16::from_le_bytes([header[4], header[5]]));
+            let table = phys(phys_offset, config, length);
+            (table.starts_with(b"PCMP") && length >= 44 && checksum_ok(table)).then_some(table)
+        });
+        let entries = table.map_or(&[][..], |table| &table[44..]);
+        let mut rest = entries;
+        core::iter::from_fn(move || {
+            loop {
+                let (&kind, _) = rest.split_first()?;
+                // Processors take 20 bytes, other known entries 8.
+                let len = match kind {
+                    0 => 20,
+                    1..=4 => 8,
+                    _ => return None,
+                };
+                let entry = rest.get(..len)?;
+                rest = &rest[len..];
+                if kind == 0 {
+                    return Some(Processor {
+                        apic_id: entry[1],
+                        enabled: entry[3] & 1 != 0,
+                    });
+                }
+            }
+        })
+    }
+}
+
+/// CPUs the test runner gives QEMU, with `-smp` in Cargo.toml's
+/// `test-args`. Change both together.
+#[cfg(test)]
+const EXPECTED_CPUS: usize = 4;
+
+#[test_case]
+fn test_all_cpus_online() {
+    assert_eq!(cpu_count(), EXPECTED_CPUS);
+    let deadline = crate::time::monotonic() + Duration::from_secs(1);
+    while online_count() < EXPECTED_CPUS && crate::time::monotonic() < deadline {
+        core::hint::spin_loop();
+    }
+    assert_eq!(online_count(), EXPECTED_CPUS);
+    for index in 0..cpu_count() {
+        assert!(is_online(index), "cpu {} not online", index);
+    }
+    assert_eq!(current().map(|cpu| cpu.index), Some(0));
+    let ids: alloc::vec::Vec<u8> =
+        PER_CPU[..cpu_count()].iter().map(|cpu| cpu.apic_id.load(Ordering::Relaxed)).collect();
+    assert!(ids.iter().enumerate().all(|(i, id)| !ids[..i].contains(id)), "{:?}", ids);
+}
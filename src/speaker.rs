@@ -0,0 +1,181 @@
+//! PC speaker.
+//!
+//! The speaker is driven by PIT channel 2 in square-wave mode, gated through
+//! bits 0 (timer 2 gate) and 1 (speaker data) of port `0x61`. Channel 2 has
+//! its own reload value and latch, so programming it never disturbs channel
+//! 0 and the tick rate; [`beep`] is safe to call before
+//! [`time::init_pit`](crate::time::init_pit).
+//!
+//! Notes are timed with [`time::sleep_ms`](crate::time::sleep_ms) when timer
+//! ticks are running. Before that, or with interrupts disabled (as in the
+//! panic handler), they are timed by counting the edges of channel 2's
+//! output, which port `0x61` reflects in bit 5.
+//!
+//! Nothing here is synchronized: overlapping notes from two callers cut each
+//! other off. To hear the speaker under QEMU, run it with e.g.
+//! `-audiodev pa,id=snd0 -machine pcspk-audiodev=snd0`.
+
+use x86_64::instructions::interrupts;
+
+use crate::arch::port::{Port, PortGroup};
+use crate::time::{self, PitPorts, PIT_FREQUENCY_HZ};
+
+/// System control port B.
+const CONTROL: Port<u8> = Port::new("speaker", 0x61);
+/// Port `0x61` bits: channel 2 gate, speaker data, channel 2 output.
+const TIMER2_GATE: u8 = 1 << 0;
+const SPEAKER_DATA: u8 = 1 << 1;
+const TIMER2_OUT: u8 = 1 << 5;
+
+/// Frequency channel 2 runs at during rests that are timed by counting
+/// edges.
+const REST_TIMING_HZ: u32 = 1000;
+
+/// Polls of port `0x61` to wait for one output edge before deciding the
+/// channel isn't running.
+const EDGE_TIMEOUT_POLLS: u32 = 100_000;
+
+/// Return the channel 2 reload value closest to `freq_hz`, or `None` for a
+/// rest (frequency 0).
+///
+/// Frequencies too low for the 16-bit counter get the lowest one it can
+/// make (about 18 Hz).
+pub fn divisor(freq_hz: u32) -> Option<u16> {
+    if freq_hz == 0 {
+        return None;
+    }
+    let divisor = (PIT_FREQUENCY_HZ + freq_hz / 2) / freq_hz;
+    Some(divisor.clamp(1, u16::MAX as u32) as u16)
+}
+
+/// Return how many output edges channel 2 makes in `duration_ms` with
+/// reload value `divisor`. The output changes twice per period.
+fn edges_in(duration_ms: u64, divisor: u16) -> u64 {
+    duration_ms * 2 * PIT_FREQUENCY_HZ as u64 / (1000 * divisor as u64)
+}
+
+/// Start channel 2 as a square wave with reload value `divisor`, with the
+/// speaker connected if `audible`.
+fn start(divisor: u16, audible: bool) {
+    let pit = PitPorts::standard();
+    unsafe {
+        // channel 2, access mode lobyte/hibyte, mode 3 (square wave), binary
+        pit.command.write(0xb6);
+        pit.channel2.write(divisor as u8);
+        pit.channel2.write((divisor >> 8) as u8);
+        let gates = if audible { TIMER2_GATE | SPEAKER_DATA } else { TIMER2_GATE };
+        let value = CONTROL.read() & !(TIMER2_GATE | SPEAKER_DATA);
+        CONTROL.write(value | gates);
+    }
+}
+
+/// Clear both gate bits, silencing the speaker and stopping channel 2.
+fn stop() {
+    unsafe {
+        let value = CONTROL.read();
+        CONTROL.write(value & !(TIMER2_GATE | SPEAKER_DATA));
+    }
+}
+
+/// Busy-wait for `edges` changes of channel 2's output.
+///
+/// Gives up if the output stops changing, so a missing or unemulated
+/// channel can't hang the caller.
+fn wait_edges(edges: u64) {
+    let mut last = unsafe { CONTROL.read() } & TIMER2_OUT;
+    for _ in 0..edges {
+        let mut polls = 0;
+        loop {
+            let out = unsafe { CONTROL.read() } & TIMER2_OUT;
+            if out != last {
+                last = out;
+                break;
+            }
+            polls += 1;
+            if polls == EDGE_TIMEOUT_POLLS {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Whether notes can be timed by sleeping on timer ticks.
+fn ticks_running() -> bool {
+    interrupts::are_enabled() && time::pit_configured()
+}
+
+/// Play `freq_hz` for `duration_ms`, or stay silent for that long if the
+/// frequency is 0.
+pub fn beep(freq_hz: u32, duration_ms: u64) {
+    if ticks_running() {
+        if let Some(divisor) = divisor(freq_hz) {
+            start(divisor, true);
+        }
+        time::sleep_ms(duration_ms);
+    } else {
+        let (divisor, audible) = match divisor(freq_hz) {
+            Some(divisor) => (divisor, true),
+            None => (divisor(REST_TIMING_HZ).unwrap(), false),
+        };
+        start(divisor, audible);
+        wait_edges(edges_in(duration_ms, divisor));
+    }
+    stop();
+}
+
+/// Play a sequence of `(frequency, milliseconds)` notes; frequency 0 rests.
+pub fn play(notes: &[(u32, u64)]) {
+    for &(freq_hz, duration_ms) in notes {
+        beep(freq_hz, duration_ms);
+    }
+}
+
+/// Like [`beep`], but awaits a timer instead of blocking.
+pub async fn beep_async(freq_hz: u32, duration_ms: u64) {
+    if let Some(divisor) = divisor(freq_hz) {
+        start(divisor, true);
+    }
+    crate::task::timer::sleep(core::time::Duration::from_millis(duration_ms)).await;
+    stop();
+}
+
+/// Like [`play`], but awaits a timer for each note instead of blocking.
+pub async fn play_async(notes: &[(u32, u64)]) {
+    for &(freq_hz, duration_ms) in notes {
+        beep_async(freq_hz, duration_ms).await;
+    }
+}
+
+/// A short falling tone, for the panic handler.
+///
+/// Doesn't depend on interrupts or the tick counter.
+pub fn sad_beep() {
+    interrupts::without_interrupts(|| play(&[(494, 150), (0, 50), (392, 150), (0, 50), (330, 400)]));
+}
+
+#[test_case]
+fn test_divisor() {
+    assert_eq!(divisor(0), None);
+    assert_eq!(divisor(1000), Some(1193));
+    assert_eq!(divisor(440), Some(2712));
+    // Out of range frequencies are clamped.
+    assert_eq!(divisor(1), Some(u16::MAX));
+    assert_eq!(divisor(PIT_FREQUENCY_HZ * 2), Some(1));
+}
+
+#[test_case]
+fn test_edges_in() {
+    // 1000 Hz for 10 ms is 10 periods, 20 edges.
+    assert_eq!(edges_in(10, divisor(1000).unwrap()), 20);
+    assert_eq!(edges_in(0, 1), 0);
+}
+
+#[test_case]
+fn test_rest_takes_its_duration() {
+    use crate::interrupts::ticks;
+
+    let start = ticks();
+    play(&[(0, 20), (0, 10)]);
+    assert!(ticks() >= start + 3);
+}
@@ -0,0 +1,271 @@
+//! A status bar on the top screen row.
+//!
+//! [`run`] redraws it once a second while it is [enabled](enable): uptime,
+//! UTC time of day, heap use, task count and CPU use, each right-aligned in
+//! a fixed width so the bar doesn't shift as values change. Anything not
+//! available yet (the heap before it is set up, the time before the wall
+//! clock is) shows as `--`, and a value too wide for its field is cut off
+//! with a `+`. CPU use comes from the time the executor spends halted, so
+//! it stays `--` until the executor runs. Without a keyboard, `kbd: none`
+//! takes the place of the name on the left.
+//!
+//! The row is the writer's [status
+//! row](crate::vga_buffer::Writer::set_status_row) while the bar is on and
+//! drawn with [`write_status`](crate::vga_buffer::Writer::write_status), so
+//! the text below scrolls and the cursor moves as if it weren't there. The
+//! bar can't be turned on while rows are
+//! [reserved](crate::vga_buffer::Writer::reserve_rows).
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use crate::allocator;
+use crate::collections::FixedString;
+use crate::rtc::DateTime;
+use crate::task::executor;
+use crate::task::keyboard;
+use crate::task::timer;
+use crate::time;
+use crate::vga_buffer::{BUFFER_WIDTH, Color, ColorCode, WRITER};
+
+/// Screen row the bar occupies.
+const ROW: usize = 0;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// What the bar shows. `None` fields are drawn as placeholders.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub uptime: Duration,
+    /// UTC time of day.
+    pub time: Option<DateTime>,
+    pub heap_percent: Option<usize>,
+    pub tasks: Option<usize>,
+    pub cpu_percent: Option<usize>,
+    /// [`keyboard::init`] found no keyboard.
+    pub no_keyboard: bool,
+}
+
+impl Summary {
+    /// Gather the current values, with CPU use measured since `cpu` was
+    /// last sampled.
+    pub fn collect(cpu: &mut CpuMeter) -> Self {
+        let heap = allocator::heap_stats();
+        Summary {
+            uptime: time::uptime(),
+            time: time::wallclock::now().map(|now| now.date_time()),
+            heap_percent: (heap.used * 100).checked_div(heap.size),
+            tasks: executor::is_running().then(executor::task_count),
+            cpu_percent: cpu.sample(time::rdtsc(), executor::halt_cycles()),
+            no_keyboard: !keyboard::is_present(),
+        }
+    }
+}
+
+/// CPU use between samples, from the cycles the executor spent halted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuMeter {
+    /// TSC and halted cycles at the last sample.
+    last: Option<(u64, u64)>,
+}
+
+impl CpuMeter {
+    /// Return the percentage of cycles since the last sample that weren't
+    /// spent halted, given the executor's `halted` cycles so far. `None` on
+    /// the first sample, or without them.
+    pub fn sample(&mut self, tsc: u64, halted: Option<u64>) -> Option<usize> {
+        let halted = halted?;
+        let (last_tsc, last_halted) = self.last.replace((tsc, halted))?;
+        let elapsed = tsc.wrapping_sub(last_tsc);
+        let idle = halted.wrapping_sub(last_halted).min(elapsed);
+        Some(((elapsed - idle) * 100).checked_div(elapsed).unwrap_or(0) as usize)
+    }
+}
+
+/// Append `label` and `value` right-aligned in `width` columns.
+fn field(
+    row: &mut FixedString<BUFFER_WIDTH>,
+    label: &str,
+    width: usize,
+    value: Option<&dyn fmt::Display>,
+) {
+    let mut text = FixedString::<24>::new();
+    let _ = match value {
+        Some(value) => write!(text, "{}", value),
+        None => text.write_str("--"),
+    };
+    let value = text.as_str();
+    let _ = row.write_str(label);
+    if text.is_truncated() || value.len() > width {
+        let _ = row.write_str(&value[..width.saturating_sub(1).min(value.len())]);
+        let _ = row.write_str("+");
+    } else {
+        let _ = write!(row, "{:>width$}", value, width = width);
+    }
+}
+
+struct Uptime(Duration);
+
+impl fmt::Display for Uptime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0.as_secs();
+        if secs >= 86_400 {
+            write!(f, "{}d ", secs / 86_400)?;
+        }
+        write!(f, "{:02}:{:02}:{:02}", secs / 3600 % 24, secs / 60 % 60, secs % 60)
+    }
+}
+
+struct TimeOfDay(DateTime);
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}:{:02}", self.0.hour, self.0.minute, self.0.second)
+    }
+}
+
+struct Percent(usize);
+
+impl fmt::Display for Percent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}%", self.0)
+    }
+}
+
+/// Lay out `summary` as a full screen row.
+pub fn render(summary: &Summary) -> [u8; BUFFER_WIDTH] {
+    let time = summary.time.map(TimeOfDay);
+    let heap = summary.heap_percent.map(Percent);
+    let cpu = summary.cpu_percent.map(Percent);
+
+    let mut fields = FixedString::<BUFFER_WIDTH>::new();
+    field(&mut fields, "up ", 11, Some(&Uptime(summary.uptime)));
+    field(&mut fields, " | utc ", 8, time.as_ref().map(|t| t as _));
+    field(&mut fields, " | heap ", 4, heap.as_ref().map(|p| p as _));
+    field(&mut fields, " | tasks ", 3, summary.tasks.as_ref().map(|n| n as _));
+    field(&mut fields, " | cpu ", 4, cpu.as_ref().map(|p| p as _));
+    let _ = fields.write_str(" ");
+
+    let mut row = [b' '; BUFFER_WIDTH];
+    let name: &[u8] = if summary.no_keyboard { b" kbd: none" } else { b" chronos" };
+    row[..name.len()].copy_from_slice(name);
+    let fields = fields.as_str().as_bytes();
+    row[BUFFER_WIDTH - fields.len()..].copy_from_slice(fields);
+    row
+}
+
+/// Draw `summary` if the bar is on.
+fn draw(summary: &Summary) {
+    let row = render(summary);
+    let Ok(writer) = WRITER.try_get() else {
+        return;
+    };
+    let mut writer = writer.lock();
+    // Checked under the lock, so a draw can't land after `disable`.
+    if ENABLED.load(Ordering::Relaxed) {
+        let text = core::str::from_utf8(&row).unwrap_or("");
+        writer.write_status(text, ColorCode::new(Color::Black, Color::LightGray));
+    }
+}
+
+/// Make the top row the status row and show the bar there. Does nothing
+/// if the row can't be taken, or another status row is in use.
+pub fn enable() {
+    if let Ok(writer) = WRITER.try_get() {
+        let mut writer = writer.lock();
+        let free = writer.status_row().is_none_or(|row| row == ROW);
+        if free && writer.set_status_row(Some(ROW)) {
+            ENABLED.store(true, Ordering::Relaxed);
+        }
+    }
+    draw(&Summary::collect(&mut CpuMeter::default()));
+}
+
+/// Remove the bar and give its row back to the scrolling text.
+pub fn disable() {
+    if let Ok(writer) = WRITER.try_get() {
+        let mut writer = writer.lock();
+        if ENABLED.swap(false, Ordering::Relaxed) {
+            writer.set_status_row(None);
+        }
+    }
+}
+
+/// Return whether the bar is shown.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Redraw the bar once a second. Runs for as long as the kernel does;
+/// spawn it once.
+pub async fn run() {
+    let mut cpu = CpuMeter::default();
+    loop {
+        // Sampled even while off, so CPU use is current once turned on.
+        let summary = Summary::collect(&mut cpu);
+        if is_enabled() {
+            draw(&summary);
+        }
+        timer::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+#[cfg(test)]
+fn rendered(summary: &Summary) -> alloc::string::String {
+    alloc::string::String::from_utf8(render(summary).to_vec()).unwrap()
+}
+
+#[test_case]
+fn test_render_full() {
+    let summary = Summary {
+        uptime: Duration::from_secs(3 * 3600 + 25 * 60 + 7),
+        time: Some(DateTime { year: 2026, month: 10, day: 16, hour: 9, minute: 5, second: 59 }),
+        heap_percent: Some(42),
+        tasks: Some(7),
+        cpu_percent: Some(100),
+        no_keyboard: false,
+    };
+    let row = rendered(&summary);
+    assert_eq!(row.len(), BUFFER_WIDTH);
+    assert_eq!(
+        row,
+        " chronos       up    03:25:07 | utc 09:05:59 | heap  42% | tasks   7 | cpu 100% "
+    );
+}
+
+#[test_case]
+fn test_render_placeholders_and_truncation() {
+    let summary = Summary {
+        uptime: Duration::from_secs(12_345 * 86_400 + 1),
+        tasks: Some(1234),
+        ..Summary::default()
+    };
+    assert_eq!(
+        rendered(&summary),
+        " chronos       up 12345d 00:+ | utc       -- | heap   -- | tasks 12+ | cpu   -- "
+    );
+    // The bar is the same width whatever the values.
+    let days = Summary { uptime: Duration::from_secs(2 * 86_400), ..Summary::default() };
+    assert_eq!(rendered(&days).find(" up "), rendered(&summary).find(" up "));
+    assert!(rendered(&days).contains("up 2d 00:00:00 |"));
+}
+
+#[test_case]
+fn test_render_without_keyboard() {
+    let summary = Summary { no_keyboard: true, ..Summary::default() };
+    assert_eq!(
+        rendered(&summary),
+        " kbd: none     up    00:00:00 | utc       -- | heap   -- | tasks  -- | cpu   -- "
+    );
+}
+
+#[test_case]
+fn test_cpu_meter() {
+    let mut cpu = CpuMeter::default();
+    assert_eq!(cpu.sample(1000, Some(0)), None);
+    assert_eq!(cpu.sample(2000, Some(750)), Some(25));
+    assert_eq!(cpu.sample(3000, Some(750)), Some(100));
+    assert_eq!(cpu.sample(3000, Some(750)), Some(0));
+    assert_eq!(cpu.sample(4000, None), None);
+}
@@ -0,0 +1,183 @@
+//! Kernel symbol table, for printing code addresses as `function+offset`.
+//!
+//! The table lives in a `.ksyms` section of fixed size. A build script runs
+//! before the kernel is linked, so it can't know the kernel's addresses;
+//! instead the cargo runner fills the section after linking, with
+//! `tools/ksyms.rs`. Since the size doesn't change, neither does any
+//! address. A kernel that didn't go through the runner (a plain
+//! `cargo build`) keeps the empty table it was compiled with, and
+//! [`resolve`] finds nothing, so callers print raw addresses.
+//!
+//! Only functions are listed, so addresses in the table itself or in other
+//! data never resolve. Names are demangled, without their hash, and cut at
+//! 128 bytes.
+//!
+//! Layout, all little-endian:
+//!
+//! - header: the magic `KSYM`, the symbol count (u32), the offset of the
+//!   name area (u32) and its length (u32)
+//! - one 16-byte entry per symbol, sorted by address: the address (u64),
+//!   the size (u32) and the offset of the name in the name area (u32)
+//! - the name area: each name is a length byte followed by UTF-8
+//!
+//! [`resolve`] reads the table in place and never allocates or locks, so
+//! panic and fault handlers may use it.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::Range;
+
+/// Size of the `.ksyms` section.
+pub const TABLE_SIZE: usize = 2 * 1024 * 1024;
+
+const MAGIC: [u8; 4] = *b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+/// The table: empty until the runner fills it in.
+///
+/// Starting with a valid header keeps the section from being all zeroes,
+/// which the linker could turn into an unstored (NOBITS) one.
+#[used]
+#[unsafe(link_section = ".ksyms")]
+static TABLE: Table = Table(UnsafeCell::new(empty_table()));
+
+/// The table's bytes. The `UnsafeCell` tells the compiler they may not be
+/// what they were initialized with, so it can't fold reads of the empty
+/// table into the code.
+#[repr(transparent)]
+struct Table(UnsafeCell<[u8; TABLE_SIZE]>);
+
+// SAFETY: nothing in the kernel writes the table; only the runner does,
+// in the binary, before it boots.
+unsafe impl Sync for Table {}
+
+const fn empty_table() -> [u8; TABLE_SIZE] {
+    let mut table = [0; TABLE_SIZE];
+    table[0] = MAGIC[0];
+    table[1] = MAGIC[1];
+    table[2] = MAGIC[2];
+    table[3] = MAGIC[3];
+    table[8] = HEADER_SIZE as u8;
+    table
+}
+
+/// The table as it is in memory.
+fn table() -> &'static [u8; TABLE_SIZE] {
+    // SAFETY: never written while the kernel runs; see `Table`.
+    unsafe { &*TABLE.0.get() }
+}
+
+/// The parts of the table, if it is well formed.
+struct Symbols {
+    entries: &'static [u8],
+    names: &'static [u8],
+}
+
+impl Symbols {
+    fn get() -> Option<Symbols> {
+        let table = table();
+        if table[..4] != MAGIC {
+            return None;
+        }
+        let count = u32_at(table, 4)? as usize;
+        let names_offset = u32_at(table, 8)? as usize;
+        let names_len = u32_at(table, 12)? as usize;
+        let entries = table.get(HEADER_SIZE..HEADER_SIZE.checked_add(count * ENTRY_SIZE)?)?;
+        let names = table.get(names_offset..names_offset.checked_add(names_len)?)?;
+        Some(Symbols { entries, names })
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    /// The address, size and name offset of entry `index`.
+    fn entry(&self, index: usize) -> Option<(u64, u32, u32)> {
+        let entry = self.entries.get(index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE)?;
+        Some((u64_at(entry, 0)?, u32_at(entry, 8)?, u32_at(entry, 12)?))
+    }
+
+    fn name(&self, offset: u32) -> Option<&'static str> {
+        let names = self.names;
+        let len = usize::from(*names.get(offset as usize)?);
+        let start = offset as usize + 1;
+        core::str::from_utf8(names.get(start..start + len)?).ok()
+    }
+}
+
+/// Return how many symbols the table holds; zero if it wasn't filled in.
+pub fn count() -> usize {
+    Symbols::get().map_or(0, |symbols| symbols.len())
+}
+
+/// Return the addresses the listed functions span, from the start of the
+/// first to the end of the last; `None` if the table wasn't filled in.
+pub fn text_range() -> Option<Range<u64>> {
+    let symbols = Symbols::get()?;
+    let (first, _, _) = symbols.entry(0)?;
+    let (last, size, _) = symbols.entry(symbols.len().checked_sub(1)?)?;
+    Some(first..last + u64::from(size.max(1)))
+}
+
+/// Return the function containing `addr` and the offset of `addr` in it.
+pub fn resolve(addr: u64) -> Option<(&'static str, usize)> {
+    let symbols = Symbols::get()?;
+    // The last symbol starting at or below `addr`.
+    let (mut low, mut high) = (0, symbols.len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if symbols.entry(mid)?.0 <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let (start, size, name) = symbols.entry(low.checked_sub(1)?)?;
+    // Symbols without a size only cover their first byte.
+    let offset = addr - start;
+    if offset >= u64::from(size.max(1)) {
+        return None;
+    }
+    Some((symbols.name(name)?, offset as usize))
+}
+
+/// A code address that displays as `function+0x1f` when it resolves, and
+/// as the bare address otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol(pub u64);
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match resolve(self.0) {
+            Some((name, offset)) => write!(f, "{}+{:#x}", name, offset),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+#[test_case]
+fn test_resolve_known_function() {
+    let addr = resolve as fn(u64) -> Option<(&'static str, usize)> as usize as u64;
+    assert!(count() > 0, "symbol table is empty; was the kernel run through tools/runner.sh?");
+    assert_eq!(resolve(addr), Some(("chronos::symbols::resolve", 0)));
+    assert_eq!(resolve(addr + 5), Some(("chronos::symbols::resolve", 5)));
+    assert_eq!(
+        alloc::format!("{}", Symbol(addr + 0x1f)),
+        "chronos::symbols::resolve+0x1f"
+    );
+
+    // Data, including the table itself, isn't covered.
+    let table = table().as_ptr() as u64;
+    assert_eq!(resolve(table), None);
+    assert_eq!(resolve(table + 100), None);
+    assert_eq!(alloc::format!("{}", Symbol(table)), alloc::format!("{:#x}", table));
+}
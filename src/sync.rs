@@ -0,0 +1,430 @@
+//! Kernel globals and the locks shared with interrupt handlers.
+//!
+//! A plain [`spin::Mutex`] that an interrupt handler also takes deadlocks as
+//! soon as the interrupt arrives while the interrupted code holds it: the
+//! handler spins forever on a lock whose holder can't run. [`IrqMutex`]
+//! disables interrupts for as long as its guard lives, so callers no longer
+//! have to remember to wrap the lock in `without_interrupts`.
+//!
+//! [`Global`] holds a kernel-wide value that the staged init (see
+//! [`crate::init`]) sets up explicitly, instead of on first use. Using one
+//! too early fails with its name rather than silently building it in
+//! whatever context happened to touch it first.
+//!
+//! With the `lock-order` feature, named [`IrqMutex`]es are checked for
+//! being taken in inconsistent orders; see [`lock_order`].
+
+#[cfg(feature = "lock-order")]
+pub mod lock_order;
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::{ManuallyDrop, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+/// Why a [`Global`] couldn't be used or set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GlobalError {
+    /// The global with this name was read before its `init`.
+    UsedBeforeInit(&'static str),
+    /// The global with this name was initialized a second time.
+    AlreadyInitialized(&'static str),
+}
+
+impl fmt::Display for GlobalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GlobalError::UsedBeforeInit(name) => write!(f, "used before init: {}", name),
+            GlobalError::AlreadyInitialized(name) => write!(f, "initialized twice: {}", name),
+        }
+    }
+}
+
+/// [`Global`] states.
+const UNINIT: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+/// A named kernel global, set once by an explicit [`init`](Self::init).
+///
+/// Reading never blocks: [`try_get`](Self::try_get) fails straight away if
+/// the value isn't there yet, so print paths and interrupt handlers can use
+/// it at any time.
+pub struct Global<T> {
+    name: &'static str,
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// SAFETY: the value is written once, before `state` becomes READY, and only
+// shared after that.
+unsafe impl<T: Send + Sync> Sync for Global<T> {}
+
+impl<T> Global<T> {
+    /// Create an uninitialized global called `name` in error messages.
+    pub const fn new(name: &'static str) -> Self {
+        Global {
+            name,
+            state: AtomicU8::new(UNINIT),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Store `value` and return a reference to it. Fails if the global was
+    /// already initialized.
+    pub fn init(&self, value: T) -> Result<&T, GlobalError> {
+        self.state
+            .compare_exchange(UNINIT, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .map_err(|_| GlobalError::AlreadyInitialized(self.name))?;
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(READY, Ordering::Release);
+        self.try_get()
+    }
+
+    /// Return the value, or an error if [`init`](Self::init) hasn't
+    /// finished.
+    pub fn try_get(&self) -> Result<&T, GlobalError> {
+        if self.state.load(Ordering::Acquire) == READY {
+            Ok(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            Err(GlobalError::UsedBeforeInit(self.name))
+        }
+    }
+
+    /// Return the value.
+    ///
+    /// # Panics
+    ///
+    /// Panics with "used before init: <name>" before [`init`](Self::init).
+    pub fn get(&self) -> &T {
+        match self.try_get() {
+            Ok(value) => value,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Return whether [`init`](Self::init) has run.
+    pub fn is_initialized(&self) -> bool {
+        self.try_get().is_ok()
+    }
+
+    /// Take the value out, making the global uninitialized again.
+    ///
+    /// # Safety
+    ///
+    /// No reference returned by [`get`](Self::get) or
+    /// [`try_get`](Self::try_get) may still be in use.
+    #[cfg(test)]
+    unsafe fn reset(&self) -> Option<T> {
+        self.state.compare_exchange(READY, WRITING, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        let value = unsafe { (*self.value.get()).assume_init_read() };
+        self.state.store(UNINIT, Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T> Drop for Global<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// A spinlock that keeps interrupts disabled while it is held.
+///
+/// [`lock`](Self::lock) saves whether interrupts were enabled, disables
+/// them, and only then spins. Dropping the guard releases the lock and
+/// restores the saved state. A lock taken while another guard is alive
+/// finds interrupts already off, so releasing it leaves them off until the
+/// outer guard goes too.
+///
+/// A lock made with [`named`](Self::named) is checked for lock order
+/// inversions under the `lock-order` feature.
+pub struct IrqMutex<T> {
+    inner: spin::Mutex<T>,
+    name: Option<&'static str>,
+    #[cfg(feature = "lock-order")]
+    class: lock_order::Class,
+}
+
+/// Guard of an [`IrqMutex`]; derefs to the protected value.
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    /// Whether to enable interrupts again after unlocking.
+    reenable: bool,
+    #[cfg(feature = "lock-order")]
+    held: lock_order::Held,
+}
+
+impl<T> IrqMutex<T> {
+    /// Create an unlocked mutex holding `value`.
+    pub const fn new(value: T) -> Self {
+        IrqMutex {
+            inner: spin::Mutex::new(value),
+            name: None,
+            #[cfg(feature = "lock-order")]
+            class: lock_order::Class::new(),
+        }
+    }
+
+    /// Create an unlocked mutex holding `value`, called `name` when lock
+    /// order is checked. Locks with the same name count as one.
+    pub const fn named(name: &'static str, value: T) -> Self {
+        IrqMutex {
+            inner: spin::Mutex::new(value),
+            name: Some(name),
+            #[cfg(feature = "lock-order")]
+            class: lock_order::Class::new(),
+        }
+    }
+
+    /// Return the name given to [`named`](Self::named).
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Disable interrupts and acquire the lock, spinning until it is free.
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let reenable = interrupts::are_enabled();
+        interrupts::disable();
+        self.lock_with(reenable, Location::caller())
+    }
+
+    /// Acquire the lock with interrupts already off, checking the order
+    /// first so an inversion is reported rather than spun on.
+    fn lock_with(&self, reenable: bool, site: &'static Location<'static>) -> IrqMutexGuard<'_, T> {
+        #[cfg(feature = "lock-order")]
+        let held = self.class.acquire(self.name, site);
+        #[cfg(not(feature = "lock-order"))]
+        let _ = site;
+        let guard = self.inner.lock();
+        held_count().fetch_add(1, Ordering::Relaxed);
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(guard),
+            reenable,
+            #[cfg(feature = "lock-order")]
+            held,
+        }
+    }
+
+    /// Acquire the lock if it is free, with interrupts disabled until the
+    /// guard drops. On failure, the interrupt state is left as it was.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let reenable = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                held_count().fetch_add(1, Ordering::Relaxed);
+                Some(IrqMutexGuard {
+                    guard: ManuallyDrop::new(guard),
+                    reenable,
+                    #[cfg(feature = "lock-order")]
+                    held: self.class.try_acquire(self.name, Location::caller()),
+                })
+            }
+            None => {
+                if reenable {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Acquire the lock when interrupts are known to be off, as in an
+    /// interrupt handler. Skips saving the interrupt state; the guard never
+    /// enables interrupts.
+    #[track_caller]
+    pub fn lock_irq_already_disabled(&self) -> IrqMutexGuard<'_, T> {
+        debug_assert!(!interrupts::are_enabled(), "interrupts are enabled");
+        self.lock_with(false, Location::caller())
+    }
+
+    /// Return whether the lock is held. Only a hint: it may change right
+    /// after.
+    pub fn is_locked(&self) -> bool {
+        self.inner.try_lock().is_none()
+    }
+
+    /// Release the lock without a guard.
+    ///
+    /// The holder's interrupt state is not restored.
+    ///
+    /// # Safety
+    ///
+    /// No guard of this lock may be used afterwards.
+    pub unsafe fn force_unlock(&self) {
+        unsafe { self.inner.force_unlock() }
+    }
+}
+
+/// Return how many [`IrqMutex`] guards this CPU holds: taken, and neither
+/// dropped nor forgotten. [`force_unlock`](IrqMutex::force_unlock) doesn't
+/// count as a drop.
+pub fn locks_held() -> usize {
+    held_count().load(Ordering::Relaxed)
+}
+
+/// The calling CPU's count behind [`locks_held`].
+fn held_count() -> &'static AtomicUsize {
+    &crate::smp::this_cpu().locks_held
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock first: an interrupt arriving right after `enable` may want
+        // the lock.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        held_count().fetch_sub(1, Ordering::Relaxed);
+        #[cfg(feature = "lock-order")]
+        self.held.release();
+        if self.reenable {
+            interrupts::enable();
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for IrqMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("IrqMutex").field("data", &*guard).finish(),
+            None => f.write_str("IrqMutex { <locked> }"),
+        }
+    }
+}
+
+#[test_case]
+fn test_global_init_order() {
+    static VALUE: Global<u32> = Global::new("VALUE");
+
+    assert_eq!(VALUE.try_get(), Err(GlobalError::UsedBeforeInit("VALUE")));
+    assert_eq!(alloc::format!("{}", VALUE.try_get().unwrap_err()), "used before init: VALUE");
+    assert_eq!(VALUE.init(7), Ok(&7));
+    assert_eq!(VALUE.init(8), Err(GlobalError::AlreadyInitialized("VALUE")));
+    assert_eq!(*VALUE.get(), 7);
+    assert_eq!(unsafe { VALUE.reset() }, Some(7));
+    assert!(!VALUE.is_initialized());
+    assert_eq!(unsafe { VALUE.reset() }, None);
+}
+
+#[test_case]
+fn test_writer_used_before_init() {
+    use crate::vga_buffer::WRITER;
+
+    interrupts::without_interrupts(|| {
+        let writer = unsafe { WRITER.reset() }.unwrap();
+        let err = WRITER.try_get().err().unwrap();
+        assert_eq!(alloc::format!("{}", err), "used before init: WRITER");
+        // Printing drops the output rather than panicking.
+        crate::vga_buffer::_print(format_args!("printed with no writer\n"));
+        assert!(WRITER.init(writer).is_ok());
+    });
+}
+
+#[test_case]
+fn test_nested_locks_restore_interrupts() {
+    static OUTER: IrqMutex<u32> = IrqMutex::new(0);
+    static INNER: IrqMutex<u32> = IrqMutex::new(0);
+
+    assert!(interrupts::are_enabled());
+    let mut outer = OUTER.lock();
+    assert!(!interrupts::are_enabled());
+    {
+        let mut inner = INNER.lock();
+        *inner += 1;
+        assert!(!interrupts::are_enabled());
+    }
+    // Dropping the inner guard must not enable interrupts under the outer.
+    assert!(!interrupts::are_enabled());
+    assert!(INNER.try_lock().is_some());
+    assert!(!interrupts::are_enabled());
+    *outer += 1;
+    drop(outer);
+    assert!(interrupts::are_enabled());
+
+    // A failed try_lock leaves interrupts as they were.
+    let held = OUTER.lock();
+    interrupts::enable();
+    assert!(OUTER.try_lock().is_none());
+    assert!(interrupts::are_enabled());
+    interrupts::disable();
+    drop(held);
+    assert!(interrupts::are_enabled());
+
+    // Taken with interrupts off, a guard leaves them off.
+    interrupts::disable();
+    drop(OUTER.lock_irq_already_disabled());
+    drop(OUTER.lock());
+    assert!(!interrupts::are_enabled());
+    interrupts::enable();
+    assert_eq!(*OUTER.lock() + *INNER.lock(), 2);
+}
+
+#[test_case]
+fn test_locks_held_counts_guards() {
+    static LOCK: IrqMutex<u32> = IrqMutex::new(0);
+
+    let before = locks_held();
+    let guard = LOCK.lock();
+    assert_eq!(locks_held(), before + 1);
+    assert!(LOCK.try_lock().is_none());
+    assert_eq!(locks_held(), before + 1);
+    drop(guard);
+    let guard = LOCK.try_lock().unwrap();
+    assert_eq!(locks_held(), before + 1);
+    drop(guard);
+    assert_eq!(locks_held(), before);
+}
+
+#[test_case]
+fn test_writer_held_across_timer_ticks() {
+    // The old deadlock: a timer interrupt arriving while WRITER is held runs
+    // a handler that wants WRITER too. Holding it now keeps IRQs off, so no
+    // tick can be handled until it is released.
+    use crate::interrupts::ticks;
+    use crate::time::rdtsc;
+
+    // Time a tick in TSC cycles, so the lock is held across several.
+    let wait_for_tick = || {
+        let start = ticks();
+        while ticks() == start {
+            core::hint::spin_loop();
+        }
+        rdtsc()
+    };
+    wait_for_tick();
+    let tick_start = rdtsc();
+    let cycles_per_tick = wait_for_tick() - tick_start;
+
+    let before = ticks();
+    let writer = crate::vga_buffer::WRITER.get().lock();
+    let deadline = rdtsc() + 3 * cycles_per_tick;
+    while rdtsc() < deadline {
+        core::hint::spin_loop();
+    }
+    assert_eq!(ticks(), before);
+    drop(writer);
+    // The tick held back by the PIC is delivered once interrupts are on.
+    wait_for_tick();
+    crate::println!("printed after holding the writer");
+}
@@ -0,0 +1,278 @@
+//! Lock order checking for [`IrqMutex`](super::IrqMutex), with the
+//! `lock-order` feature.
+//!
+//! Two locks taken in opposite orders on two paths deadlock only when both
+//! paths run at once, which may be rare enough never to show up in testing.
+//! Here every [named](super::IrqMutex::named) lock belongs to a class, one
+//! per name, numbered in the order classes are first locked. Each CPU keeps
+//! the classes it holds; taking class B while holding A records the edge
+//! A → B in a matrix shared by all CPUs, together with where both were
+//! taken. If B → A was ever recorded, the order is inconsistent and the
+//! lock panics with "lock order inversion: B vs A" and both acquisition
+//! sites, before it starts spinning. Checking stops after the first
+//! inversion.
+//!
+//! Unnamed locks aren't checked, and neither are plain `spin::Mutex`es. A
+//! CPU is told apart by its [per-CPU block](crate::smp::current); before it
+//! has one, it counts as the boot CPU.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::panic::Location;
+use core::ptr::{self, null_mut};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicU32, Ordering};
+
+use crate::collections::FixedVec;
+use crate::smp::{self, MAX_CPUS};
+
+/// Lock classes the matrix has room for. Classes past this aren't checked.
+pub const MAX_CLASSES: usize = 32;
+/// Locks one CPU can hold at once and still have them checked.
+const MAX_HELD: usize = 16;
+
+/// [`Class`] states other than a class number.
+const UNREGISTERED: u8 = u8::MAX;
+const UNTRACKED: u8 = u8::MAX - 1;
+
+type Site = &'static Location<'static>;
+
+/// Where the edge `from` → `to` was first seen.
+struct Edge {
+    /// Where `from` was taken.
+    held: AtomicPtr<Location<'static>>,
+    /// Where `to` was then taken.
+    taken: AtomicPtr<Location<'static>>,
+}
+
+/// Class names, by number.
+static NAMES: spin::Mutex<FixedVec<&'static str, MAX_CLASSES>> = spin::Mutex::new(FixedVec::new());
+/// `EDGES[a]` has bit `b` set once class `b` was taken while `a` was held.
+static EDGES: [AtomicU32; MAX_CLASSES] = [const { AtomicU32::new(0) }; MAX_CLASSES];
+static SITES: [[Edge; MAX_CLASSES]; MAX_CLASSES] = [const {
+    [const { Edge { held: AtomicPtr::new(null_mut()), taken: AtomicPtr::new(null_mut()) } };
+        MAX_CLASSES]
+}; MAX_CLASSES];
+/// Set once an inversion was reported, so the panic that follows (which
+/// prints under locks of its own) isn't checked too.
+static TRIPPED: AtomicBool = AtomicBool::new(false);
+
+/// A lock held by a CPU.
+#[derive(Clone, Copy)]
+struct HeldLock {
+    class: u8,
+    site: Site,
+}
+
+/// Each CPU's held locks, innermost last.
+struct HeldStacks([UnsafeCell<FixedVec<HeldLock, MAX_HELD>>; MAX_CPUS]);
+
+// SAFETY: a CPU only touches its own stack, with interrupts off.
+unsafe impl Sync for HeldStacks {}
+
+static HELD: HeldStacks = HeldStacks([const { UnsafeCell::new(FixedVec::new()) }; MAX_CPUS]);
+
+/// Run `f` on the calling CPU's held locks. Interrupts must be off.
+fn with_held<R>(f: impl FnOnce(&mut FixedVec<HeldLock, MAX_HELD>) -> R) -> R {
+    let cpu = smp::current().map_or(0, |cpu| cpu.index);
+    f(unsafe { &mut *HELD.0[cpu].get() })
+}
+
+/// The class of one lock, looked up by name when it is first taken.
+pub struct Class(AtomicU8);
+
+impl Class {
+    pub const fn new() -> Self {
+        Class(AtomicU8::new(UNREGISTERED))
+    }
+
+    /// Return the class number of the lock called `name`, registering it
+    /// on first use; `None` if the lock isn't checked.
+    fn get(&self, name: Option<&'static str>) -> Option<u8> {
+        match self.0.load(Ordering::Relaxed) {
+            UNTRACKED => None,
+            UNREGISTERED => {
+                let class = name.and_then(register);
+                self.0.store(class.unwrap_or(UNTRACKED), Ordering::Relaxed);
+                class
+            }
+            class => Some(class),
+        }
+    }
+
+    /// Check that taking the lock called `name` at `site` keeps the order
+    /// seen so far, then count it as held. Interrupts must be off.
+    ///
+    /// # Panics
+    ///
+    /// Panics with "lock order inversion: ..." if some held lock was
+    /// earlier taken after this one.
+    pub(super) fn acquire(&self, name: Option<&'static str>, site: Site) -> Held {
+        let held = self.try_acquire(name, site);
+        if let Some(class) = held.0
+            && let Err(inversion) = check(class, site)
+        {
+            // Leave the books as they were for anything the panic locks.
+            held.release();
+            if !TRIPPED.swap(true, Ordering::Relaxed) {
+                panic!("{}", inversion);
+            }
+        }
+        held
+    }
+
+    /// Count the lock called `name` as held without checking the order, as
+    /// for a `try_lock` that succeeded: it can't deadlock. Interrupts must
+    /// be off.
+    pub(super) fn try_acquire(&self, name: Option<&'static str>, site: Site) -> Held {
+        if TRIPPED.load(Ordering::Relaxed) {
+            return Held(None);
+        }
+        let Some(class) = self.get(name) else {
+            return Held(None);
+        };
+        let pushed = with_held(|stack| stack.push(HeldLock { class, site }).is_ok());
+        Held(pushed.then_some(class))
+    }
+}
+
+impl Default for Class {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A checked lock the calling CPU holds; [`release`](Self::release) it
+/// when the lock is.
+pub(super) struct Held(Option<u8>);
+
+impl Held {
+    /// Forget the lock. Interrupts must still be off.
+    pub(super) fn release(&self) {
+        let Some(class) = self.0 else { return };
+        with_held(|stack| {
+            if let Some(index) = stack.iter().rposition(|held| held.class == class) {
+                stack[index..].rotate_left(1);
+                stack.pop();
+            }
+        });
+    }
+}
+
+/// Return the number of class `name`, adding it if it's new.
+fn register(name: &'static str) -> Option<u8> {
+    let mut names = NAMES.lock();
+    if let Some(class) = names.iter().position(|&known| known == name) {
+        return Some(class as u8);
+    }
+    names.push(name).ok()?;
+    Some((names.len() - 1) as u8)
+}
+
+fn name(class: u8) -> &'static str {
+    NAMES.lock().get(usize::from(class)).copied().unwrap_or("?")
+}
+
+/// Two locks seen taken in both orders.
+#[derive(Debug, Clone, Copy)]
+pub struct Inversion {
+    /// The lock taken first the first time, and where. The site is `None`
+    /// if the CPU that saw it hasn't finished recording it.
+    pub first: (&'static str, Option<Site>),
+    /// The lock taken while holding it, and where.
+    pub then: (&'static str, Option<Site>),
+    /// The lock held now, the other way round, and where it was taken.
+    pub now_held: Site,
+    /// Where [`first`](Self::first) is being taken now.
+    pub now_taking: Site,
+}
+
+impl fmt::Display for Inversion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (first, first_at) = self.first;
+        let (then, then_at) = self.then;
+        writeln!(f, "lock order inversion: {} vs {}", first, then)?;
+        write!(f, "  earlier: {} at ", first)?;
+        write_site(f, first_at)?;
+        write!(f, ", then {} at ", then)?;
+        write_site(f, then_at)?;
+        writeln!(f)?;
+        write!(f, "  now:     {} at {}, then {} at {}", then, self.now_held, first, self.now_taking)
+    }
+}
+
+fn write_site(f: &mut fmt::Formatter<'_>, site: Option<Site>) -> fmt::Result {
+    match site {
+        Some(site) => write!(f, "{}", site),
+        None => f.write_str("?"),
+    }
+}
+
+/// Record the edges from every held class to `class`, taken at `site`,
+/// unless one of them has been seen the other way round.
+fn check(class: u8, site: Site) -> Result<(), Inversion> {
+    with_held(|stack| {
+        // The new lock was pushed last; everything below it is held.
+        let held = &stack[..stack.len() - 1];
+        for earlier in held.iter().filter(|held| held.class != class) {
+            let (a, b) = (usize::from(earlier.class), usize::from(class));
+            if EDGES[b].load(Ordering::Acquire) & (1 << a) != 0 {
+                let edge = &SITES[b][a];
+                let first_at = unsafe { edge.held.load(Ordering::Relaxed).as_ref() };
+                let then_at = unsafe { edge.taken.load(Ordering::Relaxed).as_ref() };
+                return Err(Inversion {
+                    first: (name(class), first_at),
+                    then: (name(earlier.class), then_at),
+                    now_held: earlier.site,
+                    now_taking: site,
+                });
+            }
+            let edge = &SITES[a][b];
+            // The first CPU to see the edge records where.
+            if edge
+                .taken
+                .compare_exchange(null_mut(), ptr::from_ref(site).cast_mut(), Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+            {
+                edge.held.store(ptr::from_ref(earlier.site).cast_mut(), Ordering::Relaxed);
+            }
+            EDGES[a].fetch_or(1 << b, Ordering::Release);
+        }
+        Ok(())
+    })
+}
+
+/// Forget every lock the calling CPU holds, for when the code holding them
+/// was abandoned (see [`crate::task::recovery`]). Interrupts must be off.
+pub fn forget_held() {
+    with_held(|stack| stack.clear());
+}
+
+/// Return how many classes have been registered.
+pub fn class_count() -> usize {
+    NAMES.lock().len()
+}
+
+#[test_case]
+fn test_inversion_found_before_spinning() {
+    use super::IrqMutex;
+
+    static C: IrqMutex<()> = IrqMutex::named("test C", ());
+    static D: IrqMutex<()> = IrqMutex::named("test D", ());
+
+    let c = C.lock();
+    let d = D.lock();
+    drop(d);
+    drop(c);
+    let d = D.lock();
+    // try_lock can't deadlock, so it isn't checked; check it by hand.
+    let c = C.try_lock().expect("C is free");
+    let class = C.class.get(C.name()).expect("C is named");
+    let inversion = check(class, Location::caller()).expect_err("D then C after C then D");
+    drop(c);
+    drop(d);
+
+    let message = alloc::format!("{}", inversion);
+    assert!(message.starts_with("lock order inversion: test C vs test D\n"), "{}", message);
+    assert!(message.contains("src/sync/lock_order.rs"));
+    assert!(!TRIPPED.load(Ordering::Relaxed));
+}
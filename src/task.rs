@@ -0,0 +1,260 @@
+//! Cooperative multitasking built on Rust futures.
+//!
+//! A [`Task`] is a heap-allocated, pinned future with no output. Executors
+//! (see [`executor`] and [`simple_executor`]) own a set of tasks and poll them until they
+//! complete. Tasks only give up the CPU at `.await` points, so nothing here
+//! preempts a task that never returns `Poll::Pending`.
+
+use crate::console::Console;
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+pub mod blocking;
+pub mod channel;
+pub mod executor;
+pub mod futures;
+pub mod join;
+pub mod keyboard;
+pub mod line_edit;
+pub mod recovery;
+pub mod simple_executor;
+pub mod sync;
+pub mod timer;
+
+pub use blocking::{block_on, block_on_with_timeout};
+pub use channel::channel;
+pub use recovery::OnPanic;
+pub use timer::{sleep, sleep_ticks, timeout, Elapsed};
+
+/// Unique identifier of a task.
+///
+/// IDs come from a global counter and are never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    /// Allocate the next unused task ID.
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Scheduling priority of a task.
+///
+/// The executor always prefers ready tasks of a higher priority, with a
+/// starvation guard that still lets lower priorities run now and then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    /// Latency-sensitive work such as input echo.
+    High,
+    /// The default.
+    #[default]
+    Normal,
+    /// Background work such as scrubbers.
+    Low,
+}
+
+impl Priority {
+    /// Number of priority levels.
+    pub const COUNT: usize = 3;
+
+    /// All priorities, highest first.
+    pub const ALL: [Priority; Priority::COUNT] = [Priority::High, Priority::Normal, Priority::Low];
+
+    /// Index of this priority in [`ALL`](Self::ALL).
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A unit of cooperative work: a pinned, boxed future returning `()`.
+///
+/// Creating a task requires the heap to be initialized.
+pub struct Task {
+    id: TaskId,
+    /// Optional human-readable name shown in task listings.
+    name: Option<&'static str>,
+    priority: Priority,
+    /// What the executor does if polling this task panics.
+    on_panic: OnPanic,
+    /// Where its `print!` output goes, if not the selected console.
+    console: Option<Console>,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    /// Longest single poll observed so far, in timer ticks.
+    ///
+    /// A task that never yields shows up here with a large value, which is
+    /// the usual sign that it is starving everything else.
+    #[cfg(debug_assertions)]
+    max_poll_ticks: u64,
+}
+
+impl Task {
+    /// Wrap `future` into a task with a fresh [`TaskId`].
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Self::build(None, future)
+    }
+
+    /// Like [`new`](Self::new), but gives the task a name for debugging.
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
+        Self::build(Some(name), future)
+    }
+
+    fn build(name: Option<&'static str>, future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            name,
+            priority: Priority::default(),
+            on_panic: OnPanic::default(),
+            console: executor::task_console(),
+            future: Box::pin(future),
+            #[cfg(debug_assertions)]
+            max_poll_ticks: 0,
+        }
+    }
+
+    /// Return this task's ID.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Return this task's name, if it was given one.
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Set the scheduling priority (the default is [`Priority::Normal`]).
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
+
+    /// Return this task's scheduling priority.
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Send this task's `print!` output to `console`, whatever the selected
+    /// console is. Tasks take the console of the task that created them
+    /// unless given one.
+    pub fn with_console(mut self, console: Console) -> Task {
+        self.console = Some(console);
+        self
+    }
+
+    /// Return the console this task prints to, if it has its own.
+    pub fn console(&self) -> Option<Console> {
+        self.console
+    }
+
+    /// Choose what happens if this task panics (the default is
+    /// [`OnPanic::Halt`]). See [`recovery`] for how panics are contained.
+    pub fn on_panic(mut self, on_panic: OnPanic) -> Task {
+        self.on_panic = on_panic;
+        self
+    }
+
+    /// Return the longest time a single poll of this task has taken, in
+    /// timer ticks.
+    ///
+    /// Only tracked in debug builds.
+    #[cfg(debug_assertions)]
+    pub fn max_poll_ticks(&self) -> u64 {
+        self.max_poll_ticks
+    }
+
+    /// Poll the wrapped future once.
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        #[cfg(debug_assertions)]
+        let start = crate::interrupts::ticks();
+
+        let result = self.future.as_mut().poll(context);
+
+        #[cfg(debug_assertions)]
+        {
+            let elapsed = crate::interrupts::ticks() - start;
+            self.max_poll_ticks = self.max_poll_ticks.max(elapsed);
+        }
+        result
+    }
+}
+
+/// Give other ready tasks a chance to run before continuing.
+///
+/// Tasks are never preempted, so a long-running computation should await this
+/// between chunks of work. The returned future is `Pending` exactly once: it
+/// wakes itself immediately, so the executor moves the task to the back of
+/// the ready queue, and completes on the next poll.
+///
+/// ```ignore
+/// for chunk in work.chunks(64) {
+///     process(chunk);
+///     task::yield_now().await;
+/// }
+/// ```
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// Future returned by [`yield_now`].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[test_case]
+fn test_yield_now_is_pending_once() {
+    use core::sync::atomic::AtomicUsize;
+    use test_util::counting_waker;
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let waker = counting_waker(&WAKES);
+    let mut context = Context::from_waker(&waker);
+    let mut future = yield_now();
+
+    assert_eq!(Pin::new(&mut future).poll(&mut context), Poll::Pending);
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    assert_eq!(Pin::new(&mut future).poll(&mut context), Poll::Ready(()));
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+}
+
+/// Helpers shared by the task-related unit tests.
+#[cfg(test)]
+pub(crate) mod test_util {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::task::{RawWaker, RawWakerVTable, Waker};
+
+    /// Build a waker that increments `counter` every time it is woken.
+    pub fn counting_waker(counter: &'static AtomicUsize) -> Waker {
+        fn clone(data: *const ()) -> RawWaker {
+            RawWaker::new(data, &VTABLE)
+        }
+        fn wake(data: *const ()) {
+            let counter = unsafe { &*(data as *const AtomicUsize) };
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+        fn drop(_: *const ()) {}
+
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+        let raw = RawWaker::new(counter as *const AtomicUsize as *const (), &VTABLE);
+        unsafe { Waker::from_raw(raw) }
+    }
+}
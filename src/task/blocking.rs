@@ -0,0 +1,202 @@
+//! Run a future to completion from code that isn't a task.
+//!
+//! Init code and tests sometimes need the result of an async operation, a
+//! sleep or a value from a channel, before the executor's main loop runs.
+//! [`block_on`] polls the future with a waker that only sets a flag, and
+//! halts with interrupts enabled between polls, so the timer and keyboard
+//! interrupts that drive the wake-ups still arrive.
+//!
+//! Blocking from an interrupt handler, or from a task the executor is
+//! polling, would stop the very thing that has to wake the future. Debug
+//! builds assert that neither is the case; see [`can_block`].
+
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::fmt;
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts;
+
+use super::{executor, Elapsed};
+use crate::interrupts::{in_interrupt, ticks};
+
+/// Why the caller can't [`block_on`] a future.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CannotBlock {
+    /// An interrupt handler is running.
+    InInterrupt,
+    /// The executor is polling a task.
+    InTask,
+}
+
+impl fmt::Display for CannotBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CannotBlock::InInterrupt => f.write_str("block_on called from an interrupt handler"),
+            CannotBlock::InTask => {
+                f.write_str("block_on called from a task; it would stall the executor")
+            }
+        }
+    }
+}
+
+/// Return whether the caller may block on a future.
+pub fn can_block() -> Result<(), CannotBlock> {
+    if in_interrupt() {
+        Err(CannotBlock::InInterrupt)
+    } else if executor::is_polling() {
+        Err(CannotBlock::InTask)
+    } else {
+        Ok(())
+    }
+}
+
+/// Wakes [`block_on`] by setting a flag.
+struct FlagWaker {
+    woken: AtomicBool,
+}
+
+impl Wake for FlagWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+    }
+}
+
+/// Poll `future` until it completes and return its output.
+///
+/// The CPU halts between polls until an interrupt arrives, with interrupts
+/// enabled even if the caller had them off; they are off again on return.
+/// The waker is reference-counted, so the heap must be up.
+///
+/// # Panics
+///
+/// In debug builds, panics if called from an interrupt handler or from a
+/// task (see [`can_block`]).
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    match run(future, None) {
+        Ok(output) => output,
+        Err(Elapsed) => unreachable!("block_on has no deadline"),
+    }
+}
+
+/// Like [`block_on`], but give up after `ticks` timer ticks and drop the
+/// future.
+pub fn block_on_with_timeout<F: Future>(future: F, ticks: u64) -> Result<F::Output, Elapsed> {
+    run(future, Some(self::ticks() + ticks))
+}
+
+fn run<F: Future>(future: F, deadline: Option<u64>) -> Result<F::Output, Elapsed> {
+    if let Err(reason) = can_block() {
+        debug_assert!(false, "{}", reason);
+    }
+    let were_enabled = interrupts::are_enabled();
+    let flag = Arc::new(FlagWaker { woken: AtomicBool::new(true) });
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    let result = loop {
+        if flag.woken.swap(false, Ordering::Acquire)
+            && let Poll::Ready(output) = future.as_mut().poll(&mut context)
+        {
+            break Ok(output);
+        }
+        if deadline.is_some_and(|deadline| ticks() >= deadline) {
+            break Err(Elapsed);
+        }
+        // As in the executor: check with interrupts off so a wake-up between
+        // the check and `hlt` isn't missed.
+        interrupts::disable();
+        if flag.woken.load(Ordering::Acquire) {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    };
+    if !were_enabled {
+        interrupts::disable();
+    }
+    result
+}
+
+#[test_case]
+fn test_block_on_ready_future() {
+    assert_eq!(block_on(async { 7 }), 7);
+    assert_eq!(block_on_with_timeout(async { "now" }, 0), Ok("now"));
+}
+
+#[test_case]
+fn test_block_on_sleep_and_channel() {
+    use super::{channel, sleep_ticks, timer};
+    use futures_util::StreamExt;
+
+    let start = ticks();
+    block_on(sleep_ticks(3));
+    assert!((3..=5).contains(&(ticks() - start)), "slept {} ticks", ticks() - start);
+    assert_eq!(timer::active_count(), 0);
+
+    // The receiver is woken from the timer interrupt path, not by polling.
+    let (sender, mut receiver) = channel(2);
+    let received = block_on(async {
+        let sent = async {
+            sleep_ticks(2).await;
+            sender.try_send(42).unwrap();
+        };
+        super::futures::join(sent, receiver.next()).await.1
+    });
+    assert_eq!(received, Some(42));
+}
+
+#[test_case]
+fn test_block_on_timeout_fires() {
+    use super::timer;
+
+    let start = ticks();
+    let result = block_on_with_timeout(core::future::pending::<()>(), 3);
+    assert_eq!(result, Err(Elapsed));
+    assert!((3..=5).contains(&(ticks() - start)), "gave up after {} ticks", ticks() - start);
+
+    // A sleep that outlasts the timeout is dropped and unregistered.
+    assert_eq!(block_on_with_timeout(super::sleep_ticks(50), 2), Err(Elapsed));
+    assert_eq!(timer::active_count(), 0);
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_block_on_restores_interrupts_off() {
+    interrupts::disable();
+    block_on(super::sleep_ticks(1));
+    assert!(!interrupts::are_enabled());
+    interrupts::enable();
+}
+
+#[test_case]
+fn test_misuse_detected_in_task_and_interrupt() {
+    use super::executor::Executor;
+    use super::Task;
+    use crate::interrupts::IrqContext;
+
+    static IN_TASK: spin::Mutex<Option<Result<(), CannotBlock>>> = spin::Mutex::new(None);
+
+    assert_eq!(can_block(), Ok(()));
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        *IN_TASK.lock() = Some(can_block());
+    }));
+    executor.run_until_idle();
+    assert_eq!(*IN_TASK.lock(), Some(Err(CannotBlock::InTask)));
+    assert_eq!(can_block(), Ok(()));
+
+    interrupts::without_interrupts(|| {
+        let irq = IrqContext::enter();
+        assert_eq!(can_block(), Err(CannotBlock::InInterrupt));
+        drop(irq);
+    });
+    assert_eq!(can_block(), Ok(()));
+}
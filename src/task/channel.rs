@@ -0,0 +1,275 @@
+//! Bounded channel for handing values from interrupt handlers to tasks.
+//!
+//! [`channel`] allocates a fixed-size lock-free queue up front. After that,
+//! [`Sender::try_send`] never blocks or allocates, so it can be called from
+//! interrupt context; values that don't fit are dropped and counted. The
+//! [`Receiver`] is a [`Stream`] that parks the consuming task until a value
+//! arrives, and ends once every sender has been dropped and the queue is
+//! drained.
+
+use alloc::sync::Arc;
+use core::fmt;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+/// State shared by all ends of one channel.
+struct Shared<T> {
+    queue: ArrayQueue<T>,
+    /// Waker of the task polling the receiver.
+    waker: AtomicWaker,
+    /// Number of live [`Sender`]s.
+    senders: AtomicUsize,
+    /// Set when the receiver is dropped.
+    closed: AtomicBool,
+    /// Values rejected because the queue was full.
+    dropped: AtomicU64,
+}
+
+/// Create a channel that buffers up to `capacity` values.
+///
+/// Panics if `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: ArrayQueue::new(capacity),
+        waker: AtomicWaker::new(),
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+        dropped: AtomicU64::new(0),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Error returned by [`Sender::try_send`], handing the value back.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The queue is full; the value was dropped and counted.
+    Full(T),
+    /// The receiver is gone.
+    Closed(T),
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("channel full"),
+            TrySendError::Closed(_) => f.write_str("channel closed"),
+        }
+    }
+}
+
+/// Sending end of a [`channel`]. Cloneable; safe to use from interrupt context.
+///
+/// Dropping the last sender frees nothing as long as the receiver is alive,
+/// so that is fine in interrupt context too.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Queue `value` and wake the receiver.
+    ///
+    /// Never blocks or allocates.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.shared.closed.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+        match self.shared.queue.push(value) {
+            Ok(()) => {
+                self.shared.waker.wake();
+                Ok(())
+            }
+            Err(value) => {
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                Err(TrySendError::Full(value))
+            }
+        }
+    }
+
+    /// Return the number of values dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Return whether the queue is full, so a send right now would fail.
+    pub fn is_full(&self) -> bool {
+        self.shared.queue.is_full()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Let the receiver observe the close.
+            self.shared.waker.wake();
+        }
+    }
+}
+
+/// Receiving end of a [`channel`].
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Take the next queued value without waiting.
+    pub fn try_recv(&self) -> Option<T> {
+        self.shared.queue.pop()
+    }
+
+    /// Return the number of values dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Return whether every sender has been dropped.
+    fn senders_gone(&self) -> bool {
+        self.shared.senders.load(Ordering::Acquire) == 0
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        // fast path
+        if let Some(value) = self.try_recv() {
+            return Poll::Ready(Some(value));
+        }
+
+        // Register before the second check so a send (or the last sender's
+        // drop) landing in between either shows up below or wakes the freshly
+        // registered waker.
+        self.shared.waker.register(cx.waker());
+        if let Some(value) = self.try_recv() {
+            self.shared.waker.take();
+            return Poll::Ready(Some(value));
+        }
+        if self.senders_gone() {
+            // A final send may have raced with the close check.
+            return Poll::Ready(self.try_recv());
+        }
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn test_values_arrive_in_fifo_order() {
+    use crate::task::test_util::counting_waker;
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let (sender, mut receiver) = channel(4);
+    for value in 1..=4 {
+        sender.try_send(value).unwrap();
+    }
+
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    for expected in 1..=4 {
+        assert_eq!(
+            Pin::new(&mut receiver).poll_next(&mut cx),
+            Poll::Ready(Some(expected))
+        );
+    }
+    assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Pending);
+}
+
+#[test_case]
+fn test_full_queue_drops_and_counts() {
+    let (sender, receiver) = channel(2);
+    assert_eq!(sender.try_send('a'), Ok(()));
+    assert_eq!(sender.try_send('b'), Ok(()));
+    assert_eq!(sender.try_send('c'), Err(TrySendError::Full('c')));
+    assert_eq!(sender.try_send('d'), Err(TrySendError::Full('d')));
+    assert_eq!(receiver.dropped(), 2);
+
+    // Draining makes room again.
+    assert_eq!(receiver.try_recv(), Some('a'));
+    assert_eq!(sender.try_send('e'), Ok(()));
+    assert_eq!(receiver.try_recv(), Some('b'));
+    assert_eq!(receiver.try_recv(), Some('e'));
+    assert_eq!(sender.dropped(), 2);
+}
+
+#[test_case]
+fn test_send_wakes_receiver_registered_in_between() {
+    use crate::task::test_util::counting_waker;
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let (sender, mut receiver) = channel(4);
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+
+    // A send with nobody registered wakes no one but must not be lost.
+    sender.try_send(1).unwrap();
+    assert_eq!(WAKES.load(Ordering::SeqCst), 0);
+    assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Ready(Some(1)));
+
+    // An empty poll registers; the next send wakes exactly once.
+    assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Pending);
+    sender.try_send(2).unwrap();
+    sender.try_send(3).unwrap();
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Ready(Some(2)));
+    assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Ready(Some(3)));
+}
+
+#[test_case]
+fn test_stream_ends_when_senders_drop() {
+    use crate::task::test_util::counting_waker;
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let (sender, mut receiver) = channel(4);
+    let second = sender.clone();
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+
+    second.try_send(7).unwrap();
+    drop(sender);
+    drop(second);
+
+    // Buffered values are still delivered before the end of the stream.
+    assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Ready(Some(7)));
+    assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Ready(None));
+
+    // A receiver parked on an open channel is woken by the last drop.
+    let (sender, mut receiver) = channel::<u8>(4);
+    assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Pending);
+    drop(sender);
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    assert_eq!(Pin::new(&mut receiver).poll_next(&mut cx), Poll::Ready(None));
+}
+
+#[test_case]
+fn test_send_after_receiver_dropped_is_closed() {
+    let (sender, receiver) = channel(1);
+    drop(receiver);
+    assert_eq!(sender.try_send(()), Err(TrySendError::Closed(())));
+    assert_eq!(sender.dropped(), 0);
+}
@@ -0,0 +1,1229 @@
+//! Waker-driven executor that sleeps the CPU when no task is ready.
+//!
+//! Unlike [`SimpleExecutor`](super::simple_executor::SimpleExecutor), this
+//! executor only polls tasks whose waker has been invoked. Wakers push the
+//! task's ID into a shared lock-free queue, which makes them safe to call from
+//! interrupt handlers. When the queue is empty the CPU is halted until the
+//! next interrupt.
+//!
+//! Once [`Executor::run`] has been called the executor can no longer be
+//! reached directly; new work is submitted through a [`Spawner`] instead.
+//! Per-task bookkeeping is kept in a global table so [`dump_tasks`] can list
+//! tasks while the executor is running.
+//!
+//! Each task's CPU time is measured with two TSC reads per poll, with the
+//! default `cpu-time` feature (see [`task_cpu_cycles`]), and the time spent
+//! halted always is (see [`halt_cycles`]). The `task-stats` feature adds
+//! per-executor counters (see `ExecutorStats`), for a handful of atomic
+//! adds per poll and wake-up, and per-task poll-duration histograms.
+
+use super::join::{self, JoinHandle};
+use super::recovery;
+use super::{OnPanic, Priority, Task, TaskId};
+use crate::console::Console;
+use crate::interrupts::ticks;
+use crate::smp::MAX_CPUS;
+use crate::time::Stopwatch;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::fmt;
+use core::future::Future;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+#[cfg(feature = "task-stats")]
+use core::sync::atomic::AtomicUsize;
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Maximum number of pending wake-ups that can be queued per priority.
+const TASK_QUEUE_CAPACITY: usize = 100;
+
+/// Number of consecutive polls of higher-priority tasks after which one
+/// waiting lower-priority task is polled, so background work still advances.
+const STARVATION_LIMIT: usize = 8;
+
+/// Maximum number of tasks submitted through a [`Spawner`] that can wait for
+/// the executor to pick them up.
+const NEW_TASK_QUEUE_CAPACITY: usize = 32;
+
+/// Scheduling state of a task, as shown by [`dump_tasks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Woken (or newly spawned) and waiting in the ready queue.
+    Ready,
+    /// Returned `Pending` and hasn't been woken since.
+    Waiting,
+}
+
+/// Bookkeeping for one live task.
+struct TaskInfo {
+    name: Option<&'static str>,
+    priority: Priority,
+    spawn_tick: u64,
+    polls: u64,
+    last_polled_tick: Option<u64>,
+    waker: Arc<TaskWaker>,
+    /// TSC cycles spent polling the task.
+    #[cfg(feature = "cpu-time")]
+    cpu_cycles: u64,
+    #[cfg(feature = "task-stats")]
+    poll_histogram: PollHistogram,
+}
+
+impl TaskInfo {
+    fn state(&self) -> TaskState {
+        if self.waker.woken.load(Ordering::Relaxed) {
+            TaskState::Ready
+        } else {
+            TaskState::Waiting
+        }
+    }
+}
+
+/// Metadata of every task owned by any executor, keyed by task ID.
+///
+/// Locked with interrupts disabled so a dump triggered from interrupt context
+/// can't deadlock against the executor.
+static TASK_TABLE: Mutex<BTreeMap<TaskId, TaskInfo>> = Mutex::new(BTreeMap::new());
+
+fn with_task_table<R>(f: impl FnOnce(&mut BTreeMap<TaskId, TaskInfo>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut TASK_TABLE.lock()))
+}
+
+/// Return the number of live tasks, over all executors.
+pub fn task_count() -> usize {
+    with_task_table(|table| table.len())
+}
+
+/// Write a `ps`-like table of all live tasks to `out`.
+///
+/// Tick columns are in timer ticks since boot; a `-` in the last-poll column
+/// means the task hasn't been polled yet. CPU time is in microseconds, or
+/// `-` before the TSC rate is measured or without the `cpu-time` feature.
+pub fn dump_tasks(out: &mut impl fmt::Write) -> fmt::Result {
+    with_task_table(|table| {
+        writeln!(
+            out,
+            "{:>5} {:<6} {:<8} {:>8} {:>8} {:>9} {:>11}  NAME",
+            "ID", "PRIO", "STATE", "POLLS", "SPAWNED", "LAST POLL", "CPU TIME"
+        )?;
+        for (id, info) in table.iter() {
+            let priority = match info.priority {
+                Priority::High => "high",
+                Priority::Normal => "normal",
+                Priority::Low => "low",
+            };
+            let state = match info.state() {
+                TaskState::Ready => "ready",
+                TaskState::Waiting => "waiting",
+            };
+            write!(
+                out,
+                "{:>5} {:<6} {:<8} {:>8} {:>8} ",
+                id.0, priority, state, info.polls, info.spawn_tick
+            )?;
+            match info.last_polled_tick {
+                Some(tick) => write!(out, "{:>9}", tick)?,
+                None => write!(out, "{:>9}", "-")?,
+            }
+            #[cfg(feature = "cpu-time")]
+            let micros = crate::time::cycles_to_micros(info.cpu_cycles);
+            #[cfg(not(feature = "cpu-time"))]
+            let micros: Option<u64> = None;
+            match micros {
+                Some(micros) => write!(out, " {:>9}us", micros)?,
+                None => write!(out, " {:>11}", "-")?,
+            }
+            writeln!(out, "  {}", info.name.unwrap_or("<unnamed>"))?;
+        }
+        Ok(())
+    })
+}
+
+/// Return the TSC cycles spent polling task `id`, if it is still alive.
+#[cfg(feature = "cpu-time")]
+pub fn task_cpu_cycles(id: TaskId) -> Option<u64> {
+    with_task_table(|table| table.get(&id).map(|info| info.cpu_cycles))
+}
+
+/// Distribution of a task's poll durations, with the `task-stats` feature.
+///
+/// Bucket `i` counts polls shorter than `1024 << 2 * i` TSC cycles; the last
+/// bucket counts everything longer.
+#[cfg(feature = "task-stats")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PollHistogram {
+    pub buckets: [u64; PollHistogram::BUCKETS],
+}
+
+#[cfg(feature = "task-stats")]
+impl PollHistogram {
+    pub const BUCKETS: usize = 8;
+
+    fn record(&mut self, cycles: u64) {
+        let bucket = (0..Self::BUCKETS - 1)
+            .find(|&i| cycles < 1024 << (2 * i))
+            .unwrap_or(Self::BUCKETS - 1);
+        self.buckets[bucket] += 1;
+    }
+}
+
+/// Return the poll histogram of task `id`, if it is still alive.
+#[cfg(feature = "task-stats")]
+pub fn poll_histogram(id: TaskId) -> Option<PollHistogram> {
+    with_task_table(|table| table.get(&id).map(|info| info.poll_histogram))
+}
+
+/// Snapshot of an executor's counters, with the `task-stats` feature.
+#[cfg(feature = "task-stats")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExecutorStats {
+    /// Task polls, including the final one.
+    pub polls: u64,
+    /// Wake-ups queued, including the initial one of every spawned task.
+    pub wakes: u64,
+    /// Polls of a task that had already been polled since its last wake-up,
+    /// because it was woken again before that poll. They usually just return
+    /// `Pending` again.
+    pub spurious_polls: u64,
+    /// Most wake-ups ever queued at once, over all priorities.
+    pub max_ready_depth: usize,
+    /// TSC cycles spent polling tasks.
+    pub poll_cycles: u64,
+    /// TSC cycles spent halted waiting for an interrupt.
+    pub halt_cycles: u64,
+}
+
+/// Prints a one-line digest, e.g. for a status line.
+#[cfg(feature = "task-stats")]
+impl fmt::Display for ExecutorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.poll_cycles + self.halt_cycles;
+        let busy_percent = (self.poll_cycles * 100).checked_div(total).unwrap_or(0);
+        write!(
+            f,
+            "polls {} wakes {} spurious {} max-ready {} polling {}%",
+            self.polls, self.wakes, self.spurious_polls, self.max_ready_depth, busy_percent
+        )
+    }
+}
+
+/// Live counters behind [`ExecutorStats`].
+///
+/// Wakers update them from any context, including interrupt handlers.
+#[cfg(feature = "task-stats")]
+#[derive(Default)]
+struct StatsCounters {
+    polls: AtomicU64,
+    wakes: AtomicU64,
+    spurious_polls: AtomicU64,
+    max_ready_depth: AtomicUsize,
+    poll_cycles: AtomicU64,
+}
+
+#[cfg(feature = "task-stats")]
+impl StatsCounters {
+    fn snapshot(&self, halt_cycles: u64) -> ExecutorStats {
+        ExecutorStats {
+            polls: self.polls.load(Ordering::Relaxed),
+            wakes: self.wakes.load(Ordering::Relaxed),
+            spurious_polls: self.spurious_polls.load(Ordering::Relaxed),
+            max_ready_depth: self.max_ready_depth.load(Ordering::Relaxed),
+            poll_cycles: self.poll_cycles.load(Ordering::Relaxed),
+            halt_cycles,
+        }
+    }
+}
+
+/// Ready queues of the executor that entered [`Executor::run`].
+static RUNNING: OnceCell<Arc<ReadyQueues>> = OnceCell::uninit();
+
+/// Set while an executor is polling a task.
+static POLLING: AtomicBool = AtomicBool::new(false);
+
+/// Console of the task each CPU is polling, as `Console as u8`, or
+/// [`NO_CONSOLE`]; indexed by CPU.
+static TASK_CONSOLE: [AtomicU8; MAX_CPUS] = [const { AtomicU8::new(NO_CONSOLE) }; MAX_CPUS];
+
+/// [`TASK_CONSOLE`] outside tasks, and in tasks without a console.
+const NO_CONSOLE: u8 = u8::MAX;
+
+/// Return the calling CPU's [`TASK_CONSOLE`].
+fn task_console_slot() -> &'static AtomicU8 {
+    &TASK_CONSOLE[crate::smp::this_cpu().index]
+}
+
+/// Set by [`park`].
+static PARKED: AtomicBool = AtomicBool::new(false);
+
+/// Stop the running executor from polling tasks, for shutdown.
+///
+/// The poll in progress finishes; after it, the executor only halts.
+/// Registered with [`power::on_shutdown`](crate::power::on_shutdown) by
+/// [`Executor::run`].
+pub fn park() {
+    PARKED.store(true, Ordering::Release);
+}
+
+/// Return whether an executor is polling a task, i.e. whether the caller
+/// is (called from) a task.
+pub fn is_polling() -> bool {
+    POLLING.load(Ordering::Relaxed)
+}
+
+/// Return the console the task this CPU is polling was given, if it was
+/// given one. See [`Task::with_console`].
+pub fn task_console() -> Option<Console> {
+    Console::from_u8(task_console_slot().load(Ordering::Relaxed))
+}
+
+/// Return whether an executor has entered [`Executor::run`].
+pub fn is_running() -> bool {
+    RUNNING.get().is_some()
+}
+
+/// Return the counters of the running executor, or `None` before
+/// [`Executor::run`] has been called.
+#[cfg(feature = "task-stats")]
+pub fn stats() -> Option<ExecutorStats> {
+    RUNNING.get().map(|ready| ready.snapshot())
+}
+
+/// Return the TSC cycles the running executor has spent halted, or `None`
+/// before [`Executor::run`] has been called. Counted with or without the
+/// `task-stats` feature, for the [status bar](crate::statusbar)'s CPU use.
+pub fn halt_cycles() -> Option<u64> {
+    RUNNING.get().map(|ready| ready.halt_cycles.load(Ordering::Relaxed))
+}
+
+/// One FIFO of woken task IDs per priority level.
+struct ReadyQueues {
+    queues: [ArrayQueue<TaskId>; Priority::COUNT],
+    /// TSC cycles spent halted waiting for an interrupt; two TSC reads
+    /// per halt, so always counted.
+    halt_cycles: AtomicU64,
+    #[cfg(feature = "task-stats")]
+    stats: StatsCounters,
+}
+
+impl ReadyQueues {
+    fn new() -> Self {
+        ReadyQueues {
+            queues: core::array::from_fn(|_| ArrayQueue::new(TASK_QUEUE_CAPACITY)),
+            halt_cycles: AtomicU64::new(0),
+            #[cfg(feature = "task-stats")]
+            stats: StatsCounters::default(),
+        }
+    }
+
+    fn push(&self, priority: Priority, task_id: TaskId) {
+        self.queues[priority.index()]
+            .push(task_id)
+            .expect("task_queue full");
+        #[cfg(feature = "task-stats")]
+        {
+            let depth = self.queues.iter().map(ArrayQueue::len).sum();
+            self.stats.wakes.fetch_add(1, Ordering::Relaxed);
+            self.stats.max_ready_depth.fetch_max(depth, Ordering::Relaxed);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(ArrayQueue::is_empty)
+    }
+
+    #[cfg(feature = "task-stats")]
+    fn snapshot(&self) -> ExecutorStats {
+        self.stats.snapshot(self.halt_cycles.load(Ordering::Relaxed))
+    }
+}
+
+/// Executor that polls woken tasks and halts the CPU while idle.
+///
+/// Ready tasks are polled highest [`Priority`] first. After
+/// [`STARVATION_LIMIT`] consecutive polls that passed over waiting
+/// lower-priority work, one lower-priority task gets a turn.
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    ready: Arc<ReadyQueues>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+    new_tasks: Arc<ArrayQueue<SendTask>>,
+    /// Consecutive polls that skipped over non-empty lower-priority queues.
+    starvation_streak: usize,
+}
+
+impl Executor {
+    /// Create an executor with no tasks.
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            ready: Arc::new(ReadyQueues::new()),
+            waker_cache: BTreeMap::new(),
+            new_tasks: Arc::new(ArrayQueue::new(NEW_TASK_QUEUE_CAPACITY)),
+            starvation_streak: 0,
+        }
+    }
+
+    /// Return a handle that can spawn tasks onto this executor while it runs.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            new_tasks: self.new_tasks.clone(),
+        }
+    }
+
+    /// Add `task` and schedule it for its first poll.
+    ///
+    /// Panics if a task with the same ID was already spawned.
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        let name = task.name();
+        let priority = task.priority();
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        let waker = Arc::new(TaskWaker {
+            task_id,
+            priority,
+            ready: self.ready.clone(),
+            woken: AtomicBool::new(false),
+        });
+        self.waker_cache.insert(task_id, waker.clone());
+        with_task_table(|table| {
+            table.insert(
+                task_id,
+                TaskInfo {
+                    name,
+                    priority,
+                    spawn_tick: ticks(),
+                    polls: 0,
+                    last_polled_tick: None,
+                    waker: waker.clone(),
+                    #[cfg(feature = "cpu-time")]
+                    cpu_cycles: 0,
+                    #[cfg(feature = "task-stats")]
+                    poll_histogram: PollHistogram::default(),
+                },
+            )
+        });
+        waker.wake_task();
+    }
+
+    /// Return the longest single poll of task `id` in timer ticks, if the
+    /// task is still alive.
+    ///
+    /// Only tracked in debug builds; useful for spotting tasks that never
+    /// [`yield_now`](super::yield_now).
+    #[cfg(debug_assertions)]
+    pub fn max_poll_ticks(&self, id: TaskId) -> Option<u64> {
+        self.tasks.get(&id).map(Task::max_poll_ticks)
+    }
+
+    /// Return a snapshot of this executor's counters.
+    #[cfg(feature = "task-stats")]
+    pub fn stats(&self) -> ExecutorStats {
+        self.ready.snapshot()
+    }
+
+    /// Run tasks forever, halting the CPU whenever none are ready, until
+    /// [`park`]ed.
+    ///
+    /// From then on, [`is_running`] returns `true`, and with the `task-stats`
+    /// feature, `stats` reports this executor's counters.
+    pub fn run(&mut self) -> ! {
+        if RUNNING.try_init_once(|| self.ready.clone()).is_ok() {
+            crate::power::on_shutdown("executor", park, crate::power::DEFAULT_TIMEOUT);
+        }
+        while !PARKED.load(Ordering::Acquire) {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+        crate::hlt_loop();
+    }
+
+    /// Poll until no task is ready, without halting.
+    ///
+    /// Lets unit tests elsewhere in the crate drive an executor
+    /// deterministically.
+    #[cfg(test)]
+    pub(crate) fn run_until_idle(&mut self) {
+        self.run_ready_tasks();
+    }
+
+    /// Move tasks submitted through a [`Spawner`] into the task map.
+    fn spawn_new_tasks(&mut self) {
+        while let Some(SendTask(task)) = self.new_tasks.pop() {
+            self.spawn(task);
+        }
+    }
+
+    /// Pop the next task ID to poll, applying the starvation guard.
+    fn next_ready(&mut self) -> Option<TaskId> {
+        let queues = &self.ready.queues;
+        let top = queues.iter().position(|queue| !queue.is_empty())?;
+
+        if self.starvation_streak >= STARVATION_LIMIT
+            && let Some(task_id) = queues[top + 1..].iter().find_map(ArrayQueue::pop)
+        {
+            self.starvation_streak = 0;
+            return Some(task_id);
+        }
+
+        let task_id = queues[top].pop()?;
+        if queues[top + 1..].iter().any(|queue| !queue.is_empty()) {
+            self.starvation_streak += 1;
+        } else {
+            self.starvation_streak = 0;
+        }
+        Some(task_id)
+    }
+
+    /// Poll ready tasks, highest priority first, until every queue is empty.
+    ///
+    /// Tasks submitted through a [`Spawner`] are picked up before each poll, so
+    /// work spawned by a running task starts within the same pass. Wake-ups for
+    /// tasks that have already completed are ignored.
+    fn run_ready_tasks(&mut self) {
+        while !PARKED.load(Ordering::Acquire) {
+            self.spawn_new_tasks();
+            let Some(task_id) = self.next_ready() else {
+                break;
+            };
+
+            // destructure `self` to avoid borrow checker errors
+            let Self {
+                tasks,
+                waker_cache,
+                ..
+            } = self;
+
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => continue, // task no longer exists
+            };
+            let task_waker = &waker_cache[&task_id];
+            if !task_waker.woken.swap(false, Ordering::Relaxed) {
+                #[cfg(feature = "task-stats")]
+                self.ready.stats.spurious_polls.fetch_add(1, Ordering::Relaxed);
+            }
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            #[cfg(any(feature = "cpu-time", feature = "task-stats"))]
+            let stopwatch = Stopwatch::start();
+            // Saved rather than cleared after, for an executor run by a task.
+            let was_polling = POLLING.swap(true, Ordering::Relaxed);
+            let console = task.console.map_or(NO_CONSOLE, |console| console as u8);
+            let was_console = task_console_slot().swap(console, Ordering::Relaxed);
+            let result = match task.on_panic {
+                OnPanic::Halt => Some(task.poll(&mut context)),
+                _ => recovery::poll_contained(task, &mut context),
+            };
+            task_console_slot().store(was_console, Ordering::Relaxed);
+            POLLING.store(was_polling, Ordering::Relaxed);
+            let Some(result) = result else {
+                self.remove_panicked(task_id);
+                continue;
+            };
+            #[cfg(any(feature = "cpu-time", feature = "task-stats"))]
+            let cycles = stopwatch.elapsed_cycles();
+            #[cfg(feature = "task-stats")]
+            {
+                self.ready.stats.polls.fetch_add(1, Ordering::Relaxed);
+                self.ready.stats.poll_cycles.fetch_add(cycles, Ordering::Relaxed);
+            }
+
+            let now = ticks();
+            with_task_table(|table| match result {
+                Poll::Ready(()) => {
+                    table.remove(&task_id);
+                }
+                Poll::Pending => {
+                    if let Some(info) = table.get_mut(&task_id) {
+                        info.polls += 1;
+                        info.last_polled_tick = Some(now);
+                        #[cfg(feature = "cpu-time")]
+                        info.cpu_cycles += cycles;
+                        #[cfg(feature = "task-stats")]
+                        info.poll_histogram.record(cycles);
+                    }
+                }
+            });
+            if result.is_ready() {
+                // task done -> remove it and its cached waker
+                tasks.remove(&task_id);
+                waker_cache.remove(&task_id);
+            }
+        }
+    }
+
+    /// Drop the bookkeeping of a task whose poll panicked, and respawn it if
+    /// it asked for that.
+    ///
+    /// The task's future is leaked rather than dropped: the panic may have
+    /// left it in a state its destructors don't expect.
+    fn remove_panicked(&mut self, task_id: TaskId) {
+        self.waker_cache.remove(&task_id);
+        with_task_table(|table| table.remove(&task_id));
+        if let Some(task) = self.tasks.remove(&task_id) {
+            let on_panic = task.on_panic;
+            core::mem::forget(task);
+            if let OnPanic::Respawn(make_task) = on_panic {
+                self.spawn(make_task());
+            }
+        }
+    }
+
+    /// Halt until the next interrupt if no task is ready.
+    ///
+    /// Interrupts are disabled while checking the queue so that a wake-up
+    /// arriving between the check and `hlt` cannot be missed; `enable_and_hlt`
+    /// re-enables them atomically with halting.
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+        interrupts::disable();
+        if self.ready.is_empty() && self.new_tasks.is_empty() {
+            let stopwatch = Stopwatch::start();
+            enable_and_hlt();
+            self.ready.halt_cycles.fetch_add(stopwatch.elapsed_cycles(), Ordering::Relaxed);
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        with_task_table(|table| {
+            for id in self.tasks.keys() {
+                table.remove(id);
+            }
+        });
+    }
+}
+
+/// Cloneable handle for spawning tasks onto a running [`Executor`].
+///
+/// Spawning pushes onto a lock-free queue that the executor drains on its
+/// next pass, so it works from other tasks and threads. A halted executor
+/// picks the task up after the next interrupt, at the latest the next
+/// timer tick.
+///
+/// Interrupt handlers may spawn too, with [`try_spawn`](Self::try_spawn):
+/// the task is boxed beforehand as a [`SendTask`], so nothing allocates in
+/// the handler, and a full queue hands the task back instead of panicking.
+#[derive(Clone)]
+pub struct Spawner {
+    new_tasks: Arc<ArrayQueue<SendTask>>,
+}
+
+impl Spawner {
+    /// Spawn `future` as a new task and return its ID.
+    ///
+    /// Panics if too many spawned tasks are waiting for the executor.
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+        self.submit(Task::new(future))
+    }
+
+    /// Like [`spawn`](Self::spawn), but gives the task a name for
+    /// [`dump_tasks`].
+    pub fn spawn_named(
+        &self,
+        name: &'static str,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) -> TaskId {
+        self.submit(Task::named(name, future))
+    }
+
+    /// Like [`spawn`](Self::spawn), but with an explicit scheduling priority.
+    pub fn spawn_with_priority(
+        &self,
+        priority: Priority,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) -> TaskId {
+        self.submit(Task::new(future).with_priority(priority))
+    }
+
+    /// Spawn `future` and return a [`JoinHandle`] that resolves to its output.
+    ///
+    /// Dropping the handle detaches the task; [`JoinHandle::abort`] cancels
+    /// it.
+    pub fn spawn_with_handle<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = join::joinable(future);
+        self.submit(task);
+        handle
+    }
+
+    /// Queue `task`, boxed beforehand, without allocating, and return its
+    /// ID. Safe in interrupt handlers. Hands the task back if too many
+    /// spawned tasks are waiting for the executor.
+    pub fn try_spawn(&self, task: SendTask) -> Result<TaskId, SendTask> {
+        let id = task.id();
+        self.new_tasks.push(task).map(|()| id)
+    }
+
+    /// Queue a task built from a `Send` future for the executor.
+    fn submit(&self, task: Task) -> TaskId {
+        match self.try_spawn(SendTask(task)) {
+            Ok(id) => id,
+            Err(_) => panic!("spawn queue full"),
+        }
+    }
+}
+
+/// A task built from a `Send` future, so it may cross from the spawning
+/// context to the executor. Built ahead of time, it lets an interrupt
+/// handler [`try_spawn`](Spawner::try_spawn) without allocating.
+pub struct SendTask(Task);
+
+impl SendTask {
+    /// Box `future` as a task with a fresh [`TaskId`].
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> SendTask {
+        SendTask(Task::new(future))
+    }
+
+    /// Like [`new`](Self::new), but gives the task a name for
+    /// [`dump_tasks`].
+    pub fn named(
+        name: &'static str,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) -> SendTask {
+        SendTask(Task::named(name, future))
+    }
+
+    /// Return the task's ID.
+    pub fn id(&self) -> TaskId {
+        self.0.id()
+    }
+}
+
+// SAFETY: `SendTask` is only constructed from `Send` futures, in its own
+// constructors and in `Spawner::submit`, whose callers require the future
+// (and, for joinable tasks, its output) to be `Send`; `Task` itself is just
+// not declared `Send` because it type-erases the future.
+unsafe impl Send for SendTask {}
+
+/// Waker that reschedules a task by pushing its ID into the ready queue.
+///
+/// Created once per task at spawn time and cached until the task completes.
+struct TaskWaker {
+    task_id: TaskId,
+    priority: Priority,
+    ready: Arc<ReadyQueues>,
+    /// Set when woken, cleared right before the task is polled.
+    woken: AtomicBool,
+}
+
+impl TaskWaker {
+    fn wake_task(&self) {
+        self.woken.store(true, Ordering::Relaxed);
+        self.ready.push(self.priority, self.task_id);
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+/// Slot through which test futures hand their waker to the test body.
+#[cfg(test)]
+static TEST_WAKER: spin::Mutex<Option<Waker>> = spin::Mutex::new(None);
+
+#[test_case]
+fn test_wake_before_poll() {
+    use core::future::poll_fn;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(poll_fn(|cx| {
+        if POLLS.fetch_add(1, Ordering::SeqCst) == 0 {
+            *TEST_WAKER.lock() = Some(cx.waker().clone());
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    })));
+
+    executor.run_ready_tasks();
+    assert_eq!(POLLS.load(Ordering::SeqCst), 1);
+
+    // Nothing woke the task, so it must not be polled again.
+    executor.run_ready_tasks();
+    assert_eq!(POLLS.load(Ordering::SeqCst), 1);
+
+    TEST_WAKER.lock().take().unwrap().wake();
+    executor.run_ready_tasks();
+    assert_eq!(POLLS.load(Ordering::SeqCst), 2);
+    assert!(executor.tasks.is_empty());
+    assert!(executor.waker_cache.is_empty());
+}
+
+#[test_case]
+fn test_wake_during_poll() {
+    use core::future::poll_fn;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(poll_fn(|cx| {
+        if POLLS.fetch_add(1, Ordering::SeqCst) < 3 {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    })));
+
+    // Self-wakes during a poll are picked up within the same pass.
+    executor.run_ready_tasks();
+    assert_eq!(POLLS.load(Ordering::SeqCst), 4);
+    assert!(executor.tasks.is_empty());
+    assert!(executor.waker_cache.is_empty());
+}
+
+#[test_case]
+fn test_wake_after_completion_is_ignored() {
+    let mut executor = Executor::new();
+    let task = Task::new(async {});
+    let id = task.id();
+    executor.spawn(task);
+    executor.run_ready_tasks();
+
+    executor.ready.push(Priority::Normal, id);
+    executor.run_ready_tasks();
+    assert!(executor.tasks.is_empty());
+    assert!(executor.waker_cache.is_empty());
+}
+
+#[test_case]
+fn test_sleep_if_idle_waits_for_interrupt() {
+    use crate::interrupts;
+
+    let executor = Executor::new();
+    let start = interrupts::ticks();
+    for _ in 0..1000 {
+        executor.sleep_if_idle();
+        if interrupts::ticks() != start {
+            break;
+        }
+    }
+    assert!(interrupts::ticks() > start);
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_yielding_tasks_interleave() {
+    use alloc::rc::Rc;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    let progress = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for id in 0..2 {
+        let progress = progress.clone();
+        executor.spawn(Task::new(async move {
+            for step in 0..3 {
+                progress.borrow_mut().push((id, step));
+                super::yield_now().await;
+            }
+        }));
+    }
+    executor.run_ready_tasks();
+
+    assert_eq!(
+        *progress.borrow(),
+        [(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2)]
+    );
+    assert!(executor.tasks.is_empty());
+}
+
+#[cfg(debug_assertions)]
+#[test_case]
+fn test_max_poll_ticks_records_long_polls() {
+    use crate::interrupts;
+
+    let mut executor = Executor::new();
+    let task = Task::new(async {
+        // Hog the CPU across a tick boundary, then yield once so the task is
+        // still alive when the counter is inspected.
+        let start = interrupts::ticks();
+        while interrupts::ticks() < start + 2 {
+            core::hint::spin_loop();
+        }
+        super::yield_now().await;
+        core::future::pending::<()>().await;
+    });
+    let id = task.id();
+    executor.spawn(task);
+    executor.run_ready_tasks();
+
+    assert!(executor.max_poll_ticks(id).unwrap() >= 2);
+}
+
+#[cfg(feature = "cpu-time")]
+#[test_case]
+fn test_cpu_time_accounts_busy_polls() {
+    let hz = crate::time::tsc_hz().expect("TSC rate not measured");
+    // 20 ms of spinning.
+    let spin = hz / 50;
+    let mut executor = Executor::new();
+    let task = Task::new(async move {
+        let stopwatch = Stopwatch::start();
+        while stopwatch.elapsed_cycles() < spin {
+            core::hint::spin_loop();
+        }
+        super::yield_now().await;
+        core::future::pending::<()>().await;
+    });
+    let id = task.id();
+    executor.spawn(task);
+    executor.run_ready_tasks();
+
+    let cycles = task_cpu_cycles(id).unwrap();
+    assert!(cycles >= spin && cycles < spin + spin / 2, "{} cycles for {}", cycles, spin);
+    let micros = crate::time::cycles_to_micros(cycles).unwrap();
+    assert!((19_000..30_000).contains(&micros), "{} us", micros);
+    let mut dump = alloc::string::String::new();
+    dump_tasks(&mut dump).unwrap();
+    assert!(dump.lines().next().unwrap().contains("CPU TIME"));
+}
+
+#[test_case]
+fn test_spawn_from_running_task() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static INNER_RAN: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(Task::new(async move {
+        spawner.spawn(async {
+            INNER_RAN.store(true, Ordering::SeqCst);
+        });
+    }));
+    executor.run_ready_tasks();
+
+    assert!(INNER_RAN.load(Ordering::SeqCst));
+    assert!(executor.tasks.is_empty());
+    assert!(executor.new_tasks.is_empty());
+}
+
+#[test_case]
+fn test_spawn_from_interrupt_context() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use x86_64::instructions::interrupts;
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+
+    let task = || {
+        SendTask::new(async {
+            super::yield_now().await;
+            RUNS.fetch_add(1, Ordering::SeqCst);
+        })
+    };
+    let mut tasks: alloc::vec::Vec<_> = (0..NEW_TASK_QUEUE_CAPACITY + 1).map(|_| task()).collect();
+    let allocated = crate::allocator::arena_stats();
+
+    // Interrupt handlers run with IF cleared and inside an IRQ context; the
+    // tasks are boxed already, so spawning only pushes onto the queue, and
+    // the one that doesn't fit comes back.
+    let spare = interrupts::without_interrupts(|| {
+        let _irq = crate::interrupts::IrqContext::enter();
+        let spare = tasks.pop().unwrap();
+        for task in tasks.drain(..) {
+            assert!(spawner.try_spawn(task).is_ok());
+        }
+        spawner.try_spawn(spare).err()
+    });
+    assert_eq!(crate::allocator::arena_stats(), allocated);
+    assert!(spare.is_some());
+    executor.run_ready_tasks();
+
+    assert_eq!(RUNS.load(Ordering::SeqCst), NEW_TASK_QUEUE_CAPACITY);
+    assert!(executor.tasks.is_empty());
+}
+
+#[test_case]
+fn test_dump_tasks_lists_named_tasks() {
+    use alloc::string::String;
+
+    /// Return the whitespace-separated columns of the row naming `name`.
+    fn row<'a>(dump: &'a str, name: &str) -> Option<alloc::vec::Vec<&'a str>> {
+        dump.lines()
+            .find(|line| line.ends_with(name))
+            .map(|line| line.split_whitespace().collect())
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::named("dump-test-yielder", async {
+        super::yield_now().await;
+        super::yield_now().await;
+        core::future::pending::<()>().await;
+    }));
+    executor.spawn(Task::named("dump-test-finished", async {}));
+    executor.run_ready_tasks();
+    executor.spawner().spawn_named("dump-test-new", async {});
+    executor.spawn_new_tasks();
+
+    let mut dump = String::new();
+    dump_tasks(&mut dump).unwrap();
+
+    let yielder = row(&dump, "dump-test-yielder").expect("yielder missing from dump");
+    assert_eq!(yielder[1], "normal");
+    assert_eq!(yielder[2], "waiting");
+    assert_eq!(yielder[3], "3");
+    assert_ne!(yielder[5], "-");
+
+    let new = row(&dump, "dump-test-new").expect("new task missing from dump");
+    assert_eq!(new[2], "ready");
+    assert_eq!(new[3], "0");
+    assert_eq!(new[5], "-");
+
+    assert!(row(&dump, "dump-test-finished").is_none());
+
+    // Dropping the executor drops its tasks from the table as well.
+    drop(executor);
+    let mut dump = String::new();
+    dump_tasks(&mut dump).unwrap();
+    assert!(!dump.contains("dump-test-"));
+}
+
+#[test_case]
+fn test_high_priority_runs_first() {
+    use alloc::rc::Rc;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for (priority, tag) in [(Priority::Low, 'l'), (Priority::Normal, 'n'), (Priority::High, 'h')] {
+        let order = order.clone();
+        executor.spawn(Task::new(async move { order.borrow_mut().push(tag) }).with_priority(priority));
+    }
+    executor.run_ready_tasks();
+
+    assert_eq!(*order.borrow(), ['h', 'n', 'l']);
+}
+
+#[test_case]
+fn test_low_priority_is_not_starved() {
+    use core::sync::atomic::AtomicUsize;
+
+    const HIGH_POLLS: usize = 200;
+    static HIGH: AtomicUsize = AtomicUsize::new(0);
+    static LOW: AtomicUsize = AtomicUsize::new(0);
+    static LOW_WHEN_HIGH_DONE: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    spawner.spawn_with_priority(Priority::High, async {
+        for _ in 0..HIGH_POLLS {
+            HIGH.fetch_add(1, Ordering::SeqCst);
+            super::yield_now().await;
+        }
+        LOW_WHEN_HIGH_DONE.store(LOW.load(Ordering::SeqCst), Ordering::SeqCst);
+    });
+    spawner.spawn_with_priority(Priority::Low, async {
+        while HIGH.load(Ordering::SeqCst) < HIGH_POLLS {
+            LOW.fetch_add(1, Ordering::SeqCst);
+            super::yield_now().await;
+        }
+    });
+    executor.run_ready_tasks();
+
+    let low = LOW_WHEN_HIGH_DONE.load(Ordering::SeqCst);
+    assert!(low >= HIGH_POLLS / (STARVATION_LIMIT + 1) - 1, "low task starved: {}", low);
+    assert!(low < HIGH_POLLS / 2, "high task lost its majority: {}", low);
+    assert!(executor.tasks.is_empty());
+}
+
+#[test_case]
+fn test_join_handle_returns_output() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static JOINED: AtomicU64 = AtomicU64::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let handle = spawner.spawn_with_handle(async {
+        super::yield_now().await;
+        42u64
+    });
+    spawner.spawn(async move {
+        JOINED.store(handle.await, Ordering::SeqCst);
+    });
+    executor.run_ready_tasks();
+
+    assert_eq!(JOINED.load(Ordering::SeqCst), 42);
+    assert!(executor.tasks.is_empty());
+}
+
+#[test_case]
+fn test_join_after_task_finished() {
+    use super::test_util::counting_waker;
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    let mut handle = executor.spawner().spawn_with_handle(async { "done" });
+    executor.run_ready_tasks();
+    assert!(handle.is_finished());
+
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    assert_eq!(Pin::new(&mut handle).poll(&mut cx), Poll::Ready("done"));
+    assert_eq!(WAKES.load(Ordering::SeqCst), 0);
+}
+
+#[test_case]
+fn test_aborted_task_never_completes() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static COMPLETED: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    let handle = executor.spawner().spawn_with_handle(async {
+        super::sleep_ticks(2).await;
+        COMPLETED.store(true, Ordering::SeqCst);
+    });
+    executor.run_ready_tasks();
+    assert_eq!(executor.tasks.len(), 1);
+    assert_eq!(super::timer::active_count(), 1);
+
+    handle.abort();
+    executor.run_ready_tasks();
+    assert!(executor.tasks.is_empty());
+    assert_eq!(super::timer::active_count(), 0);
+
+    let start = ticks();
+    while ticks() < start + 4 {
+        x86_64::instructions::hlt();
+    }
+    executor.run_ready_tasks();
+    assert!(!COMPLETED.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "task-stats")]
+#[test_case]
+fn test_stats_follow_scripted_run() {
+    use alloc::string::ToString;
+    use core::future::poll_fn;
+
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    assert_eq!(executor.stats(), ExecutorStats::default());
+
+    executor.spawn(Task::new(poll_fn(|cx| {
+        if DONE.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        *TEST_WAKER.lock() = Some(cx.waker().clone());
+        Poll::Pending
+    })));
+    executor.spawn(Task::new(async {}));
+    let stats = executor.stats();
+    assert_eq!((stats.wakes, stats.max_ready_depth, stats.polls), (2, 2, 0));
+
+    executor.run_ready_tasks();
+    let stats = executor.stats();
+    assert_eq!((stats.polls, stats.spurious_polls), (2, 0));
+    assert!(stats.poll_cycles > 0);
+
+    // Two wake-ups before the next poll: the second poll is spurious.
+    let waker = TEST_WAKER.lock().take().unwrap();
+    waker.wake_by_ref();
+    waker.wake_by_ref();
+    executor.run_ready_tasks();
+    let stats = executor.stats();
+    assert_eq!((stats.wakes, stats.polls, stats.spurious_polls), (4, 4, 1));
+
+    // Nothing is ready, so this halts until the next timer tick.
+    executor.sleep_if_idle();
+    assert!(executor.stats().halt_cycles > 0);
+
+    DONE.store(true, Ordering::SeqCst);
+    waker.wake();
+    executor.run_ready_tasks();
+    let stats = executor.stats();
+    assert_eq!((stats.wakes, stats.polls, stats.spurious_polls), (5, 5, 1));
+    assert!(executor.tasks.is_empty());
+    assert!(stats.to_string().starts_with("polls 5 wakes 5 spurious 1 max-ready 2"));
+}
+
+#[cfg(feature = "task-stats")]
+#[test_case]
+fn test_poll_histogram_counts_polls() {
+    let mut executor = Executor::new();
+    let task = Task::new(async {
+        for _ in 0..3 {
+            super::yield_now().await;
+        }
+        core::future::pending::<()>().await;
+    });
+    let id = task.id();
+    executor.spawn(task);
+    executor.run_ready_tasks();
+
+    let histogram = poll_histogram(id).unwrap();
+    assert_eq!(histogram.buckets.iter().sum::<u64>(), 4);
+}
+
+#[test_case]
+fn test_tasks_print_to_their_console() {
+    use crate::console::{self, Console};
+    use crate::vga_buffer::screen_contains;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static INHERITED: AtomicBool = AtomicBool::new(false);
+
+    let previous = console::selected();
+    for active in [Console::Serial, Console::Vga] {
+        console::select(active).unwrap();
+        let mut executor = Executor::new();
+        executor.spawn(
+            Task::new(async move { crate::println!("on-vga-while-{}", active) })
+                .with_console(Console::Vga),
+        );
+        executor.spawn(
+            Task::new(async move {
+                crate::println!("on-serial-while-{}", active);
+                let child = Task::new(async {});
+                INHERITED.store(child.console() == Some(Console::Serial), Ordering::Relaxed);
+            })
+            .with_console(Console::Serial),
+        );
+        executor.run_ready_tasks();
+        assert!(screen_contains(&alloc::format!("on-vga-while-{}", active)));
+        assert!(!screen_contains(&alloc::format!("on-serial-while-{}", active)));
+        assert!(INHERITED.load(Ordering::Relaxed));
+        assert_eq!(task_console(), None);
+    }
+    console::select(previous).unwrap();
+}
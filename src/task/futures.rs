@@ -0,0 +1,316 @@
+//! Small future and stream combinators.
+//!
+//! [`race`] and [`join`] combine two futures; [`StreamExt`] adds `next`,
+//! `map` and `take_until` to any [`Stream`]. Together they cover things like
+//! "wait for a key press or a timeout" without hand-written poll functions.
+//!
+//! The combinators pin their inner futures structurally: a field is only
+//! ever reached through a `Pin` projected from the pinned combinator, none of
+//! the types implement `Drop` or `Unpin` by hand, and a field is never moved
+//! out while pinned. That is what makes the unchecked projections below
+//! sound.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+
+/// Output of [`race`]: which future finished first, and its output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// Wait for whichever of `a` and `b` finishes first; the other is dropped
+/// with the `Race` future.
+///
+/// `a` is polled first, so it wins if both are ready at once.
+pub fn race<A: Future, B: Future>(a: A, b: B) -> Race<A, B> {
+    Race { a, b }
+}
+
+/// Future returned by [`race`].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Race<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A: Future, B: Future> Future for Race<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // SAFETY: structural projection, see the module docs.
+        let this = unsafe { self.get_unchecked_mut() };
+        let a = unsafe { Pin::new_unchecked(&mut this.a) };
+        if let Poll::Ready(output) = a.poll(cx) {
+            return Poll::Ready(Either::Left(output));
+        }
+        let b = unsafe { Pin::new_unchecked(&mut this.b) };
+        b.poll(cx).map(Either::Right)
+    }
+}
+
+/// A future inside [`Join`] that may have finished already.
+enum MaybeDone<F: Future> {
+    Pending(F),
+    Done(F::Output),
+    Taken,
+}
+
+impl<F: Future> MaybeDone<F> {
+    /// Poll the future if it hasn't finished; return whether it has.
+    fn poll_done(self: Pin<&mut Self>, cx: &mut Context) -> bool {
+        // SAFETY: the future is pinned in place until it completes, and only
+        // its output is moved afterwards.
+        let this = unsafe { self.get_unchecked_mut() };
+        match this {
+            MaybeDone::Pending(future) => {
+                match unsafe { Pin::new_unchecked(future) }.poll(cx) {
+                    Poll::Ready(output) => {
+                        *this = MaybeDone::Done(output);
+                        true
+                    }
+                    Poll::Pending => false,
+                }
+            }
+            MaybeDone::Done(_) => true,
+            MaybeDone::Taken => panic!("Join polled after completion"),
+        }
+    }
+
+    fn take(self: Pin<&mut Self>) -> F::Output {
+        // SAFETY: only called once the future has completed, so nothing
+        // pinned is moved.
+        let this = unsafe { self.get_unchecked_mut() };
+        match core::mem::replace(this, MaybeDone::Taken) {
+            MaybeDone::Done(output) => output,
+            _ => unreachable!("output taken before completion"),
+        }
+    }
+}
+
+/// Wait for both `a` and `b`, polling them concurrently.
+pub fn join<A: Future, B: Future>(a: A, b: B) -> Join<A, B> {
+    Join {
+        a: MaybeDone::Pending(a),
+        b: MaybeDone::Pending(b),
+    }
+}
+
+/// Future returned by [`join`].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Join<A: Future, B: Future> {
+    a: MaybeDone<A>,
+    b: MaybeDone<B>,
+}
+
+impl<A: Future, B: Future> Future for Join<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // SAFETY: structural projection, see the module docs.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut a = unsafe { Pin::new_unchecked(&mut this.a) };
+        let mut b = unsafe { Pin::new_unchecked(&mut this.b) };
+        let a_done = a.as_mut().poll_done(cx);
+        let b_done = b.as_mut().poll_done(cx);
+        if a_done && b_done {
+            Poll::Ready((a.take(), b.take()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Extension methods for [`Stream`]s.
+pub trait StreamExt: Stream {
+    /// Wait for the next item; `None` once the stream has ended.
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Unpin,
+    {
+        Next { stream: self }
+    }
+
+    /// Transform every item with `f`.
+    fn map<T, F>(self, f: F) -> Map<Self, F>
+    where
+        Self: Sized,
+        F: FnMut(Self::Item) -> T,
+    {
+        Map { stream: self, f }
+    }
+
+    /// Yield items until `signal` completes, then end the stream.
+    ///
+    /// `signal` is checked before every item, so an item that is ready at the
+    /// same time as the signal is not yielded.
+    fn take_until<S: Future>(self, signal: S) -> TakeUntil<Self, S>
+    where
+        Self: Sized,
+    {
+        TakeUntil {
+            stream: self,
+            signal,
+            done: false,
+        }
+    }
+}
+
+impl<S: Stream + ?Sized> StreamExt for S {}
+
+/// Future returned by [`StreamExt::next`].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Next<'a, S: ?Sized> {
+    stream: &'a mut S,
+}
+
+impl<S: Stream + Unpin + ?Sized> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+/// Stream returned by [`StreamExt::map`].
+#[must_use = "streams do nothing unless polled"]
+pub struct Map<S, F> {
+    stream: S,
+    f: F,
+}
+
+impl<S, F, T> Stream for Map<S, F>
+where
+    S: Stream,
+    F: FnMut(S::Item) -> T,
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        // SAFETY: `stream` is pinned structurally, `f` is not pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+        stream.poll_next(cx).map(|item| item.map(&mut this.f))
+    }
+}
+
+/// Stream returned by [`StreamExt::take_until`].
+#[must_use = "streams do nothing unless polled"]
+pub struct TakeUntil<S, F> {
+    stream: S,
+    signal: F,
+    done: bool,
+}
+
+impl<S: Stream, F: Future> Stream for TakeUntil<S, F> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        // SAFETY: structural projection, see the module docs.
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.done {
+            return Poll::Ready(None);
+        }
+        // The signal is not polled again once it completed.
+        if unsafe { Pin::new_unchecked(&mut this.signal) }.poll(cx).is_ready() {
+            this.done = true;
+            return Poll::Ready(None);
+        }
+        let item = unsafe { Pin::new_unchecked(&mut this.stream) }.poll_next(cx);
+        if let Poll::Ready(None) = item {
+            this.done = true;
+        }
+        item
+    }
+}
+
+#[test_case]
+fn test_race_picks_faster_sleep() {
+    use super::{block_on, sleep_ticks};
+
+    let start = crate::interrupts::ticks();
+    let output = block_on(race(
+        async {
+            sleep_ticks(20).await;
+            "slow"
+        },
+        async {
+            sleep_ticks(2).await;
+            "fast"
+        },
+    ));
+    assert_eq!(output, Either::Right("fast"));
+    assert!(crate::interrupts::ticks() - start < 20);
+    // Dropping the race cancels the slower sleep.
+    assert_eq!(super::timer::active_count(), 0);
+}
+
+#[test_case]
+fn test_join_waits_for_both_channels() {
+    use super::channel;
+    use super::test_util::counting_waker;
+    use core::pin::pin;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let (numbers_tx, mut numbers) = channel::<u32>(4);
+    let (letters_tx, mut letters) = channel::<char>(4);
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+
+    let mut both = pin!(join(numbers.next(), letters.next()));
+    assert!(both.as_mut().poll(&mut cx).is_pending());
+
+    letters_tx.try_send('x').unwrap();
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    assert!(both.as_mut().poll(&mut cx).is_pending());
+
+    numbers_tx.try_send(7).unwrap();
+    assert_eq!(WAKES.load(Ordering::SeqCst), 2);
+    assert_eq!(both.as_mut().poll(&mut cx), Poll::Ready((Some(7), Some('x'))));
+}
+
+#[test_case]
+fn test_take_until_cuts_off_infinite_stream() {
+    use super::test_util::counting_waker;
+    use core::future::poll_fn;
+    use core::pin::pin;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    /// Counts up forever.
+    struct Naturals(u32);
+
+    impl Stream for Naturals {
+        type Item = u32;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<u32>> {
+            self.0 += 1;
+            Poll::Ready(Some(self.0))
+        }
+    }
+
+    let signal = poll_fn(|_| {
+        if STOP.load(Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    });
+    let mut squares = pin!(Naturals(0).map(|n| n * n).take_until(signal));
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+
+    for expected in [1, 4, 9] {
+        assert_eq!(squares.as_mut().poll_next(&mut cx), Poll::Ready(Some(expected)));
+    }
+    STOP.store(true, Ordering::SeqCst);
+    assert_eq!(squares.as_mut().poll_next(&mut cx), Poll::Ready(None));
+    assert_eq!(squares.as_mut().poll_next(&mut cx), Poll::Ready(None));
+}
@@ -0,0 +1,122 @@
+//! Join handles for tasks that produce a value.
+//!
+//! [`Spawner::spawn_with_handle`](super::executor::Spawner::spawn_with_handle)
+//! wraps the user future in a [`Joinable`] task that stores the output in a
+//! slot shared with the returned [`JoinHandle`]. The handle is a future that
+//! completes with that output.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use super::{Task, TaskId};
+
+/// State shared between a joinable task and its handle.
+struct JoinState<T> {
+    output: Mutex<Option<T>>,
+    /// Waker of whoever awaits the [`JoinHandle`].
+    join_waker: AtomicWaker,
+    /// Waker of the task itself, so [`JoinHandle::abort`] can get it polled.
+    task_waker: AtomicWaker,
+    aborted: AtomicBool,
+}
+
+/// Task wrapper that publishes its future's output to a [`JoinHandle`].
+struct Joinable<F: Future> {
+    future: Pin<Box<F>>,
+    state: Arc<JoinState<F::Output>>,
+}
+
+/// Wrap `future` in a task and return the handle that joins it.
+pub(super) fn joinable<F>(future: F) -> (Task, JoinHandle<F::Output>)
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    let state = Arc::new(JoinState {
+        output: Mutex::new(None),
+        join_waker: AtomicWaker::new(),
+        task_waker: AtomicWaker::new(),
+        aborted: AtomicBool::new(false),
+    });
+    let task = Task::new(Joinable {
+        future: Box::pin(future),
+        state: state.clone(),
+    });
+    let id = task.id();
+    (task, JoinHandle { id, state })
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // Aborted tasks finish without polling the user future again; the
+        // executor then drops it along with this wrapper.
+        if self.state.aborted.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        self.state.task_waker.register(cx.waker());
+        match self.future.as_mut().poll(cx) {
+            Poll::Ready(output) => {
+                *self.state.output.lock() = Some(output);
+                self.state.join_waker.wake();
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Handle to a spawned task's output.
+///
+/// Awaiting the handle yields the task's return value. Dropping it detaches
+/// the task, which keeps running; use [`abort`](Self::abort) to cancel it.
+#[must_use = "dropping a JoinHandle detaches the task"]
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Return the ID of the task this handle joins.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Return whether the task has produced its output.
+    pub fn is_finished(&self) -> bool {
+        self.state.output.lock().is_some()
+    }
+
+    /// Cancel the task.
+    ///
+    /// The task is removed before its next poll, without its future running
+    /// again. Has no effect if the task already finished.
+    pub fn abort(self) {
+        self.state.aborted.store(true, Ordering::Release);
+        self.state.task_waker.wake();
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        if let Some(output) = self.state.output.lock().take() {
+            return Poll::Ready(output);
+        }
+        self.state.join_waker.register(cx.waker());
+
+        // The task may have finished between the check and registering.
+        match self.state.output.lock().take() {
+            Some(output) => Poll::Ready(output),
+            None => Poll::Pending,
+        }
+    }
+}
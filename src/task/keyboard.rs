@@ -0,0 +1,1525 @@
+//! Asynchronous keyboard input.
+//!
+//! The keyboard interrupt handler only reads the raw scancode and hands it to
+//! [`add_scancode`], which queues it in a static [`FixedRing`] and wakes
+//! whoever is waiting. Decoding happens later in task context through
+//! [`ScancodeStream`] and [`KeyStream`], so the IRQ path stays short and never
+//! allocates. [`lines`] adds line editing on top for console input.
+//!
+//! [`KeyStream`] yields an [`InputEvent`] per key press: a character, a
+//! named key such as Home or an arrow, or a named key with modifiers held;
+//! see [`input`]. It follows Num Lock, which decides whether the keypad
+//! types digits or moves around, and keeps the keyboard's Num Lock LED in
+//! step. Ctrl+Alt+Del is handled by [`KeyStream`] itself and reboots the
+//! machine, and Alt+F1 to Alt+F4 put that [virtual terminal](vt) on the
+//! screen.
+//!
+//! Some bytes on the data port aren't scancodes. A keyboard that is
+//! plugged back in announces itself with `0xAA` (its self-test passing) or
+//! `0xFC` (failing), and `0x00` or `0xFF` mark an overrun of its buffer.
+//! The handler counts these in [`stats`], tells the stream to start
+//! decoding afresh so a sequence cut short can't garble later keys, and for
+//! the two self-test bytes wakes [`reinit_task`], which resets the keyboard,
+//! finds out its scancode set again, since a different keyboard may send
+//! another, and restores its LED and typematic settings. Replies to those
+//! commands are taken by the handler too and never reach the stream.
+//!
+//! A keymap loaded at run time can change what keys type; see [`keymap`].
+//! The console's input can be recorded and replayed; see [`replay`].
+//!
+//! [`init`] resets the keyboard and finds out which scancode set arrives:
+//! set 1 when the controller translates (the usual case), otherwise
+//! whatever the keyboard reports, defaulting to its native set 2. [`info`]
+//! returns what it, or the latest re-initialization, found. When nothing
+//! acknowledges the reset, the keyboard IRQ stays masked, [`is_present`]
+//! returns false and the console reads COM1 alone.
+
+use super::line_edit::{Lines, VgaEcho};
+use crate::arch::port::{Port, PortGroup};
+use crate::collections::FixedRing;
+use crate::error::KernelError;
+use crate::interrupts::InterruptIndex;
+use crate::time;
+use crate::vga_buffer::vt;
+use crate::{ensure, println};
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1,
+    ScancodeSet2,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub mod input;
+pub mod keymap;
+pub mod replay;
+
+pub use input::{InputEvent, KeyAction, Modifiers};
+pub use keymap::{load_keymap, reset_keymap, KeymapError};
+use input::Translator;
+
+/// The 8042 PS/2 controller's ports.
+pub struct Ps2Ports {
+    /// Scancodes and device replies.
+    pub data: Port<u8>,
+    /// Status when read, controller commands when written.
+    pub status_cmd: Port<u8>,
+}
+
+impl PortGroup for Ps2Ports {
+    const DEVICE: &'static str = "ps2";
+    const BASE: u16 = 0x60;
+
+    fn at(base: u16) -> Self {
+        Ps2Ports {
+            data: Port::new(Self::DEVICE, base),
+            status_cmd: Port::new(Self::DEVICE, base + 4),
+        }
+    }
+}
+
+/// The controller's status register, read from port `0x64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerStatus(pub u8);
+
+impl ControllerStatus {
+    /// A byte is waiting on the data port.
+    pub const OUTPUT_FULL: u8 = 1 << 0;
+    /// The controller hasn't taken the last byte written yet.
+    pub const INPUT_FULL: u8 = 1 << 1;
+    /// Set by the firmware once the system passed its self-test.
+    pub const SYSTEM: u8 = 1 << 2;
+    /// The last byte written went to the command port.
+    pub const COMMAND: u8 = 1 << 3;
+    pub const TIMEOUT: u8 = 1 << 6;
+    pub const PARITY: u8 = 1 << 7;
+
+    pub fn has(self, flag: u8) -> bool {
+        self.0 & flag != 0
+    }
+}
+
+impl fmt::Display for ControllerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#04x}", self.0)?;
+        for (flag, name) in [
+            (Self::OUTPUT_FULL, "output-full"),
+            (Self::INPUT_FULL, "input-full"),
+            (Self::SYSTEM, "system"),
+            (Self::COMMAND, "command"),
+            (Self::TIMEOUT, "timeout"),
+            (Self::PARITY, "parity-error"),
+        ] {
+            if self.has(flag) {
+                write!(f, " {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the controller's status register.
+pub fn controller_status() -> ControllerStatus {
+    // Reading the status register has no side effects.
+    ControllerStatus(unsafe { Ps2Ports::standard().status_cmd.read() })
+}
+
+/// Controller command: read the configuration byte.
+const READ_CONFIG: u8 = 0x20;
+/// Configuration bit: the controller translates set 2 to set 1.
+const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+/// Status polls before giving up on the controller during [`init`], each
+/// [`POLL_INTERVAL_US`] apart.
+const POLL_LIMIT: u32 = 10_000;
+const POLL_INTERVAL_US: u32 = 10;
+
+/// Bytes the keyboard sends that aren't scancodes.
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+const SELF_TEST_PASSED: u8 = 0xAA;
+const SELF_TEST_FAILED: u8 = 0xFC;
+const OVERRUN: u8 = 0x00;
+const OVERRUN_SET1: u8 = 0xFF;
+
+/// Prefix of extended scancodes.
+const EXTENDED: u8 = 0xE0;
+const LEFT_SHIFT_MAKE: u8 = 0x2A;
+/// Left Shift's release, the same byte as [`SELF_TEST_PASSED`].
+const LEFT_SHIFT_BREAK: u8 = 0xAA;
+
+/// Keyboard commands.
+const SET_LEDS: u8 = 0xED;
+const SET_TYPEMATIC: u8 = 0xF3;
+/// Followed by 0 to ask for the current set, or a set to switch to.
+const SCANCODE_SET: u8 = 0xF0;
+const ENABLE_SCANNING: u8 = 0xF4;
+const RESET: u8 = 0xFF;
+
+/// Reads of [`POLL_LIMIT`] polls each to wait for the self-test after a
+/// reset, which takes a few hundred milliseconds.
+const SELF_TEST_READS: u32 = 10;
+
+/// Number of scancodes buffered before new ones are dropped.
+const SCANCODE_QUEUE_CAPACITY: usize = 100;
+
+/// Scancode input filled from interrupt context.
+static SCANCODES: ScancodeInput = ScancodeInput::new();
+
+/// What the interrupt handler queues for the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Input {
+    Scancode(u8),
+    /// The keyboard was replugged or lost input, so a sequence in progress
+    /// won't be finished.
+    Reset,
+}
+
+/// Counts of out-of-band keyboard bytes and of re-initializations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ps2Stats {
+    /// `0xAA` outside a command: the keyboard was plugged in again.
+    pub hot_plugs: u64,
+    /// `0xFC` outside a command.
+    pub self_test_failures: u64,
+    /// `0x00` or `0xFF`: the keyboard's buffer overflowed.
+    pub overruns: u64,
+    /// Times [`reinit_task`] was woken.
+    pub reinits_scheduled: u64,
+    /// Re-initializations that completed.
+    pub reinits: u64,
+    pub reinit_failures: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    hot_plugs: AtomicU64,
+    self_test_failures: AtomicU64,
+    overruns: AtomicU64,
+    reinits_scheduled: AtomicU64,
+    reinits: AtomicU64,
+    reinit_failures: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            hot_plugs: AtomicU64::new(0),
+            self_test_failures: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+            reinits_scheduled: AtomicU64::new(0),
+            reinits: AtomicU64::new(0),
+            reinit_failures: AtomicU64::new(0),
+        }
+    }
+
+    fn snapshot(&self) -> Ps2Stats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Ps2Stats {
+            hot_plugs: load(&self.hot_plugs),
+            self_test_failures: load(&self.self_test_failures),
+            overruns: load(&self.overruns),
+            reinits_scheduled: load(&self.reinits_scheduled),
+            reinits: load(&self.reinits),
+            reinit_failures: load(&self.reinit_failures),
+        }
+    }
+}
+
+fn bump(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The scancode queue, read by the one stream opened on it, and what the
+/// interrupt handler needs to tell scancodes from other bytes.
+///
+/// The queue is static, so pushing never allocates, even before a stream is
+/// opened.
+struct ScancodeInput {
+    queue: FixedRing<Input, SCANCODE_QUEUE_CAPACITY>,
+    /// Waker of the stream's task.
+    waker: AtomicWaker,
+    /// Set once [`ScancodeStream`] is reading the queue.
+    opened: AtomicBool,
+    /// Input dropped because the queue was full.
+    dropped: AtomicU64,
+    warned_full: AtomicBool,
+    warned_uninit: AtomicBool,
+    /// Scancodes arrive in set 2 rather than set 1.
+    set2: AtomicBool,
+    /// The last scancode was an [`EXTENDED`] prefix. Set 1 only.
+    after_prefix: AtomicBool,
+    /// Left Shift is down, so `0xAA` is its release. Set 1 only.
+    left_shift: AtomicBool,
+    /// Set when [`reinit_task`] should run.
+    reinit: AtomicBool,
+    /// LED state, restored after a re-initialization: see [`LED_NUM_LOCK`]
+    /// and the others.
+    leds: AtomicU8,
+    /// Set when [`reinit_task`] should send `leds` to the keyboard.
+    leds_changed: AtomicBool,
+    reinit_waker: AtomicWaker,
+    counters: Counters,
+}
+
+impl ScancodeInput {
+    const fn new() -> Self {
+        ScancodeInput {
+            queue: FixedRing::new(),
+            waker: AtomicWaker::new(),
+            opened: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            warned_full: AtomicBool::new(false),
+            warned_uninit: AtomicBool::new(false),
+            set2: AtomicBool::new(false),
+            after_prefix: AtomicBool::new(false),
+            left_shift: AtomicBool::new(false),
+            reinit: AtomicBool::new(false),
+            leds: AtomicU8::new(LED_NUM_LOCK),
+            leds_changed: AtomicBool::new(false),
+            reinit_waker: AtomicWaker::new(),
+            counters: Counters::new(),
+        }
+    }
+
+    /// Start taking input for a stream.
+    ///
+    /// Panics if called more than once.
+    fn open(&self) {
+        let opened = self.opened.swap(true, Ordering::AcqRel);
+        assert!(!opened, "ScancodeStream::new should only be called once");
+    }
+
+    /// Take the oldest queued input, if any.
+    fn pop(&self) -> Option<Input> {
+        self.queue.pop()
+    }
+
+    /// Sort a byte read from the data port: queue scancodes, and count and
+    /// act on the rest.
+    fn receive(&self, byte: u8) {
+        // No set 2 scancode is 0xAA, so only set 1 needs the context.
+        let set2 = self.set2.load(Ordering::Relaxed);
+        let after_prefix = self.after_prefix.swap(byte == EXTENDED, Ordering::Relaxed);
+        match byte {
+            OVERRUN | OVERRUN_SET1 => {
+                bump(&self.counters.overruns);
+                self.restart();
+            }
+            SELF_TEST_PASSED
+                if set2 || (!after_prefix && !self.left_shift.load(Ordering::Relaxed)) =>
+            {
+                bump(&self.counters.hot_plugs);
+                self.restart();
+                self.schedule_reinit();
+            }
+            SELF_TEST_FAILED => {
+                bump(&self.counters.self_test_failures);
+                self.restart();
+                self.schedule_reinit();
+            }
+            _ => {
+                // Extended codes with the same second byte are other keys.
+                if !set2 && !after_prefix && matches!(byte, LEFT_SHIFT_MAKE | LEFT_SHIFT_BREAK) {
+                    self.left_shift.store(byte == LEFT_SHIFT_MAKE, Ordering::Relaxed);
+                }
+                self.push(byte);
+            }
+        }
+    }
+
+    /// Forget the decoding state here and tell the stream to do the same.
+    fn restart(&self) {
+        self.after_prefix.store(false, Ordering::Relaxed);
+        self.left_shift.store(false, Ordering::Relaxed);
+        self.send(Input::Reset);
+    }
+
+    /// Decode what arrives as `set` from now on. If that is a change, the
+    /// stream starts decoding afresh, in the new set.
+    fn use_set(&self, set: ScancodeSet) {
+        let set2 = set == ScancodeSet::Set2;
+        let changed = self.set2.swap(set2, Ordering::Relaxed) != set2;
+        if changed && self.opened.load(Ordering::Acquire) {
+            self.restart();
+        }
+    }
+
+    /// Return the set scancodes arrive in.
+    fn set(&self) -> ScancodeSet {
+        if self.set2.load(Ordering::Relaxed) {
+            ScancodeSet::Set2
+        } else {
+            ScancodeSet::Set1
+        }
+    }
+
+    fn schedule_reinit(&self) {
+        bump(&self.counters.reinits_scheduled);
+        self.reinit.store(true, Ordering::Release);
+        self.reinit_waker.wake();
+    }
+
+    /// Turn `led` on or off, and have [`reinit_task`] tell the keyboard if
+    /// that changed anything.
+    fn set_led(&self, led: u8, on: bool) {
+        let previous = if on {
+            self.leds.fetch_or(led, Ordering::Relaxed)
+        } else {
+            self.leds.fetch_and(!led, Ordering::Relaxed)
+        };
+        if (previous & led != 0) != on {
+            self.leds_changed.store(true, Ordering::Release);
+            self.reinit_waker.wake();
+        }
+    }
+
+    /// Send a scancode to the stream.
+    fn push(&self, scancode: u8) {
+        self.send(Input::Scancode(scancode));
+    }
+
+    /// Queue `input` for the stream.
+    ///
+    /// Input is dropped (with a one-time warning) when the queue is full or
+    /// has not been created yet.
+    fn send(&self, input: Input) {
+        if !self.opened.load(Ordering::Acquire) {
+            warn_once(&self.warned_uninit, "scancode queue uninitialized");
+            return;
+        }
+        match self.queue.push(input) {
+            Ok(()) => self.waker.wake(),
+            Err(_) => {
+                bump(&self.dropped);
+                warn_once(&self.warned_full, "scancode queue full; dropping keyboard input");
+            }
+        }
+    }
+}
+
+/// Print `message` once per `flag`, no matter how often it is hit.
+fn warn_once(flag: &AtomicBool, message: &str) {
+    if !flag.swap(true, Ordering::Relaxed) {
+        println!("WARNING: {}", message);
+    }
+}
+
+/// Why the keyboard couldn't be set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// There is no 8042 PS/2 controller.
+    NoController,
+    /// The keyboard didn't answer a command.
+    Timeout,
+    /// The keyboard asked for a command again more times than allowed.
+    Resend,
+    /// The keyboard failed its self-test after a reset.
+    SelfTestFailed,
+}
+
+impl fmt::Display for Ps2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ps2Error::NoController => f.write_str("no ps/2 controller"),
+            Ps2Error::Timeout => f.write_str("keyboard did not answer"),
+            Ps2Error::Resend => f.write_str("keyboard kept asking for a resend"),
+            Ps2Error::SelfTestFailed => f.write_str("keyboard failed its self-test"),
+        }
+    }
+}
+
+/// A scancode set the decoder understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+/// How the keyboard was found to be configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyboardInfo {
+    /// Whether the controller translates set 2 to set 1, if it said.
+    pub translation: Option<bool>,
+    /// The set the keyboard reported (1, 2 or 3), when asked. It is only
+    /// asked when the controller doesn't translate.
+    pub reported_set: Option<u8>,
+    /// The set scancodes are decoded as.
+    pub set: ScancodeSet,
+}
+
+impl Default for KeyboardInfo {
+    fn default() -> Self {
+        KeyboardInfo { translation: None, reported_set: None, set: ScancodeSet::Set1 }
+    }
+}
+
+impl fmt::Display for KeyboardInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let set = match self.set {
+            ScancodeSet::Set1 => 1,
+            ScancodeSet::Set2 => 2,
+        };
+        write!(f, "scancode set {}, translation ", set)?;
+        match self.translation {
+            Some(true) => f.write_str("on")?,
+            Some(false) => f.write_str("off")?,
+            None => f.write_str("unknown")?,
+        }
+        if let Some(reported) = self.reported_set {
+            write!(f, ", keyboard reports set {}", reported)?;
+        }
+        Ok(())
+    }
+}
+
+static INFO: Mutex<Option<KeyboardInfo>> = Mutex::new(None);
+
+/// Set by [`init`] when a keyboard acknowledged the reset.
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// Return whether [`init`] found a keyboard. Without one the keyboard IRQ
+/// is masked and input only comes from COM1.
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::Acquire)
+}
+
+/// Record whether there is a keyboard, and mask its IRQ if not.
+///
+/// Exposed for tests, which pretend the keyboard is gone.
+#[doc(hidden)]
+pub fn set_present(present: bool) {
+    PRESENT.store(present, Ordering::Release);
+    crate::interrupts::set_masked(InterruptIndex::Keyboard, !present);
+}
+
+/// Return how [`init`], or the latest re-initialization, found the
+/// keyboard configured, or the set 1 default before [`init`] has run.
+pub fn info() -> KeyboardInfo {
+    INFO.lock().unwrap_or_default()
+}
+
+/// Record how the keyboard was found configured, and decode what it sends
+/// in its set from now on.
+fn found(info: KeyboardInfo) {
+    SCANCODES.use_set(info.set);
+    *INFO.lock() = Some(info);
+}
+
+/// Pick the set to decode. With translation the controller always hands
+/// over set 1; without it the keyboard's native set 2 is the likely one.
+fn choose_set(translation: Option<bool>, reported_set: Option<u8>) -> ScancodeSet {
+    match (translation, reported_set) {
+        (Some(false), Some(1)) => ScancodeSet::Set1,
+        (Some(false), _) => ScancodeSet::Set2,
+        _ => ScancodeSet::Set1,
+    }
+}
+
+/// Turn the keyboard's answer to "which set?" into a set number. Through
+/// translation the answer arrives translated too.
+fn reported_set(reply: u8) -> Option<u8> {
+    match reply {
+        0x01 | 0x43 => Some(1),
+        0x02 | 0x41 => Some(2),
+        0x03 | 0x3F => Some(3),
+        _ => None,
+    }
+}
+
+/// Wait until the controller has taken the last byte written.
+fn wait_input_empty() -> Result<(), Ps2Error> {
+    for _ in 0..POLL_LIMIT {
+        if !controller_status().has(ControllerStatus::INPUT_FULL) {
+            return Ok(());
+        }
+        let _ = time::pit_oneshot_us(POLL_INTERVAL_US);
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// Wait for a byte on the data port and read it. Only for use with
+/// interrupts off, or the keyboard handler takes it first.
+fn read_polled(ports: &Ps2Ports) -> Result<u8, Ps2Error> {
+    for _ in 0..POLL_LIMIT {
+        if controller_status().has(ControllerStatus::OUTPUT_FULL) {
+            return Ok(unsafe { ports.data.read() });
+        }
+        let _ = time::pit_oneshot_us(POLL_INTERVAL_US);
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// Send `byte` to the keyboard and poll for its acknowledgement.
+fn send_polled(ports: &Ps2Ports, byte: u8) -> Result<(), Ps2Error> {
+    wait_input_empty()?;
+    unsafe { ports.data.write(byte) };
+    match read_polled(ports)? {
+        ACK => Ok(()),
+        _ => Err(Ps2Error::Resend),
+    }
+}
+
+/// Reset the keyboard by polling and wait for its self-test.
+///
+/// Fails with [`Ps2Error::Timeout`] when nothing acknowledges the reset;
+/// a keyboard that acknowledges it but fails the self-test, or never
+/// reports on it, is still there.
+fn reset_polled(ports: &Ps2Ports) -> Result<(), Ps2Error> {
+    // A byte left over from the firmware would be taken for the reply.
+    while controller_status().has(ControllerStatus::OUTPUT_FULL) {
+        let _ = unsafe { ports.data.read() };
+    }
+    send_polled(ports, RESET)?;
+    for _ in 0..SELF_TEST_READS {
+        match read_polled(ports) {
+            Ok(SELF_TEST_PASSED) => return Ok(()),
+            Ok(SELF_TEST_FAILED) => {
+                println!("keyboard: {}", Ps2Error::SelfTestFailed);
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+    println!("keyboard: no self-test result after the reset");
+    Ok(())
+}
+
+/// Read the controller's translation bit and, without translation, ask the
+/// keyboard for its set.
+fn detect(ports: &Ps2Ports) -> KeyboardInfo {
+    interrupts::without_interrupts(|| {
+        let translation = wait_input_empty()
+            .and_then(|()| {
+                unsafe { ports.status_cmd.write(READ_CONFIG) };
+                read_polled(ports)
+            })
+            .map(|config| config & CONFIG_TRANSLATE != 0)
+            .ok();
+        let reported_set = match translation {
+            Some(false) => send_polled(ports, SCANCODE_SET)
+                .and_then(|()| send_polled(ports, 0))
+                .and_then(|()| read_polled(ports))
+                .ok()
+                .and_then(reported_set),
+            _ => None,
+        };
+        KeyboardInfo { translation, reported_set, set: choose_set(translation, reported_set) }
+    })
+}
+
+/// Check for an 8042 PS/2 controller, reset the keyboard and find out
+/// which scancode set it delivers. Without a controller, the status port
+/// reads as all ones.
+///
+/// The keyboard IRQ is masked throughout, and stays masked unless a
+/// keyboard acknowledges the reset. No keyboard is not an error; see
+/// [`is_present`].
+pub fn init() -> Result<(), KernelError> {
+    set_present(false);
+    let ports = Ps2Ports::standard();
+    let status = unsafe { ports.status_cmd.read() };
+    ensure!(status != 0xff, Ps2Error::NoController);
+    if let Err(err) = reset_polled(&ports) {
+        println!("keyboard: none found ({}); reading input from COM1", err);
+        return Ok(());
+    }
+    found(detect(&ports));
+    // The keyboard comes up with its LEDs off, but decoding starts with Num
+    // Lock on.
+    SCANCODES.leds_changed.store(true, Ordering::Release);
+    set_present(true);
+    Ok(())
+}
+
+/// Handle a byte read by the keyboard interrupt handler.
+///
+/// Must not block or allocate, since it runs in interrupt context. Bytes
+/// that arrive without a keyboard are dropped.
+pub(crate) fn add_scancode(scancode: u8) {
+    if !is_present() {
+        return;
+    }
+    if COMMAND.awaiting.load(Ordering::Acquire)
+        && matches!(scancode, ACK | RESEND | SELF_TEST_PASSED | SELF_TEST_FAILED)
+    {
+        // A full ring means the reply is stale anyway.
+        let _ = COMMAND.replies.push(scancode);
+        return;
+    }
+    // The keyboard is disconnected while a recording plays.
+    if replay::is_replaying() {
+        return;
+    }
+    SCANCODES.receive(scancode);
+}
+
+/// Return the number of scancodes dropped because the stream fell behind.
+pub fn dropped_scancodes() -> u64 {
+    SCANCODES.dropped.load(Ordering::Relaxed)
+}
+
+/// Return the counts of out-of-band keyboard bytes and re-initializations.
+pub fn stats() -> Ps2Stats {
+    SCANCODES.counters.snapshot()
+}
+
+/// LED bits, as the keyboard's set-LEDs command takes them.
+pub const LED_SCROLL_LOCK: u8 = 1 << 0;
+pub const LED_NUM_LOCK: u8 = 1 << 1;
+pub const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// Typematic byte restored after a re-initialization: the keyboard's own
+/// default, 10.9 repeats a second after 500 ms.
+static TYPEMATIC: AtomicU8 = AtomicU8::new(0x2B);
+
+/// Set the LEDs and typematic rate, and apply them through [`reinit_task`].
+pub fn configure(leds: u8, typematic: u8) {
+    SCANCODES.leds.store(leds & 0b111, Ordering::Relaxed);
+    TYPEMATIC.store(typematic & 0x7F, Ordering::Relaxed);
+    SCANCODES.schedule_reinit();
+}
+
+/// State of a command [`reinit_task`] is sending.
+struct Command {
+    /// Replies go to `replies` rather than the scancode stream.
+    awaiting: AtomicBool,
+    replies: FixedRing<u8, 4>,
+}
+
+static COMMAND: Command = Command { awaiting: AtomicBool::new(false), replies: FixedRing::new() };
+
+/// Times a command is sent before giving up on [`RESEND`] replies.
+const COMMAND_TRIES: usize = 3;
+
+/// Wait for a reply to a command, for at most `timeout`.
+async fn reply(timeout: Duration) -> Option<u8> {
+    let deadline = time::monotonic() + timeout;
+    loop {
+        if let Some(reply) = COMMAND.replies.pop() {
+            return Some(reply);
+        }
+        if time::monotonic() >= deadline {
+            return None;
+        }
+        super::timer::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+/// Send `byte` to the keyboard and wait for it to be acknowledged.
+async fn send(byte: u8) -> Result<(), Ps2Error> {
+    let ports = Ps2Ports::standard();
+    for _ in 0..COMMAND_TRIES {
+        while COMMAND.replies.pop().is_some() {}
+        wait_input_empty()?;
+        unsafe { ports.data.write(byte) };
+        match reply(Duration::from_millis(100)).await {
+            Some(ACK) => return Ok(()),
+            Some(RESEND) => continue,
+            _ => return Err(Ps2Error::Timeout),
+        }
+    }
+    Err(Ps2Error::Resend)
+}
+
+/// Reset the keyboard, find out its scancode set again, restore its
+/// settings and turn scanning back on.
+async fn reinitialize() -> Result<(), Ps2Error> {
+    send(RESET).await?;
+    // The self-test takes a few hundred milliseconds.
+    match reply(Duration::from_secs(1)).await {
+        Some(SELF_TEST_PASSED) => {}
+        Some(SELF_TEST_FAILED) => return Err(Ps2Error::SelfTestFailed),
+        _ => return Err(Ps2Error::Timeout),
+    }
+    // A different keyboard may have been plugged in, sending another set.
+    found(detect(&Ps2Ports::standard()));
+    send_leds().await?;
+    send(SET_TYPEMATIC).await?;
+    send(TYPEMATIC.load(Ordering::Relaxed)).await?;
+    send(ENABLE_SCANNING).await
+}
+
+/// Send the LED state to the keyboard.
+async fn send_leds() -> Result<(), Ps2Error> {
+    SCANCODES.leds_changed.store(false, Ordering::Relaxed);
+    send(SET_LEDS).await?;
+    send(SCANCODES.leds.load(Ordering::Relaxed)).await
+}
+
+/// What [`reinit_task`] was asked to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Request {
+    Reinit,
+    /// Only the LEDs changed.
+    Leds,
+}
+
+/// Resolves once a re-initialization or an LED update has been requested.
+struct Requested(&'static ScancodeInput);
+
+impl Future for Requested {
+    type Output = Request;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Request> {
+        let input = self.0;
+        input.reinit_waker.register(cx.waker());
+        if input.reinit.swap(false, Ordering::Acquire) {
+            Poll::Ready(Request::Reinit)
+        } else if input.leds_changed.load(Ordering::Acquire) {
+            Poll::Ready(Request::Leds)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Re-initialize the keyboard whenever it is plugged back in or fails its
+/// self-test, and update its LEDs when they change, forever. Spawn it once.
+///
+/// Returns at once when [`init`] found no keyboard.
+pub async fn reinit_task() {
+    if !is_present() {
+        return;
+    }
+    loop {
+        let request = Requested(&SCANCODES).await;
+        COMMAND.awaiting.store(true, Ordering::Release);
+        let result = match request {
+            Request::Reinit => reinitialize().await,
+            Request::Leds => send_leds().await,
+        };
+        COMMAND.awaiting.store(false, Ordering::Release);
+        match (request, result) {
+            (Request::Reinit, Ok(())) => bump(&SCANCODES.counters.reinits),
+            (Request::Reinit, Err(err)) => {
+                bump(&SCANCODES.counters.reinit_failures);
+                println!("keyboard: re-initialization failed: {}", err);
+            }
+            (Request::Leds, Ok(())) => {}
+            (Request::Leds, Err(err)) => println!("keyboard: setting the leds failed: {}", err),
+        }
+    }
+}
+
+/// Stream of raw scancodes delivered by the keyboard interrupt.
+///
+/// There is only one scancode queue, so only one stream may be created.
+pub struct ScancodeStream {
+    input: &'static ScancodeInput,
+    /// Decoding should start over before the next scancode.
+    reset: bool,
+    /// Where what is taken from the queue is recorded, if anywhere.
+    recorder: Option<&'static replay::Recorder>,
+}
+
+impl ScancodeStream {
+    /// Return a stream reading the scancode queue.
+    ///
+    /// Panics if called more than once.
+    pub fn new() -> Self {
+        ScancodeStream { recorder: Some(&replay::RECORDER), ..Self::with_input(&SCANCODES) }
+    }
+
+    fn with_input(input: &'static ScancodeInput) -> Self {
+        input.open();
+        ScancodeStream {
+            input,
+            reset: false,
+            recorder: None,
+        }
+    }
+
+    #[cfg(test)]
+    fn with_recorder(input: &'static ScancodeInput, recorder: &'static replay::Recorder) -> Self {
+        ScancodeStream { recorder: Some(recorder), ..Self::with_input(input) }
+    }
+
+    /// Return whether decoding should start over, because the keyboard was
+    /// replugged or lost input since the last call.
+    pub fn take_reset(&mut self) -> bool {
+        core::mem::take(&mut self.reset)
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        loop {
+            let input = match self.input.pop() {
+                Some(input) => input,
+                None => {
+                    // Register before looking again, so input queued in
+                    // between either shows up or wakes the new waker.
+                    self.input.waker.register(cx.waker());
+                    match self.input.pop() {
+                        Some(input) => {
+                            self.input.waker.take();
+                            input
+                        }
+                        None => return Poll::Pending,
+                    }
+                }
+            };
+            if let Some(recorder) = self.recorder {
+                recorder.record(input);
+            }
+            match input {
+                Input::Scancode(scancode) => return Poll::Ready(Some(scancode)),
+                Input::Reset => self.reset = true,
+            }
+        }
+    }
+}
+
+/// Watches key events for the Ctrl+Alt+Del chord.
+///
+/// `pc_keyboard` doesn't track the Alt keys, so both modifiers are followed
+/// here from the raw events.
+#[derive(Default)]
+struct CtrlAltDel {
+    ctrl: bool,
+    alt: bool,
+}
+
+impl CtrlAltDel {
+    /// Update the modifier state and return whether `event` completes the
+    /// chord.
+    fn observe(&mut self, event: &KeyEvent) -> bool {
+        let down = event.state != KeyState::Up;
+        match event.code {
+            KeyCode::LControl | KeyCode::RControl => self.ctrl = down,
+            KeyCode::LAlt | KeyCode::RAltGr => self.alt = down,
+            KeyCode::Delete => return down && self.ctrl && self.alt,
+            _ => {}
+        }
+        false
+    }
+}
+
+/// Stream of [`InputEvent`]s decoded from a [`ScancodeStream`] with the US
+/// layout, or the loaded [`keymap`] over it.
+///
+/// Ctrl+letter combinations are decoded as the matching control characters
+/// (Ctrl+U is `'\u{15}'`), which the line editor relies on. Ctrl+Alt+Del
+/// calls [`power::reboot`](crate::power::reboot) when the stream sees it,
+/// and Alt+F*n* calls [`vt::switch_to`] for terminal *n* - 1; neither is
+/// yielded. Alt with the other function keys is.
+pub struct KeyStream {
+    scancodes: ScancodeStream,
+    set: ScancodeSet,
+    keyboard: Decoder,
+    translator: Translator,
+    chord: CtrlAltDel,
+}
+
+/// A `pc_keyboard` decoder for either set; the set is a type parameter
+/// there.
+enum Decoder {
+    Set1(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    Set2(Keyboard<layouts::Us104Key, ScancodeSet2>),
+}
+
+impl Decoder {
+    fn new(set: ScancodeSet) -> Self {
+        let control = HandleControl::MapLettersToUnicode;
+        match set {
+            ScancodeSet::Set1 => {
+                Decoder::Set1(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, control))
+            }
+            ScancodeSet::Set2 => {
+                Decoder::Set2(Keyboard::new(ScancodeSet2::new(), layouts::Us104Key, control))
+            }
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Result<Option<KeyEvent>, pc_keyboard::Error> {
+        match self {
+            Decoder::Set1(keyboard) => keyboard.add_byte(byte),
+            Decoder::Set2(keyboard) => keyboard.add_byte(byte),
+        }
+    }
+
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        match self {
+            Decoder::Set1(keyboard) => keyboard.process_keyevent(event),
+            Decoder::Set2(keyboard) => keyboard.process_keyevent(event),
+        }
+    }
+}
+
+impl KeyStream {
+    /// Decode the keys of `scancodes`, in the set the keyboard was last
+    /// found to send. Decoding follows it when a re-initialization finds
+    /// another.
+    pub fn new(scancodes: ScancodeStream) -> Self {
+        let set = scancodes.input.set();
+        Self::with_set(scancodes, set)
+    }
+
+    fn with_set(scancodes: ScancodeStream, set: ScancodeSet) -> Self {
+        KeyStream {
+            scancodes,
+            set,
+            keyboard: Decoder::new(set),
+            translator: Translator::new(),
+            chord: CtrlAltDel::default(),
+        }
+    }
+
+    /// Return whether Num Lock is on.
+    pub fn numlock(&self) -> bool {
+        self.translator.numlock()
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = InputEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<InputEvent>> {
+        let this = &mut *self;
+        // Most scancodes (releases, modifiers, prefixes) don't produce a key.
+        loop {
+            let scancode = match Pin::new(&mut this.scancodes).poll_next(cx) {
+                Poll::Ready(Some(scancode)) => scancode,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let input = this.scancodes.input;
+            if this.scancodes.take_reset() {
+                this.set = input.set();
+                this.keyboard = Decoder::new(this.set);
+                this.translator = Translator::new();
+                this.chord = CtrlAltDel::default();
+                input.set_led(LED_NUM_LOCK, this.translator.numlock());
+            }
+            let Ok(Some(key_event)) = this.keyboard.add_byte(scancode) else {
+                continue;
+            };
+            if this.chord.observe(&key_event) {
+                crate::power::reboot();
+            }
+            let decoded = this.keyboard.process_keyevent(key_event.clone());
+            let numlock = this.translator.numlock();
+            let event = this.translator.translate(&key_event, decoded);
+            if this.translator.numlock() != numlock {
+                input.set_led(LED_NUM_LOCK, this.translator.numlock());
+            }
+            if let Some(InputEvent::Chord(Modifiers::ALT, KeyAction::Function(key))) = event
+                && vt::switch_to(usize::from(key) - 1).is_ok()
+            {
+                continue;
+            }
+            if let Some(event) = event {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+}
+
+/// Return the lines typed on the keyboard, echoed to the VGA screen.
+///
+/// Opens the scancode stream, so it panics if called more than once.
+pub fn lines() -> Lines<KeyStream, VgaEcho> {
+    Lines::new(KeyStream::new(ScancodeStream::new()), VgaEcho::new())
+}
+
+#[test_case]
+fn test_stream_yields_scancodes_in_order() {
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+
+    static QUEUE: ScancodeInput = ScancodeInput::new();
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut stream = ScancodeStream::with_input(&QUEUE);
+    for scancode in [0x1e, 0x9e, 0x30, 0xb0] {
+        QUEUE.push(scancode);
+    }
+
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    for expected in [0x1e, 0x9e, 0x30, 0xb0] {
+        assert_eq!(
+            Pin::new(&mut stream).poll_next(&mut cx),
+            Poll::Ready(Some(expected))
+        );
+    }
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+}
+
+#[test_case]
+fn test_push_wakes_registered_waker_once() {
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+
+    static QUEUE: ScancodeInput = ScancodeInput::new();
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut stream = ScancodeStream::with_input(&QUEUE);
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+
+    // An empty poll registers the waker; the next push must wake it.
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+    QUEUE.push(0x1c);
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        Pin::new(&mut stream).poll_next(&mut cx),
+        Poll::Ready(Some(0x1c))
+    );
+
+    // Waking consumed the registration, so later pushes don't wake anyone
+    // until the stream is polled empty again.
+    QUEUE.push(0x9c);
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        Pin::new(&mut stream).poll_next(&mut cx),
+        Poll::Ready(Some(0x9c))
+    );
+}
+
+#[test_case]
+fn test_push_before_poll_is_not_lost() {
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+
+    static QUEUE: ScancodeInput = ScancodeInput::new();
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut stream = ScancodeStream::with_input(&QUEUE);
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+
+    // A scancode pushed while nobody is registered has no one to wake, but
+    // the next poll must still return it rather than park the consumer.
+    QUEUE.push(0x2a);
+    assert_eq!(WAKES.load(Ordering::SeqCst), 0);
+    assert_eq!(
+        Pin::new(&mut stream).poll_next(&mut cx),
+        Poll::Ready(Some(0x2a))
+    );
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+    QUEUE.push(0xaa);
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+}
+
+#[test_case]
+fn test_push_without_queue_is_dropped() {
+    static QUEUE: ScancodeInput = ScancodeInput::new();
+
+    QUEUE.push(0x1e);
+    QUEUE.push(0x1e);
+    assert!(QUEUE.warned_uninit.load(Ordering::Relaxed));
+    assert!(QUEUE.queue.is_empty());
+}
+
+#[test_case]
+fn test_ctrl_alt_del_chord() {
+    let event = |code, state| KeyEvent { code, state };
+    let mut chord = CtrlAltDel::default();
+
+    assert!(!chord.observe(&event(KeyCode::Delete, KeyState::Down)));
+    assert!(!chord.observe(&event(KeyCode::LControl, KeyState::Down)));
+    assert!(!chord.observe(&event(KeyCode::Delete, KeyState::Down)));
+    assert!(!chord.observe(&event(KeyCode::RAltGr, KeyState::Down)));
+    assert!(!chord.observe(&event(KeyCode::Delete, KeyState::Up)));
+    assert!(chord.observe(&event(KeyCode::Delete, KeyState::Down)));
+
+    // Releasing a modifier breaks the chord.
+    assert!(!chord.observe(&event(KeyCode::LControl, KeyState::Up)));
+    assert!(!chord.observe(&event(KeyCode::Delete, KeyState::Down)));
+}
+
+#[test_case]
+fn test_hot_plug_restarts_decoding_and_schedules_reinit() {
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+
+    static QUEUE: ScancodeInput = ScancodeInput::new();
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut keys = KeyStream::new(ScancodeStream::with_input(&QUEUE));
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    let mut requested = Requested(&QUEUE);
+    assert_eq!(Pin::new(&mut requested).poll(&mut cx), Poll::Pending);
+
+    // An extended prefix cut off by the unplug, then the keyboard's
+    // announcement and an 'a' press and release.
+    for byte in [EXTENDED, SELF_TEST_PASSED, 0x1e, 0x9e] {
+        QUEUE.receive(byte);
+    }
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    assert_eq!(Pin::new(&mut requested).poll(&mut cx), Poll::Ready(Request::Reinit));
+    assert_eq!(Pin::new(&mut keys).poll_next(&mut cx), Poll::Ready(Some(InputEvent::Char('a'))));
+    assert_eq!(
+        QUEUE.counters.snapshot(),
+        Ps2Stats { hot_plugs: 1, reinits_scheduled: 1, ..Ps2Stats::default() }
+    );
+}
+
+#[test_case]
+fn test_shift_release_is_not_hot_plug() {
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+
+    static QUEUE: ScancodeInput = ScancodeInput::new();
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut stream = ScancodeStream::with_input(&QUEUE);
+    // Left Shift press and release, then the extended fake-shift release.
+    let bytes = [LEFT_SHIFT_MAKE, LEFT_SHIFT_BREAK, EXTENDED, LEFT_SHIFT_BREAK];
+    for byte in bytes {
+        QUEUE.receive(byte);
+    }
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    for expected in bytes {
+        assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Ready(Some(expected)));
+    }
+    assert!(!stream.take_reset());
+    assert_eq!(QUEUE.counters.snapshot(), Ps2Stats::default());
+
+    // With Shift up, the same byte on its own is the keyboard returning.
+    QUEUE.receive(SELF_TEST_PASSED);
+    QUEUE.receive(0x1e);
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Ready(Some(0x1e)));
+    assert!(stream.take_reset());
+    assert_eq!(QUEUE.counters.snapshot().hot_plugs, 1);
+}
+
+#[test_case]
+fn test_overrun_and_self_test_failure() {
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+
+    static QUEUE: ScancodeInput = ScancodeInput::new();
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut stream = ScancodeStream::with_input(&QUEUE);
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+
+    // Overruns only restart decoding.
+    QUEUE.receive(OVERRUN);
+    QUEUE.receive(OVERRUN_SET1);
+    assert!(!QUEUE.reinit.load(Ordering::Relaxed));
+    QUEUE.receive(SELF_TEST_FAILED);
+    assert!(QUEUE.reinit.load(Ordering::Relaxed));
+    assert_eq!(
+        QUEUE.counters.snapshot(),
+        Ps2Stats {
+            self_test_failures: 1,
+            overruns: 2,
+            reinits_scheduled: 1,
+            ..Ps2Stats::default()
+        }
+    );
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+    assert!(stream.take_reset());
+}
+
+#[test_case]
+fn test_controller_status_display() {
+    let status = ControllerStatus(ControllerStatus::OUTPUT_FULL | ControllerStatus::SYSTEM);
+    assert_eq!(alloc::format!("{}", status), "0x05 output-full system");
+    assert_eq!(alloc::format!("{}", ControllerStatus(0)), "0x00");
+}
+
+#[cfg(test)]
+fn decode(set: ScancodeSet, bytes: &[u8]) -> alloc::vec::Vec<DecodedKey> {
+    let mut decoder = Decoder::new(set);
+    let mut keys = alloc::vec::Vec::new();
+    for &byte in bytes {
+        if let Ok(Some(event)) = decoder.add_byte(byte) {
+            keys.extend(decoder.process_keyevent(event));
+        }
+    }
+    keys
+}
+
+#[test_case]
+fn test_both_sets_decode_alike() {
+    let keys = |text: &str| text.chars().map(DecodedKey::Unicode).collect::<alloc::vec::Vec<_>>();
+    // 'a' pressed and released.
+    assert_eq!(decode(ScancodeSet::Set1, &[0x1e, 0x9e]), keys("a"));
+    assert_eq!(decode(ScancodeSet::Set2, &[0x1c, 0xf0, 0x1c]), keys("a"));
+    // Shift+A, then Enter.
+    assert_eq!(decode(ScancodeSet::Set1, &[0x2a, 0x1e, 0x9e, 0xaa, 0x1c, 0x9c]), keys("A\n"));
+    assert_eq!(
+        decode(ScancodeSet::Set2, &[0x12, 0x1c, 0xf0, 0x1c, 0xf0, 0x12, 0x5a, 0xf0, 0x5a]),
+        keys("A\n")
+    );
+    // Each set's bytes mean something else in the other.
+    assert_ne!(decode(ScancodeSet::Set1, &[0x1c, 0xf0, 0x1c]), keys("a"));
+}
+
+#[test_case]
+fn test_choose_set() {
+    assert_eq!(choose_set(Some(true), None), ScancodeSet::Set1);
+    assert_eq!(choose_set(None, None), ScancodeSet::Set1);
+    assert_eq!(choose_set(Some(false), None), ScancodeSet::Set2);
+    assert_eq!(choose_set(Some(false), Some(2)), ScancodeSet::Set2);
+    assert_eq!(choose_set(Some(false), Some(1)), ScancodeSet::Set1);
+    assert_eq!(choose_set(Some(false), Some(3)), ScancodeSet::Set2);
+    assert_eq!(reported_set(0x41), Some(2));
+    assert_eq!(reported_set(0x02), Some(2));
+    assert_eq!(reported_set(0xfa), None);
+
+    let info = KeyboardInfo { translation: Some(false), reported_set: Some(2), set: ScancodeSet::Set2 };
+    assert_eq!(
+        alloc::format!("{}", info),
+        "scancode set 2, translation off, keyboard reports set 2"
+    );
+    assert_eq!(alloc::format!("{}", KeyboardInfo::default()), "scancode set 1, translation unknown");
+}
+
+#[test_case]
+fn test_set2_hot_plug_and_release_prefix() {
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+
+    static QUEUE: ScancodeInput = ScancodeInput::new();
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    QUEUE.set2.store(true, Ordering::Relaxed);
+    let mut keys = KeyStream::with_set(ScancodeStream::with_input(&QUEUE), ScancodeSet::Set2);
+    // 0x2A is V in set 2, not Left Shift, so it doesn't hide the
+    // announcement; the release prefix before it is cut off.
+    for byte in [0x2a, 0xf0, SELF_TEST_PASSED, 0x1c, 0xf0, 0x1c] {
+        QUEUE.receive(byte);
+    }
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    assert_eq!(Pin::new(&mut keys).poll_next(&mut cx), Poll::Ready(Some(InputEvent::Char('v'))));
+    assert_eq!(Pin::new(&mut keys).poll_next(&mut cx), Poll::Ready(Some(InputEvent::Char('a'))));
+    assert_eq!(QUEUE.counters.snapshot().hot_plugs, 1);
+}
+
+#[test_case]
+fn test_hot_plug_redetects_set() {
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+
+    static QUEUE: ScancodeInput = ScancodeInput::new();
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut keys = KeyStream::new(ScancodeStream::with_input(&QUEUE));
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    // A set 1 keyboard types A, then a set 2 one takes its place and types B.
+    for byte in [0x1e, 0x9e, SELF_TEST_PASSED] {
+        QUEUE.receive(byte);
+    }
+    assert_eq!(Pin::new(&mut keys).poll_next(&mut cx), Poll::Ready(Some(InputEvent::Char('a'))));
+    // What the re-initialization finds.
+    QUEUE.use_set(ScancodeSet::Set2);
+    for byte in [0x32, 0xf0, 0x32] {
+        QUEUE.receive(byte);
+    }
+    assert_eq!(Pin::new(&mut keys).poll_next(&mut cx), Poll::Ready(Some(InputEvent::Char('b'))));
+    assert_eq!(QUEUE.set(), ScancodeSet::Set2);
+    assert_eq!(QUEUE.counters.snapshot().hot_plugs, 1);
+}
+
+#[cfg(test)]
+fn input_events(set: ScancodeSet, bytes: &[u8]) -> alloc::vec::Vec<InputEvent> {
+    let mut decoder = Decoder::new(set);
+    let mut translator = Translator::new();
+    let mut events = alloc::vec::Vec::new();
+    for &byte in bytes {
+        if let Ok(Some(event)) = decoder.add_byte(byte) {
+            let decoded = decoder.process_keyevent(event.clone());
+            events.extend(translator.translate(&event, decoded));
+        }
+    }
+    events
+}
+
+#[test_case]
+fn test_keypad_follows_numlock_set1() {
+    use InputEvent::{Char, Key};
+
+    let on = [
+        0x47, 0xc7, // keypad 7
+        EXTENDED, 0x47, EXTENDED, 0xc7, // Home
+        0x4c, 0xcc, // keypad 5
+        0x53, 0xd3, // keypad .
+        EXTENDED, 0x53, EXTENDED, 0xd3, // Delete
+        EXTENDED, 0x1c, EXTENDED, 0x9c, // keypad Enter
+        0x4e, 0xce, // keypad +
+        EXTENDED, 0x35, EXTENDED, 0xb5, // keypad /
+        // Home with the fake Shift some keyboards send around it.
+        EXTENDED, 0x2a, EXTENDED, 0x47, EXTENDED, 0xc7, EXTENDED, 0xaa,
+    ];
+    assert_eq!(
+        input_events(ScancodeSet::Set1, &on),
+        [
+            Char('7'),
+            Key(KeyAction::Home),
+            Char('5'),
+            Char('.'),
+            Key(KeyAction::Delete),
+            Char('\n'),
+            Char('+'),
+            Char('/'),
+            Key(KeyAction::Home),
+        ]
+    );
+
+    let mut off = alloc::vec![0x45, 0xc5];
+    off.extend([
+        0x47, 0xc7, // keypad 7
+        0x48, 0xc8, // keypad 8
+        0x4c, 0xcc, // keypad 5
+        0x53, 0xd3, // keypad .
+        EXTENDED, 0x4f, EXTENDED, 0xcf, // End
+        EXTENDED, 0x1c, EXTENDED, 0x9c, // keypad Enter
+        0x4e, 0xce, // keypad +
+    ]);
+    assert_eq!(
+        input_events(ScancodeSet::Set1, &off),
+        [
+            Key(KeyAction::Home),
+            Key(KeyAction::Up),
+            Key(KeyAction::Delete),
+            Key(KeyAction::End),
+            Char('\n'),
+            Char('+'),
+        ]
+    );
+}
+
+#[test_case]
+fn test_keypad_follows_numlock_set2() {
+    use InputEvent::{Char, Key};
+
+    let release = |code: u8| [0xf0, code];
+    let extended = |code: u8| [EXTENDED, code, EXTENDED, 0xf0, code];
+    let mut on = alloc::vec![0x6c];
+    on.extend(release(0x6c));
+    on.extend(extended(0x6c));
+    on.extend(extended(0x71));
+    assert_eq!(
+        input_events(ScancodeSet::Set2, &on),
+        [Char('7'), Key(KeyAction::Home), Key(KeyAction::Delete)]
+    );
+
+    let mut off = alloc::vec![0x77, 0xf0, 0x77, 0x6c];
+    off.extend(release(0x6c));
+    off.extend([0x71]);
+    off.extend(release(0x71));
+    off.extend(extended(0x6c));
+    off.extend(extended(0x5a));
+    assert_eq!(
+        input_events(ScancodeSet::Set2, &off),
+        [Key(KeyAction::Home), Key(KeyAction::Delete), Key(KeyAction::Home), Char('\n')]
+    );
+}
+
+#[test_case]
+fn test_named_keys_with_modifiers_are_chords() {
+    use InputEvent::{Chord, Key};
+
+    // Shift+PageUp, then PageUp on its own.
+    let bytes = [0x2a, EXTENDED, 0x49, EXTENDED, 0xc9, 0xaa, EXTENDED, 0x49, EXTENDED, 0xc9];
+    assert_eq!(
+        input_events(ScancodeSet::Set1, &bytes),
+        [Chord(Modifiers::SHIFT, KeyAction::PageUp), Key(KeyAction::PageUp)]
+    );
+    // Ctrl+Left, and Alt+F2.
+    let bytes = [0x1d, EXTENDED, 0x4b, EXTENDED, 0xcb, 0x9d, 0x38, 0x3c, 0xbc, 0xb8];
+    assert_eq!(
+        input_events(ScancodeSet::Set1, &bytes),
+        [Chord(Modifiers::CTRL, KeyAction::Left), Chord(Modifiers::ALT, KeyAction::Function(2))]
+    );
+    // Ctrl+letter is still a control character.
+    assert_eq!(input_events(ScancodeSet::Set1, &[0x1d, 0x16, 0x96, 0x9d]), [InputEvent::Char('\u{15}')]);
+}
+
+#[test_case]
+fn test_numlock_toggle_updates_led() {
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+
+    static QUEUE: ScancodeInput = ScancodeInput::new();
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut keys = KeyStream::with_set(ScancodeStream::with_input(&QUEUE), ScancodeSet::Set1);
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    let mut requested = Requested(&QUEUE);
+    assert!(keys.numlock());
+    assert_eq!(Pin::new(&mut requested).poll(&mut cx), Poll::Pending);
+
+    for byte in [0x45, 0xc5, 0x47, 0xc7] {
+        QUEUE.receive(byte);
+    }
+    assert_eq!(
+        Pin::new(&mut keys).poll_next(&mut cx),
+        Poll::Ready(Some(InputEvent::Key(KeyAction::Home)))
+    );
+    assert!(!keys.numlock());
+    assert_eq!(QUEUE.leds.load(Ordering::Relaxed) & LED_NUM_LOCK, 0);
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    assert_eq!(Pin::new(&mut requested).poll(&mut cx), Poll::Ready(Request::Leds));
+}
+
+#[test_case]
+fn test_alt_function_key_switches_terminal() {
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+
+    static QUEUE: ScancodeInput = ScancodeInput::new();
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut keys = KeyStream::with_set(ScancodeStream::with_input(&QUEUE), ScancodeSet::Set1);
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    assert_eq!(vt::current(), 0);
+
+    // Alt+F2, then `a`.
+    for byte in [0x38, 0x3c, 0xbc, 0xb8, 0x1e, 0x9e] {
+        QUEUE.receive(byte);
+    }
+    // The chord is swallowed, so the next event is the `a`.
+    assert_eq!(Pin::new(&mut keys).poll_next(&mut cx), Poll::Ready(Some(InputEvent::Char('a'))));
+    assert_eq!(vt::current(), 1);
+    assert_eq!(Pin::new(&mut keys).poll_next(&mut cx), Poll::Pending);
+
+    vt::switch_to(0).unwrap();
+    assert_eq!(vt::current(), 0);
+}
+
+#[test_case]
+fn test_absent_keyboard_is_masked_and_ignored() {
+    let was_present = is_present();
+    set_present(false);
+    assert!(crate::interrupts::is_masked(InterruptIndex::Keyboard));
+
+    // A stray byte, and one that would otherwise count as a hot plug.
+    let heap_used = crate::allocator::heap_stats().used;
+    let hot_plugs = stats().hot_plugs;
+    add_scancode(0x1e);
+    add_scancode(SELF_TEST_PASSED);
+    assert_eq!(crate::allocator::heap_stats().used, heap_used);
+    assert_eq!(stats().hot_plugs, hot_plugs);
+
+    set_present(was_present);
+    assert_eq!(crate::interrupts::is_masked(InterruptIndex::Keyboard), !was_present);
+}
@@ -0,0 +1,181 @@
+//! Key events as consumers see them.
+//!
+//! `pc_keyboard` hands out a [`DecodedKey`], which is either a character or
+//! a raw [`KeyCode`]. That leaves some keys ambiguous: both Delete keys come
+//! out as the DEL character (which the line editor takes for Backspace),
+//! keypad 5 types a `5` with Num Lock off, and nothing says whether a
+//! modifier was held with an arrow key. [`Translator`] turns each event into
+//! one [`InputEvent`] instead, so consumers match on a single enum:
+//!
+//! - typed characters, including keypad digits and operators with Num Lock
+//!   on and keypad Enter, are [`InputEvent::Char`];
+//! - the navigation keys, whether the grey `0xE0`-prefixed ones or the
+//!   keypad with Num Lock off, are [`InputEvent::Key`];
+//! - a navigation key pressed with Shift, Ctrl or Alt held is an
+//!   [`InputEvent::Chord`].
+//!
+//! Keys a loaded [`keymap`](super::keymap) maps type its characters
+//! instead of the US layout's, chosen from the Shift, AltGr and Caps Lock
+//! state followed here.
+
+use pc_keyboard::{DecodedKey, KeyCode, KeyEvent, KeyState};
+
+/// A key that doesn't type a character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAction {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    /// F1 to F12.
+    Function(u8),
+}
+
+impl KeyAction {
+    /// Return the action of a raw key `pc_keyboard` decoded, if it has one.
+    fn from_code(code: KeyCode) -> Option<KeyAction> {
+        let action = match code {
+            KeyCode::ArrowUp => KeyAction::Up,
+            KeyCode::ArrowDown => KeyAction::Down,
+            KeyCode::ArrowLeft => KeyAction::Left,
+            KeyCode::ArrowRight => KeyAction::Right,
+            KeyCode::Home => KeyAction::Home,
+            KeyCode::End => KeyAction::End,
+            KeyCode::PageUp => KeyAction::PageUp,
+            KeyCode::PageDown => KeyAction::PageDown,
+            KeyCode::Insert => KeyAction::Insert,
+            KeyCode::Delete => KeyAction::Delete,
+            KeyCode::F1 => KeyAction::Function(1),
+            KeyCode::F2 => KeyAction::Function(2),
+            KeyCode::F3 => KeyAction::Function(3),
+            KeyCode::F4 => KeyAction::Function(4),
+            KeyCode::F5 => KeyAction::Function(5),
+            KeyCode::F6 => KeyAction::Function(6),
+            KeyCode::F7 => KeyAction::Function(7),
+            KeyCode::F8 => KeyAction::Function(8),
+            KeyCode::F9 => KeyAction::Function(9),
+            KeyCode::F10 => KeyAction::Function(10),
+            KeyCode::F11 => KeyAction::Function(11),
+            KeyCode::F12 => KeyAction::Function(12),
+            _ => return None,
+        };
+        Some(action)
+    }
+}
+
+/// Modifier keys held with a [`KeyAction`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl Modifiers {
+    pub const SHIFT: Modifiers = Modifiers { shift: true, ctrl: false, alt: false };
+    pub const CTRL: Modifiers = Modifiers { shift: false, ctrl: true, alt: false };
+    pub const ALT: Modifiers = Modifiers { shift: false, ctrl: false, alt: true };
+
+    /// Return whether no modifier is held.
+    pub fn is_empty(self) -> bool {
+        self == Modifiers::default()
+    }
+}
+
+/// One decoded key press.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    /// A typed character. Ctrl+letter is the matching control character
+    /// (Ctrl+U is `'\u{15}'`).
+    Char(char),
+    /// A named key, pressed on its own.
+    Key(KeyAction),
+    /// A named key pressed with modifiers held.
+    Chord(Modifiers, KeyAction),
+}
+
+/// Turns key events into [`InputEvent`]s and follows Num Lock.
+///
+/// Num Lock starts on and Caps Lock off, as `pc_keyboard`'s decoder
+/// assumes.
+pub(super) struct Translator {
+    held: Modifiers,
+    /// Right Alt, which also counts as Alt in `held`.
+    altgr: bool,
+    numlock: bool,
+    capslock: bool,
+}
+
+impl Translator {
+    pub(super) fn new() -> Self {
+        Translator { held: Modifiers::default(), altgr: false, numlock: true, capslock: false }
+    }
+
+    /// Return whether Num Lock is on.
+    pub(super) fn numlock(&self) -> bool {
+        self.numlock
+    }
+
+    /// Translate `event`, which `pc_keyboard` decoded as `decoded`.
+    pub(super) fn translate(
+        &mut self,
+        event: &KeyEvent,
+        decoded: Option<DecodedKey>,
+    ) -> Option<InputEvent> {
+        let down = event.state != KeyState::Up;
+        match event.code {
+            KeyCode::LShift | KeyCode::RShift => self.held.shift = down,
+            KeyCode::LControl | KeyCode::RControl => self.held.ctrl = down,
+            KeyCode::LAlt => self.held.alt = down,
+            KeyCode::RAltGr => {
+                self.held.alt = down;
+                self.altgr = down;
+            }
+            _ => {}
+        }
+        // The decoder tells Num Lock from the Pause sequence, which shares
+        // its code.
+        match decoded {
+            Some(DecodedKey::RawKey(KeyCode::NumpadLock)) => {
+                self.numlock = !self.numlock;
+                return None;
+            }
+            Some(DecodedKey::RawKey(KeyCode::CapsLock)) => {
+                self.capslock = !self.capslock;
+                return None;
+            }
+            _ => {}
+        }
+        if down && let Some(chars) = super::keymap::lookup(event.code) {
+            return self.mapped(chars);
+        }
+        let action = match (event.code, decoded?) {
+            (KeyCode::Delete, _) => KeyAction::Delete,
+            (KeyCode::NumpadPeriod, _) if !self.numlock => KeyAction::Delete,
+            // The middle of the keypad does nothing without Num Lock.
+            (KeyCode::Numpad5, _) if !self.numlock => return None,
+            (_, DecodedKey::Unicode(character)) => return Some(InputEvent::Char(character)),
+            (_, DecodedKey::RawKey(code)) => KeyAction::from_code(code)?,
+        };
+        if self.held.is_empty() {
+            Some(InputEvent::Key(action))
+        } else {
+            Some(InputEvent::Chord(self.held, action))
+        }
+    }
+
+    /// Return what a key the loaded keymap maps to `chars` types now.
+    fn mapped(&self, chars: super::keymap::KeyChars) -> Option<InputEvent> {
+        if self.held.ctrl {
+            let letter = chars.plain.filter(char::is_ascii_alphabetic)?;
+            return Some(InputEvent::Char(char::from(letter as u8 & 0x1f)));
+        }
+        chars.select(self.held.shift, self.altgr, self.capslock).map(InputEvent::Char)
+    }
+}
@@ -0,0 +1,320 @@
+//! Keymaps loaded at run time, over the built-in US layout.
+//!
+//! A keymap says what some keys type, by their scancode set 1 make code.
+//! [`load_keymap`] checks one and installs it; from then on
+//! [`KeyStream`](super::KeyStream) types its characters for those keys and
+//! leaves every other key, and the modifiers themselves, to the US layout.
+//! [`reset_keymap`] goes back to the US layout alone. `keymap=<file>` on the
+//! command line loads one from the ramdisk at boot, and the shell's `keymap`
+//! command does the same later.
+//!
+//! Keymaps are text:
+//!
+//! ```text
+//! chronos keymap 1
+//! # scancode plain shift altgr flags...
+//! 15 z Z - caps
+//! 10 q Q @ caps
+//! 0c ß ? U+5C
+//! ```
+//!
+//! After the header line, each line maps the key with the given hex
+//! scancode to the characters it types on its own, with Shift, and with
+//! AltGr. A character is written as itself, as `U+` and its hex code point,
+//! or as `-` for none; a key with no character at the level in use types
+//! nothing. Lines starting with `#` and blank lines are skipped.
+//!
+//! The flags are `caps`, for keys that Caps Lock shifts, and `dead`, for
+//! dead keys, which are accepted but type their character straight away
+//! for now. With Ctrl held, a key whose plain character is an ASCII letter
+//! types the matching control character, as the US layout does.
+//! Modifier and lock keys can't be remapped.
+
+use alloc::vec::Vec;
+use core::fmt;
+use pc_keyboard::KeyCode;
+
+use super::{Decoder, ScancodeSet};
+use crate::sync::IrqMutex;
+use crate::{cmdline, fs, println};
+
+/// First line of a keymap.
+pub const HEADER: &str = "chronos keymap 1";
+
+/// What one key types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyChars {
+    pub plain: Option<char>,
+    pub shift: Option<char>,
+    pub altgr: Option<char>,
+    /// Caps Lock acts as Shift for this key.
+    pub caps: bool,
+    /// A dead key; not handled yet.
+    pub dead: bool,
+}
+
+impl KeyChars {
+    /// Return the character typed with the given modifiers and locks.
+    pub fn select(&self, shift: bool, altgr: bool, capslock: bool) -> Option<char> {
+        if altgr {
+            self.altgr
+        } else if shift != (capslock && self.caps) {
+            self.shift
+        } else {
+            self.plain
+        }
+    }
+}
+
+/// A parsed keymap.
+#[derive(Debug, Clone, Default)]
+pub struct Keymap {
+    keys: Vec<(KeyCode, u8, KeyChars)>,
+}
+
+/// Why a keymap was rejected. Lines count from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeymapError {
+    /// The file isn't UTF-8.
+    NotUtf8,
+    /// The first line isn't [`HEADER`].
+    Header,
+    /// A line doesn't have a scancode and three characters, or has an
+    /// unknown flag.
+    Syntax(usize),
+    /// The scancode isn't a set 1 make code of a key.
+    Scancode(usize),
+    /// The scancode belongs to a modifier or lock key.
+    Modifier(usize),
+    /// The scancode was already mapped.
+    Duplicate(usize),
+    /// A character field isn't one character, `U+` code point or `-`.
+    Character(usize),
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeymapError::NotUtf8 => f.write_str("not UTF-8"),
+            KeymapError::Header => write!(f, "not a keymap (want \"{}\")", HEADER),
+            KeymapError::Syntax(line) => write!(f, "line {}: bad entry", line),
+            KeymapError::Scancode(line) => write!(f, "line {}: not a key scancode", line),
+            KeymapError::Modifier(line) => write!(f, "line {}: can't map a modifier", line),
+            KeymapError::Duplicate(line) => write!(f, "line {}: scancode mapped twice", line),
+            KeymapError::Character(line) => write!(f, "line {}: bad character", line),
+        }
+    }
+}
+
+/// Return the key that set 1 make code `scancode` belongs to.
+fn key_code(scancode: u8) -> Option<KeyCode> {
+    if scancode >= 0x80 {
+        return None;
+    }
+    match Decoder::new(ScancodeSet::Set1).add_byte(scancode) {
+        Ok(Some(event)) => Some(event.code),
+        _ => None,
+    }
+}
+
+/// Return whether remapping `code` would break modifier tracking.
+fn is_modifier(code: KeyCode) -> bool {
+    matches!(
+        code,
+        KeyCode::LShift
+            | KeyCode::RShift
+            | KeyCode::LControl
+            | KeyCode::RControl
+            | KeyCode::LAlt
+            | KeyCode::RAltGr
+            | KeyCode::CapsLock
+            | KeyCode::NumpadLock
+            | KeyCode::ScrollLock
+    )
+}
+
+/// Parse a character field.
+fn parse_char(field: &str) -> Result<Option<char>, ()> {
+    if field == "-" {
+        return Ok(None);
+    }
+    if let Some(hex) = field.strip_prefix("U+") {
+        let point = u32::from_str_radix(hex, 16).map_err(|_| ())?;
+        return char::from_u32(point).map(Some).ok_or(());
+    }
+    let mut chars = field.chars();
+    match (chars.next(), chars.next()) {
+        (Some(character), None) => Ok(Some(character)),
+        _ => Err(()),
+    }
+}
+
+impl Keymap {
+    /// Parse a keymap in the format described in the [module docs](self).
+    pub fn parse(text: &str) -> Result<Keymap, KeymapError> {
+        let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line.trim()));
+        if lines.next().map(|(_, line)| line) != Some(HEADER) {
+            return Err(KeymapError::Header);
+        }
+        let mut keymap = Keymap::default();
+        for (number, line) in lines {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(scancode), Some(plain), Some(shift), Some(altgr)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(KeymapError::Syntax(number));
+            };
+            let scancode =
+                u8::from_str_radix(scancode, 16).map_err(|_| KeymapError::Syntax(number))?;
+            let code = key_code(scancode).ok_or(KeymapError::Scancode(number))?;
+            if is_modifier(code) {
+                return Err(KeymapError::Modifier(number));
+            }
+            if keymap.get(code).is_some() {
+                return Err(KeymapError::Duplicate(number));
+            }
+            let character = |field| parse_char(field).map_err(|()| KeymapError::Character(number));
+            let mut chars = KeyChars {
+                plain: character(plain)?,
+                shift: character(shift)?,
+                altgr: character(altgr)?,
+                caps: false,
+                dead: false,
+            };
+            for flag in fields {
+                match flag {
+                    "caps" => chars.caps = true,
+                    "dead" => chars.dead = true,
+                    _ => return Err(KeymapError::Syntax(number)),
+                }
+            }
+            keymap.keys.push((code, scancode, chars));
+        }
+        Ok(keymap)
+    }
+
+    /// Return what `code` types, if the keymap maps it.
+    pub fn get(&self, code: KeyCode) -> Option<KeyChars> {
+        self.keys.iter().find(|(key, _, _)| *key == code).map(|&(_, _, chars)| chars)
+    }
+
+    /// Return what the key with set 1 make code `scancode` types, if the
+    /// keymap maps it.
+    pub fn get_scancode(&self, scancode: u8) -> Option<KeyChars> {
+        self.keys.iter().find(|(_, key, _)| *key == scancode).map(|&(_, _, chars)| chars)
+    }
+
+    /// Return how many keys are mapped.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+static ACTIVE: IrqMutex<Option<Keymap>> = IrqMutex::named("keymap", None);
+
+/// Check the keymap in `data` and use it in place of the one in use, if
+/// any. A rejected keymap leaves the one in use alone.
+pub fn load_keymap(data: &[u8]) -> Result<(), KeymapError> {
+    let text = core::str::from_utf8(data).map_err(|_| KeymapError::NotUtf8)?;
+    let keymap = Keymap::parse(text)?;
+    *ACTIVE.lock() = Some(keymap);
+    Ok(())
+}
+
+/// Go back to the built-in US layout.
+pub fn reset_keymap() {
+    *ACTIVE.lock() = None;
+}
+
+/// Return how many keys the loaded keymap maps, or `None` if there is none.
+pub fn loaded_keys() -> Option<usize> {
+    ACTIVE.lock().as_ref().map(Keymap::len)
+}
+
+/// Return what `code` types under the loaded keymap.
+pub(super) fn lookup(code: KeyCode) -> Option<KeyChars> {
+    ACTIVE.lock().as_ref()?.get(code)
+}
+
+/// Load the keymap named by the `keymap=` option from the ramdisk or an
+/// in-memory file, if there is one.
+pub(crate) fn init() {
+    let Some(path) = cmdline::get_str("keymap") else {
+        return;
+    };
+    let result = match fs::root().and_then(|root| root.open(path)) {
+        Some(file) => load_keymap(file.as_slice()),
+        None => match fs::mem::get(path) {
+            Some(data) => load_keymap(&data),
+            None => {
+                println!("keymap: {}: no such file", path);
+                return;
+            }
+        },
+    };
+    if let Err(err) = result {
+        println!("keymap: {}: {}", path, err);
+    }
+}
+
+/// Path of the sample keymap in the test ramdisk.
+#[cfg(test)]
+const SAMPLE: &str = "keymaps/de.kmap";
+
+#[test_case]
+fn test_sample_keymap_overrides_keys() {
+    use super::{input_events, InputEvent::Char, EXTENDED};
+
+    let file = fs::root().and_then(|root| root.open(SAMPLE)).expect("sample keymap missing");
+    load_keymap(file.as_slice()).unwrap();
+    let typed = |bytes: &[u8]| input_events(ScancodeSet::Set1, bytes);
+    // The Y and Z keys swap, Shift and Caps Lock shift them, AltGr+Q is @.
+    assert_eq!(typed(&[0x15, 0x95, 0x2c, 0xac]), [Char('z'), Char('y')]);
+    assert_eq!(typed(&[0x2a, 0x15, 0x95, 0xaa]), [Char('Z')]);
+    assert_eq!(typed(&[0x3a, 0xba, 0x2c, 0xac, 0x3a, 0xba, 0x2c, 0xac]), [Char('Y'), Char('y')]);
+    assert_eq!(typed(&[EXTENDED, 0x38, 0x10, 0x90, EXTENDED, 0xb8]), [Char('@')]);
+    assert_eq!(typed(&[0x27, 0xa7, 0x2a, 0x03, 0x83, 0xaa]), [Char('ö'), Char('"')]);
+    // Caps Lock doesn't shift digits, and AltGr without a character types
+    // nothing.
+    assert_eq!(typed(&[0x3a, 0xba, 0x03, 0x83, 0x3a, 0xba]), [Char('2')]);
+    assert!(typed(&[EXTENDED, 0x38, 0x15, 0x95, EXTENDED, 0xb8]).is_empty());
+    // Ctrl follows the mapped letter; unmapped keys keep the US layout.
+    assert_eq!(typed(&[0x1d, 0x15, 0x95, 0x9d]), [Char('\u{1a}')]);
+    assert_eq!(typed(&[0x1e, 0x9e]), [Char('a')]);
+    assert_eq!(loaded_keys(), Some(12));
+
+    reset_keymap();
+    assert_eq!(loaded_keys(), None);
+    assert_eq!(typed(&[0x15, 0x95]), [Char('y')]);
+}
+
+#[test_case]
+fn test_malformed_keymaps_rejected() {
+    let parse = |body: &str| Keymap::parse(&alloc::format!("{}\n{}", HEADER, body)).map(|_| ());
+    assert_eq!(Keymap::parse("15 z Z -").unwrap_err(), KeymapError::Header);
+    assert_eq!(Keymap::parse("chronos keymap 2\n").unwrap_err(), KeymapError::Header);
+    assert_eq!(parse("15 z Z"), Err(KeymapError::Syntax(2)));
+    assert_eq!(parse("# ok\n\nzz z Z -"), Err(KeymapError::Syntax(4)));
+    assert_eq!(parse("15 z Z - shouty"), Err(KeymapError::Syntax(2)));
+    assert_eq!(parse("95 z Z -"), Err(KeymapError::Scancode(2)));
+    assert_eq!(parse("2a z Z -"), Err(KeymapError::Modifier(2)));
+    assert_eq!(parse("3a z Z -"), Err(KeymapError::Modifier(2)));
+    assert_eq!(parse("15 z Z -\n15 y Y -"), Err(KeymapError::Duplicate(3)));
+    assert_eq!(parse("15 zz Z -"), Err(KeymapError::Character(2)));
+    assert_eq!(parse("15 U+D800 Z -"), Err(KeymapError::Character(2)));
+    assert_eq!(load_keymap(b"\xff\xfe"), Err(KeymapError::NotUtf8));
+
+    let keymap = Keymap::parse(&alloc::format!("{}\n0c U+DF ? U+5C dead", HEADER)).unwrap();
+    let chars = keymap.get_scancode(0x0c).unwrap();
+    assert_eq!((chars.plain, chars.shift, chars.altgr), (Some('ß'), Some('?'), Some('\\')));
+    assert!(chars.dead && !chars.caps);
+    // A rejected keymap leaves the built-in layout in use.
+    assert_eq!(loaded_keys(), None);
+}
@@ -0,0 +1,375 @@
+//! Recording and replaying keyboard input, to reproduce input-dependent
+//! bugs.
+//!
+//! While recording, the console's [`ScancodeStream`](super::ScancodeStream)
+//! appends everything it takes from the scancode queue, scancodes and
+//! decoding restarts alike, to a ring: each entry gets a sequence number
+//! and the timer tick it was taken on, counted from the start of the
+//! recording. A [`Log`] displays as a compact hex log, one entry a line,
+//! which the shell's `replay dump` prints on COM1.
+//!
+//! Replaying disconnects the keyboard, so the interrupt handler drops what
+//! it reads, and [`run`] sends a log's entries into the scancode queue at
+//! their recorded tick offsets. Everything after the queue is the same
+//! either way.
+//!
+//! `replay=record` on the command line records from boot, and
+//! `replay=<file>` replays a log from the ramdisk or from an in-memory file
+//! received with `recv`. The shell's `replay` command does the same later.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use super::{Input, ScancodeInput, SCANCODES};
+use crate::collections::FixedRing;
+use crate::interrupts::ticks;
+use crate::sync::IrqMutex;
+use crate::task::timer;
+use crate::{cmdline, fs, println};
+
+/// Entries the recording ring keeps; older ones are overwritten.
+pub const LOG_CAPACITY: usize = 2048;
+
+/// First line of a dumped log.
+const HEADER: &str = "chronos input log 1";
+
+/// One input taken from the scancode queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    /// Counts up from zero; a gap at the start means the ring wrapped.
+    pub seq: u32,
+    /// Timer ticks since the recording started.
+    pub tick: u64,
+    input: Input,
+}
+
+impl Entry {
+    /// Return the scancode, or `None` for a decoding restart.
+    pub fn scancode(&self) -> Option<u8> {
+        match self.input {
+            Input::Scancode(scancode) => Some(scancode),
+            Input::Reset => None,
+        }
+    }
+}
+
+/// A recorded input session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Log {
+    pub entries: Vec<Entry>,
+}
+
+/// Why a dumped log couldn't be read back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The first line isn't the log header.
+    Header,
+    /// The entry on this line (counting from 1) is malformed.
+    Line(usize),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Header => f.write_str("not an input log"),
+            ParseError::Line(line) => write!(f, "bad entry on line {}", line),
+        }
+    }
+}
+
+impl Log {
+    /// Read back a log written by its [`Display`](fmt::Display) impl.
+    pub fn parse(text: &str) -> Result<Log, ParseError> {
+        let mut lines = text.lines();
+        if lines.next().map(str::trim) != Some(HEADER) {
+            return Err(ParseError::Header);
+        }
+        let mut entries = Vec::new();
+        for (index, line) in lines.enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = parse_entry(line).ok_or(ParseError::Line(index + 2))?;
+            entries.push(entry);
+        }
+        Ok(Log { entries })
+    }
+}
+
+/// Parse `seq tick byte`, all hex, with `--` for a restart.
+fn parse_entry(line: &str) -> Option<Entry> {
+    let mut fields = line.split_whitespace();
+    let seq = u32::from_str_radix(fields.next()?, 16).ok()?;
+    let tick = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let input = match fields.next()? {
+        "--" => Input::Reset,
+        byte => Input::Scancode(u8::from_str_radix(byte, 16).ok()?),
+    };
+    fields.next().is_none().then_some(Entry { seq, tick, input })
+}
+
+impl fmt::Display for Log {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        for entry in &self.entries {
+            write!(f, "{:x} {:x} ", entry.seq, entry.tick)?;
+            match entry.scancode() {
+                Some(scancode) => writeln!(f, "{:02x}", scancode)?,
+                None => writeln!(f, "--")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Records what a [`ScancodeStream`](super::ScancodeStream) takes from its
+/// queue.
+pub(super) struct Recorder {
+    entries: IrqMutex<FixedRing<Entry, LOG_CAPACITY>>,
+    active: AtomicBool,
+    seq: AtomicU32,
+    start: AtomicU64,
+}
+
+impl Recorder {
+    pub(super) const fn new() -> Self {
+        Recorder {
+            entries: IrqMutex::named("input log", FixedRing::new()),
+            active: AtomicBool::new(false),
+            seq: AtomicU32::new(0),
+            start: AtomicU64::new(0),
+        }
+    }
+
+    /// Forget what was recorded and record from now on.
+    fn start(&self) {
+        let mut entries = self.entries.lock();
+        entries.clear();
+        self.seq.store(0, Ordering::Relaxed);
+        self.start.store(ticks(), Ordering::Relaxed);
+        self.active.store(true, Ordering::Release);
+    }
+
+    fn stop(&self) {
+        self.active.store(false, Ordering::Release);
+    }
+
+    /// Append `input`, if recording.
+    pub(super) fn record(&self, input: Input) {
+        if !self.active.load(Ordering::Acquire) {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let tick = ticks() - self.start.load(Ordering::Relaxed);
+        entries.push_overwrite(Entry { seq, tick, input });
+    }
+
+    /// Copy out what has been recorded.
+    fn log(&self) -> Log {
+        Log { entries: self.entries.lock().iter().copied().collect() }
+    }
+}
+
+/// The console stream's recorder.
+pub(super) static RECORDER: Recorder = Recorder::new();
+
+/// Set while a log is being replayed.
+static REPLAYING: AtomicBool = AtomicBool::new(false);
+
+/// A log waiting for [`run`], and the waker of [`run`].
+static QUEUED: Mutex<Option<Log>> = Mutex::new(None);
+static QUEUED_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Start recording the console's keyboard input, dropping any earlier
+/// recording.
+pub fn record() {
+    RECORDER.start();
+}
+
+/// Stop recording, and cut a replay short.
+pub fn stop() {
+    RECORDER.stop();
+    REPLAYING.store(false, Ordering::Release);
+}
+
+/// Return whether keyboard input is being recorded.
+pub fn is_recording() -> bool {
+    RECORDER.active.load(Ordering::Acquire)
+}
+
+/// Return whether a log is being replayed, with the keyboard disconnected.
+pub fn is_replaying() -> bool {
+    REPLAYING.load(Ordering::Acquire)
+}
+
+/// Return what has been recorded so far.
+pub fn recorded() -> Log {
+    RECORDER.log()
+}
+
+/// Have [`run`] replay `log`, after any replay in progress.
+pub fn queue(log: Log) {
+    *QUEUED.lock() = Some(log);
+    QUEUED_WAKER.wake();
+}
+
+/// Read the log in `path`: a ramdisk file, or an in-memory one.
+pub fn load(path: &str) -> Result<Log, String> {
+    let text = match fs::root().and_then(|root| root.open(path)) {
+        Some(file) => String::from_utf8_lossy(file.as_slice()).into_owned(),
+        None => match fs::mem::get(path) {
+            Some(data) => String::from_utf8_lossy(&data).into_owned(),
+            None => return Err(alloc::format!("{}: no such file", path)),
+        },
+    };
+    Log::parse(&text).map_err(|err| alloc::format!("{}: {}", path, err))
+}
+
+/// Send the entries of `log` to `target` at their tick offsets from now.
+/// Returns early if `playing` is cleared.
+async fn play(target: &ScancodeInput, log: &Log, playing: &AtomicBool) {
+    let start = ticks();
+    for entry in &log.entries {
+        if !playing.load(Ordering::Acquire) {
+            return;
+        }
+        let due = start + entry.tick;
+        let now = ticks();
+        if due > now {
+            timer::sleep_ticks(due - now).await;
+        }
+        target.send(entry.input);
+    }
+}
+
+/// Resolves to the next log passed to [`queue`].
+struct Queued;
+
+impl Future for Queued {
+    type Output = Log;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Log> {
+        QUEUED_WAKER.register(cx.waker());
+        match QUEUED.lock().take() {
+            Some(log) => Poll::Ready(log),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Act on the `replay` command-line option, then replay each log passed
+/// to [`queue`], forever. Spawn it once.
+pub async fn run() {
+    match cmdline::get_str("replay") {
+        Some("record") => record(),
+        Some(path) => match load(path) {
+            Ok(log) => queue(log),
+            Err(err) => println!("replay: {}", err),
+        },
+        None => {}
+    }
+    loop {
+        let log = Queued.await;
+        println!("replay: {} inputs", log.entries.len());
+        REPLAYING.store(true, Ordering::Release);
+        play(&SCANCODES, &log, &REPLAYING).await;
+        REPLAYING.store(false, Ordering::Release);
+    }
+}
+
+#[test_case]
+fn test_log_round_trips_through_text() {
+    let log = Log {
+        entries: alloc::vec![
+            Entry { seq: 0, tick: 0, input: Input::Scancode(0x1e) },
+            Entry { seq: 1, tick: 0x2a, input: Input::Reset },
+            Entry { seq: 2, tick: 0x2b, input: Input::Scancode(0x9e) },
+        ],
+    };
+    let text = alloc::format!("{}", log);
+    assert_eq!(text, "chronos input log 1\n0 0 1e\n1 2a --\n2 2b 9e\n");
+    assert_eq!(Log::parse(&text), Ok(log));
+    assert_eq!(Log::parse("0 0 1e\n"), Err(ParseError::Header));
+    assert_eq!(Log::parse("chronos input log 1\n0 0 1e 9e\n"), Err(ParseError::Line(2)));
+}
+
+#[test_case]
+fn test_replay_matches_recording() {
+    use crate::task::line_edit::{Echo, LineEditor};
+    use super::{InputEvent, KeyStream, ScancodeSet, ScancodeStream, OVERRUN};
+    use crate::task::blocking::block_on;
+    use crate::task::test_util::counting_waker;
+    use core::sync::atomic::AtomicUsize;
+    use futures_util::stream::Stream;
+
+    struct NoEcho;
+
+    impl Echo for NoEcho {
+        fn redraw(&mut self, _line: &str) {}
+        fn submit(&mut self) {}
+    }
+
+    static LIVE: ScancodeInput = ScancodeInput::new();
+    static REPLAYED: ScancodeInput = ScancodeInput::new();
+    static TEST_RECORDER: Recorder = Recorder::new();
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    let mut drain = |keys: &mut KeyStream, events: &mut Vec<InputEvent>| {
+        while let Poll::Ready(Some(event)) = Pin::new(&mut *keys).poll_next(&mut cx) {
+            events.push(event);
+        }
+    };
+
+    // "ls", Enter, Shift+e "cho", an overrun, then " hi" and Enter, in set 1.
+    let first = [0x26, 0xa6, 0x1f, 0x9f, 0x1c, 0x9c, 0x2a, 0x12, 0x92, 0xaa, 0x2e, 0xae];
+    let second = [0x23, 0xa3, 0x18, 0x98, 0x39, 0xb9, 0x23, 0xa3, 0x17, 0x97, 0x1c, 0x9c];
+    let stream = ScancodeStream::with_recorder(&LIVE, &TEST_RECORDER);
+    let mut live = KeyStream::with_set(stream, ScancodeSet::Set1);
+    let mut live_events = Vec::new();
+    TEST_RECORDER.start();
+    for byte in first {
+        LIVE.receive(byte);
+    }
+    drain(&mut live, &mut live_events);
+    let tick = ticks();
+    while ticks() < tick + 2 {
+        x86_64::instructions::hlt();
+    }
+    LIVE.receive(OVERRUN);
+    for byte in second {
+        LIVE.receive(byte);
+    }
+    drain(&mut live, &mut live_events);
+    TEST_RECORDER.stop();
+
+    let log = Log::parse(&alloc::format!("{}", TEST_RECORDER.log())).unwrap();
+    assert_eq!(log.entries.len(), first.len() + second.len() + 1);
+    assert!(log.entries.last().unwrap().tick >= 2);
+
+    let stream = ScancodeStream::with_input(&REPLAYED);
+    let mut replayed = KeyStream::with_set(stream, ScancodeSet::Set1);
+    let playing = AtomicBool::new(true);
+    let start = ticks();
+    block_on(play(&REPLAYED, &log, &playing));
+    assert!(ticks() - start >= log.entries.last().unwrap().tick);
+    let mut replayed_events = Vec::new();
+    drain(&mut replayed, &mut replayed_events);
+    assert_eq!(replayed_events, live_events);
+
+    let history = |events: &[InputEvent]| -> Vec<String> {
+        let mut editor = LineEditor::new();
+        events.iter().filter_map(|&event| editor.feed(event, &mut NoEcho)).collect()
+    };
+    assert_eq!(history(&live_events), ["ls", "Echo hi"]);
+    assert_eq!(history(&replayed_events), history(&live_events));
+}
@@ -0,0 +1,589 @@
+//! Line editing for console input.
+//!
+//! [`Lines`] turns a stream of [`InputEvent`]s into a stream of completed
+//! lines. A [`LineEditor`] keeps the line being typed and supports
+//! backspace, Ctrl+U (kill the line) and Ctrl+W (delete the last word);
+//! every edit is shown through an [`Echo`], which for the console is
+//! [`VgaEcho`].
+//!
+//! The editor remembers the last [`HISTORY_SIZE`] lines submitted, skipping
+//! a line that repeats the one before. Up and Down step through them; a
+//! recalled line is a copy, so editing it leaves the history alone until
+//! it is submitted as a new line. Tab asks a [`Completer`] for the ways to
+//! finish the word before the cursor and cycles through them on repeated
+//! presses, back to what was typed.
+//!
+//! `Lines` drains every key that is already available before returning
+//! `Pending`, so a burst of input (such as a paste) is never cut short while
+//! it sits in the source's queue.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+
+use super::keyboard::{InputEvent, KeyAction};
+use crate::collections::FixedRing;
+use crate::vga_buffer::vt;
+
+/// Maximum length of a line in bytes; further input is ignored.
+pub const LINE_CAPACITY: usize = 256;
+
+/// Number of submitted lines the editor remembers.
+pub const HISTORY_SIZE: usize = 32;
+
+/// Backspace and delete both remove the last character.
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+/// Ctrl+U and Ctrl+W, as decoded with `HandleControl::MapLettersToUnicode`.
+const KILL_LINE: char = '\u{15}';
+const KILL_WORD: char = '\u{17}';
+const TAB: char = '\t';
+
+/// Return the ways to finish the word of `line` that starts at `start`,
+/// each as the full replacement for that word.
+pub type Completer = fn(line: &str, start: usize) -> Vec<String>;
+
+/// Where a [`LineEditor`] shows its edits.
+pub trait Echo {
+    /// Show `line` as the new contents of the line being edited.
+    fn redraw(&mut self, line: &str);
+
+    /// The line was submitted; move on to a fresh one.
+    fn submit(&mut self);
+}
+
+/// Lines submitted before, oldest first.
+struct History {
+    lines: FixedRing<String, HISTORY_SIZE>,
+}
+
+impl History {
+    fn get(&mut self, index: usize) -> Option<&String> {
+        let (older, newer) = self.lines.as_slices();
+        older.get(index).or_else(|| newer.get(index.checked_sub(older.len())?))
+    }
+
+    fn push(&mut self, line: &str) {
+        let last = self.lines.len().checked_sub(1);
+        let repeat = last.and_then(|last| self.get(last)).is_some_and(|last| last == line);
+        if line.is_empty() || repeat {
+            return;
+        }
+        self.lines.push_overwrite(line.into());
+    }
+}
+
+/// Where Up and Down have got to in the history.
+struct Recall {
+    index: usize,
+    /// The line being typed before the first Up.
+    draft: String,
+}
+
+/// Candidates of the completion that repeated Tabs are cycling through.
+struct Completion {
+    /// Where the completed word starts.
+    start: usize,
+    /// The candidates, then the word as typed.
+    choices: Vec<String>,
+    /// The choice shown.
+    shown: usize,
+}
+
+/// An editable line buffer fed one key at a time.
+pub struct LineEditor {
+    line: String,
+    history: History,
+    recall: Option<Recall>,
+    completer: Option<Completer>,
+    completion: Option<Completion>,
+}
+
+impl LineEditor {
+    /// Create an editor with an empty line.
+    pub fn new() -> Self {
+        LineEditor {
+            line: String::with_capacity(LINE_CAPACITY),
+            history: History { lines: FixedRing::new() },
+            recall: None,
+            completer: None,
+            completion: None,
+        }
+    }
+
+    /// Complete words with `completer` when Tab is pressed.
+    pub fn with_completer(mut self, completer: Completer) -> Self {
+        self.completer = Some(completer);
+        self
+    }
+
+    /// Return the line typed so far.
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Apply `key` to the line and echo the result.
+    ///
+    /// Returns the finished line when `key` is Enter. Keys that don't change
+    /// the line (other named keys and chords, unknown control characters,
+    /// input past [`LINE_CAPACITY`]) are ignored without echoing.
+    pub fn feed(&mut self, key: InputEvent, echo: &mut impl Echo) -> Option<String> {
+        let completion = self.completion.take();
+        let character = match key {
+            InputEvent::Char(character) => character,
+            InputEvent::Key(KeyAction::Up) => {
+                self.recall_older()?;
+                echo.redraw(&self.line);
+                return None;
+            }
+            InputEvent::Key(KeyAction::Down) => {
+                self.recall_newer()?;
+                echo.redraw(&self.line);
+                return None;
+            }
+            InputEvent::Key(_) | InputEvent::Chord(..) => return None,
+        };
+        match character {
+            '\n' | '\r' => {
+                echo.submit();
+                self.recall = None;
+                self.history.push(&self.line);
+                let empty = String::with_capacity(LINE_CAPACITY);
+                return Some(mem::replace(&mut self.line, empty));
+            }
+            TAB => self.complete(completion)?,
+            BACKSPACE | DELETE => {
+                self.line.pop()?;
+            }
+            KILL_LINE if !self.line.is_empty() => self.line.clear(),
+            KILL_WORD => {
+                let start = word_start(&self.line);
+                if start == self.line.len() {
+                    return None;
+                }
+                self.line.truncate(start);
+            }
+            ' '..='~' if self.line.len() < LINE_CAPACITY => self.line.push(character),
+            _ => return None,
+        }
+        echo.redraw(&self.line);
+        None
+    }
+
+    /// Replace the line with the next older history entry.
+    fn recall_older(&mut self) -> Option<()> {
+        let index = match &self.recall {
+            Some(recall) => recall.index.checked_sub(1)?,
+            None => self.history.lines.len().checked_sub(1)?,
+        };
+        let entry = self.history.get(index)?.clone();
+        let draft = mem::replace(&mut self.line, entry);
+        match &mut self.recall {
+            Some(recall) => recall.index = index,
+            None => self.recall = Some(Recall { index, draft }),
+        }
+        Some(())
+    }
+
+    /// Replace the line with the next newer history entry, or with the line
+    /// that was being typed once past the newest.
+    fn recall_newer(&mut self) -> Option<()> {
+        let recall = self.recall.as_mut()?;
+        recall.index += 1;
+        match self.history.get(recall.index) {
+            Some(entry) => self.line = entry.clone(),
+            None => self.line = self.recall.take()?.draft,
+        }
+        Some(())
+    }
+
+    /// Complete the last word, or show the next choice if the previous key
+    /// was also Tab.
+    fn complete(&mut self, previous: Option<Completion>) -> Option<()> {
+        let completion = match previous {
+            Some(mut completion) => {
+                completion.shown = (completion.shown + 1) % completion.choices.len();
+                completion
+            }
+            None => {
+                let start = self.line.rfind(' ').map_or(0, |i| i + 1);
+                let mut choices = (self.completer?)(&self.line, start);
+                choices.retain(|choice| start + choice.len() <= LINE_CAPACITY);
+                if choices.is_empty() {
+                    return None;
+                }
+                // A single choice is taken and the next word begun.
+                if let [choice] = &mut choices[..]
+                    && !choice.ends_with('/')
+                    && start + choice.len() < LINE_CAPACITY
+                {
+                    choice.push(' ');
+                } else {
+                    choices.push(self.line[start..].into());
+                }
+                Completion { start, choices, shown: 0 }
+            }
+        };
+        self.line.truncate(completion.start);
+        self.line.push_str(&completion.choices[completion.shown]);
+        if completion.choices.len() > 1 {
+            self.completion = Some(completion);
+        }
+        Some(())
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Return the index where the last word of `line` starts, counting the
+/// spaces after it as part of the word.
+fn word_start(line: &str) -> usize {
+    line.trim_end_matches(' ').rfind(' ').map_or(0, |i| i + 1)
+}
+
+/// Return the last `width` bytes of `line`, so the end being typed stays
+/// visible. Lines only ever hold printable ASCII.
+fn visible_tail(line: &str, width: usize) -> &str {
+    &line[line.len().saturating_sub(width)..]
+}
+
+/// Echoes the line being edited on the last row of the VGA screen.
+///
+/// The line starts wherever the cursor was at the first edit (usually right
+/// after a prompt). Lines that don't fit scroll horizontally, keeping the
+/// last column free for the cursor.
+#[derive(Default)]
+pub struct VgaEcho {
+    start: Option<usize>,
+}
+
+impl VgaEcho {
+    /// Fewest columns worth editing in; closer to the edge than this, the
+    /// line starts on a fresh row.
+    const MIN_WIDTH: usize = 16;
+
+    /// Create an echo that starts the next line at the cursor.
+    pub fn new() -> Self {
+        VgaEcho { start: None }
+    }
+}
+
+impl Echo for VgaEcho {
+    fn redraw(&mut self, line: &str) {
+        let Some(writer) = vt::current_terminal() else {
+            return;
+        };
+        let mut writer = writer.lock();
+        let width = writer.width();
+        let start = *self.start.get_or_insert_with(|| {
+            if writer.column() + Self::MIN_WIDTH > width {
+                writer.write_byte(b'\n');
+            }
+            writer.column()
+        });
+        writer.set_column(start);
+        writer.write_string(visible_tail(line, width - 1 - start));
+        writer.clear_from_cursor();
+    }
+
+    fn submit(&mut self) {
+        self.start = None;
+        if let Some(writer) = vt::current_terminal() {
+            writer.lock().write_byte(b'\n');
+        }
+    }
+}
+
+/// Stream of lines edited from a stream of keys.
+///
+/// Created by [`keyboard::lines`](super::keyboard::lines) for the console.
+pub struct Lines<K, E> {
+    keys: K,
+    editor: LineEditor,
+    echo: E,
+}
+
+impl<K, E> Lines<K, E>
+where
+    K: Stream<Item = InputEvent> + Unpin,
+    E: Echo + Unpin,
+{
+    /// Edit lines from `keys`, showing the edits through `echo`.
+    pub fn new(keys: K, echo: E) -> Self {
+        Lines {
+            keys,
+            editor: LineEditor::new(),
+            echo,
+        }
+    }
+
+    /// Complete words with `completer` when Tab is pressed.
+    pub fn with_completer(mut self, completer: Completer) -> Self {
+        self.editor = self.editor.with_completer(completer);
+        self
+    }
+}
+
+impl<K, E> Stream for Lines<K, E>
+where
+    K: Stream<Item = InputEvent> + Unpin,
+    E: Echo + Unpin,
+{
+    type Item = String;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<String>> {
+        let this = self.get_mut();
+        loop {
+            match Pin::new(&mut this.keys).poll_next(cx) {
+                Poll::Ready(Some(key)) => {
+                    if let Some(line) = this.editor.feed(key, &mut this.echo) {
+                        return Poll::Ready(Some(line));
+                    }
+                }
+                // A half-typed line is discarded when the input ends.
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Records echo calls instead of drawing them.
+#[cfg(test)]
+#[derive(Default)]
+struct RecordingEcho {
+    calls: alloc::vec::Vec<String>,
+}
+
+#[cfg(test)]
+impl Echo for RecordingEcho {
+    fn redraw(&mut self, line: &str) {
+        self.calls.push(line.into());
+    }
+
+    fn submit(&mut self) {
+        self.calls.push("<submit>".into());
+    }
+}
+
+#[test_case]
+fn test_editing_keys() {
+    use alloc::vec::Vec;
+
+    let mut editor = LineEditor::new();
+    let mut echo = RecordingEcho::default();
+    let keys = "ls /binx\u{8}\u{17}usr\u{15}ps  -a\u{17}\u{17}echo hi\n";
+    let mut lines = keys
+        .chars()
+        .filter_map(|c| editor.feed(InputEvent::Char(c), &mut echo));
+
+    assert_eq!(lines.next().as_deref(), Some("echo hi"));
+    assert_eq!(lines.next(), None);
+    let calls: Vec<&str> = echo.calls.iter().map(String::as_str).collect();
+    assert_eq!(calls[7], "ls /binx");
+    assert_eq!(calls[8], "ls /bin");
+    // Ctrl+W deletes back to the start of the word.
+    assert_eq!(calls[9], "ls ");
+    assert_eq!(calls[12], "ls usr");
+    // Ctrl+U kills the whole line.
+    assert_eq!(calls[13], "");
+    assert_eq!(calls[19], "ps  -a");
+    assert_eq!(calls[20], "ps  ");
+    // A word and the spaces after it go together.
+    assert_eq!(calls[21], "");
+    assert_eq!(calls.last(), Some(&"<submit>"));
+    assert_eq!(editor.line(), "");
+}
+
+#[test_case]
+fn test_ignored_keys_do_not_echo() {
+    let mut editor = LineEditor::new();
+    let mut echo = RecordingEcho::default();
+    for key in [
+        InputEvent::Char(BACKSPACE),
+        InputEvent::Char(KILL_LINE),
+        InputEvent::Char(KILL_WORD),
+        InputEvent::Char('\u{1b}'),
+        InputEvent::Key(KeyAction::Left),
+    ] {
+        assert_eq!(editor.feed(key, &mut echo), None);
+    }
+    assert!(echo.calls.is_empty());
+
+    // Input past the capacity is dropped.
+    for _ in 0..LINE_CAPACITY + 10 {
+        editor.feed(InputEvent::Char('x'), &mut echo);
+    }
+    assert_eq!(editor.line().len(), LINE_CAPACITY);
+    assert_eq!(echo.calls.len(), LINE_CAPACITY);
+}
+
+#[test_case]
+fn test_long_lines_scroll_horizontally() {
+    let line = "0123456789abcdef";
+    assert_eq!(visible_tail(line, 40), line);
+    assert_eq!(visible_tail(line, 6), "abcdef");
+    assert_eq!(visible_tail(line, 0), "");
+}
+
+#[test_case]
+fn test_burst_of_keys_yields_every_line() {
+    use super::channel;
+    use super::test_util::counting_waker;
+    use alloc::format;
+    use core::sync::atomic::AtomicUsize;
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    // A paste that arrives all at once, longer than any single line.
+    let (keys_tx, keys_rx) = channel::<InputEvent>(512);
+    let mut lines = Lines::new(keys_rx, RecordingEcho::default());
+    for i in 0..10 {
+        for c in format!("line number {}\n", i).chars() {
+            keys_tx.try_send(InputEvent::Char(c)).unwrap();
+        }
+    }
+
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+    for i in 0..10 {
+        assert_eq!(
+            Pin::new(&mut lines).poll_next(&mut cx),
+            Poll::Ready(Some(format!("line number {}", i)))
+        );
+    }
+    assert_eq!(Pin::new(&mut lines).poll_next(&mut cx), Poll::Pending);
+    assert_eq!(keys_tx.dropped(), 0);
+    let submits = lines.echo.calls.iter().filter(|c| *c == "<submit>").count();
+    assert_eq!(submits, 10);
+}
+
+#[cfg(test)]
+fn feed_str(editor: &mut LineEditor, echo: &mut RecordingEcho, keys: &str) -> Option<String> {
+    let mut line = None;
+    for c in keys.chars() {
+        line = editor.feed(InputEvent::Char(c), echo).or(line);
+    }
+    line
+}
+
+#[test_case]
+fn test_history_recall() {
+    let up = InputEvent::Key(KeyAction::Up);
+    let down = InputEvent::Key(KeyAction::Down);
+    let mut editor = LineEditor::new();
+    let mut echo = RecordingEcho::default();
+
+    // Nothing to recall yet.
+    assert_eq!(editor.feed(up, &mut echo), None);
+    assert!(echo.calls.is_empty());
+
+    // Blank lines and repeats aren't remembered.
+    for line in ["ls\n", "\n", "mem\n", "mem\n", "uptime\n"] {
+        feed_str(&mut editor, &mut echo, line);
+    }
+    feed_str(&mut editor, &mut echo, "ec");
+    editor.feed(up, &mut echo);
+    assert_eq!(editor.line(), "uptime");
+    editor.feed(up, &mut echo);
+    assert_eq!(editor.line(), "mem");
+    editor.feed(up, &mut echo);
+    assert_eq!(editor.line(), "ls");
+    // At the oldest entry, Up does nothing.
+    let calls = echo.calls.len();
+    editor.feed(up, &mut echo);
+    assert_eq!(echo.calls.len(), calls);
+    editor.feed(down, &mut echo);
+    editor.feed(down, &mut echo);
+    assert_eq!(echo.calls.last().map(String::as_str), Some("uptime"));
+    // Past the newest entry, the line being typed comes back.
+    editor.feed(down, &mut echo);
+    assert_eq!(editor.line(), "ec");
+    assert_eq!(editor.feed(down, &mut echo), None);
+    assert_eq!(feed_str(&mut editor, &mut echo, "ho\n").as_deref(), Some("echo"));
+}
+
+#[test_case]
+fn test_editing_recalled_line_keeps_history() {
+    let up = InputEvent::Key(KeyAction::Up);
+    let mut editor = LineEditor::new();
+    let mut echo = RecordingEcho::default();
+    feed_str(&mut editor, &mut echo, "cat /etc/motd\n");
+
+    editor.feed(up, &mut echo);
+    feed_str(&mut editor, &mut echo, "\u{17}\u{8}");
+    assert_eq!(editor.line(), "cat");
+    editor.feed(InputEvent::Key(KeyAction::Down), &mut echo);
+    editor.feed(up, &mut echo);
+    assert_eq!(editor.line(), "cat /etc/motd");
+
+    // Submitting the edited copy adds it as a new entry.
+    editor.feed(up, &mut echo);
+    assert_eq!(feed_str(&mut editor, &mut echo, "\u{8}\n").as_deref(), Some("cat /etc/mot"));
+    editor.feed(up, &mut echo);
+    assert_eq!(editor.line(), "cat /etc/mot");
+    editor.feed(up, &mut echo);
+    assert_eq!(editor.line(), "cat /etc/motd");
+}
+
+#[test_case]
+fn test_history_keeps_newest() {
+    let mut editor = LineEditor::new();
+    let mut echo = RecordingEcho::default();
+    for i in 0..HISTORY_SIZE + 5 {
+        feed_str(&mut editor, &mut echo, &alloc::format!("line {}\n", i));
+    }
+    for _ in 0..HISTORY_SIZE + 5 {
+        editor.feed(InputEvent::Key(KeyAction::Up), &mut echo);
+    }
+    assert_eq!(editor.line(), "line 5");
+}
+
+#[cfg(test)]
+fn test_completer(line: &str, start: usize) -> Vec<String> {
+    let word = &line[start..];
+    ["help", "hexdump", "halt"]
+        .into_iter()
+        .filter(|name| name.starts_with(word))
+        .map(String::from)
+        .collect()
+}
+
+#[test_case]
+fn test_tab_completion_cycles() {
+    let mut editor = LineEditor::new().with_completer(test_completer);
+    let mut echo = RecordingEcho::default();
+
+    feed_str(&mut editor, &mut echo, "h\t");
+    assert_eq!(editor.line(), "help");
+    feed_str(&mut editor, &mut echo, "\t");
+    assert_eq!(editor.line(), "hexdump");
+    feed_str(&mut editor, &mut echo, "\t\t");
+    // Back to the word as typed, then round again.
+    assert_eq!(editor.line(), "h");
+    feed_str(&mut editor, &mut echo, "\t");
+    assert_eq!(editor.line(), "help");
+
+    // Any other key ends the cycle; a single match is taken with a space.
+    feed_str(&mut editor, &mut echo, "\u{15}he\tx");
+    assert_eq!(editor.line(), "helpx");
+    feed_str(&mut editor, &mut echo, "\u{15}hex\t");
+    assert_eq!(editor.line(), "hexdump ");
+
+    // No match leaves the line alone without echoing.
+    let calls = echo.calls.len();
+    feed_str(&mut editor, &mut echo, "zz\t");
+    assert_eq!(editor.line(), "hexdump zz");
+    assert_eq!(echo.calls.len(), calls + 2);
+
+    // Without a completer, Tab is ignored.
+    let mut plain = LineEditor::new();
+    assert_eq!(feed_str(&mut plain, &mut echo, "h\t\n").as_deref(), Some("h"));
+}
@@ -0,0 +1,357 @@
+//! Containing panics in individual tasks.
+//!
+//! The kernel is built with `panic = "abort"`, so a panic can't unwind back
+//! into the executor. Instead, tasks that opted in through
+//! [`Task::on_panic`](super::Task::on_panic) are polled through
+//! [`poll_contained`], which saves a recovery point (the callee-saved
+//! registers and stack pointer, like `setjmp`) right before the poll, along
+//! with the stack it is on, the CPU and the interrupt depth. The panic
+//! handler calls [`recover`]. If this CPU has a recovery point and the panic
+//! happened in its context, it logs the panic with the task's name,
+//! force-unlocks the kernel's global spinlocks, and jumps back to the
+//! recovery point, which then reports the poll as panicked. The executor
+//! removes the task (and respawns it if asked to) without running its
+//! destructors, since its state may be half-updated.
+//!
+//! A panic elsewhere is left to the panic handler, even while a contained
+//! task is polled. That covers an interrupt handler that interrupted the
+//! task, since the interrupt depth differs, and a double fault, since it
+//! runs on its own stack.
+//!
+//! Every stack frame between the recovery point and the panic is discarded
+//! without being dropped, so anything they owned is leaked. The spinlocks
+//! that are force-unlocked are the ones tasks commonly hold (the output
+//! locks and the timer wheel). Any other [`IrqMutex`](crate::sync::IrqMutex)
+//! the task took and still holds, such as the heap's, may guard
+//! half-updated state, so then the panic isn't contained either; the
+//! [held lock count](crate::sync::locks_held) tells.
+
+use core::arch::asm;
+use core::fmt;
+use core::ops::Range;
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use x86_64::instructions::interrupts;
+
+use super::Task;
+use crate::println;
+use crate::smp::{self, MAX_CPUS};
+use crate::sync;
+
+/// What the executor does when a task panics.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum OnPanic {
+    /// Treat it like any other kernel panic. The default.
+    #[default]
+    Halt,
+    /// Log the panic and drop the task; the rest of the kernel keeps running.
+    Remove,
+    /// Like [`Remove`](Self::Remove), then spawn the task returned by the
+    /// function in its place.
+    Respawn(fn() -> Task),
+}
+
+/// Saved callee-saved registers (rbx, rbp, r12–r15) and stack pointer.
+#[repr(C)]
+struct JmpBuf([u64; 7]);
+
+/// A recovery point for the task currently being polled, and the context
+/// it was set in.
+struct RecoveryPoint {
+    buf: JmpBuf,
+    name: Option<&'static str>,
+    /// The stack the task is polled on, if it is a known one.
+    stack: Option<Range<u64>>,
+    cpu: usize,
+    irq_depth: usize,
+    /// [`sync::locks_held`] before the poll.
+    locks_held: usize,
+}
+
+impl RecoveryPoint {
+    fn new(name: Option<&'static str>) -> Self {
+        let cpu = smp::this_cpu();
+        RecoveryPoint {
+            buf: JmpBuf([0; 7]),
+            name,
+            stack: crate::backtrace::stack_containing(stack_pointer()),
+            cpu: cpu.index,
+            irq_depth: cpu.irq_depth.load(Ordering::Relaxed),
+            locks_held: sync::locks_held(),
+        }
+    }
+
+    /// Return whether code running now, with stack pointer `rsp`, is the
+    /// poll this point was set for, or something it called.
+    fn owns(&self, rsp: u64) -> bool {
+        let cpu = smp::this_cpu();
+        // The poll runs below the saved stack pointer.
+        self.stack.as_ref().is_some_and(|stack| stack.contains(&rsp))
+            && rsp < self.buf.0[6]
+            && cpu.index == self.cpu
+            && cpu.irq_depth.load(Ordering::Relaxed) == self.irq_depth
+    }
+}
+
+/// The innermost active recovery point of each CPU, or null; indexed by
+/// CPU.
+static RECOVERY_POINTS: [AtomicPtr<RecoveryPoint>; MAX_CPUS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+/// Return the calling CPU's slot in [`RECOVERY_POINTS`].
+fn recovery_slot() -> &'static AtomicPtr<RecoveryPoint> {
+    &RECOVERY_POINTS[smp::this_cpu().index]
+}
+
+fn stack_pointer() -> u64 {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+    rsp
+}
+
+/// Number of task panics contained so far.
+static CONTAINED_PANICS: AtomicU64 = AtomicU64::new(0);
+
+/// Return the number of task panics that were contained since boot.
+pub fn contained_panics() -> u64 {
+    CONTAINED_PANICS.load(Ordering::Relaxed)
+}
+
+/// Arguments and result of one contained poll.
+struct PollCall<'a, 'b> {
+    task: &'a mut Task,
+    cx: &'a mut Context<'b>,
+    result: Option<Poll<()>>,
+}
+
+/// Poll `task` with a recovery point in place.
+///
+/// Returns `None` if the poll panicked. In that case interrupts are enabled
+/// again (the executor always polls with interrupts on).
+pub(super) fn poll_contained(task: &mut Task, cx: &mut Context) -> Option<Poll<()>> {
+    extern "C" fn trampoline(data: *mut u8) {
+        let call = unsafe { &mut *(data as *mut PollCall) };
+        call.result = Some(call.task.poll(call.cx));
+    }
+
+    let mut point = RecoveryPoint::new(task.name());
+    let mut call = PollCall {
+        task,
+        cx,
+        result: None,
+    };
+
+    let slot = recovery_slot();
+    let previous = slot.swap(&mut point, Ordering::SeqCst);
+    let panicked = unsafe {
+        call_with_recovery(
+            &mut point.buf,
+            trampoline,
+            &mut call as *mut PollCall as *mut u8,
+        )
+    };
+    slot.store(previous, Ordering::SeqCst);
+
+    if panicked != 0 {
+        interrupts::enable();
+        return None;
+    }
+    call.result
+}
+
+/// Try to contain a panic by jumping back into the executor.
+///
+/// Called first thing from the panic handlers. Returns if no contained task
+/// is being polled on this CPU, if the panic happened outside that poll, or
+/// if the task holds a lock that can't be released safely. The caller
+/// should then handle the panic as usual.
+pub fn recover(info: &PanicInfo) {
+    interrupts::disable();
+    let slot = recovery_slot();
+    let point = slot.load(Ordering::SeqCst);
+    // SAFETY: a point stays valid while it is in the slot; `poll_contained`
+    // takes it out before returning.
+    if point.is_null() || !unsafe { &*point }.owns(stack_pointer()) {
+        return;
+    }
+    let point = unsafe { &*point };
+
+    // The task's frames are abandoned either way: contained, or halted in
+    // the panic handler.
+    let released = unsafe { force_unlock_kernel_locks() };
+    let taken = sync::locks_held().saturating_sub(point.locks_held);
+    if taken > released {
+        return;
+    }
+    smp::this_cpu().locks_held.store(point.locks_held, Ordering::Relaxed);
+    slot.store(ptr::null_mut(), Ordering::SeqCst);
+    CONTAINED_PANICS.fetch_add(1, Ordering::Relaxed);
+    println!("task {} panicked: {}", TaskName(point.name), info);
+    unsafe { jump_to_recovery(&point.buf) }
+}
+
+/// Release spinlocks a panicking task may have been holding, and return
+/// how many [`IrqMutex`](crate::sync::IrqMutex)es that were held it
+/// released.
+///
+/// # Safety
+///
+/// Only call after the panicking task's stack frames have been abandoned,
+/// so no guard for these locks is still in use.
+unsafe fn force_unlock_kernel_locks() -> usize {
+    // Unlocking a free spinlock is a no-op, so there's no need to check.
+    let released = unsafe {
+        super::timer::force_unlock();
+        crate::emergency::take_over()
+    };
+    #[cfg(feature = "lock-order")]
+    crate::sync::lock_order::forget_held();
+    released.len()
+}
+
+/// Displays a task name, or `<unnamed>`.
+struct TaskName(Option<&'static str>);
+
+impl fmt::Display for TaskName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(name) => write!(f, "'{}'", name),
+            None => f.write_str("<unnamed>"),
+        }
+    }
+}
+
+/// Save the recovery state into `buf`, then call `f(data)` and return 0.
+///
+/// If [`jump_to_recovery`] is called with `buf` while `f` runs, this instead
+/// returns 1, with `f`'s frames abandoned.
+#[unsafe(naked)]
+unsafe extern "C" fn call_with_recovery(
+    buf: *mut JmpBuf,
+    f: extern "C" fn(*mut u8),
+    data: *mut u8,
+) -> u64 {
+    core::arch::naked_asm!(
+        "mov [rdi], rbx",
+        "mov [rdi + 8], rbp",
+        "mov [rdi + 16], r12",
+        "mov [rdi + 24], r13",
+        "mov [rdi + 32], r14",
+        "mov [rdi + 40], r15",
+        // rsp points at our return address here
+        "mov [rdi + 48], rsp",
+        // realign the stack for the call
+        "sub rsp, 8",
+        "mov rdi, rdx",
+        "call rsi",
+        "add rsp, 8",
+        "xor eax, eax",
+        "ret",
+    )
+}
+
+/// Restore the state saved in `buf` and return 1 from the matching
+/// [`call_with_recovery`].
+#[unsafe(naked)]
+unsafe extern "C" fn jump_to_recovery(buf: *const JmpBuf) -> ! {
+    core::arch::naked_asm!(
+        "mov rbx, [rdi]",
+        "mov rbp, [rdi + 8]",
+        "mov r12, [rdi + 16]",
+        "mov r13, [rdi + 24]",
+        "mov r14, [rdi + 32]",
+        "mov r15, [rdi + 40]",
+        "mov rsp, [rdi + 48]",
+        "mov eax, 1",
+        "ret",
+    )
+}
+
+#[test_case]
+fn test_panicking_task_is_respawned() {
+    use super::executor::Executor;
+    use core::sync::atomic::AtomicUsize;
+
+    static STARTS: AtomicUsize = AtomicUsize::new(0);
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+    async fn flaky() {
+        super::yield_now().await;
+        if STARTS.fetch_add(1, Ordering::SeqCst) == 0 {
+            panic!("first run always fails");
+        }
+        FINISHED.fetch_add(1, Ordering::SeqCst);
+    }
+    fn make_flaky() -> Task {
+        Task::named("flaky", flaky()).on_panic(OnPanic::Respawn(make_flaky))
+    }
+
+    let contained = contained_panics();
+    let mut executor = Executor::new();
+    executor.spawn(make_flaky());
+    executor.run_until_idle();
+
+    assert_eq!(STARTS.load(Ordering::SeqCst), 2);
+    assert_eq!(FINISHED.load(Ordering::SeqCst), 1);
+    assert_eq!(contained_panics(), contained + 1);
+    assert!(recovery_slot().load(Ordering::SeqCst).is_null());
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_point_owns_only_its_context() {
+    let rsp = stack_pointer();
+    let mut point = RecoveryPoint::new(None);
+    // As if saved by `call_with_recovery` a little further up.
+    point.buf.0[6] = rsp + 512;
+    assert!(point.owns(rsp));
+    assert!(!point.owns(rsp + 1024));
+
+    point.irq_depth += 1;
+    assert!(!point.owns(rsp));
+    point.irq_depth -= 1;
+    point.cpu = MAX_CPUS;
+    assert!(!point.owns(rsp));
+    point.cpu = smp::this_cpu().index;
+    point.stack = None;
+    assert!(!point.owns(rsp));
+}
+
+#[test_case]
+fn test_kernel_stays_usable_after_contained_panic() {
+    use super::executor::{dump_tasks, Executor};
+    use alloc::string::String;
+    use core::sync::atomic::AtomicBool;
+
+    static OTHER_RAN: AtomicBool = AtomicBool::new(false);
+
+    let mut executor = Executor::new();
+    executor.spawn(
+        Task::named("doomed", async {
+            // Die while holding the VGA and serial locks with interrupts off.
+            interrupts::disable();
+            let _writer = crate::vga_buffer::WRITER.get().lock();
+            let _serial = crate::serial::SERIAL1.get().lock();
+            panic!("holding locks");
+        })
+        .on_panic(OnPanic::Remove),
+    );
+    executor.spawn(Task::new(async {
+        OTHER_RAN.store(true, Ordering::SeqCst);
+    }));
+    executor.run_until_idle();
+
+    assert!(OTHER_RAN.load(Ordering::SeqCst));
+    assert!(interrupts::are_enabled());
+    assert!(crate::vga_buffer::WRITER.get().try_lock().is_some());
+    assert!(crate::serial::SERIAL1.get().try_lock().is_some());
+    // Printing would deadlock if the locks were still held.
+    crate::println!("still alive");
+    crate::serial_print!("");
+
+    let mut dump = String::new();
+    dump_tasks(&mut dump).unwrap();
+    assert!(!dump.contains("doomed"));
+}
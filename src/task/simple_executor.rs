@@ -0,0 +1,130 @@
+//! A minimal round-robin executor.
+//!
+//! [`SimpleExecutor`] keeps tasks in a FIFO queue and polls them one after the
+//! other with a waker that does nothing. Pending tasks simply go to the back
+//! of the queue, so the executor busy-polls until every task has finished.
+
+use super::Task;
+use alloc::collections::VecDeque;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+/// Executor that polls its tasks round-robin until all of them complete.
+pub struct SimpleExecutor {
+    task_queue: VecDeque<Task>,
+}
+
+impl SimpleExecutor {
+    /// Create an executor with no tasks.
+    pub fn new() -> SimpleExecutor {
+        SimpleExecutor {
+            task_queue: VecDeque::new(),
+        }
+    }
+
+    /// Queue `task` to be polled by [`run`](Self::run).
+    pub fn spawn(&mut self, task: Task) {
+        self.task_queue.push_back(task)
+    }
+
+    /// Poll queued tasks until every one of them has returned `Ready`.
+    ///
+    /// Returns once the queue is empty.
+    pub fn run(&mut self) {
+        while let Some(mut task) = self.task_queue.pop_front() {
+            let waker = dummy_waker();
+            let mut context = Context::from_waker(&waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {} // task done
+                Poll::Pending => self.task_queue.push_back(task),
+            }
+        }
+    }
+}
+
+impl Default for SimpleExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Build a `RawWaker` whose operations are all no-ops.
+fn dummy_raw_waker() -> RawWaker {
+    fn no_op(_: *const ()) {}
+    fn clone(_: *const ()) -> RawWaker {
+        dummy_raw_waker()
+    }
+
+    let vtable = &RawWakerVTable::new(clone, no_op, no_op, no_op);
+    RawWaker::new(core::ptr::null(), vtable)
+}
+
+/// Build a waker that ignores wake-ups; the executor re-polls regardless.
+fn dummy_waker() -> Waker {
+    unsafe { Waker::from_raw(dummy_raw_waker()) }
+}
+
+/// Future that returns `Pending` a fixed number of times before completing.
+#[cfg(test)]
+struct PendingTimes(usize);
+
+#[cfg(test)]
+impl core::future::Future for PendingTimes {
+    type Output = ();
+
+    fn poll(mut self: core::pin::Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        if self.0 == 0 {
+            Poll::Ready(())
+        } else {
+            self.0 -= 1;
+            Poll::Pending
+        }
+    }
+}
+
+#[test_case]
+fn test_run_completes_all_tasks() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = SimpleExecutor::new();
+    for pending in 0..5 {
+        executor.spawn(Task::new(async move {
+            PendingTimes(pending).await;
+            COMPLETED.fetch_add(1, Ordering::Relaxed);
+        }));
+    }
+    executor.run();
+
+    assert_eq!(COMPLETED.load(Ordering::Relaxed), 5);
+    assert!(executor.task_queue.is_empty());
+}
+
+#[test_case]
+fn test_run_polls_round_robin() {
+    use alloc::rc::Rc;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = SimpleExecutor::new();
+    for id in 0..2 {
+        let order = order.clone();
+        executor.spawn(Task::new(async move {
+            for _ in 0..3 {
+                order.borrow_mut().push(id);
+                PendingTimes(1).await;
+            }
+        }));
+    }
+    executor.run();
+
+    assert_eq!(*order.borrow(), [0, 1, 0, 1, 0, 1]);
+}
+
+#[test_case]
+fn test_task_ids_are_unique() {
+    let a = Task::new(async {});
+    let b = Task::new(async {});
+    assert_ne!(a.id(), b.id());
+}
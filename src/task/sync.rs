@@ -0,0 +1,268 @@
+//! Synchronization primitives for async tasks.
+//!
+//! Spinning on a [`spin::Mutex`] inside a task stalls the whole executor until
+//! the holder (which can't run) lets go. [`AsyncMutex`] parks the waiting task
+//! instead and hands the lock over directly when it is released.
+
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+/// Bookkeeping behind an [`AsyncMutex`].
+struct State {
+    locked: bool,
+    /// Waiting [`Lock`] futures in arrival order, with their latest wakers.
+    waiters: VecDeque<(u64, Waker)>,
+    /// Waiter the lock was handed to on release but that hasn't been polled
+    /// yet. The mutex stays locked on its behalf.
+    handed_to: Option<u64>,
+    next_waiter: u64,
+}
+
+/// A mutex whose [`lock`](Self::lock) waits asynchronously.
+///
+/// Waiters are served in FIFO order: releasing the lock passes ownership to
+/// the longest-waiting task and wakes only that task. The internal state is
+/// guarded by a spinlock that is only held for a few instructions, so this
+/// must not be used from interrupt handlers.
+pub struct AsyncMutex<T> {
+    state: spin::Mutex<State>,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the value is only reachable through a `MutexGuard`, of which at most
+// one exists at a time.
+unsafe impl<T: Send> Send for AsyncMutex<T> {}
+unsafe impl<T: Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    /// Create an unlocked mutex holding `value`.
+    pub const fn new(value: T) -> Self {
+        AsyncMutex {
+            state: spin::Mutex::new(State {
+                locked: false,
+                waiters: VecDeque::new(),
+                handed_to: None,
+                next_waiter: 0,
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Wait until the lock is available and take it.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            waiter: None,
+        }
+    }
+
+    /// Take the lock if nobody holds or is waiting for it.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        Some(MutexGuard { mutex: self })
+    }
+
+    /// Return the number of tasks waiting for the lock.
+    pub fn waiters(&self) -> usize {
+        self.state.lock().waiters.len()
+    }
+
+    /// Release the lock, handing it to the first waiter if there is one.
+    fn unlock(&self) {
+        let mut state = self.state.lock();
+        match state.waiters.pop_front() {
+            Some((id, waker)) => {
+                state.handed_to = Some(id);
+                drop(state);
+                waker.wake();
+            }
+            None => state.locked = false,
+        }
+    }
+}
+
+/// Future returned by [`AsyncMutex::lock`].
+///
+/// Dropping it while waiting gives up its place in the queue.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Lock<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+    /// Our ID once we've joined the wait queue.
+    waiter: Option<u64>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<MutexGuard<'a, T>> {
+        let mutex = self.mutex;
+        let mut state = mutex.state.lock();
+        match self.waiter {
+            None if !state.locked => {
+                state.locked = true;
+                Poll::Ready(MutexGuard { mutex })
+            }
+            None => {
+                let id = state.next_waiter;
+                state.next_waiter += 1;
+                state.waiters.push_back((id, cx.waker().clone()));
+                self.waiter = Some(id);
+                Poll::Pending
+            }
+            Some(id) if state.handed_to == Some(id) => {
+                state.handed_to = None;
+                self.waiter = None;
+                Poll::Ready(MutexGuard { mutex })
+            }
+            Some(id) => {
+                if let Some((_, waker)) = state.waiters.iter_mut().find(|(w, _)| *w == id)
+                    && !waker.will_wake(cx.waker())
+                {
+                    *waker = cx.waker().clone();
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Lock<'_, T> {
+    fn drop(&mut self) {
+        let Some(id) = self.waiter else {
+            return;
+        };
+        let mut state = self.mutex.state.lock();
+        if state.handed_to == Some(id) {
+            // We were given the lock but never took it; pass it on.
+            state.handed_to = None;
+            drop(state);
+            self.mutex.unlock();
+        } else {
+            state.waiters.retain(|(w, _)| *w != id);
+        }
+    }
+}
+
+/// Access to the value of a locked [`AsyncMutex`]; unlocks when dropped.
+pub struct MutexGuard<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+}
+
+// SAFETY: a shared guard only gives out `&T`, so sharing it is sharing
+// `&T`. Without this the guard would be `Sync` whenever the mutex is, that
+// is for any `T: Send`.
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[test_case]
+fn test_contending_tasks_see_consistent_totals() {
+    use super::executor::Executor;
+
+    static COUNTER: AsyncMutex<u64> = AsyncMutex::new(0);
+
+    async fn add(n: u64) {
+        for _ in 0..n {
+            let mut guard = COUNTER.lock().await;
+            let value = *guard;
+            // Let the other task run while holding the lock.
+            super::yield_now().await;
+            *guard = value + 1;
+        }
+    }
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    spawner.spawn(add(50));
+    spawner.spawn(add(50));
+    executor.run_until_idle();
+
+    assert_eq!(*COUNTER.try_lock().unwrap(), 100);
+    assert_eq!(COUNTER.waiters(), 0);
+}
+
+#[test_case]
+fn test_waiters_acquire_in_fifo_order() {
+    use super::test_util::counting_waker;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static WAKES: [AtomicUsize; 3] = [const { AtomicUsize::new(0) }; 3];
+
+    let mutex = AsyncMutex::new(());
+    let guard = mutex.try_lock().unwrap();
+    let mut locks = [mutex.lock(), mutex.lock(), mutex.lock()];
+    let wakers = [0, 1, 2].map(|i| counting_waker(&WAKES[i]));
+    for (lock, waker) in locks.iter_mut().zip(&wakers) {
+        assert!(Pin::new(lock).poll(&mut Context::from_waker(waker)).is_pending());
+    }
+    assert!(mutex.try_lock().is_none());
+
+    // Each release wakes exactly the next waiter in line.
+    drop(guard);
+    for i in 0..3 {
+        for (j, wakes) in WAKES.iter().enumerate() {
+            assert_eq!(wakes.load(Ordering::SeqCst), usize::from(j <= i));
+        }
+        let mut cx = Context::from_waker(&wakers[i]);
+        let Poll::Ready(guard) = Pin::new(&mut locks[i]).poll(&mut cx) else {
+            panic!("waiter {} was not handed the lock", i);
+        };
+        drop(guard);
+    }
+    assert!(mutex.try_lock().is_some());
+}
+
+#[test_case]
+fn test_dropped_waiter_leaves_queue() {
+    use super::test_util::counting_waker;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let mutex = AsyncMutex::new(0);
+    let guard = mutex.try_lock().unwrap();
+    let waker = counting_waker(&WAKES);
+    let mut cx = Context::from_waker(&waker);
+
+    let mut first = mutex.lock();
+    let mut second = mutex.lock();
+    assert!(Pin::new(&mut first).poll(&mut cx).is_pending());
+    assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+    assert_eq!(mutex.waiters(), 2);
+
+    drop(first);
+    assert_eq!(mutex.waiters(), 1);
+
+    // A waiter dropped after being handed the lock passes it on.
+    drop(guard);
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    drop(second);
+    assert_eq!(mutex.waiters(), 0);
+    assert!(mutex.try_lock().is_some());
+}
@@ -0,0 +1,603 @@
+//! Async sleeps and timeouts driven by the timer interrupt.
+//!
+//! Pending timers live in a hierarchical hashed timer wheel: [`LEVELS`]
+//! levels of [`SLOTS`] slots each, where a slot on level `k` spans
+//! `SLOTS^k` ticks. A timer is filed on the lowest level whose range covers
+//! its remaining time; whenever the lower levels wrap around, the matching
+//! slot one level up is cascaded down. Timers further out than the whole
+//! wheel wait on an overflow list that is re-examined when the top level
+//! wraps.
+//!
+//! Each [`TimerFuture`] embeds its own list node, so inserting and cancelling
+//! are O(1) and the wheel never allocates. The future is `!Unpin`: once it
+//! has been polled its node may be linked into the wheel, and dropping it
+//! unlinks the node again.
+//!
+//! Every tick the timer interrupt handler calls [`wake_expired`], which
+//! advances the wheel and wakes expired timers. It only relinks nodes and
+//! wakes wakers by reference, so the interrupt path never allocates or frees.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::future::{poll_fn, Future};
+use core::marker::PhantomPinned;
+use core::pin::{pin, Pin};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::interrupts::ticks;
+use crate::time;
+
+/// Number of wheel levels.
+pub const LEVELS: usize = 4;
+
+/// Number of slots per level.
+pub const SLOTS: usize = 64;
+
+/// `log2(SLOTS)`.
+const SLOT_BITS: u32 = SLOTS.trailing_zeros();
+
+/// Where a node is currently linked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Unlinked,
+    Slot { level: usize, slot: usize },
+    Overflow,
+}
+
+/// Intrusive list node of a pending timer.
+struct Node {
+    /// Tick count at which the timer expires.
+    deadline: u64,
+    prev: *mut Node,
+    next: *mut Node,
+    waker: Option<Waker>,
+    location: Location,
+    /// Set once the wheel has woken `waker`.
+    fired: bool,
+}
+
+impl Node {
+    const fn new(deadline: u64) -> Self {
+        Node {
+            deadline,
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            waker: None,
+            location: Location::Unlinked,
+            fired: false,
+        }
+    }
+}
+
+/// Counters describing the timer wheel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimerStats {
+    /// Timers currently linked into the wheel.
+    pub active: usize,
+    /// Timers moved down a level (or out of the overflow list).
+    pub cascades: u64,
+    /// Timers that expired and were woken.
+    pub fired: u64,
+}
+
+/// The timer wheel. Nodes are owned by their [`TimerFuture`]s.
+struct Wheel {
+    slots: [[*mut Node; SLOTS]; LEVELS],
+    overflow: *mut Node,
+    /// Last tick the wheel has processed.
+    current: u64,
+    stats: TimerStats,
+}
+
+// SAFETY: the wheel is only accessed under the `WHEEL` lock, and the nodes it
+// points to are only touched under that lock as well.
+unsafe impl Send for Wheel {}
+
+impl Wheel {
+    const fn new() -> Self {
+        Wheel {
+            slots: [[ptr::null_mut(); SLOTS]; LEVELS],
+            overflow: ptr::null_mut(),
+            current: 0,
+            stats: TimerStats {
+                active: 0,
+                cascades: 0,
+                fired: 0,
+            },
+        }
+    }
+
+    /// Number of ticks covered by one slot on `level`.
+    fn granularity(level: usize) -> u64 {
+        1 << (SLOT_BITS * level as u32)
+    }
+
+    /// Find the list a node with `deadline` belongs in, relative to
+    /// `self.current`.
+    fn location_for(&self, deadline: u64) -> Location {
+        let delta = deadline - self.current;
+        for level in 0..LEVELS {
+            if delta < Self::granularity(level + 1) {
+                let slot = (deadline >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+                return Location::Slot { level, slot };
+            }
+        }
+        Location::Overflow
+    }
+
+    fn head(&mut self, location: Location) -> &mut *mut Node {
+        match location {
+            Location::Slot { level, slot } => &mut self.slots[level][slot],
+            Location::Overflow => &mut self.overflow,
+            Location::Unlinked => unreachable!("unlinked nodes have no list"),
+        }
+    }
+
+    /// Link `node` at the front of the list for `location`.
+    unsafe fn link(&mut self, node: *mut Node, location: Location) {
+        let head = self.head(location);
+        unsafe {
+            (*node).prev = ptr::null_mut();
+            (*node).next = *head;
+            if !(*head).is_null() {
+                (**head).prev = node;
+            }
+            *head = node;
+            (*node).location = location;
+        }
+    }
+
+    /// Take `node` out of whatever list it is in.
+    unsafe fn unlink(&mut self, node: *mut Node) {
+        unsafe {
+            let location = (*node).location;
+            let (prev, next) = ((*node).prev, (*node).next);
+            if prev.is_null() {
+                *self.head(location) = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+            (*node).prev = ptr::null_mut();
+            (*node).next = ptr::null_mut();
+            (*node).location = Location::Unlinked;
+        }
+    }
+
+    /// Add `node` to the wheel. Returns `false` (without linking it) if its
+    /// deadline has already been processed.
+    unsafe fn insert(&mut self, node: *mut Node, now: u64) -> bool {
+        if self.stats.active == 0 {
+            // With nothing pending the interrupt handler stops advancing the
+            // wheel, so catch up first.
+            self.current = now;
+        }
+        let deadline = unsafe { (*node).deadline };
+        if deadline <= self.current {
+            return false;
+        }
+        let location = self.location_for(deadline);
+        unsafe { self.link(node, location) };
+        self.stats.active += 1;
+        true
+    }
+
+    /// Remove `node` from the wheel if it is linked.
+    unsafe fn remove(&mut self, node: *mut Node) {
+        if unsafe { (*node).location } != Location::Unlinked {
+            unsafe { self.unlink(node) };
+            self.stats.active -= 1;
+        }
+    }
+
+    /// Refile every node of the list at `location` relative to the current
+    /// tick.
+    fn cascade(&mut self, location: Location) {
+        let mut node = core::mem::take(self.head(location));
+        while !node.is_null() {
+            unsafe {
+                let next = (*node).next;
+                let target = self.location_for((*node).deadline);
+                self.link(node, target);
+                if target != location {
+                    self.stats.cascades += 1;
+                }
+                node = next;
+            }
+        }
+    }
+
+    /// Process every tick up to and including `now`, waking expired timers.
+    fn advance(&mut self, now: u64) {
+        while self.current < now {
+            if self.stats.active == 0 {
+                self.current = now;
+                break;
+            }
+            self.current += 1;
+            let tick = self.current;
+
+            // Cascade from the top down so timers can fall through several
+            // levels within the same tick.
+            if tick.is_multiple_of(Self::granularity(LEVELS)) {
+                self.cascade(Location::Overflow);
+            }
+            for level in (1..LEVELS).rev() {
+                if tick.is_multiple_of(Self::granularity(level)) {
+                    let slot = (tick >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+                    self.cascade(Location::Slot { level, slot });
+                }
+            }
+
+            let location = Location::Slot {
+                level: 0,
+                slot: tick as usize % SLOTS,
+            };
+            let mut node = *self.head(location);
+            while !node.is_null() {
+                unsafe {
+                    let next = (*node).next;
+                    debug_assert_eq!((*node).deadline, tick);
+                    self.unlink(node);
+                    self.stats.active -= 1;
+                    self.stats.fired += 1;
+                    (*node).fired = true;
+                    if let Some(waker) = &(*node).waker {
+                        waker.wake_by_ref();
+                    }
+                    node = next;
+                }
+            }
+        }
+    }
+}
+
+/// The global timer wheel.
+///
+/// Always locked with interrupts disabled, since the timer interrupt handler
+/// takes the same lock.
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+
+/// Mirror of the wheel's active count, so the interrupt handler can skip the
+/// lock while no timer is pending.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Advance the wheel to `now`, waking every timer that expired.
+///
+/// Called from the timer interrupt handler; only wakes, never allocates or
+/// frees.
+pub(crate) fn wake_expired(now: u64) {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let mut wheel = WHEEL.lock();
+    wheel.advance(now);
+    ACTIVE.store(wheel.stats.active, Ordering::Relaxed);
+}
+
+/// Return the number of sleeps currently registered with the timer wheel.
+pub fn active_count() -> usize {
+    stats().active
+}
+
+/// Return a snapshot of the timer wheel counters.
+pub fn stats() -> TimerStats {
+    interrupts::without_interrupts(|| WHEEL.lock().stats)
+}
+
+/// Release the timer wheel lock after a contained task panic.
+///
+/// # Safety
+///
+/// See [`recovery`](super::recovery); no guard for the lock may still be in
+/// use.
+pub(super) unsafe fn force_unlock() {
+    unsafe { WHEEL.force_unlock() };
+}
+
+/// Future that completes once the tick counter reaches a deadline.
+///
+/// Created by [`sleep`] and [`sleep_ticks`]. Dropping it before it completes
+/// cancels the sleep: its waker will not be called.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct TimerFuture {
+    /// The wheel node; touched by the interrupt handler while linked.
+    node: UnsafeCell<Node>,
+    _pin: PhantomPinned,
+}
+
+// SAFETY: the node is only accessed with the wheel lock held, apart from its
+// deadline, which never changes.
+unsafe impl Send for TimerFuture {}
+
+impl TimerFuture {
+    fn new(deadline: u64) -> Self {
+        TimerFuture {
+            node: UnsafeCell::new(Node::new(deadline)),
+            _pin: PhantomPinned,
+        }
+    }
+
+    fn deadline(&self) -> u64 {
+        unsafe { (*self.node.get()).deadline }
+    }
+
+    /// Link this future's node into the wheel, or refresh its waker if it
+    /// already is.
+    ///
+    /// Returns `false` if the deadline has already passed.
+    fn register(self: Pin<&mut Self>, waker: &Waker) -> bool {
+        let node = self.node.get();
+        interrupts::without_interrupts(|| {
+            let mut wheel = WHEEL.lock();
+            unsafe {
+                if (*node).fired {
+                    return false;
+                }
+                match &mut (*node).waker {
+                    Some(current) if current.will_wake(waker) => {}
+                    slot => *slot = Some(waker.clone()),
+                }
+                if (*node).location != Location::Unlinked {
+                    return true;
+                }
+                // Pinned, so the node stays put until `drop` unlinks it.
+                let linked = wheel.insert(node, ticks());
+                ACTIVE.store(wheel.stats.active, Ordering::Relaxed);
+                linked
+            }
+        })
+    }
+
+    /// Take this future's node out of the wheel, if it is linked.
+    fn unregister(&self) {
+        let node = self.node.get();
+        interrupts::without_interrupts(|| {
+            let mut wheel = WHEEL.lock();
+            unsafe { wheel.remove(node) };
+            ACTIVE.store(wheel.stats.active, Ordering::Relaxed);
+        });
+    }
+}
+
+impl Future for TimerFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if ticks() >= self.deadline() {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        if self.register(cx.waker()) {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+impl Drop for TimerFuture {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Sleep for at least `duration`.
+///
+/// The duration is rounded up to whole timer ticks (see
+/// [`time::TIMER_HZ`]), plus one tick because the current tick is already
+/// partly over.
+pub fn sleep(duration: Duration) -> TimerFuture {
+    sleep_ticks(time::duration_to_ticks(duration) + 1)
+}
+
+/// Sleep until `n` more timer ticks have elapsed.
+pub fn sleep_ticks(n: u64) -> TimerFuture {
+    TimerFuture::new(ticks() + n)
+}
+
+/// Error returned by [`timeout`] when the deadline passes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+/// Run `future` for at most `duration`.
+///
+/// Returns `Err(Elapsed)` if the sleep finishes first; the inner future is
+/// dropped in that case.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    let mut future = pin!(future);
+    let mut sleep = pin!(sleep(duration));
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Halt until `counter` is non-zero or `max_ticks` ticks have passed.
+#[cfg(test)]
+fn wait_for_wake(counter: &AtomicUsize, max_ticks: u64) {
+    let start = ticks();
+    while counter.load(Ordering::SeqCst) == 0 && ticks() < start + max_ticks {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_sleep_wakes_within_tolerance() {
+    use crate::task::block_on;
+
+    let start = ticks();
+    block_on(sleep(Duration::from_millis(50)));
+    let elapsed = ticks() - start;
+
+    let expected = time::duration_to_ticks(Duration::from_millis(50));
+    assert!(elapsed >= expected && elapsed <= expected + 2, "elapsed {} ticks", elapsed);
+    assert_eq!(active_count(), 0);
+}
+
+#[test_case]
+fn test_sleeps_wake_in_deadline_order() {
+    use crate::task::test_util::counting_waker;
+
+    static WAKES_LATE: AtomicUsize = AtomicUsize::new(0);
+    static WAKES_EARLY: AtomicUsize = AtomicUsize::new(0);
+
+    let late_waker = counting_waker(&WAKES_LATE);
+    let early_waker = counting_waker(&WAKES_EARLY);
+    let mut late = pin!(sleep_ticks(6));
+    let mut early = pin!(sleep_ticks(2));
+
+    // Register the later deadline first.
+    assert!(late.as_mut().poll(&mut Context::from_waker(&late_waker)).is_pending());
+    assert!(early.as_mut().poll(&mut Context::from_waker(&early_waker)).is_pending());
+
+    wait_for_wake(&WAKES_EARLY, 100);
+    assert_eq!(WAKES_EARLY.load(Ordering::SeqCst), 1);
+    assert_eq!(WAKES_LATE.load(Ordering::SeqCst), 0);
+
+    wait_for_wake(&WAKES_LATE, 100);
+    assert_eq!(WAKES_LATE.load(Ordering::SeqCst), 1);
+}
+
+#[test_case]
+fn test_dropped_sleep_never_fires() {
+    use crate::task::test_util::counting_waker;
+    use alloc::boxed::Box;
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    let waker = counting_waker(&WAKES);
+    let mut sleep = Box::pin(sleep_ticks(2));
+    assert!(sleep.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    assert_eq!(active_count(), 1);
+    drop(sleep);
+    assert_eq!(active_count(), 0);
+
+    wait_for_wake(&WAKES, 5);
+    assert_eq!(WAKES.load(Ordering::SeqCst), 0);
+}
+
+#[test_case]
+fn test_timeout() {
+    use crate::task::block_on;
+
+    assert_eq!(block_on(timeout(Duration::from_millis(20), async { 7 })), Ok(7));
+
+    let start = ticks();
+    let never = timeout(Duration::from_millis(20), core::future::pending::<()>());
+    assert_eq!(block_on(never), Err(Elapsed));
+    assert!(ticks() - start >= time::duration_to_ticks(Duration::from_millis(20)));
+    assert_eq!(active_count(), 0);
+}
+
+#[test_case]
+fn test_wheel_stress_fires_on_deadline() {
+    use core::sync::atomic::AtomicU64;
+    use core::task::{RawWaker, RawWakerVTable};
+
+    const TIMERS: usize = 10_000;
+    /// Span of the levels below the top one; later timers start on level 3.
+    const LOW_LEVELS: u64 = (SLOTS * SLOTS * SLOTS) as u64;
+    /// Span of the whole wheel; later timers start on the overflow list.
+    const ALL_LEVELS: u64 = LOW_LEVELS * SLOTS as u64;
+    const START: u64 = 1_000;
+    const END: u64 = START + ALL_LEVELS + LOW_LEVELS;
+    const NEVER: u64 = u64::MAX;
+
+    // Too big for the kernel heap, so the nodes live in a static.
+    static mut NODES: [Node; TIMERS] = [const { Node::new(0) }; TIMERS];
+    static FIRED_AT: [AtomicU64; TIMERS] = [const { AtomicU64::new(NEVER) }; TIMERS];
+    /// Indices of the timers in the order they fired.
+    static ORDER: [AtomicUsize; TIMERS] = [const { AtomicUsize::new(0) }; TIMERS];
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    static NOW: AtomicU64 = AtomicU64::new(0);
+
+    /// Waker that records the simulated tick at which timer `index` fired.
+    fn index_waker(index: usize) -> Waker {
+        fn clone(data: *const ()) -> RawWaker {
+            RawWaker::new(data, &VTABLE)
+        }
+        fn wake(data: *const ()) {
+            FIRED_AT[data as usize].store(NOW.load(Ordering::Relaxed), Ordering::Relaxed);
+            ORDER[FIRED.fetch_add(1, Ordering::Relaxed)].store(data as usize, Ordering::Relaxed);
+        }
+        fn drop(_: *const ()) {}
+
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+        unsafe { Waker::from_raw(RawWaker::new(index as *const (), &VTABLE)) }
+    }
+
+    // xorshift64
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut random = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+
+    // A private wheel driven by a simulated clock. A third of the timers
+    // each start below level 3, on level 3, and on the overflow list.
+    let mut wheel = Wheel::new();
+    let nodes = unsafe { (&raw mut NODES).as_mut() }.unwrap();
+    for (index, node) in nodes.iter_mut().enumerate() {
+        let delay = match index % 3 {
+            0 => 1 + random() % (LOW_LEVELS - 1),
+            1 => LOW_LEVELS + random() % (ALL_LEVELS - LOW_LEVELS),
+            _ => ALL_LEVELS + random() % LOW_LEVELS,
+        };
+        *node = Node::new(START + delay);
+        node.waker = Some(index_waker(index));
+        assert!(unsafe { wheel.insert(node, START) });
+    }
+    assert_eq!(wheel.stats.active, TIMERS);
+    assert!(nodes.iter().any(|node| matches!(node.location, Location::Slot { level: 3, .. })));
+    assert!(nodes.iter().any(|node| node.location == Location::Overflow));
+
+    // Partway through, cancel every seventh timer that is still pending.
+    let cancel_at = START + LOW_LEVELS / 2;
+    for now in START + 1..=END {
+        NOW.store(now, Ordering::Relaxed);
+        wheel.advance(now);
+        if now == cancel_at {
+            for node in nodes.iter_mut().step_by(7) {
+                unsafe { wheel.remove(node) };
+            }
+        }
+    }
+
+    assert_eq!(wheel.stats.active, 0);
+    assert!(wheel.stats.cascades > 0);
+    for (index, node) in nodes.iter().enumerate() {
+        let fired_at = FIRED_AT[index].load(Ordering::Relaxed);
+        if index % 7 == 0 && node.deadline > cancel_at {
+            assert_eq!(fired_at, NEVER, "cancelled timer {} fired", index);
+        } else {
+            assert_eq!(fired_at, node.deadline, "timer {} fired off its deadline", index);
+        }
+    }
+    let fired = FIRED.load(Ordering::Relaxed);
+    let deadline = |n: usize| nodes[ORDER[n].load(Ordering::Relaxed)].deadline;
+    for n in 1..fired {
+        assert!(deadline(n - 1) <= deadline(n), "firing {} is out of deadline order", n);
+    }
+}
@@ -0,0 +1,241 @@
+//! Helpers for tests that need more control than a plain `fn()`.
+//!
+//! Plain `#[test_case]` functions still work as before. Tests that need
+//! per-test settings are declared through [`kernel_test!`](crate::kernel_test),
+//! which wraps the function in a [`KernelTest`] carrying its [`TestFlags`].
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use crate::{serial_print, serial_println, Testable};
+
+/// Whether hardware interrupts stay enabled while a test runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Irqs {
+    /// Run with whatever interrupt state the runner already has (the default).
+    On,
+    /// Run with interrupts disabled; the previous state is restored afterwards.
+    ///
+    /// Useful for timing-sensitive tests that would be perturbed by the timer
+    /// and keyboard IRQs.
+    Off,
+}
+
+/// Per-test settings declared through `kernel_test!`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestFlags {
+    /// Interrupt state used while the test body runs.
+    pub irqs: Irqs,
+}
+
+impl TestFlags {
+    /// Flags used when a test doesn't declare any.
+    pub const DEFAULT: TestFlags = TestFlags { irqs: Irqs::On };
+}
+
+/// A test function plus the flags it was declared with.
+///
+/// Built by `kernel_test!`; not usually constructed by hand.
+pub struct KernelTest {
+    /// Fully qualified test name printed by the runner.
+    pub name: &'static str,
+    /// The test body.
+    pub func: fn(),
+    /// Settings applied by the runner around `func`.
+    pub flags: TestFlags,
+}
+
+impl Testable for KernelTest {
+    fn run(&self) {
+        serial_print!("{}...\t", self.name);
+        match self.flags.irqs {
+            Irqs::On => (self.func)(),
+            Irqs::Off => with_irqs_off(self.func),
+        }
+        serial_println!("[ok]");
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Run `f` with interrupts disabled, restoring the previous state afterwards.
+///
+/// Usable ad hoc inside a test to shield a short timing-sensitive section
+/// without declaring the whole test `irqs: off`.
+pub fn with_irqs_off<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    x86_64::instructions::interrupts::without_interrupts(f)
+}
+
+/// Declare a test with optional flags.
+///
+/// ```ignore
+/// kernel_test!(fn plain() { ... });
+/// kernel_test!(irqs: off, fn timing_sensitive() { ... });
+/// ```
+///
+/// The function is defined as written and registered with the custom test
+/// runner as a [`KernelTest`](crate::test_framework::KernelTest).
+#[macro_export]
+macro_rules! kernel_test {
+    (fn $name:ident() $body:block) => {
+        $crate::kernel_test!(@emit $name, $crate::test_framework::TestFlags::DEFAULT, $body);
+    };
+    (irqs: $irqs:ident, fn $name:ident() $body:block) => {
+        $crate::kernel_test!(
+            @emit $name,
+            $crate::test_framework::TestFlags { irqs: $crate::kernel_test!(@irqs $irqs) },
+            $body
+        );
+    };
+    (@irqs on) => { $crate::test_framework::Irqs::On };
+    (@irqs off) => { $crate::test_framework::Irqs::Off };
+    (@emit $name:ident, $flags:expr, $body:block) => {
+        #[cfg(test)]
+        fn $name() $body
+
+        // The module shares the test's name so the registered static lives
+        // next to the function without needing a generated identifier.
+        #[cfg(test)]
+        mod $name {
+            #[test_case]
+            static TEST: $crate::test_framework::KernelTest =
+                $crate::test_framework::KernelTest {
+                    name: concat!(module_path!(), "::", stringify!($name)),
+                    func: super::$name,
+                    flags: $flags,
+                };
+        }
+    };
+}
+
+/// Return whether `message` formats to exactly `expected`.
+///
+/// Panic handlers in integration tests use this to check
+/// [`PanicInfo::message`](core::panic::PanicInfo::message) without a heap.
+pub fn message_is(message: impl fmt::Display, expected: &str) -> bool {
+    let mut matches = Matches { rest: expected, ok: true };
+    let _ = write!(matches, "{}", message);
+    matches.ok && matches.rest.is_empty()
+}
+
+/// Compares what is written against a string, piece by piece.
+struct Matches<'a> {
+    rest: &'a str,
+    ok: bool,
+}
+
+impl Write for Matches<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self.rest.strip_prefix(s) {
+            Some(rest) if self.ok => self.rest = rest,
+            _ => self.ok = false,
+        }
+        Ok(())
+    }
+}
+
+/// Most bytes [`record_serial`] keeps.
+pub const SERIAL_RECORD_SIZE: usize = 4096;
+
+/// Bytes sent to COM1 while recording, as far as they fit.
+static SENT: [AtomicU8; SERIAL_RECORD_SIZE] = [const { AtomicU8::new(0) }; SERIAL_RECORD_SIZE];
+static SENT_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Start or stop recording every byte sent to COM1, for tests that check
+/// what the host got. Starting drops what an earlier recording kept.
+/// Recording takes no lock, so it goes on through panics, and through
+/// [emergency](crate::emergency) output.
+pub fn record_serial(on: bool) {
+    if on {
+        SENT_LEN.store(0, Ordering::Relaxed);
+    }
+    crate::serial::set_tap(if on { Some(record) } else { None });
+}
+
+fn record(bytes: &[u8]) {
+    for &byte in bytes {
+        let at = SENT_LEN.fetch_add(1, Ordering::Relaxed);
+        if let Some(slot) = SENT.get(at) {
+            slot.store(byte, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Return whether `text` was sent to COM1 while [recording](record_serial).
+pub fn serial_sent(text: &str) -> bool {
+    let len = SENT_LEN.load(Ordering::Relaxed).min(SERIAL_RECORD_SIZE);
+    let mut bytes = [0; SERIAL_RECORD_SIZE];
+    for (byte, slot) in bytes.iter_mut().zip(&SENT[..len]) {
+        *byte = slot.load(Ordering::Relaxed);
+    }
+    bytes[..len].windows(text.len()).any(|window| window == text.as_bytes())
+}
+
+/// Busy-wait for roughly `iterations` loop turns without relying on the timer.
+#[cfg(test)]
+fn spin_for(iterations: u64) {
+    for _ in 0..iterations {
+        core::hint::spin_loop();
+    }
+}
+
+crate::kernel_test!(irqs: off, fn irqs_off_test_freezes_ticks() {
+    use crate::interrupts;
+
+    assert!(!x86_64::instructions::interrupts::are_enabled());
+    let start = interrupts::ticks();
+    spin_for(20_000_000);
+    assert_eq!(interrupts::ticks(), start);
+});
+
+crate::kernel_test!(fn ticks_resume_after_irqs_off_test() {
+    use crate::interrupts;
+
+    assert!(x86_64::instructions::interrupts::are_enabled());
+    let start = interrupts::ticks();
+    while interrupts::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+});
+
+#[test_case]
+fn with_irqs_off_restores_previous_state() {
+    use x86_64::instructions::interrupts;
+
+    assert!(interrupts::are_enabled());
+    let inside = with_irqs_off(interrupts::are_enabled);
+    assert!(!inside);
+    assert!(interrupts::are_enabled());
+
+    // Nested use must not re-enable interrupts early.
+    with_irqs_off(|| {
+        with_irqs_off(|| {});
+        assert!(!interrupts::are_enabled());
+    });
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn message_is_compares_whole_messages() {
+    assert!(message_is(format_args!("block {} of {}", 3, 8), "block 3 of 8"));
+    assert!(!message_is(format_args!("block {} of {}", 3, 8), "block 3 of"));
+    assert!(!message_is(format_args!("block {}", 3), "block 3 of 8"));
+    assert!(!message_is(format_args!("x{}y", 1), "xzy"));
+}
+
+#[test_case]
+fn serial_recording_sees_what_is_sent() {
+    record_serial(true);
+    crate::serial_println!("recorded {}", 42);
+    crate::emergency_println!("emergency {}", 43);
+    record_serial(false);
+    crate::serial_println!("not recorded");
+    assert!(serial_sent("recorded 42\n"));
+    assert!(serial_sent("emergency 43\r\n"));
+    assert!(!serial_sent("not recorded"));
+}
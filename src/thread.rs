@@ -0,0 +1,465 @@
+//! Preemptive kernel threads.
+//!
+//! Each thread has its own heap-allocated kernel stack. Switching saves the
+//! callee-saved registers on the outgoing stack, stores its stack pointer in
+//! the thread's [`Context`], and restores the incoming thread the same way
+//! (see [`switch_to`]). The timer interrupt calls [`preempt`] on every tick,
+//! which switches round-robin between runnable threads; [`yield_now`] does the
+//! same voluntarily.
+//!
+//! The code that called [`Thread::spawn`] first (normally the boot path running
+//! the executor) becomes thread 0. An idle thread running
+//! [`hlt_loop`](crate::hlt_loop) is only scheduled when nothing else can run.
+//!
+//! Locking rule: the scheduler lock is only ever `try_lock`ed on the switch
+//! path, and nothing allocates or frees while holding it. A thread holding the
+//! lock therefore simply isn't preempted until it lets go, and a preempted
+//! thread holding the allocator lock can't deadlock the switch.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use conquer_once::spin::OnceCell;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use crate::gdt;
+
+/// Maximum number of threads (including the boot and idle threads).
+const MAX_THREADS: usize = 16;
+
+/// Stack size of the idle thread.
+const IDLE_STACK_SIZE: usize = 4096 * 2;
+
+/// Unique identifier of a kernel thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Return the numeric value of this ID.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// Saved CPU state of a thread that is not running.
+///
+/// The callee-saved registers live on the thread's own stack; only the stack
+/// pointer needs to be stored here.
+#[derive(Debug, Default)]
+#[repr(C)]
+struct Context {
+    rsp: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ThreadState {
+    Running,
+    Runnable,
+    Exited,
+}
+
+/// A kernel thread.
+pub struct Thread {
+    id: ThreadId,
+    context: Context,
+    /// Owned kernel stack; `None` for the boot thread, which runs on the
+    /// bootloader-provided stack.
+    stack: Option<Box<[u8]>>,
+    /// Stack to enter the kernel on from user mode, in place of the top of
+    /// `stack`, while the thread runs a user process (see
+    /// [`set_entry_stack`]).
+    entry_stack: Option<VirtAddr>,
+    entry: Option<fn()>,
+    state: ThreadState,
+    is_idle: bool,
+}
+
+impl Thread {
+    /// Spawn a thread that runs `entry` on a fresh stack of `stack_size` bytes.
+    ///
+    /// The thread is scheduled on a following timer tick (or
+    /// [`yield_now`]) and exits when `entry` returns. Panics if the thread
+    /// table is full.
+    pub fn spawn(entry: fn(), stack_size: usize) -> ThreadId {
+        init_scheduler();
+        let thread = Box::new(Thread::new(Some(entry), stack_size, false));
+        let id = thread.id;
+        SCHEDULER
+            .lock()
+            .insert(thread)
+            .unwrap_or_else(|_| panic!("thread table full"));
+        id
+    }
+
+    /// Create a thread whose first switch-in starts [`thread_start`].
+    fn new(entry: Option<fn()>, stack_size: usize, is_idle: bool) -> Thread {
+        let mut stack = vec![0u8; stack_size].into_boxed_slice();
+        let stack_top = (stack.as_mut_ptr() as u64 + stack_size as u64) & !0xf;
+
+        // Initial frame popped by `switch_to`: six callee-saved registers, then
+        // the return address. The extra zero slot above it keeps the ABI
+        // alignment (rsp % 16 == 8 at function entry) for `thread_start`.
+        let frame_top = stack_top - 8;
+        let frame = [0u64, 0, 0, 0, 0, 0, thread_start as *const () as u64, 0];
+        let rsp = frame_top - 7 * 8;
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len());
+        }
+
+        Thread {
+            id: ThreadId::new(),
+            context: Context { rsp },
+            stack: Some(stack),
+            entry_stack: None,
+            entry,
+            state: ThreadState::Runnable,
+            is_idle,
+        }
+    }
+
+    /// Address just past the end of this thread's stack, if it owns one.
+    fn stack_top(&self) -> Option<VirtAddr> {
+        self.stack
+            .as_ref()
+            .map(|stack| VirtAddr::from_ptr(stack.as_ptr()) + stack.len() as u64)
+    }
+}
+
+/// Round-robin scheduler over a fixed table of threads.
+struct Scheduler {
+    threads: [Option<Box<Thread>>; MAX_THREADS],
+    current: usize,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Scheduler {
+            threads: [const { None }; MAX_THREADS],
+            current: 0,
+        }
+    }
+
+    fn is_initialized(&self) -> bool {
+        self.threads[0].is_some()
+    }
+
+    fn insert(&mut self, thread: Box<Thread>) -> Result<(), Box<Thread>> {
+        match self.threads.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(thread);
+                Ok(())
+            }
+            None => Err(thread),
+        }
+    }
+
+    /// Pick the next runnable non-idle thread after the current one, falling
+    /// back to the idle thread.
+    fn pick_next(&self) -> Option<usize> {
+        let candidates = (1..=MAX_THREADS).map(|offset| (self.current + offset) % MAX_THREADS);
+        let mut idle = None;
+        for index in candidates {
+            if let Some(thread) = &self.threads[index] {
+                if thread.state == ThreadState::Exited {
+                    continue;
+                }
+                if thread.is_idle {
+                    idle = Some(index);
+                } else {
+                    return Some(index);
+                }
+            }
+        }
+        idle
+    }
+
+    /// Remove one exited thread other than the current one.
+    fn take_exited(&mut self) -> Option<Box<Thread>> {
+        let current = self.current;
+        self.threads
+            .iter_mut()
+            .enumerate()
+            .filter(|(index, _)| *index != current)
+            .find(|(_, slot)| matches!(slot, Some(thread) if thread.state == ThreadState::Exited))
+            .and_then(|(_, slot)| slot.take())
+    }
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+
+/// ID of thread 0, set once the scheduler is.
+static BOOT_THREAD: OnceCell<ThreadId> = OnceCell::uninit();
+
+/// Register the calling context as thread 0 and create the idle thread.
+fn init_scheduler() {
+    if SCHEDULER.lock().is_initialized() {
+        return;
+    }
+
+    // Allocate before taking the lock (see the module docs).
+    let boot = Box::new(Thread {
+        id: ThreadId::new(),
+        context: Context::default(),
+        stack: None,
+        entry_stack: None,
+        entry: None,
+        state: ThreadState::Running,
+        is_idle: false,
+    });
+    let idle = Box::new(Thread::new(None, IDLE_STACK_SIZE, true));
+
+    let mut scheduler = SCHEDULER.lock();
+    if !scheduler.is_initialized() {
+        BOOT_THREAD.init_once(|| boot.id);
+        scheduler.threads[0] = Some(boot);
+        scheduler.threads[1] = Some(idle);
+        scheduler.current = 0;
+    }
+}
+
+/// Switch to the next runnable thread, if any.
+///
+/// Must be called with interrupts disabled. Does nothing if the scheduler is
+/// busy (some thread holds its lock) or there is nothing else to run.
+fn schedule() {
+    let (old, new) = {
+        let Some(mut scheduler) = SCHEDULER.try_lock() else {
+            return;
+        };
+        if !scheduler.is_initialized() {
+            return;
+        }
+        let current = scheduler.current;
+        let Some(next) = scheduler.pick_next() else {
+            return;
+        };
+        if next == current {
+            return;
+        }
+
+        let next_thread = scheduler.threads[next].as_mut().unwrap();
+        next_thread.state = ThreadState::Running;
+        if let Some(stack_top) = next_thread.entry_stack.or(next_thread.stack_top()) {
+            gdt::set_kernel_stack(stack_top);
+        }
+        let new: *const Context = &next_thread.context;
+
+        let current_thread = scheduler.threads[current].as_mut().unwrap();
+        if current_thread.state == ThreadState::Running {
+            current_thread.state = ThreadState::Runnable;
+        }
+        let old: *mut Context = &mut current_thread.context;
+
+        scheduler.current = next;
+        (old, new)
+    };
+
+    // The threads are boxed, so the contexts stay put after the lock is
+    // released; with interrupts off nothing else can touch them meanwhile.
+    unsafe { switch_to(old, new) };
+}
+
+/// Preemption hook called by the timer interrupt handler after its EOI.
+pub(crate) fn preempt() {
+    schedule();
+}
+
+/// Give up the CPU to the next runnable thread.
+pub fn yield_now() {
+    reap_exited();
+    interrupts::without_interrupts(schedule);
+}
+
+/// Return the ID of the running thread, if threading has been started.
+pub fn current() -> Option<ThreadId> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler.threads[scheduler.current].as_ref().map(|thread| thread.id)
+    })
+}
+
+/// Return the ID of thread 0, the code that started threading, if it has
+/// been started.
+pub fn boot_thread() -> Option<ThreadId> {
+    BOOT_THREAD.try_get().ok().copied()
+}
+
+/// Make the CPU enter the kernel on `stack_top` when the current thread is
+/// interrupted in user mode, or go back to the thread's own stack with
+/// `None`. The setting follows the thread across switches.
+pub(crate) fn set_entry_stack(stack_top: Option<VirtAddr>) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        // Before threads start there's no thread to remember it on, and
+        // nothing else changes the TSS.
+        let own = scheduler.threads[current].as_mut().and_then(|thread| {
+            thread.entry_stack = stack_top;
+            thread.stack_top()
+        });
+        if let Some(stack_top) = stack_top.or(own) {
+            gdt::set_kernel_stack(stack_top);
+        }
+    })
+}
+
+/// Return the address range of the running thread's own stack.
+///
+/// `None` on the boot thread, or if the scheduler is locked (this is used on
+/// panic paths, which must not spin).
+pub(crate) fn current_stack() -> Option<Range<u64>> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.try_lock()?;
+        let thread = scheduler.threads[scheduler.current].as_ref()?;
+        let stack = thread.stack.as_ref()?;
+        let bottom = stack.as_ptr() as u64;
+        Some(bottom..bottom + stack.len() as u64)
+    })
+}
+
+/// Return whether thread `id` exists and hasn't exited yet.
+pub fn is_alive(id: ThreadId) -> bool {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().threads.iter().flatten().any(|thread| {
+            thread.id == id && thread.state != ThreadState::Exited
+        })
+    })
+}
+
+/// Free the stacks of threads that have exited.
+///
+/// Runs in thread context and drops each stack after releasing the scheduler
+/// lock, so the allocator is never entered while the lock is held.
+fn reap_exited() {
+    loop {
+        let dead = interrupts::without_interrupts(|| SCHEDULER.lock().take_exited());
+        match dead {
+            Some(thread) => drop(thread),
+            None => break,
+        }
+    }
+}
+
+/// Mark the current thread as exited and switch away for good.
+fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        if let Some(thread) = scheduler.threads[current].as_mut() {
+            thread.state = ThreadState::Exited;
+        }
+    }
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+/// First code run by every spawned thread.
+///
+/// Reached through the `ret` at the end of [`switch_to`], with interrupts
+/// still disabled from the switch path.
+extern "C" fn thread_start() -> ! {
+    let entry = {
+        let scheduler = SCHEDULER.lock();
+        scheduler.threads[scheduler.current]
+            .as_ref()
+            .and_then(|thread| thread.entry)
+    };
+    interrupts::enable();
+    match entry {
+        Some(entry) => {
+            entry();
+            exit();
+        }
+        None => crate::hlt_loop(),
+    }
+}
+
+/// Save the callee-saved registers and stack pointer into `old`, then resume
+/// the thread whose state is in `new`.
+///
+/// Returns (into the caller of the matching earlier `switch_to`) once some
+/// other thread switches back to `old`.
+#[unsafe(naked)]
+unsafe extern "C" fn switch_to(old: *mut Context, new: *const Context) {
+    core::arch::naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, [rsi]",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
+
+#[test_case]
+fn test_threads_are_preempted() {
+    use core::sync::atomic::{AtomicBool, AtomicU64};
+
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static COUNTER_A: AtomicU64 = AtomicU64::new(0);
+    static COUNTER_B: AtomicU64 = AtomicU64::new(0);
+
+    // Neither thread ever yields; they only make progress if the timer
+    // preempts whoever is running.
+    fn count_a() {
+        while !STOP.load(Ordering::Relaxed) {
+            COUNTER_A.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    fn count_b() {
+        while !STOP.load(Ordering::Relaxed) {
+            COUNTER_B.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let a = Thread::spawn(count_a, 4096 * 4);
+    let b = Thread::spawn(count_b, 4096 * 4);
+    assert_ne!(a, b);
+
+    let start = crate::interrupts::ticks();
+    while (COUNTER_A.load(Ordering::Relaxed) == 0 || COUNTER_B.load(Ordering::Relaxed) == 0)
+        && crate::interrupts::ticks() < start + 100
+    {
+        core::hint::spin_loop();
+    }
+    assert!(COUNTER_A.load(Ordering::Relaxed) > 0);
+    assert!(COUNTER_B.load(Ordering::Relaxed) > 0);
+
+    STOP.store(true, Ordering::Relaxed);
+    while is_alive(a) || is_alive(b) {
+        yield_now();
+    }
+    yield_now();
+    assert_eq!(
+        interrupts::without_interrupts(|| SCHEDULER.lock().threads.iter().flatten().count()),
+        2,
+        "exited threads were not reaped"
+    );
+}
+
+#[test_case]
+fn test_current_thread_is_boot_thread() {
+    let boot = boot_thread().expect("threading not started");
+    assert_eq!(current(), Some(boot));
+    assert!(is_alive(boot));
+    yield_now();
+    assert_eq!(current(), Some(boot));
+}
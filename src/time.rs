@@ -0,0 +1,362 @@
+//! Timekeeping based on the legacy PIT (Programmable Interval Timer).
+//!
+//! The PIT drives IRQ0 and therefore the tick counter in
+//! [`interrupts::ticks`](crate::interrupts::ticks). Out of reset it runs at
+//! roughly 18.2 Hz; [`init_pit`] reprograms channel 0 to [`TIMER_HZ`] so ticks
+//! map onto a known, reasonably fine-grained period.
+//!
+//! Delays shorter than a tick use channel 2 instead: [`pit_oneshot_us`]
+//! counts it down once and polls its output, leaving channel 0 and the
+//! tick rate alone. The CPUs share the channel, so one delay runs at a time.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::arch::port::{Port, PortGroup};
+use crate::sync::IrqMutex;
+
+pub mod wallclock;
+
+/// Input clock of the PIT in Hz.
+pub const PIT_FREQUENCY_HZ: u32 = 1_193_182;
+
+/// Rate at which the timer interrupt fires after [`init_pit`].
+pub const TIMER_HZ: u32 = 100;
+
+/// Reload value programmed into PIT channel 0.
+const PIT_DIVISOR: u16 = (PIT_FREQUENCY_HZ / TIMER_HZ) as u16;
+
+/// The PIT's data and command ports.
+pub struct PitPorts {
+    /// Channel 0 data port (wired to IRQ0).
+    pub channel0: Port<u8>,
+    /// Channel 2 data port (wired to the speaker).
+    pub channel2: Port<u8>,
+    /// Mode/command register.
+    pub command: Port<u8>,
+}
+
+impl PortGroup for PitPorts {
+    const DEVICE: &'static str = "pit";
+    const BASE: u16 = 0x40;
+
+    fn at(base: u16) -> Self {
+        PitPorts {
+            channel0: Port::new(Self::DEVICE, base),
+            channel2: Port::new(Self::DEVICE, base + 2),
+            command: Port::new(Self::DEVICE, base + 3),
+        }
+    }
+}
+
+/// Set once [`init_pit`] has programmed channel 0.
+static PIT_CONFIGURED: AtomicBool = AtomicBool::new(false);
+
+/// Program PIT channel 0 to fire IRQ0 at [`TIMER_HZ`].
+///
+/// Should be called before interrupts are enabled so the first tick already
+/// has the expected period.
+pub fn init_pit() {
+    let pit = PitPorts::standard();
+
+    // channel 0, access mode lobyte/hibyte, mode 3 (square wave), binary
+    unsafe {
+        pit.command.write(0x36);
+        pit.channel0.write((PIT_DIVISOR & 0xff) as u8);
+        pit.channel0.write((PIT_DIVISOR >> 8) as u8);
+    }
+    PIT_CONFIGURED.store(true, Ordering::Relaxed);
+}
+
+/// Return whether [`init_pit`] has run, so ticks come at [`TIMER_HZ`].
+pub fn pit_configured() -> bool {
+    PIT_CONFIGURED.load(Ordering::Relaxed)
+}
+
+/// Block for at least `ms` milliseconds, halting between timer ticks.
+///
+/// Needs ticks at the configured rate: panics if interrupts are disabled
+/// (the CPU would never wake up) and is only accurate after [`init_pit`].
+/// Tasks should await [`task::timer::sleep`](crate::task::timer::sleep)
+/// instead.
+pub fn sleep_ms(ms: u64) {
+    use crate::interrupts::ticks;
+
+    assert!(
+        x86_64::instructions::interrupts::are_enabled(),
+        "sleep_ms with interrupts disabled"
+    );
+    // One extra tick, because the current one is already partly over.
+    let deadline = ticks() + duration_to_ticks(Duration::from_millis(ms)) + 1;
+    while ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// System control port B: channel 2's gate in bit 0, the speaker in bit 1,
+/// and channel 2's output in bit 5 (see [`crate::speaker`]).
+const PORT_B: Port<u8> = Port::new("pit", 0x61);
+const TIMER2_GATE: u8 = 1 << 0;
+const SPEAKER_DATA: u8 = 1 << 1;
+const TIMER2_OUT: u8 = 1 << 5;
+
+/// Longest delay [`pit_oneshot_us`] can count: a full 16-bit count of
+/// channel 2, about 54.9 ms.
+pub const MAX_ONESHOT_US: u32 = (u16::MAX as u64 * 1_000_000 / PIT_FREQUENCY_HZ as u64) as u32;
+
+/// Polls of port B before deciding channel 2 isn't counting. Far more than
+/// a full count takes.
+const ONESHOT_POLL_LIMIT: u32 = 10_000_000;
+
+/// Held while [`pit_oneshot_us`] programs and polls channel 2, so two CPUs'
+/// delays can't reprogram it under each other.
+static ONESHOT: IrqMutex<()> = IrqMutex::named("PIT one-shot", ());
+
+/// Why [`pit_oneshot_us`] couldn't delay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DelayError {
+    /// Longer than [`MAX_ONESHOT_US`].
+    TooLong(u32),
+    /// Channel 2's output never went high.
+    NoOutput,
+}
+
+impl fmt::Display for DelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DelayError::TooLong(us) => {
+                write!(f, "{} us is longer than the {} us a one-shot can count", us, MAX_ONESHOT_US)
+            }
+            DelayError::NoOutput => f.write_str("pit channel 2 did not count down"),
+        }
+    }
+}
+
+/// Return the channel 2 count for `us` microseconds, rounded up.
+fn oneshot_count(us: u32) -> u16 {
+    let count = (us as u64 * PIT_FREQUENCY_HZ as u64).div_ceil(1_000_000);
+    count.clamp(1, u16::MAX as u64) as u16
+}
+
+/// Busy-wait for at least `us` microseconds, up to [`MAX_ONESHOT_US`].
+///
+/// Counts PIT channel 2 down once in mode 0 and polls its output through
+/// port `0x61`, so it works with interrupts off, before the timer runs, and
+/// without the TSC; the resolution is one PIT clock (about 0.84 us).
+/// Channel 2 also drives the speaker: a note playing meanwhile is cut off,
+/// and the speaker gate bits are put back afterwards. Interrupts are off
+/// for the delay, and a delay on another CPU waits for this one.
+pub fn pit_oneshot_us(us: u32) -> Result<(), DelayError> {
+    if us > MAX_ONESHOT_US {
+        return Err(DelayError::TooLong(us));
+    }
+    let count = oneshot_count(us);
+    let _oneshot = ONESHOT.lock();
+    let pit = PitPorts::standard();
+    let gates = TIMER2_GATE | SPEAKER_DATA;
+    let saved = unsafe { PORT_B.read() } & gates;
+    let stopped = unsafe { PORT_B.read() } & !gates;
+    let mut result = Err(DelayError::NoOutput);
+    unsafe {
+        PORT_B.write(stopped);
+        // channel 2, access mode lobyte/hibyte, mode 0 (interrupt on
+        // terminal count), binary. The output goes low until the count
+        // runs out.
+        pit.command.write(0xb0);
+        pit.channel2.write(count as u8);
+        pit.channel2.write((count >> 8) as u8);
+        PORT_B.write(stopped | TIMER2_GATE);
+        for _ in 0..ONESHOT_POLL_LIMIT {
+            if PORT_B.read() & TIMER2_OUT != 0 {
+                result = Ok(());
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        PORT_B.write((PORT_B.read() & !gates) | saved);
+    }
+    result
+}
+
+/// Convert a duration into timer ticks, rounding up.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = duration.as_nanos() * TIMER_HZ as u128;
+    nanos.div_ceil(1_000_000_000) as u64
+}
+
+/// Convert a number of timer ticks into a duration.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks * (1_000_000_000 / TIMER_HZ as u64))
+}
+
+/// Time since the timer interrupt started firing, at tick granularity.
+pub fn uptime() -> Duration {
+    ticks_to_duration(crate::interrupts::ticks())
+}
+
+/// TSC at the most recent timer tick.
+static LAST_TICK_TSC: AtomicU64 = AtomicU64::new(0);
+/// Running average of TSC cycles per tick; 0 until two ticks have passed.
+static CYCLES_PER_TICK: AtomicU64 = AtomicU64::new(0);
+/// Largest value [`monotonic`] has returned, in nanoseconds.
+static MONOTONIC_HIGH_WATER: AtomicU64 = AtomicU64::new(0);
+
+/// Note the TSC at a timer tick, for [`monotonic`]. Called by the timer
+/// handler before it bumps the tick count.
+pub(crate) fn record_tick() {
+    let now = rdtsc();
+    let last = LAST_TICK_TSC.swap(now, Ordering::Relaxed);
+    if last == 0 {
+        return;
+    }
+    let cycles = now.wrapping_sub(last);
+    let average = match CYCLES_PER_TICK.load(Ordering::Relaxed) {
+        0 => cycles,
+        average => average - average / 8 + cycles / 8,
+    };
+    CYCLES_PER_TICK.store(average, Ordering::Relaxed);
+}
+
+/// Return the average TSC cycles per timer tick, once two ticks have been
+/// seen.
+pub fn cycles_per_tick() -> Option<u64> {
+    match CYCLES_PER_TICK.load(Ordering::Relaxed) {
+        0 => None,
+        cycles => Some(cycles),
+    }
+}
+
+/// Time since the timer started, with sub-tick resolution.
+///
+/// Counts ticks like [`uptime`], and interpolates within the current tick
+/// with the TSC. The interpolation never reaches the next tick, and the
+/// result never goes backwards, even if the TSC rate wobbles.
+pub fn monotonic() -> Duration {
+    let period = 1_000_000_000 / TIMER_HZ as u64;
+    // With interrupts off, the tick count and its TSC can't change between
+    // the two loads.
+    let nanos = x86_64::instructions::interrupts::without_interrupts(|| {
+        let ticks = crate::interrupts::ticks();
+        let since_tick = rdtsc().wrapping_sub(LAST_TICK_TSC.load(Ordering::Relaxed));
+        let interpolated = match CYCLES_PER_TICK.load(Ordering::Relaxed) {
+            0 => 0,
+            cycles => (since_tick as u128 * period as u128 / cycles as u128).min(period as u128 - 1),
+        };
+        ticks * period + interpolated as u64
+    });
+    let previous = MONOTONIC_HIGH_WATER.fetch_max(nanos, Ordering::Relaxed);
+    Duration::from_nanos(nanos.max(previous))
+}
+
+/// Return the TSC rate in Hz, as measured against the timer, once two
+/// ticks have been seen.
+pub fn tsc_hz() -> Option<u64> {
+    cycles_per_tick().map(|cycles| cycles * TIMER_HZ as u64)
+}
+
+/// Convert TSC cycles to microseconds at the [measured](tsc_hz) rate.
+pub fn cycles_to_micros(cycles: u64) -> Option<u64> {
+    let hz = tsc_hz()?;
+    Some((u128::from(cycles) * 1_000_000 / u128::from(hz)) as u64)
+}
+
+/// Read the CPU's time-stamp counter.
+///
+/// Its rate is only known once [`tsc_hz`] has measured it; until then,
+/// cycle counts are only good for comparing against each other.
+pub fn rdtsc() -> u64 {
+    // SAFETY: RDTSC is available on every x86_64 CPU.
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Measures elapsed TSC cycles.
+#[derive(Debug, Clone, Copy)]
+pub struct Stopwatch {
+    start: u64,
+}
+
+impl Stopwatch {
+    /// Start measuring now.
+    pub fn start() -> Self {
+        Stopwatch { start: rdtsc() }
+    }
+
+    /// Return the cycles elapsed since [`start`](Self::start).
+    pub fn elapsed_cycles(&self) -> u64 {
+        rdtsc().wrapping_sub(self.start)
+    }
+}
+
+#[test_case]
+fn test_duration_to_ticks_rounds_up() {
+    assert_eq!(duration_to_ticks(Duration::ZERO), 0);
+    assert_eq!(duration_to_ticks(Duration::from_nanos(1)), 1);
+    assert_eq!(duration_to_ticks(Duration::from_millis(10)), 1);
+    assert_eq!(duration_to_ticks(Duration::from_millis(11)), 2);
+    assert_eq!(duration_to_ticks(Duration::from_secs(3)), 3 * TIMER_HZ as u64);
+}
+
+#[test_case]
+fn test_stopwatch_counts_up() {
+    let stopwatch = Stopwatch::start();
+    let first = stopwatch.elapsed_cycles();
+    for _ in 0..1000 {
+        core::hint::spin_loop();
+    }
+    assert!(stopwatch.elapsed_cycles() > first);
+}
+
+#[test_case]
+fn test_ticks_to_duration() {
+    assert_eq!(ticks_to_duration(0), Duration::ZERO);
+    assert_eq!(ticks_to_duration(TIMER_HZ as u64), Duration::from_secs(1));
+    assert_eq!(duration_to_ticks(ticks_to_duration(42)), 42);
+}
+
+#[test_case]
+fn test_sleep_ms_waits_for_ticks() {
+    let start = crate::interrupts::ticks();
+    sleep_ms(20);
+    assert!(crate::interrupts::ticks() >= start + duration_to_ticks(Duration::from_millis(20)));
+}
+
+#[test_case]
+fn test_oneshot_limits() {
+    assert_eq!(MAX_ONESHOT_US, 54_924);
+    assert_eq!(oneshot_count(0), 1);
+    assert_eq!(oneshot_count(1), 2);
+    assert_eq!(oneshot_count(10_000), 11_932);
+    assert_eq!(oneshot_count(MAX_ONESHOT_US), 65_535);
+    assert_eq!(pit_oneshot_us(MAX_ONESHOT_US + 1), Err(DelayError::TooLong(MAX_ONESHOT_US + 1)));
+}
+
+#[test_case]
+fn test_oneshot_agrees_with_ticks() {
+    let gates = unsafe { PORT_B.read() } & (TIMER2_GATE | SPEAKER_DATA);
+    // Each 10 ms one-shot spans one tick, give or take the tick it
+    // started in.
+    let start = crate::interrupts::ticks();
+    for _ in 0..10 {
+        let before = crate::interrupts::ticks();
+        pit_oneshot_us(10_000).unwrap();
+        let ticks = crate::interrupts::ticks() - before;
+        assert!(ticks <= 2, "10 ms one-shot took {} ticks", ticks);
+    }
+    let ticks = crate::interrupts::ticks() - start;
+    assert!(ticks.abs_diff(10) <= 1, "ten 10 ms one-shots took {} ticks", ticks);
+    assert_eq!(unsafe { PORT_B.read() } & (TIMER2_GATE | SPEAKER_DATA), gates);
+}
+
+#[test_case]
+fn test_monotonic_never_goes_back() {
+    let mut last = monotonic();
+    let start = crate::interrupts::ticks();
+    // Across a few ticks, so tick boundaries are crossed.
+    while crate::interrupts::ticks() < start + 3 {
+        let now = monotonic();
+        assert!(now >= last, "{:?} after {:?}", now, last);
+        last = now;
+    }
+    assert!(last >= ticks_to_duration(start + 3) - ticks_to_duration(1));
+}
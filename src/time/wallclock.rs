@@ -0,0 +1,231 @@
+//! Wall-clock time.
+//!
+//! Reading the RTC is slow and only gives whole seconds, so [`now`] doesn't
+//! touch it. Instead, [`init`] and [`resync`] read the RTC once and pair the
+//! reading with [`monotonic`] time. `now` then adds the monotonic time
+//! elapsed since that anchor.
+//!
+//! An RTC reading only says the time is somewhere in the second after it.
+//! [`resync`] leaves the clock alone when it is inside that second. When it
+//! is off by less than [`STEP_THRESHOLD`], the clock is slewed: the error is
+//! worked off at [`SLEW_RATE_PPM`], so the clock never jumps and never runs
+//! backwards. Larger errors are stepped, which can move the clock back.
+//! [`resync_task`] resyncs every [`RESYNC_INTERVAL`].
+
+use core::fmt;
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::monotonic;
+use crate::println;
+use crate::rtc::{self, DateTime, RtcError};
+
+/// How often [`resync_task`] checks the clock against the RTC.
+pub const RESYNC_INTERVAL: Duration = Duration::from_secs(64);
+
+/// Errors at least this large are stepped rather than slewed.
+pub const STEP_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// How fast a slew works off an error, in parts per million of elapsed
+/// time. At 5000 ppm, a one-second error takes 200 seconds.
+pub const SLEW_RATE_PPM: u64 = 5000;
+
+/// A point in wall-clock time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    since_epoch: Duration,
+}
+
+impl Timestamp {
+    /// The timestamp `since_epoch` after 1970-01-01 00:00:00 UTC.
+    pub fn from_unix(since_epoch: Duration) -> Self {
+        Timestamp { since_epoch }
+    }
+
+    /// Time since the Unix epoch.
+    pub fn since_epoch(&self) -> Duration {
+        self.since_epoch
+    }
+
+    /// Whole seconds since the Unix epoch.
+    pub fn to_unix_seconds(&self) -> u64 {
+        self.since_epoch.as_secs()
+    }
+
+    /// The calendar date and time, truncated to the second.
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_unix_seconds(self.to_unix_seconds() as i64)
+    }
+}
+
+/// The date and time to the millisecond, e.g. `2024-02-29 13:05:09.250`.
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:03}", self.date_time(), self.since_epoch.subsec_millis())
+    }
+}
+
+/// What [`resync`] did to the clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Adjustment {
+    /// The clock was within the RTC's second.
+    None,
+    /// The clock is being slewed by this many nanoseconds.
+    Slew { nanos: i64 },
+    /// The clock was stepped by this many nanoseconds.
+    Step { nanos: i64 },
+}
+
+/// Wall-clock time at a monotonic instant, plus an error being slewed away.
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    wall: Duration,
+    monotonic: Duration,
+    /// Nanoseconds still to add (or, if negative, remove) at
+    /// [`SLEW_RATE_PPM`].
+    correction: i64,
+}
+
+impl Anchor {
+    /// The wall-clock time at monotonic time `now`.
+    fn at(&self, now: Duration) -> Duration {
+        let elapsed = now.saturating_sub(self.monotonic);
+        let slewed = (elapsed.as_nanos() * u128::from(SLEW_RATE_PPM) / 1_000_000)
+            .min(u128::from(self.correction.unsigned_abs())) as u64;
+        let slewed = Duration::from_nanos(slewed);
+        if self.correction >= 0 {
+            self.wall + elapsed + slewed
+        } else {
+            (self.wall + elapsed).saturating_sub(slewed)
+        }
+    }
+}
+
+static ANCHOR: Mutex<Option<Anchor>> = Mutex::new(None);
+
+/// Read the RTC and start the clock from it.
+pub fn init() -> Result<(), RtcError> {
+    let date = rtc::now()?;
+    let anchor = Anchor {
+        wall: unix_duration(date),
+        monotonic: monotonic(),
+        correction: 0,
+    };
+    interrupts::without_interrupts(|| *ANCHOR.lock() = Some(anchor));
+    if crate::klog::level() > 0 {
+        println!("clock: {} UTC", date);
+    }
+    Ok(())
+}
+
+fn unix_duration(date: DateTime) -> Duration {
+    Duration::from_secs(date.to_unix_seconds().max(0) as u64)
+}
+
+/// Return the current time, or `None` before [`init`].
+pub fn now() -> Option<Timestamp> {
+    let anchor = interrupts::without_interrupts(|| *ANCHOR.lock())?;
+    Some(Timestamp::from_unix(anchor.at(monotonic())))
+}
+
+/// Like [`now`], but `None` instead of waiting if the clock is being
+/// adjusted. For the serial output path, which must not spin.
+pub(crate) fn try_now() -> Option<Timestamp> {
+    let anchor = interrupts::without_interrupts(|| ANCHOR.try_lock().map(|anchor| *anchor))??;
+    Some(Timestamp::from_unix(anchor.at(monotonic())))
+}
+
+/// Compare the clock against the RTC and correct it.
+pub fn resync() -> Result<Adjustment, RtcError> {
+    let date = rtc::now()?;
+    Ok(adjust(unix_duration(date), monotonic()))
+}
+
+/// Correct the clock given that the RTC read `rtc` at monotonic time
+/// `now`.
+fn adjust(rtc: Duration, now: Duration) -> Adjustment {
+    interrupts::without_interrupts(|| {
+        let mut anchor = ANCHOR.lock();
+        let Some(current) = *anchor else {
+            *anchor = Some(Anchor { wall: rtc, monotonic: now, correction: 0 });
+            return Adjustment::Step { nanos: 0 };
+        };
+        let reported = current.at(now);
+        // The RTC's second runs from `rtc` up to, not including, `rtc + 1s`.
+        let latest = rtc + Duration::from_secs(1) - Duration::from_nanos(1);
+        let error = if reported < rtc {
+            (rtc - reported).as_nanos() as i64
+        } else if reported > latest {
+            -((reported - latest).as_nanos() as i64)
+        } else {
+            0
+        };
+        let (wall, correction, adjustment) = match error {
+            0 => (reported, 0, Adjustment::None),
+            _ if error.unsigned_abs() < STEP_THRESHOLD.as_nanos() as u64 => {
+                (reported, error, Adjustment::Slew { nanos: error })
+            }
+            _ => (rtc, 0, Adjustment::Step { nanos: error }),
+        };
+        *anchor = Some(Anchor { wall, monotonic: now, correction });
+        adjustment
+    })
+}
+
+/// Resync with the RTC every [`RESYNC_INTERVAL`], forever.
+pub async fn resync_task() {
+    loop {
+        crate::task::sleep(RESYNC_INTERVAL).await;
+        match resync() {
+            Ok(Adjustment::Step { nanos }) => println!("clock: stepped by {} ms", nanos / 1_000_000),
+            Ok(_) => {}
+            Err(err) => println!("clock: resync failed: {}", err),
+        }
+    }
+}
+
+#[test_case]
+fn test_timestamp() {
+    let timestamp = Timestamp::from_unix(Duration::from_millis(951_825_600_250));
+    assert_eq!(timestamp.to_unix_seconds(), 951_825_600);
+    assert_eq!(alloc::format!("{}", timestamp), "2000-02-29 12:00:00.250");
+}
+
+#[test_case]
+fn test_slew_stays_monotonic() {
+    let saved = interrupts::without_interrupts(|| *ANCHOR.lock());
+    let base = Duration::from_secs(1_700_000_000);
+    let start = monotonic();
+    interrupts::without_interrupts(|| {
+        *ANCHOR.lock() = Some(Anchor { wall: base, monotonic: start, correction: 0 })
+    });
+
+    // The clock is 1.5s ahead of an RTC second: slew it back.
+    let before = now().unwrap();
+    let reported = before.since_epoch();
+    let rtc = reported - Duration::from_millis(1500);
+    let adjustment = adjust(rtc, monotonic());
+    assert!(matches!(adjustment, Adjustment::Slew { nanos } if nanos < 0 && nanos > -600_000_000));
+    let mut last = now().unwrap();
+    assert!(last >= before);
+    let until = crate::interrupts::ticks() + 3;
+    while crate::interrupts::ticks() < until {
+        let current = now().unwrap();
+        assert!(current >= last);
+        last = current;
+    }
+    // Slewing back still lets time advance, just more slowly.
+    assert!(last > before);
+
+    // Within the RTC's second: nothing to do.
+    let reported = now().unwrap().since_epoch();
+    assert_eq!(adjust(reported - Duration::from_millis(300), monotonic()), Adjustment::None);
+
+    // A large error is stepped.
+    let far = reported + Duration::from_secs(3600);
+    assert!(matches!(adjust(far, monotonic()), Adjustment::Step { nanos } if nanos > 0));
+    assert!(now().unwrap().since_epoch() >= far);
+
+    interrupts::without_interrupts(|| *ANCHOR.lock() = saved);
+}
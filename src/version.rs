@@ -0,0 +1,86 @@
+//! Version and build information, shown in the boot banner.
+
+use core::fmt;
+
+/// Identifies the running kernel build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// Crate name.
+    pub name: &'static str,
+    /// Crate version.
+    pub version: &'static str,
+    /// Short git commit hash, or "unknown".
+    pub git_hash: &'static str,
+    /// When the build script last ran, in UTC, or "unknown".
+    pub build_time: &'static str,
+    /// Heap allocator in use.
+    pub allocator: &'static str,
+    /// Interrupt controller mode.
+    pub interrupt_mode: &'static str,
+}
+
+/// Return information about this kernel build.
+pub fn version_info() -> VersionInfo {
+    VersionInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("CHRONOS_GIT_HASH"),
+        build_time: env!("CHRONOS_BUILD_TIME"),
+        allocator: crate::allocator::ALLOCATOR_NAME,
+        interrupt_mode: crate::interrupts::MODE,
+    }
+}
+
+impl VersionInfo {
+    /// Format a one-line summary, e.g. for a panic screen footer.
+    pub fn short(&self) -> impl fmt::Display + '_ {
+        struct Short<'a>(&'a VersionInfo);
+
+        impl fmt::Display for Short<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{} {} ({})", self.0.name, self.0.version, self.0.git_hash)
+            }
+        }
+
+        Short(self)
+    }
+}
+
+/// The boot banner: two lines, each fitting an 80-column screen.
+impl fmt::Display for VersionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}, built {}", self.short(), self.build_time)?;
+        write!(
+            f,
+            "allocator: {}, interrupts: {}",
+            self.allocator, self.interrupt_mode
+        )
+    }
+}
+
+#[test_case]
+fn test_version_info_fields_are_set() {
+    let info = version_info();
+    for field in [
+        info.name,
+        info.version,
+        info.git_hash,
+        info.build_time,
+        info.allocator,
+        info.interrupt_mode,
+    ] {
+        assert!(!field.is_empty());
+    }
+    assert_eq!(info.name, "chronos");
+}
+
+#[test_case]
+fn test_banner_fits_screen() {
+    use alloc::string::ToString;
+
+    let banner = version_info().to_string();
+    assert_eq!(banner.lines().count(), 2);
+    for line in banner.lines() {
+        assert!(line.len() <= crate::vga_buffer::BUFFER_WIDTH, "too wide: {}", line);
+    }
+}
@@ -4,23 +4,151 @@
 //! text buffer at memory address `0xb8000` in 80x25 text mode.
 //!
 //! It supports:
-//! - Colored text output
+//! - Colored text output, with [`Writer::set_color`] or the
+//!   `print_colored!` / `println_colored!` macros
 //! - Line wrapping and scrolling
 //! - `print!` / `println!` macros similar to the Rust standard library
 //!
 //! All writes to VGA memory are performed using volatile accesses to ensure
 //! the compiler does not optimize them away.
+//!
+//! The writer can [`Render`] straight into VGA memory, where every scroll
+//! reads and rewrites the whole screen, or into a shadow copy in RAM that
+//! scrolls by moving a ring index and copies only the changed rows out at
+//! the end of each write. [`WRITER`] starts buffered; a `write!` to it is
+//! one write however many pieces it formats, and [`Writer::flush`] copies
+//! out anything drawn but not yet shown. [`set_immediate_mode`] switches
+//! to direct rendering for paths, like the panic handler's, that want every
+//! byte on the screen at once, and [`set_render`] picks either.
+//!
+//! [`Writer::write_at`], [`Writer::fill_region`] and [`Writer::draw_box`]
+//! draw text, filled rectangles and line boxes anywhere on the screen,
+//! clipped at its edges, without moving the cursor or scrolling.
+//!
+//! The first or last row can be made a status row with
+//! [`Writer::set_status_row`] and drawn with [`Writer::write_status`], as
+//! the [status bar](crate::statusbar) does with the first. A status row on
+//! the bottom moves the cursor's row up to the one above it.
+//!
+//! Rows at the top can instead be reserved with [`Writer::reserve_rows`],
+//! as the panic screen does: scrolling and clearing leave them alone, and
+//! only those three draw there. The top row belongs to one or the other:
+//! [`Writer::set_status_row`] won't take it while rows are reserved.
+//!
+//! Rows that scroll off the top go into a scrollback of
+//! [`SCROLLBACK_LINES`] rows. [`Writer::scroll_up`] and
+//! [`Writer::scroll_down`] page through it by redrawing the screen from the
+//! scrollback and a saved copy of the live rows. Any output to the
+//! scrolling rows snaps the view back to the bottom first, so new text is
+//! always seen as it arrives; [`Writer::write_at`] in the reserved rows
+//! leaves the view alone.
+//!
+//! The font is code page 437, so [`Writer::write_string`] writes each
+//! character as its [`cp437`] byte: box drawing, arrows, `°` and accented
+//! letters come out right, and characters the font lacks come out as `?`.
+//!
+//! [`Writer::write_string`] also understands the ANSI escape sequences most
+//! terminal output uses: SGR colors (`ESC[31m` and the like, mapped onto
+//! [`Color`]), cursor positioning (`ESC[<row>;<col>H`, counted from the top
+//! of the scrolling rows) and erase display (`ESC[2J`). Other sequences
+//! are swallowed, not printed.
+//!
+//! The blinking hardware cursor follows the writer's cursor. It is moved
+//! once at the end of each write, through the CRT controller's cursor
+//! location registers, and only when it actually changes.
+//!
+//! [`WRITER`] writes to the standard 80x25 buffer at `0xb8000`.
+//! [`Writer::new`] makes a writer of any size up to that for any address,
+//! such as a buffer in RAM, and [`remap`] moves [`WRITER`] to where the
+//! page tables have put its buffer.
+//!
+//! The screen starts in 80x25 text mode; [`set_mode`] switches it to 80x50
+//! and back, resizing every [terminal](vt). See [`mode`](mod@mode).
+//!
+//! There are [`vt::VT_COUNT`] virtual terminals, [`WRITER`] the first of
+//! them. `print!` goes to the one on the screen; see [`vt`].
+//!
+//! [`init`] probes for a VGA adapter first. Without one [`WRITER`] stays
+//! unset, nothing touches `0xb8000`, and the [console](crate::console)
+//! goes to COM1 instead.
 
 use core::fmt;
-use lazy_static::lazy_static;
-use spin::Mutex;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::arch::port::{Port, PortGroup};
+use crate::collections::{FixedRing, FixedString};
+use crate::output::KernelOutput;
+use crate::sync::{Global, GlobalError, IrqMutex};
 use volatile::Volatile;
+use x86_64::VirtAddr;
+
+pub mod cp437;
+pub mod mode;
+pub mod vt;
+
+pub use mode::{mode, set_mode, ModeError, TextMode};
+
+/// Number of text rows in the standard 80x25 text mode.
+pub const BUFFER_HEIGHT: usize = 25;
+
+/// Number of text rows in 80x50 mode, and the most a [`Writer`] can have.
+pub const MAX_BUFFER_HEIGHT: usize = 50;
 
-/// Number of text rows in VGA text mode.
-const BUFFER_HEIGHT: usize = 25;
+/// Number of text columns in VGA text mode, and the most a [`Writer`] can
+/// have.
+pub const BUFFER_WIDTH: usize = 80;
 
-/// Number of text columns in VGA text mode.
-const BUFFER_WIDTH: usize = 80;
+/// Rows the scrollback keeps.
+pub const SCROLLBACK_LINES: usize = 200;
+
+/// Columns between tab stops.
+pub const TAB_WIDTH: usize = 8;
+
+/// Rows the [`panic_screen`] takes at the top; output after it scrolls
+/// below them.
+pub const PANIC_BANNER_ROWS: usize = 12;
+
+/// Most rows the panic message gets on the [`panic_screen`].
+const PANIC_MESSAGE_ROWS: usize = 4;
+
+/// The colors text starts in, and that `ESC[0m` goes back to.
+const DEFAULT_COLOR: ColorCode = ColorCode::new(Color::Yellow, Color::Black);
+
+/// ANSI color numbers 0 to 7, then their bright versions.
+const ANSI_COLORS: [Color; 16] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+    Color::DarkGray,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::Yellow,
+    Color::LightBlue,
+    Color::Pink,
+    Color::LightCyan,
+    Color::White,
+];
+
+/// Most parameters of an escape sequence that are kept; later ones are
+/// dropped.
+const MAX_ESCAPE_PARAMS: usize = 8;
+
+/// How far [`Writer`] is through an ANSI escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    /// Not in a sequence.
+    None,
+    /// After `ESC`.
+    Started,
+    /// After `ESC [`, with the parameters so far; `last` indexes the one
+    /// being read.
+    Csi { params: [u16; MAX_ESCAPE_PARAMS], last: usize },
+}
 
 /// Prints formatted text to the VGA buffer without a trailing newline.
 ///
@@ -40,18 +168,51 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Prints formatted text in the given foreground and background
+/// [`Color`]s, without a trailing newline.
+///
+/// The colors apply to this text on the screen only; other consoles get it
+/// plain.
+#[macro_export]
+macro_rules! print_colored {
+    ($fg:expr, $bg:expr, $($arg:tt)*) => (
+        $crate::vga_buffer::_print_colored($fg, $bg, format_args!($($arg)*))
+    );
+}
+
+/// Prints formatted text in the given colors with a trailing newline, like
+/// `print_colored!`.
+#[macro_export]
+macro_rules! println_colored {
+    ($fg:expr, $bg:expr) => ($crate::print_colored!($fg, $bg, "\n"));
+    ($fg:expr, $bg:expr, $($arg:tt)*) => (
+        $crate::print_colored!($fg, $bg, "{}\n", format_args!($($arg)*))
+    );
+}
+
+/// Clears the screen; see [`clear_screen`](crate::vga_buffer::clear_screen).
+#[macro_export]
+macro_rules! clear {
+    () => ($crate::vga_buffer::clear_screen());
+}
+
 /// Internal print function used by the `print!` and `println!` macros.
 ///
-/// This function acquires the global VGA writer lock and forwards the
-/// formatted output to it.
+/// This function writes the formatted output to the caller's
+/// [console](crate::console::current) with
+/// [`print_to`](crate::console::print_to), which also records it in the
+/// [kernel log](crate::klog). The console is picked once, so output in
+/// progress when the selection changes finishes where it started.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
+    crate::console::print_to(crate::console::current(), args);
+}
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+/// Internal print function used by the `print_colored!` and
+/// `println_colored!` macros.
+#[doc(hidden)]
+pub fn _print_colored(foreground: Color, background: Color, args: fmt::Arguments) {
+    crate::console::print_colored_to(crate::console::current(), foreground, background, args);
 }
 
 /// VGA color values.
@@ -79,19 +240,66 @@ pub enum Color {
     White = 15,
 }
 
+impl Color {
+    /// Returns the ANSI color number, 0 to 7 or a bright 8 to 15, that
+    /// escape sequences name this color by.
+    pub fn ansi(self) -> u8 {
+        ANSI_COLORS.iter().position(|&color| color == self).unwrap_or(0) as u8
+    }
+
+    /// Returns the color with palette index `value`, of which only the low
+    /// four bits count.
+    pub fn from_u8(value: u8) -> Color {
+        const ALL: [Color; 16] = [
+            Color::Black,
+            Color::Blue,
+            Color::Green,
+            Color::Cyan,
+            Color::Red,
+            Color::Magenta,
+            Color::Brown,
+            Color::LightGray,
+            Color::DarkGray,
+            Color::LightBlue,
+            Color::LightGreen,
+            Color::LightCyan,
+            Color::LightRed,
+            Color::Pink,
+            Color::Yellow,
+            Color::White,
+        ];
+        ALL[usize::from(value & 0xf)]
+    }
+}
+
 /// A packed VGA color code combining foreground and background colors.
 ///
 /// The lower 4 bits represent the foreground color, and the upper 4 bits
 /// represent the background color.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
     /// Creates a new `ColorCode` from a foreground and background color.
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    pub const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    /// Returns the packed byte, as VGA memory holds it.
+    pub fn as_u8(self) -> u8 {
+        self.0
+    }
+
+    /// Returns the foreground color.
+    pub fn foreground(self) -> Color {
+        Color::from_u8(self.0)
+    }
+
+    /// Returns the background color.
+    pub fn background(self) -> Color {
+        Color::from_u8(self.0 >> 4)
+    }
 }
 
 /// A single character in the VGA text buffer.
@@ -104,64 +312,586 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-/// Representation of the VGA text buffer.
+/// A copy of everything on a [`Writer`]'s screen, with its cursor and
+/// colors, from [`Writer::save_screen`].
 ///
-/// The buffer is a 2D array of `ScreenChar`s laid out exactly as expected
-/// by the VGA hardware.
-#[repr(transparent)]
-struct Buffer {
-    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
+/// It lives inline, about 8 KiB, so it can be kept in a static: start
+/// one with [`ScreenSnapshot::new`].
+#[derive(Clone)]
+pub struct ScreenSnapshot {
+    cells: [Row; MAX_BUFFER_HEIGHT],
+    width: usize,
+    height: usize,
+    column_position: usize,
+    row_up: usize,
+    color_code: ColorCode,
+}
+
+impl ScreenSnapshot {
+    /// Creates an empty snapshot, which restores to a blank screen.
+    pub const fn new() -> Self {
+        ScreenSnapshot {
+            cells: [[BLANK; BUFFER_WIDTH]; MAX_BUFFER_HEIGHT],
+            width: BUFFER_WIDTH,
+            height: BUFFER_HEIGHT,
+            column_position: 0,
+            row_up: 0,
+            color_code: DEFAULT_COLOR,
+        }
+    }
+
+    /// Returns the character at `row` and `col` with its foreground and
+    /// background colors, as [`Writer::char_at`] returned them.
+    pub fn char_at(&self, row: usize, col: usize) -> Option<(u8, Color, Color)> {
+        if row >= self.height || col >= self.width {
+            return None;
+        }
+        let cell = self.cells[row][col];
+        let color = cell.color_code;
+        Some((cell.ascii_character, color.foreground(), color.background()))
+    }
+}
+
+impl Default for ScreenSnapshot {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+/// How the writer updates VGA memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Render {
+    /// Write each character straight to VGA memory. Scrolling copies every
+    /// row up by one.
+    Direct,
+    /// Draw into a [`Shadow`] in RAM and copy the rows that changed to VGA
+    /// memory when a write finishes.
+    Buffered,
+}
+
+/// The screen as drawn in [`Render::Buffered`] mode.
+struct Shadow {
+    /// Screen rows as a ring starting at `top`, so scrolling moves no
+    /// characters. Only the writer's height and width of it are used.
+    rows: [[ScreenChar; BUFFER_WIDTH]; MAX_BUFFER_HEIGHT],
+    /// Index in `rows` of the top screen row.
+    top: usize,
+    /// Screen rows that differ from VGA memory, one bit each.
+    dirty: u64,
+}
+
+const BLANK: ScreenChar = ScreenChar { ascii_character: b' ', color_code: ColorCode(0) };
+
+/// One row of the screen, as wide as the widest writer.
+type Row = [ScreenChar; BUFFER_WIDTH];
+
 /// A writer type for the VGA text buffer.
 ///
 /// Maintains the current cursor position and color state, and provides
-/// methods for writing bytes and strings to the screen.
+/// methods for writing bytes and strings to the screen. [`WRITER`] writes
+/// to the screen at `0xb8000`, 80x25 until [`set_mode`] changes it;
+/// [`Writer::new`] makes one for any text buffer up to 80x50.
 pub struct Writer {
-    /// Current column position on the last row.
+    /// Current column position on the last row. `width` means the row is
+    /// full and the wrap is pending: it happens before the next
+    /// printable byte, so a newline straight after a full row doesn't leave
+    /// an empty one.
     column_position: usize,
 
     /// Current foreground/background color.
     color_code: ColorCode,
 
-    /// Reference to the VGA text buffer.
-    buffer: &'static mut Buffer,
+    /// The text buffer's cells, row after row.
+    buffer: &'static mut [Volatile<ScreenChar>],
+
+    width: usize,
+    height: usize,
+
+    render: Render,
+
+    /// Used in [`Render::Buffered`] mode only.
+    shadow: Shadow,
+
+    /// Rows at the top kept out of scrolling by [`reserve_rows`](Self::reserve_rows).
+    scroll_top: usize,
+
+    /// The first or last row, kept out of scrolling for
+    /// [`write_status`](Self::write_status).
+    status_row: Option<usize>,
+
+    /// The CRT controller, for the hardware cursor; `None` for a buffer
+    /// that isn't on the screen.
+    ports: Option<VgaPorts>,
+
+    /// Cell index the hardware cursor was last moved to.
+    cursor: Option<u16>,
+
+    /// Rows that scrolled off the top, oldest first.
+    scrollback: FixedRing<Row, SCROLLBACK_LINES>,
+
+    /// How many rows the view is scrolled back; 0 shows the live screen.
+    view_offset: usize,
+
+    /// The live screen, kept while the view is scrolled back.
+    saved: [Row; MAX_BUFFER_HEIGHT],
+
+    /// Rows the cursor is above the last scrolling row. Only cursor
+    /// positioning sets it; each newline moves down one until it is 0.
+    row_up: usize,
+
+    escape: Escape,
 }
 
 impl Writer {
+    /// Creates a writer for a text buffer of `width` by `height` cells at
+    /// `buffer_addr`, laid out as VGA text memory is: rows one after the
+    /// other, each cell a character byte and then a color byte. The size is
+    /// clamped to at least 1x1 and at most [`BUFFER_WIDTH`] by
+    /// [`MAX_BUFFER_HEIGHT`].
+    ///
+    /// The writer starts in [`Render::Direct`] mode and doesn't touch the
+    /// hardware cursor.
+    ///
+    /// # Safety
+    ///
+    /// `buffer_addr` must be valid for reads and writes of the
+    /// `width * height` cells, and nothing else may use them, for as long as
+    /// the writer is used.
+    pub unsafe fn new(buffer_addr: *mut u8, width: usize, height: usize) -> Writer {
+        let width = width.clamp(1, BUFFER_WIDTH);
+        let height = height.clamp(1, MAX_BUFFER_HEIGHT);
+        Writer {
+            column_position: 0,
+            color_code: DEFAULT_COLOR,
+            buffer: unsafe { core::slice::from_raw_parts_mut(buffer_addr.cast(), width * height) },
+            width,
+            height,
+            render: Render::Direct,
+            shadow: Shadow {
+                rows: [[BLANK; BUFFER_WIDTH]; MAX_BUFFER_HEIGHT],
+                top: 0,
+                dirty: 0,
+            },
+            scroll_top: 0,
+            status_row: None,
+            ports: None,
+            cursor: None,
+            scrollback: FixedRing::new(),
+            view_offset: 0,
+            saved: [[BLANK; BUFFER_WIDTH]; MAX_BUFFER_HEIGHT],
+            row_up: 0,
+            escape: Escape::None,
+        }
+    }
+
+    /// Returns the number of columns.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns the number of rows.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Changes the number of rows to `height`, clamped as in
+    /// [`new`](Self::new), for a new [`TextMode`]. The screen is cleared and
+    /// the cursor goes to the start of the last scrolling row. A status row
+    /// on the bottom moves to the new bottom; reserved rows stay, as long as
+    /// a row is left to scroll.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must be valid for `width * height` cells, as for
+    /// [`new`](Self::new).
+    unsafe fn set_height(&mut self, height: usize) {
+        let height = height.clamp(1, MAX_BUFFER_HEIGHT);
+        self.snap_to_bottom();
+        let cells = self.width * height;
+        let buffer = self.buffer.as_mut_ptr();
+        self.buffer = unsafe { core::slice::from_raw_parts_mut(buffer, cells) };
+        if self.status_row.is_some_and(|row| row != 0) {
+            self.status_row = Some(height - 1);
+        }
+        self.height = height;
+        if self.top() >= self.bottom() {
+            self.status_row = None;
+        }
+        self.scroll_top = self.scroll_top.min(self.bottom() - 1);
+        self.escape = Escape::None;
+        self.shadow.top = 0;
+        for row in 0..height {
+            self.clear_row(row);
+        }
+        self.row_up = 0;
+        self.column_position = 0;
+        self.cursor = None;
+        self.present();
+    }
+
+    /// Returns the cell at `row` and `col`, which must be on the screen.
+    fn cell(&self, row: usize, col: usize) -> &Volatile<ScreenChar> {
+        &self.buffer[row * self.width + col]
+    }
+
     /// Writes a single byte to the VGA buffer.
     ///
     /// Printable ASCII bytes are written directly. Newlines cause the screen
-    /// to scroll.
+    /// to scroll, and a form feed (`0x0c`) clears it. A backspace blanks the
+    /// cell before the cursor and moves back onto it, a carriage return goes
+    /// to the start of the row, and a tab moves to the next multiple of
+    /// [`TAB_WIDTH`] columns, or to a new line past the end of the row.
+    /// `ESC` starts an ANSI escape sequence.
     pub fn write_byte(&mut self, byte: u8) {
+        self.put_byte(byte);
+        self.present();
+    }
+
+    /// Set how the screen is updated. Switching to [`Render::Direct`]
+    /// first copies out anything not yet shown.
+    pub fn set_render(&mut self, render: Render) {
+        if render == self.render {
+            return;
+        }
+        self.snap_to_bottom();
+        match render {
+            Render::Direct => self.present(),
+            Render::Buffered => {
+                for (index, c) in self.buffer.iter().enumerate() {
+                    self.shadow.rows[index / self.width][index % self.width] = c.read();
+                }
+                self.shadow.top = 0;
+                self.shadow.dirty = 0;
+            }
+        }
+        self.render = render;
+    }
+
+    /// Returns how the screen is updated.
+    pub fn render(&self) -> Render {
+        self.render
+    }
+
+    /// Copies the rows changed since the last call to VGA memory, in
+    /// [`Render::Buffered`] mode, and moves the hardware cursor.
+    fn present(&mut self) {
+        let (width, height) = (self.width, self.height);
+        let shadow = &mut self.shadow;
+        for row in 0..height {
+            if shadow.dirty & (1 << row) == 0 {
+                continue;
+            }
+            let chars = &shadow.rows[(shadow.top + row) % height];
+            for (col, &c) in chars[..width].iter().enumerate() {
+                self.buffer[row * width + col].write(c);
+            }
+        }
+        shadow.dirty = 0;
+        self.move_cursor();
+    }
+
+    /// Returns live screen row `row`, which in [`Render::Direct`] mode is in
+    /// VGA memory. Only call it while the view is at the bottom.
+    fn screen_row(&self, row: usize) -> Row {
+        match self.render {
+            Render::Direct => self.read_row(row),
+            Render::Buffered => self.shadow.rows[(self.shadow.top + row) % self.height],
+        }
+    }
+
+    /// Reads `row` of the text buffer; cells past the width are blank.
+    fn read_row(&self, row: usize) -> Row {
+        core::array::from_fn(|col| match col < self.width {
+            true => self.cell(row, col).read(),
+            false => BLANK,
+        })
+    }
+
+    /// Shows the rows `lines` further back in the scrollback, as far as it
+    /// goes.
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.set_view(self.view_offset.saturating_add(lines));
+    }
+
+    /// Shows the rows `lines` nearer the live screen, as far as it goes.
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.set_view(self.view_offset.saturating_sub(lines));
+    }
+
+    /// Shows the live screen again.
+    pub fn scroll_to_bottom(&mut self) {
+        self.set_view(0);
+    }
+
+    /// Returns how many rows the view is scrolled back; 0 at the bottom.
+    pub fn scrolled_back(&self) -> usize {
+        self.view_offset
+    }
+
+    fn snap_to_bottom(&mut self) {
+        if self.view_offset != 0 {
+            self.set_view(0);
+        }
+    }
+
+    /// Redraws the scrolling rows as they were `offset` rows back.
+    ///
+    /// The rows are the scrollback followed by the live screen; the view is
+    /// the last screenful of them, moved `offset` rows up. It is drawn
+    /// straight into VGA memory, so the live screen is saved first and put
+    /// back at offset 0.
+    fn set_view(&mut self, offset: usize) {
+        let offset = offset.min(self.scrollback.len());
+        if offset == self.view_offset {
+            return;
+        }
+        let (top, bottom) = (self.top(), self.bottom());
+        if self.view_offset == 0 {
+            self.present();
+            for row in top..bottom {
+                self.saved[row] = self.read_row(row);
+            }
+        }
+        self.view_offset = offset;
+        let (older, newer) = self.scrollback.as_slices();
+        let history = older.len() + newer.len();
+        for row in top..bottom {
+            // Index of this row in the scrollback-then-live sequence.
+            let index = history - offset + (row - top);
+            let chars = if index < older.len() {
+                &older[index]
+            } else if index < history {
+                &newer[index - older.len()]
+            } else {
+                &self.saved[top + index - history]
+            };
+            for (col, &c) in chars[..self.width].iter().enumerate() {
+                self.buffer[row * self.width + col].write(c);
+            }
+        }
+    }
+
+    /// Moves the hardware cursor to where the next character goes, if it
+    /// isn't there already. After the last column it stays on the last
+    /// cell until the next character wraps the line.
+    fn move_cursor(&mut self) {
+        let col = self.column_position.min(self.width - 1);
+        let position = (self.cursor_row() * self.width + col) as u16;
+        if self.cursor == Some(position) {
+            return;
+        }
+        if let Some(ports) = &self.ports {
+            let [low, high] = position.to_le_bytes();
+            ports.write_crtc(CRTC_CURSOR_HIGH, high);
+            ports.write_crtc(CRTC_CURSOR_LOW, low);
+        }
+        self.cursor = Some(position);
+    }
+
+    /// Returns the cell index, `row * width + col`, the hardware cursor was
+    /// last moved to, or `None` before the first write.
+    pub fn hardware_cursor(&self) -> Option<u16> {
+        self.cursor
+    }
+
+    /// Shows the hardware cursor as scanlines `start` to `end` of the cell,
+    /// each 0 to 15 (15 is the bottom).
+    pub fn enable_cursor(&mut self, start: u8, end: u8) {
+        let Some(ports) = &self.ports else {
+            return;
+        };
+        let old_start = ports.read_crtc(CRTC_CURSOR_START);
+        ports.write_crtc(CRTC_CURSOR_START, (old_start & 0xC0) | (start & 0x1F));
+        let old_end = ports.read_crtc(CRTC_CURSOR_END);
+        ports.write_crtc(CRTC_CURSOR_END, (old_end & 0xE0) | (end & 0x1F));
+    }
+
+    /// Hides the hardware cursor.
+    pub fn disable_cursor(&mut self) {
+        if let Some(ports) = &self.ports {
+            ports.write_crtc(CRTC_CURSOR_START, CURSOR_DISABLE);
+        }
+    }
+
+    /// Sets the character at `row` and `col` of the screen.
+    fn put(&mut self, row: usize, col: usize, c: ScreenChar) {
+        if (self.top()..self.bottom()).contains(&row) {
+            self.snap_to_bottom();
+        }
+        match self.render {
+            Render::Direct => self.buffer[row * self.width + col].write(c),
+            Render::Buffered => {
+                self.shadow.rows[(self.shadow.top + row) % self.height][col] = c;
+                self.shadow.dirty |= 1 << row;
+            }
+        }
+    }
+
+    /// Writes a byte without presenting it; see [`write_byte`](Self::write_byte).
+    fn put_byte(&mut self, byte: u8) {
+        if self.escape != Escape::None {
+            self.escape_byte(byte);
+            return;
+        }
         match byte {
+            0x1b => self.escape = Escape::Started,
             b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
+            0x0c => self.blank_screen(),
+            0x08 => {
+                if self.column_position > 0 {
+                    let col = self.column_position.min(self.width) - 1;
+                    let blank = ScreenChar { ascii_character: b' ', color_code: self.color_code };
+                    self.put(self.cursor_row(), col, blank);
+                    self.column_position = col;
+                }
+            }
+            b'\r' => self.column_position = 0,
+            b'\t' => {
+                let stop = (self.column_position / TAB_WIDTH + 1) * TAB_WIDTH;
+                if stop > self.width {
                     self.new_line();
+                } else {
+                    self.column_position = stop;
                 }
+            }
+            byte => self.put_glyph(byte),
+        }
+    }
+
+    /// Writes the glyph for `byte` at the cursor, even for the bytes
+    /// [`put_byte`](Self::put_byte) takes as control bytes.
+    fn put_glyph(&mut self, byte: u8) {
+        // Wrap only now, when there is something to put on the new row.
+        if self.column_position >= self.width {
+            self.new_line();
+        }
+
+        let row = self.cursor_row();
+        let col = self.column_position;
 
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
+        let color_code = self.color_code;
+        self.put(row, col, ScreenChar {
+            ascii_character: byte,
+            color_code,
+        });
+        self.column_position += 1;
+    }
+
+    /// Takes the next byte of an escape sequence, acting on the sequence
+    /// once its final byte arrives.
+    fn escape_byte(&mut self, byte: u8) {
+        self.escape = match (self.escape, byte) {
+            (Escape::Started, b'[') => Escape::Csi { params: [0; MAX_ESCAPE_PARAMS], last: 0 },
+            // Intermediate bytes, such as the `(` of `ESC(B`.
+            (Escape::Started, 0x20..=0x2f) => Escape::Started,
+            (Escape::Csi { mut params, last }, b'0'..=b'9') => {
+                if let Some(param) = params.get_mut(last) {
+                    *param = param.saturating_mul(10).saturating_add(u16::from(byte - b'0'));
+                }
+                Escape::Csi { params, last }
+            }
+            (Escape::Csi { params, last }, b';') => Escape::Csi { params, last: last + 1 },
+            // Private markers and intermediate bytes, such as the `?` of `ESC[?25l`.
+            (csi @ Escape::Csi { .. }, 0x20..=0x3f) => csi,
+            (Escape::Csi { params, last }, 0x40..=0x7e) => {
+                let count = (last + 1).min(MAX_ESCAPE_PARAMS);
+                self.csi(byte, &params[..count]);
+                Escape::None
+            }
+            _ => Escape::None,
+        };
+    }
 
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code: self.color_code,
-                });
-                self.column_position += 1;
+    /// Carries out the CSI sequence with final byte `command`.
+    fn csi(&mut self, command: u8, params: &[u16]) {
+        match command {
+            b'm' => params.iter().for_each(|&param| self.select_graphic_rendition(param)),
+            b'H' | b'f' => {
+                // Both count from 1, and a missing or 0 one means 1.
+                let row = usize::from(params[0].max(1)) - 1;
+                let col = usize::from(params.get(1).copied().unwrap_or(0).max(1)) - 1;
+                let row = (self.top() + row).min(self.bottom() - 1);
+                self.row_up = self.bottom() - 1 - row;
+                self.column_position = col.min(self.width - 1);
+            }
+            b'J' if matches!(params[0], 2 | 3) => {
+                for row in self.top()..self.bottom() {
+                    self.clear_row(row);
+                }
             }
+            _ => {}
         }
     }
 
+    /// Applies SGR parameter `param`, if it is a color or a reset.
+    fn select_graphic_rendition(&mut self, param: u16) {
+        let (foreground, background) = (self.color_code.foreground(), self.color_code.background());
+        let ansi = |index: u16| ANSI_COLORS[usize::from(index)];
+        self.color_code = match param {
+            0 => DEFAULT_COLOR,
+            30..=37 => ColorCode::new(ansi(param - 30), background),
+            39 => ColorCode::new(DEFAULT_COLOR.foreground(), background),
+            40..=47 => ColorCode::new(foreground, ansi(param - 40)),
+            49 => ColorCode::new(foreground, DEFAULT_COLOR.background()),
+            90..=97 => ColorCode::new(ansi(param - 90 + 8), background),
+            100..=107 => ColorCode::new(foreground, ansi(param - 100 + 8)),
+            _ => return,
+        };
+    }
+
     /// Advances the buffer to a new line, scrolling the screen if necessary.
     fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+        self.snap_to_bottom();
+        if self.row_up > 0 {
+            // Below a positioned cursor there are rows left to move down to.
+            self.row_up -= 1;
+            self.column_position = 0;
+            return;
+        }
+        let (top, bottom) = (self.top(), self.bottom());
+        let leaving = self.screen_row(top);
+        self.scrollback.push_overwrite(leaving);
+        match self.render {
+            Render::Direct => {
+                let width = self.width;
+                for index in (top + 1) * width..bottom * width {
+                    let character = self.buffer[index].read();
+                    self.buffer[index - width].write(character);
+                }
+            }
+            Render::Buffered if top == 0 && bottom == self.height => {
+                // The old top row comes round as the new bottom one.
+                self.shadow.top = (self.shadow.top + 1) % self.height;
+                self.shadow.dirty = (1 << self.height) - 1;
+            }
+            Render::Buffered => {
+                // Reserved rows must stay put, so move the rest in RAM.
+                let height = self.height;
+                let shadow = &mut self.shadow;
+                for row in top + 1..bottom {
+                    let from = (shadow.top + row) % height;
+                    let to = (shadow.top + row - 1) % height;
+                    shadow.rows[to] = shadow.rows[from];
+                }
+                shadow.dirty |= ((1 << bottom) - 1) & !((1 << top) - 1);
             }
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
+        self.clear_row(bottom - 1);
+        self.column_position = 0;
+    }
+
+    /// Blanks every row but the reserved ones in the current colors and
+    /// moves the cursor to the start of the last one.
+    pub fn clear_screen(&mut self) {
+        self.blank_screen();
+        self.present();
+    }
+
+    fn blank_screen(&mut self) {
+        for row in self.top()..self.bottom() {
+            self.clear_row(row);
+        }
+        self.row_up = 0;
         self.column_position = 0;
     }
 
@@ -171,74 +901,1407 @@ impl Writer {
             ascii_character: b' ',
             color_code: self.color_code,
         };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        for col in 0..self.width {
+            self.put(row, col, blank);
         }
     }
 }
 
 impl Writer {
-    /// Writes a string to the VGA buffer.
+    /// Writes a string to the VGA buffer, a character at a time.
     ///
-    /// Non-printable bytes are replaced with `0xfe`.
+    /// The control characters [`write_byte`](Self::write_byte) handles are
+    /// kept, other ASCII control characters are replaced with `0xfe`, and
+    /// the rest are written as their [`cp437`] glyph, or `?` if the font has
+    /// none. [`write_byte`](Self::write_byte) writes raw bytes instead.
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+        self.put_str(s);
+        self.present();
+    }
+
+    /// Writes a string without presenting it; see
+    /// [`write_string`](Self::write_string).
+    fn put_str(&mut self, s: &str) {
+        for c in s.chars() {
+            match c {
+                ' '..='~' | '\n' | '\x0c' | '\x08' | '\r' | '\t' | '\x1b' => self.put_byte(c as u8),
+                c => self.put_glyph(glyph(c)),
             }
         }
     }
-}
 
-/// Allows the VGA writer to be used with Rust’s formatting infrastructure.
-impl fmt::Write for Writer {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.write_string(s);
-        Ok(())
+    /// Copies whatever has been drawn but not yet shown to VGA memory, in
+    /// [`Render::Buffered`] mode. Every write does this when it finishes,
+    /// so only code that draws some other way needs it.
+    pub fn flush(&mut self) {
+        self.present();
     }
-}
 
-// Global VGA text buffer writer.
-//
-// This is protected by a spinlock to allow safe concurrent access from
-// different execution contexts (e.g. interrupts).
-//
-// # Safety
-// The memory address `0xb8000` must be mapped and correspond to a VGA
-// text buffer in the current execution environment.
-lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
-}
+    /// Draws every character straight into VGA memory if `immediate`, or
+    /// into the shadow copy otherwise; see [`Render`].
+    pub fn set_immediate_mode(&mut self, immediate: bool) {
+        self.set_render(if immediate { Render::Direct } else { Render::Buffered });
+    }
 
-#[test_case]
-fn test_println_simple() {
-    println!("test_println_simple output");
-}
+    /// Returns the colors new text is written in.
+    pub fn color_code(&self) -> ColorCode {
+        self.color_code
+    }
 
-#[test_case]
-fn test_println_many() {
-    for _ in 0..200 {
-        println!("test_println_many output");
+    /// Writes text from here on in `foreground` on `background`. Text
+    /// already on the screen keeps its colors; rows blanked by scrolling or
+    /// clearing get the new background.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
     }
-}
 
-#[test_case]
-fn test_println_output() {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
+    /// Runs `f` with the colors set as by [`set_color`](Self::set_color),
+    /// then puts the previous ones back.
+    pub fn with_color<R>(
+        &mut self,
+        foreground: Color,
+        background: Color,
+        f: impl FnOnce(&mut Writer) -> R,
+    ) -> R {
+        let previous = self.color_code;
+        self.set_color(foreground, background);
+        let result = f(self);
+        self.color_code = previous;
+        result
+    }
 
-    let s = "Some test string that fits on a single line";
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed");
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
+    /// Returns the character at `row` and `col` on the screen with its
+    /// foreground and background colors, or `None` outside the screen.
+    ///
+    /// Reads VGA memory, which is up to date after every write in either
+    /// [`Render`] mode. The cursor and colors don't change.
+    pub fn char_at(&self, row: usize, col: usize) -> Option<(u8, Color, Color)> {
+        if row >= self.height || col >= self.width {
+            return None;
         }
-    });
+        let cell = self.cell(row, col).read();
+        let color = cell.color_code;
+        Some((cell.ascii_character, color.foreground(), color.background()))
+    }
+
+    /// Returns a copy of the characters in `row`, padded with blanks past
+    /// the width, or `None` below the screen. Like
+    /// [`char_at`](Self::char_at), it changes nothing.
+    pub fn row_text(&self, row: usize) -> Option<[u8; BUFFER_WIDTH]> {
+        if row >= self.height {
+            return None;
+        }
+        Some(self.read_row(row).map(|c| c.ascii_character))
+    }
+
+    /// Returns a copy of the screen as it is shown, with the cursor position
+    /// and colors. Nothing on the screen changes, but a view scrolled back
+    /// returns to the bottom first.
+    pub fn save_screen(&mut self) -> ScreenSnapshot {
+        self.snap_to_bottom();
+        self.present();
+        let mut snapshot = ScreenSnapshot::new();
+        for row in 0..self.height {
+            snapshot.cells[row] = self.read_row(row);
+        }
+        snapshot.width = self.width;
+        snapshot.height = self.height;
+        snapshot.column_position = self.column_position;
+        snapshot.row_up = self.row_up;
+        snapshot.color_code = self.color_code;
+        snapshot
+    }
+
+    /// Puts back every cell, the cursor and the colors from `snapshot`,
+    /// reserved and status rows included. A snapshot of a larger writer is
+    /// cut to this one's size.
+    pub fn restore_screen(&mut self, snapshot: &ScreenSnapshot) {
+        self.snap_to_bottom();
+        for row in 0..self.height.min(snapshot.height) {
+            for col in 0..self.width.min(snapshot.width) {
+                self.put(row, col, snapshot.cells[row][col]);
+            }
+        }
+        self.column_position = snapshot.column_position.min(self.width);
+        self.row_up = snapshot.row_up;
+        self.color_code = snapshot.color_code;
+        self.present();
+    }
+
+    /// Returns the cursor column on the last row.
+    pub fn column(&self) -> usize {
+        self.column_position
+    }
+
+    /// Moves the cursor to `column` on the last row, clamped to the width.
+    pub fn set_column(&mut self, column: usize) {
+        self.column_position = column.min(self.width);
+        self.move_cursor();
+    }
+
+    /// Blanks the last row from the cursor to the end of the line.
+    ///
+    /// The cursor doesn't move.
+    pub fn clear_from_cursor(&mut self) {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        for col in self.column_position..self.width {
+            self.put(self.cursor_row(), col, blank);
+        }
+        self.present();
+    }
+
+    /// Keeps the top `rows` rows out of scrolling and clearing, leaving at
+    /// least one row to scroll.
+    pub fn reserve_rows(&mut self, rows: usize) {
+        self.snap_to_bottom();
+        self.scroll_top = rows.min(self.bottom() - 1);
+    }
+
+    /// Returns how many rows at the top are reserved.
+    pub fn reserved_rows(&self) -> usize {
+        self.scroll_top
+    }
+
+    /// Keeps `row`, the first or the last, out of scrolling and clearing
+    /// for [`write_status`](Self::write_status), or gives it back with
+    /// `None`. Returns false, changing nothing, for any other row, for the
+    /// first while [`reserve_rows`](Self::reserve_rows) holds it, or if no
+    /// row would be left to scroll.
+    ///
+    /// Taking the last row scrolls the text up one first, so the cursor's
+    /// row stays on screen; giving it back blanks it and starts a new line
+    /// there.
+    pub fn set_status_row(&mut self, row: Option<usize>) -> bool {
+        if row == self.status_row {
+            return true;
+        }
+        if row == Some(0) && self.scroll_top > 0 {
+            return false;
+        }
+        let top = self.scroll_top.max(usize::from(row == Some(0)));
+        let last = self.height - 1;
+        let bottom = if row == Some(last) { last } else { self.height };
+        if row.is_some_and(|row| row != 0 && row != last) || top >= bottom {
+            return false;
+        }
+        self.snap_to_bottom();
+        let (old, old_bottom) = (self.status_row, self.bottom());
+        if bottom < old_bottom {
+            // The cursor's row moves up into the one above.
+            let column = self.column_position;
+            self.new_line();
+            self.column_position = column;
+        }
+        self.status_row = row;
+        if let Some(old) = old
+            && old >= self.scroll_top
+        {
+            self.clear_row(old);
+        }
+        if bottom > old_bottom {
+            // The cursor is now on the old status row.
+            self.column_position = 0;
+        }
+        self.present();
+        true
+    }
+
+    /// Returns the status row, if there is one.
+    pub fn status_row(&self) -> Option<usize> {
+        self.status_row
+    }
+
+    /// Draws `text` across the status row in `color`, cut off or padded
+    /// with blanks to the full width. The cursor doesn't move and nothing
+    /// scrolls; without a status row it does nothing.
+    ///
+    /// Characters are written as [`write_string`](Self::write_string)
+    /// writes those it doesn't take as control characters.
+    pub fn write_status(&mut self, text: &str, color: ColorCode) {
+        let Some(row) = self.status_row else {
+            return;
+        };
+        let mut chars = text.chars();
+        for col in 0..self.width {
+            let ascii_character = chars.next().map_or(b' ', glyph);
+            self.put(row, col, ScreenChar { ascii_character, color_code: color });
+        }
+        self.present();
+    }
+
+    /// Draws the [`panic_screen`] over whatever is on the screen.
+    fn draw_panic_screen(&mut self, info: &PanicInfo) {
+        use core::fmt::Write;
+
+        let color = ColorCode::new(Color::White, Color::Red);
+        self.snap_to_bottom();
+        self.set_immediate_mode(true);
+        self.escape = Escape::None;
+        self.status_row = None;
+        self.scroll_top = 0;
+        self.color_code = color;
+        self.blank_screen();
+        let center = |writer: &mut Writer, row: usize, text: &str| {
+            let col = writer.width.saturating_sub(text.chars().count()) / 2;
+            writer.write_at(row, col, text, color);
+        };
+
+        center(self, 1, "*** KERNEL PANIC ***");
+        let mut message = FixedString::<{ BUFFER_WIDTH * PANIC_MESSAGE_ROWS }>::new();
+        let _ = write!(message, "{}", info.message());
+        let mut rest = message.as_str();
+        for row in 3..3 + PANIC_MESSAGE_ROWS {
+            // A row ends at a newline or when it is full.
+            let full = rest.char_indices().nth(self.width).map_or(rest.len(), |(at, _)| at);
+            let end = rest.find('\n').map_or(full, |newline| newline.min(full));
+            center(self, row, &rest[..end]);
+            rest = &rest[end..];
+            rest = rest.strip_prefix('\n').unwrap_or(rest);
+        }
+        if let Some(location) = info.location() {
+            let mut at = FixedString::<BUFFER_WIDTH>::new();
+            let _ = write!(at, "at {}:{}:{}", location.file(), location.line(), location.column());
+            center(self, 3 + PANIC_MESSAGE_ROWS + 1, at.as_str());
+        }
+        center(self, PANIC_BANNER_ROWS - 2, "system halted");
+        self.reserve_rows(PANIC_BANNER_ROWS);
+    }
+
+    /// First row that scrolls.
+    fn top(&self) -> usize {
+        match self.status_row {
+            Some(0) => self.scroll_top.max(1),
+            _ => self.scroll_top,
+        }
+    }
+
+    /// One past the last row that scrolls.
+    fn bottom(&self) -> usize {
+        match self.status_row {
+            Some(row) if row == self.height - 1 => row,
+            _ => self.height,
+        }
+    }
+
+    /// The row the cursor is on: the last one that scrolls, unless cursor
+    /// positioning moved it up.
+    fn cursor_row(&self) -> usize {
+        (self.bottom() - 1).saturating_sub(self.row_up).max(self.top())
+    }
+
+    /// Writes `text` at `row` and `col` in `color`, cut off at the edge of
+    /// the screen, and returns how many characters were written. The cursor
+    /// doesn't move and nothing scrolls.
+    ///
+    /// Characters are written as [`write_string`](Self::write_string)
+    /// writes those it doesn't take as control characters.
+    pub fn write_at(&mut self, row: usize, col: usize, text: &str, color: ColorCode) -> usize {
+        if row >= self.height {
+            return 0;
+        }
+        let mut written = 0;
+        for (col, c) in (col..self.width).zip(text.chars()) {
+            self.put(row, col, ScreenChar { ascii_character: glyph(c), color_code: color });
+            written += 1;
+        }
+        self.present();
+        written
+    }
+
+    /// Fills rows `row0` up to `row1` and columns `col0` up to `col1`, the
+    /// ends not included, with `c` in `color`. Cells off the screen are
+    /// left out. The cursor doesn't move and nothing scrolls.
+    pub fn fill_region(
+        &mut self,
+        row0: usize,
+        col0: usize,
+        row1: usize,
+        col1: usize,
+        c: char,
+        color: ColorCode,
+    ) {
+        let cell = ScreenChar { ascii_character: glyph(c), color_code: color };
+        for row in row0..row1.min(self.height) {
+            for col in col0..col1.min(self.width) {
+                self.put(row, col, cell);
+            }
+        }
+        self.present();
+    }
+
+    /// Draws the outline of a box `rows` high and `cols` wide, its top left
+    /// corner at `row0` and `col0`, in `color` with the code page 437 line
+    /// characters. The inside is left as it is, and so are cells off the
+    /// screen; a box less than 2x2 draws nothing. The cursor doesn't move
+    /// and nothing scrolls.
+    pub fn draw_box(
+        &mut self,
+        row0: usize,
+        col0: usize,
+        rows: usize,
+        cols: usize,
+        color: ColorCode,
+    ) {
+        if rows < 2 || cols < 2 {
+            return;
+        }
+        let row1 = row0.saturating_add(rows - 1);
+        let col1 = col0.saturating_add(cols - 1);
+        for col in col0.saturating_add(1)..col1.min(self.width) {
+            self.put_clipped(row0, col, '─', color);
+            self.put_clipped(row1, col, '─', color);
+        }
+        for row in row0.saturating_add(1)..row1.min(self.height) {
+            self.put_clipped(row, col0, '│', color);
+            self.put_clipped(row, col1, '│', color);
+        }
+        let corners = [(row0, col0, '┌'), (row0, col1, '┐'), (row1, col0, '└'), (row1, col1, '┘')];
+        for (row, col, corner) in corners {
+            self.put_clipped(row, col, corner, color);
+        }
+        self.present();
+    }
+
+    /// Sets the character at `row` and `col` to `c`, if that is on the
+    /// screen.
+    fn put_clipped(&mut self, row: usize, col: usize, c: char, color: ColorCode) {
+        if row < self.height && col < self.width {
+            self.put(row, col, ScreenChar { ascii_character: glyph(c), color_code: color });
+        }
+    }
+}
+
+/// Returns the byte that shows `c`: its [`cp437`] glyph, `0xfe` for an
+/// ASCII control character, or `?`.
+fn glyph(c: char) -> u8 {
+    cp437::from_char(c).unwrap_or(if c.is_ascii() { 0xfe } else { b'?' })
+}
+
+/// Allows the VGA writer to be used with Rust’s formatting infrastructure.
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+
+    /// Formats everything before presenting any of it, so a `write!` of
+    /// many pieces copies each changed row to VGA memory once.
+    fn write_fmt(&mut self, args: fmt::Arguments) -> fmt::Result {
+        struct Unpresented<'a>(&'a mut Writer);
+
+        impl fmt::Write for Unpresented<'_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.0.put_str(s);
+                Ok(())
+            }
+        }
+
+        let result = fmt::write(&mut Unpresented(self), args);
+        self.present();
+        result
+    }
+}
+
+impl KernelOutput for Writer {
+    fn write_output(&mut self, args: fmt::Arguments) {
+        let _ = fmt::Write::write_fmt(self, args);
+    }
+}
+
+/// Global VGA text buffer writer, set up by [`init`].
+///
+/// This is protected by an `IrqMutex`, so interrupts stay off while it is
+/// held and a handler that prints can't deadlock on it.
+pub static WRITER: Global<IrqMutex<Writer>> = Global::new("WRITER");
+
+/// The VGA registers [`probe`] reads and the writer moves the cursor
+/// with.
+pub struct VgaPorts {
+    /// Miscellaneous output register, read side.
+    pub misc_output: Port<u8>,
+    /// CRT controller index: selects the register `crtc_data` accesses.
+    pub crtc_index: Port<u8>,
+    pub crtc_data: Port<u8>,
+    /// Sequencer index and data, for [`set_mode`].
+    pub seq_index: Port<u8>,
+    pub seq_data: Port<u8>,
+    /// Graphics controller index and data, for [`set_mode`].
+    pub gc_index: Port<u8>,
+    pub gc_data: Port<u8>,
+}
+
+impl VgaPorts {
+    /// Write `value` to CRT controller register `index`.
+    fn write_crtc(&self, index: u8, value: u8) {
+        // SAFETY: the cursor registers only affect how the cursor looks.
+        unsafe {
+            self.crtc_index.write(index);
+            self.crtc_data.write(value);
+        }
+    }
+
+    /// Read CRT controller register `index`.
+    fn read_crtc(&self, index: u8) -> u8 {
+        // SAFETY: as in `write_crtc`; reads have no side effects.
+        unsafe {
+            self.crtc_index.write(index);
+            self.crtc_data.read()
+        }
+    }
+
+    /// Write `value` to sequencer register `index`.
+    ///
+    /// # Safety
+    ///
+    /// The sequencer decides how VGA memory is laid out; the caller must
+    /// put it back as text mode needs it before anything draws.
+    unsafe fn write_seq(&self, index: u8, value: u8) {
+        unsafe {
+            self.seq_index.write(index);
+            self.seq_data.write(value);
+        }
+    }
+
+    /// Write `value` to graphics controller register `index`.
+    ///
+    /// # Safety
+    ///
+    /// As for [`write_seq`](Self::write_seq).
+    unsafe fn write_gc(&self, index: u8, value: u8) {
+        unsafe {
+            self.gc_index.write(index);
+            self.gc_data.write(value);
+        }
+    }
+}
+
+/// CRT controller cursor registers.
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_END: u8 = 0x0B;
+const CRTC_CURSOR_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOW: u8 = 0x0F;
+/// Cursor start register bit that hides the cursor.
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+impl PortGroup for VgaPorts {
+    const DEVICE: &'static str = "vga";
+    const BASE: u16 = 0x3C0;
+
+    fn at(base: u16) -> Self {
+        VgaPorts {
+            misc_output: Port::new(Self::DEVICE, base + 0x0C),
+            crtc_index: Port::new(Self::DEVICE, base + 0x14),
+            crtc_data: Port::new(Self::DEVICE, base + 0x15),
+            seq_index: Port::new(Self::DEVICE, base + 0x04),
+            seq_data: Port::new(Self::DEVICE, base + 0x05),
+            gc_index: Port::new(Self::DEVICE, base + 0x0E),
+            gc_data: Port::new(Self::DEVICE, base + 0x0F),
+        }
+    }
+}
+
+/// Return whether a VGA adapter answers at `ports`.
+///
+/// Without one the bus floats and the register reads as all ones.
+pub fn probe(ports: &VgaPorts) -> bool {
+    // Reading the register has no side effects.
+    unsafe { ports.misc_output.read() != 0xFF }
+}
+
+/// Set by [`force_absent`].
+static FORCED_ABSENT: AtomicBool = AtomicBool::new(false);
+
+/// Make [`init`] act as if there were no VGA adapter.
+///
+/// For tests of headless boots; call before [`crate::init`].
+#[doc(hidden)]
+pub fn force_absent() {
+    FORCED_ABSENT.store(true, Ordering::Relaxed);
+}
+
+/// Return whether [`init`] found a VGA adapter and set up [`WRITER`].
+pub fn is_present() -> bool {
+    WRITER.is_initialized()
+}
+
+fn output(args: fmt::Arguments) {
+    if let Ok(writer) = WRITER.try_get() {
+        writer.lock().write_output(args);
+    }
+}
+
+/// Send [`kprintln!`](crate::kprintln) output to the screen, once [`init`]
+/// has found it. Returns `false` if there was no room for another
+/// [output](crate::output).
+pub fn register() -> bool {
+    crate::output::register(output)
+}
+
+/// Set up [`WRITER`], if [`probe`] finds a VGA adapter. Output printed
+/// before this is dropped from the screen, though it still reaches serial
+/// and the kernel log.
+///
+/// The memory address `0xb8000` must be mapped and correspond to a VGA
+/// text buffer, as it is under the bootloader's identity mapping, for
+/// [`MAX_BUFFER_HEIGHT`] rows if [`set_mode`] is to be used.
+pub fn init() -> Result<(), GlobalError> {
+    if FORCED_ABSENT.load(Ordering::Relaxed) || !probe(&VgaPorts::standard()) {
+        return Ok(());
+    }
+    let mut writer = unsafe { Writer::new(0xb8000 as *mut u8, BUFFER_WIDTH, BUFFER_HEIGHT) };
+    writer.ports = Some(VgaPorts::standard());
+    // Keeps what the firmware left on the screen.
+    writer.set_render(Render::Buffered);
+    WRITER.init(IrqMutex::named("WRITER", writer))?;
+    crate::emergency::register(&WRITER);
+    vt::init(BUFFER_WIDTH, BUFFER_HEIGHT)
+}
+
+/// Point the writer on the screen, [`WRITER`] unless another
+/// [terminal](vt) is shown, at the text buffer mapped at `new_addr`, as
+/// when the page tables move it. The swap happens under the writer's lock,
+/// so no write goes to the old address after this returns. What is on the
+/// screen stays put; only the address changes.
+///
+/// # Safety
+///
+/// `new_addr` must map the same text buffer, [`MAX_BUFFER_HEIGHT`] rows of
+/// it, or memory at least as large, for as long as the writer is used.
+pub unsafe fn remap(new_addr: VirtAddr) {
+    vt::with_screen(|writer| {
+        let cells = writer.buffer.len();
+        writer.buffer = unsafe { core::slice::from_raw_parts_mut(new_addr.as_mut_ptr(), cells) };
+    });
+}
+
+/// Copy [`WRITER`]'s screen with [`Writer::save_screen`], under one hold
+/// of its lock. `None` without a VGA adapter.
+pub fn save_screen() -> Option<ScreenSnapshot> {
+    Some(WRITER.try_get().ok()?.lock().save_screen())
+}
+
+/// Put back a screen from [`save_screen`] with
+/// [`Writer::restore_screen`], under one hold of [`WRITER`]'s lock.
+pub fn restore_screen(snapshot: &ScreenSnapshot) {
+    if let Ok(writer) = WRITER.try_get() {
+        writer.lock().restore_screen(snapshot);
+    }
+}
+
+/// Blank the screen with [`Writer::clear_screen`], if there is one.
+///
+/// [`WRITER`]'s lock keeps interrupts off, so output from a handler lands
+/// before or after the clear, never halfway through it.
+pub fn clear_screen() {
+    if let Ok(writer) = WRITER.try_get() {
+        writer.lock().clear_screen();
+    }
+}
+
+/// Clear the screen to white on red and show `info`'s message and location
+/// under a heading, with a "system halted" banner below. The top
+/// [`PANIC_BANNER_ROWS`] rows are then reserved, so whatever the panic
+/// handler prints next scrolls underneath them in the same colors.
+///
+/// Takes [`WRITER`] even if it is locked, and puts it back on the screen
+/// if another [terminal](vt) is shown.
+///
+/// # Safety
+///
+/// Whoever holds the [`WRITER`] lock must never use their guard again; call
+/// this only on a path that doesn't return to them, as with
+/// [`emergency::take_over`](crate::emergency::take_over).
+pub unsafe fn panic_screen(info: &PanicInfo) {
+    let Ok(writer) = WRITER.try_get() else {
+        return;
+    };
+    // SAFETY: the holders are abandoned, as the caller promises.
+    unsafe { vt::reclaim() };
+    writer.lock().draw_panic_screen(info);
+}
+
+/// Show the hardware cursor as scanlines `start` to `end`; see
+/// [`Writer::enable_cursor`].
+pub fn enable_cursor(start: u8, end: u8) {
+    if let Ok(writer) = WRITER.try_get() {
+        writer.lock().enable_cursor(start, end);
+    }
+}
+
+/// Hide the hardware cursor.
+pub fn disable_cursor() {
+    if let Ok(writer) = WRITER.try_get() {
+        writer.lock().disable_cursor();
+    }
+}
+
+/// Set how [`WRITER`] updates the screen; see [`Render`].
+pub fn set_render(render: Render) {
+    if let Ok(writer) = WRITER.try_get() {
+        writer.lock().set_render(render);
+    }
+}
+
+/// Have [`WRITER`] draw straight into VGA memory, or not; see
+/// [`Writer::set_immediate_mode`].
+pub fn set_immediate_mode(immediate: bool) {
+    if let Ok(writer) = WRITER.try_get() {
+        writer.lock().set_immediate_mode(immediate);
+    }
+}
+
+#[test_case]
+fn test_println_simple() {
+    println!("test_println_simple output");
+}
+
+#[test_case]
+fn test_println_many() {
+    for _ in 0..200 {
+        println!("test_println_many output");
+    }
+}
+
+/// Return whether `text` is on the screen, within a row.
+#[cfg(test)]
+pub(crate) fn screen_contains(text: &str) -> bool {
+    let writer = vt::current_terminal().unwrap().lock();
+    (0..writer.height()).filter_map(|row| writer.row_text(row)).any(|row| {
+        row.windows(text.len()).any(|window| window == text.as_bytes())
+    })
+}
+
+#[test_case]
+fn test_println_output() {
+    use core::fmt::Write;
+
+    let s = "Some test string that fits on a single line";
+    let mut writer = WRITER.get().lock();
+    writeln!(writer, "\n{}", s).expect("writeln failed");
+    for (i, c) in s.chars().enumerate() {
+        let screen_char = writer.cell(BUFFER_HEIGHT - 2, i).read();
+        assert_eq!(char::from(screen_char.ascii_character), c);
+    }
+}
+
+#[test_case]
+fn test_println_while_timer_ticks() {
+    use crate::interrupts::ticks;
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    // Print in a tight loop with the timer on, so ticks arrive between and
+    // during prints; each line is read back under the same hold of the lock.
+    assert!(interrupts::are_enabled());
+    let start = ticks();
+    let mut count = 0;
+    while ticks() < start + 3 {
+        let mut line = FixedString::<32>::new();
+        write!(line, "tick loop {}", count).unwrap();
+        let mut writer = WRITER.get().lock();
+        writeln!(writer, "\n{}", line.as_str()).unwrap();
+        assert!(writer.row_text(BUFFER_HEIGHT - 2).unwrap().starts_with(line.as_str().as_bytes()));
+        drop(writer);
+        crate::println!("printed {}", count);
+        count += 1;
+    }
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_clear_from_cursor() {
+    let mut writer = WRITER.get().lock();
+    writer.write_string("\nhello world");
+    writer.set_column(5);
+    writer.clear_from_cursor();
+    assert_eq!(writer.column(), 5);
+    for (i, c) in "hello      ".chars().enumerate() {
+        assert_eq!(char::from(writer.cell(BUFFER_HEIGHT - 1, i).read().ascii_character), c);
+    }
+}
+
+#[test_case]
+fn test_buffered_render_matches_direct() {
+    let screen = |writer: &Writer| -> alloc::vec::Vec<ScreenChar> {
+        writer.buffer.iter().map(|c| c.read()).collect()
+    };
+    let text = "\x0cfirst\nsecond line that is long enough to wrap past the eightieth column of the \
+                screen\n\u{7f}third";
+
+    let mut writer = WRITER.get().lock();
+    let render = writer.render();
+    writer.set_render(Render::Direct);
+    for _ in 0..30 {
+        writer.write_string(text);
+    }
+    writer.set_column(2);
+    writer.clear_from_cursor();
+    let direct = screen(&writer);
+
+    writer.set_render(Render::Buffered);
+    writer.write_string("\x0c");
+    for _ in 0..30 {
+        writer.write_string(text);
+    }
+    writer.set_column(2);
+    writer.clear_from_cursor();
+    let buffered = screen(&writer);
+    assert_eq!(writer.shadow.dirty, 0);
+    writer.set_render(render);
+    drop(writer);
+    assert!(direct == buffered);
+}
+
+#[test_case]
+fn test_write_at_and_reserved_rows() {
+    let cell = |writer: &Writer, row: usize, col: usize| writer.cell(row, col).read();
+
+    let mut writer = WRITER.get().lock();
+    let (reserved, previous) = (writer.reserved_rows(), writer.render());
+    for render in [Render::Direct, Render::Buffered] {
+        writer.set_render(render);
+        writer.reserve_rows(1);
+        writer.write_string("\x0cabc");
+        let status = ColorCode::new(Color::Black, Color::LightGray);
+        assert_eq!(writer.write_at(0, 76, "status", status), 4);
+        assert_eq!(writer.write_at(BUFFER_HEIGHT, 0, "ignored", status), 0);
+        assert_eq!(writer.column(), 3);
+        assert_eq!(cell(&writer, BUFFER_HEIGHT - 1, 2).ascii_character, b'c');
+        assert_eq!(cell(&writer, 0, 76).ascii_character, b's');
+        assert_eq!(cell(&writer, 0, 79).ascii_character, b't');
+        assert_eq!(cell(&writer, 0, 79).color_code, status);
+
+        // Scrolling and clearing leave the reserved row alone.
+        for _ in 0..BUFFER_HEIGHT + 2 {
+            writer.write_string("line\n");
+        }
+        assert_eq!(cell(&writer, 0, 76).ascii_character, b's');
+        assert_eq!(cell(&writer, 1, 0).ascii_character, b'l');
+        writer.write_string("\x0c");
+        assert_eq!(cell(&writer, 0, 77).ascii_character, b't');
+        assert_eq!(cell(&writer, 1, 0).ascii_character, b' ');
+        writer.write_at(0, 76, "    ", ColorCode::new(Color::Black, Color::Black));
+    }
+    writer.reserve_rows(reserved);
+    writer.set_render(previous);
+}
+
+#[test_case]
+fn test_draw_box_and_fill_region() {
+    let byte = |writer: &Writer, row, col| writer.cell(row, col).read().ascii_character;
+    let color = ColorCode::new(Color::LightCyan, Color::Blue);
+
+    let mut writer = WRITER.get().lock();
+    writer.write_string("\x0cbefore");
+    let cursor = writer.hardware_cursor();
+    writer.draw_box(2, 10, 5, 20, color);
+    writer.fill_region(3, 11, 6, 29, '░', color);
+    assert_eq!(writer.write_at(4, 12, "inside", color), 6);
+    assert_eq!((writer.column(), writer.hardware_cursor()), ("before".len(), cursor));
+
+    let corners = [(2, 10, 0xda), (2, 29, 0xbf), (6, 10, 0xc0), (6, 29, 0xd9)];
+    for (row, col, corner) in corners {
+        assert_eq!(byte(&writer, row, col), corner, "row {} column {}", row, col);
+    }
+    assert!((11..29).all(|col| byte(&writer, 2, col) == 0xc4 && byte(&writer, 6, col) == 0xc4));
+    assert!((3..6).all(|row| byte(&writer, row, 10) == 0xb3 && byte(&writer, row, 29) == 0xb3));
+    assert_eq!((byte(&writer, 3, 11), byte(&writer, 5, 28)), (0xb0, 0xb0));
+    assert_eq!(&writer.row_text(4).unwrap()[10..20], b"\xb3\xb0inside\xb0\xb0");
+    assert_eq!((byte(&writer, 7, 10), byte(&writer, 2, 30)), (b' ', b' '));
+    assert_eq!(writer.cell(2, 10).read().color_code, color);
+    drop(writer);
+
+    // Printing goes on after the cursor, and scrolls the box with the rest.
+    crate::println!(" after");
+    let writer = WRITER.get().lock();
+    assert!(writer.row_text(BUFFER_HEIGHT - 2).unwrap().starts_with(b"before after"));
+    assert_eq!((byte(&writer, 1, 10), byte(&writer, 5, 29)), (0xda, 0xd9));
+}
+
+#[test_case]
+fn test_regions_are_clipped() {
+    let (width, height) = (10, 4);
+    let mut cells = alloc::vec![0u16; width * height];
+    let mut writer = ram_writer(&mut cells, width, height);
+    let color = DEFAULT_COLOR;
+    writer.write_string("\x0c");
+
+    assert_eq!(writer.write_at(1, 7, "clipped", color), 3);
+    writer.draw_box(2, 8, 5, 5, color);
+    writer.draw_box(0, 0, 1, 5, color);
+    writer.fill_region(3, 0, usize::MAX, 2, '#', color);
+    writer.fill_region(0, 5, 0, 9, '#', color);
+    assert_eq!(&writer.row_text(0).unwrap()[..width], b"          ");
+    assert_eq!(&writer.row_text(1).unwrap()[..width], b"       cli");
+    assert_eq!(&writer.row_text(2).unwrap()[..width], b"        \xda\xc4");
+    assert_eq!(&writer.row_text(3).unwrap()[..width], b"##      \xb3 ");
+    assert_eq!(writer.column(), 0);
+}
+
+#[test_case]
+fn test_color_code_packing() {
+    assert_eq!(ColorCode::new(Color::Yellow, Color::Black).as_u8(), 0x0e);
+    assert_eq!(ColorCode::new(Color::White, Color::Blue).as_u8(), 0x1f);
+    assert_eq!(ColorCode::new(Color::Black, Color::LightGray).as_u8(), 0x70);
+    assert_eq!(ColorCode::new(Color::Pink, Color::White).as_u8(), 0xfd);
+}
+
+#[test_case]
+fn test_set_color_applies_to_later_text() {
+    let cell = |writer: &Writer, col| writer.cell(BUFFER_HEIGHT - 1, col).read();
+
+    let mut writer = WRITER.get().lock();
+    let previous = writer.color_code();
+    writer.write_string("\nabc");
+    writer.set_color(Color::White, Color::Blue);
+    let blue = ColorCode::new(Color::White, Color::Blue);
+    assert_eq!(cell(&writer, 2).color_code, previous);
+    writer.write_string("d");
+    assert_eq!(cell(&writer, 3).color_code, blue);
+
+    // Scrolling blanks the new row in the current background.
+    writer.write_string("\n");
+    let blank = ScreenChar { ascii_character: b' ', color_code: blue };
+    assert!((0..BUFFER_WIDTH).all(|col| cell(&writer, col) == blank));
+
+    let red = ColorCode::new(Color::LightRed, Color::Black);
+    let column = writer.with_color(Color::LightRed, Color::Black, |writer| {
+        writer.write_string("e");
+        writer.column()
+    });
+    assert_eq!(column, 1);
+    assert_eq!(cell(&writer, 0).color_code, red);
+    assert_eq!(writer.color_code(), blue);
+    writer.color_code = previous;
+}
+
+#[test_case]
+fn test_colored_prints_from_two_threads() {
+    use crate::thread::{self, Thread};
+
+    const LINES: usize = 10;
+
+    fn print_red() {
+        for _ in 0..LINES {
+            println_colored!(Color::LightRed, Color::Black, "%%%%");
+        }
+    }
+
+    let default = {
+        let mut writer = WRITER.get().lock();
+        writer.clear_screen();
+        writer.color_code()
+    };
+    // The timer preempts whichever thread is printing, between lines.
+    let red = Thread::spawn(print_red, 4096 * 4);
+    for _ in 0..LINES {
+        println_colored!(Color::LightGreen, Color::Black, "####");
+    }
+    while thread::is_alive(red) {
+        thread::yield_now();
+    }
+
+    let writer = WRITER.get().lock();
+    assert_eq!(writer.color_code(), default);
+    let cells = || writer.buffer.iter().map(|cell| cell.read());
+    let red = ColorCode::new(Color::LightRed, Color::Black);
+    let green = ColorCode::new(Color::LightGreen, Color::Black);
+    let count = |byte, color| {
+        cells()
+            .filter(|c| c.ascii_character == byte)
+            .inspect(|c| assert_eq!(c.color_code, color))
+            .count()
+    };
+    assert_eq!(count(b'%', red), 4 * LINES);
+    assert_eq!(count(b'#', green), 4 * LINES);
+}
+
+#[test_case]
+fn test_backspace_return_and_tab() {
+    let row = |writer: &Writer| -> alloc::string::String {
+        let row = writer.row_text(BUFFER_HEIGHT - 1).unwrap();
+        row.iter().map(|&byte| char::from(byte)).collect()
+    };
+
+    let mut writer = WRITER.get().lock();
+    writer.write_string("\nab\x08c");
+    assert!(row(&writer).starts_with("ac "));
+    assert_eq!(writer.column(), 2);
+    writer.write_string("\n\x08\x08x");
+    assert!(row(&writer).starts_with("x "));
+
+    writer.write_string("\nhello\rj");
+    assert!(row(&writer).starts_with("jello "));
+    assert_eq!(writer.column(), 1);
+
+    writer.write_string("\na\tb\t\tc");
+    assert!(row(&writer).starts_with("a       b               c "));
+    writer.write_string("\n\tx");
+    assert_eq!(writer.column(), TAB_WIDTH + 1);
+
+    // The last stop is the end of the row; a tab past it starts a new one.
+    writer.set_column(75);
+    writer.write_string("\t");
+    assert_eq!(writer.column(), BUFFER_WIDTH);
+    writer.write_string("\t");
+    assert_eq!(writer.column(), 0);
+    writer.write_string("z");
+    assert!(row(&writer).starts_with("z "));
+}
+
+#[test_case]
+fn test_println_reads_back() {
+    let text = "read-back test: 0123456789";
+    println!("{}", text);
+    let writer = WRITER.get().lock();
+    let (column, color) = (writer.column(), writer.color_code());
+    let row = BUFFER_HEIGHT - 2;
+    for (col, byte) in text.bytes().enumerate() {
+        let cell = writer.char_at(row, col);
+        assert_eq!(cell, Some((byte, color.foreground(), color.background())));
+    }
+    assert_eq!(writer.char_at(row, text.len()).map(|(byte, ..)| byte), Some(b' '));
+    assert_eq!(writer.char_at(BUFFER_HEIGHT, 0), None);
+    assert_eq!(writer.char_at(0, BUFFER_WIDTH), None);
+    assert_eq!(writer.row_text(BUFFER_HEIGHT), None);
+    // Reading moved nothing.
+    assert_eq!((writer.column(), writer.color_code()), (column, color));
+}
+
+/// Prints more lines than `writer` has rows, then checks which are left.
+#[cfg(test)]
+fn check_scrolling(writer: &mut Writer) {
+    use core::fmt::Write;
+
+    let height = writer.height();
+    let last = height + 4;
+    for i in 0..=last {
+        writeln!(writer, "scroll line {:02}", i).unwrap();
+    }
+    // The last line printed is just above the empty last row, and each
+    // row above holds the line before.
+    for row in writer.reserved_rows()..height - 1 {
+        let mut expected = FixedString::<16>::new();
+        write!(expected, "scroll line {:02}", last - (height - 2 - row)).unwrap();
+        let expected = expected.as_str().as_bytes();
+        let text = writer.row_text(row).unwrap();
+        assert_eq!(&text[..expected.len()], expected, "row {}", row);
+    }
+    assert!(writer.row_text(height - 1).unwrap().iter().all(|&byte| byte == b' '));
+}
+
+#[test_case]
+fn test_scrolling_moves_text_up() {
+    check_scrolling(&mut WRITER.get().lock());
+}
+
+#[test_case]
+fn test_clear_screen() {
+    for _ in 0..BUFFER_HEIGHT {
+        println!("{:79}", "fill");
+    }
+    let previous = {
+        let mut writer = WRITER.get().lock();
+        let previous = writer.color_code();
+        writer.set_color(Color::White, Color::Blue);
+        previous
+    };
+    clear!();
+
+    let mut writer = WRITER.get().lock();
+    assert_eq!(writer.column(), 0);
+    for row in writer.reserved_rows()..BUFFER_HEIGHT {
+        for col in 0..BUFFER_WIDTH {
+            assert_eq!(writer.char_at(row, col), Some((b' ', Color::White, Color::Blue)));
+        }
+    }
+    writer.color_code = previous;
+}
+
+#[test_case]
+fn test_hardware_cursor_follows_writer() {
+    const LAST_ROW: u16 = ((BUFFER_HEIGHT - 1) * BUFFER_WIDTH) as u16;
+
+    let mut writer = WRITER.get().lock();
+    let render = writer.render();
+    for render in [Render::Direct, Render::Buffered] {
+        writer.set_render(render);
+        writer.write_string("\nabc");
+        assert_eq!(writer.hardware_cursor(), Some(LAST_ROW + 3));
+        writer.set_column(10);
+        assert_eq!(writer.hardware_cursor(), Some(LAST_ROW + 10));
+        // A scroll puts it back at the start of the last row.
+        writer.write_string("\n");
+        assert_eq!(writer.hardware_cursor(), Some(LAST_ROW));
+        // A full row leaves it on the last cell until the next character.
+        writer.write_string(&"x".repeat(BUFFER_WIDTH));
+        assert_eq!(writer.hardware_cursor(), Some(LAST_ROW + BUFFER_WIDTH as u16 - 1));
+        writer.write_string("y");
+        assert_eq!(writer.hardware_cursor(), Some(LAST_ROW + 1));
+    }
+    writer.set_render(render);
+
+    // Both registers read back as written; only the low five bits count.
+    writer.enable_cursor(14, 15);
+    let ports = writer.ports.as_ref().unwrap();
+    assert_eq!(ports.read_crtc(CRTC_CURSOR_START) & 0x3F, 14);
+    assert_eq!(ports.read_crtc(CRTC_CURSOR_END) & 0x1F, 15);
+    writer.disable_cursor();
+    let ports = writer.ports.as_ref().unwrap();
+    assert_ne!(ports.read_crtc(CRTC_CURSOR_START) & CURSOR_DISABLE, 0);
+    writer.enable_cursor(14, 15);
+}
+
+#[test_case]
+fn test_scrollback() {
+    use core::fmt::Write;
+
+    let mut writer = WRITER.get().lock();
+    let previous = writer.render();
+    for render in [Render::Direct, Render::Buffered] {
+        writer.set_render(render);
+        writer.write_string("\x0c");
+        for i in 0..60 {
+            writeln!(writer, "history {:03}", i).unwrap();
+        }
+        let live: [_; BUFFER_HEIGHT] = core::array::from_fn(|row| writer.row_text(row).unwrap());
+        let row_starts = |writer: &Writer, text: &[u8]| {
+            writer.row_text(BUFFER_HEIGHT - 2).unwrap().starts_with(text)
+        };
+
+        // Live, the row above the empty last one holds line 59.
+        writer.scroll_up(10);
+        assert_eq!(writer.scrolled_back(), 10);
+        assert!(row_starts(&writer, b"history 049"));
+        writer.scroll_down(5);
+        assert!(row_starts(&writer, b"history 054"));
+        writer.scroll_up(usize::MAX);
+        assert!(writer.scrolled_back() <= SCROLLBACK_LINES);
+
+        writer.scroll_to_bottom();
+        assert_eq!(writer.scrolled_back(), 0);
+        for (row, text) in live.iter().enumerate() {
+            assert_eq!(&writer.row_text(row).unwrap(), text, "row {}", row);
+        }
+
+        // Output while scrolled back snaps to the bottom.
+        writer.scroll_up(3);
+        writer.write_string("new");
+        assert_eq!(writer.scrolled_back(), 0);
+        assert_eq!(&writer.row_text(BUFFER_HEIGHT - 1).unwrap()[..3], b"new");
+    }
+    writer.set_render(previous);
+}
+
+/// Prints `width` letters and a newline to `writer`, then checks the rows
+/// they left.
+#[cfg(test)]
+fn check_line_of_width(writer: &mut Writer, width: usize) {
+    let (columns, height) = (writer.width(), writer.height());
+    let line: alloc::string::String = (0..width).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+    writer.write_string("\x0cmarker\n");
+    writer.write_string(&line);
+    writer.write_string("\n");
+
+    let wrapped = width.saturating_sub(1) / columns;
+    let first = height - 2 - wrapped;
+    assert!(writer.row_text(first - 1).unwrap().starts_with(b"marker"));
+    let mut printed = alloc::vec::Vec::new();
+    for row in first..height - 1 {
+        printed.extend_from_slice(&writer.row_text(row).unwrap()[..columns]);
+    }
+    assert_eq!(&printed[..width], line.as_bytes());
+    assert!(printed[width..].iter().all(|&byte| byte == b' '));
+    assert!(writer.row_text(height - 1).unwrap().iter().all(|&byte| byte == b' '));
+    assert_eq!(writer.column(), 0);
+}
+
+#[test_case]
+fn test_line_of_79_columns() {
+    check_line_of_width(&mut WRITER.get().lock(), BUFFER_WIDTH - 1);
+}
+
+#[test_case]
+fn test_line_of_80_columns_takes_one_row() {
+    check_line_of_width(&mut WRITER.get().lock(), BUFFER_WIDTH);
+}
+
+#[test_case]
+fn test_line_of_81_columns_wraps_once() {
+    check_line_of_width(&mut WRITER.get().lock(), BUFFER_WIDTH + 1);
+}
+
+#[test_case]
+fn test_status_row_stays_put() {
+    use core::fmt::Write;
+
+    let status_color = ColorCode::new(Color::White, Color::Blue);
+    let mut writer = WRITER.get().lock();
+    assert!(!writer.set_status_row(Some(5)));
+    writer.reserve_rows(2);
+    assert!(!writer.set_status_row(Some(0)));
+    writer.reserve_rows(0);
+    for status in [0, BUFFER_HEIGHT - 1] {
+        assert!(writer.set_status_row(Some(status)));
+        writer.write_string("\x0c");
+        for i in 0..50 {
+            writeln!(writer, "line {:02}", i).unwrap();
+            write!(writer, "ab").unwrap();
+            writer.write_status("status", status_color);
+            assert_eq!(writer.column(), 2);
+            writer.write_string("\r  \r");
+        }
+        writer.write_status("ticks 50", status_color);
+
+        let text = writer.row_text(status).unwrap();
+        assert!(text.starts_with(b"ticks 50"));
+        assert!(text[8..].iter().all(|&byte| byte == b' '));
+        assert_eq!(writer.cell(status, 79).read().color_code, status_color);
+
+        // The scrolling rows end with the last line and the empty cursor row.
+        let cursor_row = if status == 0 { BUFFER_HEIGHT - 1 } else { BUFFER_HEIGHT - 2 };
+        let first = if status == 0 { 1 } else { 0 };
+        assert!(writer.row_text(cursor_row).unwrap().iter().all(|&byte| byte == b' '));
+        assert!(writer.row_text(cursor_row - 1).unwrap().starts_with(b"line 49"));
+        let lines = cursor_row - first;
+        let mut expected = FixedString::<8>::new();
+        write!(expected, "line {:02}", 50 - lines).unwrap();
+        assert!(writer.row_text(first).unwrap().starts_with(expected.as_str().as_bytes()));
+        assert_eq!(writer.hardware_cursor(), Some((cursor_row * BUFFER_WIDTH) as u16));
+    }
+    assert!(writer.set_status_row(None));
+    assert_eq!(writer.status_row(), None);
+    assert!(writer.row_text(BUFFER_HEIGHT - 1).unwrap().iter().all(|&byte| byte == b' '));
+}
+
+#[test_case]
+fn test_ansi_colors() {
+    let cell = |writer: &Writer, col: usize| writer.cell(BUFFER_HEIGHT - 1, col).read();
+
+    let mut writer = WRITER.get().lock();
+    let previous = writer.color_code();
+    writer.write_string("\x0ca\x1b[31mb\x1b[44;93mc\x1b[39md\x1b[0me\x1b[5;7mf");
+    assert_eq!(&writer.row_text(BUFFER_HEIGHT - 1).unwrap()[..7], b"abcdef ");
+    assert_eq!(writer.column(), 6);
+    let expected = [
+        previous,
+        ColorCode::new(Color::Red, previous.background()),
+        ColorCode::new(Color::Yellow, Color::Blue),
+        ColorCode::new(DEFAULT_COLOR.foreground(), Color::Blue),
+        DEFAULT_COLOR,
+        // Blink and reverse video aren't colors, so nothing changes.
+        DEFAULT_COLOR,
+    ];
+    for (col, &color_code) in expected.iter().enumerate() {
+        assert_eq!(cell(&writer, col).color_code, color_code, "column {}", col);
+    }
+    writer.color_code = previous;
+}
+
+#[test_case]
+fn test_ansi_cursor_and_erase() {
+    let mut writer = WRITER.get().lock();
+    let top = writer.reserved_rows();
+    writer.write_string("\x0cbottom\x1b[2J");
+    assert!(writer.row_text(BUFFER_HEIGHT - 1).unwrap().iter().all(|&byte| byte == b' '));
+    // Erasing leaves the cursor where it was.
+    assert_eq!(writer.column(), 6);
+
+    writer.write_string("\x1b[H1\x1b[3;10Hthree\nnext");
+    assert_eq!(writer.row_text(top).unwrap()[0], b'1');
+    assert_eq!(&writer.row_text(top + 2).unwrap()[9..14], b"three");
+    // The newline moves down a row without scrolling.
+    assert_eq!(&writer.row_text(top + 3).unwrap()[..4], b"next");
+    assert_eq!(writer.hardware_cursor(), Some(((top + 3) * BUFFER_WIDTH + 4) as u16));
+
+    // Rows and columns past the edge are clamped to it.
+    writer.write_string("\x1b[99;99Hz");
+    assert_eq!(writer.row_text(BUFFER_HEIGHT - 1).unwrap()[BUFFER_WIDTH - 1], b'z');
+    writer.write_string("\x0c");
+}
+
+#[test_case]
+fn test_unknown_escapes_are_swallowed() {
+    let mut writer = WRITER.get().lock();
+    writer.write_string("\x0ca\x1b[?25lb\x1b[1;2;3;4;5;6;7;8;9;10Xc\x1b(Bd\x1b7e");
+    assert_eq!(&writer.row_text(BUFFER_HEIGHT - 1).unwrap()[..6], b"abcde ");
+}
+
+#[test_case]
+fn test_unicode_is_written_as_cp437() {
+    let mut writer = WRITER.get().lock();
+    writer.write_string("\x0c┌─┐ 21°C → café… ✓\x7f");
+    let text = writer.row_text(BUFFER_HEIGHT - 1).unwrap();
+    let expected = [
+        0xda, 0xc4, 0xbf, b' ', b'2', b'1', 0xf8, b'C', b' ', 0x1a, b' ', b'c', b'a', b'f', 0x82,
+        b'?', b' ', b'?', 0xfe,
+    ];
+    assert_eq!(&text[..expected.len()], &expected);
+    // One cell per character, however many bytes it takes in UTF-8.
+    assert_eq!(writer.column(), expected.len());
+
+    // The arrow's byte is ESC, but as a glyph it doesn't start a sequence.
+    writer.write_string("←x");
+    assert_eq!(&writer.row_text(BUFFER_HEIGHT - 1).unwrap()[expected.len()..][..2], &[0x1b, b'x']);
+}
+
+/// A writer over `cells`, as a `width` by `height` text buffer in RAM.
+#[cfg(test)]
+fn ram_writer(cells: &mut [u16], width: usize, height: usize) -> Writer {
+    assert_eq!(cells.len(), width * height);
+    // SAFETY: the callers keep `cells` alive, and leave it alone, for as
+    // long as they use the writer.
+    unsafe { Writer::new(cells.as_mut_ptr().cast(), width, height) }
+}
+
+#[test_case]
+fn test_writer_over_ram_buffer() {
+    let (width, height) = (40, 10);
+    let mut cells = alloc::vec![0u16; width * height];
+    let mut writer = ram_writer(&mut cells, width, height);
+    assert_eq!((writer.width(), writer.height()), (width, height));
+
+    for render in [Render::Direct, Render::Buffered] {
+        writer.set_render(render);
+        check_scrolling(&mut writer);
+        for line in [width - 1, width, width + 1] {
+            check_line_of_width(&mut writer, line);
+        }
+        // The cursor counts cells across the narrower rows.
+        writer.write_string("abc");
+        assert_eq!(writer.hardware_cursor(), Some(((height - 1) * width + 3) as u16));
+        assert_eq!(writer.char_at(0, width), None);
+    }
+    drop(writer);
+    // Each cell is the character, then the color.
+    let cell = cells[(height - 1) * width];
+    assert_eq!(cell, u16::from(DEFAULT_COLOR.as_u8()) << 8 | u16::from(b'a'));
+}
+
+#[test_case]
+fn test_writer_size_is_clamped() {
+    let mut cells = alloc::vec![0u16; BUFFER_WIDTH * BUFFER_HEIGHT];
+    let writer = unsafe { Writer::new(cells.as_mut_ptr().cast(), 500, 0) };
+    assert_eq!((writer.width(), writer.height()), (BUFFER_WIDTH, 1));
+}
+
+#[test_case]
+fn test_height_change_resizes_writer() {
+    use core::fmt::Write;
+
+    let width = BUFFER_WIDTH;
+    let mut cells = alloc::vec![0u16; width * MAX_BUFFER_HEIGHT];
+    let mut writer = ram_writer(&mut cells, width, BUFFER_HEIGHT);
+    let blank = Some([b' '; BUFFER_WIDTH]);
+
+    for render in [Render::Direct, Render::Buffered] {
+        writer.set_render(render);
+        assert!(writer.set_status_row(Some(BUFFER_HEIGHT - 1)));
+        writer.reserve_rows(2);
+        writer.write_string("left over");
+        unsafe { writer.set_height(MAX_BUFFER_HEIGHT) };
+        assert_eq!(writer.height(), MAX_BUFFER_HEIGHT);
+        assert_eq!(writer.status_row(), Some(MAX_BUFFER_HEIGHT - 1));
+        assert_eq!((writer.reserved_rows(), writer.column()), (2, 0));
+        let cursor = (MAX_BUFFER_HEIGHT - 2) * width;
+        assert_eq!(writer.hardware_cursor(), Some(cursor as u16));
+        assert!((0..MAX_BUFFER_HEIGHT).all(|row| writer.row_text(row) == blank));
+
+        // Rows 2 to 48 scroll, and all of them fill up.
+        for line in 0..MAX_BUFFER_HEIGHT - 3 {
+            write!(writer, "\nrow {}", line).unwrap();
+        }
+        assert!(writer.row_text(2).unwrap().starts_with(b"row 0"));
+        assert!(writer.row_text(MAX_BUFFER_HEIGHT - 2).unwrap().starts_with(b"row 46"));
+
+        unsafe { writer.set_height(BUFFER_HEIGHT) };
+        assert_eq!(writer.status_row(), Some(BUFFER_HEIGHT - 1));
+        assert_eq!(writer.row_text(BUFFER_HEIGHT), None);
+        assert!(writer.set_status_row(None));
+        check_scrolling(&mut writer);
+        writer.reserve_rows(0);
+    }
+}
+
+#[test_case]
+fn test_remap_keeps_writing() {
+    let mut writer = WRITER.get().lock();
+    let old = writer.buffer.as_mut_ptr();
+    writer.write_string("\nbefore remap");
+    drop(writer);
+    // The same memory through the physical-memory mapping, which is a
+    // different virtual address.
+    let offset = crate::memory::PHYS_OFFSET.get();
+    let new_addr = *offset + 0xb8000u64;
+    unsafe { remap(new_addr) };
+    let mut writer = WRITER.get().lock();
+    assert_eq!(writer.buffer.as_mut_ptr().cast(), new_addr.as_mut_ptr::<u8>());
+    writer.write_string(" after");
+    assert!(writer.row_text(BUFFER_HEIGHT - 1).unwrap().starts_with(b"before remap after"));
+    writer.buffer = unsafe { core::slice::from_raw_parts_mut(old, BUFFER_WIDTH * BUFFER_HEIGHT) };
+}
+
+#[test_case]
+fn test_buffered_writes_wait_for_flush() {
+    use core::fmt::Write;
+
+    let (width, height) = (20, 4);
+    let mut cells = alloc::vec![0u16; width * height];
+    let mut writer = ram_writer(&mut cells, width, height);
+    writer.set_immediate_mode(false);
+    assert_eq!(writer.render(), Render::Buffered);
+
+    writer.put_str("drawn");
+    assert_eq!(writer.char_at(height - 1, 0).map(|(byte, ..)| byte), Some(0));
+    writer.flush();
+    assert!(writer.row_text(height - 1).unwrap().starts_with(b"drawn"));
+
+    // Lines that scroll in the shadow all reach the screen at the end.
+    write!(writer, "\n{}\n{}\n{}", "one", "two", "three").unwrap();
+    let rows: [_; 4] = core::array::from_fn(|row| writer.row_text(row).unwrap());
+    assert!(rows[0].starts_with(b"drawn") && rows[1].starts_with(b"one"));
+    assert!(rows[2].starts_with(b"two") && rows[3].starts_with(b"three"));
+
+    writer.set_immediate_mode(true);
+    assert_eq!(writer.render(), Render::Direct);
+    writer.put_str("!");
+    assert_eq!(writer.char_at(height - 1, 5).map(|(byte, ..)| byte), Some(b'!'));
+}
+
+#[test_case]
+fn test_save_and_restore_screen() {
+    let mut writer = WRITER.get().lock();
+    let previous = writer.color_code();
+    writer.write_string("\x0cfirst row of the saved screen\n");
+    writer.set_color(Color::LightCyan, Color::Magenta);
+    writer.write_string("colored");
+    writer.set_color(Color::White, Color::Black);
+    writer.write_string(" then white");
+    let snapshot = writer.save_screen();
+    let cells: [_; BUFFER_HEIGHT * BUFFER_WIDTH] = core::array::from_fn(|index| {
+        writer.cell(index / BUFFER_WIDTH, index % BUFFER_WIDTH).read()
+    });
+
+    writer.set_color(Color::Black, Color::LightGray);
+    for _ in 0..BUFFER_HEIGHT {
+        writer.write_string("clobbered clobbered clobbered\n");
+    }
+    writer.write_string("\x1b[3;4Hmoved");
+
+    writer.restore_screen(&snapshot);
+    for (index, cell) in cells.iter().enumerate() {
+        let (row, col) = (index / BUFFER_WIDTH, index % BUFFER_WIDTH);
+        assert_eq!(writer.cell(row, col).read(), *cell, "row {} column {}", row, col);
+        let (byte, foreground, background) = snapshot.char_at(row, col).unwrap();
+        assert_eq!(ColorCode::new(foreground, background), cell.color_code);
+        assert_eq!(byte, cell.ascii_character);
+    }
+    assert_eq!(writer.column(), "colored then white".len());
+    assert_eq!(writer.color_code(), ColorCode::new(Color::White, Color::Black));
+    let cursor = (BUFFER_HEIGHT - 1) * BUFFER_WIDTH + writer.column();
+    assert_eq!(writer.hardware_cursor(), Some(cursor as u16));
+    writer.color_code = previous;
 }
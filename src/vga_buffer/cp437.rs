@@ -0,0 +1,58 @@
+//! Unicode to code page 437, the character set of the VGA text font.
+//!
+//! Bytes `0x20` to `0x7e` are ASCII. The rest show glyphs: smileys, card
+//! suits and arrows below `0x20`, a house at `0x7f`, and accented Latin
+//! letters, box drawing, Greek letters and math symbols from `0x80` up.
+
+/// The glyphs of bytes `0x01` to `0x1f`.
+const LOW: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►', '◄', '↕', '‼',
+    '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// The glyphs of bytes `0x80` to `0xff`.
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', // 0x80
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', // 0x90
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', // 0xa0
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', // 0xb0
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', // 0xc0
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', // 0xd0
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', // 0xe0
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}', // 0xf0
+];
+
+/// Returns the byte whose glyph is `c`, or `None` if the font has no such
+/// glyph. Printable ASCII maps to itself; ASCII control characters have
+/// no glyph of their own and map to `None`.
+pub fn from_char(c: char) -> Option<u8> {
+    match c {
+        ' '..='~' => Some(c as u8),
+        '⌂' => Some(0x7f),
+        // Greek letters the font shares with a look-alike.
+        'β' => Some(0xe1),
+        'μ' => Some(0xe6),
+        _ => {
+            let position = |table: &[char]| table.iter().position(|&glyph| glyph == c);
+            if let Some(index) = position(&LOW) {
+                Some(index as u8 + 0x01)
+            } else {
+                position(&HIGH).map(|index| index as u8 + 0x80)
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_from_char() {
+    assert_eq!(from_char('A'), Some(b'A'));
+    assert_eq!(from_char('\n'), None);
+    assert_eq!(from_char('☺'), Some(0x01));
+    assert_eq!(from_char('→'), Some(0x1a));
+    assert_eq!(from_char('Ç'), Some(0x80));
+    assert_eq!(from_char('─'), Some(0xc4));
+    assert_eq!(from_char('°'), Some(0xf8));
+    assert_eq!(from_char('\u{a0}'), Some(0xff));
+    assert_eq!(from_char('…'), None);
+    assert_eq!(from_char('€'), None);
+}
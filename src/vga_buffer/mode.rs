@@ -0,0 +1,247 @@
+//! 80x25 and 80x50 text modes.
+//!
+//! Both modes draw 400 scanlines; 80x50 fits twice the rows by drawing
+//! each with an 8-scanline font instead of the 16-scanline one. There is
+//! no 8-scanline font to load, so [`set_mode`] makes one from the font the
+//! card has: it reads the glyphs out of font plane 2, ORs each pair of
+//! scanlines into one and writes them back. The 16-scanline glyphs are
+//! kept and put back on the way to 80x25. Then it sets the CRT
+//! controller's character height and cursor shape, and resizes every
+//! [terminal](super::vt), which clears them.
+//!
+//! The font plane is reached through the physical-memory mapping, so
+//! modes can be set once [memory](crate::memory) is up. Setting 80x50
+//! needs the `vga-80x50` feature, since it pokes registers some adapters
+//! other than QEMU's may not take kindly to.
+
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+
+use super::{vt, VgaPorts, BUFFER_HEIGHT, MAX_BUFFER_HEIGHT};
+
+/// A text mode [`set_mode`] can switch to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TextMode {
+    /// 80 columns by 25 rows of 16-scanline characters. The default.
+    T80x25 = 0,
+    /// 80 columns by 50 rows of 8-scanline characters.
+    T80x50 = 1,
+}
+
+impl TextMode {
+    /// Return the number of rows.
+    pub fn rows(self) -> usize {
+        match self {
+            TextMode::T80x25 => BUFFER_HEIGHT,
+            TextMode::T80x50 => MAX_BUFFER_HEIGHT,
+        }
+    }
+
+    /// Return the scanlines each character is drawn with.
+    pub fn font_height(self) -> u8 {
+        match self {
+            TextMode::T80x25 => 16,
+            TextMode::T80x50 => 8,
+        }
+    }
+
+    fn from_u8(value: u8) -> TextMode {
+        match value {
+            1 => TextMode::T80x50,
+            _ => TextMode::T80x25,
+        }
+    }
+}
+
+impl fmt::Display for TextMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TextMode::T80x25 => "80x25",
+            TextMode::T80x50 => "80x50",
+        })
+    }
+}
+
+/// Why [`set_mode`] refused a mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeError {
+    /// There is no VGA adapter.
+    NoVga,
+    /// The physical-memory mapping, through which the font is loaded,
+    /// isn't set up yet.
+    NotMapped,
+    /// The mode needs a feature this kernel was built without.
+    Disabled,
+}
+
+impl fmt::Display for ModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModeError::NoVga => f.write_str("no vga adapter"),
+            ModeError::NotMapped => f.write_str("physical memory isn't mapped yet"),
+            ModeError::Disabled => f.write_str("built without the vga-80x50 feature"),
+        }
+    }
+}
+
+static MODE: AtomicU8 = AtomicU8::new(TextMode::T80x25 as u8);
+
+/// Physical address of the graphics window font plane 2 is mapped into.
+const FONT_PLANE: u64 = 0xa0000;
+/// Glyphs in the font, and the bytes of font plane 2 each takes.
+const GLYPHS: usize = 256;
+const GLYPH_STRIDE: usize = 32;
+
+/// The 16-scanline font, saved while 80x50 mode has its squashed one
+/// loaded.
+static FONT_16: Mutex<[[u8; 16]; GLYPHS]> = Mutex::new([[0; 16]; GLYPHS]);
+
+/// Sequencer registers.
+const SEQ_RESET: u8 = 0x00;
+const SEQ_MAP_MASK: u8 = 0x02;
+const SEQ_MEMORY_MODE: u8 = 0x04;
+/// Graphics controller registers.
+const GC_READ_MAP: u8 = 0x04;
+const GC_MODE: u8 = 0x05;
+const GC_MISC: u8 = 0x06;
+/// CRT controller register holding the character height less one in its
+/// low five bits.
+const CRTC_MAX_SCAN_LINE: u8 = 0x09;
+
+/// Return the text mode the screen is in.
+pub fn mode() -> TextMode {
+    TextMode::from_u8(MODE.load(Ordering::Relaxed))
+}
+
+/// Switch the screen to `mode`, clearing every terminal and putting its
+/// cursor at the start of the last row. Nothing happens if the screen is
+/// in `mode` already.
+pub fn set_mode(mode: TextMode) -> Result<(), ModeError> {
+    if mode == TextMode::T80x50 && !cfg!(feature = "vga-80x50") {
+        return Err(ModeError::Disabled);
+    }
+    let offset = *crate::memory::PHYS_OFFSET.try_get().map_err(|_| ModeError::NotMapped)?;
+    let plane: *mut u8 = (offset + FONT_PLANE).as_mut_ptr();
+    vt::lock_all(|writers| {
+        let old = self::mode();
+        if old == mode {
+            return Ok(());
+        }
+        let ports = writers.iter().find_map(|writer| writer.ports.as_ref());
+        let ports = ports.ok_or(ModeError::NoVga)?;
+        // SAFETY: every writer is locked, so nothing draws while the font
+        // plane is mapped in, at `plane`.
+        unsafe { with_font_plane(ports, || load_font(plane, mode)) };
+        let height = mode.font_height();
+        let scan_line = ports.read_crtc(CRTC_MAX_SCAN_LINE);
+        ports.write_crtc(CRTC_MAX_SCAN_LINE, (scan_line & 0xE0) | (height - 1));
+        for writer in writers.iter_mut() {
+            // SAFETY: VGA memory and the terminals' buffers in RAM all hold
+            // `MAX_BUFFER_HEIGHT` rows.
+            unsafe { writer.set_height(mode.rows()) };
+            writer.enable_cursor(height - 2, height - 1);
+        }
+        MODE.store(mode as u8, Ordering::Relaxed);
+        Ok(())
+    })
+    .unwrap_or(Err(ModeError::NoVga))
+}
+
+/// Run `f` with font plane 2 mapped at [`FONT_PLANE`] for plain reads
+/// and writes, then map the text buffer back.
+///
+/// # Safety
+///
+/// Nothing may touch VGA memory until this returns.
+unsafe fn with_font_plane<R>(ports: &VgaPorts, f: impl FnOnce() -> R) -> R {
+    // SAFETY: nothing draws until the text layout is back, as the caller
+    // promises.
+    unsafe {
+        ports.write_seq(SEQ_RESET, 0x01);
+        // Write plane 2 only, addressed sequentially.
+        ports.write_seq(SEQ_MAP_MASK, 0x04);
+        ports.write_seq(SEQ_MEMORY_MODE, 0x07);
+        ports.write_seq(SEQ_RESET, 0x03);
+        // Read plane 2, without odd/even, from 0xa0000.
+        ports.write_gc(GC_READ_MAP, 0x02);
+        ports.write_gc(GC_MODE, 0x00);
+        ports.write_gc(GC_MISC, 0x04);
+        let result = f();
+        // Back to text: planes 0 and 1 with odd/even, at 0xb8000.
+        ports.write_seq(SEQ_RESET, 0x01);
+        ports.write_seq(SEQ_MAP_MASK, 0x03);
+        ports.write_seq(SEQ_MEMORY_MODE, 0x03);
+        ports.write_seq(SEQ_RESET, 0x03);
+        ports.write_gc(GC_READ_MAP, 0x00);
+        ports.write_gc(GC_MODE, 0x10);
+        ports.write_gc(GC_MISC, 0x0E);
+        result
+    }
+}
+
+/// Load the font for `mode` into font plane 2.
+///
+/// # Safety
+///
+/// The plane must be mapped at `plane`, as in [`with_font_plane`].
+unsafe fn load_font(plane: *mut u8, mode: TextMode) {
+    let mut font = FONT_16.lock();
+    for (index, glyph) in font.iter_mut().enumerate() {
+        let at = plane.wrapping_add(index * GLYPH_STRIDE);
+        // SAFETY: every glyph lies inside the plane.
+        unsafe {
+            match mode {
+                TextMode::T80x50 => {
+                    for (line, byte) in glyph.iter_mut().enumerate() {
+                        *byte = ptr::read_volatile(at.add(line));
+                    }
+                    for line in 0..8 {
+                        let squashed = glyph[2 * line] | glyph[2 * line + 1];
+                        ptr::write_volatile(at.add(line), squashed);
+                    }
+                }
+                TextMode::T80x25 => {
+                    for (line, &byte) in glyph.iter().enumerate() {
+                        ptr::write_volatile(at.add(line), byte);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_mode_names_and_rows() {
+    assert_eq!((TextMode::T80x25.rows(), TextMode::T80x50.rows()), (25, 50));
+    assert_eq!(alloc::format!("{}", TextMode::T80x50), "80x50");
+    assert_eq!(TextMode::from_u8(TextMode::T80x50 as u8), TextMode::T80x50);
+    assert_eq!(mode(), TextMode::T80x25);
+    assert_eq!(set_mode(TextMode::T80x25), Ok(()));
+    if !cfg!(feature = "vga-80x50") {
+        assert_eq!(set_mode(TextMode::T80x50), Err(ModeError::Disabled));
+    }
+}
+
+#[cfg(feature = "vga-80x50")]
+#[test_case]
+fn test_switch_to_80x50_and_back() {
+    use super::WRITER;
+
+    set_mode(TextMode::T80x50).unwrap();
+    assert_eq!(mode(), TextMode::T80x50);
+    assert_eq!(WRITER.get().lock().height(), MAX_BUFFER_HEIGHT);
+    assert_eq!(vt::terminal(1).unwrap().lock().height(), MAX_BUFFER_HEIGHT);
+    crate::println!("on fifty rows");
+    let writer = WRITER.get().lock();
+    assert!(writer.row_text(MAX_BUFFER_HEIGHT - 2).unwrap().starts_with(b"on fifty rows"));
+    drop(writer);
+
+    set_mode(TextMode::T80x25).unwrap();
+    let writer = WRITER.get().lock();
+    assert_eq!(writer.height(), BUFFER_HEIGHT);
+    assert!(!super::screen_contains("on fifty rows"));
+    assert_eq!(writer.hardware_cursor(), Some(((BUFFER_HEIGHT - 1) * writer.width()) as u16));
+}
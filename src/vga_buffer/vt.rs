@@ -0,0 +1,244 @@
+//! Virtual terminals: [`VT_COUNT`] screens sharing the one VGA buffer.
+//!
+//! Terminal 0 is [`WRITER`]; the others are writers of the same size set
+//! up by [`init`]. Each has its own colors, cursor, scrollback and text,
+//! but only the [`current`] one draws into VGA memory. The rest draw into
+//! text buffers in RAM laid out the same way. [`switch_to`] swaps the two
+//! buffers' contents and then the buffers themselves, so the whole screen
+//! is redrawn from the target's text, and hands it the hardware cursor.
+//! Alt+F1 to Alt+F4 call it from the [keyboard](crate::task::keyboard).
+//!
+//! `print!` output goes to the current terminal; [`write_to`] writes to
+//! any of them. The [status bar](crate::statusbar) and
+//! [`kprintln!`](crate::kprintln) stay on terminal 0.
+//!
+//! A switch holds both terminals' locks, taken in terminal order, so it
+//! never lands inside another CPU's write, and the locks keep interrupts
+//! off, so a keyboard interrupt can't land inside one on this CPU. Output
+//! whose terminal was picked before a switch finishes there.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Render, Writer, BUFFER_WIDTH, MAX_BUFFER_HEIGHT, WRITER};
+use crate::sync::{Global, GlobalError, IrqMutex, IrqMutexGuard};
+
+/// Number of virtual terminals, counting [`WRITER`].
+pub const VT_COUNT: usize = 4;
+
+/// Terminals 1 and up; terminal 0 is [`WRITER`].
+static TERMINALS: [Global<IrqMutex<Writer>>; VT_COUNT - 1] =
+    [Global::new("VT1"), Global::new("VT2"), Global::new("VT3")];
+const NAMES: [&str; VT_COUNT - 1] = ["VT1", "VT2", "VT3"];
+
+/// The terminal on the screen.
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+/// Held across a switch, so two can't both swap out the same terminal.
+static SWITCH: IrqMutex<()> = IrqMutex::named("VT switch", ());
+
+/// Cells of the largest screen, in 80x50 [mode](super::mode).
+const CELLS: usize = BUFFER_WIDTH * MAX_BUFFER_HEIGHT;
+
+/// Text buffers for the terminals that aren't on the screen. A switch
+/// trades one for VGA memory, so there is one fewer than terminals.
+struct Backing(UnsafeCell<[[u16; CELLS]; VT_COUNT - 1]>);
+
+// SAFETY: `init` hands each buffer to one writer, and after that it is only
+// used through that writer's lock.
+unsafe impl Sync for Backing {}
+
+static BACKING: Backing = Backing(UnsafeCell::new([[0; CELLS]; VT_COUNT - 1]));
+
+/// Why a terminal couldn't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VtError {
+    /// There is no terminal with this number.
+    NoSuchTerminal(usize),
+    /// There is no VGA adapter, so no terminals.
+    NoVga,
+}
+
+impl fmt::Display for VtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VtError::NoSuchTerminal(id) => write!(f, "no virtual terminal {}", id),
+            VtError::NoVga => f.write_str("no vga adapter"),
+        }
+    }
+}
+
+/// Set up terminals 1 and up, blank, the size of [`WRITER`].
+pub(super) fn init(width: usize, height: usize) -> Result<(), GlobalError> {
+    for (index, terminal) in TERMINALS.iter().enumerate() {
+        let cells = BACKING.0.get().cast::<[u16; CELLS]>().wrapping_add(index);
+        // SAFETY: the buffer is static, holds the largest screen, and no
+        // other writer is given it.
+        let mut writer = unsafe { Writer::new(cells.cast(), width, height) };
+        writer.clear_screen();
+        writer.set_render(Render::Buffered);
+        terminal.init(IrqMutex::named(NAMES[index], writer))?;
+        crate::emergency::register(terminal);
+    }
+    Ok(())
+}
+
+/// Return terminal `id`'s writer, if there is one.
+pub fn terminal(id: usize) -> Option<&'static IrqMutex<Writer>> {
+    match id {
+        0 => WRITER.try_get().ok(),
+        _ => TERMINALS.get(id - 1)?.try_get().ok(),
+    }
+}
+
+/// Return the number of the terminal on the screen.
+pub fn current() -> usize {
+    CURRENT.load(Ordering::Acquire)
+}
+
+/// Return the writer of the terminal on the screen, which `print!` uses.
+pub fn current_terminal() -> Option<&'static IrqMutex<Writer>> {
+    terminal(current())
+}
+
+/// Write `args` to terminal `id`, on the screen or not.
+pub fn write_to(id: usize, args: fmt::Arguments) -> Result<(), VtError> {
+    use core::fmt::Write;
+
+    let writer = lookup(id)?;
+    let _ = writer.lock().write_fmt(args);
+    Ok(())
+}
+
+/// Put terminal `id` on the screen.
+pub fn switch_to(id: usize) -> Result<(), VtError> {
+    let target = lookup(id)?;
+    let _switching = SWITCH.lock();
+    let shown = current();
+    if shown == id {
+        return Ok(());
+    }
+    let source = lookup(shown)?;
+    // Always the lower terminal first, for a consistent lock order.
+    let (mut from, mut to) = if shown < id {
+        let from = source.lock();
+        (from, target.lock())
+    } else {
+        let to = target.lock();
+        (source.lock(), to)
+    };
+    swap_screens(&mut from, &mut to);
+    CURRENT.store(id, Ordering::Release);
+    Ok(())
+}
+
+fn lookup(id: usize) -> Result<&'static IrqMutex<Writer>, VtError> {
+    if !super::is_present() {
+        return Err(VtError::NoVga);
+    }
+    terminal(id).ok_or(VtError::NoSuchTerminal(id))
+}
+
+/// Move `to` onto the screen `from` is on, and `from` into `to`'s RAM.
+fn swap_screens(from: &mut Writer, to: &mut Writer) {
+    from.present();
+    to.present();
+    for (shown, hidden) in from.buffer.iter_mut().zip(to.buffer.iter_mut()) {
+        let c = shown.read();
+        shown.write(hidden.read());
+        hidden.write(c);
+    }
+    core::mem::swap(&mut from.buffer, &mut to.buffer);
+    core::mem::swap(&mut from.ports, &mut to.ports);
+    // The hardware cursor is still where `from` left it.
+    to.cursor = None;
+    to.move_cursor();
+}
+
+/// Run `f` on the writer of the terminal on the screen, with no switch
+/// able to start until it returns. `None` without a VGA adapter.
+pub(super) fn with_screen<R>(f: impl FnOnce(&mut Writer) -> R) -> Option<R> {
+    let _switching = SWITCH.lock();
+    Some(f(&mut current_terminal()?.lock()))
+}
+
+/// Run `f` on every terminal's writer, in terminal order, with no switch
+/// able to start until it returns. `None` without a VGA adapter.
+pub(super) fn lock_all<R>(f: impl FnOnce(&mut [IrqMutexGuard<'_, Writer>]) -> R) -> Option<R> {
+    let _switching = SWITCH.lock();
+    let terminals: [_; VT_COUNT] = core::array::from_fn(terminal);
+    if terminals.iter().any(Option::is_none) {
+        return None;
+    }
+    // `from_fn` goes in index order, the same lock order as `switch_to`.
+    let mut writers: [_; VT_COUNT] = core::array::from_fn(|id| terminals[id].unwrap().lock());
+    Some(f(&mut writers))
+}
+
+/// Put terminal 0 back on the screen for the panic handler.
+///
+/// # Safety
+///
+/// As for [`panic_screen`](super::panic_screen): whoever holds a terminal's
+/// lock, or is switching, must never go on.
+pub(super) unsafe fn reclaim() {
+    for id in [current(), 0] {
+        if let Some(terminal) = terminal(id)
+            && terminal.is_locked()
+        {
+            unsafe { terminal.force_unlock() };
+        }
+    }
+    if SWITCH.is_locked() {
+        unsafe { SWITCH.force_unlock() };
+    }
+    let _ = switch_to(0);
+}
+
+#[test_case]
+fn test_switch_shows_the_terminals_text() {
+    let last = WRITER.get().lock().height() - 1;
+    assert_eq!(current(), 0);
+    WRITER.get().lock().write_string("\non terminal zero");
+    write_to(2, format_args!("\x0con terminal two")).unwrap();
+    assert!(super::screen_contains("on terminal zero"));
+    assert!(!super::screen_contains("on terminal two"));
+
+    switch_to(2).unwrap();
+    assert_eq!(current(), 2);
+    assert!(super::screen_contains("on terminal two"));
+    assert!(!super::screen_contains("on terminal zero"));
+    let two = terminal(2).unwrap().lock();
+    let cursor = last * two.width() + "on terminal two".len();
+    assert_eq!(two.hardware_cursor(), Some(cursor as u16));
+    drop(two);
+    // Terminal 0 keeps its text, and takes more off the screen.
+    WRITER.get().lock().write_string(" still");
+    assert!(!super::screen_contains("zero still"));
+
+    switch_to(0).unwrap();
+    assert!(WRITER.get().lock().row_text(last).unwrap().starts_with(b"on terminal zero still"));
+    assert!(!super::screen_contains("on terminal two"));
+    let two = terminal(2).unwrap().lock();
+    assert!(two.row_text(last).unwrap().starts_with(b"on terminal two"));
+}
+
+#[test_case]
+fn test_print_goes_to_current_terminal() {
+    switch_to(1).unwrap();
+    crate::println!("\nprinted on one");
+    switch_to(0).unwrap();
+    assert!(!super::screen_contains("printed on one"));
+    switch_to(1).unwrap();
+    assert!(super::screen_contains("printed on one"));
+    switch_to(0).unwrap();
+}
+
+#[test_case]
+fn test_bad_terminal_is_refused() {
+    assert_eq!(switch_to(VT_COUNT), Err(VtError::NoSuchTerminal(VT_COUNT)));
+    assert_eq!(write_to(VT_COUNT, format_args!("x")), Err(VtError::NoSuchTerminal(VT_COUNT)));
+    assert_eq!(switch_to(0), Ok(()));
+    assert_eq!(current(), 0);
+}
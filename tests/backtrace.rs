@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+
+use chronos::{entry_point, exit_qemu, serial_print, serial_println, BootInfo, QemuExitCode};
+use core::fmt::{self, Write};
+use core::hint::black_box;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    // Records the boot stack the backtrace has to stay within.
+    chronos::init();
+    serial_print!("backtrace::nested_panic...\t");
+    outer();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    chronos::hlt_loop();
+}
+
+#[inline(never)]
+fn outer() {
+    middle();
+    black_box(());
+}
+
+#[inline(never)]
+fn middle() {
+    inner();
+    black_box(());
+}
+
+#[inline(never)]
+fn inner() {
+    panic!("deliberate panic three calls deep");
+}
+
+/// Copies everything written to serial into a fixed buffer.
+struct Capture {
+    buf: [u8; 2048],
+    len: usize,
+}
+
+impl Write for Capture {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial_print!("{}", s);
+        let end = (self.len + s.len()).min(self.buf.len());
+        self.buf[self.len..end].copy_from_slice(&s.as_bytes()[..end - self.len]);
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    let mut capture = Capture {
+        buf: [0; 2048],
+        len: 0,
+    };
+    serial_println!();
+    chronos::backtrace::print(&mut capture).unwrap();
+
+    let printed = core::str::from_utf8(&capture.buf[..capture.len]).unwrap();
+    let mut addresses = [""; chronos::backtrace::MAX_FRAMES];
+    let mut distinct = 0;
+    for address in printed.split_whitespace().filter(|word| word.starts_with("0x")) {
+        if !addresses[..distinct].contains(&address) {
+            addresses[distinct] = address;
+            distinct += 1;
+        }
+    }
+
+    // At least the panic machinery, inner, middle, outer and main.
+    if distinct >= 5 {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n\nonly {} distinct return addresses", distinct);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    chronos::hlt_loop();
+}
@@ -8,16 +8,7 @@ use core::panic::PanicInfo;
 
 use chronos::println;
 
-#[unsafe(no_mangle)] // don't mangle the name of this function
-pub extern "C" fn _start() -> ! {
-    test_main();
-
-    loop {}
-}
-
-fn test_runner(tests: &[&dyn Fn()]) {
-    unimplemented!();
-}
+chronos::test_entry_point!();
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+
+use chronos::task::executor::Executor;
+use chronos::task::{block_on, Task};
+use chronos::test_framework::message_is;
+use chronos::{entry_point, exit_qemu, serial_print, serial_println, BootInfo, QemuExitCode};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+const EXPECTED: &str = "block_on called from a task; it would stall the executor";
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    chronos::init();
+    chronos::init_memory(boot_info);
+    serial_print!("block_on_misuse::block_on_in_task_panics...\t");
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        block_on(chronos::task::sleep_ticks(1));
+        serial_println!("[test did not panic]");
+        exit_qemu(QemuExitCode::Failed);
+    }));
+    executor.run();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if message_is(info.message(), EXPECTED) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n\nError: {}\nexpected: {}", info, EXPECTED);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    chronos::hlt_loop();
+}
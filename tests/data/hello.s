@@ -0,0 +1,26 @@
+# The user program shipped as /bin/hello in the ramdisk: writes a line to
+# standard output and exits with status 7. tests/user_process.rs runs it.
+#
+# Rebuild after editing with:
+#
+#   as --64 -o /tmp/hello.o tests/data/hello.s
+#   ld -static -nostdlib --build-id=none -z noexecstack -z noseparate-code \
+#      -z max-page-size=4096 -Ttext-segment=0x200000000000 -s \
+#      -o initrd/bin/hello /tmp/hello.o
+
+    .text
+    .global _start
+_start:
+    mov $1, %eax            # write(1, message, length)
+    mov $1, %edi
+    lea message(%rip), %rsi
+    mov $length, %edx
+    int $0x80
+    mov $60, %eax           # exit(7)
+    mov $7, %edi
+    int $0x80
+    hlt                     # exit doesn't return; faults if it does
+
+message:
+    .ascii "hello from user space\n"
+    .set length, . - message
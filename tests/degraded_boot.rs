@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+use chronos::error::KernelError;
+use chronos::init::{self, InitError, Stage};
+use chronos::task::{executor::Executor, Task};
+use chronos::{entry_point, exit_qemu, serial_print, serial_println, BootInfo, QemuExitCode};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+/// Fail the non-critical devices stage and check that boot still reaches
+/// the executor, with the failure on record.
+fn main(boot_info: &'static BootInfo) -> ! {
+    init::inject_failure(Stage::Devices);
+    chronos::init();
+    serial_print!("degraded_boot::devices_failure_is_survivable...\t");
+    chronos::init_memory(boot_info);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(check_report()));
+    executor.run();
+}
+
+async fn check_report() {
+    let report = init::report();
+    let devices = report.get(Stage::Devices).expect("devices stage did not run");
+    assert!(matches!(devices.result, Err(KernelError::Init(InitError::Injected))));
+    assert_eq!(report.failures().count(), 1);
+    // The stages after it still ran.
+    assert!(report.get(Stage::Executor).is_some_and(|record| record.result.is_ok()));
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    exit_qemu(QemuExitCode::Failed);
+    chronos::hlt_loop();
+}
@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+use chronos::interrupts::{self, EarlyFault};
+use chronos::{entry_point, exit_qemu, serial_print, serial_println, BootInfo, QemuExitCode};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+/// Page fault before the full IDT is loaded and check that the early
+/// handler reports it.
+fn main(_boot_info: &'static BootInfo) -> ! {
+    interrupts::init_early();
+    interrupts::set_early_fault_hook(check_fault);
+    chronos::serial::init().expect("serial");
+    serial_print!("early_fault::page_fault_before_init...\t");
+
+    // Nothing is mapped this high in the bootloader's page tables.
+    unsafe { core::ptr::read_volatile(0xdeadbeef000 as *const u64) };
+
+    panic!("Execution continued after the page fault");
+}
+
+fn check_fault(fault: &EarlyFault, message: &str) {
+    assert_eq!(fault.vector, 14);
+    assert_ne!(fault.rip, 0);
+    assert!(message.starts_with("EARLY EXCEPTION: page fault (vector 14) at rip 0x"));
+    // The message went out on COM1 before the hook was called; finish the
+    // test's line after it.
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    chronos::test_panic_handler(info)
+}
@@ -0,0 +1,31 @@
+#![no_std]
+#![no_main]
+
+use chronos::{
+    entry_point, exit_qemu, serial_print, serial_println, BootInfo, DebugExit, IoSize,
+    QemuExitCode,
+};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+/// Exit through an `isa-debug-exit` device with `iosize=0x01` at port 0xf8,
+/// which `tools/runner.sh` adds for this binary along with the matching
+/// `debugexit=` options. [`exit_qemu`] has to pick the byte-wide write
+/// from what it detected, and that has to be enough to end the run.
+fn main(_boot_info: &'static BootInfo) -> ! {
+    chronos::init();
+    serial_print!("exit_qemu_byte::one_byte_device...\t");
+    assert_eq!(chronos::debug_exit(), DebugExit { port: 0xf8, size: IoSize::U8 });
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    panic!("exit_qemu returned without ending the run");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    exit_qemu(QemuExitCode::Failed);
+    chronos::hlt_loop();
+}
@@ -6,32 +6,13 @@
 
 extern crate alloc;
 
-use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use chronos::allocator::HEAP_SIZE;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 
 
-entry_point!(main);
-
-fn main(boot_info: &'static BootInfo) -> ! {
-    use chronos::allocator;
-    use chronos::memory::{self, BootInfoFrameAllocator};
-    use x86_64::VirtAddr;
-
-    chronos::init();
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("heap initialization failed");
-
-    test_main();
-    loop {}
-}
+chronos::test_entry_point!();
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use chronos::sync::Global;
+use chronos::test_framework::message_is;
+use chronos::{entry_point, exit_qemu, serial_print, serial_println, BootInfo, QemuExitCode};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+/// The panic message `dealloc` must produce.
+static EXPECTED: Global<String> = Global::new("EXPECTED");
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    chronos::init();
+    chronos::init_memory(boot_info);
+    serial_print!("heap_canary::overflow_panics_on_free...\t");
+
+    let block: Box<[u8]> = Box::from([7u8; 13]);
+    let data = block.as_ptr() as *mut u8;
+    // Built before the overflow, while the heap is still sound.
+    let expected = alloc::format!(
+        "heap corruption in block {:#x} (13 bytes): trailer canary is \
+         00 be ad de ef be ad de, expected ef be ad de ef be ad de",
+        data as usize
+    );
+    EXPECTED.init(expected).expect("init twice");
+    unsafe { data.add(13).write(0) };
+    drop(block);
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    chronos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let expected = EXPECTED.get().as_str();
+    if message_is(info.message(), expected) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n\nError: {}\nexpected: {}", info, expected);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    chronos::hlt_loop();
+}
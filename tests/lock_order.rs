@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+
+use chronos::sync::IrqMutex;
+use chronos::{entry_point, exit_qemu, serial_print, serial_println, BootInfo, QemuExitCode};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+static A: IrqMutex<u32> = IrqMutex::named("A", 0);
+static B: IrqMutex<u32> = IrqMutex::named("B", 0);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    chronos::init();
+    a_then_b();
+    b_then_a();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    chronos::hlt_loop();
+}
+
+fn a_then_b() {
+    serial_print!("lock_order::a_then_b...\t");
+    let a = A.lock();
+    let b = B.lock();
+    drop(b);
+    drop(a);
+    // Taking either alone, or B after A again, is fine.
+    drop(B.lock());
+    let a = A.lock();
+    drop(B.lock());
+    drop(a);
+    serial_println!("[ok]");
+}
+
+fn b_then_a() {
+    serial_print!("lock_order::b_then_a_panics...\t");
+    let _b = B.lock();
+    let _a = A.lock();
+}
+
+/// Checks that what is written starts with a prefix.
+struct StartsWith {
+    rest: &'static str,
+    ok: bool,
+}
+
+impl Write for StartsWith {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.rest.len());
+        self.ok &= s.as_bytes()[..n] == self.rest.as_bytes()[..n];
+        self.rest = &self.rest[n..];
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = StartsWith { rest: "lock order inversion: A vs B\n", ok: true };
+    let _ = write!(message, "{}", info.message());
+    if message.ok && message.rest.is_empty() {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n\nError: {}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    chronos::hlt_loop();
+}
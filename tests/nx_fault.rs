@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use chronos::sync::Global;
+use chronos::test_framework::message_is;
+use chronos::{entry_point, exit_qemu, serial_print, serial_println, BootInfo, QemuExitCode};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+/// The panic message the page fault handler must produce.
+static EXPECTED: Global<String> = Global::new("EXPECTED");
+
+/// Jump into a heap buffer holding a `ret` and check that the CPU refuses
+/// to run it.
+fn main(boot_info: &'static BootInfo) -> ! {
+    chronos::init();
+    chronos::init_memory(boot_info);
+    serial_print!("nx_fault::heap_is_not_executable...\t");
+
+    let code: Box<[u8; 16]> = Box::new([0xc3; 16]);
+    let addr = code.as_ptr() as usize;
+    let expected = alloc::format!("attempted execution of non-executable memory at {:#x}", addr);
+    EXPECTED.init(expected).expect("init twice");
+    // SAFETY: it isn't; the fetch should fault before anything runs.
+    let function: extern "C" fn() = unsafe { core::mem::transmute(addr) };
+    function();
+
+    serial_println!("[heap code ran]");
+    exit_qemu(QemuExitCode::Failed);
+    chronos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let expected = EXPECTED.get().as_str();
+    if message_is(info.message(), expected) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n\nError: {}\nexpected: {}", info, expected);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    chronos::hlt_loop();
+}
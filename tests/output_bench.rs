@@ -0,0 +1,130 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(chronos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+//! Output throughput benchmarks, gated against the baselines below.
+//!
+//! The baselines are cycles per iteration under QEMU with TCG. They are
+//! deliberately generous ceilings, not measurements: roughly ten times
+//! what each workload's port and VGA memory accesses should cost under
+//! TCG, so only a gross regression fails while the gate stays armed.
+//! Until they are tightened, each run prints a note that its benchmark is
+//! well under its baseline; copy the `cycles_per_iter` it prints into the
+//! matching constant then, and again when a change makes output faster or
+//! slower on purpose. A benchmark may be left at `None`, reporting only,
+//! if a comment says why it can't be gated.
+
+extern crate alloc;
+
+use chronos::bench::{self, Baseline, Measurement};
+use chronos::serial::{self, SERIAL1};
+use chronos::vga_buffer::{Render, WRITER};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+chronos::test_entry_point!();
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    chronos::test_panic_handler(info)
+}
+
+const LINES: u64 = 10_000;
+const FRAMES: u64 = 100;
+
+/// A line and, once the screen is full, a scroll of VGA memory.
+const VGA_DIRECT: Option<u64> = Some(20_000_000);
+const VGA_BUFFERED: Option<u64> = Some(20_000_000);
+/// About 70 bytes, each a write to the UART and a poll of its status.
+const SERIAL_UNBUFFERED: Option<u64> = Some(10_000_000);
+const SERIAL_BUFFERED: Option<u64> = Some(10_000_000);
+/// A clear and a full screen of VGA memory.
+const REDRAW_DIRECT: Option<u64> = Some(100_000_000);
+const REDRAW_BUFFERED: Option<u64> = Some(100_000_000);
+
+/// Gate `run` against its baseline, or just report it if it has none.
+fn check(run: &Measurement, baseline: Option<u64>) {
+    match baseline {
+        Some(cycles_per_iter) => {
+            bench::gate(run, &Baseline { cycles_per_iter, defined_in: file!() });
+        }
+        None => bench::report(run, file!()),
+    }
+}
+
+/// Write [`LINES`] lines to the screen, one call each.
+fn vga_lines(name: &'static str, render: Render, expected: Option<u64>) {
+    let mut writer = WRITER.get().lock();
+    let previous = writer.render();
+    writer.set_render(render);
+    let run = bench::measure(name, LINES, |i| {
+        writeln!(writer, "bench line {:05} the quick brown fox jumps over the lazy dog", i)
+            .unwrap();
+    });
+    writer.set_render(previous);
+    drop(writer);
+    check(&run, expected);
+}
+
+#[test_case]
+fn vga_direct() {
+    vga_lines("vga-direct", Render::Direct, VGA_DIRECT);
+}
+
+#[test_case]
+fn vga_buffered() {
+    vga_lines("vga-buffered", Render::Buffered, VGA_BUFFERED);
+}
+
+/// Clear the screen and fill every row, [`FRAMES`] times.
+fn redraw(name: &'static str, render: Render, expected: Option<u64>) {
+    let mut frame = alloc::string::String::from("\x0c");
+    for row in 0..25 {
+        let fill = (b'a' + row as u8) as char;
+        frame.extend(core::iter::repeat_n(fill, 79));
+        if row < 24 {
+            frame.push('\n');
+        }
+    }
+    let mut writer = WRITER.get().lock();
+    let previous = writer.render();
+    writer.set_render(render);
+    let run = bench::measure(name, FRAMES, |_| writer.write_string(&frame));
+    writer.set_render(previous);
+    drop(writer);
+    check(&run, expected);
+}
+
+#[test_case]
+fn redraw_direct() {
+    redraw("redraw-direct", Render::Direct, REDRAW_DIRECT);
+}
+
+#[test_case]
+fn redraw_buffered() {
+    redraw("redraw-buffered", Render::Buffered, REDRAW_BUFFERED);
+}
+
+/// Print [`LINES`] lines to COM1, one call each.
+fn serial_lines(name: &'static str, buffered: bool, expected: Option<u64>) {
+    assert!(SERIAL1.is_initialized());
+    let previous = serial::tx_buffering();
+    serial::set_tx_buffering(buffered);
+    let run = bench::measure(name, LINES, |i| {
+        chronos::serial_println!("bench line {:05} the quick brown fox jumps over the lazy dog", i);
+    });
+    serial::set_tx_buffering(previous);
+    check(&run, expected);
+}
+
+#[test_case]
+fn serial_unbuffered() {
+    serial_lines("serial-unbuffered", false, SERIAL_UNBUFFERED);
+}
+
+#[test_case]
+fn serial_buffered() {
+    serial_lines("serial-buffered", true, SERIAL_BUFFERED);
+}
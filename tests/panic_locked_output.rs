@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+use chronos::serial::SERIAL1;
+use chronos::test_framework::{record_serial, serial_sent};
+use chronos::vga_buffer::WRITER;
+use chronos::{entry_point, exit_qemu, serial_print, serial_println, BootInfo, QemuExitCode};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+const MESSAGE: &str = "panicked with the output locks held";
+
+/// Panic while holding the VGA writer and serial port locks. Without the
+/// emergency path, the panic handler would spin on them forever and the run
+/// would end in a timeout with no output.
+fn main(_boot_info: &'static BootInfo) -> ! {
+    chronos::init();
+    serial_print!("panic_locked_output::message_reaches_host...\t");
+    record_serial(true);
+    core::mem::forget(WRITER.get().lock());
+    core::mem::forget(SERIAL1.get().lock());
+    panic!("{}", MESSAGE);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // SAFETY: main never resumes.
+    unsafe { chronos::report_panic(info) };
+    record_serial(false);
+
+    // The handler's own first line, and the locks it had to release.
+    let message = serial_sent("kernel panic: panicked at tests/panic_locked_output.rs:")
+        && serial_sent(MESSAGE);
+    let released = serial_sent("released output locks: [")
+        && serial_sent("\"WRITER\"")
+        && serial_sent("\"SERIAL1\"");
+    if message && released {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n\nError: {}\nsent: {}, released: {}", info, message, released);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    chronos::hlt_loop();
+}
@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+
+use chronos::test_framework::{record_serial, serial_sent};
+use chronos::vga_buffer::{self, Color, PANIC_BANNER_ROWS, WRITER};
+use chronos::{entry_point, exit_qemu, println, serial_print, serial_println, BootInfo, QemuExitCode};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+const MESSAGE: &str = "panicked with the writer locked";
+
+/// Panic while holding the VGA writer's lock, which the panic screen has to
+/// take anyway.
+fn main(_boot_info: &'static BootInfo) -> ! {
+    chronos::init();
+    serial_print!("panic_screen::banner_is_drawn...\t");
+    record_serial(true);
+    core::mem::forget(WRITER.get().lock());
+    panic!("{}", MESSAGE);
+}
+
+/// Return whether `row` of the screen contains `text`.
+fn row_contains(row: usize, text: &str) -> bool {
+    let row = WRITER.get().lock().row_text(row).unwrap_or([0; vga_buffer::BUFFER_WIDTH]);
+    row.windows(text.len()).any(|window| window == text.as_bytes())
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // SAFETY: main never resumes.
+    unsafe { chronos::report_panic(info) };
+    record_serial(false);
+    // Output afterwards scrolls under the banner.
+    for _ in 0..vga_buffer::BUFFER_HEIGHT {
+        println!("more panic output");
+    }
+
+    let drawn = row_contains(1, "KERNEL PANIC")
+        && row_contains(3, MESSAGE)
+        && (0..PANIC_BANNER_ROWS).any(|row| row_contains(row, "panic_screen.rs:"))
+        && (0..PANIC_BANNER_ROWS).any(|row| row_contains(row, "system halted"));
+    let (colors, reserved) = {
+        let writer = WRITER.get().lock();
+        (writer.char_at(0, 0), writer.reserved_rows())
+    };
+    let reported =
+        serial_sent("kernel panic: panicked at tests/panic_screen.rs:") && serial_sent(MESSAGE);
+    let banner = colors == Some((b' ', Color::White, Color::Red)) && reserved == PANIC_BANNER_ROWS;
+    if drawn && banner && reported {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!(
+            "[failed]\n\nError: {}\ncorner: {:?}, reserved: {}, sent: {}",
+            info,
+            colors,
+            reserved,
+            reported
+        );
+        exit_qemu(QemuExitCode::Failed);
+    }
+    chronos::hlt_loop();
+}
@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+
+use chronos::{entry_point, exit_qemu, power, serial_print, serial_println, BootInfo, QemuExitCode};
+use core::panic::PanicInfo;
+use x86_64::instructions::port::Port;
+
+entry_point!(main);
+
+/// CMOS register used to count boots, one of the bytes the firmware leaves
+/// alone. QEMU keeps CMOS contents across a reset but starts every run with
+/// them zeroed.
+const BOOT_STAGE_REGISTER: u8 = 0x7e;
+
+/// A reset can't be observed from outside through bootimage, which passes
+/// the same QEMU arguments to every test (and a global `-no-reboot` would
+/// make any test that triple faults look like a clean exit). Instead the
+/// test counts its own boots: the first one reboots through
+/// `power::reboot`, the second through `power::triple_fault`, and the third
+/// reports success.
+fn main(_boot_info: &'static BootInfo) -> ! {
+    chronos::serial::init().expect("serial");
+    match read_cmos(BOOT_STAGE_REGISTER) {
+        0 => {
+            serial_print!("reboot::keyboard_controller_reset...\t");
+            write_cmos(BOOT_STAGE_REGISTER, 1);
+            power::reboot();
+        }
+        1 => {
+            serial_println!("[ok]");
+            serial_print!("reboot::triple_fault_reset...\t");
+            write_cmos(BOOT_STAGE_REGISTER, 2);
+            power::triple_fault();
+        }
+        2 => {
+            serial_println!("[ok]");
+            write_cmos(BOOT_STAGE_REGISTER, 0);
+            exit_qemu(QemuExitCode::Success);
+        }
+        stage => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: unexpected boot stage {}\n", stage);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+    chronos::hlt_loop();
+}
+
+fn read_cmos(register: u8) -> u8 {
+    // Bit 7 of the index keeps NMIs disabled during the access.
+    unsafe {
+        Port::<u8>::new(0x70).write(0x80 | register);
+        Port::<u8>::new(0x71).read()
+    }
+}
+
+fn write_cmos(register: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(0x70).write(0x80 | register);
+        Port::<u8>::new(0x71).write(value);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    exit_qemu(QemuExitCode::Failed);
+    chronos::hlt_loop();
+}
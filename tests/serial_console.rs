@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+
+use chronos::console::{self, Console};
+use chronos::{entry_point, exit_qemu, memory, println, serial, serial_print, serial_println};
+use chronos::{vga_buffer, BootInfo, QemuExitCode};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+/// The VGA text buffer, 80x25 cells of two bytes.
+const VGA: usize = 0xb8000;
+const VGA_LEN: usize = 80 * 25 * 2;
+
+/// Boot as if there were no VGA adapter and check that console output goes
+/// to COM1 and nothing touches the text buffer.
+fn main(boot_info: &'static BootInfo) -> ! {
+    vga_buffer::force_absent();
+    let before = snapshot();
+    chronos::init();
+    serial_print!("serial_console::println_reaches_host...\t");
+    chronos::init_memory(boot_info);
+
+    // Unmap the buffer, so a stray write faults instead of going unseen.
+    let page = VirtAddr::new(VGA as u64);
+    let flags = memory::page_flags(page).expect("vga buffer mapped");
+    unsafe { memory::replace_page_flags(page, flags - PageTableFlags::PRESENT) };
+
+    assert!(!vga_buffer::is_present());
+    assert_eq!(console::selected(), Console::Serial);
+    assert_eq!(console::select(Console::Vga), Err(console::ConsoleError::NoVga));
+    let sent = serial::bytes_sent();
+    println!("headless console");
+    assert!(serial::bytes_sent() >= sent + "headless console\n".len() as u64);
+
+    unsafe { memory::replace_page_flags(page, flags) };
+    assert!(snapshot() == before, "text buffer written");
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    chronos::hlt_loop();
+}
+
+/// Copy the text buffer, through the bootloader's identity mapping.
+fn snapshot() -> [u8; VGA_LEN] {
+    let mut copy = [0; VGA_LEN];
+    for (i, byte) in copy.iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile((VGA + i) as *const u8) };
+    }
+    copy
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    exit_qemu(QemuExitCode::Failed);
+    chronos::hlt_loop();
+}
@@ -1,11 +1,13 @@
 #![no_std]
 #![no_main]
 
-use chronos::{QemuExitCode, exit_qemu, serial_print, serial_println};
+use chronos::{entry_point, exit_qemu, serial_print, serial_println, BootInfo, QemuExitCode};
 use core::panic::PanicInfo;
 
-#[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    chronos::serial::init().expect("serial");
     should_fail();
     serial_println!("[test did not panic]");
     exit_qemu(QemuExitCode::Failed);
@@ -0,0 +1,32 @@
+#![no_std]
+#![no_main]
+
+use chronos::power::{self, ShutdownMethod};
+use chronos::{entry_point, exit_qemu, serial_print, serial_println, BootInfo, QemuExitCode};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+/// Power off through ACPI alone. QEMU exits with status 0 on an ACPI
+/// poweroff, which bootimage passes through as a success; if the write has
+/// no effect, the kernel halts and the run hits the test timeout.
+fn main(_boot_info: &'static BootInfo) -> ! {
+    chronos::init();
+    serial_print!("shutdown::acpi_poweroff...\t");
+    serial_println!("[ok]");
+    let acpi_only = [power::SHUTDOWN_METHODS[0], power::SHUTDOWN_METHODS[1]];
+    assert!(
+        acpi_only
+            .iter()
+            .all(|method| matches!(method, ShutdownMethod::Pm1aControl { .. }))
+    );
+    power::shutdown_with(&acpi_only);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    exit_qemu(QemuExitCode::Failed);
+    chronos::hlt_loop();
+}
@@ -2,16 +2,18 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
-use chronos::{QemuExitCode, exit_qemu, serial_print, serial_println};
+use chronos::{entry_point, exit_qemu, serial_print, serial_println, BootInfo, QemuExitCode};
 use core::panic::PanicInfo;
-use lazy_static::lazy_static;
+use chronos::sync::Global;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
-#[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    chronos::serial::init().expect("serial");
     serial_print!("stack_overflow::stack_overflow...\t");
 
-    chronos::gdt::init();
+    chronos::gdt::init().expect("gdt");
     init_test_idt();
 
     // trigger a stack overflow
@@ -26,21 +28,16 @@ fn stack_overflow() {
     volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
 }
 
-lazy_static! {
-    static ref TEST_IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(test_double_fault_handler)
-                .set_stack_index(chronos::gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-
-        idt
-    };
-}
+static TEST_IDT: Global<InterruptDescriptorTable> = Global::new("TEST_IDT");
 
 pub fn init_test_idt() {
-    TEST_IDT.load();
+    let mut idt = InterruptDescriptorTable::new();
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(test_double_fault_handler)
+            .set_stack_index(chronos::gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    TEST_IDT.init(idt).expect("idt").load();
 }
 
 extern "x86-interrupt" fn test_double_fault_handler(
@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(chronos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use core::panic::PanicInfo;
+use chronos::loader::elf;
+use chronos::memory::{AddressSpace, FRAME_ALLOCATOR};
+use chronos::process::{self, ExitStatus};
+
+chronos::test_entry_point!();
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    chronos::test_panic_handler(info)
+}
+
+/// Run `/bin/hello` (built from `tests/data/hello.s`) from the ramdisk: it
+/// prints a line through the `write` system call and exits with status 7.
+#[test_case]
+fn test_hello_runs_in_user_mode() {
+    let root = chronos::fs::root().expect("no root filesystem");
+    let program = root.open("/bin/hello").expect("/bin/hello missing from the ramdisk");
+
+    let mut frames = FRAME_ALLOCATOR.get().lock();
+    let mut space = AddressSpace::new(&mut *frames).unwrap();
+    let image = elf::load(program.as_slice(), &mut space, &mut *frames).unwrap();
+    drop(frames);
+
+    assert_eq!(process::run(image, space), Ok(ExitStatus::Exited(7)));
+    let mut log = String::new();
+    chronos::klog::dump(&mut log).unwrap();
+    assert!(log.contains("hello from user space\n"), "output missing from the log");
+}
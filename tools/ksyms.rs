@@ -0,0 +1,690 @@
+//! Fill a kernel binary's `.ksyms` section with its function symbols.
+//!
+//! Usage: `ksyms <kernel ELF>`. The runner (`tools/runner.sh`) calls this
+//! on every kernel and test binary before booting it; see
+//! `src/symbols.rs` in the kernel for the table layout.
+//!
+//! The section has a fixed size, so filling it changes no addresses: the
+//! symbols read from `.symtab` are the final ones. Names are demangled,
+//! from both the legacy and the v0 scheme, without hashes, lifetimes or
+//! the generic arguments of functions, and cut at `MAX_NAME` bytes. Where several symbols share an address the first one
+//! is kept.
+//!
+//! Built by the runner with plain `rustc`, so it has no dependencies.
+
+use std::env;
+use std::fs;
+use std::process::exit;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+const MAX_NAME: usize = 128;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        eprintln!("usage: ksyms <kernel ELF>");
+        exit(2);
+    }
+    let path = &args[1];
+    let mut image = fs::read(path).unwrap_or_else(|err| fail(&format!("{}: {}", path, err)));
+    match embed(&mut image) {
+        Ok(count) => {
+            fs::write(path, &image).unwrap_or_else(|err| fail(&format!("{}: {}", path, err)));
+            eprintln!("ksyms: {} symbols embedded in {}", count, path);
+        }
+        Err(err) => fail(&format!("{}: {}", path, err)),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("ksyms: {}", message);
+    exit(1);
+}
+
+struct Section {
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+    name: usize,
+}
+
+/// Write the table into `image` and return how many symbols it holds.
+fn embed(image: &mut [u8]) -> Result<usize, String> {
+    if image.len() < 64 || &image[..4] != b"\x7fELF" || image[4] != 2 || image[5] != 1 {
+        return Err("not a little-endian ELF64 file".into());
+    }
+    let section_offset = u64_at(image, 0x28)? as usize;
+    let entry_size = u16_at(image, 0x3a)? as usize;
+    let count = u16_at(image, 0x3c)? as usize;
+    let names_index = u16_at(image, 0x3e)? as usize;
+    let sections = (0..count)
+        .map(|index| {
+            let at = section_offset + index * entry_size;
+            Ok(Section {
+                name: u32_at(image, at)? as usize,
+                kind: u32_at(image, at + 4)?,
+                offset: u64_at(image, at + 0x18)? as usize,
+                size: u64_at(image, at + 0x20)? as usize,
+                link: u32_at(image, at + 0x28)? as usize,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let section_names = sections.get(names_index).ok_or("no section name table")?;
+    let name_of = |section: &Section| c_str(image, section_names.offset + section.name);
+
+    let table = sections
+        .iter()
+        .find(|section| name_of(section) == Some(".ksyms"))
+        .ok_or("no .ksyms section; is this a chronos kernel?")?;
+    let symtab = sections
+        .iter()
+        .find(|section| section.kind == SHT_SYMTAB)
+        .ok_or("no symbol table; was the binary stripped?")?;
+    let strtab = sections.get(symtab.link).ok_or("bad symbol string table")?;
+
+    let mut symbols = Vec::new();
+    for at in (symtab.offset..symtab.offset + symtab.size).step_by(24) {
+        let info = *image.get(at + 4).ok_or("symbol table truncated")?;
+        let shndx = u16_at(image, at + 6)?;
+        let value = u64_at(image, at + 8)?;
+        let size = u64_at(image, at + 16)?;
+        if info & 0xf != STT_FUNC || shndx == 0 || value == 0 {
+            continue;
+        }
+        let name = c_str(image, strtab.offset + u32_at(image, at)? as usize)
+            .ok_or("bad symbol name")?;
+        symbols.push((value, size.min(u32::MAX.into()) as u32, truncate(demangle(name))));
+    }
+    symbols.sort_by_key(|&(addr, _, _)| addr);
+    symbols.dedup_by_key(|&mut (addr, _, _)| addr);
+
+    let names_offset = HEADER_SIZE + symbols.len() * ENTRY_SIZE;
+    let names_len: usize = symbols.iter().map(|(_, _, name)| 1 + name.len()).sum();
+    if names_offset + names_len > table.size {
+        return Err(format!(
+            "symbol table needs {} bytes but .ksyms has {}; raise symbols::TABLE_SIZE",
+            names_offset + names_len,
+            table.size
+        ));
+    }
+
+    let mut blob = Vec::with_capacity(table.size);
+    blob.extend_from_slice(MAGIC);
+    blob.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    blob.extend_from_slice(&(names_offset as u32).to_le_bytes());
+    blob.extend_from_slice(&(names_len as u32).to_le_bytes());
+    let mut name_at = 0u32;
+    for (addr, size, name) in &symbols {
+        blob.extend_from_slice(&addr.to_le_bytes());
+        blob.extend_from_slice(&size.to_le_bytes());
+        blob.extend_from_slice(&name_at.to_le_bytes());
+        name_at += 1 + name.len() as u32;
+    }
+    for (_, _, name) in &symbols {
+        blob.push(name.len() as u8);
+        blob.extend_from_slice(name.as_bytes());
+    }
+    blob.resize(table.size, 0);
+    image[table.offset..table.offset + table.size].copy_from_slice(&blob);
+    Ok(symbols.len())
+}
+
+/// Demangle a Rust symbol, in either mangling scheme. Other names, and
+/// names that don't parse, are returned unchanged.
+fn demangle(name: &str) -> String {
+    let symbol = name.split(".llvm.").next().unwrap_or(name);
+    if let Some(rest) = symbol.strip_prefix("_R") {
+        return V0::demangle(rest).unwrap_or_else(|| name.to_string());
+    }
+    demangle_legacy(name, symbol)
+}
+
+/// Undo the legacy mangling (`_ZN` length-prefixed parts `E`), dropping
+/// the trailing hash.
+fn demangle_legacy(name: &str, symbol: &str) -> String {
+    let Some(mut rest) = symbol.strip_prefix("_ZN") else {
+        return name.to_string();
+    };
+    let mut parts = Vec::new();
+    while let Some(digits) = rest.find(|c: char| !c.is_ascii_digit()).filter(|&end| end > 0) {
+        let Ok(len) = rest[..digits].parse::<usize>() else {
+            return name.to_string();
+        };
+        let Some(part) = rest.get(digits..digits + len) else {
+            return name.to_string();
+        };
+        parts.push(part);
+        rest = &rest[digits + len..];
+    }
+    if rest != "E" || parts.is_empty() {
+        return name.to_string();
+    }
+    if let Some(last) = parts.last()
+        && last.len() == 17
+        && last.starts_with('h')
+        && last[1..].chars().all(|c| c.is_ascii_hexdigit())
+    {
+        parts.pop();
+    }
+    parts.iter().map(|part| unescape(part)).collect::<Vec<_>>().join("::")
+}
+
+/// Decode the `$..$` escapes and `..` separators inside one path part.
+fn unescape(part: &str) -> String {
+    // A leading `_` only keeps a part from starting with `$`.
+    let part = part.strip_prefix('_').filter(|rest| rest.starts_with('$')).unwrap_or(part);
+    let mut out = String::new();
+    let mut rest = part;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            out.push_str("::");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('$')
+            && let Some(end) = after.find('$')
+        {
+            let code = &after[..end];
+            let decoded = match code {
+                "SP" => Some('@'),
+                "BP" => Some('*'),
+                "RF" => Some('&'),
+                "LT" => Some('<'),
+                "GT" => Some('>'),
+                "LP" => Some('('),
+                "RP" => Some(')'),
+                "C" => Some(','),
+                _ => code
+                    .strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32),
+            };
+            match decoded {
+                Some(c) => {
+                    out.push(c);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    out.push('$');
+                    rest = after;
+                }
+            }
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/// A printer for the v0 mangling (RFC 2603), which nightly compilers use.
+///
+/// Lifetimes and crate disambiguators are left out, and so are generic
+/// arguments of the path being named (`Vec::<u8>::push` prints as
+/// `Vec::push`), which keeps the names short; generic arguments inside
+/// types are kept. Backreferences are followed with a depth limit.
+struct V0<'a> {
+    input: &'a [u8],
+    at: usize,
+    depth: u32,
+    out: String,
+}
+
+impl<'a> V0<'a> {
+    fn demangle(symbol: &'a str) -> Option<String> {
+        let mut printer = V0 { input: symbol.as_bytes(), at: 0, depth: 0, out: String::new() };
+        // An optional encoding version.
+        if printer.peek()?.is_ascii_digit() {
+            printer.decimal()?;
+        }
+        printer.path(false)?;
+        // The instantiating crate and any vendor suffix are left out.
+        Some(printer.out)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.at).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.at += 1;
+        Some(byte)
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let found = self.peek() == Some(byte);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    /// A decimal number; a leading `0` is a number of its own.
+    fn decimal(&mut self) -> Option<usize> {
+        let start = self.at;
+        if self.eat(b'0') {
+            return Some(0);
+        }
+        while self.peek()?.is_ascii_digit() {
+            self.at += 1;
+        }
+        std::str::from_utf8(&self.input[start..self.at]).ok()?.parse().ok()
+    }
+
+    /// `_` is 0, otherwise the base-62 digits before the `_`, plus one.
+    fn base62(&mut self) -> Option<u64> {
+        if self.eat(b'_') {
+            return Some(0);
+        }
+        let mut value: u64 = 0;
+        loop {
+            let digit = match self.next()? {
+                b'_' => return value.checked_add(1),
+                c @ b'0'..=b'9' => c - b'0',
+                c @ b'a'..=b'z' => c - b'a' + 10,
+                c @ b'A'..=b'Z' => c - b'A' + 36,
+                _ => return None,
+            };
+            value = value.checked_mul(62)?.checked_add(u64::from(digit))?;
+        }
+    }
+
+    /// An optional `s` disambiguator, as the number it stands for.
+    fn disambiguator(&mut self) -> Option<u64> {
+        if self.eat(b's') { self.base62()?.checked_add(1) } else { Some(0) }
+    }
+
+    fn identifier(&mut self) -> Option<&'a str> {
+        let punycode = self.eat(b'u');
+        let len = self.decimal()?;
+        self.eat(b'_');
+        let bytes = self.input.get(self.at..self.at + len)?;
+        self.at += len;
+        // Non-ASCII identifiers are rare enough to leave encoded.
+        let ident = std::str::from_utf8(bytes).ok()?;
+        if punycode { None } else { Some(ident) }
+    }
+
+    /// Run `f` at the position a backreference points to.
+    fn backref(&mut self, f: impl FnOnce(&mut Self) -> Option<()>) -> Option<()> {
+        let target = self.base62()? as usize;
+        if target >= self.at || self.depth > 64 {
+            return None;
+        }
+        let resume = self.at;
+        self.at = target;
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        self.at = resume;
+        result
+    }
+
+    /// Print a path; `in_type` keeps its generic arguments.
+    fn path(&mut self, in_type: bool) -> Option<()> {
+        match self.next()? {
+            b'C' => {
+                self.disambiguator()?;
+                let name = self.identifier()?;
+                self.out.push_str(name);
+            }
+            b'M' => {
+                self.disambiguator()?;
+                self.skip_path()?;
+                self.out.push('<');
+                self.ty()?;
+                self.out.push('>');
+            }
+            b'X' => {
+                self.disambiguator()?;
+                self.skip_path()?;
+                self.out.push('<');
+                self.ty()?;
+                self.out.push_str(" as ");
+                self.path(true)?;
+                self.out.push('>');
+            }
+            b'Y' => {
+                self.out.push('<');
+                self.ty()?;
+                self.out.push_str(" as ");
+                self.path(true)?;
+                self.out.push('>');
+            }
+            b'N' => {
+                let namespace = self.next()?;
+                self.path(in_type)?;
+                let index = self.disambiguator()?;
+                let name = self.identifier()?;
+                match namespace {
+                    b'C' => self.out.push_str(&format!("::{{closure#{}}}", index)),
+                    b'S' if name.is_empty() => self.out.push_str(&format!("::{{shim#{}}}", index)),
+                    b'S' => self.out.push_str(&format!("::{{shim:{}#{}}}", name, index)),
+                    b'A'..=b'Z' if name.is_empty() => {
+                        self.out.push_str(&format!("::{{{}#{}}}", namespace as char, index))
+                    }
+                    b'A'..=b'Z' => {
+                        self.out.push_str(&format!("::{{{}:{}#{}}}", namespace as char, name, index))
+                    }
+                    // Tuple-like constructors have no name of their own.
+                    _ if name.is_empty() => {}
+                    _ => {
+                        self.out.push_str("::");
+                        self.out.push_str(name);
+                    }
+                }
+            }
+            b'I' => {
+                self.path(in_type)?;
+                if in_type {
+                    self.generic_args()?;
+                } else {
+                    while !self.eat(b'E') {
+                        self.skip_generic_arg()?;
+                    }
+                }
+            }
+            b'B' => self.backref(|printer| printer.path(in_type))?,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Parse a path without printing it.
+    fn skip_path(&mut self) -> Option<()> {
+        let len = self.out.len();
+        let result = self.path(false);
+        self.out.truncate(len);
+        result
+    }
+
+    fn skip_generic_arg(&mut self) -> Option<()> {
+        let len = self.out.len();
+        let result = self.generic_arg().map(|_| ());
+        self.out.truncate(len);
+        result
+    }
+
+    /// Print `<args>` up to the closing `E`, leaving out lifetimes, and
+    /// the brackets too if nothing is left.
+    fn generic_args(&mut self) -> Option<()> {
+        let open = self.out.len();
+        self.out.push('<');
+        let mut first = true;
+        while !self.eat(b'E') {
+            let start = self.out.len();
+            if !first {
+                self.out.push_str(", ");
+            }
+            if self.generic_arg()? {
+                first = false;
+            } else {
+                self.out.truncate(start);
+            }
+        }
+        if first {
+            self.out.truncate(open);
+        } else {
+            self.out.push('>');
+        }
+        Some(())
+    }
+
+    /// Print one generic argument; false if it was a lifetime, which
+    /// prints nothing.
+    fn generic_arg(&mut self) -> Option<bool> {
+        if self.eat(b'L') {
+            self.base62()?;
+            return Some(false);
+        }
+        if self.eat(b'K') {
+            self.constant()?;
+        } else {
+            self.ty()?;
+        }
+        Some(true)
+    }
+
+    fn ty(&mut self) -> Option<()> {
+        let basic = match self.peek()? {
+            b'a' => "i8",
+            b'b' => "bool",
+            b'c' => "char",
+            b'd' => "f64",
+            b'e' => "str",
+            b'f' => "f32",
+            b'h' => "u8",
+            b'i' => "isize",
+            b'j' => "usize",
+            b'l' => "i32",
+            b'm' => "u32",
+            b'n' => "i128",
+            b'o' => "u128",
+            b's' => "i16",
+            b't' => "u16",
+            b'u' => "()",
+            b'v' => "...",
+            b'x' => "i64",
+            b'y' => "u64",
+            b'z' => "!",
+            b'p' => "_",
+            _ => "",
+        };
+        if !basic.is_empty() {
+            self.at += 1;
+            self.out.push_str(basic);
+            return Some(());
+        }
+        match self.peek()? {
+            b'A' => {
+                self.at += 1;
+                self.out.push('[');
+                self.ty()?;
+                self.out.push_str("; ");
+                self.constant()?;
+                self.out.push(']');
+            }
+            b'S' => {
+                self.at += 1;
+                self.out.push('[');
+                self.ty()?;
+                self.out.push(']');
+            }
+            b'T' => {
+                self.at += 1;
+                self.out.push('(');
+                let mut count = 0;
+                while !self.eat(b'E') {
+                    if count > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.ty()?;
+                    count += 1;
+                }
+                if count == 1 {
+                    self.out.push(',');
+                }
+                self.out.push(')');
+            }
+            b'R' | b'Q' => {
+                let mutable = self.next()? == b'Q';
+                if self.eat(b'L') {
+                    self.base62()?;
+                }
+                self.out.push_str(if mutable { "&mut " } else { "&" });
+                self.ty()?;
+            }
+            b'P' | b'O' => {
+                let mutable = self.next()? == b'O';
+                self.out.push_str(if mutable { "*mut " } else { "*const " });
+                self.ty()?;
+            }
+            b'F' => {
+                self.at += 1;
+                self.fn_sig()?;
+            }
+            b'D' => {
+                self.at += 1;
+                self.binder()?;
+                self.out.push_str("dyn ");
+                let mut first = true;
+                while !self.eat(b'E') {
+                    if !first {
+                        self.out.push_str(" + ");
+                    }
+                    first = false;
+                    self.dyn_trait()?;
+                }
+                // The object lifetime bound.
+                if !self.eat(b'L') {
+                    return None;
+                }
+                self.base62()?;
+            }
+            b'B' => {
+                self.at += 1;
+                self.backref(|printer| printer.ty())?;
+            }
+            _ => self.path(true)?,
+        }
+        Some(())
+    }
+
+    fn binder(&mut self) -> Option<()> {
+        if self.eat(b'G') {
+            self.base62()?;
+        }
+        Some(())
+    }
+
+    fn fn_sig(&mut self) -> Option<()> {
+        self.binder()?;
+        if self.eat(b'U') {
+            self.out.push_str("unsafe ");
+        }
+        if self.eat(b'K') {
+            let abi = if self.eat(b'C') { "C" } else { self.identifier()? };
+            self.out.push_str(&format!("extern \"{}\" ", abi.replace('_', "-")));
+        }
+        self.out.push_str("fn(");
+        let mut first = true;
+        while !self.eat(b'E') {
+            if !first {
+                self.out.push_str(", ");
+            }
+            first = false;
+            self.ty()?;
+        }
+        self.out.push(')');
+        let start = self.out.len();
+        self.out.push_str(" -> ");
+        self.ty()?;
+        if &self.out[start..] == " -> ()" {
+            self.out.truncate(start);
+        }
+        Some(())
+    }
+
+    /// A trait in a `dyn` type, with its associated type bindings.
+    fn dyn_trait(&mut self) -> Option<()> {
+        // Print the trait as a type path, then splice the bindings into
+        // its generic arguments.
+        let start = self.out.len();
+        self.path(true)?;
+        let mut bindings = Vec::new();
+        while self.eat(b'p') {
+            let name = self.identifier()?;
+            let at = self.out.len();
+            self.ty()?;
+            let ty = self.out.split_off(at);
+            bindings.push(format!("{} = {}", name, ty));
+        }
+        if !bindings.is_empty() {
+            let bindings = bindings.join(", ");
+            if self.out[start..].ends_with('>') {
+                self.out.pop();
+                self.out.push_str(", ");
+            } else {
+                self.out.push('<');
+            }
+            self.out.push_str(&bindings);
+            self.out.push('>');
+        }
+        Some(())
+    }
+
+    /// A const generic argument: an integer, `bool` or `char`.
+    fn constant(&mut self) -> Option<()> {
+        if self.eat(b'B') {
+            return self.backref(|printer| printer.constant());
+        }
+        if self.eat(b'p') {
+            self.out.push('_');
+            return Some(());
+        }
+        let ty = self.next()?;
+        let negative = self.eat(b'n');
+        let start = self.at;
+        while self.peek()? != b'_' {
+            self.at += 1;
+        }
+        let hex = std::str::from_utf8(&self.input[start..self.at]).ok()?;
+        self.at += 1;
+        let value = if hex.is_empty() { 0 } else { u128::from_str_radix(hex, 16).ok()? };
+        match ty {
+            b'b' => self.out.push_str(if value == 0 { "false" } else { "true" }),
+            b'c' => self.out.push_str(&format!("{:?}", char::from_u32(value as u32)?)),
+            b'a' | b'h' | b'i' | b'j' | b'l' | b'm' | b'n' | b'o' | b's' | b't' | b'x' | b'y' => {
+                if negative {
+                    self.out.push('-');
+                }
+                self.out.push_str(&value.to_string());
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// Cut `name` to at most `MAX_NAME` bytes, at a character boundary.
+fn truncate(mut name: String) -> String {
+    if name.len() > MAX_NAME {
+        let mut end = MAX_NAME;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+    name
+}
+
+fn c_str(image: &[u8], at: usize) -> Option<&str> {
+    let bytes = image.get(at..)?;
+    let end = bytes.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&bytes[..end]).ok()
+}
+
+fn bytes<const N: usize>(image: &[u8], at: usize) -> Result<[u8; N], String> {
+    image
+        .get(at..at + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| "file truncated".to_string())
+}
+
+fn u16_at(image: &[u8], at: usize) -> Result<u16, String> {
+    bytes(image, at).map(u16::from_le_bytes)
+}
+
+fn u32_at(image: &[u8], at: usize) -> Result<u32, String> {
+    bytes(image, at).map(u32::from_le_bytes)
+}
+
+fn u64_at(image: &[u8], at: usize) -> Result<u64, String> {
+    bytes(image, at).map(u64::from_le_bytes)
+}
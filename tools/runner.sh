@@ -0,0 +1,22 @@
+#!/bin/sh
+# Cargo runner for chronos kernels and test binaries: fill in the kernel
+# symbol table (see src/symbols.rs), then boot the binary with bootimage.
+set -e
+
+kernel="$1"
+tools="$(dirname "$0")"
+ksyms="$(dirname "$kernel")/ksyms"
+if [ ! -x "$ksyms" ] || [ "$tools/ksyms.rs" -nt "$ksyms" ]; then
+    rustc --edition 2024 -O -o "$ksyms" "$tools/ksyms.rs"
+fi
+"$ksyms" "$kernel"
+
+# Test binaries that need a QEMU device of their own, on top of test-args.
+case "$(basename "$kernel")" in
+    exit_qemu_byte-*)
+        set -- "$@" \
+            -device isa-debug-exit,iobase=0xf8,iosize=0x01 \
+            -fw_cfg "name=opt/chronos/cmdline,string=debugexit=0xf8 debugexit_size=1"
+        ;;
+esac
+exec bootimage runner "$@"